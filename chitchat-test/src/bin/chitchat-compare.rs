@@ -0,0 +1,52 @@
+use std::process::ExitCode;
+
+use chitchat::{DivergenceKind, DivergenceReport};
+use chitchat_test::ApiResponse;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "chitchat-compare",
+    about = "Reports the key-by-key divergences between the states of two chitchat-test nodes."
+)]
+struct Opt {
+    /// API endpoint of the node used as the local side of the comparison.
+    #[structopt(long = "local")]
+    local: String,
+
+    /// API endpoint of the node used as the remote side of the comparison.
+    #[structopt(long = "remote")]
+    remote: String,
+
+    /// Also report the heartbeat keys, which diverge most of the time.
+    #[structopt(long = "include_heartbeat")]
+    include_heartbeat: bool,
+}
+
+fn fetch_node_info(node_api_endpoint: &str) -> anyhow::Result<ApiResponse> {
+    let response = reqwest::blocking::get(node_api_endpoint)?.json::<ApiResponse>()?;
+    Ok(response)
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    let opt = Opt::from_args();
+    let local = fetch_node_info(&opt.local)?;
+    let remote = fetch_node_info(&opt.remote)?;
+    let mut report = DivergenceReport::compare(&local.cluster_state, &remote.cluster_state);
+    if !opt.include_heartbeat {
+        report
+            .divergences
+            .retain(|divergence| divergence.key != "heartbeat");
+    }
+    print!("{report}");
+    let num_value_mismatches = report.of_kind(DivergenceKind::ValueMismatch).count();
+    println!(
+        "{} divergences, {num_value_mismatches} value mismatches at equal versions",
+        report.divergences.len()
+    );
+    if num_value_mismatches > 0 {
+        Ok(ExitCode::FAILURE)
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
@@ -0,0 +1,53 @@
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use chitchat_test::conformance::run_conformance_suite;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "chitchat-conformance",
+    about = "Runs the chitchat protocol conformance suite against a running node."
+)]
+struct Opt {
+    /// Gossip socket addr of the node under test.
+    #[structopt(long = "target", default_value = "127.0.0.1:10000")]
+    target: SocketAddr,
+
+    /// Cluster id of the node under test.
+    #[structopt(long = "cluster_id", default_value = "testing")]
+    cluster_id: String,
+
+    /// Time to wait for each answer of the node under test.
+    #[structopt(long = "timeout_ms", default_value = "2000")]
+    timeout: u64,
+}
+
+fn main() -> ExitCode {
+    let opt = Opt::from_args();
+    let results = run_conformance_suite(
+        opt.target,
+        &opt.cluster_id,
+        Duration::from_millis(opt.timeout),
+    );
+    let mut num_failures = 0;
+    for scenario_result in &results {
+        match &scenario_result.result {
+            Ok(()) => println!("ok     {}", scenario_result.name),
+            Err(error) => {
+                num_failures += 1;
+                println!("FAILED {}: {error:#}", scenario_result.name);
+            }
+        }
+    }
+    println!(
+        "{} passed, {num_failures} failed",
+        results.len() - num_failures
+    );
+    if num_failures > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,313 @@
+//! Protocol conformance suite.
+//!
+//! The suite drives a process speaking the chitchat wire protocol over UDP through a set
+//! of scripted message exchanges, and checks that its answers match the behavior of this
+//! crate. It only relies on the wire protocol, so it can be used to validate the
+//! interoperability of alternative implementations.
+
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use chitchat::internal::{ChitchatMessage, Delta, Digest};
+use chitchat::{NodeId, Serializable};
+
+/// Maximum UDP datagram payload size (in bytes).
+const MAX_UDP_DATAGRAM_PAYLOAD_SIZE: usize = 65_507;
+
+const HEARTBEAT_KEY: &str = "heartbeat";
+
+/// A peer of the target, exchanging messages with it on behalf of a scenario.
+pub struct ConformanceDriver {
+    socket: UdpSocket,
+    node_id: NodeId,
+    target_addr: SocketAddr,
+    cluster_id: String,
+    timeout: Duration,
+}
+
+impl ConformanceDriver {
+    pub fn new(
+        target_addr: SocketAddr,
+        cluster_id: String,
+        timeout: Duration,
+    ) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind("127.0.0.1:0").context("Failed to bind driver socket.")?;
+        let local_addr = socket.local_addr()?;
+        let node_id = NodeId::new(
+            format!("conformance-driver-{}", local_addr.port()),
+            local_addr,
+        );
+        Ok(ConformanceDriver {
+            socket,
+            node_id,
+            target_addr,
+            cluster_id,
+            timeout,
+        })
+    }
+
+    pub fn node_id(&self) -> &NodeId {
+        &self.node_id
+    }
+
+    pub fn send(&self, message: &ChitchatMessage) -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        message.serialize(&mut buf);
+        self.send_bytes(&buf)
+    }
+
+    pub fn send_bytes(&self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.socket.send_to(bytes, self.target_addr)?;
+        Ok(())
+    }
+
+    pub fn send_syn(&self, digest: Digest) -> anyhow::Result<()> {
+        self.send(&ChitchatMessage::Syn {
+            cluster_id: self.cluster_id.clone(),
+            digest,
+        })
+    }
+
+    /// Waits for a message from the target matching the predicate.
+    ///
+    /// Other messages, such as gossip rounds initiated by the target, are ignored.
+    pub fn recv_matching(
+        &self,
+        predicate: impl Fn(&ChitchatMessage) -> bool,
+    ) -> anyhow::Result<ChitchatMessage> {
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = vec![0u8; MAX_UDP_DATAGRAM_PAYLOAD_SIZE];
+        loop {
+            let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                bail!("No matching message received from the target.");
+            };
+            self.socket.set_read_timeout(Some(timeout))?;
+            let Ok((len, from_addr)) = self.socket.recv_from(&mut buf) else {
+                bail!("No matching message received from the target.");
+            };
+            if from_addr != self.target_addr {
+                continue;
+            }
+            let message = ChitchatMessage::deserialize(&mut &buf[..len])
+                .context("Target sent an invalid message.")?;
+            if predicate(&message) {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Sends a syn and returns the digest and delta of the syn ack answered by the target.
+    pub fn syn_ack(&self, digest: Digest) -> anyhow::Result<(Digest, Delta)> {
+        self.send_syn(digest)?;
+        let message = self.recv_matching(|message| {
+            matches!(
+                message,
+                ChitchatMessage::SynAck { .. } | ChitchatMessage::BadCluster
+            )
+        })?;
+        match message {
+            ChitchatMessage::SynAck {
+                cluster_id,
+                digest,
+                delta,
+            } => {
+                if cluster_id != self.cluster_id {
+                    bail!("Syn ack carries cluster ID `{cluster_id}`.");
+                }
+                Ok((digest, delta))
+            }
+            _ => bail!("Expected a syn ack, got {message:?}."),
+        }
+    }
+
+    /// Returns the node id of the target, as advertised in its digest.
+    pub fn target_node_id(&self, digest: &Digest) -> anyhow::Result<NodeId> {
+        digest
+            .iter()
+            .map(|(node_id, _)| node_id)
+            .find(|node_id| node_id.gossip_public_address == self.target_addr)
+            .cloned()
+            .context("Target is missing from its own digest.")
+    }
+}
+
+/// A scripted message exchange with the target, along with its expected outcome.
+pub struct Scenario {
+    pub name: &'static str,
+    pub run: fn(&ConformanceDriver) -> anyhow::Result<()>,
+}
+
+/// Returns all the scenarios of the suite.
+pub fn scenarios() -> Vec<Scenario> {
+    vec![
+        Scenario {
+            name: "syn-with-wrong-cluster-id-is-rejected",
+            run: syn_with_wrong_cluster_id_is_rejected,
+        },
+        Scenario {
+            name: "syn-with-empty-digest-returns-target-state",
+            run: syn_with_empty_digest_returns_target_state,
+        },
+        Scenario {
+            name: "syn-ack-only-contains-stale-key-values",
+            run: syn_ack_only_contains_stale_key_values,
+        },
+        Scenario {
+            name: "ack-delta-is-applied",
+            run: ack_delta_is_applied,
+        },
+        Scenario {
+            name: "ack-with-wrong-cluster-id-is-ignored",
+            run: ack_with_wrong_cluster_id_is_ignored,
+        },
+        Scenario {
+            name: "malformed-message-is-ignored",
+            run: malformed_message_is_ignored,
+        },
+    ]
+}
+
+fn syn_with_wrong_cluster_id_is_rejected(driver: &ConformanceDriver) -> anyhow::Result<()> {
+    driver.send(&ChitchatMessage::Syn {
+        cluster_id: format!("{}-mismatch", driver.cluster_id),
+        digest: Digest::default(),
+    })?;
+    let message = driver.recv_matching(|message| {
+        matches!(
+            message,
+            ChitchatMessage::SynAck { .. } | ChitchatMessage::BadCluster
+        )
+    })?;
+    if message != ChitchatMessage::BadCluster {
+        bail!("Expected a bad cluster message, got {message:?}.");
+    }
+    Ok(())
+}
+
+fn syn_with_empty_digest_returns_target_state(driver: &ConformanceDriver) -> anyhow::Result<()> {
+    let (digest, delta) = driver.syn_ack(Digest::default())?;
+    let target_node_id = driver.target_node_id(&digest)?;
+    let target_node_delta = delta
+        .node_deltas
+        .get(&target_node_id)
+        .context("Delta is missing the target node state.")?;
+    if !target_node_delta.key_values.contains_key(HEARTBEAT_KEY) {
+        bail!("Target node state is missing the `{HEARTBEAT_KEY}` key.");
+    }
+    Ok(())
+}
+
+fn syn_ack_only_contains_stale_key_values(driver: &ConformanceDriver) -> anyhow::Result<()> {
+    let (target_digest, _) = driver.syn_ack(Digest::default())?;
+    let (_, delta) = driver.syn_ack(target_digest.clone())?;
+    for (node_id, node_delta) in &delta.node_deltas {
+        if delta.nodes_to_reset.contains(node_id) {
+            continue;
+        }
+        let floor_version = target_digest.max_version(node_id).unwrap_or(0);
+        for (key, versioned_value) in &node_delta.key_values {
+            if versioned_value.version <= floor_version {
+                bail!(
+                    "Delta contains key `{key}` of node `{}` at version {}, already covered by \
+                     digest version {floor_version}.",
+                    node_id.id,
+                    versioned_value.version
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn ack_delta_is_applied(driver: &ConformanceDriver) -> anyhow::Result<()> {
+    // Like any peer, the driver advertises its own node in its digest: the target bounds the
+    // first delta of a node by the max version advertised for it.
+    let mut digest = Digest::default();
+    digest.add_node_with_heartbeat(driver.node_id().clone(), 1, 2);
+    driver.syn_ack(digest)?;
+    let mut delta = Delta::default();
+    delta.add_node_delta(driver.node_id().clone(), HEARTBEAT_KEY, "1", 1, false);
+    delta.add_node_delta(
+        driver.node_id().clone(),
+        "conformance-key",
+        "conformance-value",
+        2,
+        false,
+    );
+    driver.send(&ChitchatMessage::Ack {
+        cluster_id: driver.cluster_id.clone(),
+        delta,
+    })?;
+
+    let (_, delta) = driver.syn_ack(Digest::default())?;
+    let versioned_value = delta
+        .node_deltas
+        .get(driver.node_id())
+        .and_then(|node_delta| node_delta.key_values.get("conformance-key"))
+        .context("Target did not gossip back the key-value sent in the ack.")?;
+    if versioned_value.value != "conformance-value" || versioned_value.version != 2 {
+        bail!("Target gossiped back an unexpected value: {versioned_value:?}.");
+    }
+    Ok(())
+}
+
+fn ack_with_wrong_cluster_id_is_ignored(driver: &ConformanceDriver) -> anyhow::Result<()> {
+    driver.syn_ack(Digest::default())?;
+    let mut delta = Delta::default();
+    delta.add_node_delta(driver.node_id().clone(), HEARTBEAT_KEY, "1", 1, false);
+    delta.add_node_delta(
+        driver.node_id().clone(),
+        "conformance-key",
+        "conformance-value",
+        2,
+        false,
+    );
+    driver.send(&ChitchatMessage::Ack {
+        cluster_id: format!("{}-mismatch", driver.cluster_id),
+        delta,
+    })?;
+
+    let (_, delta) = driver.syn_ack(Digest::default())?;
+    if delta.node_deltas.contains_key(driver.node_id()) {
+        bail!("Target applied an ack carrying another cluster ID.");
+    }
+    Ok(())
+}
+
+fn malformed_message_is_ignored(driver: &ConformanceDriver) -> anyhow::Result<()> {
+    // Unknown message type.
+    driver.send_bytes(&[255, 1, 2, 3])?;
+    // Truncated syn.
+    driver.send_bytes(&[0, 5])?;
+    driver
+        .syn_ack(Digest::default())
+        .context("Target stopped answering after receiving malformed messages.")?;
+    Ok(())
+}
+
+/// Outcome of a scenario.
+pub struct ScenarioResult {
+    pub name: &'static str,
+    pub result: anyhow::Result<()>,
+}
+
+/// Runs all the scenarios against the target. Each scenario gets its own driver, i.e. it
+/// appears to the target as a distinct peer.
+pub fn run_conformance_suite(
+    target_addr: SocketAddr,
+    cluster_id: &str,
+    timeout: Duration,
+) -> Vec<ScenarioResult> {
+    scenarios()
+        .into_iter()
+        .map(|scenario| {
+            let result = ConformanceDriver::new(target_addr, cluster_id.to_string(), timeout)
+                .and_then(|driver| (scenario.run)(&driver));
+            ScenarioResult {
+                name: scenario.name,
+                result,
+            }
+        })
+        .collect()
+}
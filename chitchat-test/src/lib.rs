@@ -1,3 +1,5 @@
+pub mod conformance;
+
 use chitchat::{ClusterStateSnapshot, NodeId};
 use serde::{Deserialize, Serialize};
 
@@ -1,9 +1,10 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
 use chitchat::transport::UdpTransport;
-use chitchat::{spawn_chitchat, Chitchat, ChitchatConfig, FailureDetectorConfig, NodeId};
+use chitchat::{spawn_chitchat, Chitchat, ChitchatConfig, NodeId, SeedFile, WriteSource};
 use chitchat_test::{ApiResponse, SetKeyValueResponse};
 use cool_id_generator::Size;
 use poem::listener::TcpListener;
@@ -30,6 +31,7 @@ impl Api {
             live_nodes: chitchat_guard.live_nodes().cloned().collect::<Vec<_>>(),
             dead_nodes: chitchat_guard.dead_nodes().cloned().collect::<Vec<_>>(),
         };
+        drop(chitchat_guard);
         Json(serde_json::to_value(&response).unwrap())
     }
 
@@ -39,7 +41,7 @@ impl Api {
         let mut chitchat_guard = self.chitchat.lock().await;
 
         let cc_state = chitchat_guard.self_node_state();
-        cc_state.set(key.as_str(), value.as_str());
+        cc_state.set_with_source(key.as_str(), value.as_str(), WriteSource::Operator);
 
         Json(serde_json::to_value(&SetKeyValueResponse { status: true }).unwrap())
     }
@@ -67,6 +69,10 @@ struct Opt {
     #[structopt(long = "seed")]
     seeds: Vec<String>,
 
+    /// File listing additional seeds, one per line, read again every 10 seconds.
+    #[structopt(long = "seed_file")]
+    seed_file: Option<PathBuf>,
+
     #[structopt(long = "interval_ms", default_value = "500")]
     interval: u64,
 }
@@ -86,16 +92,20 @@ async fn main() -> anyhow::Result<()> {
         .node_id
         .unwrap_or_else(|| generate_server_id(public_addr));
     let node_id = NodeId::new(node_id_str, public_addr);
-    let config = ChitchatConfig {
+    let mut config = ChitchatConfig {
         node_id,
         cluster_id: "testing".to_string(),
         gossip_interval: Duration::from_millis(opt.interval),
         listen_addr: opt.listen_addr,
         seed_nodes: opt.seeds.clone(),
-        failure_detector_config: FailureDetectorConfig::default(),
-        is_ready_predicate: None,
         marked_for_deletion_grace_period: 10_000,
+        tombstone_grace_period: Duration::from_secs(3_600),
+        ..Default::default()
     };
+    if let Some(seed_file) = opt.seed_file {
+        config.seed_refresh_interval = Duration::from_secs(10);
+        config.set_seed_provider(SeedFile::new(seed_file));
+    }
     let chitchat_handler = spawn_chitchat(config, Vec::new(), &UdpTransport).await?;
     let chitchat = chitchat_handler.chitchat();
     let api = Api { chitchat };
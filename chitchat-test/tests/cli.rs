@@ -6,6 +6,7 @@ use std::process::Child;
 use std::thread;
 use std::time::Duration;
 
+use chitchat::WriteSource;
 use chitchat_test::{ApiResponse, SetKeyValueResponse};
 use helpers::spawn_command;
 
@@ -72,12 +73,14 @@ fn test_multiple_nodes() {
     assert_eq!(info.live_nodes.len(), 4);
     assert_eq!(info.dead_nodes.len(), 0);
 
-    assert!(info.cluster_state.node_states.get("node_3").is_some());
+    assert!(info.cluster_state.node_states.contains_key("node_3"));
     // Check that "some_key" we set on this local node (localhost:10001) is
     // indeed set to be "some_value"
     let ns = info.cluster_state.node_states.get("node_1").unwrap();
     let v = ns.get_versioned("some_key").unwrap();
     assert_eq!(v.value, "some_value");
+    assert_eq!(ns.write_source("some_key"), Some(WriteSource::Operator));
+    assert_eq!(ns.write_source("heartbeat"), Some(WriteSource::Internal));
 }
 
 #[test]
@@ -85,7 +88,7 @@ fn test_multiple_nodes_with_dns_resolution_for_seed() {
     let _child_handles = setup_nodes(12_000, 5, 5, true);
     // Check node states through api.
     let info = get_node_info("http://127.0.0.1:12001").unwrap();
-    assert!(info.cluster_state.node_states.get("node_3").is_some());
+    assert!(info.cluster_state.node_states.contains_key("node_3"));
     assert_eq!(info.cluster_id, "testing");
     assert_eq!(info.live_nodes.len(), 4);
     assert_eq!(info.dead_nodes.len(), 0);
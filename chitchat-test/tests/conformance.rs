@@ -0,0 +1,26 @@
+mod helpers;
+
+use std::thread;
+use std::time::Duration;
+
+use chitchat_test::conformance::run_conformance_suite;
+use helpers::spawn_command;
+
+#[test]
+fn test_conformance_suite_against_chitchat_test_node() {
+    let mut node = spawn_command("--listen_addr 127.0.0.1:14000 --interval_ms 50").unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let results = run_conformance_suite(
+        "127.0.0.1:14000".parse().unwrap(),
+        "testing",
+        Duration::from_secs(2),
+    );
+    let _ = node.kill();
+    let _ = node.wait();
+    for scenario_result in &results {
+        if let Err(error) = &scenario_result.result {
+            panic!("scenario `{}` failed: {error:#}", scenario_result.name);
+        }
+    }
+    assert_eq!(results.len(), 6);
+}
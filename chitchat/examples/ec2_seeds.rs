@@ -0,0 +1,571 @@
+//! Seeds a cluster with the running EC2 instances carrying a given tag, through a custom
+//! [`SeedProvider`].
+//!
+//! The requests to the EC2 API are signed by hand, so that the example builds with the
+//! dependencies of chitchat alone. A real application would rather list the instances with the
+//! AWS SDK, e.g. `aws-sdk-ec2`, which also handles retries and every way to get credentials.
+//!
+//! Run it on an EC2 instance with:
+//!
+//! ```text
+//! cargo run --example ec2_seeds -- <node-id> <listen-addr> <tag-key> <tag-value>
+//! ```
+//!
+//! reqwest is built without TLS here: enable one of its TLS features, e.g. `rustls-tls`, to
+//! reach the HTTPS endpoint of the EC2 API, or point [`Ec2Seeds::endpoint`] to a proxy.
+
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use chitchat::transport::UdpTransport;
+use chitchat::{spawn_chitchat, ChitchatConfig, NodeId, SeedProvider};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+/// Base URL of the instance metadata service.
+const IMDS_URL: &str = "http://169.254.169.254/latest";
+
+/// Lifetime of the session tokens requested from the instance metadata service, in seconds.
+const IMDS_TOKEN_TTL_SECS: u32 = 300;
+
+/// Version of the EC2 query API.
+const EC2_API_VERSION: &str = "2016-11-15";
+
+/// An HTTP request issued by [`Ec2Seeds`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HttpRequest {
+    pub method: &'static str,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+}
+
+/// Sends the HTTP requests of [`Ec2Seeds`].
+///
+/// The requests to the instance metadata service are plain HTTP, the requests to the EC2 API
+/// are HTTPS.
+#[async_trait]
+pub trait HttpClient: Send + Sync + 'static {
+    /// Sends the request and returns the body of the response. Fails if the status of the
+    /// response is not a success.
+    async fn send(&self, request: HttpRequest) -> anyhow::Result<String>;
+}
+
+/// Seeds the cluster with the running EC2 instances carrying a given tag. Set as the seed
+/// provider, see [`ChitchatConfig::set_seed_provider`], the instances are listed again every
+/// `seed_refresh_interval`.
+///
+/// The credentials are read from the `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+/// `AWS_SESSION_TOKEN` environment variables or else, like the region when it is not set, from
+/// the instance metadata service. They must allow `ec2:DescribeInstances`.
+pub struct Ec2Seeds {
+    pub tag_key: String,
+    pub tag_value: String,
+    /// Gossip port of the instances.
+    pub port: u16,
+    /// Region of the instances. Defaults to the region of the instance we run on.
+    pub region: Option<String>,
+    /// Overrides the endpoint of the EC2 API, `https://ec2.<region>.amazonaws.com` by default.
+    pub endpoint: Option<String>,
+    http_client: Box<dyn HttpClient>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ImdsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: String,
+}
+
+impl Ec2Seeds {
+    pub fn new(
+        tag_key: impl Into<String>,
+        tag_value: impl Into<String>,
+        port: u16,
+        http_client: impl HttpClient,
+    ) -> Self {
+        Ec2Seeds {
+            tag_key: tag_key.into(),
+            tag_value: tag_value.into(),
+            port,
+            region: None,
+            endpoint: None,
+            http_client: Box::new(http_client),
+        }
+    }
+
+    async fn discover(&self) -> anyhow::Result<HashSet<SocketAddr>> {
+        let env_credentials_opt = credentials_from_env();
+        let imds_token_opt = if env_credentials_opt.is_none() || self.region.is_none() {
+            Some(self.imds_token().await?)
+        } else {
+            None
+        };
+        let credentials = match env_credentials_opt {
+            Some(credentials) => credentials,
+            None => self.imds_credentials(imds_token_opt.as_deref()).await?,
+        };
+        let region = match &self.region {
+            Some(region) => region.clone(),
+            None => {
+                self.imds_get("meta-data/placement/region", imds_token_opt.as_deref())
+                    .await?
+            }
+        };
+        let endpoint = self
+            .endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://ec2.{region}.amazonaws.com"));
+
+        let mut seed_addrs = HashSet::new();
+        let mut next_token_opt: Option<String> = None;
+        loop {
+            let mut params = vec![
+                ("Action".to_string(), "DescribeInstances".to_string()),
+                ("Version".to_string(), EC2_API_VERSION.to_string()),
+                ("Filter.1.Name".to_string(), format!("tag:{}", self.tag_key)),
+                ("Filter.1.Value.1".to_string(), self.tag_value.clone()),
+                (
+                    "Filter.2.Name".to_string(),
+                    "instance-state-name".to_string(),
+                ),
+                ("Filter.2.Value.1".to_string(), "running".to_string()),
+            ];
+            if let Some(next_token) = next_token_opt.take() {
+                params.push(("NextToken".to_string(), next_token));
+            }
+            let request =
+                sign_request(&endpoint, &params, &region, &credentials, SystemTime::now())?;
+            let response = self.http_client.send(request).await?;
+            seed_addrs.extend(
+                private_ip_addrs(&response)
+                    .into_iter()
+                    .map(|ip_addr| SocketAddr::new(ip_addr, self.port)),
+            );
+            next_token_opt = xml_value(&response, "nextToken").map(str::to_string);
+            if next_token_opt.is_none() {
+                return Ok(seed_addrs);
+            }
+        }
+    }
+
+    async fn imds_token(&self) -> anyhow::Result<String> {
+        let request = HttpRequest {
+            method: "PUT",
+            url: format!("{IMDS_URL}/api/token"),
+            headers: vec![(
+                "X-aws-ec2-metadata-token-ttl-seconds".to_string(),
+                IMDS_TOKEN_TTL_SECS.to_string(),
+            )],
+        };
+        self.http_client
+            .send(request)
+            .await
+            .context("Failed to get a token from the instance metadata service.")
+    }
+
+    async fn imds_get(&self, path: &str, imds_token_opt: Option<&str>) -> anyhow::Result<String> {
+        let headers = imds_token_opt
+            .map(|imds_token| {
+                (
+                    "X-aws-ec2-metadata-token".to_string(),
+                    imds_token.to_string(),
+                )
+            })
+            .into_iter()
+            .collect();
+        let request = HttpRequest {
+            method: "GET",
+            url: format!("{IMDS_URL}/{path}"),
+            headers,
+        };
+        let response = self.http_client.send(request).await.with_context(|| {
+            format!("Failed to get `{path}` from the instance metadata service.")
+        })?;
+        Ok(response.trim().to_string())
+    }
+
+    async fn imds_credentials(
+        &self,
+        imds_token_opt: Option<&str>,
+    ) -> anyhow::Result<AwsCredentials> {
+        let roles = self
+            .imds_get("meta-data/iam/security-credentials/", imds_token_opt)
+            .await?;
+        let role = roles
+            .lines()
+            .next()
+            .context("No IAM role is attached to the instance.")?;
+        let credentials_json = self
+            .imds_get(
+                &format!("meta-data/iam/security-credentials/{role}"),
+                imds_token_opt,
+            )
+            .await?;
+        let credentials: ImdsCredentials = serde_json::from_str(&credentials_json)?;
+        Ok(AwsCredentials {
+            access_key_id: credentials.access_key_id,
+            secret_access_key: credentials.secret_access_key,
+            session_token: Some(credentials.token),
+        })
+    }
+}
+
+#[async_trait]
+impl SeedProvider for Ec2Seeds {
+    async fn seeds(&self) -> HashSet<SocketAddr> {
+        match self.discover().await {
+            Ok(seed_addrs) => seed_addrs,
+            Err(error) => {
+                warn!(error = %error, "failed-to-discover-ec2-seeds");
+                HashSet::new()
+            }
+        }
+    }
+}
+
+struct ReqwestClient(reqwest::Client);
+
+#[async_trait]
+impl HttpClient for ReqwestClient {
+    async fn send(&self, request: HttpRequest) -> anyhow::Result<String> {
+        let method: reqwest::Method = request.method.parse()?;
+        let mut request_builder = self.0.request(method, &request.url);
+        for (name, value) in request.headers {
+            request_builder = request_builder.header(name, value);
+        }
+        let response = request_builder.send().await?.error_for_status()?;
+        Ok(response.text().await?)
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [node_id, listen_addr, tag_key, tag_value] = &args[..] else {
+        bail!("usage: ec2_seeds <node-id> <listen-addr> <tag-key> <tag-value>");
+    };
+    let listen_addr: SocketAddr = listen_addr.parse()?;
+    let mut config = ChitchatConfig {
+        node_id: NodeId::new(node_id.clone(), listen_addr),
+        listen_addr,
+        seed_refresh_interval: Duration::from_secs(30),
+        ..Default::default()
+    };
+    let http_client = ReqwestClient(reqwest::Client::new());
+    config.set_seed_provider(Ec2Seeds::new(
+        tag_key.clone(),
+        tag_value.clone(),
+        listen_addr.port(),
+        http_client,
+    ));
+    let chitchat_handle = spawn_chitchat(config, Vec::new(), &UdpTransport).await?;
+    loop {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let chitchat = chitchat_handle.chitchat();
+        let chitchat_guard = chitchat.lock().await;
+        let live_nodes: Vec<&NodeId> = chitchat_guard.live_nodes().collect();
+        println!("live nodes: {live_nodes:?}");
+    }
+}
+
+fn credentials_from_env() -> Option<AwsCredentials> {
+    Some(AwsCredentials {
+        access_key_id: std::env::var("AWS_ACCESS_KEY_ID").ok()?,
+        secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+        session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+    })
+}
+
+/// Signs a GET request to the EC2 query API, with AWS signature version 4.
+fn sign_request(
+    endpoint: &str,
+    params: &[(String, String)],
+    region: &str,
+    credentials: &AwsCredentials,
+    now: SystemTime,
+) -> anyhow::Result<HttpRequest> {
+    let host = endpoint
+        .split_once("://")
+        .map_or(endpoint, |(_, host)| host)
+        .trim_end_matches('/');
+    let (date, date_time) = amz_date(now);
+    let mut encoded_params: Vec<(String, String)> = params
+        .iter()
+        .map(|(key, value)| (uri_encode(key), uri_encode(value)))
+        .collect();
+    encoded_params.sort();
+    let query = encoded_params
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let mut headers = vec![
+        ("host".to_string(), host.to_string()),
+        ("x-amz-date".to_string(), date_time.clone()),
+    ];
+    if let Some(session_token) = &credentials.session_token {
+        headers.push(("x-amz-security-token".to_string(), session_token.clone()));
+    }
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{name}:{value}\n"))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let empty_payload_hash = hex(&Sha256::digest(b""));
+    let canonical_request =
+        format!("GET\n/\n{query}\n{canonical_headers}\n{signed_headers}\n{empty_payload_hash}");
+
+    let scope = format!("{date}/{region}/ec2/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{date_time}\n{scope}\n{}",
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let signing_key = signing_key(&credentials.secret_access_key, &date, region, "ec2");
+    let signature = hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+    headers.push((
+        "authorization".to_string(),
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, \
+             Signature={signature}",
+            credentials.access_key_id
+        ),
+    ));
+    // The HTTP client sets the host header itself.
+    headers.remove(0);
+    Ok(HttpRequest {
+        method: "GET",
+        url: format!("{}/?{query}", endpoint.trim_end_matches('/')),
+        headers,
+    })
+}
+
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let date_key = hmac_sha256(
+        format!("AWS4{secret_access_key}").as_bytes(),
+        date.as_bytes(),
+    );
+    let region_key = hmac_sha256(&date_key, region.as_bytes());
+    let service_key = hmac_sha256(&region_key, service.as_bytes());
+    hmac_sha256(&service_key, b"aws4_request")
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC should accept keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Percent-encodes everything but the unreserved characters, as required by the canonical
+/// requests of signature version 4.
+fn uri_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Returns the date, `YYYYMMDD`, and the date and time, `YYYYMMDDTHHMMSSZ`, in UTC.
+fn amz_date(now: SystemTime) -> (String, String) {
+    let secs = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+    // Converts the days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    let date = format!("{year:04}{month:02}{day:02}");
+    let date_time = format!(
+        "{date}T{:02}{:02}{:02}Z",
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60
+    );
+    (date, date_time)
+}
+
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start_tag = format!("<{tag}>");
+    let start = xml.find(&start_tag)? + start_tag.len();
+    let end = start + xml[start..].find(&format!("</{tag}>"))?;
+    Some(&xml[start..end])
+}
+
+/// Returns the primary private IP address of the instances of a `DescribeInstances` response.
+fn private_ip_addrs(response: &str) -> Vec<IpAddr> {
+    // The instance-level address precedes the addresses of the network interfaces.
+    response
+        .split("<instanceId>")
+        .skip(1)
+        .filter_map(|instance| xml_value(instance, "privateIpAddress")?.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    const DESCRIBE_INSTANCES_RESPONSE: &str = r#"<DescribeInstancesResponse>
+        <reservationSet><item><instancesSet>
+            <item>
+                <instanceId>i-1</instanceId>
+                <privateIpAddress>10.0.0.1</privateIpAddress>
+                <networkInterfaceSet><item>
+                    <privateIpAddress>10.0.0.1</privateIpAddress>
+                    <privateIpAddressesSet><item>
+                        <privateIpAddress>10.0.0.11</privateIpAddress>
+                    </item></privateIpAddressesSet>
+                </item></networkInterfaceSet>
+            </item>
+            <item>
+                <instanceId>i-2</instanceId>
+                <privateIpAddress>10.0.0.2</privateIpAddress>
+            </item>
+        </instancesSet></item></reservationSet>
+    </DescribeInstancesResponse>"#;
+
+    #[derive(Default)]
+    struct HttpClientForTest {
+        requests: Mutex<Vec<HttpRequest>>,
+    }
+
+    #[async_trait]
+    impl HttpClient for std::sync::Arc<HttpClientForTest> {
+        async fn send(&self, request: HttpRequest) -> anyhow::Result<String> {
+            let response = if request.url.ends_with("/api/token") {
+                "token".to_string()
+            } else if request.url.ends_with("/security-credentials/") {
+                "my-role\n".to_string()
+            } else if request.url.ends_with("/security-credentials/my-role") {
+                r#"{"Code":"Success","AccessKeyId":"AKID","SecretAccessKey":"secret","Token":"session"}"#
+                    .to_string()
+            } else if request.url.ends_with("/placement/region") {
+                "eu-west-1".to_string()
+            } else if request
+                .url
+                .starts_with("https://ec2.eu-west-1.amazonaws.com/")
+            {
+                DESCRIBE_INSTANCES_RESPONSE.to_string()
+            } else {
+                anyhow::bail!("unexpected request: {request:?}");
+            };
+            self.requests.lock().unwrap().push(request);
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn test_signing_key() {
+        // Example from the AWS documentation on signature version 4.
+        let signing_key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex(&signing_key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn test_amz_date() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_217_296);
+        assert_eq!(
+            amz_date(now),
+            ("20240229".to_string(), "20240229T143456Z".to_string())
+        );
+        assert_eq!(
+            amz_date(SystemTime::UNIX_EPOCH).1,
+            "19700101T000000Z".to_string()
+        );
+    }
+
+    #[test]
+    fn test_private_ip_addrs() {
+        assert_eq!(
+            private_ip_addrs(DESCRIBE_INSTANCES_RESPONSE),
+            vec![
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "10.0.0.2".parse().unwrap()
+            ]
+        );
+        assert!(xml_value(DESCRIBE_INSTANCES_RESPONSE, "nextToken").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ec2_seeds() {
+        let http_client = std::sync::Arc::new(HttpClientForTest::default());
+        let ec2_seeds = Ec2Seeds::new("cluster", "my cluster", 7280, http_client.clone());
+        // The test may run with credentials set in the environment.
+        if credentials_from_env().is_some() {
+            return;
+        }
+        assert_eq!(
+            ec2_seeds.seeds().await,
+            HashSet::from([
+                "10.0.0.1:7280".parse().unwrap(),
+                "10.0.0.2:7280".parse().unwrap()
+            ])
+        );
+        let requests = http_client.requests.lock().unwrap();
+        let describe_instances = requests.last().unwrap();
+        assert!(describe_instances
+            .url
+            .contains("Filter.1.Value.1=my%20cluster"));
+        assert!(describe_instances
+            .headers
+            .contains(&("x-amz-security-token".to_string(), "session".to_string())));
+        let (_, authorization) = describe_instances
+            .headers
+            .iter()
+            .find(|(name, _)| name == "authorization")
+            .unwrap();
+        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKID/"));
+        assert!(authorization.contains("/eu-west-1/ec2/aws4_request"));
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+/// Aggregation function computed over the numeric values of keys across nodes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum AggFn {
+    Sum,
+    Min,
+    Max,
+    Count,
+}
+
+impl AggFn {
+    /// Returns `None` for the min and max of an empty set of values.
+    pub(crate) fn apply(self, values: impl Iterator<Item = f64>) -> Option<f64> {
+        match self {
+            AggFn::Sum => Some(values.sum()),
+            AggFn::Min => values.reduce(f64::min),
+            AggFn::Max => values.reduce(f64::max),
+            AggFn::Count => Some(values.count() as f64),
+        }
+    }
+}
+
+/// Caches the results of aggregations for a given revision of the cluster state.
+///
+/// The revision must change whenever the key-values or the set of live nodes change.
+#[derive(Debug, Default)]
+pub(crate) struct AggregationCache {
+    revision: (u64, u64),
+    results: HashMap<(String, AggFn), Option<f64>>,
+}
+
+impl AggregationCache {
+    pub fn get_or_compute(
+        &mut self,
+        revision: (u64, u64),
+        prefix: &str,
+        agg_fn: AggFn,
+        compute: impl FnOnce() -> Option<f64>,
+    ) -> Option<f64> {
+        if self.revision != revision {
+            self.revision = revision;
+            self.results.clear();
+        }
+        *self
+            .results
+            .entry((prefix.to_string(), agg_fn))
+            .or_insert_with(compute)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agg_fn_apply() {
+        let values = [3.0, -1.5, 10.0];
+        assert_eq!(AggFn::Sum.apply(values.into_iter()), Some(11.5));
+        assert_eq!(AggFn::Min.apply(values.into_iter()), Some(-1.5));
+        assert_eq!(AggFn::Max.apply(values.into_iter()), Some(10.0));
+        assert_eq!(AggFn::Count.apply(values.into_iter()), Some(3.0));
+        assert_eq!(AggFn::Sum.apply(std::iter::empty()), Some(0.0));
+        assert_eq!(AggFn::Min.apply(std::iter::empty()), None);
+        assert_eq!(AggFn::Count.apply(std::iter::empty()), Some(0.0));
+    }
+
+    #[test]
+    fn test_aggregation_cache() {
+        let mut cache = AggregationCache::default();
+        assert_eq!(
+            cache.get_or_compute((1, 0), "load", AggFn::Sum, || Some(1.0)),
+            Some(1.0)
+        );
+        assert_eq!(
+            cache.get_or_compute((1, 0), "load", AggFn::Sum, || unreachable!()),
+            Some(1.0)
+        );
+        assert_eq!(
+            cache.get_or_compute((1, 0), "load", AggFn::Max, || Some(2.0)),
+            Some(2.0)
+        );
+        assert_eq!(
+            cache.get_or_compute((1, 1), "load", AggFn::Sum, || Some(3.0)),
+            Some(3.0)
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::delta::Delta;
+use crate::state::ClusterState;
+use crate::{NodeId, Version};
+
+/// A change applied to the cluster state from a peer, sent to the `audit_sink` of the
+/// [`crate::ChitchatConfig`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Address of the peer the change was received from. `None` for the changes merged from a
+    /// snapshot, see [`crate::Chitchat::merge_snapshot`].
+    pub source_addr: Option<SocketAddr>,
+    pub node_id: NodeId,
+    pub key: String,
+    /// Version of the key-value before the change, if it was known.
+    pub old_version: Option<Version>,
+    pub new_version: Version,
+    pub marked_for_deletion: bool,
+}
+
+/// Versions of the key-values of a delta before it is applied, turned into audit records once
+/// it is applied.
+pub(crate) struct PendingAuditRecords {
+    source_addr: Option<SocketAddr>,
+    old_versions: Vec<(NodeId, Arc<str>, Option<Version>)>,
+}
+
+impl PendingAuditRecords {
+    pub fn new(
+        source_addr: Option<SocketAddr>,
+        delta: &Delta,
+        cluster_state: &ClusterState,
+    ) -> Self {
+        let mut old_versions = Vec::new();
+        for (node_id, node_delta) in &delta.node_deltas {
+            let node_state_opt = cluster_state.node_state(node_id);
+            for key in node_delta.key_values.keys() {
+                let old_version = node_state_opt
+                    .and_then(|node_state| node_state.key_values.get(&**key))
+                    .map(|versioned_value| versioned_value.version);
+                old_versions.push((node_id.clone(), key.clone(), old_version));
+            }
+        }
+        PendingAuditRecords {
+            source_addr,
+            old_versions,
+        }
+    }
+
+    /// Returns the records of the key-values that changed, i.e. whose versions in
+    /// `cluster_state` differ from the versions before the delta was applied.
+    pub fn into_records(
+        self,
+        cluster_state: &ClusterState,
+    ) -> impl Iterator<Item = AuditRecord> + '_ {
+        let source_addr = self.source_addr;
+        self.old_versions
+            .into_iter()
+            .filter_map(move |(node_id, key, old_version)| {
+                let versioned_value = cluster_state.node_state(&node_id)?.key_values.get(&*key)?;
+                if Some(versioned_value.version) == old_version {
+                    return None;
+                }
+                Some(AuditRecord {
+                    source_addr,
+                    new_version: versioned_value.version,
+                    marked_for_deletion: versioned_value.marked_for_deletion,
+                    node_id,
+                    key: key.to_string(),
+                    old_version,
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_audit_records() {
+        let node1 = NodeId::for_test_localhost(10_001);
+        let source_addr: SocketAddr = "127.0.0.1:10002".parse().unwrap();
+        let mut cluster_state = ClusterState::default();
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "1", 1, false);
+        cluster_state.apply_delta(delta);
+
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "2", 2, false);
+        delta.add_node_delta(node1.clone(), "key_b", "", 3, true);
+        let pending_audit_records =
+            PendingAuditRecords::new(Some(source_addr), &delta, &cluster_state);
+        cluster_state.apply_delta(delta);
+        let audit_records: Vec<AuditRecord> =
+            pending_audit_records.into_records(&cluster_state).collect();
+        assert_eq!(
+            audit_records,
+            [
+                AuditRecord {
+                    source_addr: Some(source_addr),
+                    node_id: node1.clone(),
+                    key: "key_a".to_string(),
+                    old_version: Some(1),
+                    new_version: 2,
+                    marked_for_deletion: false,
+                },
+                AuditRecord {
+                    source_addr: Some(source_addr),
+                    node_id: node1.clone(),
+                    key: "key_b".to_string(),
+                    old_version: None,
+                    new_version: 3,
+                    marked_for_deletion: true,
+                },
+            ]
+        );
+
+        // Obsolete key-values do not change the state, and are not recorded.
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "0", 1, false);
+        let pending_audit_records =
+            PendingAuditRecords::new(Some(source_addr), &delta, &cluster_state);
+        cluster_state.apply_delta(delta);
+        assert_eq!(
+            pending_audit_records.into_records(&cluster_state).count(),
+            0
+        );
+    }
+}
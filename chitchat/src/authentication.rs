@@ -0,0 +1,401 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "encryption")]
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+#[cfg(feature = "encryption")]
+use aes_gcm::{Aes256Gcm, Nonce};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+
+use crate::message::PROTOCOL_HEADER_LEN;
+use crate::serialize::{ParseLimits, Serializable};
+use crate::ChitchatMessage;
+
+/// Length of the tags authenticating the messages.
+pub(crate) const TAG_LEN: usize = 32;
+
+/// Length of the nonces of the encrypted messages.
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+/// Length of the header of the sealed payloads.
+const SEAL_HEADER_LEN: usize = 16;
+
+/// Max number of bytes an authenticated or encrypted message takes on top of the message it
+/// carries: the protocol header, the message type, the tag, the length of the payload and the
+/// header of the payload. Encryption takes a nonce and a shorter tag.
+pub(crate) const AUTHENTICATION_OVERHEAD: usize =
+    PROTOCOL_HEADER_LEN + 1 + TAG_LEN + 4 + SEAL_HEADER_LEN;
+
+/// Context of the derivation of the encryption key from the cluster key.
+#[cfg(feature = "encryption")]
+const ENCRYPTION_KEY_CONTEXT: &[u8] = b"chitchat-encryption-key-v1";
+
+/// A key shared by the nodes of a cluster, authenticating their messages with HMAC-SHA256.
+///
+/// Messages that fail authentication are dropped. Authentication does not hide the messages,
+/// unless [`ClusterKey::with_encryption`] is set. Replays are detected as long as
+/// [`crate::ChitchatConfig::replay_window`] is set.
+#[derive(Clone)]
+pub struct ClusterKey {
+    mac: Hmac<Sha256>,
+    #[cfg(feature = "encryption")]
+    cipher: Aes256Gcm,
+    #[cfg(feature = "encryption")]
+    encrypts: bool,
+}
+
+impl ClusterKey {
+    pub fn from_bytes(key: impl AsRef<[u8]>) -> Self {
+        let mac = <Hmac<Sha256> as Mac>::new_from_slice(key.as_ref())
+            .expect("HMAC should accept keys of any size");
+        #[cfg(feature = "encryption")]
+        let cipher = {
+            let mut encryption_key_mac = mac.clone();
+            encryption_key_mac.update(ENCRYPTION_KEY_CONTEXT);
+            let encryption_key: [u8; 32] = encryption_key_mac.finalize().into_bytes().into();
+            Aes256Gcm::new(&encryption_key.into())
+        };
+        ClusterKey {
+            mac,
+            #[cfg(feature = "encryption")]
+            cipher,
+            #[cfg(feature = "encryption")]
+            encrypts: false,
+        }
+    }
+
+    /// Encrypts the messages sent, deltas and digests included, with AES-256-GCM under a key
+    /// derived from the cluster key, instead of only authenticating them.
+    ///
+    /// Encrypted messages are accepted by the nodes holding the key whether or not they
+    /// encrypt themselves, so that encryption can be turned on without dropping messages.
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption(mut self) -> Self {
+        self.encrypts = true;
+        self
+    }
+
+    /// Computes the tag of `payload`.
+    fn sign(&self, payload: &[u8]) -> [u8; TAG_LEN] {
+        let mut mac = self.mac.clone();
+        mac.update(payload);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Checks the tag of `payload`, in constant time.
+    fn verify(&self, payload: &[u8], tag: &[u8]) -> bool {
+        let mut mac = self.mac.clone();
+        mac.update(payload);
+        mac.verify_slice(tag).is_ok()
+    }
+
+    /// Wraps the serialized `payload` into an authenticated, or encrypted, message, under a
+    /// fresh [`SealHeader`].
+    pub(crate) fn seal(&self, payload: &[u8]) -> ChitchatMessage {
+        let header = SealHeader {
+            sealed_at_millis: unix_millis(SystemTime::now()),
+            nonce: rand::thread_rng().gen(),
+        };
+        let mut sealed_payload = Vec::with_capacity(SEAL_HEADER_LEN + payload.len());
+        sealed_payload.extend_from_slice(&header.sealed_at_millis.to_le_bytes());
+        sealed_payload.extend_from_slice(&header.nonce.to_le_bytes());
+        sealed_payload.extend_from_slice(payload);
+        self.seal_bytes(sealed_payload)
+    }
+
+    fn seal_bytes(&self, payload: Vec<u8>) -> ChitchatMessage {
+        #[cfg(feature = "encryption")]
+        if self.encrypts {
+            // Random nonces do not repeat in practice under a given key: their 96 bits allow for
+            // billions of messages.
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let ciphertext = self
+                .cipher
+                .encrypt(&nonce, payload.as_slice())
+                .expect("encryption should not fail");
+            return ChitchatMessage::Encrypted {
+                nonce: nonce.into(),
+                ciphertext,
+            };
+        }
+        ChitchatMessage::Authenticated {
+            tag: self.sign(&payload),
+            payload,
+        }
+    }
+
+    /// Wraps `message` into an authenticated, or encrypted, message.
+    pub(crate) fn seal_message(&self, message: &ChitchatMessage) -> ChitchatMessage {
+        let mut payload = Vec::with_capacity(message.serialized_len());
+        message.serialize(&mut payload);
+        self.seal(&payload)
+    }
+
+    /// Decrypts `ciphertext`, if it was encrypted under this key.
+    #[cfg(feature = "encryption")]
+    fn decrypt(&self, nonce: &[u8; NONCE_LEN], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()
+    }
+}
+
+/// Identifies a sealed payload, so that replays can be detected. See [`ReplayGuard`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct SealHeader {
+    /// Time at which the payload was sealed, in milliseconds since the Unix epoch.
+    pub sealed_at_millis: u64,
+    pub nonce: u64,
+}
+
+/// Returns the header and the serialized payload of `message`, if `message` is an authenticated
+/// or encrypted message sealed with one of `cluster_keys`.
+pub(crate) fn open_payload<'a>(
+    cluster_keys: impl IntoIterator<Item = &'a ClusterKey>,
+    message: ChitchatMessage,
+) -> Option<(SealHeader, Vec<u8>)> {
+    let mut sealed_payload = open_bytes(cluster_keys, message)?;
+    if sealed_payload.len() < SEAL_HEADER_LEN {
+        return None;
+    }
+    let payload = sealed_payload.split_off(SEAL_HEADER_LEN);
+    let header = SealHeader {
+        sealed_at_millis: u64::from_le_bytes(sealed_payload[..8].try_into().ok()?),
+        nonce: u64::from_le_bytes(sealed_payload[8..].try_into().ok()?),
+    };
+    Some((header, payload))
+}
+
+fn open_bytes<'a>(
+    cluster_keys: impl IntoIterator<Item = &'a ClusterKey>,
+    message: ChitchatMessage,
+) -> Option<Vec<u8>> {
+    let mut cluster_keys = cluster_keys.into_iter();
+    match message {
+        ChitchatMessage::Authenticated { payload, tag } => cluster_keys
+            .any(|cluster_key| cluster_key.verify(&payload, &tag))
+            .then_some(payload),
+        #[cfg(feature = "encryption")]
+        ChitchatMessage::Encrypted { nonce, ciphertext } => {
+            cluster_keys.find_map(|cluster_key| cluster_key.decrypt(&nonce, &ciphertext))
+        }
+        _ => None,
+    }
+}
+
+/// Deserializes the payload of a sealed message.
+pub(crate) fn deserialize_opened_message(
+    payload: &[u8],
+    parse_limits: &ParseLimits,
+) -> Option<ChitchatMessage> {
+    let message = ChitchatMessage::deserialize_with_limits(&mut &payload[..], parse_limits).ok()?;
+    // Sealed messages are never nested.
+    if matches!(
+        message,
+        ChitchatMessage::Authenticated { .. } | ChitchatMessage::Encrypted { .. }
+    ) {
+        return None;
+    }
+    Some(message)
+}
+
+/// Returns the message wrapped into `message`, if `message` is an authenticated or encrypted
+/// message sealed with one of `cluster_keys`, regardless of replays.
+#[cfg(test)]
+pub(crate) fn open<'a>(
+    cluster_keys: impl IntoIterator<Item = &'a ClusterKey>,
+    message: ChitchatMessage,
+) -> Option<ChitchatMessage> {
+    let (_header, payload) = open_payload(cluster_keys, message)?;
+    deserialize_opened_message(&payload, &ParseLimits::default())
+}
+
+/// Rejects the sealed payloads sealed more than a window away from now, or already seen, so
+/// that a captured message cannot be replayed later, e.g. to resurrect a deleted key.
+#[derive(Debug, Default)]
+pub(crate) struct ReplayGuard {
+    seen_nonces: HashSet<u64>,
+    /// Seen nonces by the time at which they can be forgotten, once their payload falls out of
+    /// the window.
+    nonce_expirations: BTreeSet<(u64, u64)>,
+}
+
+impl ReplayGuard {
+    /// Returns true if the payload was sealed within `window` of `now`, and not seen before.
+    pub fn admit(&mut self, header: SealHeader, window: Duration, now: SystemTime) -> bool {
+        let now_millis = unix_millis(now);
+        let window_millis = window.as_millis() as u64;
+        while let Some(&(expires_at_millis, nonce)) = self.nonce_expirations.first() {
+            if expires_at_millis >= now_millis {
+                break;
+            }
+            self.nonce_expirations.pop_first();
+            self.seen_nonces.remove(&nonce);
+        }
+        // Clocks of different nodes drift apart: payloads sealed slightly in the future are
+        // accepted too.
+        let expires_at_millis = header.sealed_at_millis.saturating_add(window_millis);
+        if expires_at_millis < now_millis
+            || header.sealed_at_millis > now_millis.saturating_add(window_millis)
+        {
+            return false;
+        }
+        if !self.seen_nonces.insert(header.nonce) {
+            return false;
+        }
+        self.nonce_expirations
+            .insert((expires_at_millis, header.nonce));
+        true
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl fmt::Debug for ClusterKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ClusterKey(<redacted>)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cluster_key() {
+        let cluster_key = ClusterKey::from_bytes(b"cluster-key");
+        let message = ChitchatMessage::BadCluster;
+        let authenticated_message = cluster_key.seal_message(&message);
+        assert_eq!(
+            authenticated_message.serialized_len(),
+            message.serialized_len() + AUTHENTICATION_OVERHEAD
+        );
+        assert_eq!(
+            open([&cluster_key], cluster_key.seal_message(&message)),
+            Some(ChitchatMessage::BadCluster)
+        );
+        // Unauthenticated messages and messages signed with another key are rejected.
+        assert_eq!(open([&cluster_key], ChitchatMessage::BadCluster), None);
+        let other_cluster_key = ClusterKey::from_bytes(b"other-cluster-key");
+        assert_eq!(
+            open([&other_cluster_key], cluster_key.seal_message(&message)),
+            None
+        );
+        // Any of the accepted keys authenticates a message.
+        assert_eq!(
+            open(
+                [&other_cluster_key, &cluster_key],
+                cluster_key.seal_message(&message)
+            ),
+            Some(ChitchatMessage::BadCluster)
+        );
+        let ChitchatMessage::Authenticated { payload, mut tag } =
+            cluster_key.seal_message(&message)
+        else {
+            panic!("expected an authenticated message");
+        };
+        tag[0] ^= 1;
+        assert_eq!(
+            open(
+                [&cluster_key],
+                ChitchatMessage::Authenticated { payload, tag }
+            ),
+            None
+        );
+        assert!(format!("{cluster_key:?}").contains("redacted"));
+    }
+
+    #[test]
+    fn test_replay_guard() {
+        let window = Duration::from_secs(60);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut replay_guard = ReplayGuard::default();
+        let header = SealHeader {
+            sealed_at_millis: unix_millis(now),
+            nonce: 1,
+        };
+        assert!(replay_guard.admit(header, window, now));
+        // Replays are rejected, as long as they fall within the window.
+        assert!(!replay_guard.admit(header, window, now + Duration::from_secs(1)));
+        assert!(!replay_guard.admit(header, window, now + Duration::from_secs(61)));
+        assert!(replay_guard.seen_nonces.is_empty());
+
+        // Payloads sealed too long ago, or too far in the future, are rejected.
+        let old_header = SealHeader {
+            sealed_at_millis: unix_millis(now - Duration::from_secs(61)),
+            nonce: 2,
+        };
+        assert!(!replay_guard.admit(old_header, window, now));
+        let future_header = SealHeader {
+            sealed_at_millis: unix_millis(now + Duration::from_secs(61)),
+            nonce: 3,
+        };
+        assert!(!replay_guard.admit(future_header, window, now));
+        let skewed_header = SealHeader {
+            sealed_at_millis: unix_millis(now + Duration::from_secs(30)),
+            nonce: 4,
+        };
+        assert!(replay_guard.admit(skewed_header, window, now));
+    }
+
+    #[test]
+    fn test_seal_header() {
+        let cluster_key = ClusterKey::from_bytes(b"cluster-key");
+        let sealed_message = cluster_key.seal_message(&ChitchatMessage::BadCluster);
+        let other_sealed_message = cluster_key.seal_message(&ChitchatMessage::BadCluster);
+        let (header, payload) = open_payload([&cluster_key], sealed_message).unwrap();
+        let (other_header, _) = open_payload([&cluster_key], other_sealed_message).unwrap();
+        assert_ne!(header.nonce, other_header.nonce);
+        let now_millis = unix_millis(SystemTime::now());
+        assert!(header.sealed_at_millis <= now_millis);
+        assert!(header.sealed_at_millis + 10_000 > now_millis);
+        assert_eq!(
+            deserialize_opened_message(&payload, &ParseLimits::default()),
+            Some(ChitchatMessage::BadCluster)
+        );
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_cluster_key_with_encryption() {
+        let cluster_key = ClusterKey::from_bytes(b"cluster-key");
+        let encrypting_cluster_key = cluster_key.clone().with_encryption();
+        let message = ChitchatMessage::Ack {
+            cluster_id: "secret-cluster".to_string(),
+            delta: Default::default(),
+        };
+        let encrypted_message = encrypting_cluster_key.seal_message(&message);
+        assert!(
+            encrypted_message.serialized_len()
+                <= message.serialized_len() + AUTHENTICATION_OVERHEAD
+        );
+        let ChitchatMessage::Encrypted { nonce, ciphertext } = &encrypted_message else {
+            panic!("expected an encrypted message");
+        };
+        assert!(!ciphertext
+            .windows(b"secret-cluster".len())
+            .any(|window| window == b"secret-cluster"));
+        // Nonces are not reused.
+        let ChitchatMessage::Encrypted {
+            nonce: other_nonce, ..
+        } = encrypting_cluster_key.seal_message(&message)
+        else {
+            panic!("expected an encrypted message");
+        };
+        assert_ne!(nonce, &other_nonce);
+
+        // Nodes that do not encrypt themselves accept encrypted messages.
+        assert_eq!(open([&cluster_key], encrypted_message), Some(message));
+        let other_cluster_key = ClusterKey::from_bytes(b"other-cluster-key");
+        let encrypted_message = encrypting_cluster_key.seal_message(&ChitchatMessage::BadCluster);
+        assert_eq!(open([&other_cluster_key], encrypted_message), None);
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::key_change_rates::{KeyChangeRate, KeyChangeRates};
+use crate::state::ClusterState;
+use crate::{NodeId, Version, WriteSource, HEARTBEAT_KEY};
+
+/// Maximum number of changes retained by the journal.
+const CHANGE_JOURNAL_CAPACITY: usize = 10_000;
+
+/// A change applied to the cluster state, as recorded by the change journal.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Sequence number of the change, strictly increasing from one change to the next.
+    pub seq: u64,
+    pub node_id: NodeId,
+    pub key: String,
+    pub version: Version,
+    /// Source of the write, for the changes made to the self node state. `None` for the changes
+    /// received through gossip.
+    pub source: Option<WriteSource>,
+}
+
+/// Ring buffer of the latest changes applied to the cluster state.
+///
+/// Changes are recorded by comparing the versions of the key-values to the highest version
+/// journaled so far for each node, so local writes and writes received through gossip are
+/// recorded alike. A key updated several times between two recordings is journaled once, with
+/// its latest version. Heartbeats are not journaled.
+///
+/// The change rate of each key is tracked along the way.
+#[derive(Debug)]
+pub(crate) struct ChangeJournal {
+    capacity: usize,
+    entries: VecDeque<JournalEntry>,
+    last_seq: u64,
+    journaled_max_versions: HashMap<NodeId, Version>,
+    key_change_rates: KeyChangeRates,
+}
+
+impl Default for ChangeJournal {
+    fn default() -> Self {
+        ChangeJournal::with_capacity(CHANGE_JOURNAL_CAPACITY)
+    }
+}
+
+impl ChangeJournal {
+    fn with_capacity(capacity: usize) -> Self {
+        ChangeJournal {
+            capacity,
+            entries: VecDeque::new(),
+            last_seq: 0,
+            journaled_max_versions: HashMap::new(),
+            key_change_rates: KeyChangeRates::default(),
+        }
+    }
+
+    /// Journals the key-values of `cluster_state` that changed since the last call.
+    pub fn record_changes(&mut self, cluster_state: &ClusterState) {
+        let now = Instant::now();
+        self.journaled_max_versions
+            .retain(|node_id, _| cluster_state.node_states.contains_key(node_id));
+        for (node_id, node_state) in &cluster_state.node_states {
+            let journaled_max_version = self
+                .journaled_max_versions
+                .entry(node_id.clone())
+                .or_default();
+            if node_state.max_version <= *journaled_max_version {
+                continue;
+            }
+            let changes = node_state
+                .key_values
+                .iter_newer_than(*journaled_max_version)
+                .filter(|(key, _)| &***key != HEARTBEAT_KEY);
+            *journaled_max_version = node_state.max_version;
+
+            for (key, versioned_value) in changes {
+                self.key_change_rates
+                    .record(key, versioned_value.value.len(), now);
+                self.last_seq += 1;
+                if self.entries.len() == self.capacity {
+                    self.entries.pop_front();
+                }
+                self.entries.push_back(JournalEntry {
+                    seq: self.last_seq,
+                    node_id: node_id.clone(),
+                    key: key.to_string(),
+                    version: versioned_value.version,
+                    source: node_state.write_source(key),
+                });
+            }
+        }
+    }
+
+    /// Returns the sequence number of the last journaled change.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Returns the changes journaled after the change with sequence number `seq`, or `None` if
+    /// some of them were already evicted from the journal.
+    pub fn changes_since(&self, seq: u64) -> Option<Vec<JournalEntry>> {
+        let first_retained_seq = self.last_seq + 1 - self.entries.len() as u64;
+        if seq + 1 < first_retained_seq {
+            return None;
+        }
+        let num_skipped_entries = (seq + 1 - first_retained_seq) as usize;
+        let changes = self
+            .entries
+            .iter()
+            .skip(num_skipped_entries)
+            .cloned()
+            .collect();
+        Some(changes)
+    }
+
+    /// Returns the `k` keys changing at the highest rates, in bytes per second.
+    pub fn top_talkers(&mut self, k: usize) -> Vec<KeyChangeRate> {
+        self.key_change_rates.top_k(k, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_change_journal() {
+        let mut change_journal = ChangeJournal::with_capacity(3);
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        change_journal.record_changes(&cluster_state);
+        assert_eq!(change_journal.changes_since(0), Some(Vec::new()));
+
+        let node1_state = cluster_state.node_state_mut(&node1);
+        node1_state.set_with_source("key_a", "1", WriteSource::Operator);
+        node1_state.set(HEARTBEAT_KEY, "1");
+        node1_state.set("key_b", "2");
+        cluster_state.node_state_mut(&node2);
+        change_journal.record_changes(&cluster_state);
+
+        let changes = change_journal.changes_since(0).unwrap();
+        assert_eq!(
+            changes,
+            [
+                JournalEntry {
+                    seq: 1,
+                    node_id: node1.clone(),
+                    key: "key_a".to_string(),
+                    version: 1,
+                    source: Some(WriteSource::Operator),
+                },
+                JournalEntry {
+                    seq: 2,
+                    node_id: node1.clone(),
+                    key: "key_b".to_string(),
+                    version: 3,
+                    source: Some(WriteSource::Application),
+                },
+            ]
+        );
+        // Nothing changed.
+        change_journal.record_changes(&cluster_state);
+        assert_eq!(change_journal.changes_since(2), Some(Vec::new()));
+
+        let node1_state = cluster_state.node_state_mut(&node1);
+        node1_state.set("key_a", "1-bis");
+        node1_state.set("key_a", "1-ter");
+        node1_state.mark_for_deletion("key_b");
+        change_journal.record_changes(&cluster_state);
+        let changes = change_journal.changes_since(2).unwrap();
+        let keys_versions: Vec<(u64, &str, Version)> = changes
+            .iter()
+            .map(|entry| (entry.seq, entry.key.as_str(), entry.version))
+            .collect();
+        assert_eq!(keys_versions, [(3, "key_a", 5), (4, "key_b", 6)]);
+
+        // The first change was evicted.
+        assert!(change_journal.changes_since(0).is_none());
+        assert_eq!(change_journal.changes_since(1).unwrap().len(), 3);
+        assert_eq!(change_journal.changes_since(4), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,291 @@
+#[cfg(feature = "encryption")]
+use std::fmt;
+#[cfg(feature = "json")]
+use std::io::{self, Write};
+#[cfg(feature = "json")]
+use std::path::Path;
+
+#[cfg(feature = "encryption")]
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+#[cfg(feature = "encryption")]
+use aes_gcm::{Aes256Gcm, Nonce};
+use serde::{Deserialize, Serialize};
+
+#[cfg(all(feature = "json", feature = "server"))]
+use crate::configuration::PersistenceConfig;
+use crate::{NodeId, NodeState, PeerCache};
+
+/// Prefix of the encrypted checkpoints, followed by the nonce and the ciphertext.
+#[cfg(feature = "json")]
+const ENCRYPTED_CHECKPOINT_MAGIC: &[u8] = b"chitchat-encrypted-checkpoint-v1\n";
+
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+/// Persisted state of a node, restored upon restart so that the node re-advertises its
+/// key-values immediately instead of starting empty.
+///
+/// See [`crate::Chitchat::checkpoint`] and [`crate::Chitchat::restore_checkpoint`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub cluster_id: String,
+    pub node_id: NodeId,
+    pub self_node_state: NodeState,
+    /// Heartbeat of the self node, which is not part of its serialized state.
+    #[serde(default)]
+    pub heartbeat: u64,
+    /// Time at which the checkpoint was taken, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub checkpointed_at_millis: u64,
+    /// States of the other nodes, if the cluster view was checkpointed.
+    #[serde(default)]
+    pub node_states: Vec<(NodeId, NodeState)>,
+    /// Live peers of the node, used as additional seeds upon restart.
+    #[serde(default)]
+    pub peer_cache: Option<PeerCache>,
+}
+
+#[cfg(feature = "json")]
+impl Checkpoint {
+    /// Writes the checkpoint to `path`, atomically: a crash leaves the previous checkpoint
+    /// untouched.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let checkpoint_json = serde_json::to_vec(self)?;
+        write_atomically(path, &checkpoint_json)
+    }
+
+    /// Reads the checkpoint at `path`. Returns `Ok(None)` if there is no checkpoint.
+    pub fn load(path: &Path) -> io::Result<Option<Checkpoint>> {
+        let Some(checkpoint_json) = read_if_exists(path)? else {
+            return Ok(None);
+        };
+        if checkpoint_json.starts_with(ENCRYPTED_CHECKPOINT_MAGIC) {
+            return Err(invalid_data("checkpoint is encrypted"));
+        }
+        let checkpoint = serde_json::from_slice(&checkpoint_json)?;
+        Ok(Some(checkpoint))
+    }
+
+    /// Same as [`Checkpoint::save`], encrypting the checkpoint with `key`.
+    #[cfg(feature = "encryption")]
+    pub fn save_encrypted(&self, path: &Path, key: &EncryptionKey) -> io::Result<()> {
+        let checkpoint_json = serde_json::to_vec(self)?;
+        let cipher = Aes256Gcm::new(&key.0.into());
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, checkpoint_json.as_slice())
+            .map_err(|_| io::Error::other("failed to encrypt checkpoint"))?;
+        let mut encrypted_checkpoint =
+            Vec::with_capacity(ENCRYPTED_CHECKPOINT_MAGIC.len() + NONCE_LEN + ciphertext.len());
+        encrypted_checkpoint.extend_from_slice(ENCRYPTED_CHECKPOINT_MAGIC);
+        encrypted_checkpoint.extend_from_slice(&nonce);
+        encrypted_checkpoint.extend_from_slice(&ciphertext);
+        write_atomically(path, &encrypted_checkpoint)
+    }
+
+    /// Same as [`Checkpoint::load`], decrypting the checkpoint with `key`.
+    ///
+    /// Fails if the checkpoint is not encrypted, was encrypted with another key, or was
+    /// tampered with.
+    #[cfg(feature = "encryption")]
+    pub fn load_encrypted(path: &Path, key: &EncryptionKey) -> io::Result<Option<Checkpoint>> {
+        let Some(encrypted_checkpoint) = read_if_exists(path)? else {
+            return Ok(None);
+        };
+        let Some(nonce_and_ciphertext) =
+            encrypted_checkpoint.strip_prefix(ENCRYPTED_CHECKPOINT_MAGIC)
+        else {
+            return Err(invalid_data("checkpoint is not encrypted"));
+        };
+        if nonce_and_ciphertext.len() < NONCE_LEN {
+            return Err(invalid_data("encrypted checkpoint is truncated"));
+        }
+        let (nonce, ciphertext) = nonce_and_ciphertext.split_at(NONCE_LEN);
+        let cipher = Aes256Gcm::new(&key.0.into());
+        let checkpoint_json = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                invalid_data("failed to decrypt checkpoint: wrong key or corrupted data")
+            })?;
+        let checkpoint = serde_json::from_slice(&checkpoint_json)?;
+        Ok(Some(checkpoint))
+    }
+
+    /// Saves the checkpoint as configured.
+    #[cfg(feature = "server")]
+    pub(crate) fn save_with_config(
+        &self,
+        persistence_config: &PersistenceConfig,
+    ) -> io::Result<()> {
+        #[cfg(feature = "encryption")]
+        if let Some(key) = &persistence_config.encryption_key {
+            return self.save_encrypted(&persistence_config.path, key);
+        }
+        self.save(&persistence_config.path)
+    }
+
+    /// Loads the checkpoint as configured.
+    #[cfg(feature = "server")]
+    pub(crate) fn load_with_config(
+        persistence_config: &PersistenceConfig,
+    ) -> io::Result<Option<Checkpoint>> {
+        #[cfg(feature = "encryption")]
+        if let Some(key) = &persistence_config.encryption_key {
+            return Checkpoint::load_encrypted(&persistence_config.path, key);
+        }
+        Checkpoint::load(&persistence_config.path)
+    }
+}
+
+/// 256-bit key encrypting the checkpoints with AES-GCM.
+///
+/// Chitchat does not manage keys: applications are expected to fetch the key from their secret
+/// store, e.g. an OS keyring, and pass it in the [`crate::PersistenceConfig`].
+#[cfg(feature = "encryption")]
+#[derive(Clone, Eq, PartialEq)]
+pub struct EncryptionKey(pub(crate) [u8; 32]);
+
+#[cfg(feature = "encryption")]
+impl EncryptionKey {
+    pub fn from_bytes(key: [u8; 32]) -> Self {
+        EncryptionKey(key)
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("EncryptionKey(<redacted>)")
+    }
+}
+
+/// Writes `content` to a temporary file renamed to `path`, so that a crash leaves either the
+/// previous or the new content. Both the file and its directory are synced, so that the new
+/// content survives a power loss once the function returns.
+#[cfg(feature = "json")]
+fn write_atomically(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut tmp_file = std::fs::File::create(&tmp_path)?;
+    tmp_file.write_all(content)?;
+    tmp_file.sync_all()?;
+    drop(tmp_file);
+    std::fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)
+}
+
+/// Syncs the directory of `path`, which persists the renames into it. Directories cannot be
+/// opened, nor synced, on Windows, where renames are persisted by the file system itself.
+#[cfg(feature = "json")]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    if cfg!(windows) {
+        return Ok(());
+    }
+    let parent_dir = match path.parent() {
+        Some(parent_dir) if !parent_dir.as_os_str().is_empty() => parent_dir,
+        _ => Path::new("."),
+    };
+    std::fs::File::open(parent_dir)?.sync_all()
+}
+
+#[cfg(feature = "json")]
+fn read_if_exists(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match std::fs::read(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(feature = "json")]
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_save_load() {
+        let dir = std::env::temp_dir().join(format!("chitchat-checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("checkpoint.json");
+        assert!(Checkpoint::load(&path).unwrap().is_none());
+
+        let mut self_node_state = NodeState::default();
+        self_node_state.set("key_a", "1");
+        self_node_state.set("key_b", "2");
+        self_node_state.mark_for_deletion("key_b");
+        let checkpoint = Checkpoint {
+            cluster_id: "cluster".to_string(),
+            node_id: NodeId::for_test_localhost(10_001),
+            self_node_state,
+            heartbeat: 0,
+            checkpointed_at_millis: 0,
+            node_states: Vec::new(),
+            peer_cache: None,
+        };
+        checkpoint.save(&path).unwrap();
+        let loaded_checkpoint = Checkpoint::load(&path).unwrap().unwrap();
+        assert_eq!(loaded_checkpoint.node_id, checkpoint.node_id);
+        assert_eq!(
+            loaded_checkpoint.self_node_state.key_values,
+            checkpoint.self_node_state.key_values
+        );
+        assert_eq!(loaded_checkpoint.self_node_state.max_version, 3);
+
+        std::fs::write(&path, "not-json").unwrap();
+        assert!(Checkpoint::load(&path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_checkpoint_save_load_encrypted() {
+        let dir = std::env::temp_dir().join(format!(
+            "chitchat-encrypted-checkpoint-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("checkpoint.json");
+        let key = EncryptionKey::from_bytes([7; 32]);
+        assert!(Checkpoint::load_encrypted(&path, &key).unwrap().is_none());
+
+        let mut self_node_state = NodeState::default();
+        self_node_state.set("token", "secret-token");
+        let checkpoint = Checkpoint {
+            cluster_id: "cluster".to_string(),
+            node_id: NodeId::for_test_localhost(10_001),
+            self_node_state,
+            heartbeat: 0,
+            checkpointed_at_millis: 0,
+            node_states: Vec::new(),
+            peer_cache: None,
+        };
+        checkpoint.save_encrypted(&path, &key).unwrap();
+        let encrypted_checkpoint = std::fs::read(&path).unwrap();
+        assert!(!encrypted_checkpoint
+            .windows(b"secret-token".len())
+            .any(|window| window == b"secret-token"));
+        let loaded_checkpoint = Checkpoint::load_encrypted(&path, &key).unwrap().unwrap();
+        assert_eq!(
+            loaded_checkpoint.self_node_state.get("token"),
+            Some("secret-token")
+        );
+        assert!(Checkpoint::load(&path).is_err());
+        let other_key = EncryptionKey::from_bytes([8; 32]);
+        assert!(Checkpoint::load_encrypted(&path, &other_key).is_err());
+
+        // Tampering is detected.
+        let mut tampered_checkpoint = encrypted_checkpoint;
+        *tampered_checkpoint.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, tampered_checkpoint).unwrap();
+        assert!(Checkpoint::load_encrypted(&path, &key).is_err());
+
+        // Plaintext checkpoints are rejected.
+        checkpoint.save(&path).unwrap();
+        assert!(Checkpoint::load_encrypted(&path, &key).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,27 +1,83 @@
 #![allow(clippy::derive_partial_eq_without_eq)]
 
 use std::net::SocketAddr;
+#[cfg(feature = "json")]
+use std::path::PathBuf;
 use std::time::Duration;
 
+use tokio::sync::mpsc;
+
+#[cfg(feature = "server")]
+use crate::seed_provider::SeedProvider;
 use crate::state::NodeState;
-use crate::{FailureDetectorConfig, NodeId};
+#[cfg(feature = "server")]
+use crate::ClusterKey;
+#[cfg(feature = "encryption")]
+use crate::SealedKeys;
+use crate::{
+    AuditRecord, ChitchatEvents, DeltaInterceptor, FailureDetector, FailureDetectorConfig,
+    MetricsRecorder, NodeId, ParseLimits, WriteAcl, MAX_UDP_DATAGRAM_PAYLOAD_SIZE,
+};
 
 /// A struct for configuring a Chitact instance.
 pub struct ChitchatConfig {
     pub node_id: NodeId,
     pub cluster_id: String,
+    // If set, the messages are authenticated with `cluster_key`, and the unauthenticated ones
+    // are dropped. All the nodes of the cluster must share the key.
+    #[cfg(feature = "server")]
+    pub cluster_key: Option<ClusterKey>,
+    // Keys accepted to authenticate the messages received, on top of `cluster_key`, e.g. the
+    // previous and next keys while a rotation rolls out across the cluster.
+    #[cfg(feature = "server")]
+    pub accepted_cluster_keys: Vec<ClusterKey>,
+    // If set, authenticated messages sealed more than `replay_window` away from now, or
+    // already received, are dropped, so that a captured message cannot be replayed. The clocks
+    // of the nodes must be synchronized within the window.
+    #[cfg(feature = "server")]
+    pub replay_window: Option<Duration>,
+    // If set, the messages received from a source address beyond its rate limit are dropped
+    // before being processed, so that a single peer cannot monopolize the gossip server. The
+    // UDP transport checks it on the raw datagrams, before deserializing them. Each full sync
+    // frame counts as a message: full syncs of states larger than `max_bytes_per_sec` fail.
+    // UDP source addresses can be spoofed: an attacker can exhaust the budget of a peer and get
+    // its datagrams dropped.
+    #[cfg(feature = "server")]
+    pub receive_rate_limit: Option<ReceiveRateLimit>,
+    // Bounds enforced while parsing the datagrams received from peers, before allocating their
+    // nodes and key-values. Defaults to the bounds implied by the size of a UDP datagram:
+    // tighter bounds make malformed datagrams cheaper to reject.
+    pub parse_limits: Option<ParseLimits>,
     pub gossip_interval: Duration,
+    // Fraction of the gossip interval by which each interval is randomly lengthened or
+    // shortened, e.g. `0.1` for ±10%, so that nodes started together do not gossip in lockstep.
+    // Capped at `0.5`.
+    pub gossip_interval_jitter: f64,
+    // If set, the gossip interval shrinks while the cluster churns, and grows back while it is
+    // quiescent. The interval starts at `gossip_interval`.
+    pub adaptive_gossip: Option<AdaptiveGossipConfig>,
     pub listen_addr: SocketAddr,
+    // Socket addresses, or `hostname:port` resolved again every `seed_refresh_interval`, so
+    // that seeds behind a DNS round-robin or a headless service keep working as their IPs
+    // rotate.
     pub seed_nodes: Vec<String>,
+    // If set, provides seeds in addition to `seed_nodes`, e.g. from a service registry.
+    #[cfg(feature = "server")]
+    pub seed_provider: Option<Box<dyn SeedProvider>>,
+    // Interval at which the seeds are refreshed.
+    pub seed_refresh_interval: Duration,
+    // Delays the gossip with the seeds that do not reply.
+    pub seed_backoff: SeedBackoffConfig,
     pub failure_detector_config: FailureDetectorConfig,
     // `is_ready_predicate` makes it possible for a node to advertise itself as not "ready".
     // For instance, if it is `starting` or if it lost connection to a third-party service.
     //
     // If `None`, a node is ready as long as it is alive.
     pub is_ready_predicate: Option<Box<dyn Fn(&NodeState) -> bool + Send>>,
-    // Marked for deletion grace period expressed as a number of version threshold.
+    // Marked for deletion grace period expressed as a number of version threshold. Only used
+    // with the `TombstoneGcPolicy::VersionCount` policy.
     // Chitchat ensures a marked for deletion key is eventually deleted by three mecanisms:
-    // - Garbage collection: each heartbeat, marked for deletion keys with `key_version +
+    // - Garbage collection: each gossip round, marked for deletion keys with `key_version +
     //   marked_for_deletion_grace_period < node.max_version` are deleted.
     // - Compute delta: for a given node, if `digest_node_max_version +
     //   marked_for_deletion_grace_period < node_max_version`, the node is flagged "to be reset"
@@ -29,6 +85,103 @@ pub struct ChitchatConfig {
     // - Apply delta: for a node flagged "to be reset", Chitchat will remove the node state and
     //   populate a fresh new node state with the keys and values present in the delta.
     pub marked_for_deletion_grace_period: usize,
+    // Defines how keys marked for deletion are garbage collected.
+    pub tombstone_gc_policy: TombstoneGcPolicy,
+    // Marked for deletion grace period expressed as a duration. Only used with the
+    // `TombstoneGcPolicy::WallClock` policy.
+    pub tombstone_grace_period: Duration,
+    // Defines what happens to a key-value that is too large to fit in a delta, even on its own.
+    pub oversized_key_value_policy: OversizedKeyValuePolicy,
+    // Nodes advertised by peers for which no data is received within this period are removed
+    // from the cluster state, and stop being advertised in our digest.
+    pub unknown_node_grace_period: Duration,
+    // If set, node states that underwent at least this many key-value writes since their last
+    // compaction get their key-values rebuilt, to release the memory fragmented by churn.
+    // Compaction runs on the gossip loop.
+    pub compaction_churn_threshold: Option<usize>,
+    // Defines what happens to writes issued through a `ChitchatWriter` once the server
+    // started shutting down.
+    pub write_after_shutdown_policy: WriteAfterShutdownPolicy,
+    // Caps the number of keys and the size of the key-values of each node state, local and
+    // remote, so that a buggy peer cannot exhaust our memory.
+    pub node_state_limits: NodeStateLimits,
+    // If set, caps the approximate memory used by the key-values of the cluster state,
+    // tombstones included, in bytes. Over the cap, deltas introducing new remote nodes are
+    // refused and, with the `TombstoneGcPolicy::WallClock` policy, tombstones are garbage
+    // collected without waiting for the `tombstone_grace_period`. The nodes already known keep
+    // being updated, within their `node_state_limits`.
+    pub max_cluster_state_bytes: Option<usize>,
+    // If set, the server periodically checkpoints the self node state to disk, and restores it
+    // upon startup.
+    #[cfg(feature = "json")]
+    pub persistence: Option<PersistenceConfig>,
+    // If set, intercepts the key-values received from peers before and after they are applied,
+    // e.g. to normalize or reject malformed values.
+    pub delta_interceptor: Option<Box<dyn DeltaInterceptor>>,
+    // If set, `events` is notified of the deltas applied, the node resets, the nodes marked as
+    // dead and the garbage collections.
+    pub events: Option<Box<dyn ChitchatEvents>>,
+    // If set, the key-values received from peers are dropped unless their node is allowed to
+    // publish them, so that a compromised node cannot overwrite the keys of other subsystems.
+    pub write_acl: Option<WriteAcl>,
+    // If set, a record of every change applied to the cluster state from a peer is sent to
+    // `audit_sink`, e.g. for security teams to trace who changed what. Records are dropped
+    // rather than waited for when the channel is full.
+    pub audit_sink: Option<mpsc::Sender<AuditRecord>>,
+    // If set, a delta raising the max version of a known node by more than `max_version_jump`
+    // per heartbeat of the node elapsed since that max version was reached is dropped, so that
+    // a peer cannot inflate the versions of a node and get its legitimate updates ignored as
+    // stale. The max version known for a node survives the resets of the node, and the first
+    // delta of a node is bounded by the max version advertised for it in the digest of the peer.
+    pub max_version_jump: Option<u64>,
+    // If set, the gossip, the deltas received, the failure detector and the garbage collection
+    // are instrumented through `metrics_recorder`, e.g. a `PrometheusRecorder`.
+    pub metrics_recorder: Option<Box<dyn MetricsRecorder>>,
+    // If true, the application writes of the self node are stamped with the time of the write,
+    // and the nodes recording metrics measure how long the stamped writes of their peers took to
+    // reach them. The measurement is only as accurate as the synchronization of the clocks of
+    // the nodes.
+    pub propagation_latency_tracking: bool,
+    // If set, the values of the sealed keys are encrypted by the self node before being
+    // versioned and gossiped, so that only the nodes holding the keys can read them.
+    #[cfg(feature = "encryption")]
+    pub sealed_keys: Option<SealedKeys>,
+    // If set, peers located in other regions are gossiped with less often than the peers of
+    // our own region, to reduce the traffic over wide area links.
+    pub region_aware_gossip: Option<RegionAwareGossipConfig>,
+    // If set, replaces the phi accrual failure detector configured by `failure_detector_config`.
+    // The `dead_node_grace_period` and `suspicion_timeout` of `failure_detector_config` still
+    // apply.
+    pub failure_detector: Option<Box<dyn FailureDetector>>,
+    // Number of live peers asked, at every gossip round, to probe each suspect node on our
+    // behalf, so that a faulty link between us and the node does not get it declared dead.
+    pub indirect_probe_count: usize,
+    // Defines when the state of a dead node is dropped from the cluster state.
+    pub dead_node_eviction_policy: DeadNodeEvictionPolicy,
+    // If set, the server exchanges its full state with a random live peer at this interval,
+    // over TCP, to heal the rare cases where UDP gossip fails to converge. The server then also
+    // listens on the TCP port of `listen_addr`, serving a few full syncs at once.
+    pub full_sync_interval: Option<Duration>,
+    // If set, the state, live nodes, digest and gossip statistics of the node are served as JSON
+    // over HTTP on `debug_http_listen_addr`, under `/state`, `/live_nodes`, `/digest` and
+    // `/metrics`. The endpoint is not authenticated: it must not be exposed publicly.
+    #[cfg(feature = "http-debug")]
+    pub debug_http_listen_addr: Option<SocketAddr>,
+    // Number of live peers gossiped with at every round.
+    pub gossip_fanout: GossipFanout,
+    // Key holding the zone of each node, e.g. `"zone"`, the single source of the zones used by
+    // `peer_selection`, `topology` and `region_aware_gossip`. The zones are cached, and
+    // refreshed as the cluster state changes.
+    pub zone_key: Option<String>,
+    // If set, biases the choice of the peers gossiped with at every round.
+    pub peer_selection: Option<PeerSelectionConfig>,
+    // If set, the zones of the nodes are taken into account to detect failures.
+    pub topology: Option<TopologyConfig>,
+    // If set, a leader is elected among the live nodes. See `Chitchat::current_leader`.
+    pub leader_election: Option<LeaderElectionConfig>,
+    // If true, the self node gossips with the nodes listed by the digests of its peers before
+    // learning their states, so that a joining node reaches the whole cluster from any node.
+    pub exchange_peer_addrs: bool,
 }
 
 impl ChitchatConfig {
@@ -39,18 +192,88 @@ impl ChitchatConfig {
         Self {
             node_id,
             cluster_id: "default-cluster".to_string(),
+            #[cfg(feature = "server")]
+            cluster_key: None,
+            #[cfg(feature = "server")]
+            accepted_cluster_keys: Vec::new(),
+            #[cfg(feature = "server")]
+            replay_window: Some(Duration::from_secs(60)),
+            #[cfg(feature = "server")]
+            receive_rate_limit: None,
+            parse_limits: None,
             gossip_interval: Duration::from_millis(50),
+            gossip_interval_jitter: 0.0,
+            adaptive_gossip: None,
             listen_addr,
             seed_nodes: Vec::new(),
+            #[cfg(feature = "server")]
+            seed_provider: None,
+            seed_refresh_interval: Duration::from_secs(60),
+            seed_backoff: SeedBackoffConfig::default(),
             failure_detector_config: Default::default(),
             is_ready_predicate: None,
             marked_for_deletion_grace_period: 10_000,
+            tombstone_gc_policy: TombstoneGcPolicy::default(),
+            tombstone_grace_period: Duration::from_secs(3_600),
+            oversized_key_value_policy: OversizedKeyValuePolicy::default(),
+            unknown_node_grace_period: Duration::from_secs(60),
+            compaction_churn_threshold: None,
+            write_after_shutdown_policy: WriteAfterShutdownPolicy::default(),
+            node_state_limits: NodeStateLimits::default(),
+            max_cluster_state_bytes: None,
+            #[cfg(feature = "json")]
+            persistence: None,
+            delta_interceptor: None,
+            events: None,
+            write_acl: None,
+            audit_sink: None,
+            max_version_jump: Some(10_000_000),
+            metrics_recorder: None,
+            propagation_latency_tracking: false,
+            #[cfg(feature = "encryption")]
+            sealed_keys: None,
+            region_aware_gossip: None,
+            failure_detector: None,
+            indirect_probe_count: 3,
+            dead_node_eviction_policy: DeadNodeEvictionPolicy::default(),
+            full_sync_interval: None,
+            #[cfg(feature = "http-debug")]
+            debug_http_listen_addr: None,
+            gossip_fanout: GossipFanout::default(),
+            zone_key: None,
+            peer_selection: None,
+            topology: None,
+            leader_election: None,
+            exchange_peer_addrs: false,
         }
     }
 
+    /// Returns the bounds enforced while parsing the datagrams received from peers.
+    pub(crate) fn datagram_parse_limits(&self) -> ParseLimits {
+        self.parse_limits
+            .unwrap_or_else(|| ParseLimits::for_payload_size(MAX_UDP_DATAGRAM_PAYLOAD_SIZE))
+    }
+
     pub fn set_is_ready_predicate(&mut self, pred: impl Fn(&NodeState) -> bool + Send + 'static) {
         self.is_ready_predicate = Some(Box::new(pred));
     }
+
+    pub fn set_delta_interceptor(&mut self, delta_interceptor: impl DeltaInterceptor + 'static) {
+        self.delta_interceptor = Some(Box::new(delta_interceptor));
+    }
+
+    pub fn set_events(&mut self, events: impl ChitchatEvents + 'static) {
+        self.events = Some(Box::new(events));
+    }
+
+    pub fn set_failure_detector(&mut self, failure_detector: impl FailureDetector + 'static) {
+        self.failure_detector = Some(Box::new(failure_detector));
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_seed_provider(&mut self, seed_provider: impl SeedProvider) {
+        self.seed_provider = Some(Box::new(seed_provider));
+    }
 }
 
 impl Default for ChitchatConfig {
@@ -60,14 +283,395 @@ impl Default for ChitchatConfig {
         Self {
             node_id,
             cluster_id: "default-cluster".to_string(),
+            #[cfg(feature = "server")]
+            cluster_key: None,
+            #[cfg(feature = "server")]
+            accepted_cluster_keys: Vec::new(),
+            #[cfg(feature = "server")]
+            replay_window: Some(Duration::from_secs(60)),
+            #[cfg(feature = "server")]
+            receive_rate_limit: None,
+            parse_limits: None,
             gossip_interval: Duration::from_millis(1_000),
+            gossip_interval_jitter: 0.0,
+            adaptive_gossip: None,
             listen_addr,
             seed_nodes: Vec::new(),
+            #[cfg(feature = "server")]
+            seed_provider: None,
+            seed_refresh_interval: Duration::from_secs(60),
+            seed_backoff: SeedBackoffConfig::default(),
             failure_detector_config: Default::default(),
             is_ready_predicate: None,
             // Each heartbeat increments the version, with one heartbeat each second
             // 43200 ~ 12h.
             marked_for_deletion_grace_period: 43200,
+            tombstone_gc_policy: TombstoneGcPolicy::default(),
+            tombstone_grace_period: Duration::from_secs(12 * 3_600),
+            oversized_key_value_policy: OversizedKeyValuePolicy::default(),
+            unknown_node_grace_period: Duration::from_secs(60),
+            compaction_churn_threshold: None,
+            write_after_shutdown_policy: WriteAfterShutdownPolicy::default(),
+            node_state_limits: NodeStateLimits::default(),
+            max_cluster_state_bytes: None,
+            #[cfg(feature = "json")]
+            persistence: None,
+            delta_interceptor: None,
+            events: None,
+            write_acl: None,
+            audit_sink: None,
+            max_version_jump: Some(10_000_000),
+            metrics_recorder: None,
+            propagation_latency_tracking: false,
+            #[cfg(feature = "encryption")]
+            sealed_keys: None,
+            region_aware_gossip: None,
+            failure_detector: None,
+            indirect_probe_count: 3,
+            dead_node_eviction_policy: DeadNodeEvictionPolicy::default(),
+            full_sync_interval: None,
+            #[cfg(feature = "http-debug")]
+            debug_http_listen_addr: None,
+            gossip_fanout: GossipFanout::default(),
+            zone_key: None,
+            peer_selection: None,
+            topology: None,
+            leader_election: None,
+            exchange_peer_addrs: false,
+        }
+    }
+}
+
+/// Policy applied to key-values that cannot fit in a gossip message, even
+/// if they were the only key-value of the delta.
+///
+/// Such a key-value will never be gossiped: without a policy, it would stall
+/// the propagation of all subsequent updates of its node.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OversizedKeyValuePolicy {
+    /// The key-value is left out of the delta, and the node's other key-values
+    /// are gossiped as usual. Peers never learn about the oversized key-value.
+    #[default]
+    Skip,
+    /// The node's key-values are gossiped up to the oversized key-value, which
+    /// stalls the propagation of this node's more recent updates. Other nodes
+    /// are not affected.
+    Truncate,
+}
+
+/// Defines when keys marked for deletion, a.k.a. tombstones, are garbage collected.
+///
+/// A peer that did not see a tombstone before it got garbage collected is reset.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TombstoneGcPolicy {
+    /// Tombstones are garbage collected once they are older than the `tombstone_grace_period`.
+    ///
+    /// The age of a tombstone is measured from the time the local node learnt about it.
+    #[default]
+    WallClock,
+    /// Tombstones are garbage collected once `marked_for_deletion_grace_period` versions were
+    /// written after them. The grace period then depends on the write rate of each node: the
+    /// tombstones of an idle node are not garbage collected, as heartbeats do not bump versions.
+    VersionCount,
+}
+
+/// Defines when the state of a dead node is dropped from the cluster state.
+///
+/// Until then, the node keeps being gossiped with from time to time, in case it comes back.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeadNodeEvictionPolicy {
+    /// Dead nodes are evicted once they have been dead for the given duration.
+    AfterDuration(Duration),
+    /// Dead nodes are evicted once they have been dead for the given number of
+    /// `dead_node_grace_period` of the failure detector configuration.
+    AfterGracePeriods(u32),
+    /// Dead nodes are never evicted.
+    Never,
+}
+
+impl Default for DeadNodeEvictionPolicy {
+    fn default() -> Self {
+        DeadNodeEvictionPolicy::AfterGracePeriods(1)
+    }
+}
+
+impl DeadNodeEvictionPolicy {
+    /// Returns how long a node stays dead before being evicted, if it ever is.
+    pub(crate) fn eviction_delay(self, dead_node_grace_period: Duration) -> Option<Duration> {
+        match self {
+            DeadNodeEvictionPolicy::AfterDuration(delay) => Some(delay),
+            DeadNodeEvictionPolicy::AfterGracePeriods(num_grace_periods) => {
+                Some(dead_node_grace_period.saturating_mul(num_grace_periods))
+            }
+            DeadNodeEvictionPolicy::Never => None,
+        }
+    }
+}
+
+/// Defines the number of live peers gossiped with at every round.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GossipFanout {
+    /// Gossip with a fixed number of peers.
+    Fixed(usize),
+    /// Gossip with `log2(cluster size)` peers, rounded up, and at least `min` peers. The time
+    /// it takes for an update to reach all the nodes then stays about the same as the cluster
+    /// grows.
+    Logarithmic { min: usize },
+}
+
+impl Default for GossipFanout {
+    fn default() -> Self {
+        GossipFanout::Fixed(3)
+    }
+}
+
+impl GossipFanout {
+    /// Returns the number of peers to gossip with in a cluster of `num_nodes` nodes.
+    pub fn num_peers(self, num_nodes: usize) -> usize {
+        match self {
+            GossipFanout::Fixed(num_peers) => num_peers,
+            GossipFanout::Logarithmic { min } => {
+                let log2_ceil = num_nodes.max(1).next_power_of_two().trailing_zeros() as usize;
+                log2_ceil.max(min)
+            }
+        }
+    }
+}
+
+/// Biases the choice of the peers gossiped with at every round, instead of picking them
+/// uniformly at random. Peers are picked with a probability proportional to the number of
+/// rounds since we last gossiped with them. If the zones of the nodes are known, see
+/// [`ChitchatConfig::zone_key`], at least one of the peers picked at every round belongs to
+/// another zone than ours, if any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerSelectionConfig {
+    /// Probability to also gossip with a random seed node at every round.
+    pub seed_probability: f64,
+}
+
+impl Default for PeerSelectionConfig {
+    fn default() -> Self {
+        PeerSelectionConfig {
+            seed_probability: 0.1,
+        }
+    }
+}
+
+/// Configures the adaptation of the gossip interval to the churn of the cluster, measured as
+/// the number of key-values received from peers during a gossip round.
+///
+/// While the cluster churns, the interval is halved at every round to speed up convergence.
+/// While it is quiescent, the interval grows by a quarter at every round to reduce the idle
+/// traffic. The heartbeat of the node is still bumped at most once per `gossip_interval`, so that
+/// the failure detectors of the peers do not learn the intervals of the churning rounds. The
+/// heartbeats are carried by the gossip messages: `max_interval` must stay well below the time
+/// it takes the failure detector to declare a node dead.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AdaptiveGossipConfig {
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+    /// Number of key-values received during a round above which the cluster is deemed to be
+    /// churning. The cluster is quiescent when no key-value is received.
+    pub churn_threshold: usize,
+}
+
+impl Default for AdaptiveGossipConfig {
+    fn default() -> Self {
+        AdaptiveGossipConfig {
+            min_interval: Duration::from_millis(250),
+            max_interval: Duration::from_secs(2),
+            churn_threshold: 100,
         }
     }
 }
+
+/// Configures the retries of the gossip with the seeds that do not reply, e.g. because they
+/// are not started yet.
+///
+/// The delay between two attempts doubles at every attempt left unanswered, from
+/// `initial_delay` up to `max_delay`, and resets as soon as the seed replies. The default zero
+/// `initial_delay` retries the seeds at every gossip round, which favors a fast convergence
+/// over a quiet network while seeds are down.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeedBackoffConfig {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction by which each delay is randomly lengthened or shortened, so that the nodes
+    /// started together do not retry in lockstep.
+    pub jitter: f64,
+    /// Time after which all the seeds being unreachable is reported. See
+    /// `ChitchatHandle::seeds_unreachable_watcher`.
+    pub unreachable_threshold: Duration,
+}
+
+impl Default for SeedBackoffConfig {
+    fn default() -> Self {
+        SeedBackoffConfig {
+            initial_delay: Duration::ZERO,
+            max_delay: Duration::from_secs(30),
+            jitter: 0.2,
+            unreachable_threshold: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Limits the rate of the messages received from each source address. Each address can send
+/// one second worth of messages and bytes at once, and its budget then refills continuously.
+///
+/// `max_bytes_per_sec` must allow for the largest datagrams, as messages larger than it are
+/// always dropped.
+#[cfg(feature = "server")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReceiveRateLimit {
+    pub max_messages_per_sec: u32,
+    pub max_bytes_per_sec: u64,
+}
+
+#[cfg(feature = "server")]
+impl Default for ReceiveRateLimit {
+    fn default() -> Self {
+        ReceiveRateLimit {
+            max_messages_per_sec: 500,
+            max_bytes_per_sec: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Configures the election of a leader among the self node and the live nodes that do not
+/// intend to leave. See [`crate::Chitchat::current_leader`].
+///
+/// The node with the lowest ID is elected. Upon getting elected, a node advertises an epoch
+/// greater than the epochs of all the previous leaders it knows of, and bumps it again if it
+/// learns of a greater leadership, e.g. once a partition heals. See [`crate::LeaderEpoch`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LeaderElectionConfig {
+    /// Time a node must have been live before it takes the leadership over from a live leader
+    /// with a greater ID, so that joining nodes do not make the leadership flap. A starting node
+    /// also waits for it before electing itself, so that it learns of the current leader first.
+    pub hysteresis: Duration,
+}
+
+impl Default for LeaderElectionConfig {
+    fn default() -> Self {
+        LeaderElectionConfig {
+            hysteresis: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Policy applied to the writes issued while the server shuts down.
+///
+/// Once shutdown completes, writes are always rejected: they would never be gossiped.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum WriteAfterShutdownPolicy {
+    /// Writes are rejected as soon as shutdown begins.
+    #[default]
+    Error,
+    /// Writes wait for shutdown to complete, and are then rejected.
+    Block,
+    /// Writes are accepted until a final gossip round, performed before the server stops,
+    /// flushes them to peers.
+    QueueAndFlush,
+}
+
+/// Caps applied to each node state. Live key-values only are accounted for: tombstones are
+/// bounded by garbage collection. The heartbeat key is never limited.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct NodeStateLimits {
+    /// Maximum number of keys per node state.
+    pub max_num_keys: Option<usize>,
+    /// Maximum total size of the keys and values of a node state, in bytes.
+    pub max_num_bytes: Option<usize>,
+    pub policy: NodeStateLimitPolicy,
+}
+
+impl NodeStateLimits {
+    pub(crate) fn is_unlimited(&self) -> bool {
+        self.max_num_keys.is_none() && self.max_num_bytes.is_none()
+    }
+
+    pub(crate) fn allows(&self, num_keys: usize, num_bytes: usize) -> bool {
+        self.max_num_keys
+            .is_none_or(|max_num_keys| num_keys <= max_num_keys)
+            && self
+                .max_num_bytes
+                .is_none_or(|max_num_bytes| num_bytes <= max_num_bytes)
+    }
+}
+
+/// Defines what happens to a write that would exceed the [`NodeStateLimits`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum NodeStateLimitPolicy {
+    /// The write is dropped.
+    ///
+    /// Key-values received from peers are dropped for good: they are not requested again
+    /// unless they get updated.
+    #[default]
+    Reject,
+    /// Key-values with the oldest versions are evicted to make room for the write.
+    ///
+    /// Key-values of the local node are marked for deletion, so that the eviction is gossiped.
+    /// Key-values of remote nodes are simply forgotten.
+    EvictOldest,
+}
+
+/// Configures the scheduling of the gossip with the peers of other regions.
+///
+/// The peers of other zones, see [`ChitchatConfig::zone_key`], are remote: the zone key must
+/// hold a region for the zones to be regions. Peers whose zone is unknown, e.g. because they
+/// have not gossiped it yet, are considered remote when their round-trip time exceeds
+/// `rtt_threshold`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RegionAwareGossipConfig {
+    /// Remote peers are only gossiped with once every this many gossip rounds, so the deltas
+    /// exchanged with them batch the updates accumulated in between. Remote peers whose last
+    /// delta could not fit all of them are gossiped with at every round, until it does.
+    pub cross_region_gossip_round_interval: u32,
+    pub rtt_threshold: Duration,
+}
+
+impl Default for RegionAwareGossipConfig {
+    fn default() -> Self {
+        RegionAwareGossipConfig {
+            cross_region_gossip_round_interval: 5,
+            rtt_threshold: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Configures how the zones of the nodes, see [`ChitchatConfig::zone_key`], are taken into
+/// account to detect failures.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopologyConfig {
+    /// Factor applied to the failure detection threshold of the peers of other zones, whose
+    /// heartbeats travel over slower and less reliable links.
+    pub cross_zone_failure_detection_factor: f64,
+}
+
+impl Default for TopologyConfig {
+    fn default() -> Self {
+        TopologyConfig {
+            cross_zone_failure_detection_factor: 2.0,
+        }
+    }
+}
+
+/// Configures the checkpoints of the server. See [`crate::Checkpoint`].
+#[cfg(feature = "json")]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PersistenceConfig {
+    /// Path of the checkpoint file.
+    pub path: PathBuf,
+    /// A checkpoint is also taken upon shutdown.
+    pub checkpoint_interval: Duration,
+    /// Whether the states of the other nodes are checkpointed as well. They are restored as
+    /// is, and refreshed by gossip once the node is back.
+    pub include_cluster_view: bool,
+    /// The live peers are checkpointed as well, and used as seeds upon restart. Peers not seen
+    /// alive within this duration before the restart are not.
+    pub peer_cache_max_age: Duration,
+    /// If set, checkpoints are encrypted and authenticated with this key: a checkpoint that
+    /// is not encrypted, or was tampered with, is not restored.
+    #[cfg(feature = "encryption")]
+    pub encryption_key: Option<crate::EncryptionKey>,
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Context;
+
+/// Prefix of the keys holding counters in a node state.
+pub(crate) const COUNTER_KEY_PREFIX: &str = "__counter:";
+
+pub(crate) fn counter_key(name: &str) -> String {
+    format!("{COUNTER_KEY_PREFIX}{name}")
+}
+
+/// A positive-negative counter CRDT.
+///
+/// Each node holds its own contribution to a counter in its node state, as a regular
+/// key-value: only the node writes it, and its peers store it as is.
+/// The cluster-wide value of the counter is the sum of the contributions of all nodes.
+///
+/// Both the increments and decrements of a contribution only grow, so two copies of the
+/// same contribution are merged by taking the max of each.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PnCounter {
+    pub increments: u64,
+    pub decrements: u64,
+}
+
+impl PnCounter {
+    /// Returns the value of the counter, saturating at the bounds of `i64`.
+    pub fn value(&self) -> i64 {
+        let value = self.increments as i128 - self.decrements as i128;
+        value.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+
+    pub fn merge(&self, other: &PnCounter) -> PnCounter {
+        PnCounter {
+            increments: self.increments.max(other.increments),
+            decrements: self.decrements.max(other.decrements),
+        }
+    }
+}
+
+impl fmt::Display for PnCounter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.increments, self.decrements)
+    }
+}
+
+impl FromStr for PnCounter {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        let (increments, decrements) = value
+            .split_once(':')
+            .with_context(|| format!("Invalid counter value `{value}`."))?;
+        Ok(PnCounter {
+            increments: increments.parse()?,
+            decrements: decrements.parse()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pn_counter_merge() {
+        let left = PnCounter {
+            increments: 5,
+            decrements: 1,
+        };
+        let right = PnCounter {
+            increments: 3,
+            decrements: 4,
+        };
+        let merged = left.merge(&right);
+        assert_eq!(merged, right.merge(&left));
+        assert_eq!(
+            merged,
+            PnCounter {
+                increments: 5,
+                decrements: 4,
+            }
+        );
+        assert_eq!(merged.value(), 1);
+        let overflowing = PnCounter {
+            increments: 0,
+            decrements: u64::MAX,
+        };
+        assert_eq!(overflowing.value(), i64::MIN);
+    }
+
+    #[test]
+    fn test_pn_counter_from_str() {
+        let counter = PnCounter {
+            increments: 12,
+            decrements: 3,
+        };
+        assert_eq!(counter.to_string().parse::<PnCounter>().unwrap(), counter);
+        assert!("12".parse::<PnCounter>().is_err());
+        assert!("a:3".parse::<PnCounter>().is_err());
+    }
+}
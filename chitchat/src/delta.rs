@@ -1,6 +1,11 @@
 use std::collections::{BTreeMap, HashSet};
 use std::mem;
+use std::sync::Arc;
 
+use anyhow::bail;
+
+#[cfg(any(test, feature = "unstable"))]
+use crate::key_interner::intern_key;
 use crate::serialize::*;
 use crate::{NodeId, Version, VersionedValue};
 
@@ -28,23 +33,7 @@ impl Serializable for Delta {
     }
 
     fn deserialize(buf: &mut &[u8]) -> anyhow::Result<Self> {
-        let mut node_deltas: BTreeMap<NodeId, NodeDelta> = Default::default();
-        let num_nodes = u16::deserialize(buf)?;
-        for _ in 0..num_nodes {
-            let node_id = NodeId::deserialize(buf)?;
-            let node_delta = NodeDelta::deserialize(buf)?;
-            node_deltas.insert(node_id, node_delta);
-        }
-        let num_nodes_to_reset = u16::deserialize(buf)?;
-        let mut nodes_to_reset = HashSet::new();
-        for _ in 0..num_nodes_to_reset {
-            let node_id = NodeId::deserialize(buf)?;
-            nodes_to_reset.insert(node_id);
-        }
-        Ok(Delta {
-            node_deltas,
-            nodes_to_reset,
-        })
+        Delta::deserialize_with_limits(buf, &ParseLimits::default())
     }
 
     fn serialized_len(&self) -> usize {
@@ -69,11 +58,32 @@ impl Delta {
             .map(|node_delta| node_delta.num_tuples())
             .sum()
     }
+}
+
+impl Delta {
+    /// Returns the number of key-values of the delta, across all nodes.
+    pub(crate) fn num_key_values(&self) -> usize {
+        self.node_deltas
+            .values()
+            .map(|node_delta| node_delta.key_values.len())
+            .sum()
+    }
 
+    /// Returns true if the delta holds no key-value and no node to reset.
+    pub fn is_empty(&self) -> bool {
+        self.nodes_to_reset.is_empty()
+            && self
+                .node_deltas
+                .values()
+                .all(|node_delta| node_delta.key_values.is_empty())
+    }
+
+    #[cfg(any(test, feature = "unstable"))]
     pub fn add_node_to_reset(&mut self, node_id: NodeId) {
         self.nodes_to_reset.insert(node_id);
     }
 
+    #[cfg(any(test, feature = "unstable"))]
     pub fn add_node_delta(
         &mut self,
         node_id: NodeId,
@@ -87,7 +97,7 @@ impl Delta {
             .or_default()
             .key_values
             .insert(
-                key.to_string(),
+                intern_key(key),
                 VersionedValue {
                     value: value.to_string(),
                     version,
@@ -95,11 +105,52 @@ impl Delta {
                 },
             );
     }
+
+    /// Deserializes a delta, failing if it exceeds `limits` or lists a node twice.
+    pub(crate) fn deserialize_with_limits(
+        buf: &mut &[u8],
+        limits: &ParseLimits,
+    ) -> anyhow::Result<Self> {
+        let mut node_deltas: BTreeMap<NodeId, NodeDelta> = Default::default();
+        let num_nodes = u16::deserialize(buf)? as usize;
+        if num_nodes > limits.max_nodes {
+            bail!(
+                "Delta of {num_nodes} nodes exceeds the limit of {} nodes.",
+                limits.max_nodes
+            );
+        }
+        for _ in 0..num_nodes {
+            let node_id = NodeId::deserialize(buf)?;
+            let node_delta = NodeDelta::deserialize_with_limits(buf, limits)?;
+            if node_deltas.insert(node_id, node_delta).is_some() {
+                bail!("Delta holds several node deltas for the same node.");
+            }
+        }
+        let num_nodes_to_reset = u16::deserialize(buf)? as usize;
+        if num_nodes_to_reset > limits.max_nodes {
+            bail!(
+                "Delta resetting {num_nodes_to_reset} nodes exceeds the limit of {} nodes.",
+                limits.max_nodes
+            );
+        }
+        let mut nodes_to_reset = HashSet::new();
+        for _ in 0..num_nodes_to_reset {
+            let node_id = NodeId::deserialize(buf)?;
+            if !nodes_to_reset.insert(node_id) {
+                bail!("Delta resets the same node several times.");
+            }
+        }
+        Ok(Delta {
+            node_deltas,
+            nodes_to_reset,
+        })
+    }
 }
 
 #[derive(serde::Serialize, Default, Eq, PartialEq, Debug)]
 pub struct NodeDelta {
-    pub key_values: BTreeMap<String, VersionedValue>,
+    /// Key-values of the node, with interned keys.
+    pub key_values: BTreeMap<Arc<str>, VersionedValue>,
 }
 
 impl NodeDelta {
@@ -110,6 +161,32 @@ impl NodeDelta {
             .max()
             .unwrap_or(0)
     }
+
+    fn deserialize_with_limits(buf: &mut &[u8], limits: &ParseLimits) -> anyhow::Result<Self> {
+        let mut key_values: BTreeMap<Arc<str>, VersionedValue> = Default::default();
+        let num_kvs = u16::deserialize(buf)? as usize;
+        if num_kvs > limits.max_key_values_per_node {
+            bail!(
+                "Node delta of {num_kvs} key-values exceeds the limit of {} key-values.",
+                limits.max_key_values_per_node
+            );
+        }
+        for _ in 0..num_kvs {
+            let key = deserialize_bounded_key(buf, limits.max_key_len)?;
+            let value = deserialize_bounded_string(buf, limits.max_value_len)?;
+            let version = u64::deserialize(buf)?;
+            let marked_for_deletion = bool::deserialize(buf)?;
+            let versioned_value = VersionedValue {
+                value,
+                version,
+                marked_for_deletion,
+            };
+            if key_values.insert(key, versioned_value).is_some() {
+                bail!("Node delta holds the same key several times.");
+            }
+        }
+        Ok(NodeDelta { key_values })
+    }
 }
 
 #[cfg(test)]
@@ -169,6 +246,19 @@ impl DeltaWriter {
         true
     }
 
+    /// Returns the serialized length of the delta written so far.
+    ///
+    /// The length is accounted for as entries are added, from their encoded sizes, so that
+    /// fitting a delta into the mtu never requires serializing it.
+    pub fn num_bytes(&self) -> usize {
+        self.num_bytes
+    }
+
+    /// Returns the number of bytes that can still be added to the delta.
+    pub fn remaining_capacity(&self) -> usize {
+        self.mtu.saturating_sub(self.num_bytes)
+    }
+
     fn attempt_add_bytes(&mut self, num_bytes: usize) -> bool {
         assert!(!self.reached_capacity);
         let new_num_bytes = self.num_bytes + num_bytes;
@@ -181,33 +271,82 @@ impl DeltaWriter {
     }
 
     /// Returns false if the KV could not be added because mtu was reached.
+    #[cfg(any(test, feature = "unstable"))]
     pub fn add_kv(&mut self, key: &str, versioned_value: VersionedValue) -> bool {
         assert!(!self.current_node_delta.key_values.contains_key(key));
-        // Reserve bytes for the key (2 bytes are used to store the key length) and versioned value.
-        if !self.attempt_add_bytes(
-            2 + key.len()
-                + versioned_value.value.serialized_len()
-                + versioned_value.version.serialized_len()
-                + versioned_value.marked_for_deletion.serialized_len(),
-        ) {
+        if !self.attempt_add_bytes(kv_serialized_len(key, &versioned_value)) {
             return false;
         }
         self.current_node_delta
             .key_values
-            .insert(key.to_string(), versioned_value);
+            .insert(intern_key(key), versioned_value);
+        true
+    }
+
+    /// Adds a group of KVs that must not be split across deltas.
+    ///
+    /// Either all of the KVs are added, or none of them is.
+    /// Returns false if the group could not be added because mtu was reached.
+    pub fn add_kv_group(&mut self, key_values: &[(&Arc<str>, &VersionedValue)]) -> bool {
+        assert!(key_values
+            .iter()
+            .all(|(key, _)| !self.current_node_delta.key_values.contains_key(*key)));
+        let num_bytes = key_values
+            .iter()
+            .map(|(key, versioned_value)| kv_serialized_len(key, versioned_value))
+            .sum();
+        if !self.attempt_add_bytes(num_bytes) {
+            return false;
+        }
+        for (key, versioned_value) in key_values {
+            self.current_node_delta
+                .key_values
+                .insert(Arc::clone(key), (*versioned_value).clone());
+        }
         true
     }
+
+    /// Returns true if the KV could never be added to the current node, even in a delta that
+    /// would contain nothing else.
+    ///
+    /// Contrary to `add_kv`, this does not consume any of the writer's capacity.
+    #[cfg(any(test, feature = "unstable"))]
+    pub fn exceeds_mtu(&self, key: &str, versioned_value: &VersionedValue) -> bool {
+        self.kvs_exceed_mtu(kv_serialized_len(key, versioned_value))
+    }
+
+    /// Same as `exceeds_mtu`, for a group of KVs that must be added all at once.
+    pub fn kv_group_exceeds_mtu(&self, key_values: &[(&Arc<str>, &VersionedValue)]) -> bool {
+        let kvs_len: usize = key_values
+            .iter()
+            .map(|(key, versioned_value)| kv_serialized_len(key, versioned_value))
+            .sum();
+        self.kvs_exceed_mtu(kvs_len)
+    }
+
+    fn kvs_exceed_mtu(&self, kvs_len: usize) -> bool {
+        let node_id_len = self
+            .current_node_id
+            .as_ref()
+            .map(|node_id| node_id.serialized_len())
+            .unwrap_or(0);
+        // 2 + 2 bytes for the delta header, 2 bytes for the node delta length.
+        2 + 2 + node_id_len + 2 + kvs_len > self.mtu
+    }
+}
+
+// Bytes for the key (2 bytes are used to store the key length) and versioned value.
+pub(crate) fn kv_serialized_len(key: &str, versioned_value: &VersionedValue) -> usize {
+    2 + key.len()
+        + versioned_value.value.serialized_len()
+        + versioned_value.version.serialized_len()
+        + versioned_value.marked_for_deletion.serialized_len()
 }
 
 impl From<DeltaWriter> for Delta {
     fn from(mut delta_writer: DeltaWriter) -> Delta {
         delta_writer.flush();
-        if cfg!(debug_assertions) {
-            let mut buf = Vec::new();
-            assert_eq!(delta_writer.num_bytes, delta_writer.delta.serialized_len());
-            delta_writer.delta.serialize(&mut buf);
-            assert_eq!(buf.len(), delta_writer.num_bytes);
-        }
+        debug_assert_eq!(delta_writer.num_bytes, delta_writer.delta.serialized_len());
         delta_writer.delta
     }
 }
@@ -232,23 +371,7 @@ impl Serializable for NodeDelta {
     }
 
     fn deserialize(buf: &mut &[u8]) -> anyhow::Result<Self> {
-        let mut key_values: BTreeMap<String, VersionedValue> = Default::default();
-        let num_kvs = u16::deserialize(buf)?;
-        for _ in 0..num_kvs {
-            let key = String::deserialize(buf)?;
-            let value = String::deserialize(buf)?;
-            let version = u64::deserialize(buf)?;
-            let marked_for_deletion = bool::deserialize(buf)?;
-            key_values.insert(
-                key,
-                VersionedValue {
-                    value,
-                    version,
-                    marked_for_deletion,
-                },
-            );
-        }
-        Ok(NodeDelta { key_values })
+        NodeDelta::deserialize_with_limits(buf, &ParseLimits::default())
     }
 
     fn serialized_len(&self) -> usize {
@@ -279,9 +402,102 @@ mod tests {
     fn test_delta_serialization_default() {
         test_serdeser_aux(&Delta::default(), 4);
     }
+
+    #[test]
+    fn test_delta_deserialize_with_limits() {
+        let mut delta = Delta::default();
+        delta.add_node_delta(
+            NodeId::for_test_localhost(10_001),
+            "key_a",
+            "value",
+            1,
+            false,
+        );
+        delta.add_node_delta(
+            NodeId::for_test_localhost(10_001),
+            "key_b",
+            "value",
+            2,
+            false,
+        );
+        delta.add_node_delta(
+            NodeId::for_test_localhost(10_002),
+            "key_a",
+            "value",
+            1,
+            false,
+        );
+        let buf = delta.serialize_to_vec();
+        let limits = ParseLimits {
+            max_nodes: 2,
+            max_key_values_per_node: 2,
+            max_key_len: 5,
+            max_value_len: 5,
+        };
+        assert_eq!(
+            Delta::deserialize_with_limits(&mut &buf[..], &limits).unwrap(),
+            delta
+        );
+        for exceeded_limits in [
+            ParseLimits {
+                max_nodes: 1,
+                ..limits
+            },
+            ParseLimits {
+                max_key_values_per_node: 1,
+                ..limits
+            },
+            ParseLimits {
+                max_key_len: 4,
+                ..limits
+            },
+            ParseLimits {
+                max_value_len: 4,
+                ..limits
+            },
+        ] {
+            assert!(Delta::deserialize_with_limits(&mut &buf[..], &exceeded_limits).is_err());
+        }
+        // Truncated deltas are rejected, rather than read out of bounds.
+        for len in 0..buf.len() {
+            assert!(Delta::deserialize(&mut &buf[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_delta_deserialize_rejects_duplicates() {
+        let node_id = NodeId::for_test_localhost(10_001);
+        let mut node_delta = NodeDelta::default();
+        node_delta.key_values.insert(
+            intern_key("key"),
+            VersionedValue {
+                value: "value".to_string(),
+                version: 1,
+                marked_for_deletion: false,
+            },
+        );
+        let mut buf = Vec::new();
+        2u16.serialize(&mut buf);
+        for _ in 0..2 {
+            node_id.serialize(&mut buf);
+            node_delta.serialize(&mut buf);
+        }
+        0u16.serialize(&mut buf);
+        assert!(Delta::deserialize(&mut &buf[..]).is_err());
+
+        let mut buf = Vec::new();
+        2u16.serialize(&mut buf);
+        for _ in 0..2 {
+            "key".to_string().serialize(&mut buf);
+            "value".to_string().serialize(&mut buf);
+            1u64.serialize(&mut buf);
+            false.serialize(&mut buf);
+        }
+        assert!(NodeDelta::deserialize(&mut &buf[..]).is_err());
+    }
     #[test]
     fn test_delta_serialization_simple() {
-        let mut delta_writer = DeltaWriter::with_mtu(138);
+        let mut delta_writer = DeltaWriter::with_mtu(154);
         delta_writer.add_node(NodeId::for_test_localhost(10_001));
         assert!(delta_writer.add_kv(
             "key11",
@@ -317,12 +533,12 @@ mod tests {
             },
         ));
         let delta: Delta = delta_writer.into();
-        test_serdeser_aux(&delta, 138);
+        test_serdeser_aux(&delta, 154);
     }
 
     #[test]
     fn test_delta_serialization_simple_node() {
-        let mut delta_writer = DeltaWriter::with_mtu(110);
+        let mut delta_writer = DeltaWriter::with_mtu(126);
         assert!(delta_writer.add_node(NodeId::for_test_localhost(10_001)));
         assert!(delta_writer.add_kv(
             "key11",
@@ -342,13 +558,13 @@ mod tests {
         ));
         assert!(delta_writer.add_node(NodeId::for_test_localhost(10_002)));
         let delta: Delta = delta_writer.into();
-        test_serdeser_aux(&delta, 92);
+        test_serdeser_aux(&delta, 108);
     }
 
     #[test]
     fn test_delta_serialization_simple_with_nodes_to_reset() {
-        let mut delta_writer = DeltaWriter::with_mtu(120);
-        assert!(delta_writer.add_node_to_reset(NodeId::for_test_localhost(10_000))); // Node ID takes 19 bytes
+        let mut delta_writer = DeltaWriter::with_mtu(144);
+        assert!(delta_writer.add_node_to_reset(NodeId::for_test_localhost(10_000))); // Node ID takes 27 bytes
         assert!(delta_writer.add_node(NodeId::for_test_localhost(10_001)));
         assert!(delta_writer.add_kv(
             "key11",
@@ -368,12 +584,12 @@ mod tests {
         ));
         assert!(delta_writer.add_node(NodeId::for_test_localhost(10_002)));
         let delta: Delta = delta_writer.into();
-        test_serdeser_aux(&delta, 111);
+        test_serdeser_aux(&delta, 135);
     }
 
     #[test]
     fn test_delta_serialization_exceed_mtu_on_add_node() {
-        let mut delta_writer = DeltaWriter::with_mtu(87);
+        let mut delta_writer = DeltaWriter::with_mtu(95);
         assert!(delta_writer.add_node(NodeId::for_test_localhost(10_001)));
         assert!(delta_writer.add_kv(
             "key11",
@@ -393,12 +609,12 @@ mod tests {
         ));
         assert!(!delta_writer.add_node(NodeId::for_test_localhost(10_002)));
         let delta: Delta = delta_writer.into();
-        test_serdeser_aux(&delta, 71);
+        test_serdeser_aux(&delta, 79);
     }
 
     #[test]
     fn test_delta_serialization_exceed_mtu_on_add_node_to_reset() {
-        let mut delta_writer = DeltaWriter::with_mtu(87);
+        let mut delta_writer = DeltaWriter::with_mtu(95);
         assert!(delta_writer.add_node(NodeId::for_test_localhost(10_001)));
         assert!(delta_writer.add_kv(
             "key11",
@@ -418,12 +634,12 @@ mod tests {
         ));
         assert!(!delta_writer.add_node_to_reset(NodeId::for_test_localhost(10_002)));
         let delta: Delta = delta_writer.into();
-        test_serdeser_aux(&delta, 71);
+        test_serdeser_aux(&delta, 79);
     }
 
     #[test]
     fn test_delta_serialization_exceed_mtu_on_add_kv() {
-        let mut delta_writer = DeltaWriter::with_mtu(66);
+        let mut delta_writer = DeltaWriter::with_mtu(74);
         assert!(delta_writer.add_node(NodeId::for_test_localhost(10_001)));
         assert!(delta_writer.add_kv(
             "key11",
@@ -442,13 +658,13 @@ mod tests {
             }
         ));
         let delta: Delta = delta_writer.into();
-        test_serdeser_aux(&delta, 48);
+        test_serdeser_aux(&delta, 56);
     }
 
     #[test]
     #[should_panic]
     fn test_delta_serialization_panic_if_add_after_exceed() {
-        let mut delta_writer = DeltaWriter::with_mtu(54);
+        let mut delta_writer = DeltaWriter::with_mtu(62);
         assert!(delta_writer.add_node(NodeId::for_test_localhost(10_001)));
         assert!(delta_writer.add_kv(
             "key11",
@@ -475,4 +691,86 @@ mod tests {
             },
         );
     }
+
+    #[test]
+    fn test_delta_writer_exceeds_mtu() {
+        let mut delta_writer = DeltaWriter::with_mtu(56);
+        assert!(delta_writer.add_node(NodeId::for_test_localhost(10_001)));
+        let small_versioned_value = VersionedValue {
+            value: "val11".to_string(),
+            version: 1,
+            marked_for_deletion: false,
+        };
+        let large_versioned_value = VersionedValue {
+            value: "val11-is-too-large".to_string(),
+            version: 2,
+            marked_for_deletion: false,
+        };
+        assert!(!delta_writer.exceeds_mtu("key11", &small_versioned_value));
+        assert!(delta_writer.exceeds_mtu("key12", &large_versioned_value));
+        // Checking for the size does not consume capacity.
+        assert!(delta_writer.add_kv("key11", small_versioned_value));
+        let delta: Delta = delta_writer.into();
+        test_serdeser_aux(&delta, 56);
+    }
+
+    #[test]
+    fn test_delta_writer_add_kv_group() {
+        let node_id = NodeId::for_test_localhost(10_001);
+        let versioned_value = VersionedValue {
+            value: "val11".to_string(),
+            version: 1,
+            marked_for_deletion: false,
+        };
+        let (key11, key12) = (intern_key("key11"), intern_key("key12"));
+        let key_values = [(&key11, &versioned_value), (&key12, &versioned_value)];
+
+        let mut delta_writer = DeltaWriter::with_mtu(62);
+        assert!(delta_writer.add_node(node_id.clone()));
+        assert!(!delta_writer.kv_group_exceeds_mtu(&key_values[..1]));
+        assert!(delta_writer.kv_group_exceeds_mtu(&key_values));
+        // The group does not fit: none of its KVs are added.
+        assert!(!delta_writer.add_kv_group(&key_values));
+        let delta: Delta = delta_writer.into();
+        assert!(delta
+            .node_deltas
+            .get(&node_id)
+            .map(|node_delta| node_delta.key_values.is_empty())
+            .unwrap_or(true));
+
+        let mut delta_writer = DeltaWriter::with_mtu(100);
+        assert!(delta_writer.add_node(node_id.clone()));
+        assert!(delta_writer.add_kv_group(&key_values));
+        let delta: Delta = delta_writer.into();
+        assert_eq!(delta.node_deltas[&node_id].key_values.len(), 2);
+        test_serdeser_aux(&delta, 79);
+    }
+
+    #[test]
+    fn test_delta_writer_num_bytes_matches_serialization() {
+        let versioned_value = VersionedValue {
+            value: "val".to_string(),
+            version: 3,
+            marked_for_deletion: false,
+        };
+        let tombstone = VersionedValue {
+            value: String::new(),
+            version: 4,
+            marked_for_deletion: true,
+        };
+        let mut delta_writer = DeltaWriter::with_mtu(1_000);
+        assert_eq!(delta_writer.num_bytes(), 4);
+        assert!(delta_writer.add_node_to_reset(NodeId::for_test_localhost(10_003)));
+        assert!(delta_writer.add_node(NodeId::for_test_localhost(10_001)));
+        assert!(delta_writer.add_kv("key", versioned_value.clone()));
+        let (key_a, key_b) = (intern_key("key_a"), intern_key("key_b"));
+        assert!(delta_writer.add_kv_group(&[(&key_a, &versioned_value), (&key_b, &tombstone)]));
+        assert!(delta_writer.add_node(NodeId::for_test_localhost(10_002)));
+        assert!(delta_writer.add_node(NodeId::for_test_localhost(10_003)));
+        assert!(delta_writer.add_kv("key", tombstone));
+        let num_bytes = delta_writer.num_bytes();
+        assert_eq!(delta_writer.remaining_capacity(), 1_000 - num_bytes);
+        let delta: Delta = delta_writer.into();
+        assert_eq!(delta.serialize_to_vec().len(), num_bytes);
+    }
 }
@@ -0,0 +1,45 @@
+use std::fmt;
+
+use crate::{NodeId, VersionedValue};
+
+/// Outcome of [`DeltaInterceptor::before_apply`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Interception {
+    /// The key-value is applied as received.
+    Apply,
+    /// The key-value is applied with the replacement value and deletion flag. The version of
+    /// the received key-value is kept.
+    Replace(VersionedValue),
+    /// The key-value is dropped, and will not be received again.
+    Veto,
+}
+
+/// Hooks invoked on every key-value received from peers, so that applications can validate,
+/// transform, or veto incoming updates.
+///
+/// Hooks are called while the cluster state is being updated, with the chitchat lock held: they
+/// must be cheap and must not block.
+pub trait DeltaInterceptor: Send {
+    /// Called before applying `versioned_value` to `key` of the node `node_id`.
+    ///
+    /// A replacement value is stored and gossiped under the version of the received one. Nodes
+    /// only converge if every node intercepting the key applies the same transformation, and if
+    /// transforming a replacement again leaves it unchanged.
+    fn before_apply(
+        &self,
+        _node_id: &NodeId,
+        _key: &str,
+        _versioned_value: &VersionedValue,
+    ) -> Interception {
+        Interception::Apply
+    }
+
+    /// Called after `versioned_value` was applied to `key` of the node `node_id`.
+    fn after_apply(&self, _node_id: &NodeId, _key: &str, _versioned_value: &VersionedValue) {}
+}
+
+impl fmt::Debug for dyn DeltaInterceptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("DeltaInterceptor")
+    }
+}
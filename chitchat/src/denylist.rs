@@ -0,0 +1,130 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+use crate::delta::Delta;
+use crate::NodeId;
+
+/// A peer to block, designated by its node ID or by its gossip address. See
+/// [`crate::Chitchat::block_node`].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum BlockedPeer {
+    /// Blocks all the generations of the node, as well as its gossip address.
+    NodeId(NodeId),
+    /// Blocks the gossip address, and the nodes advertising it.
+    Addr(SocketAddr),
+}
+
+impl From<NodeId> for BlockedPeer {
+    fn from(node_id: NodeId) -> Self {
+        BlockedPeer::NodeId(node_id)
+    }
+}
+
+impl From<SocketAddr> for BlockedPeer {
+    fn from(addr: SocketAddr) -> Self {
+        BlockedPeer::Addr(addr)
+    }
+}
+
+/// Peers whose messages are dropped, and whose states are kept out of the cluster state.
+///
+/// Node IDs and addresses are blocked independently: unblocking a node ID only lifts the
+/// addresses it blocked, not the ones blocked on their own.
+#[derive(Debug, Default)]
+pub(crate) struct Denylist {
+    /// Gossip addresses of the blocked node IDs, as of the time they were blocked.
+    blocked_node_ids: HashMap<String, HashSet<SocketAddr>>,
+    blocked_addrs: HashSet<SocketAddr>,
+}
+
+impl Denylist {
+    pub fn block(&mut self, peer: &BlockedPeer) {
+        match peer {
+            BlockedPeer::NodeId(node_id) => {
+                self.blocked_node_ids
+                    .entry(node_id.id.clone())
+                    .or_default()
+                    .insert(node_id.gossip_public_address);
+            }
+            BlockedPeer::Addr(addr) => {
+                self.blocked_addrs.insert(*addr);
+            }
+        }
+    }
+
+    pub fn unblock(&mut self, peer: &BlockedPeer) {
+        match peer {
+            BlockedPeer::NodeId(node_id) => {
+                self.blocked_node_ids.remove(&node_id.id);
+            }
+            BlockedPeer::Addr(addr) => {
+                self.blocked_addrs.remove(addr);
+            }
+        }
+    }
+
+    pub fn is_addr_blocked(&self, addr: SocketAddr) -> bool {
+        self.blocked_addrs.contains(&addr)
+            || self
+                .blocked_node_ids
+                .values()
+                .any(|node_id_addrs| node_id_addrs.contains(&addr))
+    }
+
+    pub fn is_node_blocked(&self, node_id: &NodeId) -> bool {
+        self.blocked_node_ids.contains_key(&node_id.id)
+            || self.is_addr_blocked(node_id.gossip_public_address)
+    }
+
+    /// Removes from the delta the node deltas of the blocked nodes, relayed by other peers.
+    pub fn filter_delta(&self, delta: &mut Delta) {
+        if self.blocked_addrs.is_empty() && self.blocked_node_ids.is_empty() {
+            return;
+        }
+        delta
+            .node_deltas
+            .retain(|node_id, _| !self.is_node_blocked(node_id));
+        delta
+            .nodes_to_reset
+            .retain(|node_id| !self.is_node_blocked(node_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_denylist() {
+        let mut denylist = Denylist::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let node1_restarted = NodeId::new(node1.id.clone(), "127.0.0.1:10003".parse().unwrap())
+            .with_generation(node1.generation + 1);
+        denylist.block(&BlockedPeer::from(node1.clone()));
+        denylist.block(&BlockedPeer::from(node2.gossip_public_address));
+        assert!(denylist.is_node_blocked(&node1_restarted));
+        assert!(denylist.is_node_blocked(&node2));
+        assert!(denylist.is_addr_blocked(node1.gossip_public_address));
+        assert!(!denylist.is_addr_blocked(node1_restarted.gossip_public_address));
+
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1_restarted.clone(), "key", "1", 1, false);
+        delta.add_node_delta(node2.clone(), "key", "1", 1, false);
+        delta.add_node_delta(NodeId::for_test_localhost(10_004), "key", "1", 1, false);
+        denylist.filter_delta(&mut delta);
+        assert_eq!(delta.node_deltas.len(), 1);
+
+        denylist.unblock(&BlockedPeer::from(node1.clone()));
+        assert!(!denylist.is_node_blocked(&node1_restarted));
+        assert!(!denylist.is_addr_blocked(node1.gossip_public_address));
+
+        // Unblocking a node ID leaves its address blocked if it was blocked on its own.
+        denylist.block(&BlockedPeer::from(node2.clone()));
+        denylist.unblock(&BlockedPeer::from(node2.clone()));
+        assert!(denylist.is_addr_blocked(node2.gossip_public_address));
+        assert!(denylist.is_node_blocked(&node2));
+        denylist.unblock(&BlockedPeer::from(node2.gossip_public_address));
+        assert!(!denylist.is_node_blocked(&node2));
+    }
+}
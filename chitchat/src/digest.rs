@@ -1,51 +1,265 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::Arc;
+
+use anyhow::bail;
 
 use crate::serialize::*;
-use crate::{NodeId, Version};
+use crate::{NodeId, NodeState, Version};
 
 /// A digest represents is a piece of information summarizing
 /// the staleness of one peer's data.
 ///
 /// It is equivalent to a map
-/// peer -> max version.
-#[derive(Debug, Default, PartialEq, Eq)]
+/// peer -> (heartbeat, max version).
+///
+/// The heartbeat is carried independently of the max version, so that the liveness of an
+/// idle node propagates without its key-values changing.
+///
+/// The digests of the self node share the entries of its [`DigestCache`], and leave the dead
+/// nodes out on read and serialization, so that computing a digest does not copy the entries
+/// of all the nodes.
+#[derive(Clone, Debug, Default)]
 pub struct Digest {
-    pub node_max_version: BTreeMap<NodeId, Version>,
+    node_digests: Arc<BTreeMap<NodeId, NodeDigest>>,
+    excluded_node_ids: BTreeSet<NodeId>,
+}
+
+/// Entry of a node in a [`Digest`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct NodeDigest {
+    pub heartbeat: u64,
+    pub max_version: Version,
 }
 
 impl Digest {
     #[cfg(test)]
     pub fn add_node(&mut self, node: NodeId, max_version: Version) {
-        self.node_max_version.insert(node, max_version);
+        self.add_node_with_heartbeat(node, 0, max_version);
+    }
+
+    #[cfg(any(test, feature = "unstable"))]
+    pub fn add_node_with_heartbeat(&mut self, node: NodeId, heartbeat: u64, max_version: Version) {
+        self.excluded_node_ids.remove(&node);
+        let node_digest = NodeDigest {
+            heartbeat,
+            max_version,
+        };
+        Arc::make_mut(&mut self.node_digests).insert(node, node_digest);
+    }
+
+    /// Returns the entry of `node_id`, if the digest lists it.
+    pub fn get(&self, node_id: &NodeId) -> Option<NodeDigest> {
+        if self.excluded_node_ids.contains(node_id) {
+            return None;
+        }
+        self.node_digests.get(node_id).copied()
+    }
+
+    /// Returns the max version of `node_id`, if the digest lists it.
+    pub fn max_version(&self, node_id: &NodeId) -> Option<Version> {
+        self.get(node_id).map(|node_digest| node_digest.max_version)
+    }
+
+    /// Returns the entries of the nodes listed by the digest, ordered by node ID.
+    pub fn iter(&self) -> impl Iterator<Item = (&NodeId, NodeDigest)> {
+        self.node_digests
+            .iter()
+            .filter(|(node_id, _)| !self.excluded_node_ids.contains(*node_id))
+            .map(|(node_id, node_digest)| (node_id, *node_digest))
+    }
+
+    /// Returns the number of nodes listed by the digest.
+    pub fn num_nodes(&self) -> usize {
+        let num_excluded_nodes = self
+            .excluded_node_ids
+            .iter()
+            .filter(|node_id| self.node_digests.contains_key(*node_id))
+            .count();
+        self.node_digests.len() - num_excluded_nodes
+    }
+
+    /// Deserializes a digest, failing if it exceeds `limits` or lists a node twice.
+    pub(crate) fn deserialize_with_limits(
+        buf: &mut &[u8],
+        limits: &ParseLimits,
+    ) -> anyhow::Result<Self> {
+        let num_nodes = u16::deserialize(buf)? as usize;
+        if num_nodes > limits.max_nodes {
+            bail!(
+                "Digest of {num_nodes} nodes exceeds the limit of {} nodes.",
+                limits.max_nodes
+            );
+        }
+        let mut node_digests: BTreeMap<NodeId, NodeDigest> = BTreeMap::new();
+        for _ in 0..num_nodes {
+            let node_id = NodeId::deserialize(buf)?;
+            let heartbeat = u64::deserialize(buf)?;
+            let max_version = u64::deserialize(buf)?;
+            let node_digest = NodeDigest {
+                heartbeat,
+                max_version,
+            };
+            if node_digests.insert(node_id, node_digest).is_some() {
+                bail!("Digest lists the same node several times.");
+            }
+        }
+        Ok(Digest {
+            node_digests: Arc::new(node_digests),
+            excluded_node_ids: BTreeSet::new(),
+        })
+    }
+}
+
+impl PartialEq for Digest {
+    fn eq(&self, other: &Digest) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for Digest {}
+
+/// Digest of all the node states, maintained as they change rather than rebuilt on every round.
+///
+/// The cluster state marks the nodes it modifies as stale, and only their entries are refreshed
+/// when the digest is next read. The entries are copied on write: a refresh only copies them if
+/// a digest returned earlier still shares them.
+#[derive(Debug, Default)]
+pub(crate) struct DigestCache {
+    node_digests: Arc<BTreeMap<NodeId, NodeDigest>>,
+    stale_node_ids: HashSet<NodeId>,
+}
+
+impl DigestCache {
+    pub fn mark_stale(&mut self, node_id: &NodeId) {
+        if !self.stale_node_ids.contains(node_id) {
+            self.stale_node_ids.insert(node_id.clone());
+        }
+    }
+
+    /// Returns the digest of `node_states`, excluding the `dead_nodes`.
+    pub fn digest(
+        &mut self,
+        node_states: &BTreeMap<NodeId, Arc<NodeState>>,
+        dead_nodes: &HashSet<&NodeId>,
+    ) -> Digest {
+        if !self.stale_node_ids.is_empty() {
+            let node_digests = Arc::make_mut(&mut self.node_digests);
+            for node_id in self.stale_node_ids.drain() {
+                if let Some(node_state) = node_states.get(&node_id) {
+                    let node_digest = NodeDigest {
+                        heartbeat: node_state.heartbeat(),
+                        max_version: node_state.max_version,
+                    };
+                    node_digests.insert(node_id, node_digest);
+                } else {
+                    node_digests.remove(&node_id);
+                }
+            }
+        }
+        Digest {
+            node_digests: self.node_digests.clone(),
+            excluded_node_ids: dead_nodes
+                .iter()
+                .map(|node_id| (*node_id).clone())
+                .collect(),
+        }
     }
 }
 
 impl Serializable for Digest {
     fn serialize(&self, buf: &mut Vec<u8>) {
-        (self.node_max_version.len() as u16).serialize(buf);
-        for (node_id, version) in &self.node_max_version {
+        (self.num_nodes() as u16).serialize(buf);
+        for (node_id, node_digest) in self.iter() {
             node_id.serialize(buf);
-            version.serialize(buf);
+            node_digest.heartbeat.serialize(buf);
+            node_digest.max_version.serialize(buf);
         }
     }
 
     fn deserialize(buf: &mut &[u8]) -> anyhow::Result<Self> {
-        let num_nodes = u16::deserialize(buf)?;
-        let mut node_max_version: BTreeMap<NodeId, Version> = Default::default();
-        for _ in 0..num_nodes {
-            let node_id = NodeId::deserialize(buf)?;
-            let version = u64::deserialize(buf)?;
-            node_max_version.insert(node_id, version);
-        }
-        Ok(Digest { node_max_version })
+        Digest::deserialize_with_limits(buf, &ParseLimits::default())
     }
 
     fn serialized_len(&self) -> usize {
-        let mut len = (self.node_max_version.len() as u16).serialized_len();
-        for (node_id, version) in &self.node_max_version {
+        let mut len = (self.num_nodes() as u16).serialized_len();
+        for (node_id, node_digest) in self.iter() {
             len += node_id.serialized_len();
-            len += version.serialized_len();
+            len += node_digest.heartbeat.serialized_len();
+            len += node_digest.max_version.serialized_len();
         }
         len
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_deserialize_with_limits() {
+        let mut digest = Digest::default();
+        digest.add_node_with_heartbeat(NodeId::for_test_localhost(10_001), 3, 1);
+        digest.add_node_with_heartbeat(NodeId::for_test_localhost(10_002), 4, 2);
+        let buf = digest.serialize_to_vec();
+        let limits = ParseLimits {
+            max_nodes: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            Digest::deserialize_with_limits(&mut &buf[..], &limits).unwrap(),
+            digest
+        );
+        let limits = ParseLimits {
+            max_nodes: 1,
+            ..Default::default()
+        };
+        assert!(Digest::deserialize_with_limits(&mut &buf[..], &limits).is_err());
+
+        let mut buf = Vec::new();
+        2u16.serialize(&mut buf);
+        for _ in 0..2 {
+            NodeId::for_test_localhost(10_001).serialize(&mut buf);
+            3u64.serialize(&mut buf);
+            1u64.serialize(&mut buf);
+        }
+        assert!(Digest::deserialize(&mut &buf[..]).is_err());
+    }
+
+    #[test]
+    fn test_digest_cache_shares_entries() {
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let mut node_states = BTreeMap::new();
+        node_states.insert(node1.clone(), Arc::new(NodeState::default()));
+        node_states.insert(node2.clone(), Arc::new(NodeState::default()));
+        let mut digest_cache = DigestCache::default();
+        digest_cache.mark_stale(&node1);
+        digest_cache.mark_stale(&node2);
+
+        let dead_nodes = HashSet::from_iter([&node2]);
+        let digest = digest_cache.digest(&node_states, &dead_nodes);
+        let other_digest = digest_cache.digest(&node_states, &HashSet::new());
+        assert!(Arc::ptr_eq(
+            &digest.node_digests,
+            &other_digest.node_digests
+        ));
+        // Dead nodes are left out on read and serialization.
+        assert_eq!(digest.num_nodes(), 1);
+        assert!(digest.get(&node2).is_none());
+        let mut expected_digest = Digest::default();
+        expected_digest.add_node(node1.clone(), 0);
+        assert_eq!(digest, expected_digest);
+        assert_eq!(digest.serialized_len(), expected_digest.serialized_len());
+        assert_eq!(
+            digest.serialize_to_vec(),
+            expected_digest.serialize_to_vec()
+        );
+
+        // Refreshing the cache leaves the digests returned earlier untouched.
+        node_states.remove(&node2);
+        digest_cache.mark_stale(&node2);
+        let digest = digest_cache.digest(&node_states, &HashSet::new());
+        assert_eq!(digest.num_nodes(), 1);
+        assert_eq!(other_digest.num_nodes(), 2);
+    }
+}
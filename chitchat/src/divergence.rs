@@ -0,0 +1,221 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ClusterStateSnapshot, KeyValues, NodeState, VersionedValue};
+
+/// Key-by-key comparison of the cluster states of two nodes.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DivergenceReport {
+    pub divergences: Vec<KeyDivergence>,
+}
+
+/// A key whose versioned value differs between the local and the remote cluster state.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct KeyDivergence {
+    pub node_id: String,
+    pub key: String,
+    pub local: Option<VersionedValue>,
+    pub remote: Option<VersionedValue>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DivergenceKind {
+    /// The key is only present in the remote state.
+    MissingLocally,
+    /// The key is only present in the local state.
+    MissingRemotely,
+    /// The key is present on both sides with different versions. This is expected for keys
+    /// that are still being gossiped.
+    VersionMismatch,
+    /// The key has different values for the same version. This should never happen.
+    ValueMismatch,
+}
+
+impl KeyDivergence {
+    pub fn kind(&self) -> DivergenceKind {
+        match (&self.local, &self.remote) {
+            (None, _) => DivergenceKind::MissingLocally,
+            (_, None) => DivergenceKind::MissingRemotely,
+            (Some(local), Some(remote)) if local.version != remote.version => {
+                DivergenceKind::VersionMismatch
+            }
+            _ => DivergenceKind::ValueMismatch,
+        }
+    }
+}
+
+impl DivergenceReport {
+    pub fn compare(local: &ClusterStateSnapshot, remote: &ClusterStateSnapshot) -> Self {
+        let empty_node_state = NodeState::default();
+        let node_ids: BTreeSet<&String> = local
+            .node_states
+            .keys()
+            .chain(remote.node_states.keys())
+            .collect();
+        let mut divergences = Vec::new();
+        for node_id in node_ids {
+            let local_key_values = key_values(&local.node_states, node_id, &empty_node_state);
+            let remote_key_values = key_values(&remote.node_states, node_id, &empty_node_state);
+            let keys: BTreeSet<&Arc<str>> = local_key_values
+                .keys()
+                .chain(remote_key_values.keys())
+                .collect();
+            for key in keys {
+                let local_value = local_key_values.get(&**key);
+                let remote_value = remote_key_values.get(&**key);
+                if local_value == remote_value {
+                    continue;
+                }
+                divergences.push(KeyDivergence {
+                    node_id: node_id.clone(),
+                    key: key.to_string(),
+                    local: local_value.cloned(),
+                    remote: remote_value.cloned(),
+                });
+            }
+        }
+        DivergenceReport { divergences }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.divergences.is_empty()
+    }
+
+    /// Returns the divergences of the given kind.
+    pub fn of_kind(&self, kind: DivergenceKind) -> impl Iterator<Item = &KeyDivergence> {
+        self.divergences
+            .iter()
+            .filter(move |divergence| divergence.kind() == kind)
+    }
+}
+
+fn key_values<'a>(
+    node_states: &'a BTreeMap<String, Arc<NodeState>>,
+    node_id: &str,
+    empty_node_state: &'a NodeState,
+) -> &'a KeyValues {
+    &node_states
+        .get(node_id)
+        .map(Arc::as_ref)
+        .unwrap_or(empty_node_state)
+        .key_values
+}
+
+fn format_versioned_value(versioned_value: &VersionedValue) -> String {
+    if versioned_value.marked_for_deletion {
+        format!("<deleted>@{}", versioned_value.version)
+    } else {
+        format!("{:?}@{}", versioned_value.value, versioned_value.version)
+    }
+}
+
+impl fmt::Display for KeyDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let local = self
+            .local
+            .as_ref()
+            .map(format_versioned_value)
+            .unwrap_or_else(|| "<missing>".to_string());
+        let remote = self
+            .remote
+            .as_ref()
+            .map(format_versioned_value)
+            .unwrap_or_else(|| "<missing>".to_string());
+        write!(
+            f,
+            "{:?} node={} key={} local={local} remote={remote}",
+            self.kind(),
+            self.node_id,
+            self.key
+        )
+    }
+}
+
+impl fmt::Display for DivergenceReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for divergence in &self.divergences {
+            writeln!(f, "{divergence}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn snapshot(node_states: Vec<(&str, NodeState)>) -> ClusterStateSnapshot {
+        ClusterStateSnapshot {
+            seed_addrs: HashSet::new(),
+            node_states: node_states
+                .into_iter()
+                .map(|(node_id, node_state)| (node_id.to_string(), Arc::new(node_state)))
+                .collect(),
+            gossip_addrs: BTreeMap::new(),
+            generations: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_divergence_report() {
+        let mut local_node_state = NodeState::default();
+        local_node_state.set("key_a", "1");
+        local_node_state.set("key_b", "2");
+        local_node_state.set("key_c", "3");
+        let mut remote_node_state = NodeState::default();
+        remote_node_state.set("key_a", "1");
+        remote_node_state.set("key_b", "2-bis");
+        remote_node_state.set("key_d", "4");
+
+        let local = snapshot(vec![("node-1", local_node_state)]);
+        let remote = snapshot(vec![
+            ("node-1", remote_node_state),
+            ("node-2", NodeState::default()),
+        ]);
+        let report = DivergenceReport::compare(&local, &remote);
+        let kinds: Vec<(&str, DivergenceKind)> = report
+            .divergences
+            .iter()
+            .map(|divergence| (divergence.key.as_str(), divergence.kind()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("key_b", DivergenceKind::ValueMismatch),
+                ("key_c", DivergenceKind::MissingRemotely),
+                ("key_d", DivergenceKind::MissingLocally),
+            ]
+        );
+        assert_eq!(report.of_kind(DivergenceKind::ValueMismatch).count(), 1);
+        assert_eq!(
+            report.divergences[0].to_string(),
+            r#"ValueMismatch node=node-1 key=key_b local="2"@2 remote="2-bis"@2"#
+        );
+        assert!(DivergenceReport::compare(&local, &local).is_empty());
+    }
+
+    #[test]
+    fn test_divergence_report_version_mismatch() {
+        let mut local_node_state = NodeState::default();
+        local_node_state.set("key_a", "1");
+        let mut remote_node_state = local_node_state.clone();
+        remote_node_state.mark_for_deletion("key_a");
+        let local = snapshot(vec![("node-1", local_node_state)]);
+        let remote = snapshot(vec![("node-1", remote_node_state)]);
+        let report = DivergenceReport::compare(&local, &remote);
+        assert_eq!(report.divergences.len(), 1);
+        assert_eq!(
+            report.divergences[0].kind(),
+            DivergenceKind::VersionMismatch
+        );
+        assert_eq!(
+            report.divergences[0].to_string(),
+            r#"VersionMismatch node=node-1 key=key_a local="1"@1 remote=<deleted>@2"#
+        );
+    }
+}
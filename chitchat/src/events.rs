@@ -0,0 +1,35 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use crate::NodeId;
+
+/// Callbacks invoked at the key points of the lifecycle of the cluster state, so that
+/// applications can wire logging or metrics without polling.
+///
+/// Callbacks are called with the chitchat lock held: they must be cheap and must not block.
+pub trait ChitchatEvents: Send {
+    /// Called after a delta holding `num_key_values` key-values was applied. `source_addr` is
+    /// the peer the delta was received from, `None` for the deltas merged from a snapshot.
+    fn on_delta_applied(&self, _source_addr: Option<SocketAddr>, _num_key_values: usize) {}
+
+    /// Called after the state of the node `node_id` was reset, either on the request of a peer
+    /// or because of a version anomaly. The node is then learnt again from scratch.
+    fn on_node_reset(&self, _node_id: &NodeId) {}
+
+    /// Called when the failure detector marks the node `node_id` as dead.
+    fn on_node_dead(&self, _node_id: &NodeId) {}
+
+    /// Called after the dead node `node_id` was evicted from the cluster state, see
+    /// [`crate::DeadNodeEvictionPolicy`]. Unlike [`crate::Chitchat::evicted_nodes_watcher`], it is
+    /// called for every evicted node.
+    fn on_node_evicted(&self, _node_id: &NodeId) {}
+
+    /// Called after `num_gced_tombstones` tombstones were garbage collected.
+    fn on_gc(&self, _num_gced_tombstones: usize) {}
+}
+
+impl fmt::Debug for dyn ChitchatEvents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ChitchatEvents")
+    }
+}
@@ -8,33 +8,59 @@ use mock_instant::Instant;
 use serde::{Deserialize, Serialize};
 use tracing::debug;
 
-use crate::NodeId;
+use crate::{DeadNodeEvictionPolicy, NodeId};
+
+/// Decides, from the heartbeats received from a node, whether the node is alive.
+///
+/// Chitchat uses a [`PhiAccrualFailureDetector`] by default. Another detector, e.g. with a fixed
+/// timeout, can be plugged in through [`crate::ChitchatConfig::set_failure_detector`].
+pub trait FailureDetector: Send {
+    /// Reports a heartbeat of the node.
+    fn report_heartbeat(&mut self, node_id: &NodeId);
+
+    /// Returns whether the node is alive, or `None` if no heartbeat of the node was reported
+    /// since it was last removed.
+    fn is_alive(&self, node_id: &NodeId) -> Option<bool>;
+
+    /// Same as [`FailureDetector::is_alive`], with a detection threshold multiplied by
+    /// `tolerance`, e.g. for nodes reached over slower links. Ignores the tolerance by default.
+    fn is_alive_with_tolerance(&self, node_id: &NodeId, _tolerance: f64) -> Option<bool> {
+        self.is_alive(node_id)
+    }
+
+    /// Returns the suspicion level of the node, the higher the more likely the node is dead.
+    /// Only used for observability.
+    fn phi(&self, _node_id: &NodeId) -> Option<f64> {
+        None
+    }
+
+    /// Applies new parameters on a live node. Ignored by default.
+    fn set_config(&mut self, _config: &FailureDetectorConfig) {}
+
+    /// Forgets the heartbeats of the node. Called when the node is marked dead, so that
+    /// detection starts afresh once the node comes back, and when the node is forgotten.
+    fn remove_node(&mut self, node_id: &NodeId);
+}
 
 /// A phi accrual failure detector implementation.
-pub struct FailureDetector {
+pub struct PhiAccrualFailureDetector {
     /// Heartbeat samples for each node.
     node_samples: HashMap<NodeId, SamplingWindow>,
     /// Failure detector configuration.
     config: FailureDetectorConfig,
-    /// Denotes live nodes.
-    live_nodes: HashSet<NodeId>,
-    /// Denotes dead nodes.
-    dead_nodes: HashMap<NodeId, Instant>,
 }
 
-impl FailureDetector {
+impl PhiAccrualFailureDetector {
     pub fn new(config: FailureDetectorConfig) -> Self {
         Self {
             node_samples: HashMap::new(),
             config,
-            live_nodes: HashSet::new(),
-            dead_nodes: HashMap::new(),
         }
     }
+}
 
-    /// Reports node heartbeat.
-    pub fn report_heartbeat(&mut self, node_id: &NodeId) {
-        debug!(node_id = ?node_id, "reporting node heartbeat.");
+impl FailureDetector for PhiAccrualFailureDetector {
+    fn report_heartbeat(&mut self, node_id: &NodeId) {
         let heartbeat_window = self.node_samples.entry(node_id.clone()).or_insert_with(|| {
             SamplingWindow::new(
                 self.config.sampling_window_size,
@@ -45,28 +71,137 @@ impl FailureDetector {
         heartbeat_window.report_heartbeat();
     }
 
-    /// Marks a node as dead or live.
-    pub fn update_node_liveliness(&mut self, node_id: &NodeId) {
-        if let Some(phi) = self.phi(node_id) {
-            debug!(node_id = ?node_id, phi = phi, "updating node liveliness");
-            if phi > self.config.phi_threshold {
-                self.live_nodes.remove(node_id);
-                self.dead_nodes.insert(node_id.clone(), Instant::now());
-                // Remove current sampling window so that when the node
-                // comes back online, we start with a fresh sampling window.
-                self.node_samples.remove(node_id);
-            } else {
-                self.live_nodes.insert(node_id.clone());
-                self.dead_nodes.remove(node_id);
-            }
+    fn is_alive(&self, node_id: &NodeId) -> Option<bool> {
+        self.is_alive_with_tolerance(node_id, 1.0)
+    }
+
+    fn is_alive_with_tolerance(&self, node_id: &NodeId, tolerance: f64) -> Option<bool> {
+        self.phi(node_id)
+            .map(|phi| phi <= self.config.phi_threshold * tolerance)
+    }
+
+    fn phi(&self, node_id: &NodeId) -> Option<f64> {
+        self.node_samples
+            .get(node_id)
+            .map(|sampling_window| sampling_window.phi())
+    }
+
+    /// Applies the new parameters to the sampling windows of all nodes, keeping their most
+    /// recent samples.
+    fn set_config(&mut self, config: &FailureDetectorConfig) {
+        for sampling_window in self.node_samples.values_mut() {
+            sampling_window.set_config(config);
+        }
+        self.config = config.clone();
+    }
+
+    fn remove_node(&mut self, node_id: &NodeId) {
+        self.node_samples.remove(node_id);
+    }
+}
+
+/// Keeps track of the live and dead nodes, as decided by a [`FailureDetector`].
+pub struct LivenessTracker {
+    failure_detector: Box<dyn FailureDetector>,
+    /// Threshold period after which dead node can be removed from the cluster.
+    dead_node_grace_period: Duration,
+    suspicion_timeout: Duration,
+    /// Denotes live nodes.
+    live_nodes: HashSet<NodeId>,
+    /// Denotes live nodes deemed faulty, with the time they started being suspected.
+    suspect_nodes: HashMap<NodeId, Instant>,
+    /// Denotes dead nodes.
+    dead_nodes: HashMap<NodeId, Instant>,
+}
+
+impl LivenessTracker {
+    /// Creates a liveness tracker relying on a [`PhiAccrualFailureDetector`].
+    pub fn new(config: FailureDetectorConfig) -> Self {
+        let dead_node_grace_period = config.dead_node_grace_period;
+        let suspicion_timeout = config.suspicion_timeout;
+        Self::with_failure_detector(
+            Box::new(PhiAccrualFailureDetector::new(config)),
+            dead_node_grace_period,
+            suspicion_timeout,
+        )
+    }
+
+    pub fn with_failure_detector(
+        failure_detector: Box<dyn FailureDetector>,
+        dead_node_grace_period: Duration,
+        suspicion_timeout: Duration,
+    ) -> Self {
+        Self {
+            failure_detector,
+            dead_node_grace_period,
+            suspicion_timeout,
+            live_nodes: HashSet::new(),
+            suspect_nodes: HashMap::new(),
+            dead_nodes: HashMap::new(),
+        }
+    }
+
+    /// Reports node heartbeat.
+    pub fn report_heartbeat(&mut self, node_id: &NodeId) {
+        debug!(node_id = ?node_id, "reporting node heartbeat.");
+        self.failure_detector.report_heartbeat(node_id);
+    }
+
+    pub fn set_config(&mut self, config: &FailureDetectorConfig) {
+        self.dead_node_grace_period = config.dead_node_grace_period;
+        self.suspicion_timeout = config.suspicion_timeout;
+        self.failure_detector.set_config(config);
+    }
+
+    /// Returns the current phi value of a node.
+    pub fn phi(&self, node_id: &NodeId) -> Option<f64> {
+        self.failure_detector.phi(node_id)
+    }
+
+    /// Marks a node as dead or live. See [`FailureDetector::is_alive_with_tolerance`] for
+    /// `tolerance`.
+    pub fn update_node_liveliness(&mut self, node_id: &NodeId, tolerance: f64) {
+        let Some(is_alive) = self
+            .failure_detector
+            .is_alive_with_tolerance(node_id, tolerance)
+        else {
+            return;
+        };
+        let phi = self.failure_detector.phi(node_id);
+        debug!(node_id = ?node_id, phi = ?phi, is_alive = is_alive, "updating node liveliness");
+        if is_alive {
+            self.live_nodes.insert(node_id.clone());
+            self.suspect_nodes.remove(node_id);
+            self.dead_nodes.remove(node_id);
+        } else if self.live_nodes.contains(node_id)
+            && self
+                .suspect_nodes
+                .entry(node_id.clone())
+                .or_insert_with(Instant::now)
+                .elapsed()
+                < self.suspicion_timeout
+        {
+            debug!(node_id = ?node_id, "suspecting node");
+        } else {
+            self.live_nodes.remove(node_id);
+            self.suspect_nodes.remove(node_id);
+            self.dead_nodes.insert(node_id.clone(), Instant::now());
+            // Forget the heartbeats of the node so that when the node
+            // comes back online, detection starts afresh.
+            self.failure_detector.remove_node(node_id);
         }
     }
 
-    /// Removes and returns the list of garbage collectible nodes.
-    pub fn garbage_collect(&mut self) -> Vec<NodeId> {
+    /// Removes and returns the list of garbage collectible nodes, according to the eviction
+    /// policy.
+    pub fn garbage_collect(&mut self, eviction_policy: DeadNodeEvictionPolicy) -> Vec<NodeId> {
+        let Some(eviction_delay) = eviction_policy.eviction_delay(self.dead_node_grace_period)
+        else {
+            return Vec::new();
+        };
         let mut garbage_collected_nodes = Vec::new();
         for (node_id, instant) in self.dead_nodes.iter() {
-            if instant.elapsed() >= self.config.dead_node_grace_period {
+            if instant.elapsed() >= eviction_delay {
                 garbage_collected_nodes.push(node_id.clone())
             }
         }
@@ -76,22 +211,39 @@ impl FailureDetector {
         garbage_collected_nodes
     }
 
+    /// Forgets everything about a node, e.g. because it restarted with a new generation.
+    pub fn remove_node(&mut self, node_id: &NodeId) {
+        self.failure_detector.remove_node(node_id);
+        self.live_nodes.remove(node_id);
+        self.suspect_nodes.remove(node_id);
+        self.dead_nodes.remove(node_id);
+    }
+
+    /// Hands the liveness of a node over to its new generation, e.g. after it moved to another
+    /// address: a live node stays live, instead of waiting for its new heartbeats.
+    pub fn replace_node(&mut self, previous_node_id: &NodeId, node_id: &NodeId) {
+        let was_live = self.live_nodes.contains(previous_node_id);
+        self.remove_node(previous_node_id);
+        if was_live {
+            self.failure_detector.report_heartbeat(node_id);
+            self.live_nodes.insert(node_id.clone());
+        }
+    }
+
     /// Returns a list of live nodes.
     pub fn live_nodes(&self) -> impl Iterator<Item = &NodeId> {
         self.live_nodes.iter()
     }
 
+    /// Returns the live nodes suspected to be dead.
+    pub fn suspect_nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.suspect_nodes.keys()
+    }
+
     /// Returns a list of dead nodes.
     pub fn dead_nodes(&self) -> impl Iterator<Item = &NodeId> {
         self.dead_nodes.keys()
     }
-
-    /// Returns the current phi value of a node.
-    fn phi(&mut self, node_id: &NodeId) -> Option<f64> {
-        self.node_samples
-            .get(node_id)
-            .map(|sampling_window| sampling_window.phi())
-    }
 }
 
 /// The failure detector config struct.
@@ -105,8 +257,12 @@ pub struct FailureDetectorConfig {
     pub max_interval: Duration,
     /// Initial interval used on startup when no previous heartbeat exists.
     pub initial_interval: Duration,
-    /// Threshold period after which dead node can be removed from the cluster.
+    /// Threshold period after which dead node can be removed from the cluster, see
+    /// [`crate::DeadNodeEvictionPolicy`].
     pub dead_node_grace_period: Duration,
+    /// Period during which a node deemed faulty is only suspected, before being declared dead.
+    /// Suspect nodes remain live: zero, the default, declares faulty nodes dead right away.
+    pub suspicion_timeout: Duration,
 }
 
 impl FailureDetectorConfig {
@@ -123,7 +279,23 @@ impl FailureDetectorConfig {
             max_interval,
             initial_interval,
             dead_node_grace_period,
+            suspicion_timeout: Duration::ZERO,
+        }
+    }
+
+    /// Returns an error if the phi threshold is not a positive number, or if the sampling
+    /// window is empty.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(self.phi_threshold.is_finite() && self.phi_threshold > 0.0) {
+            anyhow::bail!(
+                "Phi threshold must be a positive number, got {}.",
+                self.phi_threshold
+            );
         }
+        if self.sampling_window_size == 0 {
+            anyhow::bail!("Sampling window size must be at least 1.");
+        }
+        Ok(())
     }
 }
 
@@ -135,6 +307,7 @@ impl Default for FailureDetectorConfig {
             max_interval: Duration::from_secs(10),
             initial_interval: Duration::from_secs(5),
             dead_node_grace_period: Duration::from_secs(24 * 60 * 60), // 24 hours
+            suspicion_timeout: Duration::ZERO,
         }
     }
 }
@@ -163,6 +336,12 @@ impl SamplingWindow {
         }
     }
 
+    pub fn set_config(&mut self, config: &FailureDetectorConfig) {
+        self.intervals.resize(config.sampling_window_size);
+        self.max_interval = config.max_interval;
+        self.initial_interval = config.initial_interval;
+    }
+
     /// Reports a heartbeat.
     pub fn report_heartbeat(&mut self) {
         if let Some(last_value) = &self.last_heartbeat {
@@ -237,6 +416,26 @@ impl BoundedArrayStats {
         self.mean = self.sum / self.len() as f64;
     }
 
+    /// Changes the number of retained values, keeping the most recent ones.
+    pub fn resize(&mut self, size: usize) {
+        if size == self.size {
+            return;
+        }
+        let values: Vec<f64> = if self.is_filled {
+            self.data[self.index..]
+                .iter()
+                .chain(&self.data[..self.index])
+                .copied()
+                .collect()
+        } else {
+            self.data[..self.index].to_vec()
+        };
+        *self = BoundedArrayStats::new(size);
+        for value in &values[values.len().saturating_sub(size)..] {
+            self.append(*value);
+        }
+    }
+
     fn len(&self) -> usize {
         if self.is_filled {
             return self.size;
@@ -253,15 +452,15 @@ mod tests {
     use rand::prelude::*;
 
     use super::{BoundedArrayStats, SamplingWindow};
-    use crate::failure_detector::{FailureDetector, FailureDetectorConfig};
-    use crate::NodeId;
+    use crate::failure_detector::{FailureDetectorConfig, LivenessTracker};
+    use crate::{DeadNodeEvictionPolicy, NodeId};
 
     #[test]
     fn test_failure_detector() {
         let mut rng = rand::thread_rng();
-        let mut failure_detector = FailureDetector::new(FailureDetectorConfig::default());
+        let mut failure_detector = LivenessTracker::new(FailureDetectorConfig::default());
 
-        let intervals_choices = vec![1u64, 2];
+        let intervals_choices = [1u64, 2];
         let node_ids_choices = vec![
             NodeId::for_test_localhost(10_001),
             NodeId::for_test_localhost(10_002),
@@ -275,7 +474,7 @@ mod tests {
         }
 
         for node_id in &node_ids_choices {
-            failure_detector.update_node_liveliness(node_id);
+            failure_detector.update_node_liveliness(node_id, 1.0);
         }
 
         let mut live_nodes = failure_detector
@@ -284,12 +483,15 @@ mod tests {
             .collect::<Vec<_>>();
         live_nodes.sort_unstable();
         assert_eq!(live_nodes, vec!["node-10001", "node-10002", "node-10003"]);
-        assert_eq!(failure_detector.garbage_collect(), vec![]);
+        assert_eq!(
+            failure_detector.garbage_collect(DeadNodeEvictionPolicy::default()),
+            vec![]
+        );
 
         // stop reporting heartbeat for few seconds
         MockClock::advance(Duration::from_secs(50));
         for node_id in &node_ids_choices {
-            failure_detector.update_node_liveliness(node_id);
+            failure_detector.update_node_liveliness(node_id, 1.0);
         }
         let mut dead_nodes = failure_detector
             .dead_nodes()
@@ -297,11 +499,23 @@ mod tests {
             .collect::<Vec<_>>();
         dead_nodes.sort_unstable();
         assert_eq!(dead_nodes, vec!["node-10001", "node-10002", "node-10003"]);
-        assert_eq!(failure_detector.garbage_collect(), vec![]);
+        assert_eq!(
+            failure_detector.garbage_collect(DeadNodeEvictionPolicy::default()),
+            vec![]
+        );
 
         // Wait for dead_node_grace_period & garbage collect.
         MockClock::advance(Duration::from_secs(25 * 60 * 60));
-        let garbage_collected_nodes = failure_detector.garbage_collect();
+        assert_eq!(
+            failure_detector.garbage_collect(DeadNodeEvictionPolicy::Never),
+            vec![]
+        );
+        assert_eq!(
+            failure_detector.garbage_collect(DeadNodeEvictionPolicy::AfterGracePeriods(2)),
+            vec![]
+        );
+        let garbage_collected_nodes =
+            failure_detector.garbage_collect(DeadNodeEvictionPolicy::default());
         assert_eq!(
             failure_detector
                 .live_nodes()
@@ -330,8 +544,8 @@ mod tests {
     #[test]
     fn test_failure_detector_node_state_from_live_to_down_to_live() {
         let mut rng = rand::thread_rng();
-        let mut failure_detector = FailureDetector::new(FailureDetectorConfig::default());
-        let intervals_choices = vec![1u64, 2];
+        let mut failure_detector = LivenessTracker::new(FailureDetectorConfig::default());
+        let intervals_choices = [1u64, 2];
         let node_1 = NodeId::for_test_localhost(10_001);
 
         for _ in 0..=2000 {
@@ -340,7 +554,7 @@ mod tests {
             failure_detector.report_heartbeat(&node_1);
         }
 
-        failure_detector.update_node_liveliness(&node_1);
+        failure_detector.update_node_liveliness(&node_1, 1.0);
         assert_eq!(
             failure_detector
                 .live_nodes()
@@ -351,7 +565,7 @@ mod tests {
 
         // Check node-1 is down (stop reporting heartbeat).
         MockClock::advance(Duration::from_secs(20));
-        failure_detector.update_node_liveliness(&node_1);
+        failure_detector.update_node_liveliness(&node_1, 1.0);
         assert_eq!(
             failure_detector
                 .live_nodes()
@@ -366,7 +580,7 @@ mod tests {
             MockClock::advance(Duration::from_secs(*time_offset));
             failure_detector.report_heartbeat(&node_1);
         }
-        failure_detector.update_node_liveliness(&node_1);
+        failure_detector.update_node_liveliness(&node_1, 1.0);
         assert_eq!(
             failure_detector
                 .live_nodes()
@@ -378,13 +592,13 @@ mod tests {
 
     #[test]
     fn test_failure_detector_node_state_after_initial_interval() {
-        let mut failure_detector = FailureDetector::new(FailureDetectorConfig::default());
+        let mut failure_detector = LivenessTracker::new(FailureDetectorConfig::default());
 
         let node_id = NodeId::for_test_localhost(10_001);
         failure_detector.report_heartbeat(&node_id);
 
         MockClock::advance(Duration::from_secs(1));
-        failure_detector.update_node_liveliness(&node_id);
+        failure_detector.update_node_liveliness(&node_id, 1.0);
 
         let live_nodes = failure_detector
             .live_nodes()
@@ -392,7 +606,7 @@ mod tests {
             .collect::<Vec<_>>();
         assert_eq!(live_nodes, vec!["node-10001"]);
         MockClock::advance(Duration::from_secs(40));
-        failure_detector.update_node_liveliness(&node_id);
+        failure_detector.update_node_liveliness(&node_id, 1.0);
 
         let live_nodes = failure_detector
             .live_nodes()
@@ -401,6 +615,100 @@ mod tests {
         assert_eq!(live_nodes, Vec::<&str>::new());
     }
 
+    #[test]
+    fn test_failure_detector_set_config() {
+        let mut failure_detector = LivenessTracker::new(FailureDetectorConfig::default());
+
+        let node_id = NodeId::for_test_localhost(10_001);
+        failure_detector.report_heartbeat(&node_id);
+        MockClock::advance(Duration::from_secs(30));
+        failure_detector.update_node_liveliness(&node_id, 1.0);
+        assert!((failure_detector.phi(&node_id).unwrap() - 6.0).abs() < f64::EPSILON);
+        assert_eq!(failure_detector.live_nodes().count(), 1);
+
+        failure_detector.set_config(&FailureDetectorConfig {
+            phi_threshold: 5.0,
+            ..Default::default()
+        });
+        failure_detector.update_node_liveliness(&node_id, 1.0);
+        assert_eq!(failure_detector.live_nodes().count(), 0);
+        assert_eq!(failure_detector.dead_nodes().count(), 1);
+    }
+
+    #[test]
+    fn test_failure_detector_config_validate() {
+        assert!(FailureDetectorConfig::default().validate().is_ok());
+        for phi_threshold in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let config = FailureDetectorConfig {
+                phi_threshold,
+                ..Default::default()
+            };
+            assert!(config.validate().is_err());
+        }
+        let config = FailureDetectorConfig {
+            sampling_window_size: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_failure_detector_suspect_state() {
+        let mut failure_detector = LivenessTracker::new(FailureDetectorConfig {
+            suspicion_timeout: Duration::from_secs(10),
+            ..Default::default()
+        });
+
+        let node_id = NodeId::for_test_localhost(10_001);
+        failure_detector.report_heartbeat(&node_id);
+        MockClock::advance(Duration::from_secs(1));
+        failure_detector.update_node_liveliness(&node_id, 1.0);
+        assert_eq!(failure_detector.live_nodes().count(), 1);
+
+        MockClock::advance(Duration::from_secs(40));
+        failure_detector.update_node_liveliness(&node_id, 1.0);
+        assert_eq!(failure_detector.live_nodes().count(), 1);
+        assert_eq!(failure_detector.suspect_nodes().count(), 1);
+
+        // The node recovers before the suspicion timeout.
+        MockClock::advance(Duration::from_secs(5));
+        failure_detector.report_heartbeat(&node_id);
+        failure_detector.update_node_liveliness(&node_id, 1.0);
+        assert_eq!(failure_detector.live_nodes().count(), 1);
+        assert_eq!(failure_detector.suspect_nodes().count(), 0);
+
+        MockClock::advance(Duration::from_secs(41));
+        failure_detector.update_node_liveliness(&node_id, 1.0);
+        assert_eq!(failure_detector.suspect_nodes().count(), 1);
+        MockClock::advance(Duration::from_secs(10));
+        failure_detector.update_node_liveliness(&node_id, 1.0);
+        assert_eq!(failure_detector.live_nodes().count(), 0);
+        assert_eq!(failure_detector.suspect_nodes().count(), 0);
+        assert_eq!(failure_detector.dead_nodes().count(), 1);
+    }
+
+    #[test]
+    fn test_failure_detector_tolerance() {
+        let mut failure_detector = LivenessTracker::new(FailureDetectorConfig::default());
+
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        failure_detector.report_heartbeat(&node1);
+        failure_detector.report_heartbeat(&node2);
+        // phi = 50 / 5 = 10.
+        MockClock::advance(Duration::from_secs(50));
+        failure_detector.update_node_liveliness(&node1, 1.0);
+        failure_detector.update_node_liveliness(&node2, 2.0);
+        assert_eq!(
+            failure_detector.live_nodes().collect::<Vec<_>>(),
+            vec![&node2]
+        );
+        assert_eq!(
+            failure_detector.dead_nodes().collect::<Vec<_>>(),
+            vec![&node1]
+        );
+    }
+
     #[test]
     fn test_sampling_window() {
         let mut sampling_window =
@@ -448,5 +756,16 @@ mod tests {
         assert_eq!(bounded_array.len(), 10);
         assert!(bounded_array.is_filled);
         assert!((bounded_array.mean() - 8.5f64).abs() < f64::EPSILON);
+
+        // Shrinking keeps the most recent values.
+        bounded_array.resize(4);
+        assert_eq!(bounded_array.len(), 4);
+        assert!((bounded_array.mean() - 11.5f64).abs() < f64::EPSILON);
+
+        bounded_array.resize(6);
+        assert_eq!(bounded_array.len(), 4);
+        bounded_array.append(14.0);
+        assert_eq!(bounded_array.len(), 5);
+        assert!((bounded_array.mean() - 12.0f64).abs() < f64::EPSILON);
     }
 }
@@ -0,0 +1,385 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::authentication::AUTHENTICATION_OVERHEAD;
+use crate::serialize::Serializable;
+use crate::{Chitchat, ChitchatMessage, ClusterKey};
+
+/// Max size of a message exchanged during a full sync. Unlike UDP gossip, full syncs are not
+/// bound by the size of a datagram.
+const MAX_FULL_SYNC_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Max size of the frame carrying the gossip address of the peer.
+const MAX_ADDR_FRAME_SIZE: usize = 64 + AUTHENTICATION_OVERHEAD;
+
+/// Frames are read in chunks of this size, so that the memory allocated for a frame grows
+/// with the bytes actually received rather than with the length the peer announces.
+const FRAME_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Max duration of a full sync session, after which the connection is dropped.
+const FULL_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Max number of full syncs served at once. The connections accepted beyond are dropped.
+pub(crate) const MAX_CONCURRENT_FULL_SYNCS: usize = 4;
+
+/// A frame failed authentication with the cluster keys, or was replayed.
+#[derive(Debug)]
+struct UnauthenticatedFrame;
+
+impl fmt::Display for UnauthenticatedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Full sync frame failed authentication or was replayed.")
+    }
+}
+
+impl std::error::Error for UnauthenticatedFrame {}
+
+/// Runs a full sync with the node whose gossip address is `peer_addr`: a syn, syn ack and ack
+/// handshake over TCP, with deltas holding everything the digests tell is missing.
+///
+/// This heals the rare cases where UDP gossip fails to converge, as deltas are otherwise
+/// truncated to fit in a datagram.
+pub(crate) async fn full_sync(
+    chitchat: Arc<Mutex<Chitchat>>,
+    peer_addr: SocketAddr,
+) -> anyhow::Result<()> {
+    time::timeout(FULL_SYNC_TIMEOUT, full_sync_inner(&chitchat, peer_addr))
+        .await
+        .context("Full sync timed out.")?
+}
+
+async fn full_sync_inner(chitchat: &Mutex<Chitchat>, peer_addr: SocketAddr) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect(peer_addr)
+        .await
+        .with_context(|| format!("Failed to connect to {peer_addr}/TCP for full sync."))?;
+    let chitchat_guard = chitchat.lock().await;
+    let self_addr = chitchat_guard.self_node_id().gossip_public_address;
+    let syn = chitchat_guard.create_syn_message();
+    let cluster_key_opt = chitchat_guard.config.cluster_key.clone();
+    drop(chitchat_guard);
+    let cluster_key_opt = cluster_key_opt.as_ref();
+    write_frame(&mut stream, &self_addr, cluster_key_opt).await?;
+    write_frame(&mut stream, &syn, cluster_key_opt).await?;
+
+    let syn_ack: ChitchatMessage =
+        read_message_frame(&mut stream, chitchat, cluster_key_opt, peer_addr).await?;
+    if !matches!(
+        syn_ack,
+        ChitchatMessage::SynAck { .. } | ChitchatMessage::BadCluster
+    ) {
+        bail!("Expected a syn ack, got {syn_ack:?}.");
+    }
+    let ack_opt = chitchat.lock().await.process_message_with_max_payload_size(
+        peer_addr,
+        syn_ack,
+        MAX_FULL_SYNC_MESSAGE_SIZE,
+    );
+    if let Some(ack) = ack_opt {
+        write_frame(&mut stream, &ack, cluster_key_opt).await?;
+    }
+    Ok(())
+}
+
+/// Answers the full syncs initiated by peers.
+pub(crate) async fn serve_full_sync(
+    chitchat: Arc<Mutex<Chitchat>>,
+    stream: TcpStream,
+    stream_addr: SocketAddr,
+) {
+    let result = time::timeout(
+        FULL_SYNC_TIMEOUT,
+        serve_full_sync_inner(&chitchat, stream, stream_addr),
+    )
+    .await;
+    match result {
+        Ok(Ok(())) => debug!(stream_addr = %stream_addr, "served-full-sync"),
+        Ok(Err(error)) => {
+            warn!(stream_addr = %stream_addr, error = %error, "failed-to-serve-full-sync")
+        }
+        Err(_) => warn!(stream_addr = %stream_addr, "full-sync-timed-out"),
+    }
+}
+
+async fn serve_full_sync_inner(
+    chitchat: &Mutex<Chitchat>,
+    mut stream: TcpStream,
+    stream_addr: SocketAddr,
+) -> anyhow::Result<()> {
+    let cluster_key_opt = chitchat.lock().await.config.cluster_key.clone();
+    let cluster_key_opt = cluster_key_opt.as_ref();
+    // The gossip address of the peer, whose port the TCP connection does not tell. The peer
+    // cannot claim the address of another host, so that the denylist, the rate limiter and the
+    // replay guard apply to the host it connects from.
+    let peer_addr: SocketAddr = read_frame(
+        &mut stream,
+        chitchat,
+        cluster_key_opt,
+        stream_addr,
+        MAX_ADDR_FRAME_SIZE,
+    )
+    .await?;
+    if peer_addr.ip() != stream_addr.ip() {
+        bail!("Gossip address {peer_addr} does not match the connection address {stream_addr}.");
+    }
+    if chitchat.lock().await.denylist.is_addr_blocked(peer_addr) {
+        bail!("Peer {peer_addr} is blocked.");
+    }
+    let syn: ChitchatMessage =
+        read_message_frame(&mut stream, chitchat, cluster_key_opt, peer_addr).await?;
+    if !matches!(syn, ChitchatMessage::Syn { .. }) {
+        bail!("Expected a syn, got {syn:?}.");
+    }
+    let syn_ack_opt = chitchat.lock().await.process_message_with_max_payload_size(
+        peer_addr,
+        syn,
+        MAX_FULL_SYNC_MESSAGE_SIZE,
+    );
+    let Some(syn_ack) = syn_ack_opt else {
+        return Ok(());
+    };
+    let expects_ack = matches!(syn_ack, ChitchatMessage::SynAck { .. });
+    write_frame(&mut stream, &syn_ack, cluster_key_opt).await?;
+    if !expects_ack {
+        return Ok(());
+    }
+    let ack: ChitchatMessage =
+        read_message_frame(&mut stream, chitchat, cluster_key_opt, peer_addr).await?;
+    if !matches!(ack, ChitchatMessage::Ack { .. }) {
+        bail!("Expected an ack, got {ack:?}.");
+    }
+    chitchat.lock().await.process_message_with_max_payload_size(
+        peer_addr,
+        ack,
+        MAX_FULL_SYNC_MESSAGE_SIZE,
+    );
+    Ok(())
+}
+
+/// Accepts the next full sync connection, if full syncs are enabled. Never completes otherwise.
+pub(crate) async fn accept_opt(
+    listener_opt: Option<&TcpListener>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener_opt {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Writes `obj`, prefixed with its length. If the messages are authenticated, `obj` is sealed
+/// into an authenticated, or encrypted, message first.
+async fn write_frame<T: Serializable>(
+    stream: &mut TcpStream,
+    obj: &T,
+    cluster_key_opt: Option<&ClusterKey>,
+) -> anyhow::Result<()> {
+    let mut payload = Vec::with_capacity(obj.serialized_len());
+    obj.serialize(&mut payload);
+    if let Some(cluster_key) = cluster_key_opt {
+        let sealed_message = cluster_key.seal(&payload);
+        payload = Vec::with_capacity(sealed_message.serialized_len());
+        sealed_message.serialize(&mut payload);
+    }
+    let mut buf = Vec::with_capacity(4 + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&payload);
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Reads a frame holding a message exchanged during a full sync. See [`read_frame`].
+async fn read_message_frame(
+    stream: &mut TcpStream,
+    chitchat: &Mutex<Chitchat>,
+    cluster_key_opt: Option<&ClusterKey>,
+    peer_addr: SocketAddr,
+) -> anyhow::Result<ChitchatMessage> {
+    let max_len = MAX_FULL_SYNC_MESSAGE_SIZE + AUTHENTICATION_OVERHEAD;
+    read_frame(stream, chitchat, cluster_key_opt, peer_addr, max_len).await
+}
+
+/// Reads a frame of up to `max_len` bytes sent by `peer_addr`, within the receive rate limit
+/// of the peer. If the messages are authenticated, i.e. `cluster_key_opt` is set, the frame is
+/// opened by `chitchat`, which drops and counts the unauthenticated and replayed ones.
+async fn read_frame<T: Serializable>(
+    stream: &mut TcpStream,
+    chitchat: &Mutex<Chitchat>,
+    cluster_key_opt: Option<&ClusterKey>,
+    peer_addr: SocketAddr,
+    max_len: usize,
+) -> anyhow::Result<T> {
+    let len = stream.read_u32_le().await? as usize;
+    if len > max_len {
+        bail!("Full sync frame of {len} bytes exceeds the maximum size of {max_len} bytes.");
+    }
+    if !chitchat.lock().await.admit_received_message(peer_addr, len) {
+        bail!("Full sync frame of {len} bytes exceeds the receive rate limit of {peer_addr}.");
+    }
+    let mut buf = Vec::new();
+    while buf.len() < len {
+        let chunk_len = (len - buf.len()).min(FRAME_CHUNK_SIZE);
+        let chunk_start = buf.len();
+        buf.resize(chunk_start + chunk_len, 0);
+        stream.read_exact(&mut buf[chunk_start..]).await?;
+    }
+    if cluster_key_opt.is_none() {
+        return T::deserialize(&mut &buf[..]);
+    }
+    let mut chitchat_guard = chitchat.lock().await;
+    let Ok(sealed_message) = ChitchatMessage::deserialize(&mut &buf[..]) else {
+        chitchat_guard.record_unauthenticated_message(peer_addr);
+        return Err(UnauthenticatedFrame.into());
+    };
+    let payload = chitchat_guard
+        .open_sealed_payload(peer_addr, sealed_message)
+        .ok_or(UnauthenticatedFrame)?;
+    drop(chitchat_guard);
+    T::deserialize(&mut &payload[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::{ChitchatConfig, ReceiveRateLimit};
+
+    #[tokio::test]
+    async fn test_full_sync() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            vec![("key".to_string(), "value".to_string())],
+        );
+        // More key-values than fit in a datagram.
+        let initial_key_values = (0..100)
+            .map(|i| (format!("key-{i}"), "a".repeat(1_000)))
+            .collect();
+        let node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            initial_key_values,
+        );
+        let node1 = Arc::new(Mutex::new(node1));
+        let node2 = Arc::new(Mutex::new(node2));
+        let node1_id = node1.lock().await.self_node_id().clone();
+        let node2_id = node2.lock().await.self_node_id().clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let node2_clone = node2.clone();
+        let serve_handle = tokio::spawn(async move {
+            let (stream, stream_addr) = accept_opt(Some(&listener)).await.unwrap();
+            serve_full_sync(node2_clone, stream, stream_addr).await;
+        });
+        full_sync(node1.clone(), listen_addr).await.unwrap();
+        serve_handle.await.unwrap();
+
+        let node1_guard = node1.lock().await;
+        let node2_state = node1_guard.node_state(&node2_id).unwrap();
+        assert_eq!(node2_state.get("key-99"), Some("a".repeat(1_000).as_str()));
+        assert_eq!(node2_state.key_values.len(), 101);
+        let node2_guard = node2.lock().await;
+        let node1_state = node2_guard.node_state(&node1_id).unwrap();
+        assert_eq!(node1_state.get("key"), Some("value"));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn test_full_sync_encrypted() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let cluster_key = ClusterKey::from_bytes(b"cluster-key").with_encryption();
+        let mut config1 = ChitchatConfig::for_test(10_005);
+        config1.cluster_key = Some(cluster_key.clone());
+        let mut config2 = ChitchatConfig::for_test(10_006);
+        config2.cluster_key = Some(cluster_key);
+        let node1 = Chitchat::with_node_id_and_seeds(config1, empty_seeds.clone(), Vec::new());
+        let initial_key_values = vec![("key".to_string(), "value".to_string())];
+        let node2 = Chitchat::with_node_id_and_seeds(config2, empty_seeds, initial_key_values);
+        let node1 = Arc::new(Mutex::new(node1));
+        let node2 = Arc::new(Mutex::new(node2));
+        let node2_id = node2.lock().await.self_node_id().clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let node2_clone = node2.clone();
+        let serve_handle = tokio::spawn(async move {
+            let (stream, stream_addr) = accept_opt(Some(&listener)).await.unwrap();
+            serve_full_sync(node2_clone, stream, stream_addr).await;
+        });
+        full_sync(node1.clone(), listen_addr).await.unwrap();
+        serve_handle.await.unwrap();
+
+        let node1_guard = node1.lock().await;
+        let node2_state = node1_guard.node_state(&node2_id).unwrap();
+        assert_eq!(node2_state.get("key"), Some("value"));
+    }
+
+    #[tokio::test]
+    async fn test_full_sync_checks_peer() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut config = ChitchatConfig::for_test(10_007);
+        config.receive_rate_limit = Some(ReceiveRateLimit {
+            max_messages_per_sec: 100,
+            max_bytes_per_sec: 1_000,
+        });
+        let node = Chitchat::with_node_id_and_seeds(config, empty_seeds.clone(), Vec::new());
+        let node = Arc::new(Mutex::new(node));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+
+        // A peer cannot claim the gossip address of another host.
+        let spoofed_addr: SocketAddr = "10.0.0.1:10008".parse().unwrap();
+        let mut stream = TcpStream::connect(listen_addr).await.unwrap();
+        write_frame(&mut stream, &spoofed_addr, None).await.unwrap();
+        let (served_stream, stream_addr) = accept_opt(Some(&listener)).await.unwrap();
+        let result = serve_full_sync_inner(&node, served_stream, stream_addr).await;
+        assert!(result.unwrap_err().to_string().contains("does not match"));
+
+        // Frames are subject to the receive rate limit of the peer, before being read.
+        let mut stream = TcpStream::connect(listen_addr).await.unwrap();
+        let peer_addr: SocketAddr = "127.0.0.1:10008".parse().unwrap();
+        write_frame(&mut stream, &peer_addr, None).await.unwrap();
+        stream.write_u32_le(1_000_000).await.unwrap();
+        let (served_stream, stream_addr) = accept_opt(Some(&listener)).await.unwrap();
+        let result = serve_full_sync_inner(&node, served_stream, stream_addr).await;
+        assert!(result.unwrap_err().to_string().contains("rate limit"));
+        assert_eq!(node.lock().await.num_rate_limited_messages(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_full_sync_unauthenticated() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut config1 = ChitchatConfig::for_test(10_003);
+        config1.cluster_key = Some(ClusterKey::from_bytes(b"cluster-key"));
+        let mut config2 = ChitchatConfig::for_test(10_004);
+        config2.cluster_key = Some(ClusterKey::from_bytes(b"other-cluster-key"));
+        let node1 = Chitchat::with_node_id_and_seeds(config1, empty_seeds.clone(), Vec::new());
+        let node2 = Chitchat::with_node_id_and_seeds(config2, empty_seeds, Vec::new());
+        let node1 = Arc::new(Mutex::new(node1));
+        let node2 = Arc::new(Mutex::new(node2));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let node2_clone = node2.clone();
+        let serve_handle = tokio::spawn(async move {
+            let (stream, stream_addr) = accept_opt(Some(&listener)).await.unwrap();
+            serve_full_sync(node2_clone, stream, stream_addr).await;
+        });
+        full_sync(node1.clone(), listen_addr).await.unwrap_err();
+        serve_handle.await.unwrap();
+
+        assert_eq!(node2.lock().await.num_unauthenticated_messages(), 1);
+        let node2_id = node2.lock().await.self_node_id().clone();
+        assert!(node1.lock().await.node_state(&node2_id).is_none());
+    }
+}
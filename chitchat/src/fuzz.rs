@@ -0,0 +1,68 @@
+//! Entry points to fuzz the parsers of the messages received from peers.
+//!
+//! They run the very parsing code of production builds, so that applications can fuzz it
+//! continuously, e.g. with `cargo fuzz`:
+//!
+//! ```ignore
+//! #![no_main]
+//!
+//! use chitchat::fuzz::{parse_delta, ParseLimits};
+//!
+//! libfuzzer_sys::fuzz_target!(|bytes: &[u8]| {
+//!     let _ = parse_delta(bytes, &ParseLimits::default());
+//! });
+//! ```
+//!
+//! Like `chitchat::internal`, this module is not covered by semver, and is only available with
+//! the `fuzz` feature.
+
+pub use crate::delta::Delta;
+pub use crate::digest::{Digest, NodeDigest};
+pub use crate::message::ChitchatMessage;
+pub use crate::serialize::ParseLimits;
+use crate::serialize::Serializable;
+
+/// Parses a delta, as carried by the syn-acks and acks received from peers.
+pub fn parse_delta(bytes: &[u8], limits: &ParseLimits) -> anyhow::Result<Delta> {
+    Delta::deserialize_with_limits(&mut &bytes[..], limits)
+}
+
+/// Parses a digest, as carried by the syns and syn-acks received from peers.
+pub fn parse_digest(bytes: &[u8], limits: &ParseLimits) -> anyhow::Result<Digest> {
+    Digest::deserialize_with_limits(&mut &bytes[..], limits)
+}
+
+/// Parses a message with the default [`ParseLimits`], the bounds of the format. The datagrams
+/// received from peers are parsed with the tighter limits of
+/// [`ParseLimits::for_payload_size`].
+pub fn parse_message(bytes: &[u8]) -> anyhow::Result<ChitchatMessage> {
+    ChitchatMessage::deserialize(&mut &bytes[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NodeId;
+
+    #[test]
+    fn test_parse_mutated_messages() {
+        let mut delta = Delta::default();
+        delta.add_node_delta(NodeId::for_test_localhost(10_001), "key", "value", 1, false);
+        let message = ChitchatMessage::Ack {
+            cluster_id: "cluster".to_string(),
+            delta,
+        };
+        let buf = message.serialize_to_vec();
+        assert_eq!(parse_message(&buf).unwrap(), message);
+        // Every single byte mutation must be rejected or parsed, and never panic.
+        for i in 0..buf.len() {
+            for byte in [0u8, 1, 0x7f, 0xff] {
+                let mut mutated_buf = buf.clone();
+                mutated_buf[i] = byte;
+                let _ = parse_message(&mutated_buf);
+                let _ = parse_delta(&mutated_buf[1..], &ParseLimits::default());
+                let _ = parse_digest(&mutated_buf[1..], &ParseLimits::default());
+            }
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::AdaptiveGossipConfig;
+
+/// Growth factor of the gossip interval at every quiescent round.
+const QUIESCENT_GROWTH_FACTOR: f64 = 1.25;
+
+/// Upper bound of the jitter: a jitter of 1 or more would allow zero or negative intervals.
+const MAX_JITTER: f64 = 0.5;
+
+/// Computes the delay until the next gossip round. See [`crate::ChitchatConfig`]
+/// `gossip_interval_jitter` and [`AdaptiveGossipConfig`].
+#[derive(Debug)]
+pub(crate) struct GossipScheduler {
+    interval: Duration,
+    jitter: f64,
+    adaptive_gossip_opt: Option<AdaptiveGossipConfig>,
+}
+
+impl GossipScheduler {
+    pub fn new(
+        gossip_interval: Duration,
+        jitter: f64,
+        adaptive_gossip_opt: Option<AdaptiveGossipConfig>,
+    ) -> Self {
+        GossipScheduler {
+            interval: gossip_interval,
+            jitter: jitter.clamp(0.0, MAX_JITTER),
+            adaptive_gossip_opt,
+        }
+    }
+
+    /// Adapts the interval to the number of key-values received during the last round, and
+    /// returns the delay until the next round.
+    pub fn next_delay(&mut self, num_received_key_values: u64, rng: &mut impl Rng) -> Duration {
+        if let Some(adaptive_gossip) = &self.adaptive_gossip_opt {
+            let interval = if num_received_key_values >= adaptive_gossip.churn_threshold as u64 {
+                self.interval / 2
+            } else if num_received_key_values == 0 {
+                self.interval.mul_f64(QUIESCENT_GROWTH_FACTOR)
+            } else {
+                self.interval
+            };
+            self.interval = interval
+                .max(adaptive_gossip.min_interval)
+                .min(adaptive_gossip.max_interval);
+        }
+        if self.jitter == 0.0 {
+            return self.interval;
+        }
+        self.interval
+            .mul_f64(1.0 + rng.gen_range(-self.jitter..=self.jitter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn test_gossip_scheduler() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut scheduler = GossipScheduler::new(Duration::from_secs(1), 0.0, None);
+        assert_eq!(
+            scheduler.next_delay(1_000, &mut rng),
+            Duration::from_secs(1)
+        );
+
+        let adaptive_gossip = AdaptiveGossipConfig {
+            min_interval: Duration::from_millis(300),
+            max_interval: Duration::from_secs(2),
+            churn_threshold: 10,
+        };
+        let mut scheduler =
+            GossipScheduler::new(Duration::from_secs(1), 0.0, Some(adaptive_gossip));
+        assert_eq!(
+            scheduler.next_delay(10, &mut rng),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            scheduler.next_delay(100, &mut rng),
+            Duration::from_millis(300)
+        );
+        assert_eq!(
+            scheduler.next_delay(5, &mut rng),
+            Duration::from_millis(300)
+        );
+        assert_eq!(
+            scheduler.next_delay(0, &mut rng),
+            Duration::from_millis(375)
+        );
+        for _ in 0..10 {
+            scheduler.next_delay(0, &mut rng);
+        }
+        assert_eq!(scheduler.next_delay(0, &mut rng), Duration::from_secs(2));
+
+        let mut scheduler = GossipScheduler::new(Duration::from_secs(1), 0.1, None);
+        for _ in 0..100 {
+            let delay = scheduler.next_delay(0, &mut rng);
+            assert!(delay >= Duration::from_millis(900));
+            assert!(delay <= Duration::from_millis(1_100));
+        }
+
+        let mut scheduler = GossipScheduler::new(Duration::from_secs(1), 1.0, None);
+        for _ in 0..100 {
+            let delay = scheduler.next_delay(0, &mut rng);
+            assert!(delay >= Duration::from_millis(500));
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// Number of messages of a type, and their size on the wire.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct MessageStats {
+    pub num_messages: u64,
+    pub num_bytes: u64,
+}
+
+/// Cumulative statistics of the gossip of a node, returned by [`crate::Chitchat::gossip_stats`].
+///
+/// Messages are counted by the server as they are sent and received, by type, e.g. `syn` or
+/// `syn_ack`. Authenticated, or encrypted, messages are counted under the type of the message
+/// they carry, with their size on the wire.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct GossipStats {
+    pub sent: BTreeMap<&'static str, MessageStats>,
+    pub received: BTreeMap<&'static str, MessageStats>,
+    /// Messages dropped before being processed: rate limited, unauthenticated, replayed, or
+    /// belonging to another cluster.
+    pub num_dropped_messages: u64,
+    /// Payloads that could not be deserialized into a message by the transport.
+    pub num_corrupt_messages: u64,
+    /// Resets of node states sent to peers lagging behind the garbage collection of tombstones.
+    pub num_resets_sent: u64,
+    /// Resets of node states received from peers.
+    pub num_resets_received: u64,
+    /// Tombstones garbage collected.
+    pub num_gced_tombstones: u64,
+    /// Non-empty deltas sent to peers.
+    pub num_deltas_sent: u64,
+    /// Deltas sent to peers that had to leave stale key-values out, for lack of capacity. A
+    /// high share of truncated deltas means the MTU slows the convergence down.
+    pub num_truncated_deltas: u64,
+    /// Size of the deltas sent to peers, in bytes.
+    pub num_delta_bytes: u64,
+    /// Capacity the deltas sent to peers were given, in bytes, i.e. the MTU minus the rest of
+    /// their message.
+    pub num_delta_capacity_bytes: u64,
+}
+
+impl GossipStats {
+    /// Returns the share of their capacity the deltas sent filled on average, between 0 and 1,
+    /// or `None` if no delta was sent yet.
+    pub fn delta_utilization(&self) -> Option<f64> {
+        if self.num_delta_capacity_bytes == 0 {
+            return None;
+        }
+        Some(self.num_delta_bytes as f64 / self.num_delta_capacity_bytes as f64)
+    }
+}
+
+#[cfg(feature = "server")]
+impl GossipStats {
+    pub(crate) fn record_sent(&mut self, message_kind: &'static str, num_bytes: usize) {
+        let message_stats = self.sent.entry(message_kind).or_default();
+        message_stats.num_messages += 1;
+        message_stats.num_bytes += num_bytes as u64;
+    }
+
+    pub(crate) fn record_received(&mut self, message_kind: &'static str, num_bytes: usize) {
+        let message_stats = self.received.entry(message_kind).or_default();
+        message_stats.num_messages += 1;
+        message_stats.num_bytes += num_bytes as u64;
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+
+use anyhow::Context;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::{Chitchat, ClusterStateSnapshot, NodeId, Version};
+
+/// Entry of the digest served by `/digest`.
+#[derive(Serialize)]
+struct DigestEntry<'a> {
+    node_id: &'a NodeId,
+    max_version: Version,
+    heartbeat: u64,
+}
+
+/// Binds the debug HTTP endpoint of the node to `listen_addr`.
+pub(crate) fn bind_debug_http_listener(listen_addr: SocketAddr) -> anyhow::Result<TcpListener> {
+    let listener = TcpListener::bind(listen_addr).with_context(|| {
+        format!("Failed to bind to {listen_addr}/TCP for the debug HTTP endpoint.")
+    })?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Serves the debug HTTP endpoint of the node on `listener`, in the background:
+/// - `/state`: the snapshot of the cluster state, see [`Chitchat::cluster_state_watch`]. It is
+///   served without locking `chitchat`.
+/// - `/live_nodes`: the live nodes.
+/// - `/digest`: the max version and heartbeat of each node, as sent to peers.
+/// - `/metrics`: the statistics of the gossip, see [`Chitchat::gossip_stats`].
+/// - `/topology`: the recent gossip exchanges with the peers, see [`Chitchat::gossip_topology`].
+/// - `/topology.dot`: the same, rendered as a Graphviz DOT digraph.
+pub(crate) fn spawn_debug_http_server(
+    chitchat: Arc<Mutex<Chitchat>>,
+    cluster_state_watch: watch::Receiver<Arc<ClusterStateSnapshot>>,
+    listener: TcpListener,
+) -> anyhow::Result<JoinHandle<()>> {
+    let make_service = make_service_fn(move |_| {
+        let chitchat = chitchat.clone();
+        let cluster_state_watch = cluster_state_watch.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let chitchat = chitchat.clone();
+                let cluster_state_watch = cluster_state_watch.clone();
+                async move {
+                    Ok::<_, Infallible>(respond(&chitchat, &cluster_state_watch, request).await)
+                }
+            }))
+        }
+    });
+    let server = Server::from_tcp(listener)?.serve(make_service);
+    Ok(tokio::spawn(async move {
+        if let Err(error) = server.await {
+            warn!(error = %error, "debug-http-server-failed");
+        }
+    }))
+}
+
+async fn respond(
+    chitchat: &Mutex<Chitchat>,
+    cluster_state_watch: &watch::Receiver<Arc<ClusterStateSnapshot>>,
+    request: Request<Body>,
+) -> Response<Body> {
+    if request.method() != Method::GET {
+        return status_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    if request.uri().path() == "/state" {
+        let snapshot = cluster_state_watch.borrow().clone();
+        return json_response(serde_json::to_vec(&*snapshot));
+    }
+    let chitchat_guard = chitchat.lock().await;
+    let body_result = match request.uri().path() {
+        "/live_nodes" => serde_json::to_vec(&chitchat_guard.live_nodes().collect::<Vec<_>>()),
+        "/digest" => {
+            let dead_nodes: HashSet<&NodeId> = chitchat_guard.dead_nodes().collect();
+            let digest = chitchat_guard.compute_digest(&dead_nodes);
+            let digest_entries: Vec<DigestEntry> = digest
+                .iter()
+                .map(|(node_id, node_digest)| DigestEntry {
+                    node_id,
+                    max_version: node_digest.max_version,
+                    heartbeat: node_digest.heartbeat,
+                })
+                .collect();
+            serde_json::to_vec(&digest_entries)
+        }
+        "/metrics" => serde_json::to_vec(&chitchat_guard.gossip_stats()),
+        "/topology" => serde_json::to_vec(&chitchat_guard.gossip_topology()),
+        "/topology.dot" => {
+            let dot = chitchat_guard.gossip_topology().to_dot();
+            return Response::builder()
+                .header(header::CONTENT_TYPE, "text/vnd.graphviz")
+                .body(Body::from(dot))
+                .expect("response should be valid");
+        }
+        _ => return status_response(StatusCode::NOT_FOUND),
+    };
+    drop(chitchat_guard);
+    json_response(body_result)
+}
+
+fn json_response(body_result: serde_json::Result<Vec<u8>>) -> Response<Body> {
+    match body_result {
+        Ok(body) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .expect("response should be valid"),
+        Err(error) => {
+            warn!(error = %error, "failed-to-serialize-debug-http-response");
+            status_response(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .expect("response should be valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::ChitchatConfig;
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request =
+            format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_debug_http_server() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut chitchat = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds,
+            Vec::new(),
+        );
+        chitchat.self_node_state().set("key_a", "value_a");
+        let cluster_state_watch = chitchat.cluster_state_watch();
+        let chitchat = Arc::new(Mutex::new(chitchat));
+        let listener = bind_debug_http_listener("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let join_handle =
+            spawn_debug_http_server(chitchat.clone(), cluster_state_watch, listener).unwrap();
+
+        let response = get(addr, "/state").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("application/json"));
+        assert!(response.contains("\"value_a\""));
+        // The state is served while the chitchat instance is locked.
+        let chitchat_guard = chitchat.lock().await;
+        let response = get(addr, "/state").await;
+        assert!(response.contains("\"value_a\""));
+        drop(chitchat_guard);
+
+        let response = get(addr, "/digest").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("\"max_version\":2"));
+
+        let response = get(addr, "/live_nodes").await;
+        assert!(response.ends_with("\r\n\r\n[]"));
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.contains("\"num_dropped_messages\":0"));
+
+        let response = get(addr, "/topology").await;
+        assert!(response.ends_with("\"edges\":[]}"));
+
+        let response = get(addr, "/topology.dot").await;
+        assert!(response.ends_with("\r\n\r\ndigraph chitchat {\n}\n"));
+
+        let response = get(addr, "/unknown").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+        join_handle.abort();
+    }
+}
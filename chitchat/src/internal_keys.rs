@@ -0,0 +1,30 @@
+/// Prefix of the keys holding the metadata chitchat itself attaches to node states.
+///
+/// Applications cannot write keys with this prefix, and read them through the typed accessors of
+/// [`crate::NodeState`].
+pub const INTERNAL_KEY_PREFIX: &str = "__chitchat:";
+
+/// Generation of the node, incremented by the application upon every restart.
+pub(crate) const GENERATION_KEY: &str = "__chitchat:generation";
+
+/// Comma-separated addresses the node can be reached at, on top of its gossip address.
+pub(crate) const ADVERTISED_ADDRS_KEY: &str = "__chitchat:advertised_addrs";
+
+/// Set when the node is about to leave the cluster on purpose.
+pub(crate) const LEAVE_INTENT_KEY: &str = "__chitchat:leave_intent";
+
+/// Epoch of the leadership of the node, set when it gets elected. See
+/// [`crate::Chitchat::current_leader`].
+pub(crate) const LEADER_EPOCH_KEY: &str = "__chitchat:leader_epoch";
+
+/// JSON representation of the [`crate::NodeMetadata`] of the node.
+pub(crate) const METADATA_KEY: &str = "__chitchat:metadata";
+
+/// Time of the last application write of the node, in milliseconds since the Unix epoch, set at
+/// the version of the write. See [`crate::ChitchatConfig`] `propagation_latency_tracking`.
+pub(crate) const WRITE_TIMESTAMP_KEY: &str = "__chitchat:write_timestamp";
+
+/// Returns true if `key` is reserved to chitchat.
+pub fn is_reserved_key(key: &str) -> bool {
+    key.starts_with(INTERNAL_KEY_PREFIX)
+}
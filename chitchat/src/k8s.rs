@@ -0,0 +1,136 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use tracing::warn;
+
+use crate::seed_provider::resolve_seed_host;
+use crate::SeedProvider;
+
+/// Environment variable holding the namespace of the pod, typically set through the downward
+/// API.
+const POD_NAMESPACE_ENV_VAR: &str = "POD_NAMESPACE";
+
+/// File holding the namespace of the pod, mounted along with the service account token.
+const SERVICE_ACCOUNT_NAMESPACE_PATH: &str =
+    "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+/// Seeds the cluster with the pods of a Kubernetes headless service.
+///
+/// The DNS name of a headless service resolves to the addresses of its ready pods. Set as the
+/// seed provider, see [`crate::ChitchatConfig::set_seed_provider`], it is resolved again every
+/// `seed_refresh_interval`, which keeps the seeds current as pods are rescheduled.
+///
+/// ```yaml
+/// apiVersion: v1
+/// kind: Service
+/// metadata:
+///   name: chitchat
+/// spec:
+///   clusterIP: None
+///   publishNotReadyAddresses: true
+///   selector:
+///     app: my-app
+///   ports:
+///   - name: gossip
+///     port: 7280
+///     protocol: UDP
+/// ```
+///
+/// `publishNotReadyAddresses` lets pods find each other before they are ready.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KubernetesSeeds {
+    /// Name of the headless service.
+    pub service: String,
+    /// Namespace of the service. Defaults to the namespace of the pod, read from the
+    /// `POD_NAMESPACE` environment variable, set through the downward API, or else from the
+    /// service account.
+    pub namespace: Option<String>,
+    /// Gossip port of the pods.
+    pub port: u16,
+    pub cluster_domain: String,
+}
+
+impl KubernetesSeeds {
+    pub fn new(service: impl Into<String>, port: u16) -> Self {
+        KubernetesSeeds {
+            service: service.into(),
+            namespace: None,
+            port,
+            cluster_domain: "cluster.local".to_string(),
+        }
+    }
+
+    /// Returns the DNS name of the service, i.e.
+    /// `<service>.<namespace>.svc.<cluster domain>:<port>`, which can also be added to
+    /// [`crate::ChitchatConfig::seed_nodes`].
+    pub fn seed_node(&self) -> anyhow::Result<String> {
+        let namespace = match &self.namespace {
+            Some(namespace) => namespace.clone(),
+            None => pod_namespace(
+                std::env::var(POD_NAMESPACE_ENV_VAR).ok(),
+                Path::new(SERVICE_ACCOUNT_NAMESPACE_PATH),
+            )?,
+        };
+        Ok(format!(
+            "{}.{namespace}.svc.{}:{}",
+            self.service, self.cluster_domain, self.port
+        ))
+    }
+}
+
+#[async_trait]
+impl SeedProvider for KubernetesSeeds {
+    async fn seeds(&self) -> HashSet<SocketAddr> {
+        match self.seed_node() {
+            Ok(seed_node) => resolve_seed_host(&seed_node).await.unwrap_or_default(),
+            Err(error) => {
+                warn!(error = %error, "failed-to-locate-kubernetes-service");
+                HashSet::new()
+            }
+        }
+    }
+}
+
+fn pod_namespace(
+    pod_namespace_env_var: Option<String>,
+    service_account_namespace_path: &Path,
+) -> anyhow::Result<String> {
+    if let Some(namespace) = pod_namespace_env_var.filter(|namespace| !namespace.is_empty()) {
+        return Ok(namespace);
+    }
+    let namespace = std::fs::read_to_string(service_account_namespace_path).with_context(|| {
+        format!(
+            "Failed to read the namespace of the pod: set `{POD_NAMESPACE_ENV_VAR}` or the \
+             namespace of the seeds."
+        )
+    })?;
+    Ok(namespace.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kubernetes_seeds() {
+        let mut kubernetes_seeds = KubernetesSeeds::new("chitchat", 7280);
+        kubernetes_seeds.namespace = Some("prod".to_string());
+        assert_eq!(
+            kubernetes_seeds.seed_node().unwrap(),
+            "chitchat.prod.svc.cluster.local:7280"
+        );
+
+        let namespace_path = std::env::temp_dir().join("chitchat-test-k8s-namespace");
+        assert_eq!(
+            pod_namespace(Some("staging".to_string()), &namespace_path).unwrap(),
+            "staging"
+        );
+        std::fs::write(&namespace_path, "dev\n").unwrap();
+        assert_eq!(pod_namespace(None, &namespace_path).unwrap(), "dev");
+        std::fs::remove_file(&namespace_path).unwrap();
+        assert!(pod_namespace(Some(String::new()), &namespace_path).is_err());
+    }
+}
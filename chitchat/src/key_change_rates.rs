@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Duration of the windows over which the change rates are computed.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Rate at which a key changes, summed over all the nodes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KeyChangeRate {
+    pub key: String,
+    /// Number of new versions of the key per second.
+    pub updates_per_sec: f64,
+    /// Number of bytes of the new values of the key per second.
+    pub bytes_per_sec: f64,
+}
+
+#[derive(Debug, Default)]
+struct KeyChangeCounts {
+    num_updates: u64,
+    num_bytes: u64,
+}
+
+/// Counts the changes of each key over a sliding window, to find the keys responsible for most
+/// of the gossip traffic.
+///
+/// Rates are computed over the last complete window and the current one.
+#[derive(Debug)]
+pub(crate) struct KeyChangeRates {
+    current_window_start: Instant,
+    current_window: HashMap<String, KeyChangeCounts>,
+    previous_window: HashMap<String, KeyChangeCounts>,
+}
+
+impl Default for KeyChangeRates {
+    fn default() -> Self {
+        KeyChangeRates {
+            current_window_start: Instant::now(),
+            current_window: HashMap::new(),
+            previous_window: HashMap::new(),
+        }
+    }
+}
+
+impl KeyChangeRates {
+    pub fn record(&mut self, key: &str, value_len: usize, now: Instant) {
+        self.rotate_windows(now);
+        let counts = if let Some(counts) = self.current_window.get_mut(key) {
+            counts
+        } else {
+            self.current_window.entry(key.to_string()).or_default()
+        };
+        counts.num_updates += 1;
+        counts.num_bytes += value_len as u64;
+    }
+
+    fn rotate_windows(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.current_window_start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        if elapsed < 2 * RATE_WINDOW {
+            self.previous_window = std::mem::take(&mut self.current_window);
+            self.current_window_start += RATE_WINDOW;
+        } else {
+            self.previous_window.clear();
+            self.current_window.clear();
+            self.current_window_start = now;
+        }
+    }
+
+    /// Returns the `k` keys with the highest rates in bytes per second, in decreasing order.
+    pub fn top_k(&mut self, k: usize, now: Instant) -> Vec<KeyChangeRate> {
+        self.rotate_windows(now);
+        let elapsed_secs =
+            (RATE_WINDOW + now.saturating_duration_since(self.current_window_start)).as_secs_f64();
+        let mut total_counts: HashMap<&str, (u64, u64)> = HashMap::new();
+        for (key, counts) in self.previous_window.iter().chain(&self.current_window) {
+            let total_count = total_counts.entry(key.as_str()).or_default();
+            total_count.0 += counts.num_updates;
+            total_count.1 += counts.num_bytes;
+        }
+        let mut key_change_rates: Vec<KeyChangeRate> = total_counts
+            .into_iter()
+            .map(|(key, (num_updates, num_bytes))| KeyChangeRate {
+                key: key.to_string(),
+                updates_per_sec: num_updates as f64 / elapsed_secs,
+                bytes_per_sec: num_bytes as f64 / elapsed_secs,
+            })
+            .collect();
+        key_change_rates.sort_by(|left, right| {
+            right
+                .bytes_per_sec
+                .total_cmp(&left.bytes_per_sec)
+                .then_with(|| left.key.cmp(&right.key))
+        });
+        key_change_rates.truncate(k);
+        key_change_rates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_change_rates() {
+        let start = Instant::now();
+        let mut key_change_rates = KeyChangeRates {
+            current_window_start: start,
+            ..Default::default()
+        };
+        for _ in 0..60 {
+            key_change_rates.record("key_a", 10, start);
+        }
+        key_change_rates.record("key_b", 1_000, start);
+        key_change_rates.record("key_c", 1, start);
+
+        let top_talkers = key_change_rates.top_k(2, start + RATE_WINDOW);
+        assert_eq!(
+            top_talkers,
+            [
+                KeyChangeRate {
+                    key: "key_b".to_string(),
+                    updates_per_sec: 1.0 / 60.0,
+                    bytes_per_sec: 1_000.0 / 60.0,
+                },
+                KeyChangeRate {
+                    key: "key_a".to_string(),
+                    updates_per_sec: 1.0,
+                    bytes_per_sec: 10.0,
+                },
+            ]
+        );
+
+        // Changes older than two windows are forgotten.
+        key_change_rates.record("key_c", 1, start + 2 * RATE_WINDOW);
+        let top_talkers = key_change_rates.top_k(10, start + 2 * RATE_WINDOW);
+        assert_eq!(top_talkers.len(), 1);
+        assert_eq!(top_talkers[0].key, "key_c");
+
+        assert!(key_change_rates
+            .top_k(10, start + 10 * RATE_WINDOW)
+            .is_empty());
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::BTreeSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+/// Number of interned keys of a shard past which its keys no longer in use are first pruned.
+const MIN_PRUNE_THRESHOLD: usize = 256;
+
+/// Number of shards of the interner.
+const NUM_SHARDS: usize = 16;
+
+/// Keys interned by [`intern_key`], shared by all the chitchat instances of the process.
+///
+/// The keys are spread over shards locked independently, so that concurrent instances rarely
+/// wait on one another, and a prune only walks through the keys of one shard.
+static KEY_INTERNER: [Mutex<KeyInterner>; NUM_SHARDS] =
+    [const { Mutex::new(KeyInterner::new()) }; NUM_SHARDS];
+
+/// Returns the interned copy of `key`.
+///
+/// Nodes usually publish the same keys: interning them lets the node states, their version
+/// indexes and the deltas share a single allocation per key, instead of allocating the key
+/// again for every node and every delta received.
+pub(crate) fn intern_key(key: &str) -> Arc<str> {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let shard_idx = hasher.finish() as usize % NUM_SHARDS;
+    KEY_INTERNER[shard_idx].lock().unwrap().intern(key)
+}
+
+struct KeyInterner {
+    keys: BTreeSet<Arc<str>>,
+    prune_threshold: usize,
+}
+
+impl KeyInterner {
+    const fn new() -> Self {
+        KeyInterner {
+            keys: BTreeSet::new(),
+            prune_threshold: MIN_PRUNE_THRESHOLD,
+        }
+    }
+
+    fn intern(&mut self, key: &str) -> Arc<str> {
+        if let Some(interned_key) = self.keys.get(key) {
+            return interned_key.clone();
+        }
+        if self.keys.len() >= self.prune_threshold {
+            // The keys only referenced by the interner are not used anymore.
+            self.keys
+                .retain(|interned_key| Arc::strong_count(interned_key) > 1);
+            self.prune_threshold = (self.keys.len() * 2).max(MIN_PRUNE_THRESHOLD);
+        }
+        let interned_key: Arc<str> = Arc::from(key);
+        self.keys.insert(interned_key.clone());
+        interned_key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_interner() {
+        let mut key_interner = KeyInterner::new();
+        let key_a = key_interner.intern("key_a");
+        assert!(Arc::ptr_eq(&key_a, &key_interner.intern("key_a")));
+        assert_eq!(&*key_interner.intern("key_b"), "key_b");
+
+        // Unused keys are pruned once the threshold is reached.
+        for i in 0..MIN_PRUNE_THRESHOLD {
+            key_interner.intern(&format!("key_{i}"));
+        }
+        assert!(key_interner.keys.len() < MIN_PRUNE_THRESHOLD);
+        assert!(Arc::ptr_eq(&key_a, &key_interner.intern("key_a")));
+    }
+
+    #[test]
+    fn test_intern_key() {
+        let key_a = intern_key("key_a");
+        assert!(Arc::ptr_eq(&key_a, &intern_key("key_a")));
+        assert_eq!(&*intern_key("key_b"), "key_b");
+    }
+}
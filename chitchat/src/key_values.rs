@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use std::ops::{Bound, Deref};
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use smallvec::SmallVec;
+
+use crate::key_interner::intern_key;
+use crate::{Version, VersionedValue};
+
+/// Approximate memory used by key-values, returned by [`crate::ClusterState::memory_usage`].
+///
+/// Only the lengths of the keys and values are accounted for, not the overhead of the data
+/// structures holding them. Keys are accounted for in every node state holding them, although
+/// their allocations are shared.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct MemoryUsage {
+    /// Bytes used by the keys and values of the live key-values.
+    pub num_live_bytes: usize,
+    /// Bytes used by the keys and values of the key-values marked for deletion.
+    pub num_tombstone_bytes: usize,
+}
+
+impl MemoryUsage {
+    pub fn num_bytes(&self) -> usize {
+        self.num_live_bytes + self.num_tombstone_bytes
+    }
+
+    fn record_insert(&mut self, key: &str, versioned_value: &VersionedValue) {
+        *self.num_bytes_mut(versioned_value) += key.len() + versioned_value.value.len();
+    }
+
+    fn record_remove(&mut self, key: &str, versioned_value: &VersionedValue) {
+        *self.num_bytes_mut(versioned_value) -= key.len() + versioned_value.value.len();
+    }
+
+    fn num_bytes_mut(&mut self, versioned_value: &VersionedValue) -> &mut usize {
+        if versioned_value.marked_for_deletion {
+            &mut self.num_tombstone_bytes
+        } else {
+            &mut self.num_live_bytes
+        }
+    }
+}
+
+impl std::iter::Sum for MemoryUsage {
+    fn sum<I: Iterator<Item = MemoryUsage>>(iter: I) -> Self {
+        iter.fold(MemoryUsage::default(), |total, memory_usage| MemoryUsage {
+            num_live_bytes: total.num_live_bytes + memory_usage.num_live_bytes,
+            num_tombstone_bytes: total.num_tombstone_bytes + memory_usage.num_tombstone_bytes,
+        })
+    }
+}
+
+/// Key-values of a node state, indexed by version.
+///
+/// Dereferences to the map of the key-values. The index lets the key-values newer than a
+/// version be found without scanning the others, so that computing a delta scales with the
+/// staleness of the peer rather than with the size of the state. Key-values sharing a version
+/// were set in a single batch: most versions hold a single key.
+///
+/// Keys are interned, so that the index and the node states publishing the same keys share
+/// their allocations. The map and the index are copied on write, so that cloning a node state,
+/// e.g. to record a heartbeat while a snapshot of the cluster state shares it, does not copy its
+/// key-values.
+#[derive(Clone, Debug, Default)]
+pub struct KeyValues {
+    key_values: Arc<BTreeMap<Arc<str>, VersionedValue>>,
+    version_index: Arc<BTreeMap<Version, SmallVec<[Arc<str>; 1]>>>,
+    memory_usage: MemoryUsage,
+}
+
+impl KeyValues {
+    pub(crate) fn insert(
+        &mut self,
+        key: Arc<str>,
+        versioned_value: VersionedValue,
+    ) -> Option<VersionedValue> {
+        let version_index = Arc::make_mut(&mut self.version_index);
+        if let Some(previous_versioned_value) = self.key_values.get(&*key) {
+            unindex(version_index, &key, previous_versioned_value.version);
+            self.memory_usage
+                .record_remove(&key, previous_versioned_value);
+        }
+        version_index
+            .entry(versioned_value.version)
+            .or_default()
+            .push(key.clone());
+        self.memory_usage.record_insert(&key, &versioned_value);
+        Arc::make_mut(&mut self.key_values).insert(key, versioned_value)
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> Option<VersionedValue> {
+        if !self.key_values.contains_key(key) {
+            return None;
+        }
+        let versioned_value = Arc::make_mut(&mut self.key_values).remove(key).unwrap();
+        unindex(
+            Arc::make_mut(&mut self.version_index),
+            key,
+            versioned_value.version,
+        );
+        self.memory_usage.record_remove(key, &versioned_value);
+        Some(versioned_value)
+    }
+
+    /// Retains only the key-values for which `predicate` returns true.
+    ///
+    /// The key-values are not copied if they are all retained.
+    pub(crate) fn retain(&mut self, mut predicate: impl FnMut(&str, &VersionedValue) -> bool) {
+        let keys_to_remove: Vec<Arc<str>> = self
+            .key_values
+            .iter()
+            .filter(|(key, versioned_value)| !predicate(key, versioned_value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys_to_remove {
+            self.remove(&key);
+        }
+    }
+
+    /// Returns true if `self` and `other` share the same map of key-values, which is the case of
+    /// clones none of which got written to since.
+    #[cfg(test)]
+    pub(crate) fn ptr_eq(&self, other: &KeyValues) -> bool {
+        Arc::ptr_eq(&self.key_values, &other.key_values)
+    }
+
+    /// Returns the approximate memory used by the key-values, tracked as they change.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.memory_usage
+    }
+
+    /// Returns the key-values with a version strictly greater than `floor_version`, by
+    /// increasing version.
+    pub(crate) fn iter_newer_than(
+        &self,
+        floor_version: Version,
+    ) -> impl Iterator<Item = (&Arc<str>, &VersionedValue)> {
+        self.version_index
+            .range((Bound::Excluded(floor_version), Bound::Unbounded))
+            .flat_map(|(_, keys)| keys.iter())
+            .filter_map(|key| self.key_values.get_key_value(key))
+    }
+
+    /// Returns the key-values, leaving the map empty.
+    pub(crate) fn take(&mut self) -> BTreeMap<Arc<str>, VersionedValue> {
+        self.version_index = Arc::default();
+        self.memory_usage = MemoryUsage::default();
+        Arc::unwrap_or_clone(std::mem::take(&mut self.key_values))
+    }
+
+    /// Returns the map of the key-values.
+    pub fn into_map(self) -> BTreeMap<String, VersionedValue> {
+        Arc::unwrap_or_clone(self.key_values)
+            .into_iter()
+            .map(|(key, versioned_value)| (key.to_string(), versioned_value))
+            .collect()
+    }
+}
+
+fn unindex(
+    version_index: &mut BTreeMap<Version, SmallVec<[Arc<str>; 1]>>,
+    key: &str,
+    version: Version,
+) {
+    let Some(keys) = version_index.get_mut(&version) else {
+        return;
+    };
+    if let Some(position) = keys.iter().position(|indexed_key| &**indexed_key == key) {
+        keys.swap_remove(position);
+    }
+    if keys.is_empty() {
+        version_index.remove(&version);
+    }
+}
+
+// The index is derived from the key-values.
+impl PartialEq for KeyValues {
+    fn eq(&self, other: &Self) -> bool {
+        self.key_values == other.key_values
+    }
+}
+
+impl Eq for KeyValues {}
+
+impl Deref for KeyValues {
+    type Target = BTreeMap<Arc<str>, VersionedValue>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.key_values
+    }
+}
+
+impl FromIterator<(Arc<str>, VersionedValue)> for KeyValues {
+    fn from_iter<I: IntoIterator<Item = (Arc<str>, VersionedValue)>>(iter: I) -> Self {
+        let mut key_values = KeyValues::default();
+        for (key, versioned_value) in iter {
+            key_values.insert(key, versioned_value);
+        }
+        key_values
+    }
+}
+
+impl FromIterator<(String, VersionedValue)> for KeyValues {
+    fn from_iter<I: IntoIterator<Item = (String, VersionedValue)>>(iter: I) -> Self {
+        iter.into_iter()
+            .map(|(key, versioned_value)| (intern_key(&key), versioned_value))
+            .collect()
+    }
+}
+
+impl<'a> IntoIterator for &'a KeyValues {
+    type Item = (&'a Arc<str>, &'a VersionedValue);
+    type IntoIter = std::collections::btree_map::Iter<'a, Arc<str>, VersionedValue>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.key_values.iter()
+    }
+}
+
+impl Serialize for KeyValues {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.key_values.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyValues {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key_values = BTreeMap::<String, VersionedValue>::deserialize(deserializer)?;
+        Ok(key_values.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versioned_value(version: Version) -> VersionedValue {
+        VersionedValue {
+            value: version.to_string(),
+            version,
+            marked_for_deletion: false,
+        }
+    }
+
+    fn keys_newer_than(key_values: &KeyValues, floor_version: Version) -> Vec<&str> {
+        key_values
+            .iter_newer_than(floor_version)
+            .map(|(key, _)| &**key)
+            .collect()
+    }
+
+    #[test]
+    fn test_key_values_version_index() {
+        let mut key_values = KeyValues::default();
+        key_values.insert(intern_key("key_c"), versioned_value(1));
+        key_values.insert(intern_key("key_a"), versioned_value(2));
+        key_values.insert(intern_key("key_b"), versioned_value(2));
+        key_values.insert(intern_key("key_d"), versioned_value(3));
+        assert_eq!(
+            keys_newer_than(&key_values, 0),
+            ["key_c", "key_a", "key_b", "key_d"]
+        );
+        assert_eq!(keys_newer_than(&key_values, 2), ["key_d"]);
+
+        // Overwriting a key moves it to its new version.
+        let previous_versioned_value = key_values.insert(intern_key("key_c"), versioned_value(4));
+        assert_eq!(previous_versioned_value, Some(versioned_value(1)));
+        assert_eq!(
+            keys_newer_than(&key_values, 0),
+            ["key_a", "key_b", "key_d", "key_c"]
+        );
+
+        assert_eq!(key_values.remove("key_a"), Some(versioned_value(2)));
+        assert_eq!(key_values.remove("key_a"), None);
+        key_values.retain(|key, _| key != "key_d");
+        assert_eq!(keys_newer_than(&key_values, 0), ["key_b", "key_c"]);
+        assert_eq!(key_values.len(), 2);
+        assert_eq!(key_values.version_index.len(), 2);
+        // "key_b" and "key_c", with values "2" and "4".
+        assert_eq!(key_values.memory_usage().num_live_bytes, 12);
+        key_values.insert(
+            intern_key("key_b"),
+            VersionedValue {
+                value: String::new(),
+                version: 5,
+                marked_for_deletion: true,
+            },
+        );
+        assert_eq!(
+            key_values.memory_usage(),
+            MemoryUsage {
+                num_live_bytes: 6,
+                num_tombstone_bytes: 5,
+            }
+        );
+
+        #[cfg(feature = "json")]
+        {
+            let serialized = serde_json::to_string(&key_values).unwrap();
+            let deserialized: KeyValues = serde_json::from_str(&serialized).unwrap();
+            assert_eq!(keys_newer_than(&deserialized, 3), ["key_c", "key_b"]);
+            assert_eq!(deserialized.memory_usage(), key_values.memory_usage());
+        }
+
+        assert_eq!(key_values.take().len(), 2);
+        assert!(key_values.is_empty());
+        assert_eq!(key_values.memory_usage().num_bytes(), 0);
+        assert_eq!(keys_newer_than(&key_values, 0), Vec::<&str>::new());
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::NodeId;
+
+/// Epoch of a leadership, returned by [`crate::Chitchat::leader_epoch`].
+///
+/// The two sides of a partition may elect leaders advertising the same epoch number:
+/// leaderships are ordered by epoch, then by node ID, and must be compared as a whole for
+/// fencing.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct LeaderEpoch {
+    pub epoch: u64,
+    pub node_id: NodeId,
+}
+
+/// Elects the node with the lowest ID among the candidates, with hysteresis: a live leader
+/// only hands the leadership over to a lower candidate once that candidate has been a
+/// candidate for a while. See [`crate::LeaderElectionConfig`].
+///
+/// Only candidates settled for `hysteresis` are elected, so that a starting node learns of
+/// the current leader before electing itself. Once the leader is gone, the remaining
+/// candidates are usually settled: the failover is immediate.
+#[derive(Debug, Default)]
+pub(crate) struct LeaderElection {
+    leader_opt: Option<NodeId>,
+    /// Time since which each node has been a candidate without interruption.
+    candidate_since: HashMap<NodeId, Instant>,
+}
+
+impl LeaderElection {
+    /// Updates the leader, given the current candidates. Returns the elected node, if any.
+    pub fn elect(
+        &mut self,
+        candidates: &BTreeSet<NodeId>,
+        hysteresis: Duration,
+        now: Instant,
+    ) -> Option<&NodeId> {
+        self.candidate_since
+            .retain(|node_id, _| candidates.contains(node_id));
+        for node_id in candidates {
+            self.candidate_since.entry(node_id.clone()).or_insert(now);
+        }
+        let lowest_settled_candidate_opt = candidates.iter().find(|node_id| {
+            now.saturating_duration_since(self.candidate_since[*node_id]) >= hysteresis
+        });
+        let leader_opt = match &self.leader_opt {
+            Some(leader) if candidates.contains(leader) => match lowest_settled_candidate_opt {
+                Some(candidate) if candidate < leader => Some(candidate),
+                _ => Some(leader),
+            },
+            _ => lowest_settled_candidate_opt,
+        };
+        self.leader_opt = leader_opt.cloned();
+        self.leader_opt.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leader_election() {
+        let mut election = LeaderElection::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let node3 = NodeId::for_test_localhost(10_003);
+        let hysteresis = Duration::from_secs(10);
+        let now = Instant::now();
+        assert!(election.elect(&BTreeSet::new(), hysteresis, now).is_none());
+
+        // No leader is elected before the candidates settle.
+        let candidates = BTreeSet::from([node2.clone(), node3.clone()]);
+        assert!(election.elect(&candidates, hysteresis, now).is_none());
+        let later = now + Duration::from_secs(10);
+        assert_eq!(election.elect(&candidates, hysteresis, later), Some(&node2));
+
+        // The lower node 1 only takes over once it has been a candidate for long enough.
+        let candidates = BTreeSet::from([node1.clone(), node2.clone(), node3.clone()]);
+        let later = now + Duration::from_secs(15);
+        assert_eq!(election.elect(&candidates, hysteresis, later), Some(&node2));
+        let later = now + Duration::from_secs(25);
+        assert_eq!(election.elect(&candidates, hysteresis, later), Some(&node1));
+
+        // The leader is gone: the lowest remaining candidate is elected right away.
+        let candidates = BTreeSet::from([node3.clone()]);
+        assert_eq!(election.elect(&candidates, hysteresis, later), Some(&node3));
+
+        // Node 2 came back: it is a new candidate.
+        let candidates = BTreeSet::from([node2.clone(), node3.clone()]);
+        let later = now + Duration::from_secs(30);
+        assert_eq!(election.elect(&candidates, hysteresis, later), Some(&node3));
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::digest::Digest;
+use crate::state::ClusterState;
+
+/// Maximum number of peer addresses learnt at once.
+const MAX_LEARNED_PEER_ADDRS: usize = 64;
+
+/// Duration after which a peer address no longer listed by the digests received is forgotten.
+const LEARNED_PEER_ADDR_TTL: Duration = Duration::from_secs(60);
+
+/// Keeps track of the gossip addresses of the nodes listed by the digests of peers, but unknown
+/// locally, so that the server gossips with them without waiting for their node states.
+///
+/// The digests list all the live nodes a peer knows of, so a joining node learns the addresses
+/// of the whole cluster from any node it reaches, even when the deltas received only carry
+/// some of the node states.
+#[derive(Debug, Default)]
+pub(crate) struct LearnedPeerAddrs {
+    last_seen: HashMap<SocketAddr, Instant>,
+}
+
+impl LearnedPeerAddrs {
+    /// Records the addresses of the nodes listed by `digest` and missing from `cluster_state`.
+    /// Addresses beyond the bound of the tracker are dropped.
+    pub fn learn_from_digest(
+        &mut self,
+        digest: &Digest,
+        cluster_state: &ClusterState,
+        now: Instant,
+    ) {
+        for (node_id, _) in digest.iter() {
+            if cluster_state.node_state(node_id).is_some() {
+                continue;
+            }
+            let addr = node_id.gossip_public_address;
+            if self.last_seen.len() >= MAX_LEARNED_PEER_ADDRS && !self.last_seen.contains_key(&addr)
+            {
+                continue;
+            }
+            self.last_seen.insert(addr, now);
+        }
+    }
+
+    /// Forgets the addresses no digest listed for a while, and the ones `is_known` now knows of.
+    pub fn expire(&mut self, is_known: impl Fn(SocketAddr) -> bool, now: Instant) {
+        self.last_seen.retain(|addr, last_seen| {
+            !is_known(*addr) && now.saturating_duration_since(*last_seen) < LEARNED_PEER_ADDR_TTL
+        });
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.last_seen.keys().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::NodeId;
+
+    #[test]
+    fn test_learned_peer_addrs() {
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let mut cluster_state = ClusterState::default();
+        cluster_state.node_state_mut(&node1);
+        let mut digest = Digest::default();
+        digest.add_node(node1.clone(), 1);
+        digest.add_node(node2.clone(), 1);
+        let mut learned_peer_addrs = LearnedPeerAddrs::default();
+        let now = Instant::now();
+        learned_peer_addrs.learn_from_digest(&digest, &cluster_state, now);
+        // Known nodes are not learnt.
+        assert_eq!(
+            learned_peer_addrs.addrs().collect::<Vec<_>>(),
+            [node2.gossip_public_address]
+        );
+
+        learned_peer_addrs.expire(|_| false, now + LEARNED_PEER_ADDR_TTL / 2);
+        assert_eq!(learned_peer_addrs.addrs().count(), 1);
+        learned_peer_addrs.expire(|_| false, now + LEARNED_PEER_ADDR_TTL);
+        assert_eq!(learned_peer_addrs.addrs().count(), 0);
+
+        learned_peer_addrs.learn_from_digest(&digest, &cluster_state, now);
+        learned_peer_addrs.expire(|addr| addr == node2.gossip_public_address, now);
+        assert_eq!(learned_peer_addrs.addrs().count(), 0);
+
+        let mut digest = Digest::default();
+        for port in 0..MAX_LEARNED_PEER_ADDRS as u16 + 1 {
+            digest.add_node(NodeId::for_test_localhost(20_000 + port), 1);
+        }
+        learned_peer_addrs.learn_from_digest(&digest, &cluster_state, now);
+        let addrs: HashSet<SocketAddr> = learned_peer_addrs.addrs().collect();
+        assert_eq!(addrs.len(), MAX_LEARNED_PEER_ADDRS);
+    }
+}
@@ -1,36 +1,198 @@
 #![allow(clippy::type_complexity)]
 #![allow(clippy::derive_partial_eq_without_eq)]
 
-pub mod server;
-pub mod configuration;
-pub mod delta;
-pub mod digest;
-pub mod failure_detector;
-pub mod message;
-pub mod serialize;
-pub mod state;
+mod aggregate;
+mod audit;
+#[cfg(feature = "server")]
+mod authentication;
+mod change_journal;
+mod checkpoint;
+mod configuration;
+mod counter;
+mod delta;
+mod delta_interceptor;
+mod denylist;
+mod digest;
+mod divergence;
+mod events;
+mod failure_detector;
+#[cfg(feature = "server")]
+mod full_sync;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+#[cfg(feature = "server")]
+mod gossip_scheduler;
+mod gossip_stats;
+#[cfg(feature = "http-debug")]
+mod http_debug;
+mod internal_keys;
+#[cfg(feature = "k8s")]
+mod k8s;
+mod key_change_rates;
+mod key_interner;
+mod key_values;
+mod leader_election;
+mod learned_peers;
+#[cfg(feature = "mdns")]
+mod mdns;
+mod message;
+mod metrics;
+#[cfg(feature = "json")]
+mod node_metadata;
+mod observer;
+#[cfg(feature = "json")]
+mod or_set;
+mod partition;
+mod peer_backoff;
+mod peer_cache;
+mod probe_tracker;
+mod propagation;
+#[cfg(feature = "server")]
+mod rate_limiter;
+mod reset_tracker;
+mod rollback_fences;
+mod rtt_tracker;
+#[cfg(feature = "encryption")]
+mod sealed_keys;
+#[cfg(feature = "server")]
+mod seed_backoff;
+#[cfg(feature = "server")]
+mod seed_provider;
+mod serialize;
+#[cfg(feature = "server")]
+mod server;
+mod slow_peers;
+mod snapshot_diff;
+mod state;
+mod topology;
+#[cfg(feature = "server")]
 pub mod transport;
+mod unknown_node_tracker;
+mod views;
+mod write_acl;
+mod zones;
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+#[cfg(feature = "server")]
+use std::fmt;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use delta::Delta;
-use failure_detector::FailureDetector;
-pub use failure_detector::FailureDetectorConfig;
+use failure_detector::LivenessTracker;
+pub use failure_detector::{FailureDetector, FailureDetectorConfig, PhiAccrualFailureDetector};
 use serde::{Deserialize, Serialize};
 use tokio::sync::watch;
 use tokio_stream::wrappers::WatchStream;
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
 
-pub use self::configuration::ChitchatConfig;
-pub use self::state::{ClusterStateSnapshot, NodeState};
+pub use self::aggregate::AggFn;
+pub use self::audit::AuditRecord;
+#[cfg(feature = "server")]
+pub use self::authentication::ClusterKey;
+pub use self::change_journal::JournalEntry;
+pub use self::checkpoint::Checkpoint;
+#[cfg(feature = "encryption")]
+pub use self::checkpoint::EncryptionKey;
+#[cfg(feature = "json")]
+pub use self::configuration::PersistenceConfig;
+#[cfg(feature = "server")]
+pub use self::configuration::ReceiveRateLimit;
+pub use self::configuration::{
+    AdaptiveGossipConfig, ChitchatConfig, DeadNodeEvictionPolicy, GossipFanout,
+    LeaderElectionConfig, NodeStateLimitPolicy, NodeStateLimits, OversizedKeyValuePolicy,
+    PeerSelectionConfig, RegionAwareGossipConfig, SeedBackoffConfig, TombstoneGcPolicy,
+    TopologyConfig, WriteAfterShutdownPolicy,
+};
+pub use self::counter::PnCounter;
+pub use self::delta_interceptor::{DeltaInterceptor, Interception};
+pub use self::denylist::BlockedPeer;
+pub use self::divergence::{DivergenceKind, DivergenceReport, KeyDivergence};
+pub use self::events::ChitchatEvents;
+pub use self::gossip_stats::{GossipStats, MessageStats};
+pub use self::internal_keys::{is_reserved_key, INTERNAL_KEY_PREFIX};
+#[cfg(feature = "k8s")]
+pub use self::k8s::KubernetesSeeds;
+pub use self::key_change_rates::KeyChangeRate;
+pub use self::key_values::{KeyValues, MemoryUsage};
+#[cfg(feature = "mdns")]
+pub use self::mdns::MdnsSeeds;
+pub use self::metrics::{MetricsRecorder, PrometheusRecorder};
+#[cfg(feature = "json")]
+pub use self::node_metadata::NodeMetadata;
+pub use self::observer::{ObserverDelta, ObserverDigest, ObserverState};
+#[cfg(feature = "json")]
+pub use self::or_set::OrSet;
+pub use self::partition::{PartitionReport, UnreachableGroup};
+pub use self::peer_cache::{CachedPeer, PeerCache, RestoredPeers};
+#[cfg(feature = "encryption")]
+pub use self::sealed_keys::{SealedKeys, UnsealError};
+pub use self::slow_peers::SlowPeer;
+pub use self::snapshot_diff::{KeyChange, SnapshotDiff};
+#[cfg(feature = "json")]
+pub use self::state::TypedValueError;
+pub use self::state::{
+    ClusterState, ClusterStateSnapshot, CompareAndSetError, NodeState, NodeStateView,
+    ScopedNodeState, StateError, VersionAnomaly, WriteSource,
+};
+pub use self::topology::{GossipEdge, GossipTopology};
+pub use self::views::{ViewKind, ViewResult};
+pub use self::write_acl::{WriteAcl, WriteAclSubject};
+use crate::aggregate::AggregationCache;
+use crate::audit::PendingAuditRecords;
+#[cfg(feature = "server")]
+use crate::authentication::ReplayGuard;
+use crate::change_journal::ChangeJournal;
+use crate::denylist::Denylist;
 use crate::digest::Digest;
-use crate::message::syn_ack_serialized_len;
-pub use crate::message::ChitchatMessage;
-pub use crate::server::{spawn_chitchat, ChitchatHandle};
-use crate::state::ClusterState;
+use crate::internal_keys::WRITE_TIMESTAMP_KEY;
+use crate::leader_election::LeaderElection;
+pub use crate::leader_election::LeaderEpoch;
+use crate::learned_peers::LearnedPeerAddrs;
+use crate::message::{ack_serialized_len, syn_ack_serialized_len, ChitchatMessage};
+use crate::partition::{ReachabilityTracker, PARTITION_GROUPING_ROUNDS};
+use crate::peer_backoff::PeerBackoff;
+use crate::probe_tracker::ProbeTracker;
+use crate::propagation::PropagationWatermarks;
+#[cfg(feature = "server")]
+use crate::rate_limiter::SourceRateLimiter;
+use crate::reset_tracker::ResetTracker;
+use crate::rollback_fences::RollbackFences;
+use crate::rtt_tracker::RttTracker;
+#[cfg(feature = "server")]
+pub use crate::seed_backoff::SeedsUnreachable;
+#[cfg(feature = "server")]
+pub use crate::seed_provider::{SeedFile, SeedProvider};
+pub use crate::serialize::{ParseLimits, Serializable};
+#[cfg(feature = "server")]
+pub use crate::server::{spawn_chitchat, ChitchatHandle, ChitchatWriter};
+use crate::slow_peers::SlowPeerTracker;
+use crate::state::unix_timestamp_millis;
+use crate::topology::InteractionTracker;
+use crate::unknown_node_tracker::UnknownNodeTracker;
+use crate::views::MaterializedViews;
+use crate::zones::ZoneCache;
 
-/// Map key for the heartbeat node value.
+/// Building blocks of the protocol, exposed for tests and tooling, e.g. to craft messages by
+/// hand.
+///
+/// These types are not covered by semver: they can change in any release, and are only
+/// available with the `unstable` feature.
+#[cfg(feature = "unstable")]
+pub mod internal {
+    pub use crate::delta::{Delta, DeltaWriter, NodeDelta};
+    pub use crate::digest::{Digest, NodeDigest};
+    pub use crate::failure_detector::LivenessTracker;
+    pub use crate::message::ChitchatMessage;
+    #[cfg(feature = "server")]
+    pub use crate::transport::socket::Socket;
+}
+
+/// Map key set when the node starts, so that the node gets gossiped before the application sets
+/// any key, and written again when garbage collecting tombstones requires it. The heartbeat
+/// itself is carried in digests, see [`NodeState::heartbeat`].
 pub(crate) const HEARTBEAT_KEY: &str = "heartbeat";
 
 /// Maximum UDP datagram payload size (in bytes).
@@ -44,6 +206,13 @@ pub(crate) const HEARTBEAT_KEY: &str = "heartbeat";
 /// or so.
 const MAX_UDP_DATAGRAM_PAYLOAD_SIZE: usize = 65_507;
 
+/// Maximum total size of the deltas buffered while applies are frozen. See
+/// [`Chitchat::freeze_applies`].
+const MAX_FROZEN_DELTAS_NUM_BYTES: usize = 64 * 1024 * 1024;
+
+/// Minimum interval between two logs of the messages rejected for carrying another cluster ID.
+const CLUSTER_MISMATCH_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
 pub type Version = u64;
 
 /// [`NodeId`] represents a Chitchat Node identifier.
@@ -74,15 +243,23 @@ pub type Version = u64;
 /// `{node_unique_id}/{node_generation}/`.
 /// - node_unique_id: a static unique name for the node.
 /// - node_generation: a monotonically increasing value (timestamp on every run)
+///
 /// More details at <https://github.com/quickwit-oss/chitchat/issues/1#issuecomment-1059029051>
 ///
 /// Note: using timestamp to make the `id` dynamic has the potential of reusing
 /// a previously used `id` in cases where the clock is reset in the past. We believe this
 /// very rare and things should just work fine.
+///
+/// Alternatively, the `id` can stay the same across runs, and the `generation` be incremented
+/// on every run instead. Peers receiving the state of a new generation of a node immediately
+/// forget the states of its previous generations, and ignore the deltas about them.
 #[derive(Clone, Hash, Eq, PartialEq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 pub struct NodeId {
     // The unique identifier of this node in the cluster.
     pub id: String,
+    // Incarnation of the node, incremented upon every restart, e.g. a timestamp.
+    #[serde(default)]
+    pub generation: u64,
     // The SocketAddr other peers should use to communicate.
     pub gossip_public_address: SocketAddr,
 }
@@ -91,10 +268,16 @@ impl NodeId {
     pub fn new(id: String, gossip_public_address: SocketAddr) -> Self {
         Self {
             id,
+            generation: 0,
             gossip_public_address,
         }
     }
 
+    pub fn with_generation(mut self, generation: u64) -> Self {
+        self.generation = generation;
+        self
+    }
+
     pub fn for_test_localhost(port: u16) -> Self {
         NodeId::new(
             format!("node-{port}"),
@@ -109,6 +292,17 @@ impl NodeId {
     }
 }
 
+/// Two nodes advertising the same ID and generation, but different gossip addresses, e.g.
+/// because two processes were started with the same configuration.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NodeIdConflict {
+    /// The node with the lowest gossip address, whose state is kept. The self node is always
+    /// kept locally.
+    pub kept_node_id: NodeId,
+    /// The node whose deltas are dropped.
+    pub conflicting_node_id: NodeId,
+}
+
 /// A versioned value for a given Key-value pair.
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
 pub struct VersionedValue {
@@ -120,36 +314,269 @@ pub struct VersionedValue {
 pub struct Chitchat {
     config: ChitchatConfig,
     cluster_state: ClusterState,
-    heartbeat: u64,
     /// The failure detector instance.
-    failure_detector: FailureDetector,
+    failure_detector: LivenessTracker,
     /// A notification channel (sender) for sending live nodes change feed.
     ready_nodes_watcher_tx: watch::Sender<HashSet<NodeId>>,
     /// A notification channel (receiver) for receiving `ready` nodes change feed.
     ready_nodes_watcher_rx: watch::Receiver<HashSet<NodeId>>,
+    suspect_nodes_watcher_tx: watch::Sender<HashSet<NodeId>>,
+    suspect_nodes_watcher_rx: watch::Receiver<HashSet<NodeId>>,
+    /// Dead nodes evicted by the last garbage collection evicting any.
+    evicted_nodes_tx: watch::Sender<Vec<NodeId>>,
+    evicted_nodes_rx: watch::Receiver<Vec<NodeId>>,
+    /// Node resets sent to peers and not yet reflected in their digest.
+    reset_tracker: ResetTracker,
+    /// Nodes advertised by peers for which no data was ever received.
+    unknown_node_tracker: UnknownNodeTracker,
+    /// Peers rejecting our messages.
+    peer_backoff: PeerBackoff,
+    /// Peers blocked by the application. See [`Chitchat::block_node`].
+    denylist: Denylist,
+    /// Round-trip time estimates of the peers.
+    rtt_tracker: RttTracker,
+    /// Peers the last delta sent to had to leave stale key-values out for lack of room.
+    peers_with_delta_backlog: HashSet<SocketAddr>,
+    /// Versions of the self node acknowledged by the peers.
+    propagation_watermarks: PropagationWatermarks,
+    reachability_tracker: ReachabilityTracker,
+    /// Gossip exchanges with each peer.
+    interaction_tracker: InteractionTracker,
+    /// Lag of the digests of each peer, and resets sent to it.
+    slow_peer_tracker: SlowPeerTracker,
+    /// Nodes probed on behalf of peers. See [`Chitchat::take_probes_to_start`].
+    probe_tracker: ProbeTracker,
+    learned_peer_addrs: LearnedPeerAddrs,
+    /// Peers of the persisted peer cache. See [`Chitchat::restore_peer_cache`].
+    restored_peers: RestoredPeers,
+    rollback_fences: RollbackFences,
+    zone_cache: ZoneCache,
+    /// Time of the last heartbeat bumped by [`Chitchat::run_maintenance`].
+    heartbeat_at_opt: Option<Instant>,
+    /// Number of gossip rounds, used to schedule the gossip with the peers of other regions.
+    num_gossip_rounds: u64,
+    /// Latest changes applied to the cluster state.
+    change_journal: ChangeJournal,
+    /// Deltas received while the application of remote deltas is frozen.
+    frozen_applies: Option<FrozenApplies>,
+    /// Whether the deltas sent to peers are left empty. See [`Chitchat::pause_gossip`].
+    gossip_paused: bool,
+    num_compactions: u64,
+    num_compaction_reclaimed_bytes: u64,
+    #[cfg(feature = "server")]
+    shutdown_phase_tx: watch::Sender<ShutdownPhase>,
+    /// Incremented whenever the set of live nodes changes.
+    liveness_revision: u64,
+    live_nodes_watch_tx: watch::Sender<BTreeSet<NodeId>>,
+    live_nodes_watch_rx: watch::Receiver<BTreeSet<NodeId>>,
+    /// Revision of the cluster state last published to the cluster state watchers, `None`
+    /// while nobody watches it.
+    published_state_revision: Option<u64>,
+    cluster_state_watch_tx: watch::Sender<Arc<ClusterStateSnapshot>>,
+    leader_election: LeaderElection,
+    leader_watch_tx: watch::Sender<Option<NodeId>>,
+    leader_watch_rx: watch::Receiver<Option<NodeId>>,
+    aggregation_cache: AggregationCache,
+    materialized_views: MaterializedViews,
+    /// Last version anomaly detected. A receiver is kept so that sending never fails.
+    version_anomaly_tx: watch::Sender<Option<VersionAnomaly>>,
+    version_anomaly_rx: watch::Receiver<Option<VersionAnomaly>>,
+    num_version_anomalies: u64,
+    /// Last node ID conflict detected.
+    node_id_conflict_tx: watch::Sender<Option<NodeIdConflict>>,
+    node_id_conflict_rx: watch::Receiver<Option<NodeIdConflict>>,
+    num_node_id_conflicts: u64,
+    /// Number of messages rejected for carrying another cluster ID.
+    num_cluster_mismatches: u64,
+    num_unauthenticated_messages: u64,
+    num_replayed_messages: u64,
+    #[cfg(feature = "server")]
+    replay_guard: ReplayGuard,
+    num_rate_limited_messages: u64,
+    num_rate_limited_bytes: u64,
+    /// Payloads dropped by the transport before being deserialized, as of the last gossip
+    /// round.
+    num_transport_rate_limited_messages: u64,
+    num_transport_rate_limited_bytes: u64,
+    num_dropped_audit_records: u64,
+    num_dropped_frozen_deltas: u64,
+    #[cfg(feature = "server")]
+    source_rate_limiter: SourceRateLimiter,
+    /// Number of key-values received from peers, used to measure the churn of the cluster.
+    num_received_key_values: u64,
+    /// Statistics of the gossip, see [`Chitchat::gossip_stats`].
+    pub(crate) gossip_stats: GossipStats,
+    /// Rejected messages not logged yet, along with the time of the last log.
+    num_unlogged_cluster_mismatches: u64,
+    cluster_mismatch_logged_at_opt: Option<Instant>,
+}
+
+struct FrozenApplies {
+    /// Deadline after which deltas are applied again, even if the application did not
+    /// unfreeze.
+    deadline: Instant,
+    deltas: Vec<(Option<SocketAddr>, Delta)>,
+    /// Total serialized size of `deltas`.
+    num_bytes: usize,
+    max_num_bytes: usize,
+}
+
+#[cfg(feature = "server")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ShutdownPhase {
+    Running,
+    ShuttingDown,
+    ShutDown,
+}
+
+/// Outcome of the [`WriteAfterShutdownPolicy`] for a write.
+#[cfg(feature = "server")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum WriteAdmission {
+    Accept,
+    Reject,
+    WaitForShutdown,
+}
+
+/// Error returned by [`ChitchatWriter::try_set`] when a write is rejected because the server is
+/// shutting down.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WriteAfterShutdownError {
+    pub key: String,
+}
+
+#[cfg(feature = "server")]
+impl fmt::Display for WriteAfterShutdownError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "write of key `{}` rejected: chitchat is shutting down",
+            self.key
+        )
+    }
 }
 
+#[cfg(feature = "server")]
+impl std::error::Error for WriteAfterShutdownError {}
+
 impl Chitchat {
     pub fn with_node_id_and_seeds(
-        config: ChitchatConfig,
+        mut config: ChitchatConfig,
         seed_addrs: watch::Receiver<HashSet<SocketAddr>>,
         initial_key_values: Vec<(String, String)>,
     ) -> Self {
         let (ready_nodes_watcher_tx, ready_nodes_watcher_rx) = watch::channel(HashSet::new());
-        let failure_detector = FailureDetector::new(config.failure_detector_config.clone());
+        let (suspect_nodes_watcher_tx, suspect_nodes_watcher_rx) = watch::channel(HashSet::new());
+        let (evicted_nodes_tx, evicted_nodes_rx) = watch::channel(Vec::new());
+        let (live_nodes_watch_tx, live_nodes_watch_rx) = watch::channel(BTreeSet::new());
+        let (leader_watch_tx, leader_watch_rx) = watch::channel(None);
+        let (version_anomaly_tx, version_anomaly_rx) = watch::channel(None);
+        let (node_id_conflict_tx, node_id_conflict_rx) = watch::channel(None);
+        let failure_detector = match config.failure_detector.take() {
+            Some(failure_detector) => LivenessTracker::with_failure_detector(
+                failure_detector,
+                config.failure_detector_config.dead_node_grace_period,
+                config.failure_detector_config.suspicion_timeout,
+            ),
+            None => LivenessTracker::new(config.failure_detector_config.clone()),
+        };
+        let unknown_node_tracker = UnknownNodeTracker::new(config.unknown_node_grace_period);
+        let rollback_fences =
+            RollbackFences::new(config.failure_detector_config.dead_node_grace_period);
+        let zone_cache = ZoneCache::new(config.zone_key.clone());
+        let peer_backoff = PeerBackoff::new(config.gossip_interval);
+        let mut cluster_state = ClusterState::with_seed_addrs(seed_addrs);
+        cluster_state.node_state_limits = config.node_state_limits;
+        cluster_state.tombstone_gc_policy = config.tombstone_gc_policy;
+        cluster_state.max_version_jump = config.max_version_jump;
+        cluster_state.max_cluster_state_bytes = config.max_cluster_state_bytes;
+        cluster_state.delta_interceptor = config.delta_interceptor.take();
+        cluster_state.write_acl = config.write_acl.clone();
         let mut chitchat = Chitchat {
             config,
-            cluster_state: ClusterState::with_seed_addrs(seed_addrs),
-            heartbeat: 0,
+            cluster_state,
             failure_detector,
             ready_nodes_watcher_tx,
             ready_nodes_watcher_rx,
+            suspect_nodes_watcher_tx,
+            suspect_nodes_watcher_rx,
+            evicted_nodes_tx,
+            evicted_nodes_rx,
+            reset_tracker: ResetTracker::default(),
+            unknown_node_tracker,
+            denylist: Denylist::default(),
+            peer_backoff,
+            rtt_tracker: RttTracker::default(),
+            peers_with_delta_backlog: HashSet::new(),
+            propagation_watermarks: PropagationWatermarks::default(),
+            reachability_tracker: ReachabilityTracker::default(),
+            interaction_tracker: InteractionTracker::default(),
+            slow_peer_tracker: SlowPeerTracker::default(),
+            probe_tracker: ProbeTracker::default(),
+            learned_peer_addrs: LearnedPeerAddrs::default(),
+            restored_peers: RestoredPeers::default(),
+            rollback_fences,
+            zone_cache,
+            heartbeat_at_opt: None,
+            num_gossip_rounds: 0,
+            change_journal: ChangeJournal::default(),
+            frozen_applies: None,
+            gossip_paused: false,
+            num_compactions: 0,
+            num_compaction_reclaimed_bytes: 0,
+            #[cfg(feature = "server")]
+            shutdown_phase_tx: watch::channel(ShutdownPhase::Running).0,
+            liveness_revision: 0,
+            live_nodes_watch_tx,
+            live_nodes_watch_rx,
+            published_state_revision: None,
+            cluster_state_watch_tx: watch::channel(Arc::default()).0,
+            leader_election: LeaderElection::default(),
+            leader_watch_tx,
+            leader_watch_rx,
+            aggregation_cache: AggregationCache::default(),
+            materialized_views: MaterializedViews::default(),
+            version_anomaly_tx,
+            version_anomaly_rx,
+            num_version_anomalies: 0,
+            node_id_conflict_tx,
+            node_id_conflict_rx,
+            num_node_id_conflicts: 0,
+            num_cluster_mismatches: 0,
+            num_unauthenticated_messages: 0,
+            num_replayed_messages: 0,
+            #[cfg(feature = "server")]
+            replay_guard: ReplayGuard::default(),
+            num_rate_limited_messages: 0,
+            num_rate_limited_bytes: 0,
+            num_transport_rate_limited_messages: 0,
+            num_transport_rate_limited_bytes: 0,
+            num_dropped_audit_records: 0,
+            num_dropped_frozen_deltas: 0,
+            #[cfg(feature = "server")]
+            source_rate_limiter: SourceRateLimiter::default(),
+            num_received_key_values: 0,
+            gossip_stats: GossipStats::default(),
+            num_unlogged_cluster_mismatches: 0,
+            cluster_mismatch_logged_at_opt: None,
         };
 
+        #[cfg(feature = "encryption")]
+        let sealed_keys = chitchat.config.sealed_keys.clone().map(Arc::new);
+        let propagation_latency_tracking = chitchat.config.propagation_latency_tracking;
+        let generation = chitchat.config.node_id.generation;
         let self_node_state = chitchat.self_node_state();
+        #[cfg(feature = "encryption")]
+        self_node_state.set_sealed_keys(sealed_keys);
+        self_node_state.set_stamp_writes(propagation_latency_tracking);
 
         // Immediately mark node as alive to ensure it responds to SYNs.
-        self_node_state.set(HEARTBEAT_KEY, 0);
+        self_node_state.set_with_source(HEARTBEAT_KEY, 0, WriteSource::Internal);
+        // The generation is part of the node ID, but snapshots key node states by ID only.
+        if generation > 0 {
+            self_node_state.set_generation(generation);
+        }
 
         // Set initial key/value pairs.
         for (key, value) in initial_key_values {
@@ -159,6 +586,39 @@ impl Chitchat {
         chitchat
     }
 
+    /// Creates the serialized Syn message opening a gossip round with a peer. Report it with
+    /// [`Chitchat::record_syn_sent`] once sent.
+    ///
+    /// Together with [`Chitchat::process_payload`] and [`Chitchat::run_maintenance`], this lets
+    /// the protocol be driven over any transport, without the `server` feature.
+    pub fn create_syn_payload(&self) -> Vec<u8> {
+        self.create_syn_message().serialize_to_vec()
+    }
+
+    /// Processes a serialized message received from `from_addr`, and returns the serialized
+    /// reply to send back, if any. Payloads that cannot be parsed within the `parse_limits` of
+    /// the [`ChitchatConfig`] are dropped.
+    pub fn process_payload(&mut self, from_addr: SocketAddr, payload: &[u8]) -> Option<Vec<u8>> {
+        let parse_limits = self.config.datagram_parse_limits();
+        let message = ChitchatMessage::deserialize_with_limits(&mut &payload[..], &parse_limits)
+            .map_err(|error| {
+                warn!(payload_len = payload.len(), from = %from_addr, error = %error, "invalid-chitchat-payload");
+            })
+            .ok()?;
+        let reply = self.process_message(from_addr, message)?;
+        Some(reply.serialize_to_vec())
+    }
+
+    /// Returns the serialized probe responses to send, along with the address of the peer to
+    /// send each of them to. See [`Chitchat::take_probes_to_start`].
+    pub fn take_probe_response_payloads(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        self.take_probe_responses()
+            .into_iter()
+            .map(|(peer_addr, probe_response)| (peer_addr, probe_response.serialize_to_vec()))
+            .collect()
+    }
+
+    /// Creates the Syn message opening a gossip round with a peer.
     pub(crate) fn create_syn_message(&self) -> ChitchatMessage {
         let dead_nodes: HashSet<_> = self.dead_nodes().collect();
         let digest = self.compute_digest(&dead_nodes);
@@ -168,343 +628,4323 @@ impl Chitchat {
         }
     }
 
-    pub(crate) fn process_message(&mut self, msg: ChitchatMessage) -> Option<ChitchatMessage> {
+    /// Returns the addresses of the nodes to gossip with, i.e. to send a Syn message to, to probe
+    /// them on behalf of the peers that requested it.
+    ///
+    /// The peers are sent a probe response once the probed node replies, see
+    /// [`Chitchat::take_probe_response_payloads`].
+    pub fn take_probes_to_start(&mut self) -> Vec<SocketAddr> {
+        self.probe_tracker.take_probes_to_start()
+    }
+
+    /// Returns the probe responses to send, along with the address of the peer to send each of
+    /// them to. Probes whose node does not reply within a gossip interval get no response.
+    pub(crate) fn take_probe_responses(&mut self) -> Vec<(SocketAddr, ChitchatMessage)> {
+        self.probe_tracker
+            .take_responses_to_send()
+            .into_iter()
+            .filter_map(|(requester_addr, target)| {
+                let heartbeat = self.cluster_state.node_state(&target)?.heartbeat();
+                let probe_response = ChitchatMessage::ProbeResponse {
+                    cluster_id: self.config.cluster_id.clone(),
+                    target,
+                    heartbeat,
+                };
+                Some((requester_addr, probe_response))
+            })
+            .collect()
+    }
+
+    /// Processes a message received from `from_addr`, and returns the reply to send back, if
+    /// any.
+    pub(crate) fn process_message(
+        &mut self,
+        from_addr: SocketAddr,
+        msg: ChitchatMessage,
+    ) -> Option<ChitchatMessage> {
+        self.process_message_with_max_payload_size(from_addr, msg, MAX_UDP_DATAGRAM_PAYLOAD_SIZE)
+    }
+
+    /// Same as [`Chitchat::process_message`], with replies of up to `max_payload_size` bytes,
+    /// e.g. when the messages are exchanged over a stream rather than UDP datagrams.
+    #[cfg_attr(
+        feature = "tracing-spans",
+        tracing::instrument(name = "process-message", skip_all, fields(peer = %from_addr))
+    )]
+    pub(crate) fn process_message_with_max_payload_size(
+        &mut self,
+        from_addr: SocketAddr,
+        msg: ChitchatMessage,
+        max_payload_size: usize,
+    ) -> Option<ChitchatMessage> {
+        let reply_opt = self.handle_message(from_addr, msg, max_payload_size);
+        self.publish_cluster_state();
+        reply_opt
+    }
+
+    fn handle_message(
+        &mut self,
+        from_addr: SocketAddr,
+        msg: ChitchatMessage,
+        max_payload_size: usize,
+    ) -> Option<ChitchatMessage> {
+        if self.denylist.is_addr_blocked(from_addr) {
+            return None;
+        }
+        if let Some(cluster_id) = msg.cluster_id() {
+            if cluster_id != self.config.cluster_id {
+                self.record_cluster_mismatch(from_addr, cluster_id);
+                // Answering syns lets the peer back off.
+                return matches!(msg, ChitchatMessage::Syn { .. })
+                    .then_some(ChitchatMessage::BadCluster);
+            }
+        }
+        self.increment_counter(metrics::MESSAGES_RECEIVED_TOTAL, 1);
         match msg {
-            ChitchatMessage::Syn { cluster_id, digest } => {
-                if cluster_id != self.config.cluster_id {
-                    warn!(
-                        cluster_id = %cluster_id,
-                        "rejecting syn message with mismatching cluster name"
-                    );
-                    return Some(ChitchatMessage::BadCluster);
-                }
-                // Ensure for every reply from this node, at least the heartbeat is changed.
+            ChitchatMessage::Syn { digest, .. } => {
+                self.peer_backoff.record_acceptance(from_addr);
+                self.reachability_tracker.record_direct_contact(from_addr);
+                self.interaction_tracker
+                    .record_exchange(from_addr, false, Instant::now());
+                self.record_propagation_watermark(from_addr, &digest);
+                self.record_digest_lag(from_addr, &digest);
+                self.detect_version_rollback(from_addr, &digest);
+                self.report_digest_heartbeats(&digest);
+                self.record_advertised_max_versions(&digest);
+                self.learn_peer_addrs(&digest);
+                let nodes_to_force_reset =
+                    self.reset_tracker.unconfirmed_resets(from_addr, &digest);
                 let dead_nodes: HashSet<_> = self.dead_nodes().collect();
                 let self_digest = self.compute_digest(&dead_nodes);
                 let empty_delta = Delta::default();
-                let delta_mtu = MAX_UDP_DATAGRAM_PAYLOAD_SIZE
-                    - syn_ack_serialized_len(&self_digest, &empty_delta);
-                let delta = self.cluster_state.compute_delta(
-                    &digest,
-                    delta_mtu,
-                    dead_nodes,
-                    self.config.marked_for_deletion_grace_period,
-                );
+                let delta_mtu = max_payload_size.saturating_sub(syn_ack_serialized_len(
+                    &self.config.cluster_id,
+                    &self_digest,
+                    &empty_delta,
+                ));
+                let delta =
+                    self.compute_delta(from_addr, &digest, delta_mtu, &nodes_to_force_reset);
+                self.reset_tracker.record_sent_resets(from_addr, &delta);
+                self.gossip_stats.num_resets_sent += delta.nodes_to_reset.len() as u64;
+                self.slow_peer_tracker
+                    .record_resets_sent(from_addr, delta.nodes_to_reset.len());
                 self.report_to_failure_detector(&delta);
                 Some(ChitchatMessage::SynAck {
+                    cluster_id: self.config.cluster_id.clone(),
                     digest: self_digest,
                     delta,
                 })
             }
-            ChitchatMessage::SynAck { digest, delta } => {
+            ChitchatMessage::SynAck {
+                digest, mut delta, ..
+            } => {
+                self.peer_backoff.record_acceptance(from_addr);
+                self.rtt_tracker
+                    .record_syn_ack_received(from_addr, Instant::now());
+                self.reachability_tracker.record_direct_contact(from_addr);
+                self.interaction_tracker
+                    .record_exchange(from_addr, true, Instant::now());
+                self.record_propagation_watermark(from_addr, &digest);
+                self.record_digest_lag(from_addr, &digest);
+                self.detect_version_rollback(from_addr, &digest);
+                self.report_digest_heartbeats(&digest);
+                self.record_advertised_max_versions(&digest);
+                self.learn_peer_addrs(&digest);
+                self.probe_tracker.complete_probes(from_addr);
+                self.unknown_node_tracker.filter_delta(&mut delta);
+                self.denylist.filter_delta(&mut delta);
+                self.rollback_fences.filter_delta(from_addr, &mut delta);
+                self.forget_previous_generations(&mut delta);
+                self.drop_node_id_conflicts(&mut delta);
                 self.report_to_failure_detector(&delta);
-                self.cluster_state.apply_delta(delta);
-                let dead_nodes = self.dead_nodes().collect::<HashSet<_>>();
-                let delta = self.cluster_state.compute_delta(
-                    &digest,
-                    MAX_UDP_DATAGRAM_PAYLOAD_SIZE - 1,
-                    dead_nodes,
-                    self.config.marked_for_deletion_grace_period,
-                );
-                Some(ChitchatMessage::Ack { delta })
+                self.apply_delta(Some(from_addr), delta);
+                let nodes_to_force_reset =
+                    self.reset_tracker.unconfirmed_resets(from_addr, &digest);
+                let delta_mtu = max_payload_size.saturating_sub(ack_serialized_len(
+                    &self.config.cluster_id,
+                    &Delta::default(),
+                ));
+                let delta =
+                    self.compute_delta(from_addr, &digest, delta_mtu, &nodes_to_force_reset);
+                self.reset_tracker.record_sent_resets(from_addr, &delta);
+                self.gossip_stats.num_resets_sent += delta.nodes_to_reset.len() as u64;
+                self.slow_peer_tracker
+                    .record_resets_sent(from_addr, delta.nodes_to_reset.len());
+                Some(ChitchatMessage::Ack {
+                    cluster_id: self.config.cluster_id.clone(),
+                    delta,
+                })
             }
-            ChitchatMessage::Ack { delta } => {
+            ChitchatMessage::Ack { mut delta, .. } => {
+                self.reachability_tracker.record_direct_contact(from_addr);
+                self.unknown_node_tracker.filter_delta(&mut delta);
+                self.denylist.filter_delta(&mut delta);
+                self.rollback_fences.filter_delta(from_addr, &mut delta);
+                self.forget_previous_generations(&mut delta);
+                self.drop_node_id_conflicts(&mut delta);
                 self.report_to_failure_detector(&delta);
-                self.cluster_state.apply_delta(delta);
+                self.apply_delta(Some(from_addr), delta);
                 None
             }
             ChitchatMessage::BadCluster => {
-                warn!("message rejected by peer: cluster name mismatch");
+                // The peer belongs to another cluster. The message carries nothing to tell it
+                // from a spoofed one: only the answers to our pending syns are trusted, so that
+                // a forged message cannot make us back off from a peer of our choice.
+                let now = Instant::now();
+                if self.rtt_tracker.take_pending_syn(from_addr, now) {
+                    self.peer_backoff.record_rejection(from_addr, now);
+                } else {
+                    debug!(from_addr = %from_addr, "ignoring-unsolicited-bad-cluster");
+                }
+                None
+            }
+            ChitchatMessage::ProbeRequest { target, .. } => {
+                // Only the live nodes we know of are probed, so that a peer cannot make us send
+                // messages to an address of its choice.
+                if target != self.config.node_id
+                    && target.gossip_public_address != from_addr
+                    && self.live_nodes().any(|node_id| node_id == &target)
+                {
+                    self.probe_tracker
+                        .record_request(&target, from_addr, Instant::now());
+                }
+                None
+            }
+            ChitchatMessage::ProbeResponse {
+                target, heartbeat, ..
+            } => {
+                self.report_heartbeat(&target, heartbeat);
                 None
             }
+            // Sealed messages are opened by the server, before being processed.
+            ChitchatMessage::Authenticated { .. } | ChitchatMessage::Encrypted { .. } => None,
         }
     }
 
-    fn gc_keys_marked_for_deletion(&mut self) {
-        let dead_nodes = self.dead_nodes().cloned().collect::<HashSet<_>>();
-        self.cluster_state
-            .gc_keys_marked_for_deletion(self.config.marked_for_deletion_grace_period, &dead_nodes);
+    /// Returns true if a message of `num_bytes` received from `from_addr` is within the
+    /// `receive_rate_limit` of the address. Otherwise, the message is counted as rate limited,
+    /// and should be dropped.
+    #[cfg(feature = "server")]
+    pub(crate) fn admit_received_message(
+        &mut self,
+        from_addr: SocketAddr,
+        num_bytes: usize,
+    ) -> bool {
+        let Some(rate_limit) = &self.config.receive_rate_limit else {
+            return true;
+        };
+        if self
+            .source_rate_limiter
+            .admit(rate_limit, from_addr, num_bytes, Instant::now())
+        {
+            return true;
+        }
+        self.num_rate_limited_messages += 1;
+        self.num_rate_limited_bytes += num_bytes as u64;
+        debug!(peer_addr = %from_addr, num_bytes = num_bytes, "dropping-rate-limited-message");
+        false
     }
 
-    fn report_to_failure_detector(&mut self, delta: &Delta) {
-        for (node_id, node_delta) in &delta.node_deltas {
-            let local_max_version = self
+    /// Counts a message dropped for failing authentication.
+    #[cfg(feature = "server")]
+    pub(crate) fn record_unauthenticated_message(&mut self, from_addr: SocketAddr) {
+        self.num_unauthenticated_messages += 1;
+        debug!(peer_addr = %from_addr, "dropping-unauthenticated-message");
+    }
+
+    /// Returns the serialized payload of the authenticated, or encrypted, message received from
+    /// `from_addr`. Unauthenticated and replayed messages are dropped and counted.
+    #[cfg(feature = "server")]
+    pub(crate) fn open_sealed_payload(
+        &mut self,
+        from_addr: SocketAddr,
+        sealed_message: ChitchatMessage,
+    ) -> Option<Vec<u8>> {
+        let Some((header, payload)) =
+            authentication::open_payload(self.accepted_cluster_keys(), sealed_message)
+        else {
+            self.record_unauthenticated_message(from_addr);
+            return None;
+        };
+        if let Some(replay_window) = self.config.replay_window {
+            if !self
+                .replay_guard
+                .admit(header, replay_window, SystemTime::now())
+            {
+                self.num_replayed_messages += 1;
+                debug!(peer_addr = %from_addr, "dropping-replayed-message");
+                return None;
+            }
+        }
+        Some(payload)
+    }
+
+    /// Same as [`Chitchat::open_sealed_payload`], deserializing the message.
+    #[cfg(feature = "server")]
+    pub(crate) fn open_sealed_message(
+        &mut self,
+        from_addr: SocketAddr,
+        sealed_message: ChitchatMessage,
+    ) -> Option<ChitchatMessage> {
+        let payload = self.open_sealed_payload(from_addr, sealed_message)?;
+        let parse_limits = self.config.datagram_parse_limits();
+        let message_opt = authentication::deserialize_opened_message(&payload, &parse_limits);
+        if message_opt.is_none() {
+            self.record_unauthenticated_message(from_addr);
+        }
+        message_opt
+    }
+
+    /// Counts a message rejected for carrying another cluster ID, and logs the rejections at
+    /// most once per [`CLUSTER_MISMATCH_LOG_INTERVAL`].
+    fn record_cluster_mismatch(&mut self, from_addr: SocketAddr, cluster_id: &str) {
+        self.num_cluster_mismatches += 1;
+        self.num_unlogged_cluster_mismatches += 1;
+        let now = Instant::now();
+        if let Some(logged_at) = self.cluster_mismatch_logged_at_opt {
+            if now.duration_since(logged_at) < CLUSTER_MISMATCH_LOG_INTERVAL {
+                return;
+            }
+        }
+        warn!(
+            peer_addr = %from_addr,
+            cluster_id = %cluster_id,
+            num_rejected_messages = self.num_unlogged_cluster_mismatches,
+            "rejecting-messages-from-another-cluster"
+        );
+        self.num_unlogged_cluster_mismatches = 0;
+        self.cluster_mismatch_logged_at_opt = Some(now);
+    }
+
+    fn record_propagation_watermark(&mut self, peer_addr: SocketAddr, digest: &Digest) {
+        let Some(version) = digest.max_version(&self.config.node_id) else {
+            return;
+        };
+        if self.config.metrics_recorder.is_some() {
+            let self_max_version = self
                 .cluster_state
-                .node_states
-                .get(node_id)
+                .node_state(&self.config.node_id)
                 .map(|node_state| node_state.max_version)
                 .unwrap_or(0);
+            self.record_histogram(
+                metrics::PROPAGATION_LAG_VERSIONS,
+                self_max_version.saturating_sub(version) as f64,
+            );
+        }
+        let self_node_id = &self.config.node_id;
+        let Some((peer, _)) = digest.iter().find(|(node_id, _)| {
+            node_id.gossip_public_address == peer_addr && *node_id != self_node_id
+        }) else {
+            return;
+        };
+        if self.propagation_watermarks.record_digest(peer, version) {
+            self.publish_propagation_watermarks();
+        }
+    }
 
-            let delta_max_version = node_delta.max_version();
-            if local_max_version < delta_max_version {
-                self.failure_detector.report_heartbeat(node_id);
+    /// Records how many versions the digest of `peer_addr` lags behind our view of the live
+    /// nodes, the peer itself excluded.
+    fn record_digest_lag(&mut self, peer_addr: SocketAddr, digest: &Digest) {
+        let dead_nodes: HashSet<&NodeId> = self.dead_nodes().collect();
+        let lag_versions: u64 = self
+            .cluster_state
+            .node_states
+            .iter()
+            .filter(|(node_id, _)| {
+                node_id.gossip_public_address != peer_addr && !dead_nodes.contains(node_id)
+            })
+            .map(|(node_id, node_state)| {
+                let digest_version = digest.max_version(node_id).unwrap_or(0);
+                node_state.max_version.saturating_sub(digest_version)
+            })
+            .sum();
+        self.slow_peer_tracker
+            .record_digest(peer_addr, lag_versions);
+    }
+
+    fn publish_propagation_watermarks(&mut self) {
+        let cluster_state = &self.cluster_state;
+        self.propagation_watermarks
+            .retain_peers(|peer| cluster_state.node_state(peer).is_some());
+        let self_node_id = &self.config.node_id;
+        let live_peers = self
+            .failure_detector
+            .live_nodes()
+            .filter(|node_id| *node_id != self_node_id);
+        self.propagation_watermarks.publish(live_peers);
+    }
+
+    /// Returns a future resolving once at least `quorum` live peers acknowledged, through their
+    /// digest, the version `version` of the self node, e.g. the version of the key `key` just
+    /// written. Such peers received the key at this version or a more recent one.
+    ///
+    /// The future resolves to false right away if the key of the self node is not at `version`
+    /// or a more recent one, so that a typo in the key does not pass for a propagated write.
+    ///
+    /// The future does not borrow the node: it is meant to be awaited after releasing the
+    /// chitchat lock. It resolves to false if the node is dropped before the quorum is reached.
+    pub fn wait_key_propagated(
+        &self,
+        key: &str,
+        version: Version,
+        quorum: usize,
+    ) -> impl Future<Output = bool> + Send + 'static {
+        let key_version = self
+            .cluster_state
+            .node_state(&self.config.node_id)
+            .and_then(|node_state| node_state.get_versioned(key))
+            .map(|versioned_value| versioned_value.version);
+        let is_written = key_version.is_some_and(|key_version| key_version >= version);
+        let key = key.to_string();
+        let live_acknowledged_versions_rx = self.propagation_watermarks.subscribe();
+        async move {
+            if !is_written {
+                debug!(key = key, version = version, "key-not-written-at-version");
+                return false;
+            }
+            let propagated =
+                propagation::wait_for_quorum(live_acknowledged_versions_rx, version, quorum).await;
+            if propagated {
+                debug!(
+                    key = key,
+                    version = version,
+                    quorum = quorum,
+                    "key-propagated"
+                );
             }
+            propagated
         }
     }
 
-    /// Checks and marks nodes as dead / live / ready.
-    pub(crate) fn update_nodes_liveliness(&mut self) {
-        let cluster_nodes = self
+    /// Applies a delta received from `source_addr`, or merged from a snapshot if `None`.
+    #[cfg_attr(
+        feature = "tracing-spans",
+        tracing::instrument(
+            name = "apply-delta",
+            skip_all,
+            fields(
+                peer = ?source_addr,
+                num_key_values = delta.num_key_values(),
+                delta_bytes = delta.serialized_len(),
+            )
+        )
+    )]
+    fn apply_delta(&mut self, source_addr: Option<SocketAddr>, delta: Delta) {
+        let num_key_values = delta.num_key_values() as u64;
+        self.num_received_key_values += num_key_values;
+        if source_addr.is_some() {
+            self.gossip_stats.num_resets_received += delta.nodes_to_reset.len() as u64;
+        }
+        if self.config.metrics_recorder.is_some() {
+            self.record_histogram(metrics::DELTA_KEY_VALUES, num_key_values as f64);
+            self.record_histogram(metrics::DELTA_BYTES, delta.serialized_len() as f64);
+        }
+        self.unfreeze_applies_if_expired();
+        if let Some(frozen_applies) = &mut self.frozen_applies {
+            let num_bytes = delta.serialized_len();
+            if frozen_applies.num_bytes + num_bytes > frozen_applies.max_num_bytes {
+                // The key-values of the dropped delta are still missing from our digest: peers
+                // send them again once applies are unfrozen.
+                self.num_dropped_frozen_deltas += 1;
+                return;
+            }
+            frozen_applies.num_bytes += num_bytes;
+            frozen_applies.deltas.push((source_addr, delta));
+            return;
+        }
+        self.apply_delta_to_cluster_state(source_addr, delta);
+        self.change_journal.record_changes(&self.cluster_state);
+    }
+
+    fn apply_delta_to_cluster_state(&mut self, source_addr: Option<SocketAddr>, delta: Delta) {
+        let pending_audit_records_opt = self
+            .config
+            .audit_sink
+            .is_some()
+            .then(|| PendingAuditRecords::new(source_addr, &delta, &self.cluster_state));
+        let num_key_values = delta.num_key_values();
+        let reset_nodes: Vec<NodeId> = if self.config.events.is_some() {
+            delta
+                .nodes_to_reset
+                .iter()
+                .filter(|node_id| self.cluster_state.node_state(node_id).is_some())
+                .cloned()
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let new_write_timestamps = if self.config.metrics_recorder.is_some() {
+            self.new_write_timestamps(&delta)
+        } else {
+            Vec::new()
+        };
+        let version_anomalies = self.cluster_state.apply_delta(delta);
+        self.zone_cache.refresh(&self.cluster_state);
+        self.record_propagation_latencies(new_write_timestamps);
+        if let Some(events) = &self.config.events {
+            for node_id in &reset_nodes {
+                events.on_node_reset(node_id);
+            }
+            events.on_delta_applied(source_addr, num_key_values);
+        }
+        self.record_version_anomalies(version_anomalies);
+        let (Some(pending_audit_records), Some(audit_sink)) =
+            (pending_audit_records_opt, &self.config.audit_sink)
+        else {
+            return;
+        };
+        for audit_record in pending_audit_records.into_records(&self.cluster_state) {
+            // The sink must never slow the gossip down: records that do not fit are dropped.
+            if audit_sink.try_send(audit_record).is_err() {
+                self.num_dropped_audit_records += 1;
+            }
+        }
+    }
+
+    /// Resets the state of the peer `from_addr` if its digest advertises a max version of
+    /// itself lower than the one known locally.
+    fn detect_version_rollback(&mut self, from_addr: SocketAddr, digest: &Digest) {
+        let Some((node_id, advertised_max_version)) =
+            digest.iter().find_map(|(node_id, node_digest)| {
+                (node_id.gossip_public_address == from_addr && *node_id != self.config.node_id)
+                    .then_some((node_id, node_digest.max_version))
+            })
+        else {
+            return;
+        };
+        let Some(node_state) = self.cluster_state.node_state(node_id) else {
+            return;
+        };
+        if advertised_max_version >= node_state.max_version {
+            return;
+        }
+        warn!(
+            node_id = ?node_id,
+            known_max_version = node_state.max_version,
+            advertised_max_version = advertised_max_version,
+            "resetting-node-with-rolled-back-version"
+        );
+        let known_max_version = node_state.max_version;
+        let version_anomaly = VersionAnomaly::Rollback {
+            node_id: node_id.clone(),
+            known_max_version,
+            advertised_max_version,
+        };
+        // Resetting the node forgets everything tracked about it, fences included.
+        self.record_version_anomalies(vec![version_anomaly]);
+        self.rollback_fences
+            .fence(node_id, known_max_version, Instant::now());
+    }
+
+    fn record_version_anomalies(&mut self, version_anomalies: Vec<VersionAnomaly>) {
+        for version_anomaly in version_anomalies {
+            if version_anomaly.requires_reset() {
+                self.forget_node(version_anomaly.node_id());
+                if let Some(events) = &self.config.events {
+                    events.on_node_reset(version_anomaly.node_id());
+                }
+            }
+            self.num_version_anomalies += 1;
+            // A receiver is held by `self`: sending cannot fail.
+            let _ = self.version_anomaly_tx.send(Some(version_anomaly));
+        }
+    }
+
+    /// Stops applying the deltas received from peers, so that the cluster state stays stable
+    /// while the application performs a read-compute-write sequence. Deltas are buffered
+    /// meanwhile, and applied at once by [`Chitchat::unfreeze_applies`].
+    ///
+    /// As buffering blocks the convergence of the node, deltas are applied again after
+    /// `max_duration` if the application does not unfreeze earlier. Freezing again extends the
+    /// deadline. Deltas beyond 64MiB of buffered deltas are dropped, and counted by
+    /// [`Chitchat::num_dropped_frozen_deltas`]: peers send their key-values again later.
+    pub fn freeze_applies(&mut self, max_duration: Duration) {
+        let deadline = Instant::now() + max_duration;
+        match &mut self.frozen_applies {
+            Some(frozen_applies) => frozen_applies.deadline = deadline,
+            None => {
+                self.frozen_applies = Some(FrozenApplies {
+                    deadline,
+                    deltas: Vec::new(),
+                    num_bytes: 0,
+                    max_num_bytes: MAX_FROZEN_DELTAS_NUM_BYTES,
+                })
+            }
+        }
+    }
+
+    /// Applies the deltas buffered since [`Chitchat::freeze_applies`], and resumes applying the
+    /// deltas as they are received.
+    pub fn unfreeze_applies(&mut self) {
+        let Some(frozen_applies) = self.frozen_applies.take() else {
+            return;
+        };
+        debug!(num_deltas = frozen_applies.deltas.len(), "unfreeze-applies");
+        for (source_addr, delta) in frozen_applies.deltas {
+            self.apply_delta_to_cluster_state(source_addr, delta);
+        }
+        self.change_journal.record_changes(&self.cluster_state);
+    }
+
+    /// Returns true if the deltas received from peers are buffered instead of being applied.
+    pub fn applies_frozen(&self) -> bool {
+        self.frozen_applies.is_some()
+    }
+
+    fn unfreeze_applies_if_expired(&mut self) {
+        if self
+            .frozen_applies
+            .as_ref()
+            .is_some_and(|frozen_applies| Instant::now() >= frozen_applies.deadline)
+        {
+            warn!("unfreezing-applies-after-timeout");
+            self.unfreeze_applies();
+        }
+    }
+
+    /// Stops disseminating the cluster state: the deltas sent to peers are left empty, while
+    /// digests keep carrying the heartbeats, so that the node stays live. Deltas received from
+    /// peers are still applied.
+    ///
+    /// Local writes are applied to the self node state meanwhile, and disseminated once
+    /// [`Chitchat::resume_gossip`] is called.
+    pub fn pause_gossip(&mut self) {
+        if !self.gossip_paused {
+            info!("pausing-gossip");
+            self.gossip_paused = true;
+        }
+    }
+
+    /// Resumes the dissemination of the cluster state, paused by [`Chitchat::pause_gossip`].
+    pub fn resume_gossip(&mut self) {
+        if self.gossip_paused {
+            info!("resuming-gossip");
+            self.gossip_paused = false;
+        }
+    }
+
+    pub fn is_gossip_paused(&self) -> bool {
+        self.gossip_paused
+    }
+
+    /// Computes the delta to send to the peer `peer_addr`, whose digest is `digest`. Empty while
+    /// gossip is paused.
+    #[cfg_attr(
+        feature = "tracing-spans",
+        tracing::instrument(
+            name = "compute-delta",
+            skip_all,
+            fields(mtu = mtu, num_key_values, delta_bytes)
+        )
+    )]
+    fn compute_delta(
+        &mut self,
+        peer_addr: SocketAddr,
+        digest: &Digest,
+        mtu: usize,
+        nodes_to_force_reset: &HashSet<NodeId>,
+    ) -> Delta {
+        if self.gossip_paused {
+            return Delta::default();
+        }
+        let dead_nodes = self.dead_nodes().collect::<HashSet<_>>();
+        let computed_delta = self.cluster_state.compute_delta_with_truncation(
+            digest,
+            mtu,
+            dead_nodes,
+            self.config.marked_for_deletion_grace_period,
+            self.config.oversized_key_value_policy,
+            nodes_to_force_reset,
+        );
+        if !computed_delta.delta.is_empty() {
+            self.record_delta_sent(computed_delta.num_bytes, mtu, computed_delta.truncated);
+        }
+        if computed_delta.truncated {
+            self.peers_with_delta_backlog.insert(peer_addr);
+        } else {
+            self.peers_with_delta_backlog.remove(&peer_addr);
+        }
+        #[cfg(feature = "tracing-spans")]
+        {
+            let span = tracing::Span::current();
+            span.record("num_key_values", computed_delta.delta.num_key_values());
+            span.record("delta_bytes", computed_delta.num_bytes);
+        }
+        computed_delta.delta
+    }
+
+    /// Records how much of its capacity of `mtu` bytes a delta of `delta_bytes` bytes about to
+    /// be sent filled.
+    fn record_delta_sent(&mut self, delta_bytes: usize, mtu: usize, truncated: bool) {
+        self.gossip_stats.num_deltas_sent += 1;
+        self.gossip_stats.num_delta_bytes += delta_bytes as u64;
+        self.gossip_stats.num_delta_capacity_bytes += mtu as u64;
+        if truncated {
+            self.gossip_stats.num_truncated_deltas += 1;
+            self.increment_counter(metrics::TRUNCATED_DELTAS_TOTAL, 1);
+        }
+        if mtu > 0 {
+            self.record_histogram(metrics::DELTA_UTILIZATION, delta_bytes as f64 / mtu as f64);
+        }
+    }
+
+    /// Returns false if the peer at `peer_addr` is blocked, or keeps rejecting our messages and
+    /// must not be contacted until its backoff elapses.
+    pub fn can_gossip_with(&self, peer_addr: SocketAddr) -> bool {
+        !self.denylist.is_addr_blocked(peer_addr)
+            && self.peer_backoff.can_contact(peer_addr, Instant::now())
+    }
+
+    /// Blocks a peer, designated by its node ID or its gossip address: its messages are dropped,
+    /// it is not gossiped with anymore, and its state is removed, including when relayed by
+    /// other peers.
+    ///
+    /// This is meant for emergencies, e.g. a misbehaving node spamming bogus deltas that cannot
+    /// be taken offline right away. The self node cannot be blocked.
+    pub fn block_node(&mut self, peer: impl Into<BlockedPeer>) {
+        let peer = peer.into();
+        let self_node_id = &self.config.node_id;
+        let blocks_self = match &peer {
+            BlockedPeer::NodeId(node_id) => node_id.id == self_node_id.id,
+            BlockedPeer::Addr(addr) => *addr == self_node_id.gossip_public_address,
+        };
+        if blocks_self {
+            warn!(peer = ?peer, "refusing-to-block-self-node");
+            return;
+        }
+        warn!(peer = ?peer, "blocking-peer");
+        self.denylist.block(&peer);
+        let blocked_node_ids: Vec<NodeId> = self
             .cluster_state
             .nodes()
-            .filter(|&node_id| node_id != self.self_node_id())
-            .collect::<Vec<_>>();
-        for &node_id in &cluster_nodes {
-            self.failure_detector.update_node_liveliness(node_id);
+            .filter(|node_id| self.denylist.is_node_blocked(node_id))
+            .cloned()
+            .collect();
+        for node_id in &blocked_node_ids {
+            self.forget_node(node_id);
         }
+    }
 
-        let ready_nodes_before = self.ready_nodes_watcher_rx.borrow().clone();
-        let ready_nodes_after = self.ready_nodes().cloned().collect::<HashSet<_>>();
+    /// Unblocks a peer blocked by [`Chitchat::block_node`]. Its state comes back with the next
+    /// gossip rounds. Node IDs and gossip addresses are unblocked independently: a peer blocked
+    /// both ways stays blocked until both are unblocked.
+    pub fn unblock_node(&mut self, peer: impl Into<BlockedPeer>) {
+        let peer = peer.into();
+        info!(peer = ?peer, "unblocking-peer");
+        self.denylist.unblock(&peer);
+    }
 
-        if ready_nodes_before != ready_nodes_after {
-            debug!(current_node = ?self.self_node_id(), live_nodes = ?ready_nodes_after, "nodes status changed");
-            if self.ready_nodes_watcher_tx.send(ready_nodes_after).is_err() {
-                error!(current_node = ?self.self_node_id(), "error while reporting membership change event.")
-            }
+    /// Records that a syn was sent to `peer_addr`, to estimate its round-trip time and its
+    /// reachability upon receiving its syn ack. A peer of another cluster is only backed off
+    /// from when it rejects a syn recorded here.
+    pub fn record_syn_sent(&mut self, peer_addr: SocketAddr) {
+        self.rtt_tracker.record_syn_sent(peer_addr, Instant::now());
+        self.reachability_tracker.record_syn_sent(peer_addr);
+    }
+
+    /// Returns the smoothed round-trip time to `peer_addr`, if it answered one of our syns.
+    pub fn peer_rtt(&self, peer_addr: SocketAddr) -> Option<Duration> {
+        self.rtt_tracker.rtt(peer_addr)
+    }
+
+    /// Returns true if `node_id` is located in another region than the self node, according to
+    /// the [`RegionAwareGossipConfig`]. Always false if region-aware gossip is disabled.
+    pub fn is_cross_region_peer(&self, node_id: &NodeId) -> bool {
+        let Some(region_aware_gossip) = &self.config.region_aware_gossip else {
+            return false;
+        };
+        if self.node_zone(self.self_node_id()).is_some() && self.node_zone(node_id).is_some() {
+            return self.is_cross_zone_peer(node_id);
         }
+        self.peer_rtt(node_id.gossip_public_address)
+            .is_some_and(|rtt| rtt > region_aware_gossip.rtt_threshold)
+    }
 
-        // Perform garbage collection.
-        let garbage_collected_nodes = self.failure_detector.garbage_collect();
-        for node_id in garbage_collected_nodes.iter() {
-            self.cluster_state.remove_node(node_id);
+    /// Starts a new gossip round, and returns the addresses of the peers located in other
+    /// regions if they must not be gossiped with during this round.
+    ///
+    /// The peers of other regions the last delta sent to had to leave stale key-values out are
+    /// gossiped with at every round, until the updates batched in the meantime are drained.
+    pub fn start_gossip_round(&mut self) -> HashSet<SocketAddr> {
+        self.zone_cache.refresh(&self.cluster_state);
+        let known_addrs: HashSet<SocketAddr> = self
+            .cluster_state
+            .nodes()
+            .map(|node_id| node_id.gossip_public_address)
+            .collect();
+        self.learned_peer_addrs
+            .expire(|addr| known_addrs.contains(&addr), Instant::now());
+        if !self.restored_peers.peers.is_empty() {
+            self.restored_peers
+                .quarantine_conflicting_peers(&self.cluster_state);
+        }
+        self.num_gossip_rounds += 1;
+        self.increment_counter(metrics::GOSSIP_ROUNDS_TOTAL, 1);
+        let Some(region_aware_gossip) = &self.config.region_aware_gossip else {
+            return HashSet::new();
+        };
+        let round_interval = region_aware_gossip
+            .cross_region_gossip_round_interval
+            .max(1) as u64;
+        if self.num_gossip_rounds.is_multiple_of(round_interval) {
+            return HashSet::new();
         }
+        self.cluster_state
+            .nodes()
+            .filter(|node_id| self.is_cross_region_peer(node_id))
+            .map(|node_id| node_id.gossip_public_address)
+            .filter(|peer_addr| !self.peers_with_delta_backlog.contains(peer_addr))
+            .collect()
     }
 
-    pub fn node_state(&self, node_id: &NodeId) -> Option<&NodeState> {
-        self.cluster_state.node_state(node_id)
+    /// Returns the peers that keep rejecting our messages, e.g. because they belong to another
+    /// cluster. They are most likely misconfigured: they are still contacted, but with
+    /// exponentially increasing delays.
+    pub fn peers_rejecting_us(&self) -> HashSet<SocketAddr> {
+        self.peer_backoff.rejecting_peers().collect()
     }
 
-    pub fn self_node_state(&mut self) -> &mut NodeState {
-        self.cluster_state.node_state_mut(&self.config.node_id)
+    /// Runs the periodic tasks of a gossip round: bumps the heartbeat, expires keys and garbage
+    /// collects the tombstones, unknown nodes, and churn of the node states.
+    ///
+    /// Should be called once per gossip interval, before gossiping. With adaptive gossip, the
+    /// heartbeat keeps the pace of `gossip_interval` instead of the pace of the rounds: the
+    /// failure detectors of the peers would otherwise learn the intervals of the fastest rounds,
+    /// and declare the node dead as soon as the rounds slow down.
+    pub fn run_maintenance(&mut self) {
+        self.update_heartbeat_if_due(Instant::now());
+        self.expire_keys();
+        self.gc_keys_marked_for_deletion();
+        self.gc_unknown_nodes();
+        self.compact_node_states();
+        self.unfreeze_applies_if_expired();
+        self.probe_tracker
+            .expire(self.config.gossip_interval, Instant::now());
+        self.rollback_fences
+            .lift_fences(&self.cluster_state, Instant::now());
+        #[cfg(feature = "server")]
+        self.source_rate_limiter.prune(Instant::now());
+        self.change_journal.record_changes(&self.cluster_state);
+        self.publish_cluster_state();
     }
 
-    /// Retrieves the list of all live nodes.
-    pub fn live_nodes(&self) -> impl Iterator<Item = &NodeId> {
-        self.failure_detector.live_nodes()
+    fn gc_keys_marked_for_deletion(&mut self) {
+        let dead_nodes = self.dead_nodes().cloned().collect::<HashSet<_>>();
+        let num_key_values_before_gc = self.num_key_values();
+        match self.config.tombstone_gc_policy {
+            TombstoneGcPolicy::WallClock => self
+                .cluster_state
+                .gc_expired_tombstones(self.config.tombstone_grace_period, &dead_nodes),
+            TombstoneGcPolicy::VersionCount => self.cluster_state.gc_keys_marked_for_deletion(
+                self.config.marked_for_deletion_grace_period,
+                &dead_nodes,
+            ),
+        }
+        // The peers that missed the tombstones are reset, as they lag behind the gc watermark.
+        // Version counts, on the other hand, cannot tell them apart from the others.
+        if self.config.tombstone_gc_policy == TombstoneGcPolicy::WallClock
+            && self.cluster_state.exceeds_memory_limit()
+        {
+            let num_key_values_before_forced_gc = self.num_key_values();
+            self.cluster_state
+                .gc_expired_tombstones(Duration::ZERO, &dead_nodes);
+            let num_forced_gced_tombstones =
+                num_key_values_before_forced_gc - self.num_key_values();
+            if num_forced_gced_tombstones > 0 {
+                warn!(
+                    num_gced_tombstones = num_forced_gced_tombstones,
+                    "garbage-collecting-tombstones-over-memory-limit"
+                );
+            }
+        }
+        let num_gced_tombstones = num_key_values_before_gc - self.num_key_values();
+        self.gossip_stats.num_gced_tombstones += num_gced_tombstones as u64;
+        if num_gced_tombstones > 0 {
+            self.increment_counter(metrics::TOMBSTONES_GC_TOTAL, num_gced_tombstones as u64);
+            if let Some(events) = &self.config.events {
+                events.on_gc(num_gced_tombstones);
+            }
+        }
+        // A peer reset to the self node state only catches up with the garbage collected
+        // tombstones if a key-value is newer than them. As the heartbeat does not bump the max
+        // version, the heartbeat key is written again when needed.
+        let Some(self_node_state) = self.cluster_state.node_state(&self.config.node_id) else {
+            return;
+        };
+        if self_node_state.is_max_version_garbage_collected() {
+            let heartbeat = self_node_state.heartbeat();
+            self.self_node_state()
+                .set_with_source(HEARTBEAT_KEY, heartbeat, WriteSource::Internal);
+        }
     }
 
-    /// Retrieves the list of nodes that are ready.
-    /// To be ready, a node has to be alive and pass the `is_ready_predicate` as
-    /// defined in the Chitchat configuration.
-    pub fn ready_nodes(&self) -> impl Iterator<Item = &NodeId> {
-        self.live_nodes().filter(|&node_id| {
-            let is_ready_pred = if let Some(pred) = self.config.is_ready_predicate.as_ref() {
-                pred
-            } else {
-                // No predicate means that we consider all nodes as ready.
-                return true;
+    /// Returns the write timestamps carried by `delta` that are newer than the ones known
+    /// locally, with their node and version.
+    fn new_write_timestamps(&self, delta: &Delta) -> Vec<(NodeId, Version, u64)> {
+        let mut new_write_timestamps = Vec::new();
+        for (node_id, node_delta) in &delta.node_deltas {
+            if *node_id == self.config.node_id {
+                continue;
+            }
+            let Some(versioned_value) = node_delta.key_values.get(WRITE_TIMESTAMP_KEY) else {
+                continue;
             };
-            self.node_state(node_id).map(is_ready_pred).unwrap_or(false)
-        })
+            let Ok(timestamp_millis) = versioned_value.value.parse() else {
+                continue;
+            };
+            let known_version = self
+                .cluster_state
+                .node_state(node_id)
+                .and_then(NodeState::write_timestamp)
+                .map(|(version, _)| version)
+                .unwrap_or(0);
+            if versioned_value.version > known_version {
+                new_write_timestamps.push((
+                    node_id.clone(),
+                    versioned_value.version,
+                    timestamp_millis,
+                ));
+            }
+        }
+        new_write_timestamps
+    }
+
+    /// Records the time it took the writes stamped with `write_timestamps` to reach us, for the
+    /// timestamps that were applied.
+    fn record_propagation_latencies(&self, write_timestamps: Vec<(NodeId, Version, u64)>) {
+        if write_timestamps.is_empty() {
+            return;
+        }
+        let now_millis = unix_timestamp_millis(SystemTime::now());
+        for (node_id, version, timestamp_millis) in write_timestamps {
+            let applied_version = self
+                .cluster_state
+                .node_state(&node_id)
+                .and_then(NodeState::write_timestamp)
+                .map(|(version, _)| version);
+            if applied_version != Some(version) {
+                continue;
+            }
+            let latency_millis = now_millis.saturating_sub(timestamp_millis);
+            self.record_histogram(
+                metrics::PROPAGATION_LATENCY_SECONDS,
+                latency_millis as f64 / 1_000.0,
+            );
+        }
     }
 
-    /// Retrieve the list of all dead nodes.
-    pub fn dead_nodes(&self) -> impl Iterator<Item = &NodeId> {
-        self.failure_detector.dead_nodes()
+    /// Returns the number of key-values of the cluster state, tombstones included.
+    fn num_key_values(&self) -> usize {
+        self.cluster_state
+            .node_states
+            .values()
+            .map(|node_state| node_state.key_values.len())
+            .sum()
+    }
+
+    fn increment_counter(&self, name: &'static str, value: u64) {
+        if let Some(metrics_recorder) = &self.config.metrics_recorder {
+            metrics_recorder.increment_counter(name, value);
+        }
+    }
+
+    fn record_histogram(&self, name: &'static str, value: f64) {
+        if let Some(metrics_recorder) = &self.config.metrics_recorder {
+            metrics_recorder.record_histogram(name, value);
+        }
+    }
+
+    /// Removes the nodes advertised by peers for which no data was received within
+    /// `unknown_node_grace_period`.
+    pub(crate) fn gc_unknown_nodes(&mut self) {
+        let self_node_id = &self.config.node_id;
+        let unknown_nodes = self
+            .cluster_state
+            .node_states
+            .iter()
+            .filter(|(node_id, node_state)| *node_id != self_node_id && node_state.max_version == 0)
+            .map(|(node_id, _)| node_id);
+        let nodes_to_forget = self
+            .unknown_node_tracker
+            .nodes_to_forget(unknown_nodes, Instant::now());
+        for node_id in &nodes_to_forget {
+            self.cluster_state.remove_node(node_id);
+            self.reset_tracker.remove_node(node_id);
+        }
+    }
+
+    /// Compacts the node states with a lot of churn, if compaction is enabled.
+    pub(crate) fn compact_node_states(&mut self) {
+        let Some(churn_threshold) = self.config.compaction_churn_threshold else {
+            return;
+        };
+        let (num_compacted_node_states, num_reclaimed_bytes) =
+            self.cluster_state.compact_node_states(churn_threshold);
+        if num_compacted_node_states > 0 {
+            debug!(
+                num_compacted_node_states = num_compacted_node_states,
+                num_reclaimed_bytes = num_reclaimed_bytes,
+                "compacted-node-states"
+            );
+        }
+        self.num_compactions += num_compacted_node_states as u64;
+        self.num_compaction_reclaimed_bytes += num_reclaimed_bytes as u64;
+    }
+
+    /// Marks for deletion the keys of the self node whose TTL lapsed.
+    pub(crate) fn expire_keys(&mut self) {
+        let now = Instant::now();
+        let has_expired_keys = self
+            .cluster_state
+            .node_state(&self.config.node_id)
+            .is_some_and(|self_node_state| self_node_state.has_expired_keys(now));
+        if has_expired_keys {
+            self.self_node_state().expire_keys(now);
+        }
+    }
+
+    /// Forgets the nodes of which `delta` holds a newer generation, and drops the node deltas
+    /// about generations older than the ones we know of.
+    ///
+    /// A live node whose new generation advertises another gossip address, e.g. a pod
+    /// rescheduled on another host, has moved: the new generation stays live right away.
+    fn forget_previous_generations(&mut self, delta: &mut Delta) {
+        let mut newest_generations: HashMap<&str, u64> = HashMap::new();
+        for node_id in self.cluster_state.nodes().chain(delta.node_deltas.keys()) {
+            let newest_generation = newest_generations.entry(node_id.id.as_str()).or_default();
+            *newest_generation = (*newest_generation).max(node_id.generation);
+        }
+        let previous_generations: Vec<NodeId> = self
+            .cluster_state
+            .nodes()
+            .filter(|node_id| {
+                *node_id != &self.config.node_id
+                    && newest_generations[node_id.id.as_str()] > node_id.generation
+            })
+            .cloned()
+            .collect();
+        let obsolete_node_ids: Vec<NodeId> = delta
+            .node_deltas
+            .keys()
+            .filter(|node_id| newest_generations[node_id.id.as_str()] > node_id.generation)
+            .cloned()
+            .collect();
+        // The newest generations received at another address than the previous ones.
+        let moved_node_ids: Vec<Option<NodeId>> = previous_generations
+            .iter()
+            .map(|previous_node_id| {
+                delta
+                    .node_deltas
+                    .keys()
+                    .find(|node_id| {
+                        node_id.id == previous_node_id.id
+                            && node_id.generation == newest_generations[node_id.id.as_str()]
+                            && node_id.gossip_public_address
+                                != previous_node_id.gossip_public_address
+                    })
+                    .cloned()
+            })
+            .collect();
+        for node_id in &obsolete_node_ids {
+            delta.node_deltas.remove(node_id);
+            delta.nodes_to_reset.remove(node_id);
+        }
+        for (node_id, moved_node_id_opt) in previous_generations.iter().zip(moved_node_ids) {
+            if let Some(moved_node_id) = moved_node_id_opt {
+                info!(
+                    node_id = ?node_id,
+                    gossip_public_address = %moved_node_id.gossip_public_address,
+                    "node-address-changed"
+                );
+                self.failure_detector.replace_node(node_id, &moved_node_id);
+            } else {
+                info!(node_id = ?node_id, "forgetting-previous-node-generation");
+            }
+            self.forget_node(node_id);
+        }
+    }
+
+    /// Resolves the conflicts between the nodes sharing an ID and a generation, but not a gossip
+    /// address, which would otherwise silently corrupt each other's state. The node with the
+    /// lowest gossip address wins on every node, whichever is known first: the deltas of the
+    /// others are dropped, and their known states forgotten. The self node always wins locally.
+    /// Conflicts across generations are resolved by [`Chitchat::forget_previous_generations`],
+    /// which keeps the newest one.
+    fn drop_node_id_conflicts(&mut self, delta: &mut Delta) {
+        let self_node_id = &self.config.node_id;
+        let mut node_id_conflicts = Vec::new();
+        for node_id in delta.node_deltas.keys() {
+            let kept_node_id = if is_same_incarnation(node_id, self_node_id) {
+                self_node_id
+            } else {
+                let first_known_opt =
+                    node_ids_of_incarnation(&self.cluster_state.node_states, node_id).next();
+                let first_received = node_ids_of_incarnation(&delta.node_deltas, node_id)
+                    .next()
+                    .unwrap_or(node_id);
+                first_known_opt.map_or(first_received, |first_known| {
+                    first_known.min(first_received)
+                })
+            };
+            if kept_node_id != node_id {
+                node_id_conflicts.push(NodeIdConflict {
+                    kept_node_id: kept_node_id.clone(),
+                    conflicting_node_id: node_id.clone(),
+                });
+                continue;
+            }
+            for known_node_id in node_ids_of_incarnation(&self.cluster_state.node_states, node_id) {
+                if known_node_id != node_id {
+                    node_id_conflicts.push(NodeIdConflict {
+                        kept_node_id: node_id.clone(),
+                        conflicting_node_id: known_node_id.clone(),
+                    });
+                }
+            }
+        }
+        for node_id_conflict in node_id_conflicts {
+            let conflicting_node_id = &node_id_conflict.conflicting_node_id;
+            delta.node_deltas.remove(conflicting_node_id);
+            delta.nodes_to_reset.remove(conflicting_node_id);
+            if self.cluster_state.node_state(conflicting_node_id).is_some() {
+                self.forget_node(conflicting_node_id);
+            }
+            if self.node_id_conflict_rx.borrow().as_ref() == Some(&node_id_conflict) {
+                continue;
+            }
+            warn!(
+                kept_node_id = ?node_id_conflict.kept_node_id,
+                conflicting_node_id = ?conflicting_node_id,
+                "node-id-conflict"
+            );
+            self.num_node_id_conflicts += 1;
+            // A receiver is held by `self`: sending cannot fail.
+            let _ = self.node_id_conflict_tx.send(Some(node_id_conflict));
+        }
+    }
+
+    /// Removes the node from the cluster state, and everything tracked about it.
+    fn forget_node(&mut self, node_id: &NodeId) {
+        self.cluster_state.remove_node(node_id);
+        self.failure_detector.remove_node(node_id);
+        self.reset_tracker.remove_node(node_id);
+        self.unknown_node_tracker.remove_node(node_id);
+        self.rollback_fences.remove_node(node_id);
+        self.propagation_watermarks.forget_peer(node_id);
+        self.reachability_tracker.forget_node(node_id);
+        self.interaction_tracker
+            .forget_peer(node_id.gossip_public_address);
+        self.slow_peer_tracker
+            .forget_peer(node_id.gossip_public_address);
+        self.rtt_tracker.forget_peer(node_id.gossip_public_address);
+        self.peers_with_delta_backlog
+            .remove(&node_id.gossip_public_address);
+        self.publish_live_nodes();
+    }
+
+    /// Elects a leader among the self node and the live nodes, if leader election is enabled.
+    fn elect_leader(&mut self) {
+        let Some(leader_election_config) = &self.config.leader_election else {
+            return;
+        };
+        let hysteresis = leader_election_config.hysteresis;
+        let candidates: BTreeSet<NodeId> = std::iter::once(self.self_node_id())
+            .chain(self.live_nodes())
+            .filter(|node_id| {
+                self.node_state(node_id)
+                    .is_some_and(|node_state| !node_state.has_leave_intent())
+            })
+            .cloned()
+            .collect();
+        let leader_opt = self
+            .leader_election
+            .elect(&candidates, hysteresis, Instant::now())
+            .cloned();
+        let has_leader_changed = *self.leader_watch_rx.borrow() != leader_opt;
+        if leader_opt.as_ref() == Some(&self.config.node_id)
+            && (has_leader_changed || self.is_leadership_superseded())
+        {
+            let max_epoch = self
+                .cluster_state
+                .node_states
+                .values()
+                .filter_map(|node_state| node_state.leader_epoch())
+                .max()
+                .unwrap_or(0);
+            self.self_node_state().set_leader_epoch(max_epoch + 1);
+        }
+        if !has_leader_changed {
+            return;
+        }
+        info!(leader = ?leader_opt, "leader-changed");
+        // A receiver is held by `self`: sending cannot fail.
+        let _ = self.leader_watch_tx.send(leader_opt);
+    }
+
+    /// Returns whether another node advertises a leadership greater than the one of the self
+    /// node, e.g. elected on the other side of a partition that just healed.
+    fn is_leadership_superseded(&self) -> bool {
+        let leader_epoch = |node_id: &NodeId, node_state: &NodeState| {
+            Some(LeaderEpoch {
+                epoch: node_state.leader_epoch()?,
+                node_id: node_id.clone(),
+            })
+        };
+        let self_epoch_opt = self
+            .node_state(&self.config.node_id)
+            .and_then(|node_state| leader_epoch(&self.config.node_id, node_state));
+        self.cluster_state
+            .node_states
+            .iter()
+            .filter(|(node_id, _)| **node_id != self.config.node_id)
+            .any(|(node_id, node_state)| leader_epoch(node_id, node_state) > self_epoch_opt)
+    }
+
+    /// Bumps the liveness revision and notifies the live nodes watchers if the set of live
+    /// nodes changed.
+    fn publish_live_nodes(&mut self) {
+        let live_nodes: BTreeSet<NodeId> = self.live_nodes().cloned().collect();
+        if *self.live_nodes_watch_rx.borrow() == live_nodes {
+            return;
+        }
+        self.liveness_revision += 1;
+        // A receiver is held by `self`: sending cannot fail.
+        let _ = self.live_nodes_watch_tx.send(live_nodes);
+        self.publish_propagation_watermarks();
+    }
+
+    /// Publishes a snapshot of the cluster state to the cluster state watchers, if it changed
+    /// since the last one published. See [`Chitchat::cluster_state_watch`].
+    pub(crate) fn publish_cluster_state(&mut self) {
+        if self.cluster_state_watch_tx.receiver_count() == 0 {
+            // The published snapshot would keep the node states shared, making every write copy
+            // them.
+            if self.published_state_revision.take().is_some() {
+                self.cluster_state_watch_tx.send_replace(Arc::default());
+            }
+            return;
+        }
+        let revision = self.cluster_state.revision();
+        if self.published_state_revision == Some(revision) {
+            return;
+        }
+        self.published_state_revision = Some(revision);
+        self.cluster_state_watch_tx
+            .send_replace(Arc::new(self.state_snapshot()));
+    }
+
+    fn learn_peer_addrs(&mut self, digest: &Digest) {
+        if self.config.exchange_peer_addrs {
+            self.learned_peer_addrs
+                .learn_from_digest(digest, &self.cluster_state, Instant::now());
+        }
+    }
+
+    /// Returns the gossip addresses of the nodes listed by the digests of peers, that belong to
+    /// none of the nodes we know of. The server gossips with them like seeds.
+    pub fn learned_peer_addrs(&self) -> HashSet<SocketAddr> {
+        let known_addrs: HashSet<SocketAddr> = self
+            .cluster_state
+            .nodes()
+            .map(|node_id| node_id.gossip_public_address)
+            .collect();
+        self.learned_peer_addrs
+            .addrs()
+            .filter(|addr| !known_addrs.contains(addr) && !self.denylist.is_addr_blocked(*addr))
+            .collect()
+    }
+
+    fn report_to_failure_detector(&mut self, delta: &Delta) {
+        for (node_id, node_delta) in &delta.node_deltas {
+            let local_max_version = self
+                .cluster_state
+                .node_states
+                .get(node_id)
+                .map(|node_state| node_state.max_version)
+                .unwrap_or(0);
+
+            let delta_max_version = node_delta.max_version();
+            if local_max_version < delta_max_version {
+                self.failure_detector.report_heartbeat(node_id);
+                self.reachability_tracker
+                    .record_heartbeat(node_id, Instant::now());
+            }
+        }
+    }
+
+    /// Reports the nodes whose heartbeat in `digest` is newer than the one known locally.
+    ///
+    /// Nodes unknown locally are skipped: they are learnt through deltas.
+    fn report_digest_heartbeats(&mut self, digest: &Digest) {
+        for (node_id, node_digest) in digest.iter() {
+            self.report_heartbeat(node_id, node_digest.heartbeat);
+        }
+    }
+
+    /// Records the max versions advertised in `digest`, bounding the first delta received for
+    /// the nodes unknown locally.
+    fn record_advertised_max_versions(&mut self, digest: &Digest) {
+        self.cluster_state.record_advertised_max_versions(
+            digest
+                .iter()
+                .map(|(node_id, node_digest)| (node_id, node_digest.max_version)),
+        );
+    }
+
+    /// Reports the heartbeat of a peer learnt from a digest or a probe to the failure detector,
+    /// if it moved forward.
+    fn report_heartbeat(&mut self, node_id: &NodeId, heartbeat: u64) {
+        if node_id == &self.config.node_id {
+            return;
+        }
+        if self
+            .cluster_state
+            .record_digest_heartbeat(node_id, heartbeat)
+        {
+            self.failure_detector.report_heartbeat(node_id);
+            self.reachability_tracker
+                .record_heartbeat(node_id, Instant::now());
+        }
+    }
+
+    /// Returns the factor applied to the failure detection threshold of each node. See
+    /// [`ChitchatConfig::topology`].
+    fn failure_detection_tolerances(&self, node_ids: &[&NodeId]) -> Vec<f64> {
+        let Some(topology) = &self.config.topology else {
+            return vec![1.0; node_ids.len()];
+        };
+        node_ids
+            .iter()
+            .map(|node_id| {
+                if self.is_cross_zone_peer(node_id) {
+                    topology.cross_zone_failure_detection_factor
+                } else {
+                    1.0
+                }
+            })
+            .collect()
+    }
+
+    /// Checks and marks nodes as dead / live / ready.
+    pub fn update_nodes_liveliness(&mut self) {
+        self.zone_cache.refresh(&self.cluster_state);
+        let cluster_nodes = self
+            .cluster_state
+            .nodes()
+            .filter(|&node_id| node_id != self.self_node_id())
+            .collect::<Vec<_>>();
+        let tolerances = self.failure_detection_tolerances(&cluster_nodes);
+        let dead_nodes_before: HashSet<NodeId> =
+            self.failure_detector.dead_nodes().cloned().collect();
+        for (&node_id, tolerance) in cluster_nodes.iter().zip(tolerances) {
+            self.failure_detector
+                .update_node_liveliness(node_id, tolerance);
+        }
+        let newly_dead_nodes: Vec<&NodeId> = self
+            .failure_detector
+            .dead_nodes()
+            .filter(|node_id| !dead_nodes_before.contains(*node_id))
+            .collect();
+        if !newly_dead_nodes.is_empty() {
+            self.increment_counter(
+                metrics::NODES_MARKED_DEAD_TOTAL,
+                newly_dead_nodes.len() as u64,
+            );
+            if let Some(events) = &self.config.events {
+                for node_id in newly_dead_nodes {
+                    events.on_node_dead(node_id);
+                }
+            }
+        }
+        self.publish_live_nodes();
+
+        let ready_nodes_before = self.ready_nodes_watcher_rx.borrow().clone();
+        let ready_nodes_after = self.ready_nodes().cloned().collect::<HashSet<_>>();
+
+        if ready_nodes_before != ready_nodes_after {
+            debug!(current_node = ?self.self_node_id(), live_nodes = ?ready_nodes_after, "nodes status changed");
+            if self.ready_nodes_watcher_tx.send(ready_nodes_after).is_err() {
+                error!(current_node = ?self.self_node_id(), "error while reporting membership change event.")
+            }
+        }
+        let suspect_nodes = self.suspect_nodes().cloned().collect::<HashSet<_>>();
+        if *self.suspect_nodes_watcher_rx.borrow() != suspect_nodes {
+            // A receiver is held by `self`: sending cannot fail.
+            let _ = self.suspect_nodes_watcher_tx.send(suspect_nodes);
+        }
+        self.elect_leader();
+
+        // Perform garbage collection.
+        let garbage_collected_nodes = self
+            .failure_detector
+            .garbage_collect(self.config.dead_node_eviction_policy);
+        if garbage_collected_nodes.is_empty() {
+            return;
+        }
+        self.increment_counter(
+            metrics::NODES_EVICTED_TOTAL,
+            garbage_collected_nodes.len() as u64,
+        );
+        for node_id in garbage_collected_nodes.iter() {
+            info!(node_id = ?node_id, "evicting-dead-node");
+            self.forget_node(node_id);
+            if let Some(events) = &self.config.events {
+                events.on_node_evicted(node_id);
+            }
+        }
+        self.publish_cluster_state();
+        // A receiver is held by `self`: sending cannot fail.
+        let _ = self.evicted_nodes_tx.send(garbage_collected_nodes);
+    }
+
+    pub fn node_state(&self, node_id: &NodeId) -> Option<&NodeState> {
+        self.cluster_state.node_state(node_id)
+    }
+
+    /// Returns an owned view of the state of a node.
+    ///
+    /// Contrary to `node_state`, the view can be held and read without holding on to the
+    /// Chitchat instance, e.g. from a thread outside of the tokio runtime.
+    /// Cloning the returned `Arc` is cheap.
+    pub fn owned_node_state(&self, node_id: &NodeId) -> Option<Arc<NodeStateView>> {
+        let node_state = self.cluster_state.node_states.get(node_id)?.clone();
+        Some(Arc::new(NodeStateView::new(node_id.clone(), node_state)))
+    }
+
+    /// Returns the value of `key` for each node advertising it.
+    ///
+    /// See [`ClusterState::get_from_all`].
+    pub fn get_from_all(&self, key: &str) -> BTreeMap<NodeId, VersionedValue> {
+        self.cluster_state.get_from_all(key)
+    }
+
+    pub fn self_node_state(&mut self) -> &mut NodeState {
+        self.cluster_state.node_state_mut(&self.config.node_id)
+    }
+
+    #[cfg(feature = "server")]
+    /// Applies the configured [`WriteAfterShutdownPolicy`] to a write on the self node.
+    pub(crate) fn write_admission(&self) -> WriteAdmission {
+        match *self.shutdown_phase_tx.borrow() {
+            ShutdownPhase::Running => WriteAdmission::Accept,
+            ShutdownPhase::ShuttingDown => match self.config.write_after_shutdown_policy {
+                WriteAfterShutdownPolicy::Error => WriteAdmission::Reject,
+                WriteAfterShutdownPolicy::Block => WriteAdmission::WaitForShutdown,
+                WriteAfterShutdownPolicy::QueueAndFlush => WriteAdmission::Accept,
+            },
+            ShutdownPhase::ShutDown => WriteAdmission::Reject,
+        }
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn set_shutdown_phase(&mut self, shutdown_phase: ShutdownPhase) {
+        self.shutdown_phase_tx.send_replace(shutdown_phase);
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn shutdown_phase_watcher(&self) -> watch::Receiver<ShutdownPhase> {
+        self.shutdown_phase_tx.subscribe()
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn write_after_shutdown_policy(&self) -> WriteAfterShutdownPolicy {
+        self.config.write_after_shutdown_policy
+    }
+
+    /// Retrieves the list of all live nodes.
+    pub fn live_nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.failure_detector.live_nodes()
+    }
+
+    /// Retrieves the list of nodes that are ready.
+    /// To be ready, a node has to be alive and pass the `is_ready_predicate` as
+    /// defined in the Chitchat configuration.
+    pub fn ready_nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.live_nodes().filter(|&node_id| {
+            let is_ready_pred = if let Some(pred) = self.config.is_ready_predicate.as_ref() {
+                pred
+            } else {
+                // No predicate means that we consider all nodes as ready.
+                return true;
+            };
+            self.node_state(node_id).map(is_ready_pred).unwrap_or(false)
+        })
+    }
+
+    /// Retrieves the live nodes suspected to be dead, see
+    /// [`FailureDetectorConfig::suspicion_timeout`]. They are also part of the live nodes.
+    pub fn suspect_nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.failure_detector.suspect_nodes()
+    }
+
+    /// Retrieve the list of all dead nodes.
+    pub fn dead_nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.failure_detector.dead_nodes()
+    }
+
+    /// Reports the peers that went silent at about the same time, and the ones only reachable
+    /// through other peers, as early signs of a network split.
+    pub fn partition_report(&self) -> PartitionReport {
+        let unreachable_nodes = self.dead_nodes().chain(self.suspect_nodes());
+        self.reachability_tracker.report(
+            self.live_nodes(),
+            unreachable_nodes,
+            self.config.gossip_interval * PARTITION_GROUPING_ROUNDS,
+            Instant::now(),
+        )
+    }
+
+    /// Returns the recent gossip exchanges of the node with its peers, and how far each peer
+    /// lags behind the self node, e.g. to render the gossip connectivity of the cluster and spot
+    /// isolated nodes. See [`GossipTopology::to_dot`].
+    pub fn gossip_topology(&self) -> GossipTopology {
+        let self_max_version = self
+            .cluster_state
+            .node_state(&self.config.node_id)
+            .map(|node_state| node_state.max_version)
+            .unwrap_or(0);
+        let edges = self.interaction_tracker.edges(Instant::now(), |peer_addr| {
+            let node_id = self.node_id_by_addr(peer_addr);
+            let lag_versions = node_id
+                .as_ref()
+                .and_then(|node_id| self.propagation_watermarks.acknowledged_version(node_id))
+                .map(|version| self_max_version.saturating_sub(version));
+            (node_id, lag_versions)
+        });
+        GossipTopology {
+            node_id: self.config.node_id.clone(),
+            edges,
+        }
+    }
+
+    /// Returns the peers whose digests consistently lag behind the cluster state of the node, or
+    /// that had to be sent node states from scratch, slowest first, e.g. to spot the nodes with
+    /// a bad NIC or an overloaded host.
+    pub fn slow_peers(&self) -> Vec<SlowPeer> {
+        self.slow_peer_tracker
+            .slow_peers(|peer_addr| self.node_id_by_addr(peer_addr))
+    }
+
+    fn node_id_by_addr(&self, peer_addr: SocketAddr) -> Option<NodeId> {
+        self.cluster_state
+            .nodes()
+            .find(|node_id| node_id.gossip_public_address == peer_addr)
+            .cloned()
+    }
+
+    /// Returns the current phi value of each peer, i.e. how suspicious its silence is. Peers
+    /// without a phi value, e.g. freshly dead ones, are omitted.
+    pub fn peer_phis(&self) -> BTreeMap<NodeId, f64> {
+        let self_node_id = self.self_node_id();
+        self.cluster_state
+            .nodes()
+            .filter(|node_id| *node_id != self_node_id)
+            .filter_map(|node_id| {
+                let phi = self.failure_detector.phi(node_id)?;
+                Some((node_id.clone(), phi))
+            })
+            .collect()
+    }
+
+    pub fn failure_detector_config(&self) -> &FailureDetectorConfig {
+        &self.config.failure_detector_config
+    }
+
+    /// Changes the parameters of the failure detector on the fly, and reevaluates the liveness
+    /// of the nodes with them. Invalid parameters are rejected, see
+    /// [`FailureDetectorConfig::validate`].
+    pub fn set_failure_detector_config(
+        &mut self,
+        failure_detector_config: FailureDetectorConfig,
+    ) -> anyhow::Result<()> {
+        failure_detector_config.validate()?;
+        self.failure_detector.set_config(&failure_detector_config);
+        self.config.failure_detector_config = failure_detector_config;
+        self.update_nodes_liveliness();
+        Ok(())
+    }
+
+    /// Signs the messages sent with `cluster_key` from now on, still accepting the messages
+    /// signed with the previous cluster key. See [`ChitchatConfig::cluster_key`].
+    ///
+    /// To rotate the key without dropping messages, first make all the nodes accept the new
+    /// key with [`Chitchat::accept_cluster_key`], then rotate it on all the nodes, and finally
+    /// retire the previous key with [`Chitchat::retire_cluster_keys`].
+    #[cfg(feature = "server")]
+    pub fn rotate_cluster_key(&mut self, cluster_key: ClusterKey) {
+        if let Some(previous_cluster_key) = self.config.cluster_key.replace(cluster_key) {
+            self.config.accepted_cluster_keys.push(previous_cluster_key);
+        }
+    }
+
+    /// Accepts the messages signed with `cluster_key`, on top of those signed with the cluster
+    /// key, e.g. ahead of a rotation.
+    #[cfg(feature = "server")]
+    pub fn accept_cluster_key(&mut self, cluster_key: ClusterKey) {
+        self.config.accepted_cluster_keys.push(cluster_key);
+    }
+
+    /// Stops accepting the messages signed with keys other than the cluster key.
+    #[cfg(feature = "server")]
+    pub fn retire_cluster_keys(&mut self) {
+        self.config.accepted_cluster_keys.clear();
+    }
+
+    /// Returns the keys authenticating the messages received, the cluster key first. Empty if
+    /// messages are not authenticated.
+    #[cfg(feature = "server")]
+    pub(crate) fn accepted_cluster_keys(&self) -> impl Iterator<Item = &ClusterKey> {
+        let accepted_cluster_keys: &[ClusterKey] = if self.config.cluster_key.is_some() {
+            &self.config.accepted_cluster_keys
+        } else {
+            &[]
+        };
+        self.config.cluster_key.iter().chain(accepted_cluster_keys)
+    }
+
+    /// Retrieve a list of seed nodes.
+    pub fn seed_nodes(&self) -> HashSet<SocketAddr> {
+        self.cluster_state.seed_addrs()
+    }
+
+    pub fn self_node_id(&self) -> &NodeId {
+        &self.config.node_id
+    }
+
+    pub fn cluster_id(&self) -> &str {
+        &self.config.cluster_id
+    }
+
+    /// Advertises the addresses the self node can be reached at, on top of its gossip
+    /// address. Readable through [`NodeState::advertised_addrs`].
+    pub fn set_advertised_addrs(&mut self, addrs: &[SocketAddr]) {
+        self.self_node_state().set_advertised_addrs(addrs);
+    }
+
+    /// Sets the metadata of the self node, replacing its previous metadata. It can be read
+    /// through [`ClusterState::node_metadata`].
+    #[cfg(feature = "json")]
+    pub fn set_node_metadata(&mut self, metadata: &NodeMetadata) {
+        self.self_node_state().set_metadata(metadata);
+    }
+
+    /// Returns the zone of the node `node_id`, held by its [`ChitchatConfig::zone_key`], as of
+    /// the last gossip round or delta applied.
+    pub fn node_zone(&self, node_id: &NodeId) -> Option<&str> {
+        self.zone_cache.zone(node_id)
+    }
+
+    /// Returns true if the zones of the self node and of `node_id` are both known, and differ.
+    pub fn is_cross_zone_peer(&self, node_id: &NodeId) -> bool {
+        self.zone_cache.is_cross_zone(self.self_node_id(), node_id)
+    }
+
+    /// Announces that the self node is about to leave the cluster on purpose, or that it
+    /// changed its mind. Readable through [`NodeState::has_leave_intent`].
+    pub fn set_leave_intent(&mut self, leave_intent: bool) {
+        self.self_node_state().set_leave_intent(leave_intent);
+    }
+
+    fn update_heartbeat_if_due(&mut self, now: Instant) {
+        if self.config.adaptive_gossip.is_some()
+            && self.heartbeat_at_opt.is_some_and(|heartbeat_at| {
+                now.saturating_duration_since(heartbeat_at) < self.config.gossip_interval
+            })
+        {
+            return;
+        }
+        self.heartbeat_at_opt = Some(now);
+        self.update_heartbeat();
+    }
+
+    /// Increments the heartbeat of the self node, without bumping its max version.
+    pub fn update_heartbeat(&mut self) {
+        self.cluster_state.increment_heartbeat(&self.config.node_id);
+    }
+
+    /// Computes digest.
+    ///
+    /// The digest carries the heartbeat of every node, which propagates the node liveliness
+    /// through the cluster.
+    #[cfg_attr(
+        feature = "tracing-spans",
+        tracing::instrument(name = "compute-digest", skip_all)
+    )]
+    fn compute_digest(&self, dead_nodes: &HashSet<&NodeId>) -> Digest {
+        self.cluster_state.compute_digest(dead_nodes)
+    }
+
+    pub fn cluster_state(&self) -> &ClusterState {
+        &self.cluster_state
+    }
+
+    /// Returns the cluster-wide value of the counter `name`.
+    ///
+    /// Nodes contribute to a counter through [`NodeState::increment_counter`] and
+    /// [`NodeState::decrement_counter`].
+    pub fn counter_value(&self, name: &str) -> i64 {
+        self.cluster_state.counter_value(name)
+    }
+
+    /// Returns the cluster-wide observed-remove set `name`, merging the sets of all nodes.
+    ///
+    /// Nodes update their own set through [`NodeState::add_to_or_set`] and
+    /// [`NodeState::remove_from_or_set`].
+    #[cfg(feature = "json")]
+    pub fn or_set(&self, name: &str) -> OrSet {
+        self.cluster_state.or_set(name)
+    }
+
+    /// Aggregates the numeric values of the keys starting with `prefix`, across the self node
+    /// and the live nodes. Non-numeric values are ignored.
+    ///
+    /// Results are cached until the cluster state or the set of live nodes changes.
+    pub fn aggregate(&mut self, prefix: &str, agg_fn: AggFn) -> Option<f64> {
+        let revision = (self.cluster_state.revision(), self.liveness_revision);
+        let cluster_state = &self.cluster_state;
+        let self_node_id = &self.config.node_id;
+        let live_nodes = self.failure_detector.live_nodes();
+        self.aggregation_cache
+            .get_or_compute(revision, prefix, agg_fn, || {
+                let values = std::iter::once(self_node_id)
+                    .chain(live_nodes.filter(|node_id| *node_id != self_node_id))
+                    .filter_map(|node_id| cluster_state.node_state(node_id))
+                    .flat_map(|node_state| {
+                        node_state.iter_key_values(|key, _| key.starts_with(prefix))
+                    })
+                    .filter_map(|(_, versioned_value)| versioned_value.value.parse::<f64>().ok());
+                agg_fn.apply(values)
+            })
+    }
+
+    /// Registers the materialized view `name`, aggregating the values of `key` across the self
+    /// node and the live nodes. Registering a view under an existing name replaces it.
+    ///
+    /// Contrary to [`Chitchat::aggregate`], the view is maintained incrementally as the cluster
+    /// state changes, so reading it with [`Chitchat::view`] does not scan all the node states.
+    pub fn register_view(&mut self, name: &str, key: &str, kind: ViewKind) {
+        self.update_views();
+        self.materialized_views
+            .register(name, key, kind, &self.cluster_state);
+    }
+
+    /// Removes the materialized view `name`. Returns false if no such view is registered.
+    pub fn unregister_view(&mut self, name: &str) -> bool {
+        self.materialized_views.unregister(name)
+    }
+
+    /// Returns the current result of the materialized view `name`.
+    pub fn view(&mut self, name: &str) -> Option<&ViewResult> {
+        self.update_views();
+        self.materialized_views.get(name)
+    }
+
+    fn update_views(&mut self) {
+        self.change_journal.record_changes(&self.cluster_state);
+        let self_node_id = &self.config.node_id;
+        let failure_detector = &self.failure_detector;
+        self.materialized_views.update(
+            &self.cluster_state,
+            &self.change_journal,
+            self.liveness_revision,
+            || {
+                std::iter::once(self_node_id).chain(
+                    failure_detector
+                        .live_nodes()
+                        .filter(move |node_id| *node_id != self_node_id),
+                )
+            },
+        );
+    }
+
+    /// Returns the live peers of this node, to be persisted and used as seeds upon restart.
+    pub fn peer_cache(&self) -> PeerCache {
+        let last_seen_at_secs = peer_cache::unix_timestamp_secs(SystemTime::now());
+        let peers = self
+            .live_nodes()
+            .filter(|node_id| *node_id != self.self_node_id())
+            .map(|node_id| CachedPeer {
+                node_id: node_id.clone(),
+                last_seen_at_secs,
+            })
+            .collect();
+        PeerCache {
+            cluster_id: self.config.cluster_id.clone(),
+            peers,
+        }
+    }
+
+    /// Validates a persisted peer cache, see [`PeerCache::restore`], and gossips with its peers
+    /// like seeds. Peers contradicted by gossip are then quarantined at every gossip round, see
+    /// [`RestoredPeers::quarantine_conflicting_peers`].
+    pub fn restore_peer_cache(&mut self, peer_cache: PeerCache, max_age: Duration) {
+        self.restored_peers =
+            peer_cache.restore(&self.config.cluster_id, max_age, SystemTime::now());
+        self.restored_peers
+            .quarantine_conflicting_peers(&self.cluster_state);
+    }
+
+    /// Returns the peers restored from the persisted peer cache.
+    pub fn restored_peers(&self) -> &RestoredPeers {
+        &self.restored_peers
+    }
+
+    /// Returns the gossip addresses of the restored peers that can be used as seeds.
+    pub fn restored_peer_addrs(&self) -> HashSet<SocketAddr> {
+        let self_addr = self.self_node_id().gossip_public_address;
+        self.restored_peers
+            .seed_addrs()
+            .into_iter()
+            .filter(|addr| *addr != self_addr && !self.denylist.is_addr_blocked(*addr))
+            .collect()
+    }
+
+    /// Returns a checkpoint of the self node state and, if `include_cluster_view` is true, of the
+    /// states of the other nodes.
+    pub fn checkpoint(&self, include_cluster_view: bool) -> Checkpoint {
+        let self_node_id = self.self_node_id();
+        let self_node_state = self
+            .node_state(self_node_id)
+            .map(NodeState::to_checkpoint)
+            .unwrap_or_default();
+        let node_states = if include_cluster_view {
+            self.cluster_state
+                .node_states
+                .iter()
+                .filter(|(node_id, _)| *node_id != self_node_id)
+                .map(|(node_id, node_state)| (node_id.clone(), NodeState::clone(node_state)))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Checkpoint {
+            cluster_id: self.config.cluster_id.clone(),
+            node_id: self_node_id.clone(),
+            self_node_state,
+            heartbeat: self
+                .node_state(self_node_id)
+                .map(NodeState::heartbeat)
+                .unwrap_or(0),
+            checkpointed_at_millis: unix_timestamp_millis(SystemTime::now()),
+            node_states,
+            peer_cache: Some(self.peer_cache()),
+        }
+    }
+
+    /// Restores the node states of a checkpoint taken by this node. Returns false if the
+    /// checkpoint was taken by another node or in another cluster, in which case it is ignored.
+    /// Checkpoints taken by a previous generation, or at a previous gossip address, of the node
+    /// are restored.
+    ///
+    /// The self node resumes from the versions of the checkpoint, so that peers do not ignore
+    /// its new writes. Its heartbeat resumes past the heartbeats it may have sent since the
+    /// checkpoint was taken, so that peers do not ignore it either, and declare it dead.
+    /// Key-values set on the self node before the restore take precedence over the checkpointed
+    /// ones. The states of the other nodes are restored only if they are not known already.
+    pub fn restore_checkpoint(&mut self, checkpoint: Checkpoint) -> bool {
+        if checkpoint.cluster_id != self.config.cluster_id
+            || checkpoint.node_id.id != self.config.node_id.id
+        {
+            warn!(
+                cluster_id = %checkpoint.cluster_id,
+                node_id = ?checkpoint.node_id,
+                "ignoring-checkpoint-of-another-node"
+            );
+            return false;
+        }
+        // The write timestamp gets stamped again by the writes below.
+        let current_key_values: Vec<(String, String, WriteSource)> = self
+            .cluster_state
+            .node_state(&self.config.node_id)
+            .into_iter()
+            .flat_map(|self_node_state| {
+                self_node_state
+                    .iter_key_values(|key, _| key != WRITE_TIMESTAMP_KEY)
+                    .map(|(key, versioned_value)| {
+                        let source = self_node_state
+                            .write_source(key)
+                            .unwrap_or(WriteSource::Application);
+                        (key.to_string(), versioned_value.value.clone(), source)
+                    })
+            })
+            .collect();
+        // The heartbeat is bumped at most once per gossip round, and rounds last at least half a
+        // gossip interval.
+        let elapsed_millis = unix_timestamp_millis(SystemTime::now())
+            .saturating_sub(checkpoint.checkpointed_at_millis);
+        let min_round_millis = (self.config.gossip_interval.as_millis() as u64 / 2).max(1);
+        let heartbeat = checkpoint
+            .heartbeat
+            .saturating_add(elapsed_millis / min_round_millis + 1);
+        let self_node_id = self.config.node_id.clone();
+        self.cluster_state
+            .restore_node_state(self_node_id, checkpoint.self_node_state);
+        #[cfg(feature = "encryption")]
+        let sealed_keys = self.config.sealed_keys.clone().map(Arc::new);
+        let propagation_latency_tracking = self.config.propagation_latency_tracking;
+        let self_node_state = self.self_node_state();
+        #[cfg(feature = "encryption")]
+        self_node_state.set_sealed_keys(sealed_keys);
+        self_node_state.set_stamp_writes(propagation_latency_tracking);
+        self_node_state.record_digest_heartbeat(heartbeat);
+        for (key, value, source) in current_key_values {
+            self_node_state.set_with_source(key, value, source);
+        }
+        for (node_id, node_state) in checkpoint.node_states {
+            if self.cluster_state.node_state(&node_id).is_none() {
+                self.cluster_state.restore_node_state(node_id, node_state);
+            }
+        }
+        true
+    }
+
+    /// Returns a serializable snapshot of the ClusterState
+    ///
+    /// The snapshot shares the node states with the cluster state, which copies them on write:
+    /// taking a snapshot only clones one pointer per node.
+    pub fn state_snapshot(&self) -> ClusterStateSnapshot {
+        ClusterStateSnapshot::from(&self.cluster_state)
+    }
+
+    /// Merges a previously exported snapshot into the cluster state, e.g. to warm up a new node
+    /// before gossip fills the gaps. Returns the number of merged nodes.
+    ///
+    /// Key-values are merged following the same versioning rules as the deltas received from
+    /// peers: they never override newer local key-values. The self node state, and the nodes
+    /// whose gossip address is missing from the snapshot, are ignored. Merged nodes only become
+    /// live once their heartbeats are received.
+    pub fn merge_snapshot(&mut self, snapshot: ClusterStateSnapshot) -> usize {
+        let mut delta = Delta::default();
+        for (node_id, node_state) in snapshot.node_states {
+            if node_id == self.config.node_id.id {
+                continue;
+            }
+            let Some(gossip_addr) = snapshot.gossip_addrs.get(&node_id) else {
+                warn!(node_id = %node_id, "snapshot-node-without-gossip-address");
+                continue;
+            };
+            let generation = snapshot.generations.get(&node_id).copied().unwrap_or(0);
+            let node_id = NodeId::new(node_id, *gossip_addr).with_generation(generation);
+            delta.node_deltas.entry(node_id).or_default().key_values =
+                Arc::unwrap_or_clone(node_state).key_values.take();
+        }
+        self.forget_previous_generations(&mut delta);
+        self.drop_node_id_conflicts(&mut delta);
+        // The snapshot is trusted: it bounds the versions of its own nodes.
+        self.cluster_state.record_advertised_max_versions(
+            delta
+                .node_deltas
+                .iter()
+                .map(|(node_id, node_delta)| (node_id, node_delta.max_version())),
+        );
+        let num_merged_nodes = delta.node_deltas.len();
+        self.apply_delta(None, delta);
+        num_merged_nodes
+    }
+
+    /// Compares the local cluster state with the snapshot of another node, key by key.
+    pub fn compare_snapshot(&self, remote: &ClusterStateSnapshot) -> DivergenceReport {
+        DivergenceReport::compare(&self.state_snapshot(), remote)
+    }
+
+    /// Returns the key-values missing from the state of an observer whose digest is
+    /// `observer_digest`, oldest first, in a delta of at most `max_num_bytes` bytes.
+    ///
+    /// Contrary to gossip, the key-values of dead nodes are included. The observer applies the
+    /// delta to its [`ObserverState`] and asks again with its new digest, until the returned
+    /// delta is empty. Since a node delta holds at most `u16::MAX` key-values, `max_num_bytes`
+    /// should not exceed a few hundred kilobytes.
+    pub fn observer_delta(
+        &self,
+        observer_digest: &ObserverDigest,
+        max_num_bytes: usize,
+    ) -> ObserverDelta {
+        let delta = self.cluster_state.compute_delta(
+            &observer_digest.0,
+            max_num_bytes,
+            HashSet::new(),
+            self.config.marked_for_deletion_grace_period,
+            self.config.oversized_key_value_policy,
+            &HashSet::new(),
+        );
+        ObserverDelta(delta)
+    }
+
+    /// Returns the changes applied to the cluster state after the change with sequence number
+    /// `seq`, in order. Pass `0` to get all the retained changes.
+    ///
+    /// Returns `None` if some of these changes were already evicted from the journal: the
+    /// consumer has to resync from a [`Chitchat::state_snapshot`].
+    pub fn changes_since(&mut self, seq: u64) -> Option<Vec<JournalEntry>> {
+        self.change_journal.record_changes(&self.cluster_state);
+        self.change_journal.changes_since(seq)
+    }
+
+    /// Returns the `k` keys responsible for most of the gossip traffic: the keys whose new
+    /// values, summed over all the nodes, amount to the most bytes per second over the last
+    /// minute or two.
+    ///
+    /// A key updated several times between two gossip messages is only counted once, as only
+    /// its latest value is gossiped.
+    pub fn top_talkers(&mut self, k: usize) -> Vec<KeyChangeRate> {
+        self.change_journal.record_changes(&self.cluster_state);
+        self.change_journal.top_talkers(k)
+    }
+
+    /// Returns the number of node resets that had to be sent again to a peer, because the peer's
+    /// digest did not reflect the previous attempt.
+    pub fn num_reset_retransmissions(&self) -> u64 {
+        self.reset_tracker.num_reset_retransmissions()
+    }
+
+    /// Returns the number of nodes advertised by peers for which no data was received so far.
+    pub fn num_unknown_nodes(&self) -> usize {
+        self.unknown_node_tracker.num_unknown_nodes()
+    }
+
+    /// Returns the number of nodes that were forgotten because no data was received for them
+    /// within `unknown_node_grace_period`.
+    pub fn num_forgotten_unknown_nodes(&self) -> u64 {
+        self.unknown_node_tracker.num_forgotten_nodes()
+    }
+
+    /// Returns the number of node state compactions since startup.
+    pub fn num_compactions(&self) -> u64 {
+        self.num_compactions
+    }
+
+    /// Returns the number of bytes reclaimed by node state compactions since startup.
+    pub fn num_compaction_reclaimed_bytes(&self) -> u64 {
+        self.num_compaction_reclaimed_bytes
+    }
+
+    /// Returns the number of key-values rejected to enforce the `node_state_limits`, summed
+    /// over the node states of the cluster state.
+    pub fn num_rejected_key_values(&self) -> u64 {
+        self.cluster_state.num_limited_key_values().0
+    }
+
+    /// Returns the number of key-values evicted to enforce the `node_state_limits`, summed
+    /// over the node states of the cluster state.
+    pub fn num_evicted_key_values(&self) -> u64 {
+        self.cluster_state.num_limited_key_values().1
+    }
+
+    /// Returns the number of key-values received from peers and dropped since startup because
+    /// their node is not allowed to publish them. See [`WriteAcl`].
+    pub fn num_unauthorized_key_values(&self) -> u64 {
+        self.cluster_state.num_unauthorized_key_values()
+    }
+
+    /// Returns the number of node deltas received from peers and refused since startup because
+    /// they introduced new nodes while the cluster state exceeded the
+    /// [`ChitchatConfig`] `max_cluster_state_bytes`.
+    pub fn num_refused_nodes(&self) -> u64 {
+        self.cluster_state.num_refused_nodes()
+    }
+
+    /// Returns the number of version anomalies detected since startup. See
+    /// [`Chitchat::version_anomaly_watcher`].
+    pub fn num_version_anomalies(&self) -> u64 {
+        self.num_version_anomalies
+    }
+
+    /// Returns the number of messages rejected since startup for carrying the ID of another
+    /// cluster.
+    pub fn num_cluster_mismatches(&self) -> u64 {
+        self.num_cluster_mismatches
+    }
+
+    /// Returns the number of messages dropped since startup for exceeding the rate limit of
+    /// their source address. See [`ChitchatConfig::receive_rate_limit`]. The payloads dropped by
+    /// the transport are accounted for at every gossip round.
+    pub fn num_rate_limited_messages(&self) -> u64 {
+        self.num_rate_limited_messages + self.num_transport_rate_limited_messages
+    }
+
+    /// Returns the number of bytes of the messages counted by
+    /// [`Chitchat::num_rate_limited_messages`].
+    pub fn num_rate_limited_bytes(&self) -> u64 {
+        self.num_rate_limited_bytes + self.num_transport_rate_limited_bytes
+    }
+
+    /// Returns the number of audit records dropped since startup because the `audit_sink` was
+    /// full or closed. See [`ChitchatConfig::audit_sink`].
+    pub fn num_dropped_audit_records(&self) -> u64 {
+        self.num_dropped_audit_records
+    }
+
+    /// Returns the number of deltas dropped since startup because the deltas buffered while
+    /// applies were frozen reached their maximum size. See [`Chitchat::freeze_applies`].
+    pub fn num_dropped_frozen_deltas(&self) -> u64 {
+        self.num_dropped_frozen_deltas
+    }
+
+    /// Returns the number of messages dropped since startup for failing authentication with
+    /// the cluster key. See [`ChitchatConfig::cluster_key`].
+    pub fn num_unauthenticated_messages(&self) -> u64 {
+        self.num_unauthenticated_messages
+    }
+
+    /// Returns the number of authenticated messages dropped since startup for being replayed,
+    /// or sealed too long ago. See [`ChitchatConfig::replay_window`].
+    pub fn num_replayed_messages(&self) -> u64 {
+        self.num_replayed_messages
+    }
+
+    /// Returns the cumulative statistics of the gossip of the node since startup.
+    pub fn gossip_stats(&self) -> GossipStats {
+        GossipStats {
+            num_dropped_messages: self.num_rate_limited_messages()
+                + self.num_unauthenticated_messages
+                + self.num_replayed_messages
+                + self.num_cluster_mismatches,
+            ..self.gossip_stats.clone()
+        }
+    }
+
+    /// Returns the number of key-values received from peers since startup, i.e. the updates
+    /// the self node was missing.
+    pub fn num_received_key_values(&self) -> u64 {
+        self.num_received_key_values
+    }
+
+    /// Returns a watch stream yielding the last version anomaly detected, `None` until the
+    /// first one. The state of the node affected by an anomaly is reset.
+    pub fn version_anomaly_watcher(&self) -> WatchStream<Option<VersionAnomaly>> {
+        WatchStream::new(self.version_anomaly_rx.clone())
+    }
+
+    /// Returns a watch stream yielding the last node ID conflict detected, `None` until the
+    /// first one. See [`NodeIdConflict`].
+    pub fn node_id_conflict_watcher(&self) -> WatchStream<Option<NodeIdConflict>> {
+        WatchStream::new(self.node_id_conflict_rx.clone())
+    }
+
+    /// Returns the number of node ID conflicts detected since startup. A conflict is counted
+    /// once for as long as it is the last one detected.
+    pub fn num_node_id_conflicts(&self) -> u64 {
+        self.num_node_id_conflicts
+    }
+
+    /// Returns a receiver of the leader elected among the self node and the live nodes. Always
+    /// `None` unless [`ChitchatConfig::leader_election`] is set.
+    ///
+    /// Nodes may briefly disagree on the leader, e.g. while gossip converges: the epoch of
+    /// the leader, see [`Chitchat::leader_epoch`], lets the application tell leaderships apart.
+    pub fn current_leader(&self) -> watch::Receiver<Option<NodeId>> {
+        self.leader_watch_rx.clone()
+    }
+
+    /// Returns the epoch advertised by the current leader, once it is known. Epochs increase
+    /// as leaders are elected.
+    pub fn leader_epoch(&self) -> Option<LeaderEpoch> {
+        let leader = self.leader_watch_rx.borrow().clone()?;
+        let epoch = self.node_state(&leader)?.leader_epoch()?;
+        Some(LeaderEpoch {
+            epoch,
+            node_id: leader,
+        })
+    }
+
+    /// Returns a receiver of the set of live nodes, updated by the failure detector whenever it
+    /// changes.
+    pub fn live_nodes_watch(&self) -> watch::Receiver<BTreeSet<NodeId>> {
+        self.live_nodes_watch_rx.clone()
+    }
+
+    /// Returns a receiver of the snapshot of the cluster state, published after every message
+    /// processed and every call to [`Chitchat::run_maintenance`] that changed it.
+    ///
+    /// Reading the snapshot takes no lock on the chitchat instance, so frequent readers, e.g.
+    /// routing lookups, do not contend with gossip. Updates made through
+    /// [`Chitchat::self_node_state`] are published with the next message or gossip round.
+    ///
+    /// The snapshot shares the node states with the cluster state, which copies them on write:
+    /// the node states changing while the snapshot reflects them get copied. Snapshots are only
+    /// published as long as a receiver is alive.
+    pub fn cluster_state_watch(&mut self) -> watch::Receiver<Arc<ClusterStateSnapshot>> {
+        let cluster_state_watch_rx = self.cluster_state_watch_tx.subscribe();
+        self.publish_cluster_state();
+        cluster_state_watch_rx
+    }
+
+    /// Returns a watch stream for monitoring changes on the cluster's live nodes.
+    pub fn ready_nodes_watcher(&self) -> WatchStream<HashSet<NodeId>> {
+        WatchStream::new(self.ready_nodes_watcher_rx.clone())
+    }
+
+    /// Returns a watch stream yielding the dead nodes evicted from the cluster state by the last
+    /// garbage collection evicting any, see [`DeadNodeEvictionPolicy`].
+    ///
+    /// Evictions happening between two reads of the stream are only reported by the last one:
+    /// [`ChitchatEvents::on_node_evicted`] is called for every evicted node instead.
+    pub fn evicted_nodes_watcher(&self) -> WatchStream<Vec<NodeId>> {
+        WatchStream::new(self.evicted_nodes_rx.clone())
+    }
+
+    /// Returns a watch stream for monitoring changes on the cluster's suspect nodes.
+    pub fn suspect_nodes_watcher(&self) -> WatchStream<HashSet<NodeId>> {
+        WatchStream::new(self.suspect_nodes_watcher_rx.clone())
+    }
+}
+
+fn is_same_incarnation(left: &NodeId, right: &NodeId) -> bool {
+    left.id == right.id && left.generation == right.generation
+}
+
+/// Returns the node IDs of `map` sharing the ID and generation of `node_id`, by increasing
+/// gossip address.
+fn node_ids_of_incarnation<'a, V>(
+    map: &'a BTreeMap<NodeId, V>,
+    node_id: &NodeId,
+) -> impl Iterator<Item = &'a NodeId> {
+    let lowest_node_id = NodeId {
+        id: node_id.id.clone(),
+        generation: node_id.generation,
+        gossip_public_address: SocketAddr::from(([0, 0, 0, 0], 0)),
+    };
+    map.range(lowest_node_id.clone()..)
+        .map(|(node_id, _)| node_id)
+        .take_while(move |node_id| is_same_incarnation(node_id, &lowest_node_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::{Add, RangeInclusive};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use mock_instant::MockClock;
+    use tokio::sync::Mutex;
+    use tokio::time;
+    use tokio_stream::wrappers::IntervalStream;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::server::{spawn_chitchat, ChitchatHandle};
+    use crate::transport::{ChannelTransport, Transport};
+
+    const DEAD_NODE_GRACE_PERIOD: Duration = Duration::from_secs(20);
+
+    /// Records the max versions of `delta` as advertised by a peer, as a digest would, so that
+    /// the delta is accepted for nodes unknown to `node`.
+    fn advertise_max_versions(node: &mut Chitchat, delta: &Delta) {
+        node.cluster_state.record_advertised_max_versions(
+            delta
+                .node_deltas
+                .iter()
+                .map(|(node_id, node_delta)| (node_id, node_delta.max_version())),
+        );
+    }
+
+    fn run_chitchat_handshake(initiating_node: &mut Chitchat, peer_node: &mut Chitchat) {
+        let initiating_addr = initiating_node.self_node_id().gossip_public_address;
+        let peer_addr = peer_node.self_node_id().gossip_public_address;
+        let syn_message = initiating_node.create_syn_message();
+        let syn_ack_message = peer_node
+            .process_message(initiating_addr, syn_message)
+            .unwrap();
+        let ack_message = initiating_node
+            .process_message(peer_addr, syn_ack_message)
+            .unwrap();
+        assert!(peer_node
+            .process_message(initiating_addr, ack_message)
+            .is_none());
+    }
+
+    fn assert_cluster_state_eq(lhs: &NodeState, rhs: &NodeState) {
+        assert_eq!(lhs.key_values.len(), rhs.key_values.len());
+        for (key, value) in &lhs.key_values {
+            if &**key == HEARTBEAT_KEY {
+                // we ignore the heartbeat key
+                continue;
+            }
+            assert_eq!(rhs.key_values.get(key), Some(value));
+        }
+    }
+
+    fn assert_nodes_sync(nodes: &[&Chitchat]) {
+        let first_node_states = &nodes[0].cluster_state.node_states;
+        for other_node in nodes.iter().skip(1) {
+            let node_states = &other_node.cluster_state.node_states;
+            assert_eq!(first_node_states.len(), node_states.len());
+            for (key, value) in first_node_states {
+                assert_cluster_state_eq(value, node_states.get(key).unwrap());
+            }
+        }
+    }
+
+    async fn start_node(
+        node_id: NodeId,
+        seeds: &[String],
+        transport: &dyn Transport,
+    ) -> ChitchatHandle {
+        let config = ChitchatConfig {
+            node_id: node_id.clone(),
+            cluster_id: "default-cluster".to_string(),
+            gossip_interval: Duration::from_millis(100),
+            listen_addr: node_id.gossip_public_address,
+            seed_nodes: seeds.to_vec(),
+            failure_detector_config: FailureDetectorConfig {
+                dead_node_grace_period: DEAD_NODE_GRACE_PERIOD,
+                phi_threshold: 5.0,
+                initial_interval: Duration::from_millis(100),
+                ..Default::default()
+            },
+            is_ready_predicate: None,
+            marked_for_deletion_grace_period: 10_000,
+            tombstone_gc_policy: TombstoneGcPolicy::default(),
+            tombstone_grace_period: Duration::from_secs(3_600),
+            oversized_key_value_policy: OversizedKeyValuePolicy::default(),
+            unknown_node_grace_period: Duration::from_secs(60),
+            compaction_churn_threshold: None,
+            write_after_shutdown_policy: WriteAfterShutdownPolicy::default(),
+            node_state_limits: NodeStateLimits::default(),
+            max_cluster_state_bytes: None,
+            #[cfg(feature = "json")]
+            persistence: None,
+            delta_interceptor: None,
+            events: None,
+            write_acl: None,
+            audit_sink: None,
+            max_version_jump: Some(10_000_000),
+            metrics_recorder: None,
+            propagation_latency_tracking: false,
+            #[cfg(feature = "encryption")]
+            sealed_keys: None,
+            region_aware_gossip: None,
+            failure_detector: None,
+            indirect_probe_count: 3,
+            dead_node_eviction_policy: Default::default(),
+            full_sync_interval: None,
+            #[cfg(feature = "http-debug")]
+            debug_http_listen_addr: None,
+            gossip_fanout: Default::default(),
+            peer_selection: None,
+            zone_key: None,
+            topology: None,
+            leader_election: None,
+            gossip_interval_jitter: 0.0,
+            adaptive_gossip: None,
+            #[cfg(feature = "server")]
+            seed_provider: None,
+            seed_refresh_interval: Duration::from_secs(60),
+            exchange_peer_addrs: false,
+            seed_backoff: Default::default(),
+            cluster_key: None,
+            accepted_cluster_keys: Vec::new(),
+            replay_window: Some(Duration::from_secs(60)),
+            receive_rate_limit: None,
+            parse_limits: None,
+        };
+        let initial_kvs: Vec<(String, String)> = Vec::new();
+        spawn_chitchat(config, initial_kvs, transport)
+            .await
+            .unwrap()
+    }
+
+    async fn setup_nodes(
+        port_range: RangeInclusive<u16>,
+        transport: &dyn Transport,
+    ) -> Vec<ChitchatHandle> {
+        let node_ids: Vec<NodeId> = port_range.map(NodeId::for_test_localhost).collect();
+        let node_without_seed = start_node(node_ids[0].clone(), &[], transport).await;
+        let mut chitchat_handlers: Vec<ChitchatHandle> = vec![node_without_seed];
+        for node_id in &node_ids[1..] {
+            let seeds = node_ids
+                .iter()
+                .filter(|&peer_id| peer_id != node_id)
+                .map(|peer_id| peer_id.gossip_public_address.to_string())
+                .collect::<Vec<_>>();
+            chitchat_handlers.push(start_node(node_id.clone(), &seeds, transport).await);
+        }
+        // Make sure the failure detector's fake clock moves forward.
+        tokio::spawn(async {
+            let mut ticker = IntervalStream::new(time::interval(Duration::from_millis(50)));
+            while ticker.next().await.is_some() {
+                MockClock::advance(Duration::from_millis(50));
+            }
+        });
+        chitchat_handlers
+    }
+
+    async fn shutdown_nodes(nodes: Vec<ChitchatHandle>) -> anyhow::Result<()> {
+        for node in nodes {
+            node.shutdown().await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_chitchat_state(
+        chitchat: Arc<Mutex<Chitchat>>,
+        expected_node_count: usize,
+        expected_nodes: &[NodeId],
+    ) {
+        let mut ready_nodes_watcher = chitchat
+            .lock()
+            .await
+            .ready_nodes_watcher()
+            .skip_while(|live_nodes| live_nodes.len() != expected_node_count);
+        tokio::time::timeout(Duration::from_secs(50), async move {
+            let live_nodes = ready_nodes_watcher.next().await.unwrap();
+            assert_eq!(
+                live_nodes,
+                expected_nodes.iter().cloned().collect::<HashSet<_>>()
+            );
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn test_chitchat_handshake() {
+        let node_config1 = ChitchatConfig::for_test(10_001);
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            node_config1,
+            empty_seeds.clone(),
+            vec![
+                ("key1a".to_string(), "1".to_string()),
+                ("key2a".to_string(), "2".to_string()),
+            ],
+        );
+        let node_config2 = ChitchatConfig::for_test(10_002);
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            node_config2,
+            empty_seeds,
+            vec![
+                ("key1b".to_string(), "1".to_string()),
+                ("key2b".to_string(), "2".to_string()),
+            ],
+        );
+        run_chitchat_handshake(&mut node1, &mut node2);
+        assert_nodes_sync(&[&node1, &node2]);
+        // useless handshake
+        run_chitchat_handshake(&mut node1, &mut node2);
+        assert_nodes_sync(&[&node1, &node2]);
+        {
+            let state1 = node1.self_node_state();
+            state1.set("key1a", "3");
+            state1.set("key1c", "4");
+        }
+        run_chitchat_handshake(&mut node1, &mut node2);
+        assert_nodes_sync(&[&node1, &node2]);
+    }
+
+    #[test]
+    fn test_chitchat_forgets_unknown_nodes() {
+        let mut node_config1 = ChitchatConfig::for_test(10_001);
+        node_config1.unknown_node_grace_period = Duration::ZERO;
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(node_config1, empty_seeds, Vec::new());
+        let peer_addr = NodeId::for_test_localhost(10_002).gossip_public_address;
+        let ghost_node_id = NodeId::for_test_localhost(10_003);
+        let ghost_delta = || {
+            let mut delta = Delta::default();
+            delta
+                .node_deltas
+                .insert(ghost_node_id.clone(), Default::default());
+            delta
+        };
+        node1.process_message(
+            peer_addr,
+            ChitchatMessage::Ack {
+                cluster_id: node1.cluster_id().to_string(),
+                delta: ghost_delta(),
+            },
+        );
+        assert!(node1.node_state(&ghost_node_id).is_some());
+        node1.gc_unknown_nodes();
+        assert_eq!(node1.num_unknown_nodes(), 1);
+
+        std::thread::sleep(Duration::from_millis(1));
+        node1.gc_unknown_nodes();
+        assert!(node1.node_state(&ghost_node_id).is_none());
+        assert_eq!(node1.num_unknown_nodes(), 0);
+        assert_eq!(node1.num_forgotten_unknown_nodes(), 1);
+
+        // The peer keeps advertising the ghost node: it does not come back.
+        node1.process_message(
+            peer_addr,
+            ChitchatMessage::Ack {
+                cluster_id: node1.cluster_id().to_string(),
+                delta: ghost_delta(),
+            },
+        );
+        assert!(node1.node_state(&ghost_node_id).is_none());
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let node_config = ChitchatConfig::for_test(10_001);
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node = Chitchat::with_node_id_and_seeds(node_config, empty_seeds, Vec::new());
+        node.self_node_state().set("queue_depth.ingest", "2");
+        node.self_node_state().set("queue_depth.search", "3.5");
+        node.self_node_state().set("queue_depth.invalid", "NaN?");
+        assert_eq!(node.aggregate("queue_depth.", AggFn::Sum), Some(5.5));
+        assert_eq!(node.aggregate("queue_depth.", AggFn::Count), Some(2.0));
+        assert_eq!(node.aggregate("capacity", AggFn::Max), None);
+
+        node.self_node_state().set("queue_depth.ingest", "4");
+        assert_eq!(node.aggregate("queue_depth.", AggFn::Sum), Some(7.5));
+
+        // Nodes that are not live are ignored.
+        let node2_id = NodeId::for_test_localhost(10_002);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node2_id, "queue_depth.ingest", "10", 1, false);
+        node.cluster_state.apply_delta(delta);
+        assert_eq!(node.aggregate("queue_depth.", AggFn::Max), Some(4.0));
+    }
+
+    #[test]
+    fn test_materialized_views() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        node1.self_node_state().set("load", "1");
+        node1.register_view("total_load", "load", ViewKind::Sum);
+        assert_eq!(node1.view("total_load"), Some(&ViewResult::Sum(1.0)));
+
+        node1.self_node_state().set("load", "2");
+        assert_eq!(node1.view("total_load"), Some(&ViewResult::Sum(2.0)));
+
+        node2.self_node_state().set("load", "5");
+        run_chitchat_handshake(&mut node1, &mut node2);
+        // The node 2 is not live yet.
+        assert_eq!(node1.view("total_load"), Some(&ViewResult::Sum(2.0)));
+        node1.update_nodes_liveliness();
+        assert_eq!(node1.view("total_load"), Some(&ViewResult::Sum(7.0)));
+
+        assert!(node1.view("unknown").is_none());
+        assert!(node1.unregister_view("total_load"));
+        assert!(node1.view("total_load").is_none());
+    }
+
+    #[test]
+    fn test_region_aware_gossip() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1_config = ChitchatConfig::for_test(10_001);
+        node1_config.zone_key = Some("region".to_string());
+        node1_config.region_aware_gossip = Some(RegionAwareGossipConfig {
+            cross_region_gossip_round_interval: 3,
+            ..Default::default()
+        });
+        let mut node1 =
+            Chitchat::with_node_id_and_seeds(node1_config, empty_seeds.clone(), Vec::new());
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node3 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_003),
+            empty_seeds,
+            Vec::new(),
+        );
+        node1.self_node_state().set("region", "us-east-1");
+        node2.self_node_state().set("region", "eu-west-1");
+        node3.self_node_state().set("region", "us-east-1");
+        run_chitchat_handshake(&mut node1, &mut node2);
+        run_chitchat_handshake(&mut node1, &mut node3);
+
+        assert!(!node1.is_cross_region_peer(node1.self_node_id()));
+        assert!(node1.is_cross_region_peer(node2.self_node_id()));
+        assert!(!node1.is_cross_region_peer(node3.self_node_id()));
+        // Without region-aware gossip, all peers are local.
+        assert!(!node2.is_cross_region_peer(node1.self_node_id()));
+
+        let node2_addr = node2.self_node_id().gossip_public_address;
+        assert_eq!(node1.start_gossip_round(), HashSet::from([node2_addr]));
+        assert_eq!(node1.start_gossip_round(), HashSet::from([node2_addr]));
+        assert!(node1.start_gossip_round().is_empty());
+        assert_eq!(node1.start_gossip_round(), HashSet::from([node2_addr]));
+        assert!(node2.start_gossip_round().is_empty());
+
+        // The updates batched for node 2 do not fit in a single delta.
+        for i in 0..10 {
+            node1.self_node_state().set(format!("key_{i}"), "value");
+        }
+        let syn = node2.create_syn_message();
+        node1.process_message_with_max_payload_size(node2_addr, syn, 300);
+        assert!(node1.start_gossip_round().is_empty());
+        assert!(node1.start_gossip_round().is_empty());
+        run_chitchat_handshake(&mut node2, &mut node1);
+        assert_eq!(node1.start_gossip_round(), HashSet::from([node2_addr]));
+    }
+
+    #[tokio::test]
+    async fn test_wait_key_propagated() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        node1.self_node_state().set("config", "v1");
+        let version = node1
+            .self_node_state()
+            .get_versioned("config")
+            .unwrap()
+            .version;
+        assert!(node1.wait_key_propagated("config", version, 0).await);
+        // The key is not at a later version, and an unknown key is never propagated.
+        assert!(!node1.wait_key_propagated("config", version + 1, 0).await);
+        assert!(!node1.wait_key_propagated("unknown", version, 0).await);
+        let propagated = tokio::spawn(node1.wait_key_propagated("config", version, 1));
+
+        // The peer receives the key, but its digest does not reflect it yet.
+        run_chitchat_handshake(&mut node1, &mut node2);
+        node1.update_nodes_liveliness();
+        tokio::task::yield_now().await;
+        assert!(!propagated.is_finished());
+
+        run_chitchat_handshake(&mut node1, &mut node2);
+        let propagated = tokio::time::timeout(Duration::from_secs(1), propagated)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(propagated);
+    }
+
+    #[test]
+    fn test_node_restart_with_new_generation() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2_config = ChitchatConfig::for_test(10_002);
+        node2_config.node_id = node2_config.node_id.with_generation(1);
+        let mut node2 =
+            Chitchat::with_node_id_and_seeds(node2_config, empty_seeds.clone(), Vec::new());
+        let mut node3 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_003),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        node2.self_node_state().set("key_a", "1");
+        run_chitchat_handshake(&mut node1, &mut node2);
+        run_chitchat_handshake(&mut node3, &mut node2);
+        let node2_gen1_id = node2.self_node_id().clone();
+
+        let mut restarted_node2_config = ChitchatConfig::for_test(10_002);
+        restarted_node2_config.node_id = restarted_node2_config.node_id.with_generation(2);
+        let mut restarted_node2 =
+            Chitchat::with_node_id_and_seeds(restarted_node2_config, empty_seeds, Vec::new());
+        restarted_node2.self_node_state().set("key_b", "2");
+        run_chitchat_handshake(&mut node1, &mut restarted_node2);
+
+        // The state of the previous generation is forgotten right away.
+        assert!(node1.node_state(&node2_gen1_id).is_none());
+        let node2_state = node1.node_state(restarted_node2.self_node_id()).unwrap();
+        assert_eq!(node2_state.get("key_b"), Some("2"));
+        assert!(node2_state.get("key_a").is_none());
+
+        // Peers still gossiping the previous generation do not bring it back.
+        run_chitchat_handshake(&mut node1, &mut node3);
+        assert!(node1.node_state(&node2_gen1_id).is_none());
+        assert!(node3.node_state(&node2_gen1_id).is_none());
+        assert!(node3.node_state(restarted_node2.self_node_id()).is_some());
+    }
+
+    #[test]
+    fn test_version_anomalies_reset_node() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        node2.self_node_state().set("key_a", "1");
+        node2.self_node_state().set("key_b", "2");
+        run_chitchat_handshake(&mut node1, &mut node2);
+        let node2_id = node2.self_node_id().clone();
+        assert_eq!(node1.node_state(&node2_id).unwrap().max_version, 3);
+
+        // Node 2 is restored from an old backup.
+        let mut restored_node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        restored_node2.self_node_state().set("key_c", "3");
+        run_chitchat_handshake(&mut restored_node2, &mut node1);
+        assert_eq!(node1.num_version_anomalies(), 1);
+        assert_eq!(
+            *node1.version_anomaly_rx.borrow(),
+            Some(VersionAnomaly::Rollback {
+                node_id: node2_id.clone(),
+                known_max_version: 3,
+                advertised_max_version: 2,
+            })
+        );
+        // The stale state of node 2 is not sent back to it.
+        assert!(restored_node2.self_node_state().get("key_a").is_none());
+        run_chitchat_handshake(&mut node1, &mut restored_node2);
+        let node2_state = node1.node_state(&node2_id).unwrap();
+        assert_eq!(node2_state.get("key_c"), Some("3"));
+        assert!(node2_state.get("key_a").is_none());
+
+        let node3_id = NodeId::for_test_localhost(10_003);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node3_id.clone(), "key_a", "1", u64::MAX - 1, false);
+        node1.process_message(
+            node3_id.gossip_public_address,
+            ChitchatMessage::Ack {
+                cluster_id: node1.cluster_id().to_string(),
+                delta,
+            },
+        );
+        assert!(node1.node_state(&node3_id).is_none());
+        assert_eq!(node1.num_version_anomalies(), 2);
+        assert_eq!(
+            *node1.version_anomaly_rx.borrow(),
+            Some(VersionAnomaly::Overflow {
+                node_id: node3_id,
+                version: u64::MAX - 1,
+            })
+        );
+
+        let known_max_version = node1.node_state(&node2_id).unwrap().max_version;
+        let mut delta = Delta::default();
+        delta.add_node_delta(node2_id.clone(), "key_c", "4", u64::MAX / 2, false);
+        node1.process_message(
+            node2_id.gossip_public_address,
+            ChitchatMessage::Ack {
+                cluster_id: node1.cluster_id().to_string(),
+                delta,
+            },
+        );
+        // The delta is dropped, but the state of node 2 is kept.
+        let node2_state = node1.node_state(&node2_id).unwrap();
+        assert_eq!(node2_state.get("key_c"), Some("3"));
+        assert_eq!(node2_state.max_version, known_max_version);
+        assert_eq!(node1.num_version_anomalies(), 3);
+        assert_eq!(
+            *node1.version_anomaly_rx.borrow(),
+            Some(VersionAnomaly::Jump {
+                node_id: node2_id,
+                known_max_version,
+                version: u64::MAX / 2,
+            })
+        );
+    }
+
+    #[test]
+    fn test_version_rollback_is_not_undone_by_third_party() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node3 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_003),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        node2.self_node_state().set("key_a", "1");
+        node2.self_node_state().set("key_b", "2");
+        run_chitchat_handshake(&mut node1, &mut node2);
+        run_chitchat_handshake(&mut node3, &mut node2);
+        let node2_id = node2.self_node_id().clone();
+
+        // Node 2 is restored from an old backup, and only node 1 notices.
+        let mut restored_node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        restored_node2.self_node_state().set("key_c", "3");
+        run_chitchat_handshake(&mut restored_node2, &mut node1);
+        assert_eq!(node1.num_version_anomalies(), 1);
+
+        // Node 3 still holds the stale state of node 2, which node 1 does not take back.
+        run_chitchat_handshake(&mut node1, &mut node3);
+        run_chitchat_handshake(&mut node3, &mut node1);
+        assert!(node1
+            .node_state(&node2_id)
+            .and_then(|node_state| node_state.get("key_a"))
+            .is_none());
+
+        run_chitchat_handshake(&mut node1, &mut restored_node2);
+        let node2_state = node1.node_state(&node2_id).unwrap();
+        assert_eq!(node2_state.get("key_c"), Some("3"));
+        assert!(node2_state.get("key_a").is_none());
+        run_chitchat_handshake(&mut node1, &mut node3);
+        run_chitchat_handshake(&mut restored_node2, &mut node1);
+        assert_eq!(node1.num_version_anomalies(), 1);
+        assert!(node1.node_state(&node2_id).unwrap().get("key_a").is_none());
+    }
+
+    /// Failure detector whose decisions are taken by the test.
+    struct ManualFailureDetector {
+        reported_nodes: HashSet<NodeId>,
+        dead_nodes: Arc<std::sync::Mutex<HashSet<NodeId>>>,
+    }
+
+    impl FailureDetector for ManualFailureDetector {
+        fn report_heartbeat(&mut self, node_id: &NodeId) {
+            self.reported_nodes.insert(node_id.clone());
+        }
+
+        fn is_alive(&self, node_id: &NodeId) -> Option<bool> {
+            if !self.reported_nodes.contains(node_id) {
+                return None;
+            }
+            Some(!self.dead_nodes.lock().unwrap().contains(node_id))
+        }
+
+        fn remove_node(&mut self, node_id: &NodeId) {
+            self.reported_nodes.remove(node_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_live_nodes_watch() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let dead_nodes = Arc::new(std::sync::Mutex::new(HashSet::new()));
+        let mut config1 = ChitchatConfig::for_test(10_001);
+        config1.set_failure_detector(ManualFailureDetector {
+            reported_nodes: HashSet::new(),
+            dead_nodes: dead_nodes.clone(),
+        });
+        let mut node1 = Chitchat::with_node_id_and_seeds(config1, empty_seeds.clone(), Vec::new());
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        let node2_id = node2.self_node_id().clone();
+        let mut live_nodes_watch = node1.live_nodes_watch();
+        assert!(live_nodes_watch.borrow().is_empty());
+
+        run_chitchat_handshake(&mut node1, &mut node2);
+        node1.update_nodes_liveliness();
+        live_nodes_watch.changed().await.unwrap();
+        assert_eq!(
+            *live_nodes_watch.borrow(),
+            BTreeSet::from([node2_id.clone()])
+        );
+
+        dead_nodes.lock().unwrap().insert(node2_id);
+        node1.update_nodes_liveliness();
+        live_nodes_watch.changed().await.unwrap();
+        assert!(live_nodes_watch.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_node_evicted_events() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let dead_nodes = Arc::new(std::sync::Mutex::new(HashSet::new()));
+        let events = RecordingEvents::default();
+        let mut config1 = ChitchatConfig::for_test(10_001);
+        config1.dead_node_eviction_policy = DeadNodeEvictionPolicy::AfterDuration(Duration::ZERO);
+        config1.set_failure_detector(ManualFailureDetector {
+            reported_nodes: HashSet::new(),
+            dead_nodes: dead_nodes.clone(),
+        });
+        config1.set_events(events.clone());
+        let mut node1 = Chitchat::with_node_id_and_seeds(config1, empty_seeds.clone(), Vec::new());
+        let mut other_nodes: Vec<Chitchat> = [10_002, 10_003]
+            .into_iter()
+            .map(|port| {
+                Chitchat::with_node_id_and_seeds(
+                    ChitchatConfig::for_test(port),
+                    empty_seeds.clone(),
+                    Vec::new(),
+                )
+            })
+            .collect();
+        for other_node in &mut other_nodes {
+            run_chitchat_handshake(other_node, &mut node1);
+        }
+        node1.update_nodes_liveliness();
+        events.events.lock().unwrap().clear();
+        let evicted_node_id = other_nodes[0].self_node_id().clone();
+        node1
+            .rollback_fences
+            .fence(&evicted_node_id, 10, Instant::now());
+
+        // Both nodes are evicted before the watch is read: it only holds the last eviction.
+        for other_node in &other_nodes {
+            dead_nodes
+                .lock()
+                .unwrap()
+                .insert(other_node.self_node_id().clone());
+            node1.update_nodes_liveliness();
+            node1.update_nodes_liveliness();
+        }
+        assert_eq!(
+            *node1.evicted_nodes_rx.borrow(),
+            [other_nodes[1].self_node_id().clone()]
+        );
+        assert_eq!(
+            *events.events.lock().unwrap(),
+            ["node-evicted:10002", "node-evicted:10003"]
+        );
+        // Evicted nodes are forgotten altogether, e.g. their rollback fences are lifted.
+        let mut delta = Delta::default();
+        delta.add_node_delta(evicted_node_id.clone(), "key", "1", 1, false);
+        node1.rollback_fences.filter_delta(
+            other_nodes[1].self_node_id().gossip_public_address,
+            &mut delta,
+        );
+        assert_eq!(delta.node_deltas.len(), 1);
+    }
+
+    #[test]
+    fn test_node_address_change() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let node2_id = node2.self_node_id().clone();
+        run_chitchat_handshake(&mut node1, &mut node2);
+        node1.update_nodes_liveliness();
+        assert_eq!(node1.live_nodes().collect::<Vec<_>>(), vec![&node2_id]);
+
+        // Node 2 is rescheduled on another host.
+        let mut moved_node2_config = ChitchatConfig::for_test(10_003);
+        moved_node2_config.node_id =
+            NodeId::new(node2_id.id.clone(), moved_node2_config.listen_addr).with_generation(1);
+        let mut moved_node2 =
+            Chitchat::with_node_id_and_seeds(moved_node2_config, empty_seeds, Vec::new());
+        let moved_node2_id = moved_node2.self_node_id().clone();
+        run_chitchat_handshake(&mut moved_node2, &mut node1);
+        assert!(node1.node_state(&node2_id).is_none());
+        assert!(node1.node_state(&moved_node2_id).is_some());
+        assert_eq!(
+            *node1.live_nodes_watch().borrow(),
+            BTreeSet::from([moved_node2_id.clone()])
+        );
+        node1.update_nodes_liveliness();
+        assert_eq!(
+            node1.live_nodes().collect::<Vec<_>>(),
+            vec![&moved_node2_id]
+        );
+    }
+
+    #[test]
+    fn test_exchange_peer_addrs() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut nodes: Vec<Chitchat> = [10_001, 10_002, 10_003]
+            .into_iter()
+            .map(|port| {
+                let mut config = ChitchatConfig::for_test(port);
+                config.exchange_peer_addrs = true;
+                Chitchat::with_node_id_and_seeds(config, empty_seeds.clone(), Vec::new())
+            })
+            .collect();
+        let mut node3 = nodes.pop().unwrap();
+        let mut node2 = nodes.pop().unwrap();
+        let mut node1 = nodes.pop().unwrap();
+        let node1_addr = node1.self_node_id().gossip_public_address;
+        let node2_addr = node2.self_node_id().gossip_public_address;
+
+        run_chitchat_handshake(&mut node1, &mut node2);
+        assert!(node1.learned_peer_addrs().is_empty());
+        // Node 3 only reaches node 1, and receives its own state only.
+        let syn = node3.create_syn_message();
+        let syn_ack = node1
+            .process_message(node3.self_node_id().gossip_public_address, syn)
+            .unwrap();
+        let ChitchatMessage::SynAck {
+            cluster_id,
+            digest,
+            mut delta,
+        } = syn_ack
+        else {
+            panic!("expected a SynAck");
+        };
+        delta
+            .node_deltas
+            .retain(|node_id, _| node_id.gossip_public_address == node1_addr);
+        node3.process_message(
+            node1_addr,
+            ChitchatMessage::SynAck {
+                cluster_id,
+                digest,
+                delta,
+            },
+        );
+        // Node 2 is listed by the digest of node 1.
+        assert_eq!(node3.learned_peer_addrs(), HashSet::from([node2_addr]));
+
+        run_chitchat_handshake(&mut node3, &mut node2);
+        assert!(node3.learned_peer_addrs().is_empty());
+        node3.start_gossip_round();
+        assert_eq!(node3.learned_peer_addrs.addrs().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_leader_election() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut nodes: Vec<Chitchat> = [10_001, 10_002]
+            .into_iter()
+            .map(|port| {
+                let mut config = ChitchatConfig::for_test(port);
+                config.leader_election = Some(LeaderElectionConfig {
+                    hysteresis: Duration::ZERO,
+                });
+                Chitchat::with_node_id_and_seeds(config, empty_seeds.clone(), Vec::new())
+            })
+            .collect();
+        let (node1, node2) = nodes.split_at_mut(1);
+        let (node1, node2) = (&mut node1[0], &mut node2[0]);
+        let node1_id = node1.self_node_id().clone();
+        let node2_id = node2.self_node_id().clone();
+        let mut node2_leader = node2.current_leader();
+        assert!(node2_leader.borrow().is_none());
+
+        // Partitioned, both nodes elect themselves with the same epoch number.
+        node1.update_nodes_liveliness();
+        node2.update_nodes_liveliness();
+        assert_eq!(*node2_leader.borrow_and_update(), Some(node2_id.clone()));
+        assert_eq!(
+            node2.leader_epoch(),
+            Some(LeaderEpoch {
+                epoch: 1,
+                node_id: node2_id.clone()
+            })
+        );
+        assert!(node1.leader_epoch() < node2.leader_epoch());
+
+        // Once the partition heals, the node 1 outranks the leadership of the node 2.
+        run_chitchat_handshake(node1, node2);
+        node1.update_nodes_liveliness();
+        assert_eq!(*node1.current_leader().borrow(), Some(node1_id.clone()));
+        assert_eq!(
+            node1.leader_epoch(),
+            Some(LeaderEpoch {
+                epoch: 2,
+                node_id: node1_id.clone()
+            })
+        );
+
+        node2.update_nodes_liveliness();
+        node2_leader.changed().await.unwrap();
+        assert_eq!(*node2_leader.borrow(), Some(node1_id.clone()));
+        run_chitchat_handshake(node1, node2);
+        assert_eq!(node2.leader_epoch(), node1.leader_epoch());
+
+        // Nodes about to leave are not elected.
+        node1.set_leave_intent(true);
+        node1.update_nodes_liveliness();
+        assert_eq!(*node1.current_leader().borrow(), Some(node2_id));
+    }
+
+    #[test]
+    fn test_custom_failure_detector() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let dead_nodes = Arc::new(std::sync::Mutex::new(HashSet::new()));
+        let mut config1 = ChitchatConfig::for_test(10_001);
+        config1.set_failure_detector(ManualFailureDetector {
+            reported_nodes: HashSet::new(),
+            dead_nodes: dead_nodes.clone(),
+        });
+        let mut node1 = Chitchat::with_node_id_and_seeds(config1, empty_seeds.clone(), Vec::new());
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        let node2_id = node2.self_node_id().clone();
+        run_chitchat_handshake(&mut node1, &mut node2);
+        node1.update_nodes_liveliness();
+        assert_eq!(node1.live_nodes().collect::<Vec<_>>(), vec![&node2_id]);
+
+        dead_nodes.lock().unwrap().insert(node2_id.clone());
+        node1.update_nodes_liveliness();
+        assert_eq!(node1.live_nodes().count(), 0);
+        assert_eq!(node1.dead_nodes().collect::<Vec<_>>(), vec![&node2_id]);
+
+        // The detector forgot node 2 when it was marked dead, and hears from it again.
+        dead_nodes.lock().unwrap().clear();
+        node1.update_nodes_liveliness();
+        assert_eq!(node1.dead_nodes().count(), 1);
+        node2.update_heartbeat();
+        run_chitchat_handshake(&mut node1, &mut node2);
+        node1.update_nodes_liveliness();
+        assert_eq!(node1.live_nodes().collect::<Vec<_>>(), vec![&node2_id]);
+    }
+
+    #[test]
+    fn test_indirect_probe() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node3 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_003),
+            empty_seeds,
+            Vec::new(),
+        );
+        run_chitchat_handshake(&mut node1, &mut node2);
+        run_chitchat_handshake(&mut node3, &mut node2);
+        let node1_addr = node1.self_node_id().gossip_public_address;
+        let node2_id = node2.self_node_id().clone();
+        let node2_heartbeat = node1.node_state(&node2_id).unwrap().heartbeat();
+
+        node3.update_nodes_liveliness();
+        let node2_addr = node2_id.gossip_public_address;
+        let node3_addr = node3.self_node_id().gossip_public_address;
+
+        // Node 1 cannot reach node 2 anymore, but node 3 still can.
+        node2.update_heartbeat();
+        let probe_request = ChitchatMessage::ProbeRequest {
+            cluster_id: node1.cluster_id().to_string(),
+            target: node2_id.clone(),
+        };
+        assert!(node3.process_message(node1_addr, probe_request).is_none());
+        // The response waits for the probe to complete.
+        assert!(node3.take_probe_responses().is_empty());
+        assert_eq!(node3.take_probes_to_start(), [node2_addr]);
+        let syn_ack_message = node2
+            .process_message(node3_addr, node3.create_syn_message())
+            .unwrap();
+        node3.process_message(node2_addr, syn_ack_message);
+        let probe_responses = node3.take_probe_responses();
+        assert_eq!(
+            probe_responses,
+            [(
+                node1_addr,
+                ChitchatMessage::ProbeResponse {
+                    cluster_id: node3.cluster_id().to_string(),
+                    target: node2_id.clone(),
+                    heartbeat: node2_heartbeat + 1,
+                }
+            )]
+        );
+        let (_, probe_response) = probe_responses.into_iter().next().unwrap();
+        assert!(node1.process_message(node3_addr, probe_response).is_none());
+        assert_eq!(
+            node1.node_state(&node2_id).unwrap().heartbeat(),
+            node2_heartbeat + 1
+        );
+
+        // Unknown nodes, and the requester itself, are not probed.
+        for target in [NodeId::for_test_localhost(10_004), node2_id.clone()] {
+            let probe_request = ChitchatMessage::ProbeRequest {
+                cluster_id: node1.cluster_id().to_string(),
+                target,
+            };
+            assert!(node3.process_message(node2_addr, probe_request).is_none());
+        }
+        assert!(node3.take_probes_to_start().is_empty());
+    }
+
+    #[test]
+    fn test_heartbeat_is_gossiped_through_digests() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node3 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_003),
+            empty_seeds,
+            Vec::new(),
+        );
+        run_chitchat_handshake(&mut node1, &mut node2);
+        run_chitchat_handshake(&mut node3, &mut node2);
+        let node1_id = node1.self_node_id().clone();
+        let node1_max_version = node1.node_state(&node1_id).unwrap().max_version;
+
+        node1.update_heartbeat();
+        node1.update_heartbeat();
+        assert_eq!(
+            node1.node_state(&node1_id).unwrap().max_version,
+            node1_max_version
+        );
+        run_chitchat_handshake(&mut node1, &mut node2);
+        assert_eq!(node2.node_state(&node1_id).unwrap().heartbeat(), 2);
+
+        // The heartbeat reaches node 3 through the digest of node 2.
+        run_chitchat_handshake(&mut node3, &mut node2);
+        let node1_state = node3.node_state(&node1_id).unwrap();
+        assert_eq!(node1_state.heartbeat(), 2);
+        assert_eq!(node1_state.max_version, node1_max_version);
+    }
+
+    #[test]
+    fn test_node_state_limits_metrics() {
+        let mut node_config = ChitchatConfig::for_test(10_001);
+        node_config.node_state_limits = NodeStateLimits {
+            max_num_keys: Some(1),
+            max_num_bytes: None,
+            policy: NodeStateLimitPolicy::Reject,
+        };
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node = Chitchat::with_node_id_and_seeds(node_config, empty_seeds, Vec::new());
+        node.self_node_state().set("key_a", "1");
+        node.self_node_state().set("key_b", "2");
+
+        let node2_id = NodeId::for_test_localhost(10_002);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node2_id.clone(), HEARTBEAT_KEY, "1", 1, false);
+        delta.add_node_delta(node2_id.clone(), "key_a", "1", 2, false);
+        delta.add_node_delta(node2_id.clone(), "key_b", "2", 3, false);
+        advertise_max_versions(&mut node, &delta);
+        node.cluster_state.apply_delta(delta);
+        assert!(node.node_state(&node2_id).unwrap().get("key_b").is_none());
+        assert_eq!(node.num_rejected_key_values(), 2);
+        assert_eq!(node.num_evicted_key_values(), 0);
+    }
+
+    #[test]
+    fn test_max_cluster_state_bytes() {
+        let mut node_config = ChitchatConfig::for_test(10_001);
+        node_config.max_cluster_state_bytes = Some(1_000);
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node = Chitchat::with_node_id_and_seeds(node_config, empty_seeds, Vec::new());
+        let num_bytes_before = node.cluster_state().memory_usage().num_bytes();
+        node.self_node_state().set("key_a", "a".repeat(1_000));
+        let memory_usage = node.cluster_state().memory_usage();
+        assert_eq!(memory_usage.num_live_bytes, num_bytes_before + 1_005);
+        assert_eq!(memory_usage.num_tombstone_bytes, 0);
+
+        // New nodes are refused over the cap.
+        let node2_id = NodeId::for_test_localhost(10_002);
+        let ack = |cluster_id: &str| {
+            let mut delta = Delta::default();
+            delta.add_node_delta(node2_id.clone(), "key_a", "1", 1, false);
+            ChitchatMessage::Ack {
+                cluster_id: cluster_id.to_string(),
+                delta,
+            }
+        };
+        node.cluster_state
+            .record_advertised_max_versions([(&node2_id, 1)]);
+        node.process_message(node2_id.gossip_public_address, ack(node.cluster_id()));
+        assert!(node.node_state(&node2_id).is_none());
+        assert_eq!(node.num_refused_nodes(), 1);
+
+        // Tombstones are garbage collected without waiting for the grace period.
+        node.self_node_state().mark_for_deletion("key_a");
+        assert_eq!(
+            node.cluster_state().memory_usage().num_tombstone_bytes,
+            1_005
+        );
+        node.run_maintenance();
+        assert_eq!(node.cluster_state().memory_usage().num_tombstone_bytes, 0);
+        assert!(node.self_node_state().get_versioned("key_a").is_none());
+
+        node.process_message(node2_id.gossip_public_address, ack(node.cluster_id()));
+        assert!(node.node_state(&node2_id).is_some());
+        assert_eq!(node.num_refused_nodes(), 1);
+    }
+
+    #[test]
+    fn test_backoff_from_peer_of_another_cluster() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2_config = ChitchatConfig::for_test(10_002);
+        node2_config.cluster_id = "another-cluster".to_string();
+        let mut node2 = Chitchat::with_node_id_and_seeds(node2_config, empty_seeds, Vec::new());
+        let node1_addr = node1.self_node_id().gossip_public_address;
+        let node2_addr = node2.self_node_id().gossip_public_address;
+
+        assert!(node1.can_gossip_with(node2_addr));
+        // A bad cluster message that answers no syn of ours is ignored.
+        assert!(node1
+            .process_message(node2_addr, ChitchatMessage::BadCluster)
+            .is_none());
+        assert!(node1.can_gossip_with(node2_addr));
+
+        for _ in 0..5 {
+            node1.record_syn_sent(node2_addr);
+            let bad_cluster_message = node2
+                .process_message(node1_addr, node1.create_syn_message())
+                .unwrap();
+            assert_eq!(bad_cluster_message, ChitchatMessage::BadCluster);
+            assert!(node1
+                .process_message(node2_addr, bad_cluster_message)
+                .is_none());
+            assert!(!node1.can_gossip_with(node2_addr));
+        }
+        assert_eq!(node1.peers_rejecting_us(), HashSet::from([node2_addr]));
+
+        // The peer joined our cluster.
+        node2.config.cluster_id = node1.cluster_id().to_string();
+        run_chitchat_handshake(&mut node1, &mut node2);
+        assert!(node1.can_gossip_with(node2_addr));
+        assert!(node1.peers_rejecting_us().is_empty());
+    }
+
+    #[test]
+    fn test_messages_from_another_cluster_are_rejected() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2_config = ChitchatConfig::for_test(10_002);
+        node2_config.cluster_id = "another-cluster".to_string();
+        let node2 = Chitchat::with_node_id_and_seeds(node2_config, empty_seeds, Vec::new());
+        let node2_id = node2.self_node_id().clone();
+        let node2_addr = node2_id.gossip_public_address;
+
+        let mut delta = Delta::default();
+        delta.add_node_delta(node2_id.clone(), "key_a", "1", 1, false);
+        let ack = ChitchatMessage::Ack {
+            cluster_id: node2.cluster_id().to_string(),
+            delta,
+        };
+        assert!(node1.process_message(node2_addr, ack).is_none());
+        let syn_ack = ChitchatMessage::SynAck {
+            cluster_id: node2.cluster_id().to_string(),
+            digest: node2.compute_digest(&HashSet::new()),
+            delta: Delta::default(),
+        };
+        assert!(node1.process_message(node2_addr, syn_ack).is_none());
+        let probe_response = ChitchatMessage::ProbeResponse {
+            cluster_id: node2.cluster_id().to_string(),
+            target: node2_id.clone(),
+            heartbeat: 1,
+        };
+        assert!(node1.process_message(node2_addr, probe_response).is_none());
+        assert!(node1.node_state(&node2_id).is_none());
+        assert_eq!(
+            node1.process_message(node2_addr, node2.create_syn_message()),
+            Some(ChitchatMessage::BadCluster)
+        );
+        assert_eq!(node1.num_cluster_mismatches(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_node_id_conflict() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        run_chitchat_handshake(&mut node1, &mut node2);
+        let node1_id = node1.self_node_id().clone();
+        let node2_id = node2.self_node_id().clone();
+        let mut node_id_conflict_watcher = node1.node_id_conflict_watcher();
+        assert_eq!(node_id_conflict_watcher.next().await.unwrap(), None);
+
+        // Another process started with the ID of node 2.
+        let impostor_id = NodeId::new(node2_id.id.clone(), "127.0.0.1:10003".parse().unwrap());
+        let mut delta = Delta::default();
+        delta.add_node_delta(impostor_id.clone(), "key_a", "1", 1, false);
+        advertise_max_versions(&mut node1, &delta);
+        let ack = ChitchatMessage::Ack {
+            cluster_id: node1.cluster_id().to_string(),
+            delta,
+        };
+        node1.process_message(impostor_id.gossip_public_address, ack);
+        assert!(node1.node_state(&impostor_id).is_none());
+        let node_id_conflict = NodeIdConflict {
+            kept_node_id: node2_id.clone(),
+            conflicting_node_id: impostor_id,
+        };
+        assert_eq!(
+            node_id_conflict_watcher.next().await.unwrap(),
+            Some(node_id_conflict)
+        );
+        assert_eq!(node1.num_node_id_conflicts(), 1);
+
+        // The self node always wins.
+        let impostor_id = NodeId::new(node1_id.id.clone(), "127.0.0.1:10004".parse().unwrap());
+        let mut delta = Delta::default();
+        delta.add_node_delta(impostor_id.clone(), "key_a", "1", 1, false);
+        advertise_max_versions(&mut node1, &delta);
+        let ack = ChitchatMessage::Ack {
+            cluster_id: node1.cluster_id().to_string(),
+            delta,
+        };
+        node1.process_message(impostor_id.gossip_public_address, ack);
+        assert!(node1.node_state(&impostor_id).is_none());
+        let node_id_conflict = node_id_conflict_watcher.next().await.unwrap().unwrap();
+        assert_eq!(node_id_conflict.kept_node_id, node1_id);
+        assert_eq!(node1.num_node_id_conflicts(), 2);
+
+        // An impostor with a lower gossip address wins over the known node, on every node.
+        let impostor_id = NodeId::new(node2_id.id.clone(), "127.0.0.1:10000".parse().unwrap());
+        let mut delta = Delta::default();
+        delta.add_node_delta(impostor_id.clone(), "key_a", "1", 1, false);
+        advertise_max_versions(&mut node1, &delta);
+        let ack = ChitchatMessage::Ack {
+            cluster_id: node1.cluster_id().to_string(),
+            delta,
+        };
+        node1.process_message(impostor_id.gossip_public_address, ack);
+        assert!(node1.node_state(&impostor_id).is_some());
+        assert!(node1.node_state(&node2_id).is_none());
+        let node_id_conflict = NodeIdConflict {
+            kept_node_id: impostor_id.clone(),
+            conflicting_node_id: node2_id.clone(),
+        };
+        assert_eq!(
+            node_id_conflict_watcher.next().await.unwrap(),
+            Some(node_id_conflict)
+        );
+        assert_eq!(node1.num_node_id_conflicts(), 3);
+
+        // A new generation of node 2 moved to another address is not a conflict.
+        let node2_restarted_id =
+            NodeId::new(node2_id.id.clone(), "127.0.0.1:10005".parse().unwrap()).with_generation(1);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node2_restarted_id.clone(), "key_a", "1", 1, false);
+        advertise_max_versions(&mut node1, &delta);
+        let ack = ChitchatMessage::Ack {
+            cluster_id: node1.cluster_id().to_string(),
+            delta,
+        };
+        node1.process_message(node2_restarted_id.gossip_public_address, ack);
+        assert!(node1.node_state(&node2_restarted_id).is_some());
+        assert!(node1.node_state(&impostor_id).is_none());
+        assert_eq!(node1.num_node_id_conflicts(), 3);
+    }
+
+    #[test]
+    fn test_audit_sink() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let (audit_sink, mut audit_records) = tokio::sync::mpsc::channel(2);
+        let mut node2_config = ChitchatConfig::for_test(10_002);
+        node2_config.audit_sink = Some(audit_sink);
+        let mut node2 = Chitchat::with_node_id_and_seeds(node2_config, empty_seeds, Vec::new());
+        let node1_id = node1.self_node_id().clone();
+        node1.self_node_state().set("key_a", "1");
+        run_chitchat_handshake(&mut node2, &mut node1);
+
+        let mut keys = Vec::new();
+        while let Ok(audit_record) = audit_records.try_recv() {
+            assert_eq!(
+                audit_record.source_addr,
+                Some(node1_id.gossip_public_address)
+            );
+            assert_eq!(audit_record.node_id, node1_id);
+            assert_eq!(audit_record.old_version, None);
+            keys.push(audit_record.key);
+        }
+        assert!(keys.contains(&"key_a".to_string()));
+
+        // Records that do not fit in the sink are dropped and counted.
+        let num_dropped_audit_records = node2.num_dropped_audit_records();
+        for key in ["key_a", "key_b", "key_c"] {
+            node1.self_node_state().set(key, "2");
+        }
+        run_chitchat_handshake(&mut node2, &mut node1);
+        let audit_record = audit_records.try_recv().unwrap();
+        assert_eq!(audit_record.key, "key_a");
+        assert!(audit_record.old_version.is_some());
+        assert!(node2.num_dropped_audit_records() > num_dropped_audit_records);
+    }
+
+    #[test]
+    fn test_metrics_recorder() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let metrics_recorder = PrometheusRecorder::default();
+        let mut node2_config = ChitchatConfig::for_test(10_002);
+        node2_config.metrics_recorder = Some(Box::new(metrics_recorder.clone()));
+        let mut node2 = Chitchat::with_node_id_and_seeds(node2_config, empty_seeds, Vec::new());
+        node1.self_node_state().set("key_a", "1");
+        node2.start_gossip_round();
+        run_chitchat_handshake(&mut node2, &mut node1);
+
+        let rendered = metrics_recorder.render();
+        assert!(rendered.contains("chitchat_gossip_rounds_total 1\n"));
+        // The initiating node only receives the syn-ack of the handshake.
+        assert!(rendered.contains("chitchat_messages_received_total 1\n"));
+        assert!(rendered.contains("chitchat_delta_key_values_count 1\n"));
+
+        // Node 1 now knows node 2, and acknowledges its versions in its digest.
+        run_chitchat_handshake(&mut node1, &mut node2);
+        let rendered = metrics_recorder.render();
+        assert!(rendered.contains("chitchat_messages_received_total 3\n"));
+        assert!(rendered.contains("chitchat_delta_key_values_count 2\n"));
+        assert!(rendered.contains("chitchat_propagation_lag_versions_count 1\n"));
+    }
+
+    #[test]
+    fn test_delta_utilization_stats() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        assert_eq!(node1.gossip_stats().delta_utilization(), None);
+        // The state of node 1 does not fit in a single delta.
+        for i in 0..100 {
+            node1
+                .self_node_state()
+                .set(format!("key_{i}"), "a".repeat(1_000));
+        }
+        run_chitchat_handshake(&mut node2, &mut node1);
+
+        let gossip_stats = node1.gossip_stats();
+        assert_eq!(gossip_stats.num_deltas_sent, 1);
+        assert_eq!(gossip_stats.num_truncated_deltas, 1);
+        assert!(gossip_stats.delta_utilization().unwrap() > 0.95);
+
+        let gossip_stats = node2.gossip_stats();
+        assert_eq!(gossip_stats.num_deltas_sent, 1);
+        assert_eq!(gossip_stats.num_truncated_deltas, 0);
+        assert!(gossip_stats.delta_utilization().unwrap() < 0.05);
+    }
+
+    #[test]
+    fn test_slow_peers() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        for i in 0..10 {
+            node1.self_node_state().set(format!("key_{i}"), i);
+        }
+        let node1_max_version = node1.self_node_state().max_version;
+        run_chitchat_handshake(&mut node2, &mut node1);
+
+        let slow_peers = node1.slow_peers();
+        assert_eq!(slow_peers.len(), 1);
+        assert_eq!(
+            slow_peers[0].node_id,
+            Some(NodeId::for_test_localhost(10_002))
+        );
+        assert_eq!(slow_peers[0].last_lag_versions, node1_max_version);
+        assert_eq!(slow_peers[0].num_resets_sent, 0);
+
+        // Node 2 caught up: its lag fades away.
+        for _ in 0..50 {
+            run_chitchat_handshake(&mut node2, &mut node1);
+        }
+        assert!(node1.slow_peers().is_empty());
+    }
+
+    #[test]
+    fn test_gossip_topology() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        assert!(node1.gossip_topology().edges.is_empty());
+        run_chitchat_handshake(&mut node1, &mut node2);
+        run_chitchat_handshake(&mut node1, &mut node2);
+
+        let topology = node1.gossip_topology();
+        assert_eq!(topology.node_id, NodeId::for_test_localhost(10_001));
+        assert_eq!(topology.edges.len(), 1);
+        let edge = &topology.edges[0];
+        assert_eq!(edge.node_id, Some(NodeId::for_test_localhost(10_002)));
+        assert_eq!(edge.num_initiated_exchanges, 2);
+        assert_eq!(edge.num_received_exchanges, 0);
+        // Each node learnt the other one during the first handshake, and acknowledged its state
+        // during the second one.
+        assert_eq!(edge.lag_versions, Some(0));
+
+        let topology = node2.gossip_topology();
+        let edge = &topology.edges[0];
+        assert_eq!(edge.node_id, Some(NodeId::for_test_localhost(10_001)));
+        assert_eq!(edge.num_initiated_exchanges, 0);
+        assert_eq!(edge.num_received_exchanges, 2);
+        assert_eq!(edge.lag_versions, Some(0));
+    }
+
+    #[test]
+    fn test_propagation_latency_tracking() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1_config = ChitchatConfig::for_test(10_001);
+        node1_config.propagation_latency_tracking = true;
+        let mut node1 =
+            Chitchat::with_node_id_and_seeds(node1_config, empty_seeds.clone(), Vec::new());
+        let metrics_recorder = PrometheusRecorder::default();
+        let mut node2_config = ChitchatConfig::for_test(10_002);
+        node2_config.metrics_recorder = Some(Box::new(metrics_recorder.clone()));
+        let mut node2 = Chitchat::with_node_id_and_seeds(node2_config, empty_seeds, Vec::new());
+        node1.self_node_state().set("key_a", "1");
+        assert!(node1.self_node_state().write_timestamp().is_some());
+        run_chitchat_handshake(&mut node2, &mut node1);
+        let rendered = metrics_recorder.render();
+        assert!(rendered.contains(
+            "chitchat_propagation_latency_seconds_count 1
+"
+        ));
+
+        // Known writes are not measured again.
+        run_chitchat_handshake(&mut node2, &mut node1);
+        let rendered = metrics_recorder.render();
+        assert!(rendered.contains(
+            "chitchat_propagation_latency_seconds_count 1
+"
+        ));
+
+        node1.self_node_state().set("key_b", "2");
+        run_chitchat_handshake(&mut node2, &mut node1);
+        let rendered = metrics_recorder.render();
+        assert!(rendered.contains(
+            "chitchat_propagation_latency_seconds_count 2
+"
+        ));
+
+        // Only application writes are stamped.
+        assert!(node2.self_node_state().write_timestamp().is_none());
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingEvents {
+        events: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl ChitchatEvents for RecordingEvents {
+        fn on_delta_applied(&self, source_addr: Option<SocketAddr>, num_key_values: usize) {
+            self.events.lock().unwrap().push(format!(
+                "delta-applied:{}:{num_key_values}",
+                source_addr.unwrap().port()
+            ));
+        }
+
+        fn on_node_reset(&self, node_id: &NodeId) {
+            self.events.lock().unwrap().push(format!(
+                "node-reset:{}",
+                node_id.gossip_public_address.port()
+            ));
+        }
+
+        fn on_node_evicted(&self, node_id: &NodeId) {
+            self.events.lock().unwrap().push(format!(
+                "node-evicted:{}",
+                node_id.gossip_public_address.port()
+            ));
+        }
+    }
+
+    #[test]
+    fn test_chitchat_events() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let events = RecordingEvents::default();
+        let mut node1_config = ChitchatConfig::for_test(10_001);
+        node1_config.set_events(events.clone());
+        let mut node1 =
+            Chitchat::with_node_id_and_seeds(node1_config, empty_seeds.clone(), Vec::new());
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        node2.self_node_state().set("key_a", "1");
+        node2.self_node_state().set("key_b", "2");
+        run_chitchat_handshake(&mut node2, &mut node1);
+        assert_eq!(*events.events.lock().unwrap(), ["delta-applied:10002:3"]);
+
+        // Node 2 is restored from an old backup, and its state is reset.
+        let mut restored_node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        events.events.lock().unwrap().clear();
+        run_chitchat_handshake(&mut restored_node2, &mut node1);
+        assert!(events
+            .events
+            .lock()
+            .unwrap()
+            .contains(&"node-reset:10002".to_string()));
+    }
+
+    #[test]
+    fn test_block_node() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node3 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_003),
+            empty_seeds,
+            Vec::new(),
+        );
+        let node1_id = node1.self_node_id().clone();
+        let node2_id = node2.self_node_id().clone();
+        run_chitchat_handshake(&mut node2, &mut node1);
+        run_chitchat_handshake(&mut node2, &mut node3);
+
+        node1.block_node(node2_id.clone());
+        assert!(node1.node_state(&node2_id).is_none());
+        assert!(!node1.can_gossip_with(node2_id.gossip_public_address));
+        assert!(node1
+            .process_message(node2_id.gossip_public_address, node2.create_syn_message())
+            .is_none());
+        // The state of node 2 relayed by node 3 is dropped too.
+        run_chitchat_handshake(&mut node1, &mut node3);
+        run_chitchat_handshake(&mut node3, &mut node1);
+        assert!(node1.node_state(&node2_id).is_none());
+
+        // The self node cannot be blocked.
+        node1.block_node(node1_id.gossip_public_address);
+        assert!(node1.can_gossip_with(node1_id.gossip_public_address));
+
+        node1.unblock_node(node2_id.clone());
+        assert!(node1.can_gossip_with(node2_id.gossip_public_address));
+        run_chitchat_handshake(&mut node1, &mut node3);
+        assert!(node1.node_state(&node2_id).is_some());
+    }
+
+    #[test]
+    fn test_checkpoint_restore() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        node1.self_node_state().set("key_a", "1");
+        node1.self_node_state().set("key_b", "2");
+        node1
+            .self_node_state()
+            .set_with_ttl("key_ttl", "3", Duration::from_secs(3_600));
+        let node2_id = NodeId::for_test_localhost(10_002);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node2_id.clone(), "key_c", "4", 1, false);
+        advertise_max_versions(&mut node1, &delta);
+        node1.cluster_state.apply_delta(delta);
+        node1.update_heartbeat();
+        node1.update_heartbeat();
+        let checkpoint = node1.checkpoint(true);
+        assert_eq!(checkpoint.heartbeat, 2);
+        let checkpoint_max_version = checkpoint.self_node_state.max_version;
+        assert!(
+            checkpoint
+                .self_node_state
+                .get_versioned("key_ttl")
+                .unwrap()
+                .marked_for_deletion
+        );
+
+        // The node restarts with a new generation.
+        let mut restarted_node1_config = ChitchatConfig::for_test(10_001);
+        restarted_node1_config.node_id = restarted_node1_config.node_id.with_generation(1);
+        let mut restarted_node1 = Chitchat::with_node_id_and_seeds(
+            restarted_node1_config,
+            empty_seeds.clone(),
+            vec![("key_b".to_string(), "2-bis".to_string())],
+        );
+        assert!(restarted_node1.restore_checkpoint(checkpoint.clone()));
+        let self_node_state = restarted_node1
+            .node_state(restarted_node1.self_node_id())
+            .unwrap();
+        assert_eq!(self_node_state.get("key_a"), Some("1"));
+        assert_eq!(self_node_state.get("key_b"), Some("2-bis"));
+        assert!(
+            self_node_state
+                .get_versioned("key_ttl")
+                .unwrap()
+                .marked_for_deletion
+        );
+        assert!(self_node_state.max_version > checkpoint_max_version);
+        assert!(self_node_state.heartbeat() > checkpoint.heartbeat);
+        assert_eq!(
+            restarted_node1.node_state(&node2_id).unwrap().get("key_c"),
+            Some("4")
+        );
+
+        let mut node3 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_003),
+            empty_seeds,
+            Vec::new(),
+        );
+        assert!(!node3.restore_checkpoint(checkpoint));
+        assert!(node3.node_state(&node2_id).is_none());
+    }
+
+    #[test]
+    fn test_run_maintenance() {
+        let mut node_config = ChitchatConfig::for_test(10_001);
+        node_config.tombstone_grace_period = Duration::ZERO;
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node = Chitchat::with_node_id_and_seeds(node_config, empty_seeds, Vec::new());
+        node.self_node_state().set("key_a", "1");
+        node.self_node_state().mark_for_deletion("key_a");
+        node.run_maintenance();
+        let self_node_state = node.node_state(node.self_node_id()).unwrap();
+        assert_eq!(self_node_state.heartbeat(), 1);
+        assert!(self_node_state.get_versioned("key_a").is_none());
+        // The heartbeat key is written again, newer than the garbage collected tombstone.
+        let heartbeat_versioned_value = self_node_state.get_versioned(HEARTBEAT_KEY).unwrap();
+        assert_eq!(heartbeat_versioned_value.value, "1");
+        assert_eq!(heartbeat_versioned_value.version, 4);
+    }
+
+    #[test]
+    fn test_adaptive_gossip_heartbeat_keeps_gossip_interval_pace() {
+        let mut node_config = ChitchatConfig::for_test(10_001);
+        node_config.gossip_interval = Duration::from_secs(1);
+        node_config.adaptive_gossip = Some(AdaptiveGossipConfig::default());
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node = Chitchat::with_node_id_and_seeds(node_config, empty_seeds, Vec::new());
+        let now = Instant::now();
+        node.update_heartbeat_if_due(now);
+        node.update_heartbeat_if_due(now + Duration::from_millis(250));
+        node.update_heartbeat_if_due(now + Duration::from_millis(500));
+        assert_eq!(node.node_state(node.self_node_id()).unwrap().heartbeat(), 1);
+        node.update_heartbeat_if_due(now + Duration::from_secs(1));
+        assert_eq!(node.node_state(node.self_node_id()).unwrap().heartbeat(), 2);
+    }
+
+    #[test]
+    fn test_cluster_state_watch() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            vec![("key_a".to_string(), "1".to_string())],
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        let mut cluster_state_watch = node1.cluster_state_watch();
+        let node1_id = node1.self_node_id().id.clone();
+        let node2_id = node2.self_node_id().id.clone();
+        let snapshot = cluster_state_watch.borrow_and_update().clone();
+        assert_eq!(snapshot.node_states[&node1_id].get("key_a"), Some("1"));
+
+        // Writes on the self node state are published with the next gossip round.
+        node1.self_node_state().set("key_a", "2");
+        assert!(!cluster_state_watch.has_changed().unwrap());
+        node1.run_maintenance();
+        assert!(cluster_state_watch.has_changed().unwrap());
+        let snapshot = cluster_state_watch.borrow_and_update().clone();
+        assert_eq!(snapshot.node_states[&node1_id].get("key_a"), Some("2"));
+
+        // Deltas are published as soon as they are applied.
+        node2.self_node_state().set("key_b", "3");
+        run_chitchat_handshake(&mut node1, &mut node2);
+        let snapshot = cluster_state_watch.borrow_and_update().clone();
+        assert_eq!(snapshot.node_states[&node2_id].get("key_b"), Some("3"));
+
+        // Messages leaving the cluster state unchanged are not published.
+        node1.process_message(
+            node2.self_node_id().gossip_public_address,
+            ChitchatMessage::BadCluster,
+        );
+        assert!(!cluster_state_watch.has_changed().unwrap());
+        // Neither are the heartbeats and the maintenance rounds of an idle cluster.
+        node1.run_maintenance();
+        cluster_state_watch.borrow_and_update();
+        node1.update_heartbeat();
+        node1.run_maintenance();
+        assert!(!cluster_state_watch.has_changed().unwrap());
+
+        // Without receivers, the node states are no longer shared with a snapshot.
+        drop(snapshot);
+        drop(cluster_state_watch);
+        node1.run_maintenance();
+        assert_eq!(
+            Arc::strong_count(
+                node1
+                    .cluster_state
+                    .node_states
+                    .get(&node2.config.node_id)
+                    .unwrap()
+            ),
+            1
+        );
+    }
+
+    #[test]
+    fn test_changes_since() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        node1.self_node_state().set("key_a", "1");
+        node2.self_node_state().set("key_b", "2");
+        let changes = node1.changes_since(0).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].key, "key_a");
+        assert_eq!(changes[0].source, Some(WriteSource::Application));
+
+        // The consumer resumes after the last change it saw.
+        let last_seq = changes[0].seq;
+        run_chitchat_handshake(&mut node1, &mut node2);
+        let changes = node1.changes_since(last_seq).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(&changes[0].node_id, node2.self_node_id());
+        assert_eq!(changes[0].key, "key_b");
+        assert_eq!(changes[0].source, None);
+        assert!(node1.changes_since(changes[0].seq).unwrap().is_empty());
     }
 
-    /// Retrieve a list of seed nodes.
-    pub fn seed_nodes(&self) -> HashSet<SocketAddr> {
-        self.cluster_state.seed_addrs()
-    }
+    #[test]
+    fn test_process_payload() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        node1.self_node_state().set("key_a", "1");
+        node2.self_node_state().set("key_b", "2");
+        let node1_addr = node1.self_node_id().gossip_public_address;
+        let node2_addr = node2.self_node_id().gossip_public_address;
+        let syn_payload = node1.create_syn_payload();
+        let syn_ack_payload = node2.process_payload(node1_addr, &syn_payload).unwrap();
+        let ack_payload = node1.process_payload(node2_addr, &syn_ack_payload).unwrap();
+        assert!(node2.process_payload(node1_addr, &ack_payload).is_none());
+        assert_nodes_sync(&[&node1, &node2]);
 
-    pub fn self_node_id(&self) -> &NodeId {
-        &self.config.node_id
+        assert!(node2.process_payload(node1_addr, b"junk").is_none());
     }
 
-    pub fn cluster_id(&self) -> &str {
-        &self.config.cluster_id
-    }
+    #[test]
+    fn test_observer_delta() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds,
+            Vec::new(),
+        );
+        for i in 0..100 {
+            node.self_node_state()
+                .set(format!("key_{i:03}"), format!("value_{i}"));
+        }
+        let mut observer = ObserverState::default();
+        let mut num_syncs = 0;
+        loop {
+            let delta = node.observer_delta(&observer.digest(), 500);
+            if delta.is_empty() {
+                break;
+            }
+            observer.apply_delta(delta);
+            num_syncs += 1;
+        }
+        assert!(num_syncs > 1);
+        let observed_state = observer
+            .cluster_state()
+            .node_state(node.self_node_id())
+            .unwrap();
+        assert_eq!(observed_state.get("key_000"), Some("value_0"));
+        assert_eq!(observed_state.get("key_099"), Some("value_99"));
 
-    pub fn update_heartbeat(&mut self) {
-        self.heartbeat += 1;
-        let heartbeat = self.heartbeat;
-        self.self_node_state().set(HEARTBEAT_KEY, heartbeat);
+        // Only the changes made since the last sync are transferred.
+        node.self_node_state().set("key_042", "updated");
+        let delta = node.observer_delta(&observer.digest(), 500);
+        assert_eq!(delta.0.num_tuples(), 1);
+        observer.apply_delta(delta);
+        let observed_state = observer
+            .cluster_state()
+            .node_state(node.self_node_id())
+            .unwrap();
+        assert_eq!(observed_state.get("key_042"), Some("updated"));
+        assert!(node.observer_delta(&observer.digest(), 500).is_empty());
     }
 
-    /// Computes digest.
-    ///
-    /// This method also increments the heartbeat, to force the presence
-    /// of at least one update, and have the node liveliness propagated
-    /// through the cluster.
-    fn compute_digest(&self, dead_nodes: &HashSet<&NodeId>) -> Digest {
-        self.cluster_state.compute_digest(dead_nodes)
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_node_metadata_is_gossiped() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        let metadata = NodeMetadata {
+            labels: BTreeMap::from([("team".to_string(), "search".to_string())]),
+            roles: ["indexer".to_string()].into(),
+            build_version: Some("0.5.0".to_string()),
+            ..Default::default()
+        };
+        node1.set_node_metadata(&metadata);
+        run_chitchat_handshake(&mut node1, &mut node2);
+        let node1_metadata = node2
+            .cluster_state()
+            .node_metadata(node1.self_node_id())
+            .unwrap();
+        assert_eq!(
+            node1_metadata,
+            NodeMetadata {
+                version: node1_metadata.version,
+                ..metadata
+            }
+        );
+        // Applications cannot overwrite the metadata through the key-values.
+        node1.self_node_state().set("__chitchat:metadata", "{}");
+        let node1_metadata = node1
+            .cluster_state()
+            .node_metadata(node1.self_node_id())
+            .unwrap();
+        assert_eq!(node1_metadata.build_version.as_deref(), Some("0.5.0"));
     }
 
-    pub(crate) fn cluster_state(&self) -> &ClusterState {
-        &self.cluster_state
+    #[test]
+    fn test_cross_zone_failure_detection_tolerance() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut config1 = ChitchatConfig::for_test(10_001);
+        config1.zone_key = Some("zone".to_string());
+        config1.topology = Some(TopologyConfig::default());
+        let mut nodes: Vec<Chitchat> = [config1]
+            .into_iter()
+            .chain((10_002..10_005).map(ChitchatConfig::for_test))
+            .map(|config| Chitchat::with_node_id_and_seeds(config, empty_seeds.clone(), Vec::new()))
+            .collect();
+        for (node, zone_opt) in nodes.iter_mut().zip([
+            Some("us-east-1a"),
+            Some("us-east-1a"),
+            Some("us-east-1b"),
+            None,
+        ]) {
+            if let Some(zone) = zone_opt {
+                node.self_node_state().set("zone", zone);
+            }
+        }
+        let (node1, other_nodes) = nodes.split_first_mut().unwrap();
+        for node in other_nodes.iter_mut() {
+            run_chitchat_handshake(node1, node);
+        }
+        let node_ids: Vec<NodeId> = other_nodes
+            .iter()
+            .map(|node| node.self_node_id().clone())
+            .collect();
+        assert_eq!(node1.node_zone(&node_ids[1]), Some("us-east-1b"));
+        let node_id_refs: Vec<&NodeId> = node_ids.iter().collect();
+        // Peers whose zone is unknown are not penalized.
+        assert_eq!(
+            node1.failure_detection_tolerances(&node_id_refs),
+            vec![1.0, 2.0, 1.0]
+        );
+        node1.config.topology = None;
+        assert_eq!(
+            node1.failure_detection_tolerances(&node_id_refs),
+            vec![1.0, 1.0, 1.0]
+        );
     }
 
-    /// Returns a serializable snapshot of the ClusterState
-    pub fn state_snapshot(&self) -> ClusterStateSnapshot {
-        ClusterStateSnapshot::from(&self.cluster_state)
+    #[test]
+    fn test_internal_keys_are_gossiped() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2_config = ChitchatConfig::for_test(10_002);
+        node2_config.node_id = node2_config.node_id.with_generation(2);
+        let mut node2 = Chitchat::with_node_id_and_seeds(node2_config, empty_seeds, Vec::new());
+        let advertised_addr: SocketAddr = "127.0.0.1:7280".parse().unwrap();
+        node2.set_advertised_addrs(&[advertised_addr]);
+        node2.set_leave_intent(true);
+        run_chitchat_handshake(&mut node1, &mut node2);
+        let node2_state = node1.node_state(node2.self_node_id()).unwrap();
+        assert_eq!(node2_state.generation(), Some(2));
+        assert_eq!(node2_state.advertised_addrs(), [advertised_addr]);
+        assert!(node2_state.has_leave_intent());
     }
 
-    /// Returns a watch stream for monitoring changes on the cluster's live nodes.
-    pub fn ready_nodes_watcher(&self) -> WatchStream<HashSet<NodeId>> {
-        WatchStream::new(self.ready_nodes_watcher_rx.clone())
-    }
-}
+    #[test]
+    fn test_freeze_applies() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        node2.self_node_state().set("key_a", "1");
+        node1.freeze_applies(Duration::from_secs(3_600));
+        assert!(node1.applies_frozen());
+        run_chitchat_handshake(&mut node1, &mut node2);
+        run_chitchat_handshake(&mut node2, &mut node1);
+        assert!(node1.node_state(node2.self_node_id()).is_none());
+        // Our own writes are not frozen, and still get gossiped.
+        node1.self_node_state().set("key_b", "2");
+        run_chitchat_handshake(&mut node2, &mut node1);
+        let node1_state = node2.node_state(node1.self_node_id()).unwrap();
+        assert_eq!(node1_state.get("key_b"), Some("2"));
 
-#[cfg(test)]
-mod tests {
-    use std::ops::{Add, RangeInclusive};
-    use std::sync::Arc;
-    use std::time::Duration;
+        node1.unfreeze_applies();
+        assert!(!node1.applies_frozen());
+        let node2_state = node1.node_state(node2.self_node_id()).unwrap();
+        assert_eq!(node2_state.get("key_a"), Some("1"));
 
-    use mock_instant::MockClock;
-    use tokio::sync::Mutex;
-    use tokio::time;
-    use tokio_stream::wrappers::IntervalStream;
-    use tokio_stream::StreamExt;
+        // Deltas get applied again once the freeze times out.
+        node1.freeze_applies(Duration::ZERO);
+        node2.self_node_state().set("key_a", "2");
+        run_chitchat_handshake(&mut node1, &mut node2);
+        assert!(!node1.applies_frozen());
+        let node2_state = node1.node_state(node2.self_node_id()).unwrap();
+        assert_eq!(node2_state.get("key_a"), Some("2"));
 
-    use super::*;
-    use crate::server::{spawn_chitchat, ChitchatHandle};
-    use crate::transport::{ChannelTransport, Transport};
+        // Deltas beyond the maximum size of the buffer are dropped, and sent again later.
+        node1.freeze_applies(Duration::from_secs(3_600));
+        node1.frozen_applies.as_mut().unwrap().max_num_bytes = 0;
+        node2.self_node_state().set("key_a", "3");
+        run_chitchat_handshake(&mut node1, &mut node2);
+        assert_eq!(node1.num_dropped_frozen_deltas(), 1);
+        node1.unfreeze_applies();
+        let node2_state = node1.node_state(node2.self_node_id()).unwrap();
+        assert_eq!(node2_state.get("key_a"), Some("2"));
+        run_chitchat_handshake(&mut node1, &mut node2);
+        let node2_state = node1.node_state(node2.self_node_id()).unwrap();
+        assert_eq!(node2_state.get("key_a"), Some("3"));
+    }
 
-    const DEAD_NODE_GRACE_PERIOD: Duration = Duration::from_secs(20);
+    #[test]
+    fn test_pause_gossip() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds,
+            Vec::new(),
+        );
+        run_chitchat_handshake(&mut node1, &mut node2);
+        node1.pause_gossip();
+        assert!(node1.is_gossip_paused());
+        node1.self_node_state().set("key_a", "1");
+        let heartbeat = node1.self_node_state().heartbeat();
+        run_chitchat_handshake(&mut node1, &mut node2);
+        run_chitchat_handshake(&mut node2, &mut node1);
+        let node1_state = node2.node_state(node1.self_node_id()).unwrap();
+        assert!(node1_state.get("key_a").is_none());
+        // Heartbeats keep flowing through digests.
+        assert_eq!(node1_state.heartbeat(), heartbeat);
+        // Deltas from peers are still applied.
+        node2.self_node_state().set("key_b", "2");
+        run_chitchat_handshake(&mut node2, &mut node1);
+        let node2_state = node1.node_state(node2.self_node_id()).unwrap();
+        assert_eq!(node2_state.get("key_b"), Some("2"));
 
-    fn run_chitchat_handshake(initiating_node: &mut Chitchat, peer_node: &mut Chitchat) {
-        let syn_message = initiating_node.create_syn_message();
-        let syn_ack_message = peer_node.process_message(syn_message).unwrap();
-        let ack_message = initiating_node.process_message(syn_ack_message).unwrap();
-        assert!(peer_node.process_message(ack_message).is_none());
+        node1.resume_gossip();
+        assert!(!node1.is_gossip_paused());
+        run_chitchat_handshake(&mut node1, &mut node2);
+        let node1_state = node2.node_state(node1.self_node_id()).unwrap();
+        assert_eq!(node1_state.get("key_a"), Some("1"));
     }
 
-    fn assert_cluster_state_eq(lhs: &NodeState, rhs: &NodeState) {
-        assert_eq!(lhs.key_values.len(), rhs.key_values.len());
-        for (key, value) in &lhs.key_values {
-            if key == HEARTBEAT_KEY {
-                // we ignore the heartbeat key
-                continue;
-            }
-            assert_eq!(rhs.key_values.get(key), Some(value));
-        }
-    }
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_merge_snapshot() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node1 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        let mut node2 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_002),
+            empty_seeds.clone(),
+            Vec::new(),
+        );
+        node1.self_node_state().set("key_a", "1");
+        node2.self_node_state().set("key_b", "2");
+        run_chitchat_handshake(&mut node1, &mut node2);
+        let snapshot_json = serde_json::to_string(&node2.state_snapshot()).unwrap();
 
-    fn assert_nodes_sync(nodes: &[&Chitchat]) {
-        let first_node_states = &nodes[0].cluster_state.node_states;
-        for other_node in nodes.iter().skip(1) {
-            let node_states = &other_node.cluster_state.node_states;
-            assert_eq!(first_node_states.len(), node_states.len());
-            for (key, value) in first_node_states {
-                assert_cluster_state_eq(value, node_states.get(key).unwrap());
-            }
-        }
-    }
+        node2.self_node_state().set("key_b", "2-bis");
+        let mut node3 = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_003),
+            empty_seeds,
+            Vec::new(),
+        );
+        run_chitchat_handshake(&mut node3, &mut node2);
+        let snapshot: ClusterStateSnapshot = serde_json::from_str(&snapshot_json).unwrap();
+        assert_eq!(node3.merge_snapshot(snapshot), 2);
 
-    async fn start_node(
-        node_id: NodeId,
-        seeds: &[String],
-        transport: &dyn Transport,
-    ) -> ChitchatHandle {
-        let config = ChitchatConfig {
-            node_id: node_id.clone(),
-            cluster_id: "default-cluster".to_string(),
-            gossip_interval: Duration::from_millis(100),
-            listen_addr: node_id.gossip_public_address,
-            seed_nodes: seeds.to_vec(),
-            failure_detector_config: FailureDetectorConfig {
-                dead_node_grace_period: DEAD_NODE_GRACE_PERIOD,
-                phi_threshold: 5.0,
-                initial_interval: Duration::from_millis(100),
-                ..Default::default()
-            },
-            is_ready_predicate: None,
-            marked_for_deletion_grace_period: 10_000,
-        };
-        let initial_kvs: Vec<(String, String)> = Vec::new();
-        spawn_chitchat(config, initial_kvs, transport)
-            .await
-            .unwrap()
+        let node1_state = node3.node_state(node1.self_node_id()).unwrap();
+        assert_eq!(node1_state.get("key_a"), Some("1"));
+        // The snapshot is older than the state received through gossip.
+        let node2_state = node3.node_state(node2.self_node_id()).unwrap();
+        assert_eq!(node2_state.get("key_b"), Some("2-bis"));
+        // Merged nodes are not live until their heartbeats are received.
+        assert!(!node3
+            .live_nodes()
+            .any(|node_id| node_id == node1.self_node_id()));
     }
 
-    async fn setup_nodes(
-        port_range: RangeInclusive<u16>,
-        transport: &dyn Transport,
-    ) -> Vec<ChitchatHandle> {
-        let node_ids: Vec<NodeId> = port_range.map(NodeId::for_test_localhost).collect();
-        let node_without_seed = start_node(node_ids[0].clone(), &[], transport).await;
-        let mut chitchat_handlers: Vec<ChitchatHandle> = vec![node_without_seed];
-        for node_id in &node_ids[1..] {
-            let seeds = node_ids
-                .iter()
-                .filter(|&peer_id| peer_id != node_id)
-                .map(|peer_id| peer_id.gossip_public_address.to_string())
-                .collect::<Vec<_>>();
-            chitchat_handlers.push(start_node(node_id.clone(), &seeds, transport).await);
-        }
-        // Make sure the failure detector's fake clock moves forward.
-        tokio::spawn(async {
-            let mut ticker = IntervalStream::new(time::interval(Duration::from_millis(50)));
-            while ticker.next().await.is_some() {
-                MockClock::advance(Duration::from_millis(50));
-            }
-        });
-        chitchat_handlers
+    #[test]
+    fn test_top_talkers() {
+        let empty_seeds = watch::channel(Default::default()).1;
+        let mut node = Chitchat::with_node_id_and_seeds(
+            ChitchatConfig::for_test(10_001),
+            empty_seeds,
+            Vec::new(),
+        );
+        node.self_node_state().set("key_a", "1");
+        node.self_node_state().set("key_b", "x".repeat(100));
+        node.run_maintenance();
+        node.self_node_state().set("key_a", "2");
+        let top_talkers = node.top_talkers(1);
+        assert_eq!(top_talkers.len(), 1);
+        assert_eq!(top_talkers[0].key, "key_b");
+        let top_talkers = node.top_talkers(10);
+        assert_eq!(top_talkers.len(), 2);
+        assert_eq!(top_talkers[1].key, "key_a");
+        assert!(top_talkers[1].updates_per_sec > 0.0);
     }
 
-    async fn shutdown_nodes(nodes: Vec<ChitchatHandle>) -> anyhow::Result<()> {
-        for node in nodes {
-            node.shutdown().await?;
-        }
-        Ok(())
-    }
+    #[test]
+    fn test_write_admission() {
+        for (policy, admission_while_shutting_down) in [
+            (WriteAfterShutdownPolicy::Error, WriteAdmission::Reject),
+            (
+                WriteAfterShutdownPolicy::Block,
+                WriteAdmission::WaitForShutdown,
+            ),
+            (
+                WriteAfterShutdownPolicy::QueueAndFlush,
+                WriteAdmission::Accept,
+            ),
+        ] {
+            let mut node_config = ChitchatConfig::for_test(10_001);
+            node_config.write_after_shutdown_policy = policy;
+            let empty_seeds = watch::channel(Default::default()).1;
+            let mut node = Chitchat::with_node_id_and_seeds(node_config, empty_seeds, Vec::new());
+            assert_eq!(node.write_admission(), WriteAdmission::Accept);
 
-    async fn wait_for_chitchat_state(
-        chitchat: Arc<Mutex<Chitchat>>,
-        expected_node_count: usize,
-        expected_nodes: &[NodeId],
-    ) {
-        let mut ready_nodes_watcher = chitchat
-            .lock()
-            .await
-            .ready_nodes_watcher()
-            .skip_while(|live_nodes| live_nodes.len() != expected_node_count);
-        tokio::time::timeout(Duration::from_secs(50), async move {
-            let live_nodes = ready_nodes_watcher.next().await.unwrap();
+            let mut shutdown_phase_rx = node.shutdown_phase_watcher();
+            node.set_shutdown_phase(ShutdownPhase::ShuttingDown);
+            assert_eq!(node.write_admission(), admission_while_shutting_down);
             assert_eq!(
-                live_nodes,
-                expected_nodes.iter().cloned().collect::<HashSet<_>>()
+                *shutdown_phase_rx.borrow_and_update(),
+                ShutdownPhase::ShuttingDown
             );
-        })
-        .await
-        .unwrap();
+
+            node.set_shutdown_phase(ShutdownPhase::ShutDown);
+            assert_eq!(node.write_admission(), WriteAdmission::Reject);
+        }
     }
 
     #[test]
-    fn test_chitchat_handshake() {
+    fn test_owned_node_state() {
         let node_config1 = ChitchatConfig::for_test(10_001);
+        let node_id1 = node_config1.node_id.clone();
         let empty_seeds = watch::channel(Default::default()).1;
         let mut node1 = Chitchat::with_node_id_and_seeds(
             node_config1,
-            empty_seeds.clone(),
-            vec![
-                ("key1a".to_string(), "1".to_string()),
-                ("key2a".to_string(), "2".to_string()),
-            ],
-        );
-        let node_config2 = ChitchatConfig::for_test(10_002);
-        let mut node2 = Chitchat::with_node_id_and_seeds(
-            node_config2,
             empty_seeds,
-            vec![
-                ("key1b".to_string(), "1".to_string()),
-                ("key2b".to_string(), "2".to_string()),
-            ],
+            vec![("key1a".to_string(), "1".to_string())],
         );
-        run_chitchat_handshake(&mut node1, &mut node2);
-        assert_nodes_sync(&[&node1, &node2]);
-        // useless handshake
-        run_chitchat_handshake(&mut node1, &mut node2);
-        assert_nodes_sync(&[&node1, &node2]);
-        {
-            let state1 = node1.self_node_state();
-            state1.set("key1a", "3");
-            state1.set("key1c", "4");
-        }
-        run_chitchat_handshake(&mut node1, &mut node2);
-        assert_nodes_sync(&[&node1, &node2]);
+        assert!(node1
+            .owned_node_state(&NodeId::for_test_localhost(10_002))
+            .is_none());
+        let node_state_view = node1.owned_node_state(&node_id1).unwrap();
+        node1.self_node_state().set("key1a", "2");
+        let value = std::thread::spawn(move || node_state_view.get("key1a").map(str::to_string))
+            .join()
+            .unwrap();
+        assert_eq!(value.as_deref(), Some("1"));
     }
 
     #[tokio::test]
@@ -740,12 +5180,13 @@ mod tests {
 
         // Dead node should no longer be known to the cluster.
         for node in &nodes {
-            assert!(node
-                .chitchat()
-                .lock()
-                .await
-                .node_state(&dead_node_id)
-                .is_none());
+            let chitchat_guard = node.chitchat();
+            let chitchat = chitchat_guard.lock().await;
+            assert!(chitchat.node_state(&dead_node_id).is_none());
+            assert_eq!(
+                *chitchat.evicted_nodes_rx.borrow(),
+                vec![dead_node_id.clone()]
+            );
         }
 
         shutdown_nodes(nodes).await?;
@@ -0,0 +1,369 @@
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+use tracing::{debug, warn};
+
+use crate::SeedProvider;
+
+/// Name of the DNS-SD service under which the nodes advertise their gossip address.
+const SERVICE_NAME: &str = "_chitchat._udp.local";
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+
+const MDNS_PORT: u16 = 5353;
+
+/// Time spent collecting the answers to a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// TTL of the records we answer with, in seconds.
+const RECORD_TTL_SECS: u32 = 120;
+
+const TYPE_TXT: u16 = 16;
+
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+
+/// Discovers the nodes of the cluster on the local network with mDNS, without any
+/// configuration.
+///
+/// Every node answers the mDNS queries for the `_chitchat._udp.local` service with its cluster
+/// ID and gossip address, and queries the service at every refresh: the seeds are the nodes of
+/// the same cluster that answered within a second. Multicast rarely crosses subnets, so this is
+/// meant for development and edge deployments.
+pub struct MdnsSeeds {
+    cluster_id: String,
+    gossip_addr: SocketAddr,
+    responder_opt: Mutex<Option<MdnsResponder>>,
+}
+
+struct MdnsResponder {
+    socket: Arc<UdpSocket>,
+    /// Gossip addresses from the answers, along with the time they were received.
+    discovered: Arc<Mutex<Vec<(SocketAddr, Instant)>>>,
+    join_handle: JoinHandle<()>,
+}
+
+impl Drop for MdnsResponder {
+    fn drop(&mut self) {
+        self.join_handle.abort();
+    }
+}
+
+impl MdnsSeeds {
+    /// Creates the seeds of the node with the given cluster ID, advertising its public gossip
+    /// address.
+    pub fn new(cluster_id: impl Into<String>, gossip_addr: SocketAddr) -> anyhow::Result<Self> {
+        let cluster_id = cluster_id.into();
+        // TXT strings are prefixed by their length, on one byte.
+        if cluster_id.len() + "cluster=".len() > u8::MAX as usize {
+            anyhow::bail!("Cluster ID `{cluster_id}` is too long to be advertised over mDNS.");
+        }
+        Ok(MdnsSeeds {
+            cluster_id,
+            gossip_addr,
+            responder_opt: Mutex::new(None),
+        })
+    }
+
+    /// Starts answering the queries of the other nodes, upon the first refresh.
+    fn responder(
+        &self,
+    ) -> anyhow::Result<(Arc<UdpSocket>, Arc<Mutex<Vec<(SocketAddr, Instant)>>>)> {
+        let mut responder_guard = self.responder_opt.lock().unwrap();
+        if let Some(responder) = responder_guard.as_ref() {
+            return Ok((responder.socket.clone(), responder.discovered.clone()));
+        }
+        let socket = Arc::new(bind_mdns_socket()?);
+        let discovered = Arc::new(Mutex::new(Vec::new()));
+        let answer = encode_answer(&self.cluster_id, self.gossip_addr);
+        let join_handle = tokio::spawn(respond(
+            socket.clone(),
+            self.cluster_id.clone(),
+            answer,
+            discovered.clone(),
+        ));
+        *responder_guard = Some(MdnsResponder {
+            socket: socket.clone(),
+            discovered: discovered.clone(),
+            join_handle,
+        });
+        Ok((socket, discovered))
+    }
+}
+
+#[async_trait]
+impl SeedProvider for MdnsSeeds {
+    async fn seeds(&self) -> HashSet<SocketAddr> {
+        let (socket, discovered) = match self.responder() {
+            Ok(responder) => responder,
+            Err(error) => {
+                warn!(error = %error, "failed-to-start-mdns-discovery");
+                return HashSet::new();
+            }
+        };
+        let queried_at = Instant::now();
+        if let Err(error) = socket.send_to(&encode_query(), mdns_group()).await {
+            warn!(error = %error, "failed-to-send-mdns-query");
+            return HashSet::new();
+        }
+        time::sleep(QUERY_TIMEOUT).await;
+        let mut discovered_guard = discovered.lock().unwrap();
+        discovered_guard.retain(|(_, received_at)| *received_at >= queried_at);
+        discovered_guard
+            .iter()
+            .map(|(gossip_addr, _)| *gossip_addr)
+            .filter(|gossip_addr| *gossip_addr != self.gossip_addr)
+            .collect()
+    }
+}
+
+fn mdns_group() -> SocketAddr {
+    SocketAddr::V4(SocketAddrV4::new(MDNS_ADDR, MDNS_PORT))
+}
+
+fn bind_mdns_socket() -> anyhow::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Other nodes, or the mDNS daemon of the host, may be listening on the same port.
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT);
+    socket
+        .bind(&bind_addr.into())
+        .with_context(|| format!("Failed to bind to {bind_addr}/UDP for mDNS."))?;
+    socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    // Lets the nodes running on the same host find each other.
+    socket.set_multicast_loop_v4(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+async fn respond(
+    socket: Arc<UdpSocket>,
+    cluster_id: String,
+    answer: Vec<u8>,
+    discovered: Arc<Mutex<Vec<(SocketAddr, Instant)>>>,
+) {
+    let mut buf = [0u8; 9_000];
+    loop {
+        let packet = match socket.recv_from(&mut buf).await {
+            Ok((len, _)) => &buf[..len],
+            Err(error) => {
+                warn!(error = %error, "failed-to-receive-mdns-packet");
+                continue;
+            }
+        };
+        match decode_packet(packet) {
+            Some(MdnsPacket::Query) => {
+                if let Err(error) = socket.send_to(&answer, mdns_group()).await {
+                    warn!(error = %error, "failed-to-send-mdns-answer");
+                }
+            }
+            Some(MdnsPacket::Answer(answers)) => {
+                let now = Instant::now();
+                let mut discovered_guard = discovered.lock().unwrap();
+                for (answer_cluster_id, gossip_addr) in answers {
+                    if answer_cluster_id == cluster_id {
+                        debug!(gossip_addr = %gossip_addr, "discovered-node-over-mdns");
+                        discovered_guard.push((gossip_addr, now));
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+/// The mDNS packets about the chitchat service.
+#[derive(Debug, Eq, PartialEq)]
+enum MdnsPacket {
+    Query,
+    /// Cluster IDs and gossip addresses of the answering nodes.
+    Answer(Vec<(String, SocketAddr)>),
+}
+
+fn encode_header(buf: &mut Vec<u8>, flags: u16, num_questions: u16, num_answers: u16) {
+    // Queries and answers are multicast: their ID is zero.
+    buf.extend_from_slice(&0u16.to_be_bytes());
+    buf.extend_from_slice(&flags.to_be_bytes());
+    buf.extend_from_slice(&num_questions.to_be_bytes());
+    buf.extend_from_slice(&num_answers.to_be_bytes());
+    // No authority nor additional records.
+    buf.extend_from_slice(&[0u8; 4]);
+}
+
+fn encode_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+fn encode_query() -> Vec<u8> {
+    let mut buf = Vec::new();
+    encode_header(&mut buf, 0, 1, 0);
+    encode_name(&mut buf, SERVICE_NAME);
+    buf.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    buf
+}
+
+fn encode_answer(cluster_id: &str, gossip_addr: SocketAddr) -> Vec<u8> {
+    let mut rdata = Vec::new();
+    for txt in [
+        format!("cluster={cluster_id}"),
+        format!("addr={gossip_addr}"),
+    ] {
+        rdata.push(txt.len() as u8);
+        rdata.extend_from_slice(txt.as_bytes());
+    }
+    let mut buf = Vec::new();
+    // Authoritative response.
+    encode_header(&mut buf, 0x8400, 0, 1);
+    encode_name(&mut buf, SERVICE_NAME);
+    buf.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    buf.extend_from_slice(&RECORD_TTL_SECS.to_be_bytes());
+    buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&rdata);
+    buf
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    let bytes = packet.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads the name at `offset`, following compression pointers, and returns it along with the
+/// offset following it.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut next_offset_opt = None;
+    // Bounds the number of pointers, which could otherwise loop.
+    for _ in 0..128 {
+        let len = *packet.get(offset)? as usize;
+        if len == 0 {
+            let next_offset = next_offset_opt.unwrap_or(offset + 1);
+            return Some((labels.join("."), next_offset));
+        }
+        if len & 0xC0 == 0xC0 {
+            let pointer = read_u16(packet, offset)? as usize & 0x3FFF;
+            next_offset_opt.get_or_insert(offset + 2);
+            offset = pointer;
+            continue;
+        }
+        let label = packet.get(offset + 1..offset + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        offset += 1 + len;
+    }
+    None
+}
+
+fn decode_txt(rdata: &[u8]) -> Option<(String, SocketAddr)> {
+    let mut cluster_id_opt = None;
+    let mut gossip_addr_opt = None;
+    let mut offset = 0;
+    while offset < rdata.len() {
+        let len = rdata[offset] as usize;
+        let txt = std::str::from_utf8(rdata.get(offset + 1..offset + 1 + len)?).ok()?;
+        if let Some(cluster_id) = txt.strip_prefix("cluster=") {
+            cluster_id_opt = Some(cluster_id.to_string());
+        } else if let Some(gossip_addr) = txt.strip_prefix("addr=") {
+            gossip_addr_opt = gossip_addr.parse().ok();
+        }
+        offset += 1 + len;
+    }
+    Some((cluster_id_opt?, gossip_addr_opt?))
+}
+
+/// Decodes a packet, returning `None` if it is malformed or unrelated to chitchat.
+fn decode_packet(packet: &[u8]) -> Option<MdnsPacket> {
+    let flags = read_u16(packet, 2)?;
+    let num_questions = read_u16(packet, 4)?;
+    let num_records = read_u16(packet, 6)? as usize
+        + read_u16(packet, 8)? as usize
+        + read_u16(packet, 10)? as usize;
+    let mut offset = 12;
+    let mut is_query_for_service = false;
+    for _ in 0..num_questions {
+        let (name, next_offset) = read_name(packet, offset)?;
+        let question_type = read_u16(packet, next_offset)?;
+        is_query_for_service |= name.eq_ignore_ascii_case(SERVICE_NAME)
+            && (question_type == TYPE_TXT || question_type == TYPE_ANY);
+        offset = next_offset + 4;
+    }
+    let is_response = flags & 0x8000 != 0;
+    if !is_response {
+        return is_query_for_service.then_some(MdnsPacket::Query);
+    }
+    let mut answers = Vec::new();
+    for _ in 0..num_records {
+        let (name, next_offset) = read_name(packet, offset)?;
+        let record_type = read_u16(packet, next_offset)?;
+        let rdata_len = read_u16(packet, next_offset + 8)? as usize;
+        let rdata_offset = next_offset + 10;
+        let rdata = packet.get(rdata_offset..rdata_offset + rdata_len)?;
+        if record_type == TYPE_TXT && name.eq_ignore_ascii_case(SERVICE_NAME) {
+            answers.extend(decode_txt(rdata));
+        }
+        offset = rdata_offset + rdata_len;
+    }
+    if answers.is_empty() {
+        return None;
+    }
+    Some(MdnsPacket::Answer(answers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mdns_packets() {
+        assert_eq!(decode_packet(&encode_query()), Some(MdnsPacket::Query));
+
+        let gossip_addr: SocketAddr = "192.168.1.10:7280".parse().unwrap();
+        assert_eq!(
+            decode_packet(&encode_answer("my-cluster", gossip_addr)),
+            Some(MdnsPacket::Answer(vec![(
+                "my-cluster".to_string(),
+                gossip_addr
+            )]))
+        );
+
+        // A query for another service, e.g. from the mDNS daemon of the host.
+        let mut query = Vec::new();
+        encode_header(&mut query, 0, 1, 0);
+        encode_name(&mut query, "_http._tcp.local");
+        query.extend_from_slice(&TYPE_TXT.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        assert_eq!(decode_packet(&query), None);
+
+        // Compressed names point back to a previous occurrence.
+        let name_len = SERVICE_NAME.len() + 2;
+        let mut compressed = encode_answer("my-cluster", gossip_addr);
+        compressed[7] = 2;
+        compressed.extend_from_slice(&[0xC0, 12]);
+        compressed.extend_from_slice(&encode_answer("other-cluster", gossip_addr)[12 + name_len..]);
+        assert_eq!(
+            decode_packet(&compressed),
+            Some(MdnsPacket::Answer(vec![
+                ("my-cluster".to_string(), gossip_addr),
+                ("other-cluster".to_string(), gossip_addr),
+            ]))
+        );
+
+        assert_eq!(decode_packet(&[0u8; 4]), None);
+        assert!(MdnsSeeds::new("a".repeat(256), gossip_addr).is_err());
+    }
+}
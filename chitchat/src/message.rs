@@ -4,7 +4,8 @@ use anyhow::Context;
 
 use crate::delta::Delta;
 use crate::digest::Digest;
-use crate::serialize::Serializable;
+use crate::serialize::{ParseLimits, Serializable};
+use crate::NodeId;
 
 /// Chitchat message.
 ///
@@ -12,6 +13,11 @@ use crate::serialize::Serializable;
 /// between node A and node B.
 /// The names {Syn, SynAck, Ack} of the different steps are borrowed from
 /// TCP Handshake.
+///
+/// All messages but `BadCluster` carry the cluster ID of their sender, so that nodes of
+/// different clusters never merge their states. Since protocol version 1, they are
+/// decoded only from versioned messages, so that an unversioned `SynAck` or `Ack`, which carried
+/// no cluster ID, is rejected rather than misread.
 #[derive(Debug, PartialEq, Eq)]
 pub enum ChitchatMessage {
     /// Node A initiates handshakes.
@@ -19,14 +25,152 @@ pub enum ChitchatMessage {
     /// Node B returns a partial update as described
     /// in the scuttlebutt reconcialiation algorithm,
     /// and returns its own checksum.
-    SynAck { digest: Digest, delta: Delta },
+    SynAck {
+        cluster_id: String,
+        digest: Digest,
+        delta: Delta,
+    },
     /// Node A returns a partial update for B.
-    Ack { delta: Delta },
+    Ack { cluster_id: String, delta: Delta },
     /// Node B rejects the Syn message because of a
     /// cluster name mismatch between the peers.
     BadCluster,
+    /// Node A suspects `target` to be dead, and asks node B to probe it on its behalf.
+    ProbeRequest { cluster_id: String, target: NodeId },
+    /// Node B returns the last heartbeat of `target` it knows of, if any.
+    ProbeResponse {
+        cluster_id: String,
+        target: NodeId,
+        heartbeat: u64,
+    },
+    /// Wraps the serialized `payload` message, authenticated with the cluster key. See
+    /// [`crate::ChitchatConfig::cluster_key`].
+    Authenticated { payload: Vec<u8>, tag: [u8; 32] },
+    /// Wraps a serialized message, encrypted with a key derived from the cluster key. See
+    /// [`crate::ClusterKey`].
+    Encrypted {
+        nonce: [u8; 12],
+        ciphertext: Vec<u8>,
+    },
 }
 
+impl ChitchatMessage {
+    /// Returns the type of the message, as reported by [`crate::GossipStats`].
+    #[cfg(feature = "server")]
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            ChitchatMessage::Syn { .. } => "syn",
+            ChitchatMessage::SynAck { .. } => "syn_ack",
+            ChitchatMessage::Ack { .. } => "ack",
+            ChitchatMessage::BadCluster => "bad_cluster",
+            ChitchatMessage::ProbeRequest { .. } => "probe_request",
+            ChitchatMessage::ProbeResponse { .. } => "probe_response",
+            ChitchatMessage::Authenticated { .. } => "authenticated",
+            ChitchatMessage::Encrypted { .. } => "encrypted",
+        }
+    }
+
+    /// Deserializes a message, failing if its digest or delta exceeds `limits`.
+    pub(crate) fn deserialize_with_limits(
+        buf: &mut &[u8],
+        limits: &ParseLimits,
+    ) -> anyhow::Result<Self> {
+        let [tag, protocol_version] = <[u8; PROTOCOL_HEADER_LEN]>::deserialize(buf)?;
+        if tag != VERSIONED_MESSAGE_TAG {
+            anyhow::bail!("Unversioned message, sent by a release preceding protocol version 1.");
+        }
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+            anyhow::bail!("Unsupported protocol version {protocol_version}.");
+        }
+        let code = buf
+            .first()
+            .cloned()
+            .and_then(MessageType::from_code)
+            .context("Invalid message type")?;
+        buf.consume(1);
+        match code {
+            MessageType::Syn => {
+                let digest = Digest::deserialize_with_limits(buf, limits)?;
+                let cluster_id = String::deserialize(buf)?;
+                Ok(Self::Syn { cluster_id, digest })
+            }
+            MessageType::SynAck => {
+                let digest = Digest::deserialize_with_limits(buf, limits)?;
+                let delta = Delta::deserialize_with_limits(buf, limits)?;
+                let cluster_id = String::deserialize(buf)?;
+                Ok(Self::SynAck {
+                    cluster_id,
+                    digest,
+                    delta,
+                })
+            }
+            MessageType::Ack => {
+                let delta = Delta::deserialize_with_limits(buf, limits)?;
+                let cluster_id = String::deserialize(buf)?;
+                Ok(Self::Ack { cluster_id, delta })
+            }
+            MessageType::BadCluster => Ok(Self::BadCluster),
+            MessageType::ProbeRequest => {
+                let target = NodeId::deserialize(buf)?;
+                let cluster_id = String::deserialize(buf)?;
+                Ok(Self::ProbeRequest { cluster_id, target })
+            }
+            MessageType::ProbeResponse => {
+                let target = NodeId::deserialize(buf)?;
+                let heartbeat = u64::deserialize(buf)?;
+                let cluster_id = String::deserialize(buf)?;
+                Ok(Self::ProbeResponse {
+                    cluster_id,
+                    target,
+                    heartbeat,
+                })
+            }
+            MessageType::Authenticated => {
+                let tag = <[u8; 32]>::deserialize(buf)?;
+                let payload = deserialize_bytes(buf)?;
+                Ok(Self::Authenticated { payload, tag })
+            }
+            MessageType::Encrypted => {
+                let nonce = <[u8; 12]>::deserialize(buf)?;
+                let ciphertext = deserialize_bytes(buf)?;
+                Ok(Self::Encrypted { nonce, ciphertext })
+            }
+        }
+    }
+
+    /// Returns the cluster ID of the sender, if the message carries one.
+    pub fn cluster_id(&self) -> Option<&str> {
+        match self {
+            ChitchatMessage::Syn { cluster_id, .. }
+            | ChitchatMessage::SynAck { cluster_id, .. }
+            | ChitchatMessage::Ack { cluster_id, .. }
+            | ChitchatMessage::ProbeRequest { cluster_id, .. }
+            | ChitchatMessage::ProbeResponse { cluster_id, .. } => Some(cluster_id),
+            ChitchatMessage::BadCluster
+            | ChitchatMessage::Authenticated { .. }
+            | ChitchatMessage::Encrypted { .. } => None,
+        }
+    }
+}
+
+/// Version of the encoding of the messages, carried in front of every message, so that nodes
+/// can tell the messages of other releases apart instead of misreading them.
+///
+/// Version 1 introduced the generation of the node IDs and the cluster ID of every message.
+/// The releases preceding it send unversioned messages, which are rejected.
+pub(crate) const PROTOCOL_VERSION: u8 = 1;
+
+/// Oldest version of the encoding still decoded. Raising [`PROTOCOL_VERSION`] without raising
+/// this one keeps the messages of the previous release readable during rolling upgrades.
+const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// Leading byte of the versioned messages, distinct from the message types the unversioned
+/// messages start with.
+const VERSIONED_MESSAGE_TAG: u8 = 0xcc;
+
+/// Length of the tag and the protocol version leading every message.
+pub(crate) const PROTOCOL_HEADER_LEN: usize = 2;
+
 #[derive(Copy, Clone)]
 #[repr(u8)]
 enum MessageType {
@@ -34,6 +178,10 @@ enum MessageType {
     SynAck = 1u8,
     Ack = 2u8,
     BadCluster = 3u8,
+    ProbeRequest = 4u8,
+    ProbeResponse = 5u8,
+    Authenticated = 6u8,
+    Encrypted = 7u8,
 }
 
 impl MessageType {
@@ -43,6 +191,10 @@ impl MessageType {
             1 => Some(Self::SynAck),
             2 => Some(Self::Ack),
             3 => Some(Self::BadCluster),
+            4 => Some(Self::ProbeRequest),
+            5 => Some(Self::ProbeResponse),
+            6 => Some(Self::Authenticated),
+            7 => Some(Self::Encrypted),
             _ => None,
         }
     }
@@ -53,73 +205,141 @@ impl MessageType {
 
 impl Serializable for ChitchatMessage {
     fn serialize(&self, buf: &mut Vec<u8>) {
+        buf.push(VERSIONED_MESSAGE_TAG);
+        buf.push(PROTOCOL_VERSION);
         match self {
             ChitchatMessage::Syn { cluster_id, digest } => {
                 buf.push(MessageType::Syn.to_code());
                 digest.serialize(buf);
                 cluster_id.serialize(buf);
             }
-            ChitchatMessage::SynAck { digest, delta } => {
+            ChitchatMessage::SynAck {
+                cluster_id,
+                digest,
+                delta,
+            } => {
                 buf.push(MessageType::SynAck.to_code());
                 digest.serialize(buf);
                 delta.serialize(buf);
+                cluster_id.serialize(buf);
             }
-            ChitchatMessage::Ack { delta } => {
+            ChitchatMessage::Ack { cluster_id, delta } => {
                 buf.push(MessageType::Ack.to_code());
                 delta.serialize(buf);
+                cluster_id.serialize(buf);
             }
             ChitchatMessage::BadCluster => {
                 buf.push(MessageType::BadCluster.to_code());
             }
-        }
-    }
-
-    fn deserialize(buf: &mut &[u8]) -> anyhow::Result<Self> {
-        let code = buf
-            .first()
-            .cloned()
-            .and_then(MessageType::from_code)
-            .context("Invalid message type")?;
-        buf.consume(1);
-        match code {
-            MessageType::Syn => {
-                let digest = Digest::deserialize(buf)?;
-                let cluster_id = String::deserialize(buf)?;
-                Ok(Self::Syn { cluster_id, digest })
+            ChitchatMessage::ProbeRequest { cluster_id, target } => {
+                buf.push(MessageType::ProbeRequest.to_code());
+                target.serialize(buf);
+                cluster_id.serialize(buf);
             }
-            MessageType::SynAck => {
-                let digest = Digest::deserialize(buf)?;
-                let delta = Delta::deserialize(buf)?;
-                Ok(Self::SynAck { digest, delta })
+            ChitchatMessage::ProbeResponse {
+                cluster_id,
+                target,
+                heartbeat,
+            } => {
+                buf.push(MessageType::ProbeResponse.to_code());
+                target.serialize(buf);
+                heartbeat.serialize(buf);
+                cluster_id.serialize(buf);
             }
-            MessageType::Ack => {
-                let delta = Delta::deserialize(buf)?;
-                Ok(Self::Ack { delta })
+            ChitchatMessage::Authenticated { payload, tag } => {
+                buf.push(MessageType::Authenticated.to_code());
+                tag.serialize(buf);
+                serialize_bytes(payload, buf);
+            }
+            ChitchatMessage::Encrypted { nonce, ciphertext } => {
+                buf.push(MessageType::Encrypted.to_code());
+                nonce.serialize(buf);
+                serialize_bytes(ciphertext, buf);
             }
-            MessageType::BadCluster => Ok(Self::BadCluster),
         }
     }
 
+    fn deserialize(buf: &mut &[u8]) -> anyhow::Result<Self> {
+        Self::deserialize_with_limits(buf, &ParseLimits::default())
+    }
+
     fn serialized_len(&self) -> usize {
-        match self {
+        let body_len = match self {
             ChitchatMessage::Syn { cluster_id, digest } => {
                 1 + cluster_id.serialized_len() + digest.serialized_len()
             }
-            ChitchatMessage::SynAck { digest, delta } => syn_ack_serialized_len(digest, delta),
-            ChitchatMessage::Ack { delta } => 1 + delta.serialized_len(),
+            ChitchatMessage::SynAck {
+                cluster_id,
+                digest,
+                delta,
+            } => syn_ack_body_len(cluster_id, digest, delta),
+            ChitchatMessage::Ack { cluster_id, delta } => ack_body_len(cluster_id, delta),
             ChitchatMessage::BadCluster => 1,
-        }
+            ChitchatMessage::ProbeRequest { cluster_id, target } => {
+                1 + target.serialized_len() + cluster_id.serialized_len()
+            }
+            ChitchatMessage::ProbeResponse {
+                cluster_id,
+                target,
+                heartbeat,
+            } => {
+                1 + target.serialized_len()
+                    + heartbeat.serialized_len()
+                    + cluster_id.serialized_len()
+            }
+            ChitchatMessage::Authenticated { payload, tag } => 1 + tag.len() + 4 + payload.len(),
+            ChitchatMessage::Encrypted { nonce, ciphertext } => {
+                1 + nonce.len() + 4 + ciphertext.len()
+            }
+        };
+        PROTOCOL_HEADER_LEN + body_len
+    }
+}
+
+pub(crate) fn syn_ack_serialized_len(cluster_id: &str, digest: &Digest, delta: &Delta) -> usize {
+    PROTOCOL_HEADER_LEN + syn_ack_body_len(cluster_id, digest, delta)
+}
+
+pub(crate) fn ack_serialized_len(cluster_id: &str, delta: &Delta) -> usize {
+    PROTOCOL_HEADER_LEN + ack_body_len(cluster_id, delta)
+}
+
+fn syn_ack_body_len(cluster_id: &str, digest: &Digest, delta: &Delta) -> usize {
+    1 + digest.serialized_len() + delta.serialized_len() + str_serialized_len(cluster_id)
+}
+
+fn ack_body_len(cluster_id: &str, delta: &Delta) -> usize {
+    1 + delta.serialized_len() + str_serialized_len(cluster_id)
+}
+
+/// Sealed payloads are serialized as their length, on four bytes, followed by their bytes. Unlike
+/// datagrams, full sync frames may exceed 64KB.
+fn serialize_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn deserialize_bytes(buf: &mut &[u8]) -> anyhow::Result<Vec<u8>> {
+    let len_bytes = <[u8; 4]>::deserialize(buf)?;
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    if buf.len() < len {
+        anyhow::bail!("Sealed message payload is truncated.");
     }
+    let bytes = buf[..len].to_vec();
+    buf.consume(len);
+    Ok(bytes)
 }
 
-pub(crate) fn syn_ack_serialized_len(digest: &Digest, delta: &Delta) -> usize {
-    1 + digest.serialized_len() + delta.serialized_len()
+/// Strings are serialized as their length, on two bytes, followed by their bytes.
+fn str_serialized_len(s: &str) -> usize {
+    2 + s.len()
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use crate::serialize::test_serdeser_aux;
-    use crate::{ChitchatMessage, Digest, NodeId};
+    use crate::NodeId;
 
     #[test]
     fn test_syn() {
@@ -130,11 +350,59 @@ mod tests {
             cluster_id: "cluster-a".to_string(),
             digest,
         };
-        test_serdeser_aux(&syn, 68);
+        test_serdeser_aux(&syn, 102);
     }
 
     #[test]
     fn test_bad_cluster() {
-        test_serdeser_aux(&ChitchatMessage::BadCluster, 1);
+        test_serdeser_aux(&ChitchatMessage::BadCluster, 3);
+    }
+
+    #[test]
+    fn test_protocol_version() {
+        let mut buf = Vec::new();
+        ChitchatMessage::BadCluster.serialize(&mut buf);
+        assert_eq!(buf, [VERSIONED_MESSAGE_TAG, PROTOCOL_VERSION, 3]);
+        // An unversioned message of a previous release.
+        assert!(ChitchatMessage::deserialize(&mut &[3u8][..]).is_err());
+        let ack = ChitchatMessage::Ack {
+            cluster_id: "cluster-a".to_string(),
+            delta: Delta::default(),
+        };
+        let mut buf = Vec::new();
+        ack.serialize(&mut buf);
+        assert!(ChitchatMessage::deserialize(&mut &buf[PROTOCOL_HEADER_LEN..]).is_err());
+        let future_message = [VERSIONED_MESSAGE_TAG, PROTOCOL_VERSION + 1, 3];
+        assert!(ChitchatMessage::deserialize(&mut &future_message[..]).is_err());
+    }
+
+    #[test]
+    fn test_authenticated() {
+        let authenticated = ChitchatMessage::Authenticated {
+            payload: vec![3],
+            tag: [7u8; 32],
+        };
+        test_serdeser_aux(&authenticated, 40);
+        let encrypted = ChitchatMessage::Encrypted {
+            nonce: [7u8; 12],
+            ciphertext: vec![3, 4],
+        };
+        test_serdeser_aux(&encrypted, 21);
+    }
+
+    #[test]
+    fn test_probe() {
+        let target = NodeId::for_test_localhost(10_001);
+        let probe_request = ChitchatMessage::ProbeRequest {
+            cluster_id: "cluster-a".to_string(),
+            target: target.clone(),
+        };
+        test_serdeser_aux(&probe_request, 41);
+        let probe_response = ChitchatMessage::ProbeResponse {
+            cluster_id: "cluster-a".to_string(),
+            target,
+            heartbeat: 3,
+        };
+        test_serdeser_aux(&probe_response, 49);
     }
 }
@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+/// Number of gossip rounds started.
+pub(crate) const GOSSIP_ROUNDS_TOTAL: &str = "chitchat_gossip_rounds_total";
+/// Number of messages received from peers of the cluster.
+pub(crate) const MESSAGES_RECEIVED_TOTAL: &str = "chitchat_messages_received_total";
+/// Number of key-values of the deltas received from peers.
+pub(crate) const DELTA_KEY_VALUES: &str = "chitchat_delta_key_values";
+/// Serialized size of the deltas received from peers.
+pub(crate) const DELTA_BYTES: &str = "chitchat_delta_bytes";
+/// Number of versions of the self node a peer lags behind, recorded on every digest.
+pub(crate) const PROPAGATION_LAG_VERSIONS: &str = "chitchat_propagation_lag_versions";
+/// Time it took the stamped writes of peers to reach the node.
+pub(crate) const PROPAGATION_LATENCY_SECONDS: &str = "chitchat_propagation_latency_seconds";
+/// Share of its capacity each delta sent to a peer filled.
+pub(crate) const DELTA_UTILIZATION: &str = "chitchat_delta_utilization";
+/// Number of deltas sent to peers that had to leave stale key-values out for lack of capacity.
+pub(crate) const TRUNCATED_DELTAS_TOTAL: &str = "chitchat_truncated_deltas_total";
+/// Number of nodes the failure detector marked as dead.
+pub(crate) const NODES_MARKED_DEAD_TOTAL: &str = "chitchat_nodes_marked_dead_total";
+/// Number of dead nodes evicted from the cluster state.
+pub(crate) const NODES_EVICTED_TOTAL: &str = "chitchat_nodes_evicted_total";
+/// Number of tombstones garbage collected.
+pub(crate) const TOMBSTONES_GC_TOTAL: &str = "chitchat_tombstones_gc_total";
+
+/// Receives the metrics of a chitchat node, e.g. to expose them to Prometheus.
+///
+/// The recorder is called with the chitchat lock held: it must be cheap and must not block.
+///
+/// Counters:
+/// - `chitchat_gossip_rounds_total`
+/// - `chitchat_messages_received_total`
+/// - `chitchat_nodes_marked_dead_total`
+/// - `chitchat_nodes_evicted_total`
+/// - `chitchat_tombstones_gc_total`
+/// - `chitchat_truncated_deltas_total`: deltas sent that left stale key-values out, for lack of
+///   capacity.
+///
+/// Histograms:
+/// - `chitchat_delta_key_values`: number of key-values of each delta received.
+/// - `chitchat_delta_bytes`: serialized size of each delta received.
+/// - `chitchat_propagation_lag_versions`: number of versions of the self node a peer lags behind,
+///   recorded on each of its digests.
+/// - `chitchat_delta_utilization`: share of its capacity each non-empty delta sent filled.
+/// - `chitchat_propagation_latency_seconds`: time it took each stamped write of a peer to reach the
+///   node, see [`crate::ChitchatConfig`] `propagation_latency_tracking`.
+pub trait MetricsRecorder: Send {
+    fn increment_counter(&self, name: &'static str, value: u64);
+
+    fn record_histogram(&self, name: &'static str, value: f64);
+}
+
+/// Upper bounds of the buckets of the histograms that define none of their own, the default
+/// buckets of the Prometheus clients.
+const DEFAULT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Returns the upper bounds of the buckets of the histogram `name`, in increasing order.
+fn histogram_buckets(name: &str) -> &'static [f64] {
+    match name {
+        DELTA_KEY_VALUES => &[
+            1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1_000.0,
+        ],
+        DELTA_BYTES => &[64.0, 256.0, 1_024.0, 4_096.0, 16_384.0, 65_536.0],
+        PROPAGATION_LAG_VERSIONS => &[0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 1_000.0],
+        DELTA_UTILIZATION => &[0.1, 0.25, 0.5, 0.75, 0.9, 1.0],
+        _ => DEFAULT_BUCKETS,
+    }
+}
+
+#[derive(Debug)]
+struct Histogram {
+    bucket_bounds: &'static [f64],
+    /// Number of values recorded in each bucket, not cumulated. The last bucket holds the values
+    /// above the largest bound.
+    bucket_counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bucket_bounds: &'static [f64]) -> Self {
+        Histogram {
+            bucket_bounds,
+            bucket_counts: vec![0; bucket_bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn record(&mut self, value: f64) {
+        let bucket_idx = self
+            .bucket_bounds
+            .partition_point(|bucket_bound| *bucket_bound < value);
+        self.bucket_counts[bucket_idx] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct RecordedMetrics {
+    counters: BTreeMap<&'static str, u64>,
+    histograms: BTreeMap<&'static str, Histogram>,
+}
+
+/// A [`MetricsRecorder`] keeping the metrics in memory, and rendering them in the Prometheus
+/// text exposition format.
+///
+/// Histograms are rendered with their cumulative buckets, sum and count, so that quantiles can
+/// be computed across nodes. Clones share the same metrics: one clone is handed to the
+/// [`crate::ChitchatConfig`], another one serves the metrics endpoint.
+#[derive(Clone, Debug, Default)]
+pub struct PrometheusRecorder {
+    metrics: Arc<Mutex<RecordedMetrics>>,
+}
+
+impl PrometheusRecorder {
+    pub fn render(&self) -> String {
+        let metrics = self.metrics.lock().unwrap();
+        let mut rendered = String::new();
+        for (name, value) in &metrics.counters {
+            let _ = writeln!(rendered, "# TYPE {name} counter\n{name} {value}");
+        }
+        for (name, histogram) in &metrics.histograms {
+            let _ = writeln!(rendered, "# TYPE {name} histogram");
+            let mut cumulative_count = 0;
+            for (bucket_bound, bucket_count) in
+                histogram.bucket_bounds.iter().zip(&histogram.bucket_counts)
+            {
+                cumulative_count += bucket_count;
+                let _ = writeln!(
+                    rendered,
+                    "{name}_bucket{{le=\"{bucket_bound}\"}} {cumulative_count}"
+                );
+            }
+            let _ = writeln!(
+                rendered,
+                "{name}_bucket{{le=\"+Inf\"}} {}\n{name}_sum {}\n{name}_count {}",
+                histogram.count, histogram.sum, histogram.count
+            );
+        }
+        rendered
+    }
+}
+
+impl MetricsRecorder for PrometheusRecorder {
+    fn increment_counter(&self, name: &'static str, value: u64) {
+        *self
+            .metrics
+            .lock()
+            .unwrap()
+            .counters
+            .entry(name)
+            .or_default() += value;
+    }
+
+    fn record_histogram(&self, name: &'static str, value: f64) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics
+            .histograms
+            .entry(name)
+            .or_insert_with(|| Histogram::new(histogram_buckets(name)))
+            .record(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_recorder() {
+        let recorder = PrometheusRecorder::default();
+        assert_eq!(recorder.render(), "");
+        let recorder_clone = recorder.clone();
+        recorder_clone.increment_counter(GOSSIP_ROUNDS_TOTAL, 1);
+        recorder_clone.increment_counter(GOSSIP_ROUNDS_TOTAL, 2);
+        recorder_clone.record_histogram(DELTA_UTILIZATION, 0.5);
+        recorder_clone.record_histogram(DELTA_UTILIZATION, 0.2);
+        recorder_clone.record_histogram(DELTA_UTILIZATION, 1.5);
+        assert_eq!(
+            recorder.render(),
+            "# TYPE chitchat_gossip_rounds_total counter\nchitchat_gossip_rounds_total 3\n# TYPE \
+             chitchat_delta_utilization histogram\nchitchat_delta_utilization_bucket{le=\"0.1\"} \
+             0\nchitchat_delta_utilization_bucket{le=\"0.25\"} \
+             1\nchitchat_delta_utilization_bucket{le=\"0.5\"} \
+             2\nchitchat_delta_utilization_bucket{le=\"0.75\"} \
+             2\nchitchat_delta_utilization_bucket{le=\"0.9\"} \
+             2\nchitchat_delta_utilization_bucket{le=\"1\"} \
+             2\nchitchat_delta_utilization_bucket{le=\"+Inf\"} 3\nchitchat_delta_utilization_sum \
+             2.2\nchitchat_delta_utilization_count 3\n"
+        );
+    }
+}
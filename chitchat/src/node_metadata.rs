@@ -0,0 +1,23 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Version;
+
+/// Membership-level attributes of a node, kept apart from its application key-values.
+///
+/// The metadata of a node is stored as a whole under a reserved key: updating it replaces the
+/// previous metadata. Peers send stale metadata before the key-values of the other nodes.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct NodeMetadata {
+    /// Version of the metadata, set when read from a node state. Ignored when setting the
+    /// metadata.
+    #[serde(skip)]
+    pub version: Version,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub roles: BTreeSet<String>,
+    #[serde(default)]
+    pub build_version: Option<String>,
+}
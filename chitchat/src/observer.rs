@@ -0,0 +1,96 @@
+use std::collections::HashSet;
+
+use tokio::sync::watch;
+
+use crate::delta::Delta;
+use crate::digest::Digest;
+use crate::serialize::Serializable;
+use crate::state::ClusterState;
+use crate::ClusterStateSnapshot;
+
+/// Digest of the state of an observer, presented to the observed node. It is opaque: it is
+/// shipped to the node through its [`Serializable`] implementation.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ObserverDigest(pub(crate) Digest);
+
+/// Key-values missing from the state of an observer, returned by the observed node. It is
+/// opaque: it is shipped to the observer through its [`Serializable`] implementation.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ObserverDelta(pub(crate) Delta);
+
+impl ObserverDelta {
+    /// Returns true if the observer is up to date.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Serializable for ObserverDigest {
+    fn serialize(&self, buf: &mut Vec<u8>) {
+        self.0.serialize(buf);
+    }
+
+    fn deserialize(buf: &mut &[u8]) -> anyhow::Result<Self> {
+        Digest::deserialize(buf).map(ObserverDigest)
+    }
+
+    fn serialized_len(&self) -> usize {
+        self.0.serialized_len()
+    }
+}
+
+impl Serializable for ObserverDelta {
+    fn serialize(&self, buf: &mut Vec<u8>) {
+        self.0.serialize(buf);
+    }
+
+    fn deserialize(buf: &mut &[u8]) -> anyhow::Result<Self> {
+        Delta::deserialize(buf).map(ObserverDelta)
+    }
+
+    fn serialized_len(&self) -> usize {
+        self.0.serialized_len()
+    }
+}
+
+/// Copy of the cluster state kept by an observer, e.g. a CLI client dumping or watching the
+/// cluster, which does not take part in the gossip.
+///
+/// The observer syncs incrementally: it presents its [`ObserverState::digest`] to a node, which
+/// replies with the key-values the observer is missing through [`crate::Chitchat::observer_delta`],
+/// and applies them with [`ObserverState::apply_delta`]. Since the digest only holds the max
+/// version of each node, repeated sessions only transfer the changes made in between, and an
+/// interrupted sync resumes where it stopped.
+#[derive(Debug)]
+pub struct ObserverState {
+    cluster_state: ClusterState,
+}
+
+impl Default for ObserverState {
+    fn default() -> Self {
+        let (_seed_addrs_tx, seed_addrs_rx) = watch::channel(Default::default());
+        ObserverState {
+            cluster_state: ClusterState::with_seed_addrs(seed_addrs_rx),
+        }
+    }
+}
+
+impl ObserverState {
+    /// Returns the digest to present to the observed node on the next sync.
+    pub fn digest(&self) -> ObserverDigest {
+        ObserverDigest(self.cluster_state.compute_digest(&HashSet::new()))
+    }
+
+    /// Applies a delta returned by [`crate::Chitchat::observer_delta`].
+    pub fn apply_delta(&mut self, delta: ObserverDelta) {
+        self.cluster_state.apply_delta(delta.0);
+    }
+
+    pub fn cluster_state(&self) -> &ClusterState {
+        &self.cluster_state
+    }
+
+    pub fn state_snapshot(&self) -> ClusterStateSnapshot {
+        ClusterStateSnapshot::from(&self.cluster_state)
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+/// Prefix of the keys holding observed-remove sets in a node state.
+pub(crate) const OR_SET_KEY_PREFIX: &str = "__or_set:";
+
+pub(crate) fn or_set_key(name: &str) -> String {
+    format!("{OR_SET_KEY_PREFIX}{name}")
+}
+
+/// An observed-remove set CRDT.
+///
+/// Each node holds its own set in its node state: only the node writes it, and its peers
+/// store it as is. The cluster-wide set, see [`crate::Chitchat::or_set`], merges the sets of
+/// all nodes.
+///
+/// Each addition of an element is identified by a unique tag. Removing an element
+/// removes the tags observed so far, so that an addition concurrent to the removal wins.
+/// Two copies of a set are merged element-wise, by taking the union of their additions
+/// and of their removals.
+///
+/// Removed tags are kept forever, so that a stale copy of the set cannot resurrect removed
+/// elements.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct OrSet {
+    #[serde(default)]
+    adds: BTreeMap<String, BTreeSet<u64>>,
+    #[serde(default)]
+    removes: BTreeSet<u64>,
+}
+
+impl OrSet {
+    pub fn contains(&self, element: &str) -> bool {
+        self.adds.contains_key(element)
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &str> {
+        self.adds.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.adds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.adds.is_empty()
+    }
+
+    /// Adds an element, identified by `tag`. Tags must never be reused.
+    pub(crate) fn insert(&mut self, element: &str, tag: u64) {
+        self.adds
+            .entry(element.to_string())
+            .or_default()
+            .insert(tag);
+    }
+
+    /// Removes an element. Returns false if the element was not in the set.
+    pub(crate) fn remove(&mut self, element: &str) -> bool {
+        let Some(tags) = self.adds.remove(element) else {
+            return false;
+        };
+        self.removes.extend(tags);
+        true
+    }
+
+    pub fn merge(&self, other: &OrSet) -> OrSet {
+        let removes: BTreeSet<u64> = self.removes.union(&other.removes).copied().collect();
+        let mut adds: BTreeMap<String, BTreeSet<u64>> = BTreeMap::new();
+        for (element, tags) in self.adds.iter().chain(other.adds.iter()) {
+            let live_tags = tags.iter().filter(|tag| !removes.contains(tag));
+            adds.entry(element.clone()).or_default().extend(live_tags);
+        }
+        adds.retain(|_, tags| !tags.is_empty());
+        OrSet { adds, removes }
+    }
+
+    pub(crate) fn encode(&self) -> String {
+        serde_json::to_string(self).expect("serializing an OR-set should never fail")
+    }
+
+    pub(crate) fn decode(value: &str) -> Option<OrSet> {
+        serde_json::from_str(value).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_or_set_insert_remove() {
+        let mut or_set = OrSet::default();
+        or_set.insert("shard-1", 1);
+        or_set.insert("shard-2", 2);
+        assert!(or_set.remove("shard-1"));
+        assert!(!or_set.remove("shard-3"));
+        assert_eq!(or_set.elements().collect::<Vec<_>>(), ["shard-2"]);
+        assert_eq!(OrSet::decode(&or_set.encode()).unwrap(), or_set);
+        assert!(OrSet::decode("not-json").is_none());
+    }
+
+    #[test]
+    fn test_or_set_merge() {
+        let mut left = OrSet::default();
+        left.insert("shard-1", 1);
+        left.insert("shard-2", 2);
+        let mut right = left.clone();
+        // Concurrently, left removes shard-1 while right adds it again and adds shard-3.
+        left.remove("shard-1");
+        right.insert("shard-1", 3);
+        right.insert("shard-3", 4);
+        right.remove("shard-2");
+
+        let merged = left.merge(&right);
+        assert_eq!(merged, right.merge(&left));
+        assert_eq!(
+            merged.elements().collect::<Vec<_>>(),
+            ["shard-1", "shard-3"]
+        );
+        assert_eq!(merged.merge(&merged), merged);
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::NodeId;
+
+/// Number of consecutive syns a live peer must leave unanswered to be reported as only
+/// reachable through other peers.
+const UNANSWERED_SYNS_THRESHOLD: u32 = 3;
+
+/// Unreachable peers whose heartbeats stopped within this number of gossip rounds of each other
+/// are reported as a single group.
+pub(crate) const PARTITION_GROUPING_ROUNDS: u32 = 10;
+
+/// Summary of the reachability of the peers, to tell a network split from independent node
+/// failures. See [`crate::Chitchat::partition_report`].
+///
+/// From our side, the other side of a split shows up as a group of peers that all went silent
+/// at about the same time.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PartitionReport {
+    /// Groups of dead or suspect peers whose heartbeats stopped reaching us at about the same
+    /// time, most recently silenced first.
+    pub unreachable_groups: Vec<UnreachableGroup>,
+    /// Live peers that no longer answer our syns, while their heartbeats keep reaching us
+    /// through other peers: they see the rest of the cluster, but not us.
+    pub indirectly_reachable_nodes: BTreeSet<NodeId>,
+    /// Number of live peers that answer our syns.
+    pub num_reachable_peers: usize,
+}
+
+impl PartitionReport {
+    /// Returns true if we are likely on the minority side of a split: the largest group of
+    /// unreachable peers outnumbers the nodes we still reach, ourselves included.
+    pub fn is_minority(&self) -> bool {
+        let largest_group_size = self
+            .unreachable_groups
+            .iter()
+            .map(|group| group.node_ids.len())
+            .max()
+            .unwrap_or(0);
+        largest_group_size > self.num_reachable_peers + 1
+    }
+}
+
+/// Peers that went silent at about the same time.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UnreachableGroup {
+    pub node_ids: BTreeSet<NodeId>,
+    /// Time elapsed since the last heartbeat received from any node of the group.
+    pub silent_for: Duration,
+}
+
+/// Tracks how each peer reaches us: directly, by answering our syns, or only through the
+/// heartbeats relayed by other peers.
+#[derive(Debug, Default)]
+pub(crate) struct ReachabilityTracker {
+    /// Number of consecutive syns left unanswered by each peer.
+    num_unanswered_syns: HashMap<SocketAddr, u32>,
+    /// Time at which the heartbeat of each peer last moved forward.
+    last_heartbeats: HashMap<NodeId, Instant>,
+}
+
+impl ReachabilityTracker {
+    pub fn record_syn_sent(&mut self, peer_addr: SocketAddr) {
+        *self.num_unanswered_syns.entry(peer_addr).or_default() += 1;
+    }
+
+    /// Records that a message was received from `peer_addr`.
+    pub fn record_direct_contact(&mut self, peer_addr: SocketAddr) {
+        self.num_unanswered_syns.remove(&peer_addr);
+    }
+
+    pub fn record_heartbeat(&mut self, node_id: &NodeId, now: Instant) {
+        self.last_heartbeats.insert(node_id.clone(), now);
+    }
+
+    pub fn forget_node(&mut self, node_id: &NodeId) {
+        self.num_unanswered_syns
+            .remove(&node_id.gossip_public_address);
+        self.last_heartbeats.remove(node_id);
+    }
+
+    /// Builds the partition report. Unreachable peers whose heartbeats stopped within
+    /// `grouping_window` of each other are grouped together.
+    pub fn report<'a>(
+        &self,
+        live_nodes: impl Iterator<Item = &'a NodeId>,
+        unreachable_nodes: impl Iterator<Item = &'a NodeId>,
+        grouping_window: Duration,
+        now: Instant,
+    ) -> PartitionReport {
+        let unreachable_nodes: HashSet<&NodeId> = unreachable_nodes.collect();
+        let mut report = PartitionReport::default();
+
+        for node_id in live_nodes.filter(|node_id| !unreachable_nodes.contains(node_id)) {
+            let num_unanswered_syns = self
+                .num_unanswered_syns
+                .get(&node_id.gossip_public_address)
+                .copied()
+                .unwrap_or(0);
+            if num_unanswered_syns >= UNANSWERED_SYNS_THRESHOLD {
+                report.indirectly_reachable_nodes.insert(node_id.clone());
+            } else {
+                report.num_reachable_peers += 1;
+            }
+        }
+        // Peers we never received a heartbeat from cannot be told apart.
+        let mut silent_nodes: Vec<(Instant, &NodeId)> = unreachable_nodes
+            .into_iter()
+            .filter_map(|node_id| Some((*self.last_heartbeats.get(node_id)?, node_id)))
+            .collect();
+        silent_nodes.sort_unstable_by(|left, right| right.cmp(left));
+
+        let mut previous_heartbeat_opt: Option<Instant> = None;
+        for (last_heartbeat, node_id) in silent_nodes {
+            let starts_group = previous_heartbeat_opt.is_none_or(|previous_heartbeat| {
+                previous_heartbeat.duration_since(last_heartbeat) > grouping_window
+            });
+            if starts_group {
+                report.unreachable_groups.push(UnreachableGroup {
+                    node_ids: BTreeSet::new(),
+                    silent_for: now.saturating_duration_since(last_heartbeat),
+                });
+            }
+            let group = report
+                .unreachable_groups
+                .last_mut()
+                .expect("a group should have been started");
+            group.node_ids.insert(node_id.clone());
+            previous_heartbeat_opt = Some(last_heartbeat);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_report() {
+        let mut tracker = ReachabilityTracker::default();
+        let node_ids: Vec<NodeId> = (10_001..10_007).map(NodeId::for_test_localhost).collect();
+        let now = Instant::now();
+        let grouping_window = Duration::from_secs(1);
+
+        for node_id in &node_ids {
+            tracker.record_heartbeat(node_id, now);
+        }
+        // Nodes 2, 3 and 4 are cut from us at once, long after node 5 crashed.
+        tracker.record_heartbeat(&node_ids[2], now + Duration::from_secs(10));
+        tracker.record_heartbeat(&node_ids[3], now + Duration::from_millis(10_500));
+        tracker.record_heartbeat(&node_ids[4], now + Duration::from_secs(11));
+        // Node 1 keeps heartbeating through node 0, but does not answer our syns.
+        for _ in 0..UNANSWERED_SYNS_THRESHOLD {
+            tracker.record_syn_sent(node_ids[0].gossip_public_address);
+            tracker.record_syn_sent(node_ids[1].gossip_public_address);
+            tracker.record_direct_contact(node_ids[0].gossip_public_address);
+        }
+        let report = tracker.report(
+            node_ids[..2].iter(),
+            node_ids[2..].iter(),
+            grouping_window,
+            now + Duration::from_secs(20),
+        );
+        assert_eq!(
+            report,
+            PartitionReport {
+                unreachable_groups: vec![
+                    UnreachableGroup {
+                        node_ids: node_ids[2..5].iter().cloned().collect(),
+                        silent_for: Duration::from_secs(9),
+                    },
+                    UnreachableGroup {
+                        node_ids: BTreeSet::from([node_ids[5].clone()]),
+                        silent_for: Duration::from_secs(20),
+                    },
+                ],
+                indirectly_reachable_nodes: BTreeSet::from([node_ids[1].clone()]),
+                num_reachable_peers: 1,
+            }
+        );
+        assert!(report.is_minority());
+
+        tracker.forget_node(&node_ids[1]);
+        let report = tracker.report(
+            node_ids[..2].iter(),
+            std::iter::empty(),
+            grouping_window,
+            now,
+        );
+        assert!(report.indirectly_reachable_nodes.is_empty());
+        assert_eq!(report.num_reachable_peers, 2);
+        assert!(!report.is_minority());
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, info, warn};
+
+/// Maximum delay between two attempts to contact a peer rejecting our messages.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Number of consecutive rejections after which a peer is reported as rejecting us.
+const NUM_REJECTIONS_BEFORE_REPORTING: u32 = 5;
+
+#[derive(Debug)]
+struct Rejections {
+    num_consecutive_rejections: u32,
+    next_attempt_at: Instant,
+}
+
+/// Keeps track of the peers rejecting our messages, e.g. because they belong to another
+/// cluster.
+///
+/// Such peers are contacted with exponentially increasing delays, rather than at every gossip
+/// round: retrying at full rate would only flood the logs of both nodes.
+#[derive(Debug)]
+pub(crate) struct PeerBackoff {
+    initial_backoff: Duration,
+    rejections: HashMap<SocketAddr, Rejections>,
+}
+
+impl PeerBackoff {
+    pub fn new(initial_backoff: Duration) -> Self {
+        PeerBackoff {
+            initial_backoff,
+            rejections: HashMap::new(),
+        }
+    }
+
+    /// Records that `peer_addr` rejected our last message, and backs off contacting it.
+    pub fn record_rejection(&mut self, peer_addr: SocketAddr, now: Instant) {
+        let rejections = self.rejections.entry(peer_addr).or_insert(Rejections {
+            num_consecutive_rejections: 0,
+            next_attempt_at: now,
+        });
+        rejections.num_consecutive_rejections += 1;
+        let num_consecutive_rejections = rejections.num_consecutive_rejections;
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << num_consecutive_rejections.min(16))
+            .min(MAX_BACKOFF);
+        rejections.next_attempt_at = now + backoff;
+        if num_consecutive_rejections == 1 {
+            warn!(peer_addr = %peer_addr, "message-rejected-by-peer");
+        } else if num_consecutive_rejections == NUM_REJECTIONS_BEFORE_REPORTING {
+            warn!(
+                peer_addr = %peer_addr,
+                num_consecutive_rejections = num_consecutive_rejections,
+                "peer-keeps-rejecting-messages"
+            );
+        } else {
+            debug!(
+                peer_addr = %peer_addr,
+                num_consecutive_rejections = num_consecutive_rejections,
+                backoff = ?backoff,
+                "message-rejected-by-peer"
+            );
+        }
+    }
+
+    /// Records that `peer_addr` accepted our messages.
+    pub fn record_acceptance(&mut self, peer_addr: SocketAddr) {
+        if self.rejections.remove(&peer_addr).is_some() {
+            info!(peer_addr = %peer_addr, "peer-accepts-messages-again");
+        }
+    }
+
+    /// Returns false if `peer_addr` must not be contacted before its backoff elapses.
+    pub fn can_contact(&self, peer_addr: SocketAddr, now: Instant) -> bool {
+        self.rejections
+            .get(&peer_addr)
+            .is_none_or(|rejections| now >= rejections.next_attempt_at)
+    }
+
+    /// Returns the peers that rejected at least [`NUM_REJECTIONS_BEFORE_REPORTING`] messages in
+    /// a row.
+    pub fn rejecting_peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.rejections
+            .iter()
+            .filter(|(_, rejections)| {
+                rejections.num_consecutive_rejections >= NUM_REJECTIONS_BEFORE_REPORTING
+            })
+            .map(|(peer_addr, _)| *peer_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_backoff() {
+        let mut peer_backoff = PeerBackoff::new(Duration::from_secs(1));
+        let peer_addr: SocketAddr = "127.0.0.1:10001".parse().unwrap();
+        let start = Instant::now();
+        assert!(peer_backoff.can_contact(peer_addr, start));
+
+        peer_backoff.record_rejection(peer_addr, start);
+        assert!(!peer_backoff.can_contact(peer_addr, start + Duration::from_secs(1)));
+        assert!(peer_backoff.can_contact(peer_addr, start + Duration::from_secs(2)));
+
+        peer_backoff.record_rejection(peer_addr, start + Duration::from_secs(2));
+        assert!(!peer_backoff.can_contact(peer_addr, start + Duration::from_secs(5)));
+        assert!(peer_backoff.can_contact(peer_addr, start + Duration::from_secs(6)));
+
+        for _ in 2..NUM_REJECTIONS_BEFORE_REPORTING {
+            assert_eq!(peer_backoff.rejecting_peers().count(), 0);
+            peer_backoff.record_rejection(peer_addr, start);
+        }
+        assert_eq!(
+            peer_backoff.rejecting_peers().collect::<Vec<_>>(),
+            [peer_addr]
+        );
+
+        // The backoff is capped.
+        for _ in 0..100 {
+            peer_backoff.record_rejection(peer_addr, start);
+        }
+        assert!(peer_backoff.can_contact(peer_addr, start + MAX_BACKOFF));
+
+        peer_backoff.record_acceptance(peer_addr);
+        assert!(peer_backoff.can_contact(peer_addr, start));
+        assert_eq!(peer_backoff.rejecting_peers().count(), 0);
+    }
+}
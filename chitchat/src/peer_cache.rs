@@ -0,0 +1,187 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::state::ClusterState;
+use crate::NodeId;
+
+/// Peers known to a node, meant to be persisted and used as additional seeds upon restart.
+///
+/// A cache restored from disk can be arbitrarily old, or even belong to another cluster: it
+/// must go through [`PeerCache::restore`] before being used.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PeerCache {
+    pub cluster_id: String,
+    pub peers: Vec<CachedPeer>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CachedPeer {
+    pub node_id: NodeId,
+    /// Last time the peer was seen alive, in seconds since the Unix epoch.
+    pub last_seen_at_secs: u64,
+}
+
+pub(crate) fn unix_timestamp_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl PeerCache {
+    /// Validates a persisted cache against the current cluster id, and drops the peers that
+    /// were not seen alive within `max_age`.
+    ///
+    /// A cache saved by a node of another cluster is discarded as a whole.
+    pub fn restore(self, cluster_id: &str, max_age: Duration, now: SystemTime) -> RestoredPeers {
+        if self.cluster_id != cluster_id {
+            warn!(
+                cluster_id = %cluster_id,
+                peer_cache_cluster_id = %self.cluster_id,
+                "discarding-peer-cache-from-another-cluster"
+            );
+            return RestoredPeers::default();
+        }
+        let min_last_seen_at_secs = unix_timestamp_secs(now).saturating_sub(max_age.as_secs());
+        let num_peers = self.peers.len();
+        let peers: Vec<CachedPeer> = self
+            .peers
+            .into_iter()
+            .filter(|peer| peer.last_seen_at_secs >= min_last_seen_at_secs)
+            .collect();
+        let num_expired_peers = num_peers - peers.len();
+        if num_expired_peers > 0 {
+            warn!(
+                num_expired_peers = num_expired_peers,
+                "dropping-expired-cached-peers"
+            );
+        }
+        RestoredPeers {
+            peers,
+            quarantined_peers: Vec::new(),
+        }
+    }
+}
+
+/// Peers of a validated [`PeerCache`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RestoredPeers {
+    pub peers: Vec<CachedPeer>,
+    /// Peers contradicted by live gossip. They are kept aside for inspection, but must not
+    /// be used as seeds.
+    pub quarantined_peers: Vec<CachedPeer>,
+}
+
+impl RestoredPeers {
+    /// Returns the gossip addresses of the peers that can be used as seeds.
+    pub fn seed_addrs(&self) -> HashSet<SocketAddr> {
+        self.peers
+            .iter()
+            .map(|peer| peer.node_id.gossip_public_address)
+            .collect()
+    }
+
+    /// Quarantines the peers that conflict with the cluster state learnt through gossip:
+    /// - their gossip address is now advertised by a node with another id, e.g. because the peer
+    ///   left and the address got reused;
+    /// - their id is now advertised with another gossip address.
+    ///
+    /// Returns the number of newly quarantined peers.
+    pub fn quarantine_conflicting_peers(&mut self, cluster_state: &ClusterState) -> usize {
+        let peers = std::mem::take(&mut self.peers);
+        let num_quarantined_peers = self.quarantined_peers.len();
+        for peer in peers {
+            let is_conflicting = cluster_state.nodes().any(|node_id| {
+                *node_id != peer.node_id
+                    && (node_id.gossip_public_address == peer.node_id.gossip_public_address
+                        || node_id.id == peer.node_id.id)
+            });
+            if is_conflicting {
+                warn!(node_id = ?peer.node_id, "quarantining-cached-peer");
+                self.quarantined_peers.push(peer);
+            } else {
+                self.peers.push(peer);
+            }
+        }
+        self.quarantined_peers.len() - num_quarantined_peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::Delta;
+
+    fn cached_peer(port: u16, last_seen_at_secs: u64) -> CachedPeer {
+        CachedPeer {
+            node_id: NodeId::for_test_localhost(port),
+            last_seen_at_secs,
+        }
+    }
+
+    #[test]
+    fn test_peer_cache_restore() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let peer_cache = PeerCache {
+            cluster_id: "cluster".to_string(),
+            peers: vec![cached_peer(10_001, 999_000), cached_peer(10_002, 100_000)],
+        };
+        let restored_peers = peer_cache
+            .clone()
+            .restore("cluster", Duration::from_secs(3_600), now);
+        assert_eq!(restored_peers.peers, vec![cached_peer(10_001, 999_000)]);
+        assert_eq!(
+            restored_peers.seed_addrs(),
+            HashSet::from([NodeId::for_test_localhost(10_001).gossip_public_address])
+        );
+
+        let restored_peers = peer_cache.restore("another-cluster", Duration::from_secs(3_600), now);
+        assert!(restored_peers.peers.is_empty());
+    }
+
+    #[test]
+    fn test_restored_peers_quarantine_conflicting_peers() {
+        let mut restored_peers = RestoredPeers {
+            peers: vec![
+                cached_peer(10_001, 0),
+                cached_peer(10_002, 0),
+                cached_peer(10_003, 0),
+            ],
+            quarantined_peers: Vec::new(),
+        };
+        let mut cluster_state = ClusterState::default();
+        let mut delta = Delta::default();
+        // Node 1 is alive and well.
+        delta.add_node_delta(NodeId::for_test_localhost(10_001), "key", "1", 1, false);
+        // The address of node 2 is reused by another node.
+        let new_node = NodeId::new(
+            "new-node".to_string(),
+            NodeId::for_test_localhost(10_002).gossip_public_address,
+        );
+        delta.add_node_delta(new_node, "key", "1", 1, false);
+        // Node 3 moved to another address.
+        let moved_node = NodeId::new(
+            NodeId::for_test_localhost(10_003).id,
+            NodeId::for_test_localhost(10_004).gossip_public_address,
+        );
+        delta.add_node_delta(moved_node, "key", "1", 1, false);
+        cluster_state.apply_delta(delta);
+
+        assert_eq!(
+            restored_peers.quarantine_conflicting_peers(&cluster_state),
+            2
+        );
+        assert_eq!(restored_peers.peers, vec![cached_peer(10_001, 0)]);
+        assert_eq!(
+            restored_peers.quarantined_peers,
+            vec![cached_peer(10_002, 0), cached_peer(10_003, 0)]
+        );
+        assert_eq!(
+            restored_peers.quarantine_conflicting_peers(&cluster_state),
+            0
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::NodeId;
+
+/// Maximum number of nodes probed at once on behalf of peers.
+const MAX_NUM_PENDING_PROBES: usize = 64;
+
+/// Maximum number of peers waiting on the probe of a single node.
+const MAX_NUM_REQUESTERS_PER_PROBE: usize = 8;
+
+/// Maximum number of probe responses waiting to be sent.
+const MAX_NUM_RESPONSES_TO_SEND: usize = MAX_NUM_PENDING_PROBES * MAX_NUM_REQUESTERS_PER_PROBE;
+
+#[derive(Debug)]
+struct PendingProbe {
+    requester_addrs: Vec<SocketAddr>,
+    started_at: Instant,
+}
+
+/// Keeps track of the nodes probed on behalf of peers, from the probe request to the reply of
+/// the probed node.
+#[derive(Debug, Default)]
+pub(crate) struct ProbeTracker {
+    pending_probes: HashMap<NodeId, PendingProbe>,
+    probes_to_start: Vec<SocketAddr>,
+    responses_to_send: Vec<(SocketAddr, NodeId)>,
+}
+
+impl ProbeTracker {
+    /// Records a request of `requester_addr` to probe `target`, starting the probe unless it is
+    /// already running. Requests beyond the bounds of the tracker are dropped.
+    pub fn record_request(&mut self, target: &NodeId, requester_addr: SocketAddr, now: Instant) {
+        if let Some(pending_probe) = self.pending_probes.get_mut(target) {
+            if pending_probe.requester_addrs.len() < MAX_NUM_REQUESTERS_PER_PROBE
+                && !pending_probe.requester_addrs.contains(&requester_addr)
+            {
+                pending_probe.requester_addrs.push(requester_addr);
+            }
+            return;
+        }
+        if self.pending_probes.len() >= MAX_NUM_PENDING_PROBES {
+            return;
+        }
+        self.pending_probes.insert(
+            target.clone(),
+            PendingProbe {
+                requester_addrs: vec![requester_addr],
+                started_at: now,
+            },
+        );
+        self.probes_to_start.push(target.gossip_public_address);
+    }
+
+    /// Returns the addresses of the nodes to gossip with to run the probes recorded since the
+    /// last call.
+    pub fn take_probes_to_start(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.probes_to_start)
+    }
+
+    /// Completes the probes of the node at `target_addr`, which just replied: its requesters
+    /// are to be sent a response.
+    pub fn complete_probes(&mut self, target_addr: SocketAddr) {
+        let Some(target) = self
+            .pending_probes
+            .keys()
+            .find(|target| target.gossip_public_address == target_addr)
+            .cloned()
+        else {
+            return;
+        };
+        let pending_probe = self.pending_probes.remove(&target).unwrap();
+        for requester_addr in pending_probe.requester_addrs {
+            if self.responses_to_send.len() >= MAX_NUM_RESPONSES_TO_SEND {
+                return;
+            }
+            self.responses_to_send
+                .push((requester_addr, target.clone()));
+        }
+    }
+
+    /// Returns the requesters of the probes completed since the last call, along with the node
+    /// they probed.
+    pub fn take_responses_to_send(&mut self) -> Vec<(SocketAddr, NodeId)> {
+        std::mem::take(&mut self.responses_to_send)
+    }
+
+    /// Drops the probes started more than `timeout` ago: their targets did not reply, and their
+    /// requesters get no response.
+    pub fn expire(&mut self, timeout: Duration, now: Instant) {
+        self.pending_probes.retain(|_, pending_probe| {
+            now.saturating_duration_since(pending_probe.started_at) < timeout
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_tracker() {
+        let mut probe_tracker = ProbeTracker::default();
+        let target = NodeId::for_test_localhost(10_001);
+        let requester_addr: SocketAddr = "127.0.0.1:10002".parse().unwrap();
+        let other_requester_addr: SocketAddr = "127.0.0.1:10003".parse().unwrap();
+        let now = Instant::now();
+        probe_tracker.record_request(&target, requester_addr, now);
+        probe_tracker.record_request(&target, requester_addr, now);
+        probe_tracker.record_request(&target, other_requester_addr, now);
+        // The probe is started once.
+        assert_eq!(
+            probe_tracker.take_probes_to_start(),
+            [target.gossip_public_address]
+        );
+        assert!(probe_tracker.take_probes_to_start().is_empty());
+        probe_tracker.complete_probes(target.gossip_public_address);
+        probe_tracker.complete_probes(target.gossip_public_address);
+        assert_eq!(
+            probe_tracker.take_responses_to_send(),
+            [
+                (requester_addr, target.clone()),
+                (other_requester_addr, target.clone())
+            ]
+        );
+
+        probe_tracker.record_request(&target, requester_addr, now);
+        probe_tracker.take_probes_to_start();
+        probe_tracker.expire(Duration::from_secs(1), now + Duration::from_secs(1));
+        probe_tracker.complete_probes(target.gossip_public_address);
+        assert!(probe_tracker.take_responses_to_send().is_empty());
+
+        for port in 0..MAX_NUM_PENDING_PROBES as u16 + 1 {
+            let target = NodeId::for_test_localhost(20_000 + port);
+            probe_tracker.record_request(&target, requester_addr, now);
+        }
+        assert_eq!(
+            probe_tracker.take_probes_to_start().len(),
+            MAX_NUM_PENDING_PROBES
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+use tokio::sync::watch;
+
+use crate::{NodeId, Version};
+
+/// Keeps track of the max version of the self node acknowledged by each peer in its digest, to
+/// let the application wait for its writes to reach a quorum of live nodes.
+///
+/// Peers are tracked by node ID, so that a peer restarting with a new generation starts from
+/// scratch.
+#[derive(Debug)]
+pub(crate) struct PropagationWatermarks {
+    acknowledged_versions: HashMap<NodeId, Version>,
+    /// Versions acknowledged by the live peers.
+    live_acknowledged_versions_tx: watch::Sender<Vec<Version>>,
+    live_acknowledged_versions_rx: watch::Receiver<Vec<Version>>,
+}
+
+impl Default for PropagationWatermarks {
+    fn default() -> Self {
+        let (live_acknowledged_versions_tx, live_acknowledged_versions_rx) =
+            watch::channel(Vec::new());
+        PropagationWatermarks {
+            acknowledged_versions: HashMap::new(),
+            live_acknowledged_versions_tx,
+            live_acknowledged_versions_rx,
+        }
+    }
+}
+
+impl PropagationWatermarks {
+    /// Records the version of the self node found in the digest of `peer`. Returns true if the
+    /// acknowledged version changed.
+    ///
+    /// The version can move backward, e.g. if the peer restarted without bumping its generation
+    /// and lost the state of the self node.
+    pub fn record_digest(&mut self, peer: &NodeId, version: Version) -> bool {
+        if self.acknowledged_versions.get(peer) == Some(&version) {
+            return false;
+        }
+        self.acknowledged_versions.insert(peer.clone(), version);
+        true
+    }
+
+    /// Publishes the versions acknowledged by the live peers to the waiters.
+    pub fn publish<'a>(&mut self, live_peers: impl Iterator<Item = &'a NodeId>) {
+        let live_acknowledged_versions: Vec<Version> = live_peers
+            .filter_map(|peer| self.acknowledged_versions.get(peer).copied())
+            .collect();
+        if *self.live_acknowledged_versions_rx.borrow() != live_acknowledged_versions {
+            // A receiver is held by `self`: sending cannot fail.
+            let _ = self
+                .live_acknowledged_versions_tx
+                .send(live_acknowledged_versions);
+        }
+    }
+
+    pub fn acknowledged_version(&self, peer: &NodeId) -> Option<Version> {
+        self.acknowledged_versions.get(peer).copied()
+    }
+
+    pub fn forget_peer(&mut self, peer: &NodeId) {
+        self.acknowledged_versions.remove(peer);
+    }
+
+    /// Forgets the peers for which `is_known` returns false.
+    pub fn retain_peers(&mut self, is_known: impl Fn(&NodeId) -> bool) {
+        self.acknowledged_versions.retain(|peer, _| is_known(peer));
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Vec<Version>> {
+        self.live_acknowledged_versions_rx.clone()
+    }
+}
+
+/// Waits for at least `quorum` live peers to acknowledge `version`. Returns false if the
+/// watermarks are dropped first.
+pub(crate) async fn wait_for_quorum(
+    mut live_acknowledged_versions_rx: watch::Receiver<Vec<Version>>,
+    version: Version,
+    quorum: usize,
+) -> bool {
+    loop {
+        let num_acknowledgements = live_acknowledged_versions_rx
+            .borrow()
+            .iter()
+            .filter(|acknowledged_version| **acknowledged_version >= version)
+            .count();
+        if num_acknowledgements >= quorum {
+            return true;
+        }
+        if live_acknowledged_versions_rx.changed().await.is_err() {
+            return false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_propagation_watermarks() {
+        let mut watermarks = PropagationWatermarks::default();
+        let peer1 = NodeId::for_test_localhost(10_001);
+        let peer2 = NodeId::for_test_localhost(10_002);
+        assert!(wait_for_quorum(watermarks.subscribe(), 5, 0).await);
+
+        let wait_handle = tokio::spawn(wait_for_quorum(watermarks.subscribe(), 5, 2));
+        assert!(watermarks.record_digest(&peer1, 5));
+        assert!(!watermarks.record_digest(&peer1, 5));
+        assert!(watermarks.record_digest(&peer2, 4));
+        watermarks.publish([&peer1, &peer2].into_iter());
+        tokio::task::yield_now().await;
+        assert!(!wait_handle.is_finished());
+
+        assert!(watermarks.record_digest(&peer2, 6));
+        watermarks.publish([&peer1, &peer2].into_iter());
+        assert!(wait_handle.await.unwrap());
+
+        // A peer that lost the state of the self node no longer acknowledges its versions.
+        assert!(watermarks.record_digest(&peer1, 0));
+        assert_eq!(watermarks.acknowledged_version(&peer1), Some(0));
+        // A restarted peer starts from scratch.
+        let restarted_peer2 = peer2.clone().with_generation(1);
+        assert!(watermarks.acknowledged_version(&restarted_peer2).is_none());
+        watermarks.retain_peers(|peer| *peer == restarted_peer2);
+        assert!(watermarks.acknowledged_version(&peer2).is_none());
+
+        // Peers that are not live do not count.
+        let wait_handle = tokio::spawn(wait_for_quorum(watermarks.subscribe(), 5, 2));
+        watermarks.publish([&peer1].into_iter());
+        watermarks.forget_peer(&peer2);
+        drop(watermarks);
+        assert!(!wait_handle.await.unwrap());
+    }
+}
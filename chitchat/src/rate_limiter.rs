@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::ReceiveRateLimit;
+
+/// Budget left to a source address, refilled continuously up to one second worth of messages
+/// and bytes.
+#[derive(Debug)]
+struct Budget {
+    num_messages: f64,
+    num_bytes: f64,
+    refilled_at: Instant,
+}
+
+/// Token buckets limiting the rate of the messages received from each source address.
+#[derive(Debug, Default)]
+pub(crate) struct SourceRateLimiter {
+    budgets: HashMap<SocketAddr, Budget>,
+}
+
+impl SourceRateLimiter {
+    /// Returns true if a message of `num_bytes` received from `from_addr` fits in the budget of
+    /// the address, which is then consumed.
+    pub fn admit(
+        &mut self,
+        rate_limit: &ReceiveRateLimit,
+        from_addr: SocketAddr,
+        num_bytes: usize,
+        now: Instant,
+    ) -> bool {
+        let max_num_messages = rate_limit.max_messages_per_sec as f64;
+        let max_num_bytes = rate_limit.max_bytes_per_sec as f64;
+        let budget = self.budgets.entry(from_addr).or_insert(Budget {
+            num_messages: max_num_messages,
+            num_bytes: max_num_bytes,
+            refilled_at: now,
+        });
+        let elapsed_secs = now
+            .saturating_duration_since(budget.refilled_at)
+            .as_secs_f64();
+        budget.num_messages =
+            (budget.num_messages + elapsed_secs * max_num_messages).min(max_num_messages);
+        budget.num_bytes = (budget.num_bytes + elapsed_secs * max_num_bytes).min(max_num_bytes);
+        budget.refilled_at = now;
+        if budget.num_messages < 1.0 || budget.num_bytes < num_bytes as f64 {
+            return false;
+        }
+        budget.num_messages -= 1.0;
+        budget.num_bytes -= num_bytes as f64;
+        true
+    }
+
+    /// Forgets the addresses whose budget is refilled, as they are no different from unknown
+    /// addresses, so that spoofed addresses do not accumulate.
+    pub fn prune(&mut self, now: Instant) {
+        self.budgets.retain(|_, budget| {
+            now.saturating_duration_since(budget.refilled_at) < Duration::from_secs(1)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_rate_limiter() {
+        let rate_limit = ReceiveRateLimit {
+            max_messages_per_sec: 2,
+            max_bytes_per_sec: 1_000,
+        };
+        let mut rate_limiter = SourceRateLimiter::default();
+        let addr1: SocketAddr = "127.0.0.1:10001".parse().unwrap();
+        let addr2: SocketAddr = "127.0.0.1:10002".parse().unwrap();
+        let now = Instant::now();
+        assert!(rate_limiter.admit(&rate_limit, addr1, 100, now));
+        assert!(rate_limiter.admit(&rate_limit, addr1, 100, now));
+        assert!(!rate_limiter.admit(&rate_limit, addr1, 100, now));
+        // Each address has its own budget.
+        assert!(rate_limiter.admit(&rate_limit, addr2, 900, now));
+        assert!(!rate_limiter.admit(&rate_limit, addr2, 200, now));
+
+        // The budgets refill over time.
+        let later = now + Duration::from_millis(500);
+        assert!(rate_limiter.admit(&rate_limit, addr1, 100, later));
+        assert!(!rate_limiter.admit(&rate_limit, addr1, 100, later));
+        assert!(rate_limiter.admit(&rate_limit, addr2, 500, later));
+
+        rate_limiter.prune(later + Duration::from_millis(999));
+        assert_eq!(rate_limiter.budgets.len(), 2);
+        rate_limiter.prune(later + Duration::from_secs(1));
+        assert!(rate_limiter.budgets.is_empty());
+    }
+}
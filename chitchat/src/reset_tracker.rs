@@ -0,0 +1,153 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+use tracing::warn;
+
+use crate::delta::Delta;
+use crate::digest::Digest;
+use crate::{NodeId, Version};
+
+/// Keeps track of the node resets sent to each peer, until they are reflected
+/// in the peer's digest.
+///
+/// A reset that got lost would otherwise leave the peer applying subsequent
+/// partial deltas on top of a stale node state.
+#[derive(Debug, Default)]
+pub(crate) struct ResetTracker {
+    pending_resets: HashMap<SocketAddr, HashMap<NodeId, PendingReset>>,
+    num_reset_retransmissions: u64,
+}
+
+#[derive(Debug)]
+struct PendingReset {
+    /// Max version of the reset node that was sent along with the reset.
+    version: Version,
+    /// Number of times the reset was sent to the peer.
+    num_attempts: u64,
+}
+
+impl ResetTracker {
+    /// Returns the nodes for which a reset was sent to the peer but isn't reflected in the peer's
+    /// digest yet. These resets must be sent again.
+    ///
+    /// Resets that are reflected in the digest are considered confirmed and are forgotten.
+    pub fn unconfirmed_resets(
+        &mut self,
+        peer_addr: SocketAddr,
+        digest: &Digest,
+    ) -> HashSet<NodeId> {
+        let Some(pending_resets) = self.pending_resets.get_mut(&peer_addr) else {
+            return HashSet::new();
+        };
+        pending_resets.retain(|node_id, pending_reset| {
+            let peer_version = digest.max_version(node_id).unwrap_or(0);
+            peer_version < pending_reset.version
+        });
+        if pending_resets.is_empty() {
+            self.pending_resets.remove(&peer_addr);
+            return HashSet::new();
+        }
+        pending_resets.keys().cloned().collect()
+    }
+
+    /// Records the resets contained in a delta sent to the peer.
+    pub fn record_sent_resets(&mut self, peer_addr: SocketAddr, delta: &Delta) {
+        if delta.nodes_to_reset.is_empty() {
+            return;
+        }
+        let pending_resets = self.pending_resets.entry(peer_addr).or_default();
+        for node_id in &delta.nodes_to_reset {
+            let version = delta
+                .node_deltas
+                .get(node_id)
+                .map(|node_delta| node_delta.max_version())
+                .unwrap_or(0);
+            let pending_reset = pending_resets
+                .entry(node_id.clone())
+                .or_insert(PendingReset {
+                    version,
+                    num_attempts: 0,
+                });
+            if pending_reset.num_attempts > 0 {
+                self.num_reset_retransmissions += 1;
+                warn!(
+                    peer_addr = %peer_addr,
+                    node_id = ?node_id,
+                    num_attempts = pending_reset.num_attempts,
+                    "resending-unconfirmed-node-reset"
+                );
+            }
+            pending_reset.version = version;
+            pending_reset.num_attempts += 1;
+        }
+    }
+
+    /// Forgets about a node, both as a reset node and as a peer.
+    pub fn remove_node(&mut self, node_id: &NodeId) {
+        self.pending_resets.remove(&node_id.gossip_public_address);
+        self.pending_resets.retain(|_, pending_resets| {
+            pending_resets.remove(node_id);
+            !pending_resets.is_empty()
+        });
+    }
+
+    /// Returns the number of times a reset was sent again to a peer because the previous
+    /// attempt was not reflected in the peer's digest.
+    pub fn num_reset_retransmissions(&self) -> u64 {
+        self.num_reset_retransmissions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delta_with_reset(node_id: &NodeId, version: Version) -> Delta {
+        let mut delta = Delta::default();
+        delta.add_node_to_reset(node_id.clone());
+        delta.add_node_delta(node_id.clone(), "key", "value", version, false);
+        delta
+    }
+
+    #[test]
+    fn test_reset_tracker_confirmed_reset() {
+        let mut reset_tracker = ResetTracker::default();
+        let peer_addr = NodeId::for_test_localhost(10_001).gossip_public_address;
+        let node_id = NodeId::for_test_localhost(10_002);
+        reset_tracker.record_sent_resets(peer_addr, &delta_with_reset(&node_id, 5));
+
+        let mut digest = Digest::default();
+        digest.add_node(node_id, 5);
+        assert!(reset_tracker
+            .unconfirmed_resets(peer_addr, &digest)
+            .is_empty());
+        assert!(reset_tracker.pending_resets.is_empty());
+        assert_eq!(reset_tracker.num_reset_retransmissions(), 0);
+    }
+
+    #[test]
+    fn test_reset_tracker_unconfirmed_reset() {
+        let mut reset_tracker = ResetTracker::default();
+        let peer_addr = NodeId::for_test_localhost(10_001).gossip_public_address;
+        let other_peer_addr = NodeId::for_test_localhost(10_003).gossip_public_address;
+        let node_id = NodeId::for_test_localhost(10_002);
+        reset_tracker.record_sent_resets(peer_addr, &delta_with_reset(&node_id, 5));
+
+        let mut digest = Digest::default();
+        digest.add_node(node_id.clone(), 2);
+        assert!(reset_tracker
+            .unconfirmed_resets(other_peer_addr, &digest)
+            .is_empty());
+        assert_eq!(
+            reset_tracker.unconfirmed_resets(peer_addr, &digest),
+            HashSet::from_iter([node_id.clone()])
+        );
+        reset_tracker.record_sent_resets(peer_addr, &delta_with_reset(&node_id, 6));
+        assert_eq!(reset_tracker.num_reset_retransmissions(), 1);
+
+        reset_tracker.remove_node(&node_id);
+        assert!(reset_tracker
+            .unconfirmed_resets(peer_addr, &digest)
+            .is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::delta::Delta;
+use crate::state::ClusterState;
+use crate::{NodeId, Version};
+
+/// Maximum number of nodes fenced at once.
+const MAX_NUM_FENCES: usize = 1_024;
+
+#[derive(Debug)]
+struct Fence {
+    /// Max version of the node known before the rollback.
+    stale_max_version: Version,
+    fenced_at: Instant,
+}
+
+/// Keeps track of the nodes reset after a version rollback, e.g. restored from an old backup
+/// without bumping their generation.
+///
+/// Peers that did not gossip with a rolled back node yet still hold its stale state, whose
+/// versions are higher than the rolled back ones. Received from them, the stale state would be
+/// applied again, then reset again on the next digest of the node, and so on. The node deltas of
+/// a fenced node are only accepted from the node itself, until its versions catch up with the
+/// stale ones, or the fence expires.
+#[derive(Debug)]
+pub(crate) struct RollbackFences {
+    fence_duration: Duration,
+    fences: HashMap<NodeId, Fence>,
+}
+
+impl RollbackFences {
+    pub fn new(fence_duration: Duration) -> Self {
+        RollbackFences {
+            fence_duration,
+            fences: HashMap::new(),
+        }
+    }
+
+    /// Fences `node_id`, which rolled back from `stale_max_version`.
+    pub fn fence(&mut self, node_id: &NodeId, stale_max_version: Version, now: Instant) {
+        if self.fences.len() >= MAX_NUM_FENCES && !self.fences.contains_key(node_id) {
+            return;
+        }
+        let fence = self.fences.entry(node_id.clone()).or_insert(Fence {
+            stale_max_version,
+            fenced_at: now,
+        });
+        fence.stale_max_version = fence.stale_max_version.max(stale_max_version);
+        fence.fenced_at = now;
+    }
+
+    /// Removes from the delta received from `from_addr` the node deltas and resets of the fenced
+    /// nodes, unless they come from the node itself.
+    pub fn filter_delta(&self, from_addr: SocketAddr, delta: &mut Delta) {
+        if self.fences.is_empty() {
+            return;
+        }
+        let is_fenced = |node_id: &NodeId| {
+            node_id.gossip_public_address != from_addr && self.fences.contains_key(node_id)
+        };
+        delta.node_deltas.retain(|node_id, _| {
+            if is_fenced(node_id) {
+                debug!(
+                    node_id = ?node_id,
+                    from_addr = %from_addr,
+                    "dropping-node-delta-of-fenced-node"
+                );
+                return false;
+            }
+            true
+        });
+        delta.nodes_to_reset.retain(|node_id| !is_fenced(node_id));
+    }
+
+    /// Lifts the fences of the nodes whose versions caught up with the stale ones, and the
+    /// expired fences.
+    pub fn lift_fences(&mut self, cluster_state: &ClusterState, now: Instant) {
+        let fence_duration = self.fence_duration;
+        self.fences.retain(|node_id, fence| {
+            let has_caught_up = cluster_state
+                .node_state(node_id)
+                .is_some_and(|node_state| node_state.max_version >= fence.stale_max_version);
+            !has_caught_up && now.saturating_duration_since(fence.fenced_at) < fence_duration
+        });
+    }
+
+    pub fn remove_node(&mut self, node_id: &NodeId) {
+        self.fences.remove(node_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rollback_fences() {
+        let mut rollback_fences = RollbackFences::new(Duration::from_secs(10));
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let now = Instant::now();
+        rollback_fences.fence(&node1, 3, now);
+
+        let make_delta = || {
+            let mut delta = Delta::default();
+            delta.nodes_to_reset.insert(node1.clone());
+            delta.add_node_delta(node1.clone(), "key_a", "1", 3, false);
+            delta.add_node_delta(node2.clone(), "key_a", "1", 1, false);
+            delta
+        };
+        // The stale state of node 1 relayed by node 2 is dropped.
+        let mut relayed_delta = make_delta();
+        rollback_fences.filter_delta(node2.gossip_public_address, &mut relayed_delta);
+        assert!(relayed_delta.nodes_to_reset.is_empty());
+        assert_eq!(
+            relayed_delta.node_deltas.keys().collect::<Vec<_>>(),
+            [&node2]
+        );
+        // Node 1 is trusted about itself.
+        let mut direct_delta = make_delta();
+        rollback_fences.filter_delta(node1.gossip_public_address, &mut direct_delta);
+        assert_eq!(direct_delta, make_delta());
+
+        let mut cluster_state = ClusterState::default();
+        cluster_state.node_state_mut(&node1).set("key_a", "1");
+        rollback_fences.lift_fences(&cluster_state, now);
+        assert!(rollback_fences.fences.contains_key(&node1));
+        cluster_state.node_state_mut(&node1).set("key_b", "2");
+        cluster_state.node_state_mut(&node1).set("key_c", "3");
+        rollback_fences.lift_fences(&cluster_state, now);
+        assert!(rollback_fences.fences.is_empty());
+
+        rollback_fences.fence(&node2, 3, now);
+        rollback_fences.lift_fences(&cluster_state, now + Duration::from_secs(10));
+        assert!(rollback_fences.fences.is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Weight of a new sample in the smoothed round-trip time, as in TCP.
+const RTT_SMOOTHING_FACTOR: f64 = 0.125;
+
+/// Maximum number of syns waiting for a syn ack at once.
+const MAX_NUM_PENDING_SYNS: usize = 1_024;
+
+/// Duration after which a syn is no longer expected to be answered.
+const PENDING_SYN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Estimates the round-trip time to each peer, from the delay between a syn and the syn ack
+/// answering it.
+#[derive(Debug, Default)]
+pub(crate) struct RttTracker {
+    /// Time at which the last syn was sent to each peer not answering yet.
+    pending_syns: HashMap<SocketAddr, Instant>,
+    smoothed_rtts: HashMap<SocketAddr, Duration>,
+}
+
+impl RttTracker {
+    /// Records a syn sent to `peer_addr`. Syns beyond the bound of the tracker are dropped,
+    /// once the syns that timed out are.
+    pub fn record_syn_sent(&mut self, peer_addr: SocketAddr, now: Instant) {
+        if self.pending_syns.len() >= MAX_NUM_PENDING_SYNS
+            && !self.pending_syns.contains_key(&peer_addr)
+        {
+            self.pending_syns
+                .retain(|_, sent_at| now.saturating_duration_since(*sent_at) < PENDING_SYN_TIMEOUT);
+            if self.pending_syns.len() >= MAX_NUM_PENDING_SYNS {
+                return;
+            }
+        }
+        self.pending_syns.insert(peer_addr, now);
+    }
+
+    /// Records the syn ack received from `peer_addr`. Syn acks answering a syn we did not send
+    /// are ignored.
+    pub fn record_syn_ack_received(&mut self, peer_addr: SocketAddr, now: Instant) {
+        let Some(sent_at) = self.pending_syns.remove(&peer_addr) else {
+            return;
+        };
+        if now.saturating_duration_since(sent_at) >= PENDING_SYN_TIMEOUT {
+            return;
+        }
+        let sample = now.saturating_duration_since(sent_at);
+        let smoothed_rtt = match self.smoothed_rtts.get(&peer_addr) {
+            Some(smoothed_rtt) => {
+                smoothed_rtt.mul_f64(1.0 - RTT_SMOOTHING_FACTOR)
+                    + sample.mul_f64(RTT_SMOOTHING_FACTOR)
+            }
+            None => sample,
+        };
+        self.smoothed_rtts.insert(peer_addr, smoothed_rtt);
+    }
+
+    /// Removes the syn pending for `peer_addr`. Returns true if we sent one and it did not time
+    /// out, i.e. if `peer_addr` may legitimately answer it.
+    pub fn take_pending_syn(&mut self, peer_addr: SocketAddr, now: Instant) -> bool {
+        self.pending_syns
+            .remove(&peer_addr)
+            .is_some_and(|sent_at| now.saturating_duration_since(sent_at) < PENDING_SYN_TIMEOUT)
+    }
+
+    pub fn rtt(&self, peer_addr: SocketAddr) -> Option<Duration> {
+        self.smoothed_rtts.get(&peer_addr).copied()
+    }
+
+    pub fn forget_peer(&mut self, peer_addr: SocketAddr) {
+        self.pending_syns.remove(&peer_addr);
+        self.smoothed_rtts.remove(&peer_addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt_tracker() {
+        let mut rtt_tracker = RttTracker::default();
+        let peer_addr: SocketAddr = "127.0.0.1:10001".parse().unwrap();
+        let now = Instant::now();
+        assert!(rtt_tracker.rtt(peer_addr).is_none());
+
+        rtt_tracker.record_syn_ack_received(peer_addr, now);
+        assert!(rtt_tracker.rtt(peer_addr).is_none());
+
+        rtt_tracker.record_syn_sent(peer_addr, now);
+        rtt_tracker.record_syn_ack_received(peer_addr, now + Duration::from_millis(80));
+        assert_eq!(rtt_tracker.rtt(peer_addr), Some(Duration::from_millis(80)));
+
+        rtt_tracker.record_syn_sent(peer_addr, now);
+        rtt_tracker.record_syn_ack_received(peer_addr, now + Duration::from_millis(160));
+        assert_eq!(rtt_tracker.rtt(peer_addr), Some(Duration::from_millis(90)));
+
+        // Duplicate syn acks are ignored.
+        rtt_tracker.record_syn_ack_received(peer_addr, now + Duration::from_secs(10));
+        assert_eq!(rtt_tracker.rtt(peer_addr), Some(Duration::from_millis(90)));
+
+        // Syn acks answering a timed out syn are ignored.
+        rtt_tracker.record_syn_sent(peer_addr, now);
+        rtt_tracker.record_syn_ack_received(peer_addr, now + PENDING_SYN_TIMEOUT);
+        assert_eq!(rtt_tracker.rtt(peer_addr), Some(Duration::from_millis(90)));
+
+        rtt_tracker.forget_peer(peer_addr);
+        assert!(rtt_tracker.rtt(peer_addr).is_none());
+
+        assert!(!rtt_tracker.take_pending_syn(peer_addr, now));
+        rtt_tracker.record_syn_sent(peer_addr, now);
+        assert!(rtt_tracker.take_pending_syn(peer_addr, now));
+        assert!(!rtt_tracker.take_pending_syn(peer_addr, now));
+        rtt_tracker.record_syn_sent(peer_addr, now);
+        assert!(!rtt_tracker.take_pending_syn(peer_addr, now + PENDING_SYN_TIMEOUT));
+    }
+
+    #[test]
+    fn test_rtt_tracker_bounds_pending_syns() {
+        let mut rtt_tracker = RttTracker::default();
+        let now = Instant::now();
+        for port in 0..MAX_NUM_PENDING_SYNS as u16 + 1 {
+            let peer_addr = SocketAddr::from(([127, 0, 0, 1], 20_000 + port));
+            rtt_tracker.record_syn_sent(peer_addr, now);
+        }
+        assert_eq!(rtt_tracker.pending_syns.len(), MAX_NUM_PENDING_SYNS);
+
+        // Timed out syns make room for new ones.
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 10_001));
+        rtt_tracker.record_syn_sent(peer_addr, now + PENDING_SYN_TIMEOUT);
+        assert_eq!(rtt_tracker.pending_syns.len(), 1);
+    }
+}
@@ -0,0 +1,223 @@
+use std::fmt;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+
+use crate::state::NodeState;
+use crate::EncryptionKey;
+
+const NONCE_LEN: usize = 12;
+
+/// Error returned when the value of a sealed key cannot be decrypted.
+#[derive(Debug, Eq, PartialEq)]
+pub struct UnsealError {
+    pub key: String,
+}
+
+impl fmt::Display for UnsealError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to unseal the value of key `{}`", self.key)
+    }
+}
+
+impl std::error::Error for UnsealError {}
+
+/// Key prefixes whose values are encrypted end to end.
+///
+/// The values of the sealed keys are encrypted with the key of their prefix before being
+/// versioned and gossiped: the nodes that do not hold the key replicate the ciphertext without
+/// being able to read it. Keys stay in the clear, and are bound to their value, so that a
+/// sealed value cannot be replayed under another key.
+///
+/// Values are sealed on write by the self node; reading them requires
+/// [`SealedKeys::unseal`], or [`NodeState::get_unsealed`].
+#[derive(Clone, Default)]
+pub struct SealedKeys {
+    prefixes: Vec<(String, Aes256Gcm)>,
+}
+
+impl fmt::Debug for SealedKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.prefixes.iter().map(|(prefix, _)| prefix))
+            .finish()
+    }
+}
+
+impl SealedKeys {
+    /// Seals the keys starting with `key_prefix` with `key`. When prefixes overlap, the
+    /// longest one applies.
+    pub fn seal_prefix(mut self, key_prefix: impl Into<String>, key: &EncryptionKey) -> Self {
+        self.prefixes
+            .push((key_prefix.into(), Aes256Gcm::new(&key.0.into())));
+        self
+    }
+
+    fn cipher(&self, key: &str) -> Option<&Aes256Gcm> {
+        self.prefixes
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, cipher)| cipher)
+    }
+
+    /// Returns true if the value of `key` is encrypted.
+    pub fn is_sealed(&self, key: &str) -> bool {
+        self.cipher(key).is_some()
+    }
+
+    /// Returns the value to publish for `key`: `value` encrypted if the key is sealed, `value`
+    /// itself otherwise.
+    pub(crate) fn seal(&self, key: &str, value: String) -> String {
+        let Some(cipher) = self.cipher(key) else {
+            return value;
+        };
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: value.as_bytes(),
+            aad: key.as_bytes(),
+        };
+        let ciphertext = cipher
+            .encrypt(&nonce, payload)
+            .expect("encryption into a vec should not fail");
+        let mut sealed_value = String::with_capacity((NONCE_LEN + ciphertext.len()) * 2);
+        for byte in nonce.iter().chain(&ciphertext) {
+            sealed_value.push_str(&format!("{byte:02x}"));
+        }
+        sealed_value
+    }
+
+    /// Returns the value of `key` in the clear, decrypting it if the key is sealed.
+    pub fn unseal(&self, key: &str, value: &str) -> Result<String, UnsealError> {
+        let Some(cipher) = self.cipher(key) else {
+            return Ok(value.to_string());
+        };
+        let unseal_error = || UnsealError {
+            key: key.to_string(),
+        };
+        let bytes = decode_hex(value).ok_or_else(unseal_error)?;
+        if bytes.len() < NONCE_LEN {
+            return Err(unseal_error());
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: key.as_bytes(),
+        };
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| unseal_error())?;
+        String::from_utf8(plaintext).map_err(|_| unseal_error())
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|pos| u8::from_str_radix(hex.get(pos..pos + 2)?, 16).ok())
+        .collect()
+}
+
+impl NodeState {
+    /// Returns the value of `key` in the clear, decrypting it with `sealed_keys` if the key is
+    /// sealed.
+    ///
+    /// Keys marked for deletion are ignored.
+    pub fn get_unsealed(
+        &self,
+        key: &str,
+        sealed_keys: &SealedKeys,
+    ) -> Result<Option<String>, UnsealError> {
+        self.get(key)
+            .map(|value| sealed_keys.unseal(key, value))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sealed_keys() {
+        let sealed_keys = SealedKeys::default()
+            .seal_prefix("secret:", &EncryptionKey::from_bytes([1; 32]))
+            .seal_prefix("secret:other:", &EncryptionKey::from_bytes([2; 32]));
+        assert!(sealed_keys.is_sealed("secret:token"));
+        assert!(!sealed_keys.is_sealed("public:token"));
+
+        assert_eq!(sealed_keys.seal("public:token", "abc".to_string()), "abc");
+        let sealed_value = sealed_keys.seal("secret:token", "abc".to_string());
+        assert_ne!(sealed_value, "abc");
+        // Nonces are random.
+        assert_ne!(
+            sealed_keys.seal("secret:token", "abc".to_string()),
+            sealed_value
+        );
+        assert_eq!(
+            sealed_keys.unseal("secret:token", &sealed_value).unwrap(),
+            "abc"
+        );
+        // Values are bound to their key.
+        assert!(sealed_keys.unseal("secret:token2", &sealed_value).is_err());
+        assert!(sealed_keys.unseal("secret:token", "zz").is_err());
+
+        // The longest prefix applies.
+        let sealed_value = sealed_keys.seal("secret:other:token", "abc".to_string());
+        let other_sealed_keys =
+            SealedKeys::default().seal_prefix("secret:", &EncryptionKey::from_bytes([1; 32]));
+        assert!(other_sealed_keys
+            .unseal("secret:other:token", &sealed_value)
+            .is_err());
+    }
+
+    #[test]
+    fn test_node_state_sealed_keys() {
+        let sealed_keys =
+            SealedKeys::default().seal_prefix("secret:", &EncryptionKey::from_bytes([1; 32]));
+        let mut node_state = NodeState::default();
+        node_state.set_sealed_keys(Some(sealed_keys.clone().into()));
+        node_state.set("secret:token", "abc");
+        node_state.set_batch([("secret:password", "def"), ("public:name", "ghi")]);
+        assert_ne!(node_state.get("secret:token"), Some("abc"));
+        assert_ne!(node_state.get("secret:password"), Some("def"));
+        assert_eq!(node_state.get("public:name"), Some("ghi"));
+        assert_eq!(
+            node_state
+                .get_unsealed("secret:token", &sealed_keys)
+                .unwrap()
+                .as_deref(),
+            Some("abc")
+        );
+        assert_eq!(
+            node_state
+                .get_unsealed("secret:password", &sealed_keys)
+                .unwrap()
+                .as_deref(),
+            Some("def")
+        );
+        assert_eq!(
+            node_state.get_unsealed("secret:missing", &sealed_keys),
+            Ok(None)
+        );
+
+        // Compare-and-set compares the values in the clear.
+        let error = node_state
+            .compare_and_set("secret:token", Some("xyz"), "jkl")
+            .unwrap_err();
+        assert_eq!(error.current_value(), Some("abc"));
+        node_state
+            .compare_and_set("secret:token", Some("abc"), "jkl")
+            .unwrap();
+        assert_eq!(
+            node_state
+                .get_unsealed("secret:token", &sealed_keys)
+                .unwrap()
+                .as_deref(),
+            Some("jkl")
+        );
+    }
+}
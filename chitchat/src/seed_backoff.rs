@@ -0,0 +1,164 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::time::Instant;
+
+use crate::SeedBackoffConfig;
+
+/// All the seeds have been unreachable for longer than
+/// [`SeedBackoffConfig::unreachable_threshold`]. See
+/// [`crate::ChitchatHandle::seeds_unreachable_watcher`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SeedsUnreachable {
+    pub seed_addrs: HashSet<SocketAddr>,
+    /// Time elapsed since the seeds were first gossiped with in vain, when the event was
+    /// raised.
+    pub unreachable_for: Duration,
+}
+
+/// A seed gossiped with since its last reply.
+#[derive(Debug)]
+struct UnansweredSeed {
+    num_attempts: u32,
+    first_attempt_at: Instant,
+    next_attempt_at: Instant,
+}
+
+/// Delays the gossip with the seeds that do not reply, exponentially.
+#[derive(Debug)]
+pub(crate) struct SeedBackoff {
+    config: SeedBackoffConfig,
+    unanswered_seeds: HashMap<SocketAddr, UnansweredSeed>,
+}
+
+impl SeedBackoff {
+    pub fn new(config: SeedBackoffConfig) -> Self {
+        SeedBackoff {
+            config,
+            unanswered_seeds: HashMap::new(),
+        }
+    }
+
+    /// Returns true if the seed can be gossiped with at `now`.
+    pub fn is_ready(&self, seed_addr: SocketAddr, now: Instant) -> bool {
+        self.unanswered_seeds
+            .get(&seed_addr)
+            .is_none_or(|unanswered_seed| unanswered_seed.next_attempt_at <= now)
+    }
+
+    pub fn record_attempt(&mut self, seed_addr: SocketAddr, now: Instant, rng: &mut impl Rng) {
+        let unanswered_seed = self
+            .unanswered_seeds
+            .entry(seed_addr)
+            .or_insert(UnansweredSeed {
+                num_attempts: 0,
+                first_attempt_at: now,
+                next_attempt_at: now,
+            });
+        unanswered_seed.num_attempts += 1;
+        let delay = self
+            .config
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(unanswered_seed.num_attempts - 1))
+            .min(self.config.max_delay);
+        let jitter = self.config.jitter.clamp(0.0, 1.0);
+        let delay = if jitter == 0.0 {
+            delay
+        } else {
+            delay.mul_f64(1.0 + rng.gen_range(-jitter..=jitter))
+        };
+        unanswered_seed.next_attempt_at = now + delay;
+    }
+
+    /// Resets the backoff of the seed, if `addr` is one.
+    pub fn record_reply(&mut self, addr: SocketAddr) {
+        self.unanswered_seeds.remove(&addr);
+    }
+
+    /// Forgets the addresses that are no longer seeds.
+    pub fn retain_seeds(&mut self, seed_addrs: &HashSet<SocketAddr>) {
+        self.unanswered_seeds
+            .retain(|addr, _| seed_addrs.contains(addr));
+    }
+
+    /// Returns the event to raise if all the seeds have been unreachable for longer than the
+    /// threshold at `now`.
+    pub fn seeds_unreachable(
+        &self,
+        seed_addrs: &HashSet<SocketAddr>,
+        now: Instant,
+    ) -> Option<SeedsUnreachable> {
+        let unreachable_since = seed_addrs
+            .iter()
+            .map(|addr| {
+                let unanswered_seed = self.unanswered_seeds.get(addr)?;
+                Some(unanswered_seed.first_attempt_at)
+            })
+            .collect::<Option<Vec<Instant>>>()?
+            .into_iter()
+            .max()?;
+        let unreachable_for = now.saturating_duration_since(unreachable_since);
+        if unreachable_for < self.config.unreachable_threshold {
+            return None;
+        }
+        Some(SeedsUnreachable {
+            seed_addrs: seed_addrs.clone(),
+            unreachable_for,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn test_seed_backoff() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut seed_backoff = SeedBackoff::new(SeedBackoffConfig {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(3),
+            jitter: 0.0,
+            unreachable_threshold: Duration::from_secs(10),
+        });
+        let seed_addr1: SocketAddr = "127.0.0.1:10001".parse().unwrap();
+        let seed_addr2: SocketAddr = "127.0.0.1:10002".parse().unwrap();
+        let seed_addrs = HashSet::from([seed_addr1, seed_addr2]);
+        let start = Instant::now();
+        assert!(seed_backoff.is_ready(seed_addr1, start));
+
+        seed_backoff.record_attempt(seed_addr1, start, &mut rng);
+        assert!(!seed_backoff.is_ready(seed_addr1, start));
+        assert!(seed_backoff.is_ready(seed_addr1, start + Duration::from_secs(1)));
+        seed_backoff.record_attempt(seed_addr1, start + Duration::from_secs(1), &mut rng);
+        assert!(!seed_backoff.is_ready(seed_addr1, start + Duration::from_secs(2)));
+        assert!(seed_backoff.is_ready(seed_addr1, start + Duration::from_secs(3)));
+        seed_backoff.record_attempt(seed_addr1, start + Duration::from_secs(3), &mut rng);
+        seed_backoff.record_attempt(seed_addr1, start + Duration::from_secs(7), &mut rng);
+        // The delay is capped.
+        assert!(seed_backoff.is_ready(seed_addr1, start + Duration::from_secs(10)));
+
+        // Seed 2 was never gossiped with.
+        let later = start + Duration::from_secs(20);
+        assert!(seed_backoff.seeds_unreachable(&seed_addrs, later).is_none());
+        seed_backoff.record_attempt(seed_addr2, start + Duration::from_secs(5), &mut rng);
+        assert_eq!(
+            seed_backoff.seeds_unreachable(&seed_addrs, later),
+            Some(SeedsUnreachable {
+                seed_addrs: seed_addrs.clone(),
+                unreachable_for: Duration::from_secs(15),
+            })
+        );
+
+        seed_backoff.retain_seeds(&HashSet::from([seed_addr2]));
+        assert!(seed_backoff.is_ready(seed_addr1, start));
+        seed_backoff.record_reply(seed_addr2);
+        assert!(seed_backoff.is_ready(seed_addr2, later));
+        assert!(seed_backoff.seeds_unreachable(&seed_addrs, later).is_none());
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::net::lookup_host;
+use tokio::sync::watch;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+/// Source of seed nodes, e.g. a service registry, a cloud API or a file. See
+/// [`crate::ChitchatConfig::set_seed_provider`].
+///
+/// Seeds are queried upon startup, then every
+/// [`crate::ChitchatConfig::seed_refresh_interval`].
+#[async_trait]
+pub trait SeedProvider: Send + Sync + 'static {
+    /// Returns the current seeds. An empty set keeps the seeds returned last, so that a
+    /// transient failure of the source does not drop them.
+    async fn seeds(&self) -> HashSet<SocketAddr>;
+}
+
+/// Provides the seeds of [`crate::ChitchatConfig::seed_nodes`]: socket addresses, or
+/// `hostname:port`, resolved again at every refresh.
+// The latter is especially important when relying on
+// a headless service in k8s or when using DNS in general.
+//
+// In that case, we do not want to perform the resolution
+// once and forall.
+// We want to periodically retry DNS resolution,
+// in order to avoid having a split cluster.
+pub(crate) struct ConfiguredSeeds {
+    seed_addrs: HashSet<SocketAddr>,
+    /// Addresses each hostname last resolved to.
+    seed_hosts: Mutex<HashMap<String, HashSet<SocketAddr>>>,
+}
+
+impl ConfiguredSeeds {
+    pub fn new(seeds: &[String]) -> Self {
+        let mut seed_addrs = HashSet::new();
+        let mut seed_hosts = HashMap::new();
+        for seed in seeds {
+            if let Ok(seed_addr) = seed.parse() {
+                seed_addrs.insert(seed_addr);
+            } else {
+                seed_hosts.insert(seed.clone(), HashSet::new());
+            }
+        }
+        ConfiguredSeeds {
+            seed_addrs,
+            seed_hosts: Mutex::new(seed_hosts),
+        }
+    }
+}
+
+#[async_trait]
+impl SeedProvider for ConfiguredSeeds {
+    async fn seeds(&self) -> HashSet<SocketAddr> {
+        let seed_hosts: Vec<String> = self.seed_hosts.lock().unwrap().keys().cloned().collect();
+        let mut resolved_seed_hosts = Vec::with_capacity(seed_hosts.len());
+        for seed_host in seed_hosts {
+            if let Some(seed_host_addrs) = resolve_seed_host(&seed_host).await {
+                resolved_seed_hosts.push((seed_host, seed_host_addrs));
+            }
+        }
+        let mut seed_hosts_guard = self.seed_hosts.lock().unwrap();
+        // A failed lookup keeps the addresses of the previous one.
+        seed_hosts_guard.extend(resolved_seed_hosts);
+        let mut seed_addrs = self.seed_addrs.clone();
+        seed_addrs.extend(seed_hosts_guard.values().flatten());
+        seed_addrs
+    }
+}
+
+/// Provides the seeds listed in a file, one socket address or `hostname:port` per line.
+/// Blank lines and lines starting with `#` are ignored.
+///
+/// The file is read again at every refresh, so that seeds can be added to a running cluster
+/// by editing it. A missing or empty file keeps the seeds read last.
+#[derive(Clone, Debug)]
+pub struct SeedFile {
+    path: PathBuf,
+}
+
+impl SeedFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        SeedFile { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl SeedProvider for SeedFile {
+    async fn seeds(&self) -> HashSet<SocketAddr> {
+        let content = match tokio::fs::read_to_string(&self.path).await {
+            Ok(content) => content,
+            Err(error) => {
+                warn!(path = ?self.path, error = %error, "failed-to-read-seed-file");
+                return HashSet::new();
+            }
+        };
+        let seeds: Vec<String> = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect();
+        ConfiguredSeeds::new(&seeds).seeds().await
+    }
+}
+
+pub(crate) async fn resolve_seed_host(seed_host: &str) -> Option<HashSet<SocketAddr>> {
+    if let Ok(resolved_seed_addrs) = lookup_host(seed_host).await {
+        let seed_addrs: HashSet<SocketAddr> = resolved_seed_addrs.collect();
+        for seed_addr in &seed_addrs {
+            debug!(seed_host=seed_host, seed_addr=%seed_addr, "seed-addr-from_dns");
+        }
+        Some(seed_addrs)
+    } else {
+        warn!(seed_host=%seed_host, "Failed to lookup host");
+        None
+    }
+}
+
+/// Queries the seed providers, and keeps querying them every `refresh_interval` in the
+/// background for as long as the returned receiver is alive.
+// We run the resolution for the first iteration right away: this makes sure our first gossip
+// round will not be for nothing. The newcomers are supposed to chime in too, so there is no
+// need to refresh the seeds too often.
+pub(crate) async fn spawn_seed_refresh_loop(
+    seed_providers: Vec<Box<dyn SeedProvider>>,
+    refresh_interval: Duration,
+) -> watch::Receiver<HashSet<SocketAddr>> {
+    let mut provided_seeds = Vec::with_capacity(seed_providers.len());
+    for seed_provider in &seed_providers {
+        provided_seeds.push(seed_provider.seeds().await);
+    }
+    let initial_seed_addrs: HashSet<SocketAddr> =
+        provided_seeds.iter().flatten().copied().collect();
+    info!(initial_seed_addrs=?initial_seed_addrs);
+
+    let (seed_addrs_tx, seed_addrs_rx) = watch::channel(initial_seed_addrs.clone());
+    tokio::task::spawn(seed_refresh_loop(
+        seed_providers,
+        provided_seeds,
+        initial_seed_addrs,
+        seed_addrs_tx,
+        refresh_interval,
+    ));
+    seed_addrs_rx
+}
+
+async fn seed_refresh_loop(
+    seed_providers: Vec<Box<dyn SeedProvider>>,
+    mut provided_seeds: Vec<HashSet<SocketAddr>>,
+    mut last_seed_addrs: HashSet<SocketAddr>,
+    seed_addrs_tx: watch::Sender<HashSet<SocketAddr>>,
+    refresh_interval: Duration,
+) {
+    let mut interval = time::interval(refresh_interval);
+    // We actually do not want to run the polling loop right away,
+    // hence this tick.
+    interval.tick().await;
+    while seed_addrs_tx.receiver_count() > 0 {
+        interval.tick().await;
+        for (seed_provider, seeds) in seed_providers.iter().zip(provided_seeds.iter_mut()) {
+            let new_seeds = seed_provider.seeds().await;
+            if !new_seeds.is_empty() {
+                *seeds = new_seeds;
+            }
+        }
+        let seed_addrs: HashSet<SocketAddr> = provided_seeds.iter().flatten().copied().collect();
+        // Only changes wake up the receivers.
+        if seed_addrs == last_seed_addrs {
+            continue;
+        }
+        info!(seed_addrs=?seed_addrs, "seed-addrs-changed");
+        last_seed_addrs = seed_addrs.clone();
+        if seed_addrs_tx.send(seed_addrs).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_configured_seeds() {
+        let seeds = vec!["127.0.0.1:10001".to_string(), "localhost:10002".to_string()];
+        let mut seed_addrs_rx = spawn_seed_refresh_loop(
+            vec![Box::new(ConfiguredSeeds::new(&seeds))],
+            Duration::from_millis(10),
+        )
+        .await;
+        let seed_addrs = seed_addrs_rx.borrow_and_update().clone();
+        assert!(seed_addrs.contains(&"127.0.0.1:10001".parse().unwrap()));
+        assert!(seed_addrs
+            .iter()
+            .any(|seed_addr| seed_addr.port() == 10_002));
+
+        // The seeds are resolved again, but receivers are only notified of changes.
+        let changed = tokio::time::timeout(Duration::from_millis(50), seed_addrs_rx.changed());
+        assert!(changed.await.is_err());
+        assert_eq!(*seed_addrs_rx.borrow(), seed_addrs);
+    }
+
+    struct SeedsForTest(Arc<Mutex<HashSet<SocketAddr>>>);
+
+    #[async_trait]
+    impl SeedProvider for SeedsForTest {
+        async fn seeds(&self) -> HashSet<SocketAddr> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_seed_provider() {
+        let seed_addr1: SocketAddr = "127.0.0.1:10001".parse().unwrap();
+        let seed_addr2: SocketAddr = "127.0.0.1:10002".parse().unwrap();
+        let provided_seeds = Arc::new(Mutex::new(HashSet::from([seed_addr1])));
+        let mut seed_addrs_rx = spawn_seed_refresh_loop(
+            vec![
+                Box::new(SeedsForTest(provided_seeds.clone())),
+                Box::new(ConfiguredSeeds::new(&["127.0.0.1:10003".to_string()])),
+            ],
+            Duration::from_millis(10),
+        )
+        .await;
+        assert_eq!(seed_addrs_rx.borrow_and_update().len(), 2);
+
+        *provided_seeds.lock().unwrap() = HashSet::from([seed_addr2]);
+        seed_addrs_rx.changed().await.unwrap();
+        assert!(seed_addrs_rx.borrow_and_update().contains(&seed_addr2));
+        assert!(!seed_addrs_rx.borrow().contains(&seed_addr1));
+
+        // A failing provider keeps its last seeds.
+        provided_seeds.lock().unwrap().clear();
+        let changed = tokio::time::timeout(Duration::from_millis(50), seed_addrs_rx.changed());
+        assert!(changed.await.is_err());
+        assert!(seed_addrs_rx.borrow().contains(&seed_addr2));
+    }
+
+    #[tokio::test]
+    async fn test_seed_file() {
+        let path = std::env::temp_dir().join("chitchat-test-seed-file");
+        std::fs::write(&path, "# seeds\n127.0.0.1:10001\n\n  localhost:10002\n").unwrap();
+        let mut seed_addrs_rx = spawn_seed_refresh_loop(
+            vec![Box::new(SeedFile::new(&path))],
+            Duration::from_millis(10),
+        )
+        .await;
+        let seed_addrs = seed_addrs_rx.borrow_and_update().clone();
+        assert!(seed_addrs.contains(&"127.0.0.1:10001".parse().unwrap()));
+        assert!(seed_addrs
+            .iter()
+            .any(|seed_addr| seed_addr.port() == 10_002));
+
+        std::fs::write(&path, "127.0.0.1:10003\n").unwrap();
+        seed_addrs_rx.changed().await.unwrap();
+        assert_eq!(
+            *seed_addrs_rx.borrow_and_update(),
+            HashSet::from(["127.0.0.1:10003".parse().unwrap()])
+        );
+
+        // Removing the file keeps the seeds read last.
+        std::fs::remove_file(&path).unwrap();
+        let changed = tokio::time::timeout(Duration::from_millis(50), seed_addrs_rx.changed());
+        assert!(changed.await.is_err());
+        assert_eq!(seed_addrs_rx.borrow().len(), 1);
+    }
+}
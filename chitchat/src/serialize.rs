@@ -1,8 +1,10 @@
 use std::io::BufRead;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 
 use anyhow::{bail, Context};
 
+use crate::key_interner::intern_key;
 use crate::NodeId;
 
 impl Serializable for u16 {
@@ -128,6 +130,9 @@ impl Serializable for String {
 
     fn deserialize(buf: &mut &[u8]) -> anyhow::Result<Self> {
         let len: usize = u16::deserialize(buf)? as usize;
+        if buf.len() < len {
+            bail!("Buffer too short");
+        }
         let s = std::str::from_utf8(&buf[..len])?.to_string();
         buf.consume(len);
         Ok(s)
@@ -177,20 +182,116 @@ impl Serializable for SocketAddr {
 impl Serializable for NodeId {
     fn serialize(&self, buf: &mut Vec<u8>) {
         self.id.serialize(buf);
+        self.generation.serialize(buf);
         self.gossip_public_address.serialize(buf)
     }
 
     fn deserialize(buf: &mut &[u8]) -> anyhow::Result<Self> {
         let id = String::deserialize(buf)?;
+        let generation = u64::deserialize(buf)?;
         let gossip_public_address = SocketAddr::deserialize(buf)?;
         Ok(NodeId {
             id,
+            generation,
             gossip_public_address,
         })
     }
 
     fn serialized_len(&self) -> usize {
-        self.id.serialized_len() + self.gossip_public_address.serialized_len()
+        self.id.serialized_len()
+            + self.generation.serialized_len()
+            + self.gossip_public_address.serialized_len()
+    }
+}
+
+/// Keys are serialized as strings, and deserialized interned.
+impl Serializable for Arc<str> {
+    fn serialize(&self, buf: &mut Vec<u8>) {
+        (self.len() as u16).serialize(buf);
+        buf.extend(self.as_bytes())
+    }
+
+    fn deserialize(buf: &mut &[u8]) -> anyhow::Result<Self> {
+        let len: usize = u16::deserialize(buf)? as usize;
+        if buf.len() < len {
+            bail!("Buffer too short");
+        }
+        let key = intern_key(std::str::from_utf8(&buf[..len])?);
+        buf.consume(len);
+        Ok(key)
+    }
+
+    fn serialized_len(&self) -> usize {
+        2 + self.len()
+    }
+}
+
+/// Deserializes an interned key, failing before reading it if it is longer than `max_len`.
+pub(crate) fn deserialize_bounded_key(buf: &mut &[u8], max_len: usize) -> anyhow::Result<Arc<str>> {
+    let len = u16::deserialize(&mut &buf[..])? as usize;
+    if len > max_len {
+        bail!("Key of {len} bytes exceeds the limit of {max_len} bytes.");
+    }
+    Arc::<str>::deserialize(buf)
+}
+
+/// Deserializes a string, failing before reading it if it is longer than `max_len`.
+pub(crate) fn deserialize_bounded_string(
+    buf: &mut &[u8],
+    max_len: usize,
+) -> anyhow::Result<String> {
+    let len = u16::deserialize(&mut &buf[..])? as usize;
+    if len > max_len {
+        bail!("String of {len} bytes exceeds the limit of {max_len} bytes.");
+    }
+    String::deserialize(buf)
+}
+
+/// Smallest serialized node ID: an empty ID, a generation, and an IPv4 address.
+const MIN_SERIALIZED_NODE_ID_LEN: usize = 2 + 8 + 7;
+
+/// Smallest serialized key-value: an empty key and value, a version, and a tombstone flag.
+const MIN_SERIALIZED_KEY_VALUE_LEN: usize = 2 + 2 + 8 + 1;
+
+/// Bounds enforced while parsing deltas and digests.
+///
+/// The default limits are the bounds of the format itself, enforced on the full sync frames.
+/// The datagrams received from peers are parsed with the `parse_limits` of the
+/// [`crate::ChitchatConfig`], which default to [`ParseLimits::for_payload_size`] of a datagram.
+/// Tighter limits can also be set, e.g. to fuzz the parsers through `chitchat::fuzz` with smaller
+/// inputs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ParseLimits {
+    pub max_nodes: usize,
+    pub max_key_values_per_node: usize,
+    pub max_key_len: usize,
+    pub max_value_len: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_nodes: u16::MAX as usize,
+            max_key_values_per_node: u16::MAX as usize,
+            max_key_len: u16::MAX as usize,
+            max_value_len: u16::MAX as usize,
+        }
+    }
+}
+
+impl ParseLimits {
+    /// Returns the limits that any payload of at most `max_payload_size` bytes satisfies, so
+    /// that a payload announcing more nodes, key-values, or bytes than it can hold is rejected
+    /// before being parsed further.
+    pub fn for_payload_size(max_payload_size: usize) -> Self {
+        let format_limits = ParseLimits::default();
+        ParseLimits {
+            max_nodes: (max_payload_size / MIN_SERIALIZED_NODE_ID_LEN).min(format_limits.max_nodes),
+            max_key_values_per_node: (max_payload_size / MIN_SERIALIZED_KEY_VALUE_LEN)
+                .min(format_limits.max_key_values_per_node),
+            max_key_len: max_payload_size.min(format_limits.max_key_len),
+            max_value_len: max_payload_size.min(format_limits.max_value_len),
+        }
     }
 }
 
@@ -240,4 +341,44 @@ mod tests {
     fn test_serialize_bool() {
         test_serdeser_aux(&true, 1);
     }
+
+    #[test]
+    fn test_deserialize_truncated_string() {
+        let buf = "chitchat".to_string().serialize_to_vec();
+        assert!(String::deserialize(&mut &buf[..buf.len() - 1]).is_err());
+        assert!(deserialize_bounded_string(&mut &buf[..], 7).is_err());
+        assert_eq!(
+            deserialize_bounded_string(&mut &buf[..], 8).unwrap(),
+            "chitchat"
+        );
+    }
+
+    #[test]
+    fn test_parse_limits_for_payload_size() {
+        let smallest_node_id = NodeId {
+            id: String::new(),
+            generation: 0,
+            gossip_public_address: ([0, 0, 0, 0], 0).into(),
+        };
+        assert_eq!(
+            smallest_node_id.serialized_len(),
+            MIN_SERIALIZED_NODE_ID_LEN
+        );
+        let smallest_key_value_len =
+            String::new().serialized_len() * 2 + 0u64.serialized_len() + false.serialized_len();
+        assert_eq!(smallest_key_value_len, MIN_SERIALIZED_KEY_VALUE_LEN);
+
+        let limits = ParseLimits::for_payload_size(1_000);
+        assert_eq!(limits.max_nodes, 1_000 / MIN_SERIALIZED_NODE_ID_LEN);
+        assert_eq!(
+            limits.max_key_values_per_node,
+            1_000 / MIN_SERIALIZED_KEY_VALUE_LEN
+        );
+        assert_eq!(limits.max_key_len, 1_000);
+        assert_eq!(limits.max_value_len, 1_000);
+        assert_eq!(
+            ParseLimits::for_payload_size(usize::MAX),
+            ParseLimits::default()
+        );
+    }
 }
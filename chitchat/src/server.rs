@@ -1,22 +1,37 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::SocketAddr;
+use std::ops::Deref;
 use std::sync::Arc;
 use std::time::Duration;
 
+use anyhow::Context;
 use rand::prelude::*;
-use tokio::net::lookup_host;
+use tokio::net::TcpListener;
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
-use tokio::sync::{watch, Mutex};
+use tokio::sync::{watch, Mutex, OwnedMutexGuard, Semaphore};
 use tokio::task::JoinHandle;
 use tokio::time;
+use tokio_stream::wrappers::WatchStream;
 use tracing::{debug, error, info, warn};
 
+use crate::authentication::AUTHENTICATION_OVERHEAD;
+use crate::full_sync::{accept_opt, full_sync, serve_full_sync, MAX_CONCURRENT_FULL_SYNCS};
+use crate::gossip_scheduler::GossipScheduler;
+#[cfg(feature = "http-debug")]
+use crate::http_debug;
 use crate::message::ChitchatMessage;
+use crate::seed_backoff::{SeedBackoff, SeedsUnreachable};
+use crate::seed_provider::{spawn_seed_refresh_loop, ConfiguredSeeds, SeedProvider};
+use crate::serialize::Serializable;
+use crate::state::{ClusterState, ClusterStateSnapshot, NodeState};
 use crate::transport::{Socket, Transport};
-use crate::{Chitchat, ChitchatConfig, NodeId};
-
-/// Number of nodes picked for random gossip.
-const GOSSIP_COUNT: usize = 3;
+#[cfg(feature = "json")]
+use crate::Checkpoint;
+use crate::{
+    Chitchat, ChitchatConfig, ClusterKey, FailureDetectorConfig, GossipStats, NodeId,
+    ShutdownPhase, WriteAdmission, WriteAfterShutdownError, WriteAfterShutdownPolicy,
+    MAX_UDP_DATAGRAM_PAYLOAD_SIZE,
+};
 
 /// UDP Chitchat server handler.
 ///
@@ -26,131 +41,123 @@ pub struct ChitchatHandle {
     node_id: NodeId,
     command_tx: UnboundedSender<Command>,
     chitchat: Arc<Mutex<Chitchat>>,
+    seeds_unreachable_rx: watch::Receiver<Option<SeedsUnreachable>>,
     join_handle: JoinHandle<Result<(), anyhow::Error>>,
+    #[cfg(feature = "http-debug")]
+    debug_http_join_handle_opt: Option<JoinHandle<()>>,
 }
 
-const DNS_POLLING_DURATION: Duration = Duration::from_secs(60);
-
-async fn dns_refresh_loop(
-    seed_hosts_requiring_dns: HashSet<String>,
-    seed_addrs_not_requiring_resolution: HashSet<SocketAddr>,
-    seed_addrs_tx: watch::Sender<HashSet<SocketAddr>>,
-) {
-    let mut interval = time::interval(DNS_POLLING_DURATION);
-    // We actually do not want to run the polling loop right away,
-    // hence this tick.
-    interval.tick().await;
-    while seed_addrs_tx.receiver_count() > 0 {
-        interval.tick().await;
-        let mut seed_addrs = seed_addrs_not_requiring_resolution.clone();
-        for seed_host in &seed_hosts_requiring_dns {
-            resolve_seed_host(seed_host, &mut seed_addrs).await;
-        }
-        if seed_addrs_tx.send(seed_addrs).is_err() {
-            return;
-        }
-    }
-}
-
-async fn resolve_seed_host(seed_host: &str, seed_addrs: &mut HashSet<SocketAddr>) {
-    if let Ok(resolved_seed_addrs) = lookup_host(seed_host).await {
-        for seed_addr in resolved_seed_addrs {
-            if seed_addrs.contains(&seed_addr) {
-                continue;
-            }
-            debug!(seed_host=seed_host, seed_addr=%seed_addr, "seed-addr-from_dns");
-            seed_addrs.insert(seed_addr);
-        }
-    } else {
-        warn!(seed_host=%seed_host, "Failed to lookup host");
-    }
-}
-
-// The seed nodes address can be string representing a
-// socket addr directly, or hostnames:port.
-//
-// The latter is especially important when relying on
-// a headless service in k8s or when using DNS in general.
-//
-// In that case, we do not want to perform the resolution
-// once and forall.
-// We want to periodically retry DNS resolution,
-// in order to avoid having a split cluster.
-//
-// The newcomers are supposed to chime in too,
-// so there is no need to refresh it too often,
-// especially if it is not empty.
-async fn spawn_dns_refresh_loop(seeds: &[String]) -> watch::Receiver<HashSet<SocketAddr>> {
-    let mut seed_addrs_not_requiring_resolution: HashSet<SocketAddr> = Default::default();
-    let mut first_round_seed_resolution: HashSet<SocketAddr> = Default::default();
-    let mut seed_requiring_dns: HashSet<String> = Default::default();
-    for seed in seeds {
-        if let Ok(seed_addr) = seed.parse() {
-            seed_addrs_not_requiring_resolution.insert(seed_addr);
-        } else {
-            seed_requiring_dns.insert(seed.clone());
-            // We run DNS resolution for the first iteration too.
-            // It will be run in the DNS polling loop too, but running
-            // it for the first iteration makes sure our first gossip
-            // round will not be for nothing.
-            resolve_seed_host(seed, &mut first_round_seed_resolution).await;
-        }
-    }
-
-    let initial_seed_addrs: HashSet<SocketAddr> = seed_addrs_not_requiring_resolution
-        .union(&first_round_seed_resolution)
-        .cloned()
-        .collect();
-
-    info!(initial_seed_addrs=?initial_seed_addrs);
-
-    let (seed_addrs_tx, seed_addrs_rx) = watch::channel(initial_seed_addrs);
-    if !seed_requiring_dns.is_empty() {
-        tokio::task::spawn(dns_refresh_loop(
-            seed_requiring_dns,
-            seed_addrs_not_requiring_resolution,
-            seed_addrs_tx,
-        ));
-    }
-    seed_addrs_rx
-}
+/// Maximum duration of the final gossip round of the queue-and-flush shutdown policy.
+const MAX_SHUTDOWN_FLUSH_DURATION: Duration = Duration::from_secs(1);
 
 /// Launch a new server.
 ///
 /// This will start the Chitchat server as a new Tokio background task.
 pub async fn spawn_chitchat(
-    config: ChitchatConfig,
+    mut config: ChitchatConfig,
     initial_key_values: Vec<(String, String)>,
     transport: &dyn Transport,
 ) -> anyhow::Result<ChitchatHandle> {
+    config.failure_detector_config.validate()?;
     let (command_tx, command_rx) = mpsc::unbounded_channel();
 
+    let mut seed_providers: Vec<Box<dyn SeedProvider>> =
+        vec![Box::new(ConfiguredSeeds::new(&config.seed_nodes))];
+    seed_providers.extend(config.seed_provider.take());
     let seed_addrs: watch::Receiver<HashSet<SocketAddr>> =
-        spawn_dns_refresh_loop(&config.seed_nodes).await;
+        spawn_seed_refresh_loop(seed_providers, config.seed_refresh_interval).await;
 
     let socket = transport.open(config.listen_addr).await?;
+    let full_sync_listener = if config.full_sync_interval.is_some() {
+        let listener = TcpListener::bind(config.listen_addr)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to bind to {}/TCP for full sync.",
+                    config.listen_addr
+                )
+            })?;
+        Some(listener)
+    } else {
+        None
+    };
+    #[cfg(feature = "http-debug")]
+    let debug_http_listener_opt = config
+        .debug_http_listen_addr
+        .map(http_debug::bind_debug_http_listener)
+        .transpose()?;
 
     let node_id = config.node_id.clone();
 
-    let chitchat = Chitchat::with_node_id_and_seeds(config, seed_addrs, initial_key_values);
+    #[allow(unused_mut)]
+    let mut chitchat = Chitchat::with_node_id_and_seeds(config, seed_addrs, initial_key_values);
+    #[cfg(feature = "json")]
+    restore_checkpoint(&mut chitchat);
+    // The debug HTTP endpoint serves the state from the published snapshot, without locking
+    // the chitchat instance.
+    #[cfg(feature = "http-debug")]
+    let debug_http_listener_opt =
+        debug_http_listener_opt.map(|listener| (listener, chitchat.cluster_state_watch()));
     let chitchat_arc = Arc::new(Mutex::new(chitchat));
     let chitchat_arc_clone = chitchat_arc.clone();
+    let (seeds_unreachable_tx, seeds_unreachable_rx) = watch::channel(None);
+    #[cfg(feature = "http-debug")]
+    let debug_http_join_handle_opt = debug_http_listener_opt
+        .map(|(listener, cluster_state_watch)| {
+            http_debug::spawn_debug_http_server(chitchat_arc.clone(), cluster_state_watch, listener)
+        })
+        .transpose()?;
 
     let join_handle = tokio::spawn(async move {
-        Server::new(command_rx, chitchat_arc_clone, socket)
-            .await
-            .run()
-            .await
+        Server::new(
+            command_rx,
+            chitchat_arc_clone,
+            socket,
+            full_sync_listener,
+            seeds_unreachable_tx,
+        )
+        .await
+        .run()
+        .await
     });
 
     Ok(ChitchatHandle {
         node_id,
         command_tx,
         chitchat: chitchat_arc,
+        seeds_unreachable_rx,
         join_handle,
+        #[cfg(feature = "http-debug")]
+        debug_http_join_handle_opt,
     })
 }
 
+/// Restores the checkpoint of the node, and its peer cache, if persistence is enabled and a
+/// checkpoint exists.
+#[cfg(feature = "json")]
+fn restore_checkpoint(chitchat: &mut Chitchat) {
+    let Some(persistence_config) = chitchat.config.persistence.clone() else {
+        return;
+    };
+    let path = &persistence_config.path;
+    match Checkpoint::load_with_config(&persistence_config) {
+        Ok(Some(mut checkpoint)) => {
+            let peer_cache_opt = checkpoint.peer_cache.take();
+            if !chitchat.restore_checkpoint(checkpoint) {
+                return;
+            }
+            info!(path = ?path, "restored-checkpoint");
+            if let Some(peer_cache) = peer_cache_opt {
+                chitchat.restore_peer_cache(peer_cache, persistence_config.peer_cache_max_age);
+            }
+        }
+        Ok(None) => {}
+        Err(error) => {
+            warn!(path = ?path, error = %error, "failed-to-load-checkpoint");
+        }
+    }
+}
+
 impl ChitchatHandle {
     pub fn node_id(&self) -> &NodeId {
         &self.node_id
@@ -160,17 +167,168 @@ impl ChitchatHandle {
         self.chitchat.clone()
     }
 
+    /// Returns a read guard over the cluster state.
+    ///
+    /// Contrary to [`Chitchat::state_snapshot`], nothing gets cloned. The guard holds the
+    /// chitchat lock, which blocks gossip: it should be released promptly.
+    pub async fn state_read(&self) -> impl Deref<Target = ClusterState> {
+        let chitchat_guard = self.chitchat.clone().lock_owned().await;
+        OwnedMutexGuard::map(chitchat_guard, |chitchat| &mut chitchat.cluster_state)
+    }
+
+    /// Returns a receiver of the snapshot of the cluster state, which can be read without
+    /// taking the chitchat lock. See [`Chitchat::cluster_state_watch`].
+    ///
+    /// Writes made through [`ChitchatHandle::with_chitchat`] and [`ChitchatWriter::try_set`] are
+    /// published right away.
+    pub async fn cluster_state_watch(&self) -> watch::Receiver<Arc<ClusterStateSnapshot>> {
+        self.chitchat.lock().await.cluster_state_watch()
+    }
+
     /// Call a function with mutable access to the [`Chitchat`].
     pub async fn with_chitchat<F, T>(&self, mut fun: F) -> T
     where F: FnMut(&mut Chitchat) -> T {
         let mut chitchat = self.chitchat.lock().await;
-        fun(&mut chitchat)
+        let result = fun(&mut chitchat);
+        chitchat.publish_cluster_state();
+        result
+    }
+
+    /// Waits until at least `num_members` nodes, the self node included, are live, e.g. to gate
+    /// the readiness of a service on the cluster actually forming. Fails after `timeout`.
+    pub async fn wait_for_members(
+        &self,
+        num_members: usize,
+        timeout: Duration,
+    ) -> anyhow::Result<()> {
+        self.wait_for_members_matching(num_members, timeout, |_| true)
+            .await
+    }
+
+    /// Same as [`ChitchatHandle::wait_for_members`], only counting the nodes whose state
+    /// matches `predicate`, e.g. nodes advertising a given role.
+    pub async fn wait_for_members_matching(
+        &self,
+        num_members: usize,
+        timeout: Duration,
+        predicate: impl Fn(&NodeState) -> bool,
+    ) -> anyhow::Result<()> {
+        let mut live_nodes_watch = self.chitchat.lock().await.live_nodes_watch();
+        let mut num_matching_members = 0;
+        let wait = async {
+            loop {
+                let chitchat_guard = self.chitchat.lock().await;
+                let mut members: HashSet<&NodeId> = chitchat_guard.live_nodes().collect();
+                members.insert(chitchat_guard.self_node_id());
+                num_matching_members = members
+                    .into_iter()
+                    .filter(|node_id| chitchat_guard.node_state(node_id).is_some_and(&predicate))
+                    .count();
+                let gossip_interval = chitchat_guard.config.gossip_interval;
+                drop(chitchat_guard);
+                if num_matching_members >= num_members {
+                    return;
+                }
+                // The states of the nodes may come to match without the live nodes changing.
+                let _ = time::timeout(gossip_interval, live_nodes_watch.changed()).await;
+            }
+        };
+        if time::timeout(timeout, wait).await.is_err() {
+            anyhow::bail!(
+                "Timed out waiting for {num_members} members, {num_matching_members} found."
+            );
+        }
+        Ok(())
+    }
+
+    /// Stops applying the deltas received from peers for at most `max_duration`, so that the
+    /// cluster state stays stable during a read-compute-write sequence.
+    ///
+    /// See [`Chitchat::freeze_applies`].
+    pub async fn freeze_applies(&self, max_duration: Duration) {
+        self.chitchat.lock().await.freeze_applies(max_duration);
+    }
+
+    /// Atomically applies the deltas buffered since [`ChitchatHandle::freeze_applies`].
+    pub async fn unfreeze(&self) {
+        self.chitchat.lock().await.unfreeze_applies();
+    }
+
+    /// Changes the parameters of the failure detector, e.g. to tune its sensitivity during an
+    /// incident without restarting the node.
+    ///
+    /// See [`Chitchat::set_failure_detector_config`].
+    pub async fn set_failure_detector_config(
+        &self,
+        failure_detector_config: FailureDetectorConfig,
+    ) -> anyhow::Result<()> {
+        self.chitchat
+            .lock()
+            .await
+            .set_failure_detector_config(failure_detector_config)
+    }
+
+    /// Signs the messages sent with `cluster_key` from now on. See
+    /// [`Chitchat::rotate_cluster_key`].
+    pub async fn rotate_cluster_key(&self, cluster_key: ClusterKey) {
+        self.chitchat.lock().await.rotate_cluster_key(cluster_key);
+    }
+
+    /// Accepts the messages signed with `cluster_key`. See [`Chitchat::accept_cluster_key`].
+    pub async fn accept_cluster_key(&self, cluster_key: ClusterKey) {
+        self.chitchat.lock().await.accept_cluster_key(cluster_key);
+    }
+
+    /// Stops accepting the messages signed with keys other than the cluster key. See
+    /// [`Chitchat::retire_cluster_keys`].
+    pub async fn retire_cluster_keys(&self) {
+        self.chitchat.lock().await.retire_cluster_keys();
+    }
+
+    /// See [`Chitchat::gossip_stats`].
+    pub async fn gossip_stats(&self) -> GossipStats {
+        self.chitchat.lock().await.gossip_stats()
+    }
+
+    /// Returns the current phi value of each peer. See [`Chitchat::peer_phis`].
+    pub async fn peer_phis(&self) -> BTreeMap<NodeId, f64> {
+        self.chitchat.lock().await.peer_phis()
+    }
+
+    /// Returns a watch stream yielding `Some` once all the seeds have been unreachable for
+    /// longer than [`crate::SeedBackoffConfig::unreachable_threshold`], and `None` again as
+    /// soon as one of them replies.
+    pub fn seeds_unreachable_watcher(&self) -> WatchStream<Option<SeedsUnreachable>> {
+        WatchStream::new(self.seeds_unreachable_rx.clone())
+    }
+
+    /// Returns a writer of key-values on the self node, subject to the configured
+    /// [`WriteAfterShutdownPolicy`].
+    pub fn writer(&self) -> ChitchatWriter {
+        ChitchatWriter {
+            chitchat: self.chitchat.clone(),
+        }
     }
 
     /// Shut the server down.
     pub async fn shutdown(self) -> Result<(), anyhow::Error> {
+        // Writes issued from now on are subject to the write after shutdown policy.
+        self.chitchat
+            .lock()
+            .await
+            .set_shutdown_phase(ShutdownPhase::ShuttingDown);
         let _ = self.command_tx.send(Command::Shutdown);
-        self.join_handle.await?
+        #[cfg(feature = "http-debug")]
+        if let Some(debug_http_join_handle) = &self.debug_http_join_handle_opt {
+            debug_http_join_handle.abort();
+        }
+        let result = self.join_handle.await?;
+        // The server may have stopped on an error: writers must not wait forever.
+        self.chitchat
+            .lock()
+            .await
+            .set_shutdown_phase(ShutdownPhase::ShutDown);
+        result
     }
 
     /// Perform a Chitchat "handshake" with another UDP server.
@@ -180,33 +338,119 @@ impl ChitchatHandle {
     }
 }
 
+/// Sets key-values on the self node, unlike [`Chitchat::self_node_state`] writes, which
+/// are never rejected, even once the server is shut down and they will never be gossiped.
+#[derive(Clone)]
+pub struct ChitchatWriter {
+    chitchat: Arc<Mutex<Chitchat>>,
+}
+
+impl ChitchatWriter {
+    /// Sets a key-value on the self node.
+    ///
+    /// Once shutdown began, the write is handled according to the configured
+    /// [`WriteAfterShutdownPolicy`]. With [`WriteAfterShutdownPolicy::Block`], this waits for
+    /// shutdown to complete before returning an error.
+    pub async fn try_set<K: ToString, V: ToString>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Result<(), WriteAfterShutdownError> {
+        let mut shutdown_phase_rx = {
+            let mut chitchat_guard = self.chitchat.lock().await;
+            match chitchat_guard.write_admission() {
+                WriteAdmission::Accept => {
+                    chitchat_guard.self_node_state().set(key, value);
+                    chitchat_guard.publish_cluster_state();
+                    return Ok(());
+                }
+                WriteAdmission::Reject => return Err(reject_write(key)),
+                WriteAdmission::WaitForShutdown => chitchat_guard.shutdown_phase_watcher(),
+            }
+        };
+        let _ = shutdown_phase_rx
+            .wait_for(|shutdown_phase| *shutdown_phase == ShutdownPhase::ShutDown)
+            .await;
+        Err(reject_write(key))
+    }
+}
+
+fn reject_write<K: ToString>(key: K) -> WriteAfterShutdownError {
+    let key = key.to_string();
+    warn!(key = %key, "rejecting-write-after-shutdown");
+    WriteAfterShutdownError { key }
+}
+
 /// UDP server for Chitchat communication.
 struct Server {
     command_rx: UnboundedReceiver<Command>,
     chitchat: Arc<Mutex<Chitchat>>,
     transport: Box<dyn Socket>,
+    full_sync_listener: Option<TcpListener>,
+    /// Bounds the number of full syncs served at once.
+    full_sync_permits: Arc<Semaphore>,
     rng: SmallRng,
+    num_gossip_rounds: u64,
+    /// Round at which we last gossiped with each peer.
+    last_gossip_rounds: HashMap<SocketAddr, u64>,
+    seed_backoff: SeedBackoff,
+    seeds_unreachable_tx: watch::Sender<Option<SeedsUnreachable>>,
+    /// Whether the transport checks the receive rate limit before deserializing the payloads.
+    is_rate_limited_by_transport: bool,
 }
 
 impl Server {
     async fn new(
         command_rx: UnboundedReceiver<Command>,
         chitchat: Arc<Mutex<Chitchat>>,
-        transport: Box<dyn Socket>,
+        mut transport: Box<dyn Socket>,
+        full_sync_listener: Option<TcpListener>,
+        seeds_unreachable_tx: watch::Sender<Option<SeedsUnreachable>>,
     ) -> Self {
         let rng = SmallRng::from_rng(thread_rng()).expect("Failed to seed random generator");
+        let chitchat_guard = chitchat.lock().await;
+        let seed_backoff = SeedBackoff::new(chitchat_guard.config.seed_backoff.clone());
+        let is_rate_limited_by_transport = chitchat_guard
+            .config
+            .receive_rate_limit
+            .is_some_and(|rate_limit| transport.set_receive_rate_limit(rate_limit));
+        transport.set_parse_limits(chitchat_guard.config.datagram_parse_limits());
+        drop(chitchat_guard);
         Self {
             chitchat,
             command_rx,
             transport,
+            full_sync_listener,
+            full_sync_permits: Arc::new(Semaphore::new(MAX_CONCURRENT_FULL_SYNCS)),
             rng,
+            num_gossip_rounds: 0,
+            last_gossip_rounds: HashMap::new(),
+            seed_backoff,
+            seeds_unreachable_tx,
+            is_rate_limited_by_transport,
         }
     }
 
     /// Listen for new Chitchat messages.
     async fn run(&mut self) -> anyhow::Result<()> {
-        let gossip_interval = self.chitchat.lock().await.config.gossip_interval;
-        let mut gossip_interval = time::interval(gossip_interval);
+        let chitchat_guard = self.chitchat.lock().await;
+        let mut gossip_scheduler = GossipScheduler::new(
+            chitchat_guard.config.gossip_interval,
+            chitchat_guard.config.gossip_interval_jitter,
+            chitchat_guard.config.adaptive_gossip.clone(),
+        );
+        let mut num_received_key_values = chitchat_guard.num_received_key_values();
+        drop(chitchat_guard);
+        let gossip_sleep = time::sleep(Duration::ZERO);
+        tokio::pin!(gossip_sleep);
+        let mut checkpoint_interval = self.checkpoint_interval().await.map(time::interval);
+        let mut full_sync_interval = self
+            .chitchat
+            .lock()
+            .await
+            .config
+            .full_sync_interval
+            .map(time::interval);
         loop {
             tokio::select! {
                 result = self.transport.recv() => match result {
@@ -215,65 +459,290 @@ impl Server {
                     }
                     Err(err) => return Err(err),
                 },
-                _ = gossip_interval.tick() => {
-                    self.gossip_multiple().await
+                _ = &mut gossip_sleep => {
+                    self.gossip_multiple().await;
+                    let previous_num_received_key_values = num_received_key_values;
+                    num_received_key_values = self.chitchat.lock().await.num_received_key_values();
+                    let delay = gossip_scheduler.next_delay(
+                        num_received_key_values - previous_num_received_key_values,
+                        &mut self.rng,
+                    );
+                    // Like an interval, rounds are scheduled from the previous deadline, regardless
+                    // of the duration of the round.
+                    let next_gossip_at = (gossip_sleep.deadline() + delay).max(time::Instant::now());
+                    gossip_sleep.as_mut().reset(next_gossip_at);
+                },
+                _ = tick_opt(checkpoint_interval.as_mut()) => {
+                    self.checkpoint().await
+                },
+                _ = tick_opt(full_sync_interval.as_mut()) => {
+                    self.full_sync_with_random_peer().await
+                },
+                result = accept_opt(self.full_sync_listener.as_ref()) => match result {
+                    Ok((stream, stream_addr)) => {
+                        let Ok(permit) = self.full_sync_permits.clone().try_acquire_owned() else {
+                            debug!(stream_addr = %stream_addr, "dropping-full-sync-connection-over-limit");
+                            continue;
+                        };
+                        let chitchat = self.chitchat.clone();
+                        tokio::spawn(async move {
+                            serve_full_sync(chitchat, stream, stream_addr).await;
+                            drop(permit);
+                        });
+                    }
+                    Err(error) => warn!(error = %error, "failed-to-accept-full-sync-connection"),
                 },
                 command = self.command_rx.recv() => match command {
                     Some(Command::Gossip(addr)) => {
                         let _ = self.gossip(addr).await;
                     },
-                    Some(Command::Shutdown) | None => break,
+                    Some(Command::Shutdown) | None => {
+                        self.shutdown().await;
+                        break;
+                    }
                 }
             }
         }
         Ok(())
     }
 
+    /// Applies the configured [`WriteAfterShutdownPolicy`] to the writes racing shutdown.
+    async fn shutdown(&mut self) {
+        let mut chitchat_guard = self.chitchat.lock().await;
+        chitchat_guard.set_shutdown_phase(ShutdownPhase::ShuttingDown);
+        if chitchat_guard.write_after_shutdown_policy() == WriteAfterShutdownPolicy::QueueAndFlush {
+            let flush_timeout = chitchat_guard
+                .config
+                .gossip_interval
+                .min(MAX_SHUTDOWN_FLUSH_DURATION);
+            drop(chitchat_guard);
+            self.gossip_multiple().await;
+            // Peers only receive our deltas in the ack following their syn ack, so we keep
+            // handling messages for a last gossip interval, at most.
+            let _ = time::timeout(flush_timeout, async {
+                while let Ok((from_addr, message)) = self.transport.recv().await {
+                    let _ = self.handle_message(from_addr, message).await;
+                }
+            })
+            .await;
+            chitchat_guard = self.chitchat.lock().await;
+        }
+        chitchat_guard.set_shutdown_phase(ShutdownPhase::ShutDown);
+        drop(chitchat_guard);
+        self.checkpoint().await;
+    }
+
+    async fn checkpoint_interval(&self) -> Option<Duration> {
+        #[cfg(feature = "json")]
+        if let Some(persistence_config) = &self.chitchat.lock().await.config.persistence {
+            return Some(persistence_config.checkpoint_interval);
+        }
+        None
+    }
+
+    /// Writes a checkpoint of the node, if persistence is enabled.
+    async fn checkpoint(&self) {
+        #[cfg(feature = "json")]
+        {
+            let chitchat_guard = self.chitchat.lock().await;
+            let Some(persistence_config) = chitchat_guard.config.persistence.clone() else {
+                return;
+            };
+            let checkpoint = chitchat_guard.checkpoint(persistence_config.include_cluster_view);
+            drop(chitchat_guard);
+            let result = tokio::task::spawn_blocking(move || {
+                checkpoint.save_with_config(&persistence_config)
+            })
+            .await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(error)) => warn!(error = %error, "failed-to-save-checkpoint"),
+                Err(error) => warn!(error = %error, "failed-to-save-checkpoint"),
+            }
+        }
+    }
+
+    /// Spawns a full sync with a random live peer, if any.
+    async fn full_sync_with_random_peer(&mut self) {
+        let chitchat_guard = self.chitchat.lock().await;
+        let peer_addr_opt = chitchat_guard
+            .live_nodes()
+            .map(|node_id| node_id.gossip_public_address)
+            .choose(&mut self.rng);
+        drop(chitchat_guard);
+        let Some(peer_addr) = peer_addr_opt else {
+            return;
+        };
+        let chitchat = self.chitchat.clone();
+        tokio::spawn(async move {
+            if let Err(error) = full_sync(chitchat, peer_addr).await {
+                warn!(peer_addr = %peer_addr, error = %error, "failed-to-full-sync");
+            }
+        });
+    }
+
     /// Process a single UDP packet.
     async fn handle_message(
         &mut self,
         from_addr: SocketAddr,
         message: ChitchatMessage,
     ) -> anyhow::Result<()> {
+        let mut chitchat_guard = self.chitchat.lock().await;
+        let num_bytes = message.serialized_len();
+        if !self.is_rate_limited_by_transport
+            && !chitchat_guard.admit_received_message(from_addr, num_bytes)
+        {
+            return Ok(());
+        }
+        // Replies leave room for their authentication.
+        let (message, max_payload_size) = if chitchat_guard.config.cluster_key.is_some() {
+            let Some(message) = chitchat_guard.open_sealed_message(from_addr, message) else {
+                return Ok(());
+            };
+            (
+                message,
+                MAX_UDP_DATAGRAM_PAYLOAD_SIZE - AUTHENTICATION_OVERHEAD,
+            )
+        } else {
+            (message, MAX_UDP_DATAGRAM_PAYLOAD_SIZE)
+        };
+        chitchat_guard
+            .gossip_stats
+            .record_received(message.kind(), num_bytes);
+        self.seed_backoff.record_reply(from_addr);
         // Handle gossip from other servers.
-        let response = self.chitchat.lock().await.process_message(message);
+        let response = chitchat_guard.process_message_with_max_payload_size(
+            from_addr,
+            message,
+            max_payload_size,
+        );
+        // Probing a node on behalf of a peer is gossiping with it: the peer gets a response once
+        // the probed node replies.
+        let probe_targets = chitchat_guard.take_probes_to_start();
+        let probe_responses = chitchat_guard.take_probe_responses();
+        drop(chitchat_guard);
         // Send reply if necessary.
         if let Some(message) = response {
-            self.transport.send(from_addr, message).await?;
+            self.send(from_addr, message).await?;
+        }
+        for (requester_addr, probe_response) in probe_responses {
+            self.send(requester_addr, probe_response).await?;
+        }
+        for probe_target in probe_targets {
+            self.gossip(probe_target).await?;
         }
         Ok(())
     }
 
     /// Gossip to multiple randomly chosen nodes.
+    #[cfg_attr(
+        feature = "tracing-spans",
+        tracing::instrument(
+            name = "gossip-round",
+            skip_all,
+            fields(round = self.num_gossip_rounds + 1)
+        )
+    )]
     async fn gossip_multiple(&mut self) {
         // Gossip with live nodes & probabilistically include a random dead node
         let mut chitchat_guard = self.chitchat.lock().await;
+        // Peers of other regions are only eligible once every few rounds.
+        let cross_region_peers_to_skip = chitchat_guard.start_gossip_round();
+        self.num_gossip_rounds += 1;
         let cluster_state = chitchat_guard.cluster_state();
 
         let peer_nodes = cluster_state
             .nodes()
             .filter(|node_id| *node_id != chitchat_guard.self_node_id())
             .map(|node_id| node_id.gossip_public_address)
+            .filter(|addr| !cross_region_peers_to_skip.contains(addr))
             .collect::<HashSet<_>>();
         let live_nodes = chitchat_guard
             .live_nodes()
             .map(|node_id| node_id.gossip_public_address)
+            .filter(|addr| !cross_region_peers_to_skip.contains(addr))
             .collect::<HashSet<_>>();
         let dead_nodes = chitchat_guard
             .dead_nodes()
             .map(|node_id| node_id.gossip_public_address)
             .collect::<HashSet<_>>();
-        let seed_nodes: HashSet<SocketAddr> = chitchat_guard.seed_nodes();
-        let (selected_nodes, random_dead_node_opt, random_seed_node_opt) = select_nodes_for_gossip(
+        let configured_seed_nodes: HashSet<SocketAddr> = chitchat_guard.seed_nodes();
+        let mut seed_nodes = configured_seed_nodes.clone();
+        seed_nodes.extend(chitchat_guard.learned_peer_addrs());
+        seed_nodes.extend(chitchat_guard.restored_peer_addrs());
+        self.seed_backoff.retain_seeds(&seed_nodes);
+        let now = time::Instant::now();
+        seed_nodes.retain(|seed_addr| self.seed_backoff.is_ready(*seed_addr, now));
+        let gossip_fanout = chitchat_guard
+            .config
+            .gossip_fanout
+            .num_peers(cluster_state.nodes().count());
+        let suspect_nodes: Vec<NodeId> = chitchat_guard.suspect_nodes().cloned().collect();
+        let healthy_live_nodes: Vec<SocketAddr> = chitchat_guard
+            .live_nodes()
+            .filter(|node_id| !suspect_nodes.contains(node_id))
+            .map(|node_id| node_id.gossip_public_address)
+            .collect();
+        let indirect_probes = select_indirect_probes(
             &mut self.rng,
-            peer_nodes,
-            live_nodes,
-            dead_nodes,
-            seed_nodes,
+            suspect_nodes,
+            &healthy_live_nodes,
+            chitchat_guard.config.indirect_probe_count,
         );
-
-        chitchat_guard.update_heartbeat();
-        chitchat_guard.gc_keys_marked_for_deletion();
+        self.last_gossip_rounds
+            .retain(|addr, _| peer_nodes.contains(addr) || seed_nodes.contains(addr));
+        let (selected_nodes, random_dead_node_opt, random_seed_node_opt) =
+            if let Some(peer_selection) = &chitchat_guard.config.peer_selection {
+                // On startup, select from cluster nodes since we don't know any live node yet.
+                let candidate_nodes: Vec<&NodeId> = if live_nodes.is_empty() {
+                    cluster_state
+                        .nodes()
+                        .filter(|node_id| *node_id != chitchat_guard.self_node_id())
+                        .collect()
+                } else {
+                    chitchat_guard.live_nodes().collect()
+                };
+                let candidates: Vec<GossipCandidate> = candidate_nodes
+                    .into_iter()
+                    .filter(|node_id| {
+                        !cross_region_peers_to_skip.contains(&node_id.gossip_public_address)
+                    })
+                    .map(|node_id| {
+                        let addr = node_id.gossip_public_address;
+                        let last_gossip_round =
+                            self.last_gossip_rounds.get(&addr).copied().unwrap_or(0);
+                        GossipCandidate {
+                            addr,
+                            num_rounds_since_gossip: self.num_gossip_rounds - last_gossip_round,
+                            is_other_zone: chitchat_guard.is_cross_zone_peer(node_id),
+                        }
+                    })
+                    .collect();
+                select_nodes_with_policy(
+                    &mut self.rng,
+                    candidates,
+                    dead_nodes,
+                    seed_nodes,
+                    gossip_fanout,
+                    peer_selection.seed_probability,
+                )
+            } else {
+                select_nodes_for_gossip(
+                    &mut self.rng,
+                    peer_nodes,
+                    live_nodes,
+                    dead_nodes,
+                    seed_nodes,
+                    gossip_fanout,
+                )
+            };
+
+        chitchat_guard.run_maintenance();
+        chitchat_guard.gossip_stats.num_corrupt_messages = self.transport.num_invalid_payloads();
+        chitchat_guard.num_transport_rate_limited_messages =
+            self.transport.num_rate_limited_payloads();
+        chitchat_guard.num_transport_rate_limited_bytes = self.transport.num_rate_limited_bytes();
+        let cluster_id = chitchat_guard.cluster_id().to_string();
 
         // Drop lock to prevent deadlock in [`UdpSocket::gossip`].
         drop(chitchat_guard);
@@ -293,23 +762,104 @@ impl Server {
         }
 
         if let Some(random_seed_node) = random_seed_node_opt {
+            self.seed_backoff
+                .record_attempt(random_seed_node, now, &mut self.rng);
             let result = self.gossip(random_seed_node).await;
             if result.is_err() {
                 error!(node = ?random_seed_node, "Gossip error with a seed node.")
             }
         }
 
+        for (prober, target) in indirect_probes {
+            let result = self
+                .send(
+                    prober,
+                    ChitchatMessage::ProbeRequest {
+                        cluster_id: cluster_id.clone(),
+                        target,
+                    },
+                )
+                .await;
+            if result.is_err() {
+                error!(node = ?prober, "Probe request error with a live node.")
+            }
+        }
+
+        self.report_seeds_unreachable(&configured_seed_nodes, now);
+
         // Update nodes liveliness
         let mut chitchat_guard = self.chitchat.lock().await;
         chitchat_guard.update_nodes_liveliness();
     }
 
+    fn report_seeds_unreachable(&self, seed_addrs: &HashSet<SocketAddr>, now: time::Instant) {
+        let seeds_unreachable_opt = self.seed_backoff.seeds_unreachable(seed_addrs, now);
+        if seeds_unreachable_opt.is_some() == self.seeds_unreachable_tx.borrow().is_some() {
+            return;
+        }
+        if let Some(seeds_unreachable) = &seeds_unreachable_opt {
+            warn!(
+                seed_addrs = ?seeds_unreachable.seed_addrs,
+                unreachable_for = ?seeds_unreachable.unreachable_for,
+                "all-seeds-unreachable"
+            );
+        } else {
+            info!("seeds-reachable");
+        }
+        self.seeds_unreachable_tx
+            .send_replace(seeds_unreachable_opt);
+    }
+
     /// Gossip to one other UDP server.
+    #[cfg_attr(
+        feature = "tracing-spans",
+        tracing::instrument(name = "gossip", skip_all, fields(peer = %addr))
+    )]
     async fn gossip(&mut self, addr: SocketAddr) -> anyhow::Result<()> {
-        let syn = self.chitchat.lock().await.create_syn_message();
-        self.transport.send(addr, syn).await?;
+        let mut chitchat_guard = self.chitchat.lock().await;
+        if !chitchat_guard.can_gossip_with(addr) {
+            return Ok(());
+        }
+        let syn = chitchat_guard.create_syn_message();
+        chitchat_guard.record_syn_sent(addr);
+        drop(chitchat_guard);
+        self.last_gossip_rounds.insert(addr, self.num_gossip_rounds);
+        self.send(addr, syn).await?;
         Ok(())
     }
+
+    /// Sends `message`, authenticated with the cluster key if one is configured.
+    #[cfg_attr(
+        feature = "tracing-spans",
+        tracing::instrument(
+            name = "send-message",
+            skip_all,
+            fields(peer = %to_addr, message_bytes = message.serialized_len())
+        )
+    )]
+    async fn send(&mut self, to_addr: SocketAddr, message: ChitchatMessage) -> anyhow::Result<()> {
+        let mut chitchat_guard = self.chitchat.lock().await;
+        let message_kind = message.kind();
+        let message = match &chitchat_guard.config.cluster_key {
+            Some(cluster_key) => cluster_key.seal_message(&message),
+            None => message,
+        };
+        chitchat_guard
+            .gossip_stats
+            .record_sent(message_kind, message.serialized_len());
+        drop(chitchat_guard);
+        self.transport.send(to_addr, message).await
+    }
+}
+
+/// Ticks the interval, if any. Never completes otherwise.
+async fn tick_opt(interval_opt: Option<&mut time::Interval>) {
+    match interval_opt {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
 }
 
 #[derive(Debug)]
@@ -318,12 +868,17 @@ enum Command {
     Shutdown,
 }
 
+#[cfg_attr(
+    feature = "tracing-spans",
+    tracing::instrument(name = "select-gossip-peers", skip_all, fields(fanout = gossip_fanout))
+)]
 fn select_nodes_for_gossip<R>(
     rng: &mut R,
     peer_nodes: HashSet<SocketAddr>,
     live_nodes: HashSet<SocketAddr>,
     dead_nodes: HashSet<SocketAddr>,
     seed_nodes: HashSet<SocketAddr>,
+    gossip_fanout: usize,
 ) -> (Vec<SocketAddr>, Option<SocketAddr>, Option<SocketAddr>)
 where
     R: Rng + ?Sized,
@@ -331,7 +886,7 @@ where
     let live_nodes_count = live_nodes.len();
     let dead_nodes_count = dead_nodes.len();
 
-    // Select `GOSSIP_COUNT` number of live nodes.
+    // Select `gossip_fanout` number of live nodes.
     // On startup, select from cluster nodes since we don't know any live node yet.
     let nodes = if live_nodes_count == 0 {
         peer_nodes
@@ -340,7 +895,7 @@ where
     }
     .iter()
     .cloned()
-    .choose_multiple(rng, GOSSIP_COUNT);
+    .choose_multiple(rng, gossip_fanout);
 
     let mut has_gossiped_with_a_seed_node = false;
     for node_id in &nodes {
@@ -367,6 +922,89 @@ where
     (nodes, random_dead_node_opt, random_seed_node_opt)
 }
 
+/// A peer eligible for gossip, as seen by [`select_nodes_with_policy`].
+struct GossipCandidate {
+    addr: SocketAddr,
+    num_rounds_since_gossip: u64,
+    /// True if the peer belongs to another zone than ours.
+    is_other_zone: bool,
+}
+
+/// Same as [`select_nodes_for_gossip`], biased according to a [`crate::PeerSelectionConfig`].
+#[cfg_attr(
+    feature = "tracing-spans",
+    tracing::instrument(name = "select-gossip-peers", skip_all, fields(fanout = gossip_fanout))
+)]
+fn select_nodes_with_policy<R>(
+    rng: &mut R,
+    candidates: Vec<GossipCandidate>,
+    dead_nodes: HashSet<SocketAddr>,
+    seed_nodes: HashSet<SocketAddr>,
+    gossip_fanout: usize,
+    seed_probability: f64,
+) -> (Vec<SocketAddr>, Option<SocketAddr>, Option<SocketAddr>)
+where
+    R: Rng + ?Sized,
+{
+    // Peers we did not gossip with for a while are more likely to be picked.
+    let mut nodes: Vec<SocketAddr> = candidates
+        .choose_multiple_weighted(rng, gossip_fanout, |candidate| {
+            (candidate.num_rounds_since_gossip + 1) as f64
+        })
+        .map(|chosen| chosen.map(|candidate| candidate.addr).collect())
+        .unwrap_or_default();
+
+    let has_other_zone_node = candidates
+        .iter()
+        .any(|candidate| candidate.is_other_zone && nodes.contains(&candidate.addr));
+    if !has_other_zone_node && gossip_fanout > 0 {
+        let other_zone_node_opt = candidates
+            .iter()
+            .filter(|candidate| candidate.is_other_zone)
+            .choose(rng);
+        if let Some(other_zone_node) = other_zone_node_opt {
+            if nodes.len() == gossip_fanout {
+                nodes.pop();
+            }
+            nodes.push(other_zone_node.addr);
+        }
+    }
+
+    let random_dead_node_opt =
+        select_dead_node_to_gossip_with(rng, &dead_nodes, candidates.len(), dead_nodes.len());
+
+    let random_seed_node_opt = if rng.gen_bool(seed_probability.clamp(0.0, 1.0)) {
+        seed_nodes
+            .iter()
+            .filter(|seed_node| !nodes.contains(seed_node))
+            .choose(rng)
+            .copied()
+    } else {
+        None
+    };
+    (nodes, random_dead_node_opt, random_seed_node_opt)
+}
+
+/// Selects, for each suspect node, `indirect_probe_count` random healthy live nodes to probe it
+/// on our behalf.
+fn select_indirect_probes<R>(
+    rng: &mut R,
+    suspect_nodes: Vec<NodeId>,
+    healthy_live_nodes: &[SocketAddr],
+    indirect_probe_count: usize,
+) -> Vec<(SocketAddr, NodeId)>
+where
+    R: Rng + ?Sized,
+{
+    let mut indirect_probes = Vec::new();
+    for suspect_node in suspect_nodes {
+        for prober in healthy_live_nodes.choose_multiple(rng, indirect_probe_count) {
+            indirect_probes.push((*prober, suspect_node.clone()));
+        }
+    }
+    indirect_probes
+}
+
 /// Selects a dead node to gossip with, with some probability.
 fn select_dead_node_to_gossip_with<R>(
     rng: &mut R,
@@ -411,9 +1049,10 @@ mod tests {
 
     use super::*;
     use crate::message::ChitchatMessage;
-    use crate::state::NodeState;
     use crate::transport::{ChannelTransport, Transport};
-    use crate::HEARTBEAT_KEY;
+    #[cfg(feature = "json")]
+    use crate::PersistenceConfig;
+    use crate::{authentication, GossipFanout, MessageStats, ReceiveRateLimit, SeedBackoffConfig};
 
     #[derive(Debug, Default)]
     struct RngForTest {
@@ -466,7 +1105,7 @@ mod tests {
         match message {
             ChitchatMessage::Syn { cluster_id, digest } => {
                 assert_eq!(cluster_id, "default-cluster");
-                assert_eq!(digest.node_max_version.len(), 1);
+                assert_eq!(digest.num_nodes(), 1);
             }
             message => panic!("unexpected message: {message:?}"),
         }
@@ -533,6 +1172,226 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_syn_authenticated() {
+        let transport = ChannelTransport::default();
+        let cluster_key = ClusterKey::from_bytes(b"cluster-key");
+        let client_config = ChitchatConfig::for_test(2226);
+        let mut client_transport = transport
+            .open(client_config.node_id.gossip_public_address)
+            .await
+            .unwrap();
+        let client = Chitchat::with_node_id_and_seeds(client_config, empty_seeds(), Vec::new());
+
+        let mut server_config = ChitchatConfig::for_test(2225);
+        server_config.cluster_key = Some(cluster_key.clone());
+        let server_addr = server_config.node_id.gossip_public_address;
+        let handler = spawn_chitchat(server_config, Vec::new(), &transport)
+            .await
+            .unwrap();
+
+        // Unauthenticated syns, and syns signed with another key, are dropped.
+        let syn = client.create_syn_message();
+        client_transport.send(server_addr, syn).await.unwrap();
+        let other_cluster_key = ClusterKey::from_bytes(b"other-cluster-key");
+        let syn = other_cluster_key.seal_message(&client.create_syn_message());
+        client_transport.send(server_addr, syn).await.unwrap();
+        let syn = cluster_key.seal_message(&client.create_syn_message());
+        client_transport.send(server_addr, syn).await.unwrap();
+
+        let (_from_addr, syn_ack) = timeout(client_transport.recv()).await.unwrap();
+        match authentication::open([&cluster_key], syn_ack) {
+            Some(ChitchatMessage::SynAck { .. }) => (),
+            message => panic!("unexpected message: {message:?}"),
+        }
+        let num_unauthenticated_messages = handler
+            .with_chitchat(|chitchat| chitchat.num_unauthenticated_messages())
+            .await;
+        assert_eq!(num_unauthenticated_messages, 2);
+    }
+
+    #[tokio::test]
+    async fn test_syn_rate_limited() {
+        let transport = ChannelTransport::default();
+        let client_config = ChitchatConfig::for_test(2232);
+        let mut client_transport = transport
+            .open(client_config.node_id.gossip_public_address)
+            .await
+            .unwrap();
+        let client = Chitchat::with_node_id_and_seeds(client_config, empty_seeds(), Vec::new());
+
+        let mut server_config = ChitchatConfig::for_test(2231);
+        server_config.receive_rate_limit = Some(ReceiveRateLimit {
+            max_messages_per_sec: 2,
+            max_bytes_per_sec: 1_000_000,
+        });
+        let server_addr = server_config.node_id.gossip_public_address;
+        let handler = spawn_chitchat(server_config, Vec::new(), &transport)
+            .await
+            .unwrap();
+
+        for _ in 0..3 {
+            client_transport
+                .send(server_addr, client.create_syn_message())
+                .await
+                .unwrap();
+        }
+        timeout(client_transport.recv()).await.unwrap();
+        timeout(client_transport.recv()).await.unwrap();
+
+        let (num_rate_limited_messages, num_rate_limited_bytes) = handler
+            .with_chitchat(|chitchat| {
+                (
+                    chitchat.num_rate_limited_messages(),
+                    chitchat.num_rate_limited_bytes(),
+                )
+            })
+            .await;
+        assert_eq!(num_rate_limited_messages, 1);
+        assert_eq!(
+            num_rate_limited_bytes,
+            client.create_syn_message().serialized_len() as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gossip_stats() {
+        let transport = ChannelTransport::default();
+        let client_config = ChitchatConfig::for_test(2242);
+        let mut client_transport = transport
+            .open(client_config.node_id.gossip_public_address)
+            .await
+            .unwrap();
+        let client = Chitchat::with_node_id_and_seeds(client_config, empty_seeds(), Vec::new());
+
+        let mut server_config = ChitchatConfig::for_test(2241);
+        server_config.receive_rate_limit = Some(ReceiveRateLimit {
+            max_messages_per_sec: 2,
+            max_bytes_per_sec: 1_000_000,
+        });
+        let server_addr = server_config.node_id.gossip_public_address;
+        let handler = spawn_chitchat(server_config, Vec::new(), &transport)
+            .await
+            .unwrap();
+
+        for _ in 0..3 {
+            client_transport
+                .send(server_addr, client.create_syn_message())
+                .await
+                .unwrap();
+        }
+        let mut syn_acks_len = 0;
+        for _ in 0..2 {
+            let (_, syn_ack) = timeout(client_transport.recv()).await.unwrap();
+            syn_acks_len += syn_ack.serialized_len() as u64;
+        }
+
+        let gossip_stats = handler.gossip_stats().await;
+        let syn_len = client.create_syn_message().serialized_len() as u64;
+        assert_eq!(
+            gossip_stats.received.get("syn"),
+            Some(&MessageStats {
+                num_messages: 2,
+                num_bytes: 2 * syn_len,
+            })
+        );
+        assert_eq!(
+            gossip_stats.sent.get("syn_ack"),
+            Some(&MessageStats {
+                num_messages: 2,
+                num_bytes: syn_acks_len,
+            })
+        );
+        assert_eq!(gossip_stats.num_dropped_messages, 1);
+    }
+
+    #[tokio::test]
+    async fn test_syn_replayed() {
+        let transport = ChannelTransport::default();
+        let cluster_key = ClusterKey::from_bytes(b"cluster-key");
+        let client_config = ChitchatConfig::for_test(2230);
+        let mut client_transport = transport
+            .open(client_config.node_id.gossip_public_address)
+            .await
+            .unwrap();
+        let client = Chitchat::with_node_id_and_seeds(client_config, empty_seeds(), Vec::new());
+
+        let mut server_config = ChitchatConfig::for_test(2229);
+        server_config.cluster_key = Some(cluster_key.clone());
+        let server_addr = server_config.node_id.gossip_public_address;
+        let handler = spawn_chitchat(server_config, Vec::new(), &transport)
+            .await
+            .unwrap();
+
+        let syn = cluster_key.seal_message(&client.create_syn_message());
+        let ChitchatMessage::Authenticated { payload, tag } = &syn else {
+            panic!("expected an authenticated message");
+        };
+        let replayed_syn = ChitchatMessage::Authenticated {
+            payload: payload.clone(),
+            tag: *tag,
+        };
+        client_transport.send(server_addr, syn).await.unwrap();
+        timeout(client_transport.recv()).await.unwrap();
+        client_transport
+            .send(server_addr, replayed_syn)
+            .await
+            .unwrap();
+        let syn = cluster_key.seal_message(&client.create_syn_message());
+        client_transport.send(server_addr, syn).await.unwrap();
+        timeout(client_transport.recv()).await.unwrap();
+
+        let num_replayed_messages = handler
+            .with_chitchat(|chitchat| chitchat.num_replayed_messages())
+            .await;
+        assert_eq!(num_replayed_messages, 1);
+    }
+
+    #[tokio::test]
+    async fn test_rotate_cluster_key() {
+        let transport = ChannelTransport::default();
+        let old_cluster_key = ClusterKey::from_bytes(b"old-cluster-key");
+        let new_cluster_key = ClusterKey::from_bytes(b"new-cluster-key");
+        let client_config = ChitchatConfig::for_test(2228);
+        let mut client_transport = transport
+            .open(client_config.node_id.gossip_public_address)
+            .await
+            .unwrap();
+        let client = Chitchat::with_node_id_and_seeds(client_config, empty_seeds(), Vec::new());
+
+        let mut server_config = ChitchatConfig::for_test(2227);
+        server_config.cluster_key = Some(old_cluster_key.clone());
+        let server_addr = server_config.node_id.gossip_public_address;
+        let handler = spawn_chitchat(server_config, Vec::new(), &transport)
+            .await
+            .unwrap();
+
+        handler.accept_cluster_key(new_cluster_key.clone()).await;
+        let syn = new_cluster_key.seal_message(&client.create_syn_message());
+        client_transport.send(server_addr, syn).await.unwrap();
+        let (_from_addr, syn_ack) = timeout(client_transport.recv()).await.unwrap();
+        // The server still signs with the old key.
+        assert!(authentication::open([&old_cluster_key], syn_ack).is_some());
+
+        handler.rotate_cluster_key(new_cluster_key.clone()).await;
+        let syn = old_cluster_key.seal_message(&client.create_syn_message());
+        client_transport.send(server_addr, syn).await.unwrap();
+        let (_from_addr, syn_ack) = timeout(client_transport.recv()).await.unwrap();
+        assert!(authentication::open([&new_cluster_key], syn_ack).is_some());
+
+        handler.retire_cluster_keys().await;
+        let syn = old_cluster_key.seal_message(&client.create_syn_message());
+        client_transport.send(server_addr, syn).await.unwrap();
+        let syn = new_cluster_key.seal_message(&client.create_syn_message());
+        client_transport.send(server_addr, syn).await.unwrap();
+        let (_from_addr, syn_ack) = timeout(client_transport.recv()).await.unwrap();
+        assert!(authentication::open([&new_cluster_key], syn_ack).is_some());
+        let num_unauthenticated_messages = handler
+            .with_chitchat(|chitchat| chitchat.num_unauthenticated_messages())
+            .await;
+        assert_eq!(num_unauthenticated_messages, 1);
+    }
+
     #[tokio::test]
     async fn test_seeding() {
         let transport = ChannelTransport::default();
@@ -556,6 +1415,52 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_seeds_unreachable() {
+        let transport = ChannelTransport::default();
+        let seed_config = ChitchatConfig::for_test(5553);
+        let seed_addr = seed_config.node_id.gossip_public_address;
+        let mut seed_chitchat =
+            Chitchat::with_node_id_and_seeds(seed_config, empty_seeds(), Vec::new());
+
+        let mut client_config = ChitchatConfig::for_test(5554);
+        let client_addr = client_config.node_id.gossip_public_address;
+        client_config.seed_nodes = vec![seed_addr.to_string()];
+        client_config.seed_backoff = SeedBackoffConfig {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            jitter: 0.0,
+            unreachable_threshold: Duration::from_millis(200),
+        };
+        let client_handle = spawn_chitchat(client_config, Vec::new(), &transport)
+            .await
+            .unwrap();
+        let mut seeds_unreachable_watcher = client_handle.seeds_unreachable_watcher();
+        assert_eq!(seeds_unreachable_watcher.next().await.unwrap(), None);
+
+        let seeds_unreachable =
+            tokio::time::timeout(Duration::from_secs(1), seeds_unreachable_watcher.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+        assert_eq!(seeds_unreachable.seed_addrs, HashSet::from([seed_addr]));
+        assert!(seeds_unreachable.unreachable_for >= Duration::from_millis(200));
+
+        // The seed starts, and replies.
+        let mut seed_transport = transport.open(seed_addr).await.unwrap();
+        let (_, syn) = tokio::time::timeout(Duration::from_secs(1), seed_transport.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let syn_ack = seed_chitchat.process_message(client_addr, syn).unwrap();
+        seed_transport.send(client_addr, syn_ack).await.unwrap();
+        assert_eq!(
+            timeout(seeds_unreachable_watcher.next()).await.unwrap(),
+            None
+        );
+    }
+
     #[tokio::test]
     async fn test_heartbeat() {
         let transport = ChannelTransport::default();
@@ -577,29 +1482,20 @@ mod tests {
             .with_chitchat(|server_chitchat| {
                 server_chitchat.update_heartbeat();
                 let syn = server_chitchat.create_syn_message();
-                let syn_ack = test_chitchat.process_message(syn).unwrap();
-                server_chitchat.process_message(syn_ack);
+                let syn_ack = test_chitchat.process_message(server_addr, syn).unwrap();
+                server_chitchat.process_message(test_addr, syn_ack);
             })
             .await;
 
-        // Wait for syn, with updated heartbeat
+        // Wait for syn, with updated heartbeat.
         let (_, syn_message) = timeout(test_transport.recv()).await.unwrap();
-
-        // Reply.
-        let syn_ack = test_chitchat.process_message(syn_message).unwrap();
-        test_transport.send(server_addr, syn_ack).await.unwrap();
-
-        // Wait for delta to ensure heartbeat key was incremented.
-        let (_, chitchat_message) = timeout(test_transport.recv()).await.unwrap();
-        let delta = if let ChitchatMessage::Ack { delta } = chitchat_message {
-            delta
-        } else {
-            panic!("Expected ack");
+        let ChitchatMessage::Syn { digest, .. } = &syn_message else {
+            panic!("Expected syn");
         };
+        assert_eq!(digest.get(&server_id).unwrap().heartbeat, 2);
 
-        let node_delta = &delta.node_deltas.get(&server_id).unwrap().key_values;
-        let heartbeat = &node_delta.get(HEARTBEAT_KEY).unwrap().value;
-        assert_eq!(heartbeat, "2");
+        // The heartbeat does not bump the max version of the server.
+        assert_eq!(digest.max_version(&server_id), Some(1));
 
         server_handle.shutdown().await.unwrap();
     }
@@ -636,6 +1532,54 @@ mod tests {
         node2.shutdown().await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_wait_for_members() {
+        let transport = ChannelTransport::default();
+        let node1_config = ChitchatConfig::for_test(6665);
+        let node1_addr = node1_config.node_id.gossip_public_address;
+        let node1 = spawn_chitchat(node1_config, Vec::new(), &transport)
+            .await
+            .unwrap();
+        node1
+            .wait_for_members(1, Duration::from_millis(100))
+            .await
+            .unwrap();
+        let error = node1
+            .wait_for_members(2, Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Timed out waiting for 2 members, 1 found."
+        );
+
+        let mut node2_config = ChitchatConfig::for_test(6666);
+        node2_config.seed_nodes = vec![node1_addr.to_string()];
+        let node2 = spawn_chitchat(node2_config, Vec::new(), &transport)
+            .await
+            .unwrap();
+        node1
+            .wait_for_members(2, Duration::from_secs(3))
+            .await
+            .unwrap();
+
+        let is_indexer = |node_state: &NodeState| node_state.get("role") == Some("indexer");
+        node1
+            .wait_for_members_matching(1, Duration::from_millis(100), is_indexer)
+            .await
+            .unwrap_err();
+        node2
+            .with_chitchat(|chitchat| chitchat.self_node_state().set("role", "indexer"))
+            .await;
+        node1
+            .wait_for_members_matching(1, Duration::from_secs(3), is_indexer)
+            .await
+            .unwrap();
+
+        node1.shutdown().await.unwrap();
+        node2.shutdown().await.unwrap();
+    }
+
     async fn next_ready_nodes<S: Unpin + Stream<Item = HashSet<NodeId>>>(
         watcher: &mut S,
     ) -> HashSet<NodeId> {
@@ -736,6 +1680,7 @@ mod tests {
             ]),
             to_hash_set(vec![node3.gossip_public_address]),
             to_hash_set(vec![node2.gossip_public_address]),
+            3,
         );
         assert_eq!(nodes.len(), 2);
         assert_eq!(dead_node, Some(node3.gossip_public_address));
@@ -745,6 +1690,96 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_select_indirect_probes() {
+        let suspect_node = NodeId::for_test_localhost(10_001);
+        let healthy_live_nodes: Vec<SocketAddr> = (10_002..=10_005)
+            .map(NodeId::for_test_localhost)
+            .map(|node_id| node_id.gossip_public_address)
+            .collect();
+        let mut rng = RngForTest::default();
+        let indirect_probes =
+            select_indirect_probes(&mut rng, vec![suspect_node.clone()], &healthy_live_nodes, 3);
+        assert_eq!(indirect_probes.len(), 3);
+        let probers: HashSet<SocketAddr> = indirect_probes
+            .iter()
+            .map(|(prober, target)| {
+                assert_eq!(target, &suspect_node);
+                *prober
+            })
+            .collect();
+        assert_eq!(probers.len(), 3);
+
+        let indirect_probes = select_indirect_probes(&mut rng, vec![suspect_node], &[], 3);
+        assert!(indirect_probes.is_empty());
+    }
+
+    #[test]
+    fn test_gossip_fanout() {
+        assert_eq!(GossipFanout::Fixed(3).num_peers(1_000), 3);
+        let logarithmic = GossipFanout::Logarithmic { min: 3 };
+        assert_eq!(logarithmic.num_peers(0), 3);
+        assert_eq!(logarithmic.num_peers(5), 3);
+        assert_eq!(logarithmic.num_peers(16), 4);
+        assert_eq!(logarithmic.num_peers(17), 5);
+        assert_eq!(logarithmic.num_peers(1_000), 10);
+    }
+
+    #[test]
+    fn test_select_nodes_with_policy() {
+        let addrs: Vec<SocketAddr> = (10_001..=10_004)
+            .map(NodeId::for_test_localhost)
+            .map(|node_id| node_id.gossip_public_address)
+            .collect();
+        let candidate =
+            |addr: SocketAddr, num_rounds_since_gossip: u64, is_other_zone: bool| GossipCandidate {
+                addr,
+                num_rounds_since_gossip,
+                is_other_zone,
+            };
+        let mut rng = SmallRng::seed_from_u64(42);
+
+        // Peers we did not gossip with for a while are preferred.
+        let mut num_stale_peer_picks = 0;
+        for _ in 0..1_000 {
+            let (nodes, _, _) = select_nodes_with_policy(
+                &mut rng,
+                vec![
+                    candidate(addrs[0], 0, false),
+                    candidate(addrs[1], 99, false),
+                ],
+                HashSet::new(),
+                HashSet::new(),
+                1,
+                0.0,
+            );
+            if nodes == [addrs[1]] {
+                num_stale_peer_picks += 1;
+            }
+        }
+        assert!(num_stale_peer_picks > 900);
+
+        // A peer of another zone is always picked, and the seed with the given probability.
+        for _ in 0..100 {
+            let (nodes, dead_node, seed_node) = select_nodes_with_policy(
+                &mut rng,
+                vec![
+                    candidate(addrs[0], 99, false),
+                    candidate(addrs[1], 99, false),
+                    candidate(addrs[2], 0, true),
+                ],
+                HashSet::new(),
+                to_hash_set(vec![addrs[3]]),
+                2,
+                1.0,
+            );
+            assert_eq!(nodes.len(), 2);
+            assert!(nodes.contains(&addrs[2]));
+            assert_eq!(dead_node, None);
+            assert_eq!(seed_node, Some(addrs[3]));
+        }
+    }
+
     #[test]
     fn test_gossip_no_dead_node_no_seed_nodes() {
         let nodes: HashSet<SocketAddr> = (10_001..=10_005)
@@ -758,6 +1793,7 @@ mod tests {
             nodes,
             to_hash_set(vec![]),
             to_hash_set(vec![]),
+            3,
         );
         assert_eq!(nodes.len(), 3);
         assert_eq!(dead_node, None);
@@ -778,9 +1814,212 @@ mod tests {
             to_hash_set(vec![nodes[0]]),
             nodes[1..].iter().cloned().collect(),
             seeds,
+            3,
         );
         assert_eq!(gossip_nodes, &[nodes[0]]);
         assert!(gossip_dead_node.is_some());
         assert!(gossip_seed_node.is_some());
     }
+
+    #[tokio::test]
+    async fn test_state_read() {
+        let transport = ChannelTransport::default();
+        let config = ChitchatConfig::for_test(6663);
+        let node_id = config.node_id.clone();
+        let node = spawn_chitchat(
+            config,
+            vec![("key".to_string(), "value".to_string())],
+            &transport,
+        )
+        .await
+        .unwrap();
+        {
+            let cluster_state = node.state_read().await;
+            assert_eq!(
+                cluster_state.node_state(&node_id).unwrap().get("key"),
+                Some("value")
+            );
+            // The guard holds the lock.
+            assert!(node.chitchat().try_lock().is_err());
+        }
+        assert!(node.chitchat().try_lock().is_ok());
+        node.shutdown().await.unwrap();
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_restore_checkpoint_upon_restart() {
+        let dir = std::env::temp_dir().join(format!("chitchat-server-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let persistence_config = PersistenceConfig {
+            path: dir.join("checkpoint.json"),
+            checkpoint_interval: Duration::from_secs(3_600),
+            include_cluster_view: false,
+            peer_cache_max_age: Duration::from_secs(3_600),
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+        };
+        let transport = ChannelTransport::default();
+        let mut config = ChitchatConfig::for_test(6663);
+        config.persistence = Some(persistence_config.clone());
+        let node_id = config.node_id.clone();
+        let node = spawn_chitchat(config, Vec::new(), &transport)
+            .await
+            .unwrap();
+        node.with_chitchat(|chitchat| chitchat.self_node_state().set("key", "value"))
+            .await;
+        node.shutdown().await.unwrap();
+
+        let mut config = ChitchatConfig::for_test(6663);
+        config.persistence = Some(persistence_config);
+        let node = spawn_chitchat(
+            config,
+            vec![("initial_key".to_string(), "initial_value".to_string())],
+            &transport,
+        )
+        .await
+        .unwrap();
+        {
+            let cluster_state = node.state_read().await;
+            let self_node_state = cluster_state.node_state(&node_id).unwrap();
+            assert_eq!(self_node_state.get("key"), Some("value"));
+            assert_eq!(self_node_state.get("initial_key"), Some("initial_value"));
+        }
+        node.shutdown().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_restore_peer_cache_upon_restart() {
+        let dir =
+            std::env::temp_dir().join(format!("chitchat-server-peer-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let persistence_config = PersistenceConfig {
+            path: dir.join("checkpoint.json"),
+            checkpoint_interval: Duration::from_secs(3_600),
+            include_cluster_view: false,
+            peer_cache_max_age: Duration::from_secs(3_600),
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+        };
+        let transport = ChannelTransport::default();
+        let node2_config = ChitchatConfig::for_test(6664);
+        let node2_addr = node2_config.node_id.gossip_public_address;
+        let node2 = spawn_chitchat(node2_config, Vec::new(), &transport)
+            .await
+            .unwrap();
+        let mut node1_config = ChitchatConfig::for_test(6663);
+        node1_config.seed_nodes = vec![node2_addr.to_string()];
+        node1_config.persistence = Some(persistence_config.clone());
+        let node1 = spawn_chitchat(node1_config, Vec::new(), &transport)
+            .await
+            .unwrap();
+        node1
+            .wait_for_members(2, Duration::from_secs(3))
+            .await
+            .unwrap();
+        node1.shutdown().await.unwrap();
+
+        // The peers of the checkpoint are used as seeds.
+        let mut node1_config = ChitchatConfig::for_test(6663);
+        node1_config.persistence = Some(persistence_config);
+        let node1 = spawn_chitchat(node1_config, Vec::new(), &transport)
+            .await
+            .unwrap();
+        let restored_peer_addrs = node1
+            .with_chitchat(|chitchat| chitchat.restored_peer_addrs())
+            .await;
+        assert_eq!(restored_peer_addrs, HashSet::from([node2_addr]));
+        node1
+            .wait_for_members(2, Duration::from_secs(3))
+            .await
+            .unwrap();
+        node1.shutdown().await.unwrap();
+        node2.shutdown().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_writer_rejects_writes_after_shutdown() {
+        let transport = ChannelTransport::default();
+        let mut config = ChitchatConfig::for_test(6663);
+        config.write_after_shutdown_policy = WriteAfterShutdownPolicy::Error;
+        let node = spawn_chitchat(config, Vec::new(), &transport)
+            .await
+            .unwrap();
+        let writer = node.writer();
+        writer.try_set("key", "1").await.unwrap();
+        let chitchat = node.chitchat();
+        node.shutdown().await.unwrap();
+        assert_eq!(
+            writer.try_set("key", "2").await.unwrap_err(),
+            WriteAfterShutdownError {
+                key: "key".to_string()
+            }
+        );
+        assert_eq!(
+            chitchat.lock().await.self_node_state().get("key"),
+            Some("1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_writer_blocks_until_shutdown_completes() {
+        let transport = ChannelTransport::default();
+        let mut config = ChitchatConfig::for_test(6663);
+        config.write_after_shutdown_policy = WriteAfterShutdownPolicy::Block;
+        let node = spawn_chitchat(config, Vec::new(), &transport)
+            .await
+            .unwrap();
+        let chitchat = node.chitchat();
+        chitchat
+            .lock()
+            .await
+            .set_shutdown_phase(ShutdownPhase::ShuttingDown);
+        let writer = node.writer();
+        let write_handle = tokio::spawn(async move { writer.try_set("key", "1").await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!write_handle.is_finished());
+
+        node.shutdown().await.unwrap();
+        assert!(write_handle.await.unwrap().is_err());
+        assert!(chitchat.lock().await.self_node_state().get("key").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_writer_queue_and_flush() {
+        let transport = ChannelTransport::default();
+        let node1_config = ChitchatConfig::for_test(6663);
+        let node1_addr = node1_config.node_id.gossip_public_address;
+        let node1 = spawn_chitchat(node1_config, Vec::new(), &transport)
+            .await
+            .unwrap();
+
+        let mut node2_config = ChitchatConfig::for_test(6664);
+        node2_config.seed_nodes = vec![node1_addr.to_string()];
+        // Only the final gossip round can propagate the write.
+        node2_config.gossip_interval = Duration::from_secs(3_600);
+        node2_config.write_after_shutdown_policy = WriteAfterShutdownPolicy::QueueAndFlush;
+        let node2_id = node2_config.node_id.clone();
+        let node2 = spawn_chitchat(node2_config, Vec::new(), &transport)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        node2
+            .chitchat()
+            .lock()
+            .await
+            .set_shutdown_phase(ShutdownPhase::ShuttingDown);
+        node2.writer().try_set("key", "1").await.unwrap();
+        node2.shutdown().await.unwrap();
+
+        let node1_chitchat = node1.chitchat();
+        let node1_chitchat_guard = node1_chitchat.lock().await;
+        let node2_state = node1_chitchat_guard.node_state(&node2_id).unwrap();
+        assert_eq!(node2_state.get("key"), Some("1"));
+        drop(node1_chitchat_guard);
+        node1.shutdown().await.unwrap();
+    }
 }
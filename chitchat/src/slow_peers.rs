@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use crate::NodeId;
+
+/// Weight of a new digest in the smoothed lag of a peer, so that a peer only shows up as slow
+/// if it lags behind consistently.
+const LAG_SMOOTHING_FACTOR: f64 = 0.1;
+
+/// A peer lagging behind the node, returned by [`crate::Chitchat::slow_peers`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SlowPeer {
+    pub peer_addr: SocketAddr,
+    /// Node id of the peer, `None` if its state is not known yet.
+    pub node_id: Option<NodeId>,
+    /// Number of versions the digests of the peer lag behind the cluster state of the node,
+    /// smoothed over its recent digests.
+    pub smoothed_lag_versions: f64,
+    /// Number of versions the last digest of the peer lagged behind.
+    pub last_lag_versions: u64,
+    /// Number of digests received from the peer.
+    pub num_digests: u64,
+    /// Number of node states the peer had to be sent from scratch, because it lagged behind the
+    /// garbage collection of tombstones.
+    pub num_resets_sent: u64,
+}
+
+#[derive(Debug, Default)]
+struct PeerLag {
+    smoothed_lag_versions: f64,
+    last_lag_versions: u64,
+    num_digests: u64,
+    num_resets_sent: u64,
+}
+
+/// Keeps track of how far behind the digests of each peer are, and of the resets it needs.
+#[derive(Debug, Default)]
+pub(crate) struct SlowPeerTracker {
+    peer_lags: HashMap<SocketAddr, PeerLag>,
+}
+
+impl SlowPeerTracker {
+    /// Records a digest of `peer_addr` lagging `lag_versions` versions behind.
+    pub fn record_digest(&mut self, peer_addr: SocketAddr, lag_versions: u64) {
+        let peer_lag = self.peer_lags.entry(peer_addr).or_default();
+        peer_lag.smoothed_lag_versions = if peer_lag.num_digests == 0 {
+            lag_versions as f64
+        } else {
+            peer_lag.smoothed_lag_versions * (1.0 - LAG_SMOOTHING_FACTOR)
+                + lag_versions as f64 * LAG_SMOOTHING_FACTOR
+        };
+        peer_lag.last_lag_versions = lag_versions;
+        peer_lag.num_digests += 1;
+    }
+
+    pub fn record_resets_sent(&mut self, peer_addr: SocketAddr, num_resets: usize) {
+        if num_resets == 0 {
+            return;
+        }
+        self.peer_lags.entry(peer_addr).or_default().num_resets_sent += num_resets as u64;
+    }
+
+    pub fn forget_peer(&mut self, peer_addr: SocketAddr) {
+        self.peer_lags.remove(&peer_addr);
+    }
+
+    /// Returns the peers lagging behind or needing resets, slowest first, resolving their node
+    /// id with `node_id_fn`.
+    pub fn slow_peers(&self, node_id_fn: impl Fn(SocketAddr) -> Option<NodeId>) -> Vec<SlowPeer> {
+        let mut slow_peers: Vec<SlowPeer> = self
+            .peer_lags
+            .iter()
+            .filter(|(_, peer_lag)| {
+                peer_lag.smoothed_lag_versions >= 1.0 || peer_lag.num_resets_sent > 0
+            })
+            .map(|(peer_addr, peer_lag)| SlowPeer {
+                peer_addr: *peer_addr,
+                node_id: node_id_fn(*peer_addr),
+                smoothed_lag_versions: peer_lag.smoothed_lag_versions,
+                last_lag_versions: peer_lag.last_lag_versions,
+                num_digests: peer_lag.num_digests,
+                num_resets_sent: peer_lag.num_resets_sent,
+            })
+            .collect();
+        slow_peers.sort_by(|left, right| {
+            right
+                .smoothed_lag_versions
+                .total_cmp(&left.smoothed_lag_versions)
+                .then(right.num_resets_sent.cmp(&left.num_resets_sent))
+                .then(left.peer_addr.cmp(&right.peer_addr))
+        });
+        slow_peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slow_peer_tracker() {
+        let mut slow_peer_tracker = SlowPeerTracker::default();
+        let fast_peer_addr: SocketAddr = "127.0.0.1:10001".parse().unwrap();
+        let slow_peer_addr: SocketAddr = "127.0.0.1:10002".parse().unwrap();
+        let reset_peer_addr: SocketAddr = "127.0.0.1:10003".parse().unwrap();
+        slow_peer_tracker.record_digest(fast_peer_addr, 0);
+        slow_peer_tracker.record_digest(slow_peer_addr, 100);
+        slow_peer_tracker.record_digest(slow_peer_addr, 0);
+        slow_peer_tracker.record_digest(reset_peer_addr, 0);
+        slow_peer_tracker.record_resets_sent(reset_peer_addr, 2);
+        slow_peer_tracker.record_resets_sent(fast_peer_addr, 0);
+
+        let slow_peers = slow_peer_tracker.slow_peers(|_| None);
+        assert_eq!(slow_peers.len(), 2);
+        assert_eq!(slow_peers[0].peer_addr, slow_peer_addr);
+        assert_eq!(slow_peers[0].smoothed_lag_versions, 90.0);
+        assert_eq!(slow_peers[0].last_lag_versions, 0);
+        assert_eq!(slow_peers[0].num_digests, 2);
+        assert_eq!(slow_peers[1].peer_addr, reset_peer_addr);
+        assert_eq!(slow_peers[1].num_resets_sent, 2);
+
+        slow_peer_tracker.forget_peer(slow_peer_addr);
+        assert_eq!(slow_peer_tracker.slow_peers(|_| None).len(), 1);
+    }
+}
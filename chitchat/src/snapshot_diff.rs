@@ -0,0 +1,184 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ClusterStateSnapshot, KeyValues, NodeState, VersionedValue};
+
+/// Changes between two snapshots of the same cluster state, taken at different times.
+///
+/// See [`ClusterStateSnapshot::diff`].
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotDiff {
+    /// Nodes only present in the newer snapshot.
+    pub added_nodes: Vec<String>,
+    /// Nodes only present in the older snapshot.
+    pub removed_nodes: Vec<String>,
+    /// Keys set, updated, marked for deletion or garbage collected between the two snapshots.
+    /// The keys of added and removed nodes are included.
+    pub changed_keys: Vec<KeyChange>,
+}
+
+/// A key whose versioned value changed between two snapshots.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct KeyChange {
+    pub node_id: String,
+    pub key: String,
+    /// Value in the older snapshot, if any.
+    pub previous: Option<VersionedValue>,
+    /// Value in the newer snapshot, if any.
+    pub current: Option<VersionedValue>,
+}
+
+impl SnapshotDiff {
+    pub(crate) fn compute(previous: &ClusterStateSnapshot, current: &ClusterStateSnapshot) -> Self {
+        let empty_node_state = NodeState::default();
+        let mut snapshot_diff = SnapshotDiff::default();
+        for (node_id, previous_node_state) in &previous.node_states {
+            if !current.node_states.contains_key(node_id) {
+                snapshot_diff.removed_nodes.push(node_id.clone());
+                snapshot_diff.add_key_changes(node_id, previous_node_state, &empty_node_state);
+            }
+        }
+        for (node_id, current_node_state) in &current.node_states {
+            let Some(previous_node_state) = previous.node_states.get(node_id) else {
+                snapshot_diff.added_nodes.push(node_id.clone());
+                snapshot_diff.add_key_changes(node_id, &empty_node_state, current_node_state);
+                continue;
+            };
+            // Updates always bump the max version, and garbage collection always removes keys:
+            // unchanged node states can be skipped without looking at their key-values.
+            if previous_node_state.max_version == current_node_state.max_version
+                && previous_node_state.key_values.len() == current_node_state.key_values.len()
+            {
+                continue;
+            }
+            snapshot_diff.add_key_changes(node_id, previous_node_state, current_node_state);
+        }
+        snapshot_diff
+            .changed_keys
+            .sort_by(|left, right| (&left.node_id, &left.key).cmp(&(&right.node_id, &right.key)));
+        snapshot_diff
+    }
+
+    fn add_key_changes(&mut self, node_id: &str, previous: &NodeState, current: &NodeState) {
+        for (key, previous_value) in &previous.key_values {
+            match current.key_values.get(key) {
+                Some(current_value) if current_value.version == previous_value.version => {}
+                current_value_opt => self.changed_keys.push(KeyChange {
+                    node_id: node_id.to_string(),
+                    key: key.to_string(),
+                    previous: Some(previous_value.clone()),
+                    current: current_value_opt.cloned(),
+                }),
+            }
+        }
+        for (key, current_value) in added_key_values(&previous.key_values, &current.key_values) {
+            self.changed_keys.push(KeyChange {
+                node_id: node_id.to_string(),
+                key: key.to_string(),
+                previous: None,
+                current: Some(current_value.clone()),
+            });
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added_nodes.is_empty() && self.removed_nodes.is_empty() && self.changed_keys.is_empty()
+    }
+}
+
+fn added_key_values<'a>(
+    previous: &'a KeyValues,
+    current: &'a KeyValues,
+) -> impl Iterator<Item = (&'a Arc<str>, &'a VersionedValue)> {
+    current
+        .iter()
+        .filter(|(key, _)| !previous.contains_key(&***key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashSet};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn snapshot(node_states: Vec<(&str, NodeState)>) -> ClusterStateSnapshot {
+        ClusterStateSnapshot {
+            seed_addrs: HashSet::new(),
+            node_states: node_states
+                .into_iter()
+                .map(|(node_id, node_state)| (node_id.to_string(), Arc::new(node_state)))
+                .collect(),
+            gossip_addrs: BTreeMap::new(),
+            generations: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let mut node1_state = NodeState::default();
+        node1_state.set("key_a", "1");
+        node1_state.set("key_b", "2");
+        node1_state.set("key_c", "3");
+        let mut node2_state = NodeState::default();
+        node2_state.set("key_a", "1");
+        let previous = snapshot(vec![
+            ("node-1", node1_state.clone()),
+            ("node-2", node2_state.clone()),
+        ]);
+        assert!(previous.diff(&previous).is_empty());
+
+        node1_state.set("key_a", "1-bis");
+        node1_state.mark_for_deletion("key_b");
+        node1_state.set("key_d", "4");
+        let mut node3_state = NodeState::default();
+        node3_state.set("key_e", "5");
+        let current = snapshot(vec![("node-1", node1_state), ("node-3", node3_state)]);
+
+        let diff = previous.diff(&current);
+        assert_eq!(diff.added_nodes, ["node-3"]);
+        assert_eq!(diff.removed_nodes, ["node-2"]);
+        let changed_keys: Vec<(&str, &str, Option<&str>)> = diff
+            .changed_keys
+            .iter()
+            .map(|key_change| {
+                (
+                    key_change.node_id.as_str(),
+                    key_change.key.as_str(),
+                    key_change
+                        .current
+                        .as_ref()
+                        .filter(|value| !value.marked_for_deletion)
+                        .map(|value| value.value.as_str()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            changed_keys,
+            [
+                ("node-1", "key_a", Some("1-bis")),
+                ("node-1", "key_b", None),
+                ("node-1", "key_d", Some("4")),
+                ("node-2", "key_a", None),
+                ("node-3", "key_e", Some("5")),
+            ]
+        );
+        assert_eq!(diff.changed_keys[0].previous.as_ref().unwrap().value, "1");
+    }
+
+    #[test]
+    fn test_snapshot_diff_garbage_collected_key() {
+        let mut node_state = NodeState::default();
+        node_state.set("key_a", "1");
+        node_state.mark_for_deletion("key_a");
+        let previous = snapshot(vec![("node-1", node_state.clone())]);
+        node_state.gc_expired_tombstones(Duration::ZERO);
+        let current = snapshot(vec![("node-1", node_state)]);
+        let diff = previous.diff(&current);
+        assert_eq!(diff.changed_keys.len(), 1);
+        assert!(diff.changed_keys[0].previous.is_some());
+        assert!(diff.changed_keys[0].current.is_none());
+    }
+}
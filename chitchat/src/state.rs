@@ -1,42 +1,240 @@
-use std::collections::btree_map::Entry;
-use std::collections::{BTreeMap, BinaryHeap, HashSet};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fmt;
 use std::net::SocketAddr;
-use std::time::Instant;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+#[cfg(feature = "rand")]
 use rand::prelude::SliceRandom;
+#[cfg(feature = "rand")]
 use rand::Rng;
+#[cfg(feature = "json")]
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use tokio::sync::watch;
+use tracing::{debug, error, warn};
 
-use crate::delta::{Delta, DeltaWriter};
-use crate::digest::Digest;
-use crate::{NodeId, Version, VersionedValue};
+use crate::configuration::{
+    NodeStateLimitPolicy, NodeStateLimits, OversizedKeyValuePolicy, TombstoneGcPolicy,
+};
+use crate::counter::{counter_key, PnCounter};
+#[cfg(feature = "json")]
+use crate::delta::NodeDelta;
+use crate::delta::{kv_serialized_len, Delta, DeltaWriter};
+use crate::delta_interceptor::{DeltaInterceptor, Interception};
+use crate::digest::{Digest, DigestCache};
+use crate::internal_keys::{
+    is_reserved_key, ADVERTISED_ADDRS_KEY, GENERATION_KEY, LEADER_EPOCH_KEY, LEAVE_INTENT_KEY,
+    METADATA_KEY, WRITE_TIMESTAMP_KEY,
+};
+use crate::key_interner::intern_key;
+use crate::key_values::{KeyValues, MemoryUsage};
+#[cfg(feature = "json")]
+use crate::node_metadata::NodeMetadata;
+#[cfg(feature = "json")]
+use crate::or_set::{or_set_key, OrSet};
+#[cfg(feature = "encryption")]
+use crate::sealed_keys::SealedKeys;
+use crate::serialize::Serializable;
+use crate::snapshot_diff::SnapshotDiff;
+use crate::write_acl::WriteAcl;
+use crate::{NodeId, Version, VersionedValue, HEARTBEAT_KEY};
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct NodeState {
-    pub key_values: BTreeMap<String, VersionedValue>,
+    pub key_values: KeyValues,
     #[serde(skip)]
     #[serde(default = "Instant::now")]
     last_heartbeat: Instant,
+    /// Counter bumped by the node on every gossip round and carried in digests, independently
+    /// of the versions of its key-values.
+    #[serde(skip)]
+    heartbeat: u64,
     pub max_version: u64,
+    /// Source of the last local write of each key.
+    /// Writes received through gossip are not tagged.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    write_sources: BTreeMap<String, WriteSource>,
+    /// Deadlines of the keys set with a TTL.
+    #[serde(skip)]
+    expiration_deadlines: BTreeMap<String, Instant>,
+    /// Number of key-values inserted, overwritten or removed since the last compaction.
+    #[serde(skip)]
+    num_writes_since_compaction: usize,
+    /// Highest version of the tombstones garbage collected so far, or of the key-values
+    /// received when the node state got reset: missing key-values no newer than the watermark
+    /// were deleted.
+    ///
+    /// Node states are scoped to a node id, hence to a generation of the node: a new
+    /// generation starts with a fresh watermark.
+    #[serde(skip)]
+    gc_watermark: Version,
+    /// Time at which the wall-clock garbage collection first saw each tombstone, along with
+    /// the version of the tombstone.
+    #[serde(skip)]
+    deletion_timestamps: BTreeMap<String, (Version, Instant)>,
+    #[serde(skip)]
+    limits: NodeStateLimits,
+    /// Number of key-values rejected, or evicted, to enforce the limits.
+    #[serde(skip)]
+    num_rejected_key_values: u64,
+    #[serde(skip)]
+    num_evicted_key_values: u64,
+    /// Key prefixes whose values are encrypted on write. Only set on the self node state.
+    #[cfg(feature = "encryption")]
+    #[serde(skip)]
+    sealed_keys: Option<Arc<SealedKeys>>,
+    /// Whether application writes are stamped with the time of the write. Only set on the self
+    /// node state.
+    #[serde(skip)]
+    stamp_writes: bool,
 }
 
 impl Default for NodeState {
     fn default() -> Self {
         Self {
             last_heartbeat: Instant::now(),
+            heartbeat: 0,
             max_version: Default::default(),
             key_values: Default::default(),
+            write_sources: Default::default(),
+            expiration_deadlines: Default::default(),
+            num_writes_since_compaction: 0,
+            gc_watermark: 0,
+            deletion_timestamps: Default::default(),
+            limits: NodeStateLimits::default(),
+            num_rejected_key_values: 0,
+            num_evicted_key_values: 0,
+            #[cfg(feature = "encryption")]
+            sealed_keys: None,
+            stamp_writes: false,
         }
     }
 }
 
+/// Origin of a local write.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteSource {
+    /// The write was issued by the application embedding chitchat.
+    #[default]
+    Application,
+    /// The write was issued by a human operator, e.g. through an admin API.
+    Operator,
+    /// The write was issued by chitchat itself, e.g. the heartbeat.
+    Internal,
+}
+
 impl NodeState {
+    pub(crate) fn with_limits(limits: NodeStateLimits) -> Self {
+        NodeState {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn set_sealed_keys(&mut self, sealed_keys: Option<Arc<SealedKeys>>) {
+        self.sealed_keys = sealed_keys;
+    }
+
+    pub(crate) fn set_stamp_writes(&mut self, stamp_writes: bool) {
+        self.stamp_writes = stamp_writes;
+    }
+
+    /// Returns the version and the value of the write timestamp of the node, in milliseconds
+    /// since the Unix epoch.
+    pub(crate) fn write_timestamp(&self) -> Option<(Version, u64)> {
+        let versioned_value = self.get_versioned(WRITE_TIMESTAMP_KEY)?;
+        let timestamp_millis = versioned_value.value.parse().ok()?;
+        Some((versioned_value.version, timestamp_millis))
+    }
+
+    /// Stamps the application write at `version` with the current time, at the same version, so
+    /// that receivers can measure how long the write took to reach them.
+    fn stamp_write(&mut self, version: Version) {
+        if !self.stamp_writes {
+            return;
+        }
+        let timestamp_millis = unix_timestamp_millis(SystemTime::now());
+        self.key_values.insert(
+            intern_key(WRITE_TIMESTAMP_KEY),
+            VersionedValue {
+                version,
+                value: timestamp_millis.to_string(),
+                marked_for_deletion: false,
+            },
+        );
+        self.write_sources
+            .insert(WRITE_TIMESTAMP_KEY.to_string(), WriteSource::Internal);
+    }
+
+    /// Encrypts `value` if `key` is sealed.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn seal_value(&self, key: &str, value: String) -> String {
+        #[cfg(feature = "encryption")]
+        if let Some(sealed_keys) = &self.sealed_keys {
+            return sealed_keys.seal(key, value);
+        }
+        value
+    }
+
+    /// Decrypts `value` if `key` is sealed, falling back to the value as is if it cannot be
+    /// decrypted.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn unseal_value(&self, key: &str, value: &str) -> String {
+        #[cfg(feature = "encryption")]
+        if let Some(sealed_keys) = &self.sealed_keys {
+            if let Ok(value) = sealed_keys.unseal(key, value) {
+                return value;
+            }
+        }
+        value.to_string()
+    }
+
+    /// Returns the time elapsed since the last update of this node state or of its heartbeat,
+    /// received through gossip or, for the self node, since its last heartbeat.
+    pub fn time_since_heartbeat(&self) -> Duration {
+        self.last_heartbeat.elapsed()
+    }
+
+    pub(crate) fn record_heartbeat(&mut self) {
+        self.last_heartbeat = Instant::now();
+    }
+
+    /// Returns the last heartbeat counter of the node known locally.
+    pub fn heartbeat(&self) -> u64 {
+        self.heartbeat
+    }
+
+    pub(crate) fn increment_heartbeat(&mut self) {
+        self.heartbeat += 1;
+        self.record_heartbeat();
+    }
+
+    /// Returns true if the key-value with the max version of the node is a tombstone that
+    /// got garbage collected.
+    pub(crate) fn is_max_version_garbage_collected(&self) -> bool {
+        self.max_version > 0 && self.gc_watermark >= self.max_version
+    }
+
+    /// Records the heartbeat counter of the node found in a digest. Returns true if the
+    /// heartbeat moved forward.
+    pub(crate) fn record_digest_heartbeat(&mut self, heartbeat: u64) -> bool {
+        if heartbeat <= self.heartbeat {
+            return false;
+        }
+        self.heartbeat = heartbeat;
+        self.record_heartbeat();
+        true
+    }
+
     /// Returns an iterator over keys matching the given predicate.
     /// Keys marked for deletion are not returned.
     pub fn iter_key_values(
         &self,
-        predicate: impl Fn(&String, &VersionedValue) -> bool,
+        predicate: impl Fn(&str, &VersionedValue) -> bool,
     ) -> impl Iterator<Item = (&str, &VersionedValue)> {
         self.internal_iter_key_values(predicate)
             .filter(|&(_, versioned_value)| !versioned_value.marked_for_deletion)
@@ -46,23 +244,20 @@ impl NodeState {
     /// Not public as it returns also keys marked for deletion.
     fn internal_iter_key_values(
         &self,
-        predicate: impl Fn(&String, &VersionedValue) -> bool,
+        predicate: impl Fn(&str, &VersionedValue) -> bool,
     ) -> impl Iterator<Item = (&str, &VersionedValue)> {
         self.key_values
             .iter()
             .filter(move |&(key, versioned_value)| predicate(key, versioned_value))
-            .map(|(key, record)| (key.as_str(), record))
+            .map(|(key, record)| (&**key, record))
     }
 
     /// Returns an iterator over the version values that are older than `floor_version`.
     fn iter_stale_key_values(
         &self,
         floor_version: u64,
-    ) -> impl Iterator<Item = (&str, &VersionedValue)> {
-        // TODO optimize by checking the max version.
-        self.internal_iter_key_values(move |_key, versioned_value| {
-            versioned_value.version > floor_version
-        })
+    ) -> impl Iterator<Item = (&Arc<str>, &VersionedValue)> {
+        self.key_values.iter_newer_than(floor_version)
     }
 
     pub fn get(&self, key: &str) -> Option<&str> {
@@ -74,431 +269,3124 @@ impl NodeState {
         self.key_values.get(key)
     }
 
+    /// Returns the value associated to the given key, decoded from its JSON representation.
+    ///
+    /// Returns `Ok(None)` if the key is not present, and an error if the value
+    /// cannot be decoded as a `T`.
+    #[cfg(feature = "json")]
+    pub fn get_typed<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, TypedValueError> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        serde_json::from_str(value)
+            .map(Some)
+            .map_err(|source| TypedValueError::Decode {
+                key: key.to_string(),
+                source,
+            })
+    }
+
+    /// Sets the JSON representation of `value` for a given key.
+    ///
+    /// See [`NodeState::set`].
+    #[cfg(feature = "json")]
+    pub fn set_typed<K: ToString, T: Serialize + ?Sized>(
+        &mut self,
+        key: K,
+        value: &T,
+    ) -> Result<(), TypedValueError> {
+        let key = key.to_string();
+        let value = serde_json::to_string(value).map_err(|source| TypedValueError::Encode {
+            key: key.clone(),
+            source,
+        })?;
+        self.set(key, value);
+        Ok(())
+    }
+
     /// Sets a new value for a given key.
     ///
     /// Setting a new value automatically increments the
     /// version of the entire NodeState regardless of whether the
     /// value is really changed or not.
+    ///
+    /// The write is subject to the [`NodeStateLimits`] of the node state.
     pub fn set<K: ToString, V: ToString>(&mut self, key: K, value: V) {
-        let new_version = self.max_version + 1;
-        self.set_with_version(key.to_string(), value.to_string(), new_version);
+        self.set_with_source(key, value, WriteSource::Application);
     }
 
-    pub fn mark_for_deletion(&mut self, key: &str) {
-        let new_version = self.max_version + 1;
-        self.max_version = new_version;
-        if let Some(versioned_value) = self.key_values.get_mut(key) {
-            versioned_value.marked_for_deletion = true;
-            versioned_value.version = new_version;
-        }
+    /// Same as [`NodeState::set`], tagging the write with the given source.
+    pub fn set_with_source<K: ToString, V: ToString>(
+        &mut self,
+        key: K,
+        value: V,
+        source: WriteSource,
+    ) {
+        log_state_error(self.try_set_with_source(key.to_string(), value.to_string(), source));
     }
 
-    // Remove keys marked for deletion and with `version + grace_period < max_version`.
-    pub fn gc_keys_marked_for_deletion(&mut self, grace_period: usize) {
-        self.key_values.retain(|_, versioned_value| {
-            !(versioned_value.marked_for_deletion
-                && versioned_value.version + (grace_period as u64) < self.max_version)
-        });
+    /// Returns false if the write was rejected to enforce the limits, or because the key is
+    /// reserved.
+    pub(crate) fn try_set_with_source(
+        &mut self,
+        key: String,
+        value: String,
+        source: WriteSource,
+    ) -> Result<bool, StateError> {
+        if source != WriteSource::Internal && is_reserved_key(&key) {
+            warn!(key = %key, "reserved-key-write-rejected");
+            return Ok(false);
+        }
+        let value = self.seal_value(&key, value);
+        // Fail before evicting any key-value to make room.
+        self.next_version()?;
+        if !self.make_room(&BTreeMap::from([(key.as_str(), value.len())]), true) {
+            return Ok(false);
+        }
+        let new_version = self.next_version()?;
+        self.set_with_version(key.clone(), value, new_version)?;
+        self.record_write_source(key, new_version, source);
+        if source != WriteSource::Internal {
+            self.stamp_write(new_version);
+        }
+        Ok(true)
     }
 
-    fn set_with_version(&mut self, key: String, value: String, version: Version) {
-        assert!(version > self.max_version);
-        self.max_version = version;
-        self.key_values.insert(
-            key,
-            VersionedValue {
-                version,
-                value,
-                marked_for_deletion: false,
-            },
-        );
+    /// Returns the version of the next local write.
+    fn next_version(&self) -> Result<Version, StateError> {
+        self.max_version
+            .checked_add(1)
+            .ok_or(StateError::VersionOverflow)
     }
-}
-
-#[derive(Debug)]
-pub struct ClusterState {
-    pub node_states: BTreeMap<NodeId, NodeState>,
-    seed_addrs: watch::Receiver<HashSet<SocketAddr>>,
-}
 
-#[cfg(test)]
-impl Default for ClusterState {
-    fn default() -> Self {
-        let (_seed_addrs_tx, seed_addrs_rx) = watch::channel(Default::default());
-        Self {
-            node_states: Default::default(),
-            seed_addrs: seed_addrs_rx,
-        }
+    /// Returns the source of the last local write of the given key.
+    ///
+    /// Returns `None` for keys that were not written locally.
+    pub fn write_source(&self, key: &str) -> Option<WriteSource> {
+        self.write_sources.get(key).copied()
     }
-}
 
-impl ClusterState {
-    pub fn with_seed_addrs(seed_addrs: watch::Receiver<HashSet<SocketAddr>>) -> ClusterState {
-        ClusterState {
-            seed_addrs,
-            node_states: BTreeMap::new(),
+    /// Sets a new value for a given key, that gets marked for deletion if it is not set again
+    /// within `ttl`.
+    ///
+    /// Expiry is checked on every gossip round, so keys may outlive their TTL by up to
+    /// one gossip interval.
+    pub fn set_with_ttl<K: ToString, V: ToString>(&mut self, key: K, value: V, ttl: Duration) {
+        let key = key.to_string();
+        let result =
+            self.try_set_with_source(key.clone(), value.to_string(), WriteSource::Application);
+        if log_state_error(result) {
+            self.expiration_deadlines.insert(key, Instant::now() + ttl);
         }
     }
 
-    pub(crate) fn node_state_mut(&mut self, node_id: &NodeId) -> &mut NodeState {
-        // TODO use the `hash_raw_entry` feature once it gets stabilized.
-        self.node_states.entry(node_id.clone()).or_default()
+    /// Marks for deletion the keys whose TTL lapsed at `now`.
+    /// Returns true if the TTL of some key lapsed as of `now`.
+    pub(crate) fn has_expired_keys(&self, now: Instant) -> bool {
+        self.expiration_deadlines
+            .values()
+            .any(|deadline| *deadline <= now)
     }
 
-    pub fn node_state(&self, node_id: &NodeId) -> Option<&NodeState> {
-        self.node_states.get(node_id)
+    pub(crate) fn expire_keys(&mut self, now: Instant) {
+        let expired_keys: Vec<String> = self
+            .expiration_deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired_keys {
+            self.expiration_deadlines.remove(&key);
+            let is_live = self
+                .key_values
+                .get(key.as_str())
+                .map(|versioned_value| !versioned_value.marked_for_deletion)
+                .unwrap_or(false);
+            if is_live {
+                debug!(key = %key, "expire-key-value");
+                if let Err(error) = self.try_mark_for_deletion(&key) {
+                    error!(key = %key, error = %error, "failed-to-expire-key-value");
+                    continue;
+                }
+                self.write_sources.insert(key, WriteSource::Internal);
+            }
+        }
     }
 
-    pub fn nodes(&self) -> impl Iterator<Item = &NodeId> {
-        self.node_states.keys()
+    /// Returns a copy of the node state to checkpoint.
+    ///
+    /// The deadlines of the keys set with a TTL are not persisted: these keys are marked for
+    /// deletion in the copy.
+    pub(crate) fn to_checkpoint(&self) -> NodeState {
+        let mut node_state = self.clone();
+        for key in std::mem::take(&mut node_state.expiration_deadlines).into_keys() {
+            let is_live = node_state
+                .get_versioned(&key)
+                .is_some_and(|versioned_value| !versioned_value.marked_for_deletion);
+            if is_live {
+                log_state_error(node_state.try_mark_for_deletion(&key));
+            }
+        }
+        node_state
     }
 
-    pub fn seed_addrs(&self) -> HashSet<SocketAddr> {
-        self.seed_addrs.borrow().clone()
+    fn record_write_source(&mut self, key: String, version: Version, source: WriteSource) {
+        debug!(key = %key, version = version, source = ?source, "set-key-value");
+        // Any local write overrides a previously set TTL.
+        self.expiration_deadlines.remove(&key);
+        self.write_sources.insert(key, source);
     }
 
-    pub(crate) fn remove_node(&mut self, node_id: &NodeId) {
-        self.node_states.remove(node_id);
+    /// Sets a new value for a given key, only if its current value is `expected_value`.
+    ///
+    /// `None` stands for a key that is absent or marked for deletion.
+    /// On mismatch, the state is left untouched and the current value is returned in the error.
+    /// Writes that [`NodeState::set`] would drop, to reserved keys or beyond the
+    /// [`NodeStateLimits`], fail too. The values of sealed keys are compared in the clear, see
+    /// [`crate::SealedKeys`].
+    pub fn compare_and_set<V: ToString>(
+        &mut self,
+        key: &str,
+        expected_value: Option<&str>,
+        new_value: V,
+    ) -> Result<(), CompareAndSetError> {
+        if is_reserved_key(key) {
+            warn!(key = %key, "reserved-key-write-rejected");
+            return Err(CompareAndSetError::ReservedKey {
+                key: key.to_string(),
+            });
+        }
+        let current_value = self
+            .get_versioned(key)
+            .filter(|versioned_value| !versioned_value.marked_for_deletion)
+            .map(|versioned_value| self.unseal_value(key, &versioned_value.value));
+        if current_value.as_deref() != expected_value {
+            return Err(CompareAndSetError::ValueMismatch {
+                key: key.to_string(),
+                current_value,
+            });
+        }
+        match self.try_set_with_source(
+            key.to_string(),
+            new_value.to_string(),
+            WriteSource::Application,
+        ) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(CompareAndSetError::LimitExceeded {
+                key: key.to_string(),
+            }),
+            Err(error) => Err(CompareAndSetError::State {
+                key: key.to_string(),
+                error,
+            }),
+        }
     }
 
-    pub(crate) fn apply_delta(&mut self, delta: Delta) {
-        // Remove nodes to reset.
-        self.node_states
-            .retain(|node_id, _| !delta.nodes_to_reset.contains(node_id));
-        // And apply delta.
-        for (node_id, node_delta) in delta.node_deltas {
-            let mut node_state_map = self
-                .node_states
-                .entry(node_id)
-                .or_insert_with(NodeState::default);
+    /// Sets new values for a group of keys, all sharing a single new version.
+    ///
+    /// The group is never split across deltas, so that peers never observe
+    /// only a part of the batch. If a key appears several times, the last value wins.
+    ///
+    /// If the batch exceeds the [`NodeStateLimits`] under the reject policy, it is rejected as
+    /// a whole.
+    pub fn set_batch<K: ToString, V: ToString>(
+        &mut self,
+        key_values: impl IntoIterator<Item = (K, V)>,
+    ) {
+        let key_values: BTreeMap<String, String> = key_values
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        if let Some(key) = key_values.keys().find(|key| is_reserved_key(key)) {
+            warn!(key = %key, "reserved-key-write-rejected");
+            return;
+        }
+        match self.try_set_batch(key_values) {
+            // Already reported, and counted, when the batch was rejected.
+            Err(StateError::LimitExceeded { .. }) => {}
+            result => log_state_error(result),
+        }
+    }
 
-            for (key, versioned_value) in node_delta.key_values {
-                node_state_map.max_version =
-                    node_state_map.max_version.max(versioned_value.version);
-                let entry = node_state_map.key_values.entry(key);
-                match entry {
-                    Entry::Occupied(mut record) => {
-                        if record.get().version >= versioned_value.version {
-                            // Due to the message passing being totally asynchronous, it is not an
-                            // error to receive updates that are already obsolete.
-                            continue;
-                        }
-                        record.insert(versioned_value);
-                    }
-                    Entry::Vacant(vacant) => {
-                        vacant.insert(versioned_value);
-                    }
-                }
-            }
+    fn try_set_batch(&mut self, key_values: BTreeMap<String, String>) -> Result<(), StateError> {
+        if key_values.is_empty() {
+            return Ok(());
+        }
+        let key_values: BTreeMap<String, String> = key_values
+            .into_iter()
+            .map(|(key, value)| {
+                let value = self.seal_value(&key, value);
+                (key, value)
+            })
+            .collect();
+        let writes: BTreeMap<&str, usize> = key_values
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.len()))
+            .collect();
+        // Fail before evicting any key-value to make room.
+        self.next_version()?;
+        if !self.make_room(&writes, true) {
+            return Err(StateError::LimitExceeded {
+                keys: writes.keys().map(|key| key.to_string()).collect(),
+            });
+        }
+        let new_version = self.next_version()?;
+        self.max_version = new_version;
+        for (key, value) in key_values {
+            self.num_writes_since_compaction += 1;
+            self.key_values.insert(
+                intern_key(&key),
+                VersionedValue {
+                    version: new_version,
+                    value,
+                    marked_for_deletion: false,
+                },
+            );
+            self.record_write_source(key, new_version, WriteSource::Application);
+        }
+        self.stamp_write(new_version);
+        Ok(())
+    }
 
-            node_state_map.last_heartbeat = Instant::now();
+    pub fn mark_for_deletion(&mut self, key: &str) {
+        if is_reserved_key(key) {
+            warn!(key = %key, "reserved-key-write-rejected");
+            return;
         }
+        log_state_error(self.try_mark_for_deletion(key));
     }
 
-    pub fn compute_digest(&self, dead_nodes: &HashSet<&NodeId>) -> Digest {
-        Digest {
-            node_max_version: self
-                .node_states
-                .iter()
-                .filter(|(node_id, _)| !dead_nodes.contains(node_id))
-                .map(|(node_id, node_state)| (node_id.clone(), node_state.max_version))
-                .collect(),
+    fn try_mark_for_deletion(&mut self, key: &str) -> Result<(), StateError> {
+        let new_version = self.next_version()?;
+        self.max_version = new_version;
+        if let Some(mut versioned_value) = self.key_values.get(key).cloned() {
+            versioned_value.marked_for_deletion = true;
+            versioned_value.version = new_version;
+            self.key_values.insert(intern_key(key), versioned_value);
         }
+        Ok(())
     }
 
-    pub fn gc_keys_marked_for_deletion(
-        &mut self,
-        marked_for_deletion_grace_period: usize,
-        dead_nodes: &HashSet<NodeId>,
-    ) {
-        for (node_id, node_state_map) in &mut self.node_states {
-            if dead_nodes.contains(node_id) {
-                continue;
-            }
-            node_state_map.gc_keys_marked_for_deletion(marked_for_deletion_grace_period);
+    /// Removes a key, returning its value if it was present.
+    ///
+    /// Contrary to `mark_for_deletion`, a tombstone is created even if the key is absent,
+    /// so that the removal is propagated to peers that may still know about the key.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        if is_reserved_key(key) {
+            warn!(key = %key, "reserved-key-write-rejected");
+            return None;
         }
+        log_state_error(self.remove_with_source(key, WriteSource::Application))
     }
 
-    /// Implements the scuttlebutt reconciliation with the scuttle-depth ordering.
-    pub fn compute_delta(
-        &self,
-        digest: &Digest,
-        mtu: usize,
-        dead_nodes: HashSet<&NodeId>,
-        marked_for_deletion_grace_period: usize,
-    ) -> Delta {
-        let mut delta_writer = DeltaWriter::with_mtu(mtu);
+    /// Returns the generation of the node, if it is not 0. See [`crate::NodeId::generation`].
+    pub fn generation(&self) -> Option<u64> {
+        self.live_value(GENERATION_KEY)?.parse().ok()
+    }
 
-        let mut node_sorted_by_stale_length = NodeSortedByStaleLength::default();
-        for (node_id, node_state_map) in &self.node_states {
-            if dead_nodes.contains(node_id) {
-                continue;
-            }
-            let mut floor_version = digest.node_max_version.get(node_id).cloned().unwrap_or(0);
-            // Node needs to be reset if `digest.node_max_version +
-            // marked_for_deletion_grace_period` is inferior to
-            // `node_state_map.max_version`.
-            // Note that there is no need to reset if floor_version = 0 (new node).
-            if floor_version > 0
-                && floor_version + (marked_for_deletion_grace_period as u64)
-                    < node_state_map.max_version
-            {
-                // `floor_version` is set to 0 so the delta is populated with all keys and values.
-                floor_version = 0;
-                delta_writer.add_node_to_reset(node_id.clone());
-            }
-            let stale_kv_count = node_state_map.iter_stale_key_values(floor_version).count();
-            if stale_kv_count > 0 {
-                node_sorted_by_stale_length.insert(node_id, stale_kv_count);
-            }
-        }
+    /// Returns the addresses advertised by the node, on top of its gossip address. See
+    /// [`crate::Chitchat::set_advertised_addrs`].
+    pub fn advertised_addrs(&self) -> Vec<SocketAddr> {
+        let Some(advertised_addrs) = self.live_value(ADVERTISED_ADDRS_KEY) else {
+            return Vec::new();
+        };
+        advertised_addrs
+            .split(',')
+            .filter_map(|addr| addr.parse().ok())
+            .collect()
+    }
 
-        for node_id in node_sorted_by_stale_length.into_iter() {
-            if !delta_writer.add_node(node_id.clone()) {
-                break;
-            }
-            let node_state_map = self.node_states.get(node_id).unwrap();
-            let mut floor_version = digest.node_max_version.get(node_id).cloned().unwrap_or(0);
-            if node_state_map.max_version
-                > floor_version + (marked_for_deletion_grace_period as u64)
-            {
-                floor_version = 0;
-            }
-            let mut stale_kvs: Vec<(&str, &VersionedValue)> = node_state_map
-                .iter_stale_key_values(floor_version)
-                .collect();
+    /// Returns true if the node announced that it is about to leave the cluster. See
+    /// [`crate::Chitchat::set_leave_intent`].
+    pub fn has_leave_intent(&self) -> bool {
+        self.live_value(LEAVE_INTENT_KEY).is_some()
+    }
 
-            assert!(!stale_kvs.is_empty());
-            stale_kvs.sort_unstable_by_key(|(_, record)| record.version);
-            for (key, versioned_value) in stale_kvs {
-                if !delta_writer.add_kv(key, versioned_value.clone()) {
-                    let delta: Delta = delta_writer.into();
-                    return delta;
-                }
+    /// Returns the epoch of the last leadership of the node, if it was ever elected. See
+    /// [`crate::Chitchat::current_leader`].
+    pub fn leader_epoch(&self) -> Option<u64> {
+        self.live_value(LEADER_EPOCH_KEY)?.parse().ok()
+    }
+
+    /// Returns the metadata of the node, if it advertises metadata that can be decoded. See
+    /// [`crate::Chitchat::set_node_metadata`].
+    #[cfg(feature = "json")]
+    pub fn metadata(&self) -> Option<NodeMetadata> {
+        let versioned_value = self
+            .get_versioned(METADATA_KEY)
+            .filter(|versioned_value| !versioned_value.marked_for_deletion)?;
+        match serde_json::from_str::<NodeMetadata>(&versioned_value.value) {
+            Ok(metadata) => Some(NodeMetadata {
+                version: versioned_value.version,
+                ..metadata
+            }),
+            Err(error) => {
+                warn!(error = %error, "failed-to-decode-node-metadata");
+                None
             }
         }
-        delta_writer.into()
     }
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ClusterStateSnapshot {
-    pub seed_addrs: HashSet<SocketAddr>,
-    pub node_states: BTreeMap<String, NodeState>,
-}
 
-impl<'a> From<&'a ClusterState> for ClusterStateSnapshot {
-    fn from(state: &'a ClusterState) -> Self {
-        ClusterStateSnapshot {
-            seed_addrs: state.seed_addrs(),
-            node_states: state
-                .node_states
-                .iter()
-                .map(|(node_id, node_state)| (node_id.id.clone(), node_state.clone()))
-                .collect(),
-        }
+    #[cfg(feature = "json")]
+    pub(crate) fn set_metadata(&mut self, metadata: &NodeMetadata) {
+        let value = serde_json::to_string(metadata).expect("node metadata should be serializable");
+        self.set_with_source(METADATA_KEY, value, WriteSource::Internal);
     }
-}
 
-#[derive(Default)]
-struct NodeSortedByStaleLength<'a> {
-    node_per_stale_length: BTreeMap<usize, Vec<&'a NodeId>>,
-    stale_lengths: BinaryHeap<usize>,
-}
+    /// Returns true if the metadata of the node changed after `floor_version`.
+    fn has_stale_metadata(&self, floor_version: Version) -> bool {
+        self.get_versioned(METADATA_KEY)
+            .is_some_and(|versioned_value| versioned_value.version > floor_version)
+    }
 
-impl<'a> NodeSortedByStaleLength<'a> {
-    fn insert(&mut self, node_id: &'a NodeId, stale_length: usize) {
-        self.node_per_stale_length
-            .entry(stale_length)
-            .or_insert_with(|| {
-                self.stale_lengths.push(stale_length);
-                Vec::new()
-            })
-            .push(node_id);
+    pub(crate) fn set_generation(&mut self, generation: u64) {
+        self.set_with_source(GENERATION_KEY, generation, WriteSource::Internal);
     }
 
-    fn into_iter(mut self) -> impl Iterator<Item = &'a NodeId> {
-        let mut rng = random_generator();
-        std::iter::from_fn(move || self.stale_lengths.pop()).flat_map(move |length| {
-            let mut nodes = self.node_per_stale_length.remove(&length).unwrap();
-            nodes.shuffle(&mut rng);
-            nodes.into_iter()
-        })
+    pub(crate) fn set_leader_epoch(&mut self, epoch: u64) {
+        self.set_with_source(LEADER_EPOCH_KEY, epoch, WriteSource::Internal);
     }
-}
 
-#[cfg(not(test))]
+    pub(crate) fn set_advertised_addrs(&mut self, addrs: &[SocketAddr]) {
+        let advertised_addrs: Vec<String> = addrs.iter().map(SocketAddr::to_string).collect();
+        self.set_with_source(
+            ADVERTISED_ADDRS_KEY,
+            advertised_addrs.join(","),
+            WriteSource::Internal,
+        );
+    }
+
+    pub(crate) fn set_leave_intent(&mut self, leave_intent: bool) {
+        if leave_intent {
+            self.set_with_source(LEAVE_INTENT_KEY, true, WriteSource::Internal);
+        } else if self.has_leave_intent() {
+            log_state_error(self.remove_with_source(LEAVE_INTENT_KEY, WriteSource::Internal));
+        }
+    }
+
+    fn live_value(&self, key: &str) -> Option<&str> {
+        self.get_versioned(key)
+            .filter(|versioned_value| !versioned_value.marked_for_deletion)
+            .map(|versioned_value| versioned_value.value.as_str())
+    }
+
+    fn remove_with_source(
+        &mut self,
+        key: &str,
+        source: WriteSource,
+    ) -> Result<Option<String>, StateError> {
+        let new_version = self.next_version()?;
+        self.max_version = new_version;
+        let tombstone = VersionedValue {
+            value: String::new(),
+            version: new_version,
+            marked_for_deletion: true,
+        };
+        let previous_value = self.key_values.insert(intern_key(key), tombstone);
+        self.num_writes_since_compaction += 1;
+        self.record_write_source(key.to_string(), new_version, source);
+        Ok(previous_value
+            .filter(|versioned_value| !versioned_value.marked_for_deletion)
+            .map(|versioned_value| versioned_value.value))
+    }
+
+    // Remove keys marked for deletion and with `version + grace_period < max_version`.
+    pub fn gc_keys_marked_for_deletion(&mut self, grace_period: usize) {
+        self.gc_keys_marked_for_deletion_matching(grace_period, |_| true);
+    }
+
+    fn gc_keys_marked_for_deletion_matching(
+        &mut self,
+        grace_period: usize,
+        key_predicate: impl Fn(&str) -> bool,
+    ) {
+        let max_version = self.max_version;
+        self.gc_tombstones(|key, versioned_value| {
+            versioned_value.version + (grace_period as u64) < max_version && key_predicate(key)
+        });
+    }
+
+    /// Removes the keys marked for deletion for at least `grace_period`.
+    ///
+    /// A tombstone is deemed deleted from the first time this method sees it, so that the
+    /// tombstones received from peers get the full grace period.
+    pub fn gc_expired_tombstones(&mut self, grace_period: Duration) {
+        let now = Instant::now();
+        let mut deletion_timestamps = std::mem::take(&mut self.deletion_timestamps);
+        self.gc_tombstones(|key, versioned_value| {
+            let (version, deleted_at) = deletion_timestamps
+                .entry(key.to_string())
+                .or_insert((versioned_value.version, now));
+            // The key was deleted again since we last saw it.
+            if *version != versioned_value.version {
+                *version = versioned_value.version;
+                *deleted_at = now;
+            }
+            now.duration_since(*deleted_at) >= grace_period
+        });
+        let key_values = &self.key_values;
+        deletion_timestamps.retain(|key, (version, _)| {
+            key_values.get(key.as_str()).is_some_and(|versioned_value| {
+                versioned_value.marked_for_deletion && versioned_value.version == *version
+            })
+        });
+        self.deletion_timestamps = deletion_timestamps;
+    }
+
+    fn has_tombstones(&self) -> bool {
+        self.key_values
+            .values()
+            .any(|versioned_value| versioned_value.marked_for_deletion)
+    }
+
+    /// Removes the keys marked for deletion for which `is_expired` returns true.
+    fn gc_tombstones(&mut self, mut is_expired: impl FnMut(&str, &VersionedValue) -> bool) {
+        let num_key_values = self.key_values.len();
+        let mut gc_watermark = self.gc_watermark;
+        self.key_values.retain(|key, versioned_value| {
+            let is_gced = versioned_value.marked_for_deletion && is_expired(key, versioned_value);
+            if is_gced {
+                gc_watermark = gc_watermark.max(versioned_value.version);
+            }
+            !is_gced
+        });
+        self.gc_watermark = gc_watermark;
+        self.num_writes_since_compaction += num_key_values - self.key_values.len();
+        let key_values = &self.key_values;
+        self.write_sources
+            .retain(|key, _| key_values.contains_key(key.as_str()));
+    }
+
+    /// Makes room for the given writes, each given as a key and the length of its new value,
+    /// according to the limits of the node state.
+    ///
+    /// Evicted key-values of the local node are removed, so that the eviction is gossiped.
+    /// Returns false if the writes must be rejected.
+    fn make_room(&mut self, writes: &BTreeMap<&str, usize>, is_local: bool) -> bool {
+        if self.limits.is_unlimited() {
+            return true;
+        }
+        let (mut num_keys, mut num_bytes) = self.live_usage();
+        for (key, value_len) in writes {
+            if is_exempt_from_limits(key) {
+                continue;
+            }
+            match self.get_versioned(key) {
+                Some(versioned_value) if !versioned_value.marked_for_deletion => {
+                    num_bytes = num_bytes + value_len - versioned_value.value.len();
+                }
+                _ => {
+                    num_keys += 1;
+                    num_bytes += key.len() + value_len;
+                }
+            }
+        }
+        if self.limits.allows(num_keys, num_bytes) {
+            return true;
+        }
+        let mut keys_to_evict = Vec::new();
+        if self.limits.policy == NodeStateLimitPolicy::EvictOldest {
+            let mut candidates: Vec<(&Arc<str>, &VersionedValue)> = self
+                .live_key_values()
+                .filter(|(key, _)| !writes.contains_key(&***key))
+                .collect();
+            candidates.sort_by_key(|(_, versioned_value)| versioned_value.version);
+            for (key, versioned_value) in candidates {
+                if self.limits.allows(num_keys, num_bytes) {
+                    break;
+                }
+                num_keys -= 1;
+                num_bytes -= key.len() + versioned_value.value.len();
+                keys_to_evict.push(key.clone());
+            }
+        }
+        if !self.limits.allows(num_keys, num_bytes) {
+            warn!(
+                keys = ?writes.keys().collect::<Vec<_>>(),
+                limits = ?self.limits,
+                "rejecting-key-values-over-limits"
+            );
+            self.num_rejected_key_values += writes.len() as u64;
+            return false;
+        }
+        warn!(keys = ?keys_to_evict, limits = ?self.limits, "evicting-key-values-over-limits");
+        self.num_evicted_key_values += keys_to_evict.len() as u64;
+        for key in keys_to_evict {
+            if is_local {
+                log_state_error(self.remove_with_source(&key, WriteSource::Internal));
+            } else {
+                self.key_values.remove(&key);
+                self.num_writes_since_compaction += 1;
+            }
+        }
+        true
+    }
+
+    /// Returns the live key-values subject to the limits.
+    fn live_key_values(&self) -> impl Iterator<Item = (&Arc<str>, &VersionedValue)> {
+        self.key_values.iter().filter(|(key, versioned_value)| {
+            !versioned_value.marked_for_deletion && !is_exempt_from_limits(key)
+        })
+    }
+
+    /// Returns the number of keys and bytes accounted for by the limits.
+    fn live_usage(&self) -> (usize, usize) {
+        self.live_key_values()
+            .fold((0, 0), |(num_keys, num_bytes), (key, versioned_value)| {
+                (
+                    num_keys + 1,
+                    num_bytes + key.len() + versioned_value.value.len(),
+                )
+            })
+    }
+
+    /// Returns the number of key-values rejected to enforce the limits of the node state.
+    pub fn num_rejected_key_values(&self) -> u64 {
+        self.num_rejected_key_values
+    }
+
+    /// Returns the number of key-values evicted to enforce the limits of the node state.
+    pub fn num_evicted_key_values(&self) -> u64 {
+        self.num_evicted_key_values
+    }
+
+    /// Returns the number of key-values inserted, overwritten or removed since the last
+    /// compaction.
+    pub fn num_writes_since_compaction(&self) -> usize {
+        self.num_writes_since_compaction
+    }
+
+    /// Rebuilds the key-values of the node state, to release the memory left over by
+    /// churn. Returns the number of bytes reclaimed on value strings.
+    ///
+    /// The memory reclaimed by rebuilding the map itself is not accounted for. Keys are
+    /// interned, and shared with the other node states: they are left as is.
+    pub fn compact(&mut self) -> usize {
+        let mut num_reclaimed_bytes = 0;
+        let key_values = self.key_values.take();
+        self.key_values = key_values
+            .into_iter()
+            .map(|(key, mut versioned_value)| {
+                num_reclaimed_bytes +=
+                    versioned_value.value.capacity() - versioned_value.value.len();
+                versioned_value.value = versioned_value.value.as_str().to_string();
+                (key, versioned_value)
+            })
+            .collect();
+        self.num_writes_since_compaction = 0;
+        num_reclaimed_bytes
+    }
+
+    /// Returns the contribution of this node to the counter `name`. A missing, deleted, or
+    /// malformed counter counts as zero.
+    ///
+    /// See [`ClusterState::counter_value`] for the cluster-wide value of the counter.
+    pub fn counter(&self, name: &str) -> PnCounter {
+        self.get_versioned(&counter_key(name))
+            .filter(|versioned_value| !versioned_value.marked_for_deletion)
+            .and_then(|versioned_value| versioned_value.value.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Adds `amount` to the contribution of this node to the counter `name`.
+    pub fn increment_counter(&mut self, name: &str, amount: u64) {
+        let mut counter = self.counter(name);
+        counter.increments = counter.increments.saturating_add(amount);
+        self.set(counter_key(name), counter);
+    }
+
+    /// Subtracts `amount` from the contribution of this node to the counter `name`.
+    pub fn decrement_counter(&mut self, name: &str, amount: u64) {
+        let mut counter = self.counter(name);
+        counter.decrements = counter.decrements.saturating_add(amount);
+        self.set(counter_key(name), counter);
+    }
+
+    /// Returns the observed-remove set `name` of this node.
+    #[cfg(feature = "json")]
+    pub fn or_set(&self, name: &str) -> OrSet {
+        self.get_versioned(&or_set_key(name))
+            .filter(|versioned_value| !versioned_value.marked_for_deletion)
+            .and_then(|versioned_value| OrSet::decode(&versioned_value.value))
+            .unwrap_or_default()
+    }
+
+    /// Adds an element to the observed-remove set `name`.
+    #[cfg(feature = "json")]
+    pub fn add_to_or_set(&mut self, name: &str, element: &str) {
+        let mut or_set = self.or_set(name);
+        // The version of the write is used as the unique tag of the addition.
+        or_set.insert(element, self.max_version.saturating_add(1));
+        self.set(or_set_key(name), or_set.encode());
+    }
+
+    /// Removes an element from the observed-remove set `name`.
+    /// Returns false if the element was not in the set.
+    #[cfg(feature = "json")]
+    pub fn remove_from_or_set(&mut self, name: &str, element: &str) -> bool {
+        let mut or_set = self.or_set(name);
+        if !or_set.remove(element) {
+            return false;
+        }
+        self.set(or_set_key(name), or_set.encode());
+        true
+    }
+
+    /// Returns a view of the node state restricted to the keys of the given namespace.
+    ///
+    /// Keys are transparently prefixed with `{namespace}:`, so that several subsystems can
+    /// share a node state without colliding.
+    pub fn scope(&mut self, namespace: &str) -> ScopedNodeState<'_> {
+        ScopedNodeState {
+            node_state: self,
+            prefix: format!("{namespace}:"),
+        }
+    }
+
+    fn set_with_version(
+        &mut self,
+        key: String,
+        value: String,
+        version: Version,
+    ) -> Result<(), StateError> {
+        if version <= self.max_version {
+            return Err(StateError::NonIncreasingVersion {
+                key,
+                version,
+                max_version: self.max_version,
+            });
+        }
+        self.max_version = version;
+        self.num_writes_since_compaction += 1;
+        self.key_values.insert(
+            intern_key(&key),
+            VersionedValue {
+                version,
+                value,
+                marked_for_deletion: false,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// View of a node state restricted to the keys of a namespace.
+///
+/// See [`NodeState::scope`]. Keys passed to and returned by the view are relative to
+/// the namespace.
+pub struct ScopedNodeState<'a> {
+    node_state: &'a mut NodeState,
+    prefix: String,
+}
+
+impl<'a> ScopedNodeState<'a> {
+    pub fn namespace(&self) -> &str {
+        &self.prefix[..self.prefix.len() - 1]
+    }
+
+    fn full_key(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.node_state.get(&self.full_key(key))
+    }
+
+    pub fn get_versioned(&self, key: &str) -> Option<&VersionedValue> {
+        self.node_state.get_versioned(&self.full_key(key))
+    }
+
+    /// See [`NodeState::set`].
+    pub fn set<K: ToString, V: ToString>(&mut self, key: K, value: V) {
+        let full_key = self.full_key(&key.to_string());
+        self.node_state.set(full_key, value);
+    }
+
+    /// See [`NodeState::set_with_ttl`].
+    pub fn set_with_ttl<K: ToString, V: ToString>(&mut self, key: K, value: V, ttl: Duration) {
+        let full_key = self.full_key(&key.to_string());
+        self.node_state.set_with_ttl(full_key, value, ttl);
+    }
+
+    /// See [`NodeState::compare_and_set`]. The key of the error is relative to the namespace.
+    pub fn compare_and_set<V: ToString>(
+        &mut self,
+        key: &str,
+        expected_value: Option<&str>,
+        new_value: V,
+    ) -> Result<(), CompareAndSetError> {
+        let full_key = self.full_key(key);
+        self.node_state
+            .compare_and_set(&full_key, expected_value, new_value)
+            .map_err(|error| error.with_key(key))
+    }
+
+    pub fn mark_for_deletion(&mut self, key: &str) {
+        let full_key = self.full_key(key);
+        self.node_state.mark_for_deletion(&full_key);
+    }
+
+    /// See [`NodeState::remove`].
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let full_key = self.full_key(key);
+        self.node_state.remove(&full_key)
+    }
+
+    /// Returns an iterator over the key-values of the namespace.
+    /// Keys marked for deletion are not returned.
+    pub fn iter_key_values(&self) -> impl Iterator<Item = (&str, &VersionedValue)> {
+        let prefix = self.prefix.as_str();
+        self.node_state
+            .key_values
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(move |(key, _)| key.starts_with(prefix))
+            .filter(|(_, versioned_value)| !versioned_value.marked_for_deletion)
+            .map(move |(key, versioned_value)| (&key[prefix.len()..], versioned_value))
+    }
+
+    /// Returns the number of keys of the namespace, not counting keys marked for deletion.
+    pub fn num_keys(&self) -> usize {
+        self.iter_key_values().count()
+    }
+
+    /// Same as [`NodeState::gc_keys_marked_for_deletion`], restricted to the namespace.
+    pub fn gc_keys_marked_for_deletion(&mut self, grace_period: usize) {
+        let prefix = self.prefix.as_str();
+        self.node_state
+            .gc_keys_marked_for_deletion_matching(grace_period, |key| key.starts_with(prefix));
+    }
+}
+
+/// Error returned by [`NodeState::compare_and_set`] when the new value was not written.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CompareAndSetError {
+    /// The current value of the key is not the expected one.
+    ValueMismatch {
+        key: String,
+        /// Value of the key at the time of the call, `None` if the key was absent.
+        current_value: Option<String>,
+    },
+    /// The key is reserved for the internal use of chitchat.
+    ReservedKey { key: String },
+    /// The write was rejected to enforce the [`NodeStateLimits`].
+    LimitExceeded { key: String },
+    /// The write would have broken an invariant of the node state.
+    State { key: String, error: StateError },
+}
+
+impl CompareAndSetError {
+    pub fn key(&self) -> &str {
+        match self {
+            CompareAndSetError::ValueMismatch { key, .. }
+            | CompareAndSetError::ReservedKey { key }
+            | CompareAndSetError::LimitExceeded { key }
+            | CompareAndSetError::State { key, .. } => key,
+        }
+    }
+
+    /// Returns the current value of the key on mismatch, `None` if the key was absent or for
+    /// the other errors.
+    pub fn current_value(&self) -> Option<&str> {
+        match self {
+            CompareAndSetError::ValueMismatch { current_value, .. } => current_value.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn with_key(self, new_key: &str) -> Self {
+        let new_key = new_key.to_string();
+        match self {
+            CompareAndSetError::ValueMismatch { current_value, .. } => {
+                CompareAndSetError::ValueMismatch {
+                    key: new_key,
+                    current_value,
+                }
+            }
+            CompareAndSetError::ReservedKey { .. } => {
+                CompareAndSetError::ReservedKey { key: new_key }
+            }
+            CompareAndSetError::LimitExceeded { .. } => {
+                CompareAndSetError::LimitExceeded { key: new_key }
+            }
+            CompareAndSetError::State { error, .. } => CompareAndSetError::State {
+                key: new_key,
+                error,
+            },
+        }
+    }
+}
+
+impl fmt::Display for CompareAndSetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompareAndSetError::ValueMismatch {
+                key,
+                current_value: Some(current_value),
+            } => write!(
+                f,
+                "unexpected value for key `{key}`: current value is `{current_value}`"
+            ),
+            CompareAndSetError::ValueMismatch {
+                key,
+                current_value: None,
+            } => write!(f, "unexpected value for key `{key}`: key is absent"),
+            CompareAndSetError::ReservedKey { key } => write!(f, "key `{key}` is reserved"),
+            CompareAndSetError::LimitExceeded { key } => {
+                write!(f, "cannot write key `{key}`: node state limits exceeded")
+            }
+            CompareAndSetError::State { key, error } => {
+                write!(f, "cannot write key `{key}`: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompareAndSetError {}
+
+/// Error returned by the internal mutations of a node state that would break its invariants.
+///
+/// Public writers log and drop these errors, so that a logic error never takes down the
+/// gossip task.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StateError {
+    /// A key-value was about to be written with a version not greater than the max version
+    /// of the node state.
+    NonIncreasingVersion {
+        key: String,
+        version: Version,
+        max_version: Version,
+    },
+    /// The max version of the node state reached `u64::MAX`, leaving no version for new
+    /// writes.
+    VersionOverflow,
+    /// A batch of key-values was rejected as a whole to enforce the [`NodeStateLimits`].
+    LimitExceeded { keys: Vec<String> },
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NonIncreasingVersion {
+                key,
+                version,
+                max_version,
+            } => write!(
+                f,
+                "cannot write key `{key}` with version {version}: max version is {max_version}"
+            ),
+            StateError::VersionOverflow => write!(f, "max version of the node state overflowed"),
+            StateError::LimitExceeded { keys } => {
+                write!(f, "cannot write keys {keys:?}: node state limits exceeded")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Versions above this one are considered about to overflow: the node deltas carrying them are
+/// dropped.
+const MAX_SAFE_VERSION: Version = u64::MAX - u32::MAX as u64;
+
+/// Maximum number of nodes whose oversized key-values are remembered as reported.
+const MAX_NUM_REPORTED_OVERSIZED_KEY_VALUES: usize = 1_024;
+
+/// Maximum number of unknown nodes whose max version advertised in a digest is remembered.
+const MAX_NUM_ADVERTISED_MAX_VERSIONS: usize = 65_536;
+
+/// Anomaly detected in the versions advertised for a node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VersionAnomaly {
+    /// The node advertised a max version lower than the one known locally, typically because
+    /// it was restored from an old backup without bumping its generation.
+    Rollback {
+        node_id: NodeId,
+        known_max_version: Version,
+        advertised_max_version: Version,
+    },
+    /// A delta carried a version of the node close to `u64::MAX`.
+    Overflow { node_id: NodeId, version: Version },
+    /// A delta carried a version of the node exceeding the max version known locally by more
+    /// than the `max_version_jump` of the [`crate::ChitchatConfig`] per heartbeat elapsed since
+    /// that max version was reached. For a node seen for the first time, `known_max_version` is
+    /// the max version advertised for it in the digest of the peer.
+    Jump {
+        node_id: NodeId,
+        known_max_version: Version,
+        version: Version,
+    },
+}
+
+impl VersionAnomaly {
+    pub fn node_id(&self) -> &NodeId {
+        match self {
+            VersionAnomaly::Rollback { node_id, .. }
+            | VersionAnomaly::Overflow { node_id, .. }
+            | VersionAnomaly::Jump { node_id, .. } => node_id,
+        }
+    }
+
+    /// Returns true if the state of the node is to be reset. Deltas carrying implausible versions
+    /// are dropped instead, so that a peer relaying them cannot wipe the state of a node.
+    pub(crate) fn requires_reset(&self) -> bool {
+        matches!(self, VersionAnomaly::Rollback { .. })
+    }
+}
+
+/// Highest max version known for a node, along with the heartbeat of the node when it was
+/// reached. Kept across the resets of the node, so that the versions of the delta following a
+/// reset are still checked.
+#[derive(Clone, Copy, Debug)]
+struct VersionFloor {
+    max_version: Version,
+    heartbeat: u64,
+}
+
+pub(crate) fn unix_timestamp_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Logs and drops the error of an internal mutation of a node state.
+pub(crate) fn log_state_error<T: Default>(result: Result<T, StateError>) -> T {
+    result.unwrap_or_else(|error| {
+        error!(error = %error, "node-state-mutation-failed");
+        T::default()
+    })
+}
+
+/// Error returned by [`NodeState::get_typed`] and [`NodeState::set_typed`].
+#[cfg(feature = "json")]
+#[derive(Debug)]
+pub enum TypedValueError {
+    /// The value could not be encoded to JSON.
+    Encode {
+        key: String,
+        source: serde_json::Error,
+    },
+    /// The value stored for the key is not a valid JSON representation of the requested type.
+    Decode {
+        key: String,
+        source: serde_json::Error,
+    },
+}
+
+#[cfg(feature = "json")]
+impl fmt::Display for TypedValueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypedValueError::Encode { key, source } => {
+                write!(f, "failed to encode value for key `{key}`: {source}")
+            }
+            TypedValueError::Decode { key, source } => {
+                write!(f, "failed to decode value for key `{key}`: {source}")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl std::error::Error for TypedValueError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TypedValueError::Encode { source, .. } | TypedValueError::Decode { source, .. } => {
+                Some(source)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ClusterState {
+    /// States of the nodes, copied on write so that snapshots only clone pointers.
+    pub node_states: BTreeMap<NodeId, Arc<NodeState>>,
+    seed_addrs: watch::Receiver<HashSet<SocketAddr>>,
+    pub(crate) node_state_limits: NodeStateLimits,
+    pub(crate) tombstone_gc_policy: TombstoneGcPolicy,
+    pub(crate) delta_interceptor: Option<Box<dyn DeltaInterceptor>>,
+    pub(crate) write_acl: Option<WriteAcl>,
+    pub(crate) max_version_jump: Option<Version>,
+    version_floors: HashMap<NodeId, VersionFloor>,
+    /// Max versions advertised in the digests of peers for the nodes unknown locally. They bound
+    /// the first delta received for these nodes.
+    advertised_max_versions: HashMap<NodeId, Version>,
+    pub(crate) max_cluster_state_bytes: Option<usize>,
+    num_unauthorized_key_values: u64,
+    num_refused_nodes: u64,
+    revision: u64,
+    digest_cache: Mutex<DigestCache>,
+    /// Version of the last oversized key-value reported for each node. The same key-value is
+    /// left out of every delta computed until it gets overwritten: it is only reported once.
+    reported_oversized_key_values: Mutex<HashMap<NodeId, Version>>,
+}
+
+#[cfg(test)]
+impl Default for ClusterState {
+    fn default() -> Self {
+        let (_seed_addrs_tx, seed_addrs_rx) = watch::channel(Default::default());
+        Self {
+            node_states: Default::default(),
+            seed_addrs: seed_addrs_rx,
+            node_state_limits: NodeStateLimits::default(),
+            tombstone_gc_policy: TombstoneGcPolicy::default(),
+            delta_interceptor: None,
+            write_acl: None,
+            max_version_jump: None,
+            version_floors: HashMap::new(),
+            advertised_max_versions: HashMap::new(),
+            max_cluster_state_bytes: None,
+            num_unauthorized_key_values: 0,
+            num_refused_nodes: 0,
+            revision: 0,
+            digest_cache: Mutex::default(),
+            reported_oversized_key_values: Mutex::default(),
+        }
+    }
+}
+
+impl ClusterState {
+    pub fn with_seed_addrs(seed_addrs: watch::Receiver<HashSet<SocketAddr>>) -> ClusterState {
+        ClusterState {
+            seed_addrs,
+            node_states: BTreeMap::new(),
+            node_state_limits: NodeStateLimits::default(),
+            tombstone_gc_policy: TombstoneGcPolicy::default(),
+            delta_interceptor: None,
+            write_acl: None,
+            max_version_jump: None,
+            version_floors: HashMap::new(),
+            advertised_max_versions: HashMap::new(),
+            max_cluster_state_bytes: None,
+            num_unauthorized_key_values: 0,
+            num_refused_nodes: 0,
+            revision: 0,
+            digest_cache: Mutex::default(),
+            reported_oversized_key_values: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn node_state_mut(&mut self, node_id: &NodeId) -> &mut NodeState {
+        // The node state may be modified by the caller.
+        self.revision += 1;
+        self.mark_digest_stale(node_id);
+        // TODO use the `hash_raw_entry` feature once it gets stabilized.
+        let node_state_limits = self.node_state_limits;
+        let node_state = self
+            .node_states
+            .entry(node_id.clone())
+            .or_insert_with(|| Arc::new(NodeState::with_limits(node_state_limits)));
+        Arc::make_mut(node_state)
+    }
+
+    pub fn node_state(&self, node_id: &NodeId) -> Option<&NodeState> {
+        self.node_states.get(node_id).map(Arc::as_ref)
+    }
+
+    /// Returns the metadata of the node `node_id`. See [`NodeState::metadata`].
+    #[cfg(feature = "json")]
+    pub fn node_metadata(&self, node_id: &NodeId) -> Option<NodeMetadata> {
+        self.node_state(node_id)?.metadata()
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.node_states.keys()
+    }
+
+    pub fn seed_addrs(&self) -> HashSet<SocketAddr> {
+        self.seed_addrs.borrow().clone()
+    }
+
+    /// Returns the time elapsed since the last heartbeat of each node.
+    ///
+    /// See [`NodeState::time_since_heartbeat`]. Applications can build their own staleness
+    /// heuristics on top of this report, independently from the failure detector.
+    pub fn liveness_report(&self) -> BTreeMap<NodeId, Duration> {
+        self.node_states
+            .iter()
+            .map(|(node_id, node_state)| (node_id.clone(), node_state.time_since_heartbeat()))
+            .collect()
+    }
+
+    /// Returns the approximate memory used by the key-values of all the node states.
+    ///
+    /// See [`MemoryUsage`].
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.node_states
+            .values()
+            .map(|node_state| node_state.key_values.memory_usage())
+            .sum()
+    }
+
+    /// Returns true if the memory used by the key-values exceeds the `max_cluster_state_bytes`.
+    pub(crate) fn exceeds_memory_limit(&self) -> bool {
+        self.max_cluster_state_bytes
+            .is_some_and(|max_num_bytes| self.memory_usage().num_bytes() > max_num_bytes)
+    }
+
+    /// Returns a counter incremented whenever the node states may have changed.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Removes a key from the state of a node, returning its value if it was present.
+    ///
+    /// See [`NodeState::remove`]. Returns `None` if the node is unknown.
+    pub fn remove_key(&mut self, node_id: &NodeId, key: &str) -> Option<String> {
+        self.revision += 1;
+        self.mark_digest_stale(node_id);
+        Arc::make_mut(self.node_states.get_mut(node_id)?).remove(key)
+    }
+
+    /// Records the heartbeat of a node learnt from a digest. Returns true if the heartbeat moved
+    /// forward.
+    pub(crate) fn record_digest_heartbeat(&mut self, node_id: &NodeId, heartbeat: u64) -> bool {
+        let Some(node_state) = self.node_states.get_mut(node_id) else {
+            return false;
+        };
+        // Stale heartbeats must not copy the node state.
+        if heartbeat <= node_state.heartbeat() {
+            return false;
+        }
+        Arc::make_mut(node_state).record_digest_heartbeat(heartbeat);
+        self.mark_digest_stale(node_id);
+        true
+    }
+
+    /// Increments the heartbeat of a node, typically the self node.
+    ///
+    /// Like the heartbeats learnt from digests, it does not bump the revision of the cluster
+    /// state: liveness alone does not make the snapshots of the cluster state stale.
+    pub(crate) fn increment_heartbeat(&mut self, node_id: &NodeId) {
+        let node_state_limits = self.node_state_limits;
+        let node_state = self
+            .node_states
+            .entry(node_id.clone())
+            .or_insert_with(|| Arc::new(NodeState::with_limits(node_state_limits)));
+        Arc::make_mut(node_state).increment_heartbeat();
+        self.mark_digest_stale(node_id);
+    }
+
+    /// Replaces the state of a node by one restored from a checkpoint.
+    pub(crate) fn restore_node_state(&mut self, node_id: NodeId, mut node_state: NodeState) {
+        self.revision += 1;
+        self.mark_digest_stale(&node_id);
+        node_state.limits = self.node_state_limits;
+        self.node_states.insert(node_id, Arc::new(node_state));
+    }
+
+    pub(crate) fn remove_node(&mut self, node_id: &NodeId) {
+        self.revision += 1;
+        self.mark_digest_stale(node_id);
+        self.node_states.remove(node_id);
+        self.version_floors.remove(node_id);
+        self.advertised_max_versions.remove(node_id);
+    }
+
+    /// Records the max versions advertised in the digest of a peer for the nodes unknown
+    /// locally. Only used when `max_version_jump` is set.
+    pub(crate) fn record_advertised_max_versions<'a>(
+        &mut self,
+        max_versions: impl IntoIterator<Item = (&'a NodeId, Version)>,
+    ) {
+        if self.max_version_jump.is_none() {
+            return;
+        }
+        for (node_id, max_version) in max_versions {
+            let is_known = self
+                .node_states
+                .get(node_id)
+                .is_some_and(|node_state| node_state.max_version > 0)
+                || self.version_floors.contains_key(node_id);
+            if is_known {
+                continue;
+            }
+            if self.advertised_max_versions.len() >= MAX_NUM_ADVERTISED_MAX_VERSIONS
+                && !self.advertised_max_versions.contains_key(node_id)
+            {
+                continue;
+            }
+            self.advertised_max_versions
+                .insert(node_id.clone(), max_version);
+        }
+    }
+
+    fn mark_digest_stale(&mut self, node_id: &NodeId) {
+        self.digest_cache.get_mut().unwrap().mark_stale(node_id);
+    }
+
+    /// Applies a delta received from a peer.
+    ///
+    /// The node deltas carrying versions above [`MAX_SAFE_VERSION`], or too far above the max
+    /// version known for their node, are dropped, leaving the state of their node untouched. The
+    /// corresponding anomalies are returned. When `max_version_jump` is set, the first delta of a
+    /// node cannot exceed the max version advertised for it, see
+    /// [`ClusterState::record_advertised_max_versions`].
+    pub(crate) fn apply_delta(&mut self, mut delta: Delta) -> Vec<VersionAnomaly> {
+        self.revision += 1;
+        if self.exceeds_memory_limit() {
+            self.refuse_new_nodes(&mut delta);
+        }
+        let digest_cache = self.digest_cache.get_mut().unwrap();
+        for node_id in delta.nodes_to_reset.iter().chain(delta.node_deltas.keys()) {
+            digest_cache.mark_stale(node_id);
+        }
+        let mut version_anomalies = Vec::new();
+        // Remove nodes to reset, keeping their heartbeat, which deltas do not carry.
+        let mut reset_node_heartbeats: HashMap<NodeId, u64> = HashMap::new();
+        self.node_states.retain(|node_id, node_state| {
+            if !delta.nodes_to_reset.contains(node_id) {
+                return true;
+            }
+            reset_node_heartbeats.insert(node_id.clone(), node_state.heartbeat);
+            false
+        });
+        // And apply delta.
+        let delta_interceptor = self.delta_interceptor.as_deref();
+        let write_acl = self.write_acl.as_ref();
+        for (node_id, node_delta) in delta.node_deltas {
+            let delta_max_version = node_delta.max_version();
+            if delta_max_version > MAX_SAFE_VERSION {
+                warn!(
+                    node_id = ?node_id,
+                    version = delta_max_version,
+                    "dropping-node-delta-with-overflowing-version"
+                );
+                version_anomalies.push(VersionAnomaly::Overflow {
+                    node_id,
+                    version: delta_max_version,
+                });
+                continue;
+            }
+            let node_state_opt = self.node_states.get(&node_id);
+            let heartbeat = node_state_opt
+                .map(|node_state| node_state.heartbeat)
+                .or_else(|| reset_node_heartbeats.get(&node_id).copied())
+                .unwrap_or(0);
+            let version_floor_opt = self.version_floors.get(&node_id);
+            let known_max_version = node_state_opt
+                .map(|node_state| node_state.max_version)
+                .max(version_floor_opt.map(|version_floor| version_floor.max_version))
+                .unwrap_or(0);
+            // A node may raise its max version by up to `max_version_jump` per heartbeat.
+            let num_heartbeats = version_floor_opt
+                .map(|version_floor| heartbeat.saturating_sub(version_floor.heartbeat))
+                .unwrap_or(0)
+                .saturating_add(1);
+            if known_max_version == 0 && self.max_version_jump.is_some() {
+                // The first delta of a node establishes its max version: it is bounded by the
+                // max version advertised for the node.
+                let advertised_max_version_opt = self.advertised_max_versions.get(&node_id);
+                if delta_max_version > advertised_max_version_opt.copied().unwrap_or(0) {
+                    let Some(&advertised_max_version) = advertised_max_version_opt else {
+                        // The node was missing from the digests received so far, e.g. because
+                        // the peer learnt about it after sending its digest.
+                        debug!(node_id = ?node_id, "dropping-node-delta-missing-from-digest");
+                        continue;
+                    };
+                    warn!(
+                        node_id = ?node_id,
+                        known_max_version = advertised_max_version,
+                        version = delta_max_version,
+                        "dropping-node-delta-with-version-jump"
+                    );
+                    version_anomalies.push(VersionAnomaly::Jump {
+                        node_id,
+                        known_max_version: advertised_max_version,
+                        version: delta_max_version,
+                    });
+                    continue;
+                }
+            } else if self.max_version_jump.is_some_and(|max_version_jump| {
+                delta_max_version
+                    > known_max_version
+                        .saturating_add(max_version_jump.saturating_mul(num_heartbeats))
+            }) {
+                warn!(
+                    node_id = ?node_id,
+                    known_max_version = known_max_version,
+                    version = delta_max_version,
+                    "dropping-node-delta-with-version-jump"
+                );
+                version_anomalies.push(VersionAnomaly::Jump {
+                    node_id,
+                    known_max_version,
+                    version: delta_max_version,
+                });
+                continue;
+            }
+            let is_reset = delta.nodes_to_reset.contains(&node_id);
+            let node_state_limits = self.node_state_limits;
+            let node_state_map =
+                Arc::make_mut(self.node_states.entry(node_id.clone()).or_insert_with(|| {
+                    Arc::new(NodeState {
+                        heartbeat: reset_node_heartbeats.get(&node_id).copied().unwrap_or(0),
+                        ..NodeState::with_limits(node_state_limits)
+                    })
+                }));
+            let labels = match write_acl {
+                #[cfg(feature = "json")]
+                Some(write_acl) if write_acl.has_label_rules() => {
+                    node_labels(&node_delta, node_state_map)
+                }
+                _ => BTreeMap::new(),
+            };
+
+            for (key, mut versioned_value) in node_delta.key_values {
+                node_state_map.max_version =
+                    node_state_map.max_version.max(versioned_value.version);
+                let is_obsolete = match node_state_map.key_values.get(&key) {
+                    // Due to the message passing being totally asynchronous, it is not an
+                    // error to receive updates that are already obsolete.
+                    Some(record) => record.version >= versioned_value.version,
+                    // A key-value no newer than a garbage collected tombstone comes from a
+                    // delayed delta: applying it would resurrect a deleted key.
+                    None => versioned_value.version <= node_state_map.gc_watermark,
+                };
+                if is_obsolete {
+                    continue;
+                }
+                if write_acl.is_some_and(|write_acl| !write_acl.is_allowed(&node_id, &labels, &key))
+                {
+                    debug!(node_id = ?node_id, key = %key, "dropping-unauthorized-key-value");
+                    self.num_unauthorized_key_values += 1;
+                    continue;
+                }
+                if let Some(delta_interceptor) = delta_interceptor {
+                    match delta_interceptor.before_apply(&node_id, &key, &versioned_value) {
+                        Interception::Apply => {}
+                        Interception::Replace(replacement) => {
+                            versioned_value = VersionedValue {
+                                version: versioned_value.version,
+                                ..replacement
+                            };
+                        }
+                        Interception::Veto => {
+                            debug!(node_id = ?node_id, key = %key, "key-value-vetoed-by-interceptor");
+                            continue;
+                        }
+                    }
+                }
+                if !versioned_value.marked_for_deletion
+                    && !node_state_map.make_room(
+                        &BTreeMap::from([(&*key, versioned_value.value.len())]),
+                        false,
+                    )
+                {
+                    continue;
+                }
+                let key_opt = delta_interceptor.map(|_| key.clone());
+                node_state_map.key_values.insert(key, versioned_value);
+                if let Some((delta_interceptor, key)) = delta_interceptor.zip(key_opt) {
+                    delta_interceptor.after_apply(&node_id, &key, &node_state_map.key_values[&key]);
+                }
+                node_state_map.num_writes_since_compaction += 1;
+            }
+            // The delta of a reset node holds all its key-values up to its max version, as
+            // key-values are sent by increasing version.
+            if is_reset {
+                node_state_map.gc_watermark =
+                    node_state_map.gc_watermark.max(node_state_map.max_version);
+            }
+
+            node_state_map.last_heartbeat = Instant::now();
+            self.advertised_max_versions.remove(&node_id);
+            let version_floor = self.version_floors.entry(node_id).or_insert(VersionFloor {
+                max_version: 0,
+                heartbeat: 0,
+            });
+            if node_state_map.max_version > version_floor.max_version {
+                *version_floor = VersionFloor {
+                    max_version: node_state_map.max_version,
+                    heartbeat: node_state_map.heartbeat,
+                };
+            }
+        }
+        version_anomalies
+    }
+
+    /// Removes from the delta the nodes missing from the cluster state.
+    fn refuse_new_nodes(&mut self, delta: &mut Delta) {
+        let node_states = &self.node_states;
+        let num_node_deltas = delta.node_deltas.len();
+        delta
+            .node_deltas
+            .retain(|node_id, _| node_states.contains_key(node_id));
+        let num_refused_nodes = num_node_deltas - delta.node_deltas.len();
+        if num_refused_nodes > 0 {
+            debug!(
+                num_refused_nodes = num_refused_nodes,
+                "refusing-new-nodes-over-memory-limit"
+            );
+            self.num_refused_nodes += num_refused_nodes as u64;
+        }
+    }
+
+    /// Returns the number of key-values received from peers and dropped by the `write_acl`.
+    pub(crate) fn num_unauthorized_key_values(&self) -> u64 {
+        self.num_unauthorized_key_values
+    }
+
+    /// Returns the number of node deltas refused because the cluster state exceeded the
+    /// `max_cluster_state_bytes`.
+    pub(crate) fn num_refused_nodes(&self) -> u64 {
+        self.num_refused_nodes
+    }
+
+    /// Compacts the node states that underwent at least `churn_threshold` writes since their
+    /// last compaction. Returns the number of compacted node states and reclaimed bytes.
+    pub(crate) fn compact_node_states(&mut self, churn_threshold: usize) -> (usize, usize) {
+        let mut num_compacted_node_states = 0;
+        let mut num_reclaimed_bytes = 0;
+        for node_state in self.node_states.values_mut() {
+            if node_state.num_writes_since_compaction >= churn_threshold {
+                num_compacted_node_states += 1;
+                num_reclaimed_bytes += Arc::make_mut(node_state).compact();
+            }
+        }
+        (num_compacted_node_states, num_reclaimed_bytes)
+    }
+
+    /// Returns the number of key-values rejected and evicted to enforce the node state limits,
+    /// across all nodes.
+    pub(crate) fn num_limited_key_values(&self) -> (u64, u64) {
+        self.node_states
+            .values()
+            .fold((0, 0), |(num_rejected, num_evicted), node_state| {
+                (
+                    num_rejected + node_state.num_rejected_key_values,
+                    num_evicted + node_state.num_evicted_key_values,
+                )
+            })
+    }
+
+    /// Returns the cluster-wide value of the counter `name`, i.e. the sum of the contributions
+    /// of all nodes, saturating at the bounds of `i64`.
+    pub fn counter_value(&self, name: &str) -> i64 {
+        self.node_states
+            .values()
+            .map(|node_state| node_state.counter(name).value())
+            .fold(0, i64::saturating_add)
+    }
+
+    /// Returns the cluster-wide observed-remove set `name`, i.e. the merge of the sets of all
+    /// nodes.
+    #[cfg(feature = "json")]
+    pub fn or_set(&self, name: &str) -> OrSet {
+        self.node_states
+            .values()
+            .fold(OrSet::default(), |or_set, node_state| {
+                or_set.merge(&node_state.or_set(name))
+            })
+    }
+
+    /// Returns the value of `key` for each node advertising it.
+    /// Keys marked for deletion are not returned.
+    pub fn get_from_all(&self, key: &str) -> BTreeMap<NodeId, VersionedValue> {
+        self.node_states
+            .iter()
+            .filter_map(|(node_id, node_state)| {
+                let versioned_value = node_state.get_versioned(key)?;
+                if versioned_value.marked_for_deletion {
+                    return None;
+                }
+                Some((node_id.clone(), versioned_value.clone()))
+            })
+            .collect()
+    }
+
+    /// Returns the digest of the node states, excluding the `dead_nodes`.
+    ///
+    /// The digest is maintained as the node states change: only the entries of the nodes
+    /// modified since the previous call are refreshed.
+    pub(crate) fn compute_digest(&self, dead_nodes: &HashSet<&NodeId>) -> Digest {
+        self.digest_cache
+            .lock()
+            .unwrap()
+            .digest(&self.node_states, dead_nodes)
+    }
+
+    /// Returns true if the oversized key-value of `node_id` at `version` was not reported yet.
+    fn should_report_oversized_key_value(&self, node_id: &NodeId, version: Version) -> bool {
+        let mut reported_oversized_key_values = self.reported_oversized_key_values.lock().unwrap();
+        if reported_oversized_key_values.get(node_id) == Some(&version) {
+            return false;
+        }
+        if reported_oversized_key_values.len() >= MAX_NUM_REPORTED_OVERSIZED_KEY_VALUES {
+            reported_oversized_key_values.clear();
+        }
+        reported_oversized_key_values.insert(node_id.clone(), version);
+        true
+    }
+
+    pub fn gc_keys_marked_for_deletion(
+        &mut self,
+        marked_for_deletion_grace_period: usize,
+        dead_nodes: &HashSet<NodeId>,
+    ) {
+        for (node_id, node_state_map) in &mut self.node_states {
+            // Node states without tombstones are left alone, so as not to copy them.
+            if dead_nodes.contains(node_id) || !node_state_map.has_tombstones() {
+                continue;
+            }
+            Arc::make_mut(node_state_map)
+                .gc_keys_marked_for_deletion(marked_for_deletion_grace_period);
+        }
+    }
+
+    /// Removes the keys marked for deletion for at least `grace_period`.
+    ///
+    /// See [`NodeState::gc_expired_tombstones`].
+    pub fn gc_expired_tombstones(&mut self, grace_period: Duration, dead_nodes: &HashSet<NodeId>) {
+        for (node_id, node_state_map) in &mut self.node_states {
+            // Node states without tombstones are left alone, so as not to copy them.
+            if dead_nodes.contains(node_id) || !node_state_map.has_tombstones() {
+                continue;
+            }
+            Arc::make_mut(node_state_map).gc_expired_tombstones(grace_period);
+        }
+    }
+
+    /// Returns true if a peer that saw the versions of a node up to `floor_version` may have
+    /// missed a tombstone since garbage collected, and must therefore be reset.
+    fn needs_reset(
+        &self,
+        node_state: &NodeState,
+        floor_version: Version,
+        marked_for_deletion_grace_period: usize,
+    ) -> bool {
+        match self.tombstone_gc_policy {
+            TombstoneGcPolicy::WallClock => floor_version < node_state.gc_watermark,
+            TombstoneGcPolicy::VersionCount => {
+                floor_version + (marked_for_deletion_grace_period as u64) < node_state.max_version
+            }
+        }
+    }
+
+    /// Implements the scuttlebutt reconciliation with the scuttle-depth ordering.
+    ///
+    /// So that a node with a huge backlog does not starve the other stale nodes, half of the
+    /// capacity of the delta is reserved to them and shared fairly: nodes needing less than an
+    /// even share get all their stale key-values, and the others split the rest evenly. Each
+    /// stale node gets at least one group of key-values, as long as the delta has capacity left.
+    ///
+    /// Key-values that cannot fit in a delta of size `mtu` on their own are handled according to
+    /// the `oversized_key_value_policy`.
+    ///
+    /// The nodes in `nodes_to_force_reset` are reset regardless of the digest.
+    pub(crate) fn compute_delta(
+        &self,
+        digest: &Digest,
+        mtu: usize,
+        dead_nodes: HashSet<&NodeId>,
+        marked_for_deletion_grace_period: usize,
+        oversized_key_value_policy: OversizedKeyValuePolicy,
+        nodes_to_force_reset: &HashSet<NodeId>,
+    ) -> Delta {
+        self.compute_delta_with_truncation(
+            digest,
+            mtu,
+            dead_nodes,
+            marked_for_deletion_grace_period,
+            oversized_key_value_policy,
+            nodes_to_force_reset,
+        )
+        .delta
+    }
+
+    /// Same as [`ClusterState::compute_delta`], also returning the serialized length of the
+    /// delta and whether it was truncated.
+    pub(crate) fn compute_delta_with_truncation(
+        &self,
+        digest: &Digest,
+        mtu: usize,
+        dead_nodes: HashSet<&NodeId>,
+        marked_for_deletion_grace_period: usize,
+        oversized_key_value_policy: OversizedKeyValuePolicy,
+        nodes_to_force_reset: &HashSet<NodeId>,
+    ) -> ComputedDelta {
+        let mut delta_writer = DeltaWriter::with_mtu(mtu);
+
+        let mut node_sorted_by_stale_length = NodeSortedByStaleLength::default();
+        let mut stale_num_bytes: Vec<(&NodeId, usize)> = Vec::new();
+        let mut nodes_with_stale_metadata: HashSet<&NodeId> = HashSet::new();
+        for (node_id, node_state_map) in &self.node_states {
+            if dead_nodes.contains(node_id) {
+                continue;
+            }
+            let mut floor_version = digest.max_version(node_id).unwrap_or(0);
+            // Node needs to be reset if the peer may have missed tombstones that were
+            // garbage collected since.
+            // Note that there is no need to reset if floor_version = 0 (new node), unless
+            // a previous reset of the node has not been acknowledged by the peer, or tombstones
+            // were garbage collected: peers omit the nodes they deem dead from their digest,
+            // while still holding their stale state.
+            if nodes_to_force_reset.contains(node_id)
+                || floor_version == 0 && node_state_map.gc_watermark > 0
+                || floor_version > 0
+                    && self.needs_reset(
+                        node_state_map,
+                        floor_version,
+                        marked_for_deletion_grace_period,
+                    )
+            {
+                // `floor_version` is set to 0 so the delta is populated with all keys and values.
+                floor_version = 0;
+                delta_writer.add_node_to_reset(node_id.clone());
+            }
+            let (stale_kv_count, num_bytes) = node_state_map
+                .iter_stale_key_values(floor_version)
+                .fold((0, 0), |(count, num_bytes), (key, versioned_value)| {
+                    (
+                        count + 1,
+                        num_bytes + kv_serialized_len(key, versioned_value),
+                    )
+                });
+            if stale_kv_count > 0 {
+                node_sorted_by_stale_length.insert(node_id, stale_kv_count);
+                stale_num_bytes.push((node_id, num_bytes));
+            }
+            if node_state_map.has_stale_metadata(floor_version) {
+                nodes_with_stale_metadata.insert(node_id);
+            }
+        }
+        // Nodes whose metadata changed are sent first.
+        let mut sorted_nodes: Vec<&NodeId> = node_sorted_by_stale_length.into_iter().collect();
+        sorted_nodes.sort_by_key(|node_id| !nodes_with_stale_metadata.contains(node_id));
+        // Half of the capacity of the delta is reserved, fairly, to the stale nodes, on top of the
+        // bytes needed for their node ids and the lengths of their node deltas. A node can only
+        // use the capacity that is not reserved to the nodes following it.
+        let mut num_node_header_bytes: HashMap<&NodeId, usize> = stale_num_bytes
+            .iter()
+            .map(|(node_id, _)| (*node_id, node_id.serialized_len() + 2))
+            .collect();
+        let total_num_node_header_bytes: usize = num_node_header_bytes.values().sum();
+        let mut reserved_num_bytes = fair_shares(
+            stale_num_bytes,
+            delta_writer
+                .remaining_capacity()
+                .saturating_sub(total_num_node_header_bytes)
+                / 2,
+        );
+        for (node_id, num_bytes) in reserved_num_bytes.iter_mut() {
+            *num_bytes += num_node_header_bytes.remove(node_id).unwrap_or(0);
+        }
+        let mut num_bytes_reserved_to_next_nodes: usize = reserved_num_bytes.values().sum();
+
+        let mut truncated = false;
+        for node_id in sorted_nodes {
+            if !delta_writer.add_node(node_id.clone()) {
+                truncated = true;
+                break;
+            }
+            let node_state_map = self.node_states.get(node_id).unwrap();
+            let mut floor_version = digest.max_version(node_id).unwrap_or(0);
+            if nodes_to_force_reset.contains(node_id)
+                || self.needs_reset(
+                    node_state_map,
+                    floor_version,
+                    marked_for_deletion_grace_period,
+                )
+            {
+                floor_version = 0;
+            }
+            let mut stale_kvs: Vec<(&Arc<str>, &VersionedValue)> = node_state_map
+                .iter_stale_key_values(floor_version)
+                .collect();
+
+            assert!(!stale_kvs.is_empty());
+            stale_kvs.sort_unstable_by_key(|(_, record)| record.version);
+            num_bytes_reserved_to_next_nodes -= reserved_num_bytes[node_id];
+            let mut is_first_kv_group = true;
+            // KVs sharing the same version were set in a single batch and must be sent together.
+            for kv_group in
+                stale_kvs.chunk_by(|(_, left), (_, right)| left.version == right.version)
+            {
+                if delta_writer.kv_group_exceeds_mtu(kv_group) {
+                    let version = kv_group[0].1.version;
+                    if self.should_report_oversized_key_value(node_id, version) {
+                        let keys: Vec<&str> = kv_group.iter().map(|(key, _)| &***key).collect();
+                        warn!(
+                            node_id = ?node_id,
+                            keys = ?keys,
+                            version = version,
+                            mtu = mtu,
+                            policy = ?oversized_key_value_policy,
+                            "key-value-exceeds-mtu"
+                        );
+                    }
+                    match oversized_key_value_policy {
+                        OversizedKeyValuePolicy::Skip => continue,
+                        OversizedKeyValuePolicy::Truncate => break,
+                    }
+                }
+                let kv_group_num_bytes: usize = kv_group
+                    .iter()
+                    .map(|(key, versioned_value)| kv_serialized_len(key, versioned_value))
+                    .sum();
+                if !is_first_kv_group
+                    && delta_writer.remaining_capacity()
+                        < num_bytes_reserved_to_next_nodes + kv_group_num_bytes
+                {
+                    truncated = true;
+                    break;
+                }
+                if !delta_writer.add_kv_group(kv_group) {
+                    return ComputedDelta::new(delta_writer, true);
+                }
+                is_first_kv_group = false;
+            }
+        }
+        ComputedDelta::new(delta_writer, truncated)
+    }
+}
+
+/// Delta computed for a peer by [`ClusterState::compute_delta_with_truncation`].
+pub(crate) struct ComputedDelta {
+    pub delta: Delta,
+    /// Serialized length of the delta, accounted for while writing it.
+    pub num_bytes: usize,
+    /// True if stale key-values were left out for lack of capacity. Oversized key-values left
+    /// out by the `oversized_key_value_policy` do not count.
+    pub truncated: bool,
+}
+
+impl ComputedDelta {
+    fn new(delta_writer: DeltaWriter, truncated: bool) -> Self {
+        let num_bytes = delta_writer.num_bytes();
+        ComputedDelta {
+            delta: delta_writer.into(),
+            num_bytes,
+            truncated,
+        }
+    }
+}
+
+/// Splits `budget` bytes max-min fairly between nodes, each needing a given number of bytes:
+/// nodes needing less than an even share get what they need, and the others split the rest
+/// evenly.
+fn fair_shares(mut needs: Vec<(&NodeId, usize)>, mut budget: usize) -> HashMap<&NodeId, usize> {
+    needs.sort_unstable_by_key(|(_, need)| *need);
+    let mut num_nodes = needs.len();
+    let mut shares = HashMap::with_capacity(num_nodes);
+    for (node_id, need) in needs {
+        let share = need.min(budget / num_nodes);
+        budget -= share;
+        num_nodes -= 1;
+        shares.insert(node_id, share);
+    }
+    shares
+}
+
+/// The heartbeat and the internal keys are not subject to the [`NodeStateLimits`].
+fn is_exempt_from_limits(key: &str) -> bool {
+    key == HEARTBEAT_KEY || is_reserved_key(key)
+}
+
+/// Returns the labels of a node, declared by the metadata carried by its delta if any, or else
+/// by the metadata known locally, so that the first delta of a node is checked against its own
+/// labels.
+#[cfg(feature = "json")]
+fn node_labels(node_delta: &NodeDelta, node_state: &NodeState) -> BTreeMap<String, String> {
+    node_delta
+        .key_values
+        .get(METADATA_KEY)
+        .filter(|versioned_value| !versioned_value.marked_for_deletion)
+        .and_then(|versioned_value| {
+            serde_json::from_str::<NodeMetadata>(&versioned_value.value).ok()
+        })
+        .or_else(|| node_state.metadata())
+        .map(|metadata| metadata.labels)
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ClusterStateSnapshot {
+    pub seed_addrs: HashSet<SocketAddr>,
+    /// States of the nodes, shared with the cluster state they were taken from until it
+    /// modifies them.
+    pub node_states: BTreeMap<String, Arc<NodeState>>,
+    /// Gossip address of each node, needed to merge the snapshot into a live cluster state.
+    #[serde(default)]
+    pub gossip_addrs: BTreeMap<String, SocketAddr>,
+    /// Generation of each node. Only the newest generation of a node is part of the snapshot.
+    #[serde(default)]
+    pub generations: BTreeMap<String, u64>,
+}
+
+impl ClusterStateSnapshot {
+    /// Returns what changed between this snapshot and a newer snapshot `other`.
+    pub fn diff(&self, other: &ClusterStateSnapshot) -> SnapshotDiff {
+        SnapshotDiff::compute(self, other)
+    }
+}
+
+impl<'a> From<&'a ClusterState> for ClusterStateSnapshot {
+    fn from(state: &'a ClusterState) -> Self {
+        ClusterStateSnapshot {
+            seed_addrs: state.seed_addrs(),
+            node_states: state
+                .node_states
+                .iter()
+                .map(|(node_id, node_state)| (node_id.id.clone(), node_state.clone()))
+                .collect(),
+            gossip_addrs: state
+                .node_states
+                .keys()
+                .map(|node_id| (node_id.id.clone(), node_id.gossip_public_address))
+                .collect(),
+            generations: state
+                .node_states
+                .keys()
+                .map(|node_id| (node_id.id.clone(), node_id.generation))
+                .collect(),
+        }
+    }
+}
+
+/// Owned, read-only view of the state of a node.
+///
+/// A view does not borrow the Chitchat instance it was created from: it can be sent to
+/// other threads and read without any lock. It does not reflect updates that happened
+/// after its creation.
+///
+/// The view shares the node state with the cluster state, which copies it on write: creating
+/// a view only clones one pointer.
+#[derive(Clone, Debug)]
+pub struct NodeStateView {
+    node_id: NodeId,
+    node_state: Arc<NodeState>,
+}
+
+impl NodeStateView {
+    pub(crate) fn new(node_id: NodeId, node_state: Arc<NodeState>) -> Self {
+        NodeStateView {
+            node_id,
+            node_state,
+        }
+    }
+
+    pub fn node_id(&self) -> &NodeId {
+        &self.node_id
+    }
+
+    pub fn max_version(&self) -> Version {
+        self.node_state.max_version
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.get_versioned(key)
+            .map(|versioned_value| versioned_value.value.as_str())
+    }
+
+    pub fn get_versioned(&self, key: &str) -> Option<&VersionedValue> {
+        self.node_state
+            .get_versioned(key)
+            .filter(|versioned_value| !versioned_value.marked_for_deletion)
+    }
+
+    /// Returns an iterator over the key-values of the node.
+    /// Keys marked for deletion are not part of the view.
+    pub fn iter_key_values(&self) -> impl Iterator<Item = (&str, &VersionedValue)> {
+        self.node_state.iter_key_values(|_, _| true)
+    }
+}
+
+impl PartialEq for NodeStateView {
+    fn eq(&self, other: &Self) -> bool {
+        self.node_id == other.node_id
+            && self.max_version() == other.max_version()
+            && self.iter_key_values().eq(other.iter_key_values())
+    }
+}
+
+impl Eq for NodeStateView {}
+
+#[derive(Default)]
+struct NodeSortedByStaleLength<'a> {
+    node_per_stale_length: BTreeMap<usize, Vec<&'a NodeId>>,
+    stale_lengths: BinaryHeap<usize>,
+}
+
+impl<'a> NodeSortedByStaleLength<'a> {
+    fn insert(&mut self, node_id: &'a NodeId, stale_length: usize) {
+        self.node_per_stale_length
+            .entry(stale_length)
+            .or_insert_with(|| {
+                self.stale_lengths.push(stale_length);
+                Vec::new()
+            })
+            .push(node_id);
+    }
+
+    /// Nodes with the same stale length are shuffled, unless the `rand` feature is disabled,
+    /// in which case they are returned in node id order.
+    fn into_iter(mut self) -> impl Iterator<Item = &'a NodeId> {
+        #[cfg(feature = "rand")]
+        let mut rng = random_generator();
+        std::iter::from_fn(move || self.stale_lengths.pop()).flat_map(move |length| {
+            #[allow(unused_mut)]
+            let mut nodes = self.node_per_stale_length.remove(&length).unwrap();
+            #[cfg(feature = "rand")]
+            nodes.shuffle(&mut rng);
+            nodes.into_iter()
+        })
+    }
+}
+
+#[cfg(all(feature = "rand", not(test)))]
 fn random_generator() -> impl Rng {
     rand::thread_rng()
 }
 
-// We use a deterministic random generator in tests.
-#[cfg(test)]
-fn random_generator() -> impl Rng {
-    use rand::prelude::StdRng;
-    use rand::SeedableRng;
-    StdRng::seed_from_u64(9u64)
-}
+// We use a deterministic random generator in tests.
+#[cfg(all(feature = "rand", test))]
+fn random_generator() -> impl Rng {
+    use rand::prelude::StdRng;
+    use rand::SeedableRng;
+    StdRng::seed_from_u64(9u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_acl::WriteAclSubject;
+    use crate::MAX_UDP_DATAGRAM_PAYLOAD_SIZE;
+
+    #[test]
+    fn test_node_sorted_by_stale_length_empty() {
+        let node_sorted_by_stale_length = NodeSortedByStaleLength::default();
+        assert!(node_sorted_by_stale_length.into_iter().next().is_none());
+    }
+
+    #[test]
+    fn test_node_sorted_by_stale_length_simple() {
+        let mut node_sorted_by_stale_length = NodeSortedByStaleLength::default();
+        let node_ids = vec![(10_001, 1), (10_002, 2), (10_003, 3)]
+            .into_iter()
+            .map(|(port, state_length)| (NodeId::for_test_localhost(port), state_length))
+            .collect::<Vec<_>>();
+        for (node_id, state_length) in node_ids.iter() {
+            node_sorted_by_stale_length.insert(node_id, *state_length);
+        }
+        let nodes: Vec<&NodeId> = node_sorted_by_stale_length.into_iter().collect();
+        let expected_nodes: Vec<NodeId> = [10_003, 10_002, 10_001]
+            .into_iter()
+            .map(NodeId::for_test_localhost)
+            .collect();
+        assert_eq!(nodes, expected_nodes.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_node_sorted_by_stale_length_doubles() {
+        let mut node_sorted_by_stale_length = NodeSortedByStaleLength::default();
+        let node_ids = vec![(10_001, 1), (20_001, 2), (20_002, 2), (20_003, 2)]
+            .into_iter()
+            .map(|(port, state_length)| (NodeId::for_test_localhost(port), state_length))
+            .collect::<Vec<_>>();
+
+        for (node_id, state_length) in node_ids.iter() {
+            node_sorted_by_stale_length.insert(node_id, *state_length);
+        }
+
+        let nodes: Vec<NodeId> = node_sorted_by_stale_length.into_iter().cloned().collect();
+        let expected_nodes: Vec<NodeId> = vec![20_002, 20_001, 20_003, 10_001]
+            .into_iter()
+            .map(NodeId::for_test_localhost)
+            .collect();
+        assert_eq!(nodes, expected_nodes);
+    }
+
+    #[test]
+    fn test_cluster_state_missing_node() {
+        let cluster_state = ClusterState::default();
+        let node_state = cluster_state.node_state(&NodeId::for_test_localhost(10_001));
+        assert!(node_state.is_none());
+    }
+
+    #[test]
+    fn test_cluster_state_first_version_is_one() {
+        let mut cluster_state = ClusterState::default();
+        let node_state = cluster_state.node_state_mut(&NodeId::for_test_localhost(10_001));
+        node_state.set("key_a", "");
+        assert_eq!(
+            node_state.get_versioned("key_a").unwrap(),
+            &VersionedValue {
+                value: "".to_string(),
+                version: 1,
+                marked_for_deletion: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_cluster_state_set() {
+        let mut cluster_state = ClusterState::default();
+        let node_state = cluster_state.node_state_mut(&NodeId::for_test_localhost(10_001));
+        node_state.set("key_a", "1");
+        assert_eq!(
+            node_state.get_versioned("key_a").unwrap(),
+            &VersionedValue {
+                value: "1".to_string(),
+                version: 1,
+                marked_for_deletion: false,
+            }
+        );
+        node_state.set("key_b", "2");
+        assert_eq!(
+            node_state.get_versioned("key_a").unwrap(),
+            &VersionedValue {
+                value: "1".to_string(),
+                version: 1,
+                marked_for_deletion: false,
+            }
+        );
+        assert_eq!(
+            node_state.get_versioned("key_b").unwrap(),
+            &VersionedValue {
+                value: "2".to_string(),
+                version: 2,
+                marked_for_deletion: false,
+            }
+        );
+        node_state.set("key_a", "3");
+        assert_eq!(
+            node_state.get_versioned("key_a").unwrap(),
+            &VersionedValue {
+                value: "3".to_string(),
+                version: 3,
+                marked_for_deletion: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_cluster_state_set_with_same_value_updates_version() {
+        let mut cluster_state = ClusterState::default();
+        let node_state = cluster_state.node_state_mut(&NodeId::for_test_localhost(10_001));
+        node_state.set("key", "1");
+        assert_eq!(
+            node_state.get_versioned("key").unwrap(),
+            &VersionedValue {
+                value: "1".to_string(),
+                version: 1,
+                marked_for_deletion: false,
+            }
+        );
+        node_state.set("key", "1");
+        assert_eq!(
+            node_state.get_versioned("key").unwrap(),
+            &VersionedValue {
+                value: "1".to_string(),
+                version: 2,
+                marked_for_deletion: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_cluster_state_set_and_mark_for_deletion() {
+        let mut cluster_state = ClusterState::default();
+        let node_state = cluster_state.node_state_mut(&NodeId::for_test_localhost(10_001));
+        node_state.set("key", "1");
+        node_state.mark_for_deletion("key");
+        assert_eq!(
+            node_state.get_versioned("key").unwrap(),
+            &VersionedValue {
+                value: "1".to_string(),
+                version: 2,
+                marked_for_deletion: true,
+            }
+        );
+        node_state.set("key", "2");
+        assert_eq!(
+            node_state.get_versioned("key").unwrap(),
+            &VersionedValue {
+                value: "2".to_string(),
+                version: 3,
+                marked_for_deletion: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_node_state_set_batch() {
+        let mut node_state = NodeState::default();
+        node_state.set("key_a", "1");
+        node_state.set_batch([("key_b", "2"), ("key_c", "3"), ("key_b", "4")]);
+        assert_eq!(node_state.max_version, 2);
+        assert_eq!(node_state.get_versioned("key_a").unwrap().version, 1);
+        assert_eq!(
+            node_state.get_versioned("key_b").unwrap(),
+            &VersionedValue {
+                value: "4".to_string(),
+                version: 2,
+                marked_for_deletion: false,
+            }
+        );
+        assert_eq!(node_state.get_versioned("key_c").unwrap().version, 2);
+        // An empty batch does not bump the version, nor stamps a write.
+        node_state.set_stamp_writes(true);
+        node_state.set_batch(std::iter::empty::<(&str, &str)>());
+        assert_eq!(node_state.max_version, 2);
+        assert!(node_state.write_timestamp().is_none());
+        node_state.set("key_d", "5");
+        assert_eq!(node_state.get_versioned("key_d").unwrap().version, 3);
+    }
+
+    #[test]
+    fn test_node_state_mutation_errors() {
+        let mut node_state = NodeState::default();
+        node_state.set("key_a", "1");
+        assert_eq!(
+            node_state.set_with_version("key_b".to_string(), "2".to_string(), 1),
+            Err(StateError::NonIncreasingVersion {
+                key: "key_b".to_string(),
+                version: 1,
+                max_version: 1,
+            })
+        );
+        assert!(node_state.get("key_b").is_none());
+
+        // Writes are dropped rather than panicking once versions are exhausted.
+        node_state.max_version = u64::MAX;
+        assert_eq!(
+            node_state.try_set_with_source(
+                "key_b".to_string(),
+                "2".to_string(),
+                WriteSource::Application
+            ),
+            Err(StateError::VersionOverflow)
+        );
+        node_state.set("key_b", "2");
+        node_state.set_batch([("key_c", "3")]);
+        node_state.mark_for_deletion("key_a");
+        assert!(node_state.remove("key_a").is_none());
+        assert!(node_state.get("key_b").is_none());
+        assert!(node_state.get("key_c").is_none());
+        assert_eq!(node_state.get("key_a"), Some("1"));
+    }
+
+    #[test]
+    fn test_node_state_limits_reject() {
+        let mut node_state = NodeState::with_limits(NodeStateLimits {
+            max_num_keys: Some(2),
+            max_num_bytes: Some(13),
+            policy: NodeStateLimitPolicy::Reject,
+        });
+        node_state.set(HEARTBEAT_KEY, "1");
+        node_state.set("key_a", "1");
+        node_state.set("key_b", "2");
+        // Too many keys.
+        node_state.set("key_c", "3");
+        assert!(node_state.get("key_c").is_none());
+        // Too many bytes.
+        node_state.set("key_b", "234");
+        assert_eq!(node_state.get("key_b"), Some("2"));
+        node_state.set_batch([("key_a", "3"), ("key_b", "4567")]);
+        assert_eq!(node_state.get("key_a"), Some("1"));
+        assert_eq!(
+            node_state.try_set_batch(BTreeMap::from([("key_b".to_string(), "4567".to_string())])),
+            Err(StateError::LimitExceeded {
+                keys: vec!["key_b".to_string()],
+            })
+        );
+        assert_eq!(node_state.num_rejected_key_values(), 5);
+        assert_eq!(node_state.get("key_b"), Some("2"));
+        // Overwrites fitting within the limits are accepted, and the heartbeat is never
+        // limited.
+        node_state.set("key_b", "23");
+        assert_eq!(node_state.get("key_b"), Some("23"));
+        node_state.set(HEARTBEAT_KEY, "1234");
+        assert_eq!(node_state.get(HEARTBEAT_KEY), Some("1234"));
+        // Tombstones are not accounted for.
+        node_state.remove("key_a");
+        node_state.set("key_c", "3");
+        assert_eq!(node_state.get("key_c"), Some("3"));
+        assert_eq!(node_state.num_rejected_key_values(), 5);
+    }
+
+    #[test]
+    fn test_node_state_limits_evict_oldest() {
+        let mut node_state = NodeState::with_limits(NodeStateLimits {
+            max_num_keys: Some(2),
+            max_num_bytes: None,
+            policy: NodeStateLimitPolicy::EvictOldest,
+        });
+        node_state.set("key_a", "1");
+        node_state.set("key_b", "2");
+        node_state.set("key_a", "3");
+        node_state.set("key_c", "4");
+        // The oldest key is evicted through a tombstone, so that peers learn about it.
+        let evicted = node_state.get_versioned("key_b").unwrap();
+        assert!(evicted.marked_for_deletion);
+        assert_eq!(evicted.version, 4);
+        assert_eq!(node_state.get_versioned("key_c").unwrap().version, 5);
+        assert_eq!(
+            node_state.write_source("key_b"),
+            Some(WriteSource::Internal)
+        );
+        assert_eq!(node_state.num_evicted_key_values(), 1);
+
+        // A batch cannot evict its own keys.
+        node_state.set_batch([("key_d", "5"), ("key_e", "6"), ("key_f", "7")]);
+        assert!(node_state.get("key_d").is_none());
+        assert_eq!(node_state.num_rejected_key_values(), 3);
+    }
+
+    #[test]
+    fn test_cluster_state_get_from_all() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let node3 = NodeId::for_test_localhost(10_003);
+        cluster_state
+            .node_state_mut(&node1)
+            .set("grpc_address", "127.0.0.1:20001");
+        let node2_state = cluster_state.node_state_mut(&node2);
+        node2_state.set("key", "value");
+        node2_state.set("grpc_address", "127.0.0.1:20002");
+        cluster_state
+            .node_state_mut(&node3)
+            .set("grpc_address", "127.0.0.1:20003");
+        cluster_state
+            .node_state_mut(&node3)
+            .mark_for_deletion("grpc_address");
+
+        let grpc_addresses = cluster_state.get_from_all("grpc_address");
+        assert_eq!(grpc_addresses.len(), 2);
+        assert_eq!(grpc_addresses[&node1].value, "127.0.0.1:20001");
+        assert_eq!(
+            grpc_addresses[&node2],
+            VersionedValue {
+                value: "127.0.0.1:20002".to_string(),
+                version: 2,
+                marked_for_deletion: false,
+            }
+        );
+        assert!(cluster_state.get_from_all("missing").is_empty());
+    }
+
+    #[test]
+    fn test_cluster_state_liveness_report() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key", "1", 1, false);
+        cluster_state.apply_delta(delta);
+        std::thread::sleep(Duration::from_millis(50));
+
+        let mut delta = Delta::default();
+        delta.add_node_delta(node2.clone(), "key", "1", 1, false);
+        cluster_state.apply_delta(delta);
+        let liveness_report = cluster_state.liveness_report();
+        assert_eq!(liveness_report.len(), 2);
+        assert!(liveness_report[&node1] >= Duration::from_millis(50));
+        assert!(liveness_report[&node2] < liveness_report[&node1]);
+    }
+
+    #[test]
+    fn test_cluster_state_apply_delta_interceptor() {
+        use std::sync::{Arc, Mutex};
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::serialize::Serializable;
-    use crate::MAX_UDP_DATAGRAM_PAYLOAD_SIZE;
+        #[derive(Default)]
+        struct TestInterceptor {
+            applied_keys: Arc<Mutex<Vec<(String, String)>>>,
+        }
+
+        impl DeltaInterceptor for TestInterceptor {
+            fn before_apply(
+                &self,
+                _node_id: &NodeId,
+                key: &str,
+                versioned_value: &VersionedValue,
+            ) -> Interception {
+                if !key.starts_with("service:") {
+                    return Interception::Apply;
+                }
+                let value = versioned_value.value.trim();
+                if value.is_empty() {
+                    return Interception::Veto;
+                }
+                Interception::Replace(VersionedValue {
+                    value: value.to_string(),
+                    version: 100,
+                    marked_for_deletion: false,
+                })
+            }
+
+            fn after_apply(&self, _node_id: &NodeId, key: &str, versioned_value: &VersionedValue) {
+                self.applied_keys
+                    .lock()
+                    .unwrap()
+                    .push((key.to_string(), versioned_value.value.clone()));
+            }
+        }
+
+        let interceptor = TestInterceptor::default();
+        let applied_keys = interceptor.applied_keys.clone();
+        let mut cluster_state = ClusterState {
+            delta_interceptor: Some(Box::new(interceptor)),
+            ..Default::default()
+        };
+        let node1 = NodeId::for_test_localhost(10_001);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "1", 1, false);
+        delta.add_node_delta(node1.clone(), "service:a", " 10.0.0.1 ", 2, false);
+        delta.add_node_delta(node1.clone(), "service:b", "  ", 3, false);
+        cluster_state.apply_delta(delta);
+
+        let node1_state = cluster_state.node_state(&node1).unwrap();
+        assert_eq!(node1_state.get("key_a"), Some("1"));
+        let service_a = node1_state.get_versioned("service:a").unwrap();
+        assert_eq!(service_a.value, "10.0.0.1");
+        assert_eq!(service_a.version, 2);
+        assert!(node1_state.get("service:b").is_none());
+        assert_eq!(node1_state.max_version, 3);
+        assert_eq!(
+            *applied_keys.lock().unwrap(),
+            [
+                ("key_a".to_string(), "1".to_string()),
+                ("service:a".to_string(), "10.0.0.1".to_string())
+            ]
+        );
+    }
 
     #[test]
-    fn test_node_sorted_by_stale_length_empty() {
-        let node_sorted_by_stale_length = NodeSortedByStaleLength::default();
-        assert!(node_sorted_by_stale_length.into_iter().next().is_none());
+    fn test_cluster_state_apply_delta_version_jump() {
+        let mut cluster_state = ClusterState {
+            max_version_jump: Some(100),
+            ..Default::default()
+        };
+        let node1 = NodeId::for_test_localhost(10_001);
+        cluster_state.record_advertised_max_versions([(&node1, 1_000)]);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "1", 1_000, false);
+        assert!(cluster_state.apply_delta(delta).is_empty());
+        // Known nodes are bounded by the jump allowed instead.
+        cluster_state.record_advertised_max_versions([(&node1, 1)]);
+        assert!(cluster_state.advertised_max_versions.is_empty());
+
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "2", 1_100, false);
+        assert!(cluster_state.apply_delta(delta).is_empty());
+
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "3", u64::MAX / 2, false);
+        assert_eq!(
+            cluster_state.apply_delta(delta),
+            [VersionAnomaly::Jump {
+                node_id: node1.clone(),
+                known_max_version: 1_100,
+                version: u64::MAX / 2,
+            }]
+        );
+        // The node delta is dropped, and the state of the node kept.
+        let node1_state = cluster_state.node_state(&node1).unwrap();
+        assert_eq!(node1_state.get("key_a"), Some("2"));
+        assert_eq!(node1_state.max_version, 1_100);
+
+        // The known max version survives a reset of the node.
+        let mut delta = Delta::default();
+        delta.nodes_to_reset.insert(node1.clone());
+        delta.add_node_delta(node1.clone(), "key_a", "3", u64::MAX / 2, false);
+        assert_eq!(cluster_state.apply_delta(delta).len(), 1);
+        assert!(cluster_state.node_state(&node1).is_none());
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "3", u64::MAX / 2, false);
+        assert_eq!(cluster_state.apply_delta(delta).len(), 1);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "3", 1_150, false);
+        assert!(cluster_state.apply_delta(delta).is_empty());
+
+        // The jump allowed grows with the heartbeats elapsed.
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "4", 1_450, false);
+        assert_eq!(cluster_state.apply_delta(delta).len(), 1);
+        assert!(cluster_state.record_digest_heartbeat(&node1, 2));
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "4", 1_450, false);
+        assert!(cluster_state.apply_delta(delta).is_empty());
+        assert_eq!(
+            cluster_state.node_state(&node1).unwrap().get("key_a"),
+            Some("4")
+        );
     }
 
     #[test]
-    fn test_node_sorted_by_stale_length_simple() {
-        let mut node_sorted_by_stale_length = NodeSortedByStaleLength::default();
-        let node_ids = vec![(10_001, 1), (10_002, 2), (10_003, 3)]
-            .into_iter()
-            .map(|(port, state_length)| (NodeId::for_test_localhost(port), state_length))
-            .collect::<Vec<_>>();
-        for (node_id, state_length) in node_ids.iter() {
-            node_sorted_by_stale_length.insert(node_id, *state_length);
-        }
-        let nodes: Vec<&NodeId> = node_sorted_by_stale_length.into_iter().collect();
-        let expected_nodes: Vec<NodeId> = [10_003, 10_002, 10_001]
-            .into_iter()
-            .map(NodeId::for_test_localhost)
-            .collect();
-        assert_eq!(nodes, expected_nodes.iter().collect::<Vec<_>>());
+    fn test_cluster_state_apply_delta_first_version_bounded_by_digest() {
+        let mut cluster_state = ClusterState {
+            max_version_jump: Some(100),
+            ..Default::default()
+        };
+        let node1 = NodeId::for_test_localhost(10_001);
+        let version = MAX_SAFE_VERSION - 1;
+        // A node missing from the digests is not learnt.
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "1", version, false);
+        assert!(cluster_state.apply_delta(delta).is_empty());
+        assert!(cluster_state.node_state(&node1).is_none());
+
+        cluster_state.record_advertised_max_versions([(&node1, 1_000)]);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "1", version, false);
+        assert_eq!(
+            cluster_state.apply_delta(delta),
+            [VersionAnomaly::Jump {
+                node_id: node1.clone(),
+                known_max_version: 1_000,
+                version,
+            }]
+        );
+        assert!(cluster_state.node_state(&node1).is_none());
+
+        // Advertising a version above `MAX_SAFE_VERSION` does not let it through.
+        cluster_state.record_advertised_max_versions([(&node1, u64::MAX)]);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "1", MAX_SAFE_VERSION + 1, false);
+        assert_eq!(
+            cluster_state.apply_delta(delta),
+            [VersionAnomaly::Overflow {
+                node_id: node1.clone(),
+                version: MAX_SAFE_VERSION + 1,
+            }]
+        );
+        assert!(cluster_state.node_state(&node1).is_none());
+
+        cluster_state.record_advertised_max_versions([(&node1, 1_000)]);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "1", 1_000, false);
+        assert!(cluster_state.apply_delta(delta).is_empty());
+        assert_eq!(cluster_state.node_state(&node1).unwrap().max_version, 1_000);
+
+        // Forgotten nodes are bounded again.
+        cluster_state.remove_node(&node1);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "1", 1_000, false);
+        assert!(cluster_state.apply_delta(delta).is_empty());
+        assert!(cluster_state.node_state(&node1).is_none());
     }
 
     #[test]
-    fn test_node_sorted_by_stale_length_doubles() {
-        let mut node_sorted_by_stale_length = NodeSortedByStaleLength::default();
-        let node_ids = vec![(10_001, 1), (20_001, 2), (20_002, 2), (20_003, 2)]
-            .into_iter()
-            .map(|(port, state_length)| (NodeId::for_test_localhost(port), state_length))
-            .collect::<Vec<_>>();
+    fn test_cluster_state_apply_delta_write_acl() {
+        let mut cluster_state = ClusterState {
+            write_acl: Some(
+                WriteAcl::default()
+                    .allow(WriteAclSubject::NodeId("indexer".to_string()), "indexer:"),
+            ),
+            ..Default::default()
+        };
+        let indexer = NodeId::new("indexer".to_string(), "127.0.0.1:10001".parse().unwrap());
+        let searcher = NodeId::new("searcher".to_string(), "127.0.0.1:10002".parse().unwrap());
+        let mut delta = Delta::default();
+        delta.add_node_delta(indexer.clone(), "indexer:shards", "1", 1, false);
+        delta.add_node_delta(indexer.clone(), HEARTBEAT_KEY, "3", 2, false);
+        delta.add_node_delta(searcher.clone(), "indexer:shards", "2", 1, false);
+        delta.add_node_delta(searcher.clone(), "searcher:cache", "3", 2, false);
+        cluster_state.apply_delta(delta);
 
-        for (node_id, state_length) in node_ids.iter() {
-            node_sorted_by_stale_length.insert(node_id, *state_length);
-        }
+        let indexer_state = cluster_state.node_state(&indexer).unwrap();
+        assert_eq!(indexer_state.get("indexer:shards"), Some("1"));
+        assert_eq!(indexer_state.get(HEARTBEAT_KEY), Some("3"));
+        let searcher_state = cluster_state.node_state(&searcher).unwrap();
+        assert!(searcher_state.key_values.is_empty());
+        // The dropped key-values are not requested again.
+        assert_eq!(searcher_state.max_version, 2);
+        assert_eq!(cluster_state.num_unauthorized_key_values(), 2);
+    }
 
-        let nodes: Vec<NodeId> = node_sorted_by_stale_length.into_iter().cloned().collect();
-        let expected_nodes: Vec<NodeId> = vec![20_002, 20_001, 20_003, 10_001]
-            .into_iter()
-            .map(NodeId::for_test_localhost)
-            .collect();
-        assert_eq!(nodes, expected_nodes);
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_cluster_state_apply_delta_write_acl_labels() {
+        let mut cluster_state = ClusterState {
+            write_acl: Some(WriteAcl::default().allow(
+                WriteAclSubject::Label {
+                    key: "team".to_string(),
+                    value: "search".to_string(),
+                },
+                "search:",
+            )),
+            ..Default::default()
+        };
+        let node1 = NodeId::for_test_localhost(10_001);
+        let metadata = NodeMetadata {
+            labels: BTreeMap::from([("team".to_string(), "search".to_string())]),
+            ..Default::default()
+        };
+        let mut delta = Delta::default();
+        delta.add_node_delta(
+            node1.clone(),
+            METADATA_KEY,
+            &serde_json::to_string(&metadata).unwrap(),
+            1,
+            false,
+        );
+        delta.add_node_delta(node1.clone(), "search:cache", "1", 2, false);
+        delta.add_node_delta(node1.clone(), "index:shards", "1", 3, false);
+        cluster_state.apply_delta(delta);
+
+        let node1_state = cluster_state.node_state(&node1).unwrap();
+        assert_eq!(node1_state.get("search:cache"), Some("1"));
+        assert!(node1_state.get("index:shards").is_none());
+        assert_eq!(cluster_state.num_unauthorized_key_values(), 1);
     }
 
     #[test]
-    fn test_cluster_state_missing_node() {
-        let cluster_state = ClusterState::default();
-        let node_state = cluster_state.node_state(&NodeId::for_test_localhost(10_001));
-        assert!(node_state.is_none());
+    fn test_cluster_state_apply_delta_enforces_limits() {
+        let mut cluster_state = ClusterState {
+            node_state_limits: NodeStateLimits {
+                max_num_keys: None,
+                max_num_bytes: Some(12),
+                policy: NodeStateLimitPolicy::EvictOldest,
+            },
+            ..Default::default()
+        };
+        let node1 = NodeId::for_test_localhost(10_001);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "1", 1, false);
+        delta.add_node_delta(node1.clone(), "key_b", "2", 2, false);
+        delta.add_node_delta(node1.clone(), "key_c", "3", 3, false);
+        delta.add_node_delta(node1.clone(), "key_d", "this-is-too-long", 4, false);
+        cluster_state.apply_delta(delta);
+
+        let node_state = cluster_state.node_state(&node1).unwrap();
+        // Key-values of remote nodes are evicted without tombstones.
+        assert!(node_state.get_versioned("key_a").is_none());
+        assert_eq!(node_state.get("key_b"), Some("2"));
+        assert_eq!(node_state.get("key_c"), Some("3"));
+        assert!(node_state.get_versioned("key_d").is_none());
+        assert_eq!(node_state.max_version, 4);
+        assert_eq!(cluster_state.num_limited_key_values(), (1, 1));
     }
 
     #[test]
-    fn test_cluster_state_first_version_is_one() {
-        let mut cluster_state = ClusterState::default();
-        let node_state = cluster_state.node_state_mut(&NodeId::for_test_localhost(10_001));
-        node_state.set("key_a", "");
+    fn test_node_state_compare_and_set() {
+        let mut node_state = NodeState::default();
+        node_state.compare_and_set("key", None, "1").unwrap();
+        assert_eq!(
+            node_state.compare_and_set("key", None, "2").unwrap_err(),
+            CompareAndSetError::ValueMismatch {
+                key: "key".to_string(),
+                current_value: Some("1".to_string()),
+            }
+        );
+        assert_eq!(
+            node_state
+                .compare_and_set("key", Some("2"), "3")
+                .unwrap_err()
+                .current_value(),
+            Some("1")
+        );
+        assert_eq!(node_state.max_version, 1);
+        node_state.compare_and_set("key", Some("1"), "2").unwrap();
+        assert_eq!(node_state.get("key"), Some("2"));
+        assert_eq!(node_state.max_version, 2);
+        node_state.mark_for_deletion("key");
+        node_state.compare_and_set("key", None, "3").unwrap();
+        assert_eq!(node_state.get("key"), Some("3"));
+
+        // Reserved keys are never written.
+        assert_eq!(
+            node_state
+                .compare_and_set(GENERATION_KEY, None, "3")
+                .unwrap_err(),
+            CompareAndSetError::ReservedKey {
+                key: GENERATION_KEY.to_string(),
+            }
+        );
+        assert!(node_state.get(GENERATION_KEY).is_none());
+    }
+
+    #[test]
+    fn test_node_state_compare_and_set_enforces_limits() {
+        let mut node_state = NodeState::with_limits(NodeStateLimits {
+            max_num_keys: None,
+            max_num_bytes: Some(8),
+            policy: NodeStateLimitPolicy::Reject,
+        });
+        node_state.compare_and_set("key", None, "1").unwrap();
+        assert_eq!(
+            node_state
+                .compare_and_set("key", Some("1"), "123456")
+                .unwrap_err(),
+            CompareAndSetError::LimitExceeded {
+                key: "key".to_string(),
+            }
+        );
+        assert_eq!(node_state.get("key"), Some("1"));
+        assert_eq!(node_state.max_version, 1);
+    }
+
+    #[test]
+    fn test_node_state_reserved_keys() {
+        let mut node_state = NodeState::default();
+        node_state.set("__chitchat:generation", "3");
+        node_state.set_with_source("__chitchat:generation", "3", WriteSource::Operator);
+        node_state.set_batch([("key_a", "1"), ("__chitchat:leave_intent", "true")]);
+        assert_eq!(node_state.max_version, 0);
+        assert_eq!(node_state.generation(), None);
+        assert!(!node_state.has_leave_intent());
+        assert!(node_state.get("key_a").is_none());
+
+        node_state.set_generation(3);
+        node_state.set_advertised_addrs(&[
+            "127.0.0.1:7280".parse().unwrap(),
+            "[::1]:7281".parse().unwrap(),
+        ]);
+        node_state.set_leave_intent(true);
+        assert_eq!(node_state.generation(), Some(3));
+        assert_eq!(
+            node_state.advertised_addrs(),
+            [
+                "127.0.0.1:7280".parse::<SocketAddr>().unwrap(),
+                "[::1]:7281".parse().unwrap()
+            ]
+        );
+        assert!(node_state.has_leave_intent());
+
+        // Applications cannot delete the internal keys either.
+        node_state.mark_for_deletion("__chitchat:generation");
+        assert_eq!(node_state.remove("__chitchat:leave_intent"), None);
+        assert_eq!(node_state.generation(), Some(3));
+        assert!(node_state.has_leave_intent());
+
+        node_state.set_leave_intent(false);
+        assert!(!node_state.has_leave_intent());
+    }
+
+    #[test]
+    fn test_node_state_write_source() {
+        let mut node_state = NodeState::default();
+        node_state.set("key_a", "1");
+        node_state.set_with_source("key_b", "2", WriteSource::Operator);
+        node_state
+            .set_with_version("key_c".to_string(), "3".to_string(), 3)
+            .unwrap();
+        assert_eq!(
+            node_state.write_source("key_a"),
+            Some(WriteSource::Application)
+        );
+        assert_eq!(
+            node_state.write_source("key_b"),
+            Some(WriteSource::Operator)
+        );
+        assert_eq!(node_state.write_source("key_c"), None);
+        node_state.set_with_source("key_a", "4", WriteSource::Internal);
+        assert_eq!(
+            node_state.write_source("key_a"),
+            Some(WriteSource::Internal)
+        );
+
+        node_state.mark_for_deletion("key_b");
+        node_state.set("key_d", "5");
+        node_state.gc_keys_marked_for_deletion(0);
+        assert_eq!(node_state.write_source("key_b"), None);
+    }
+
+    #[test]
+    fn test_node_state_remove() {
+        let mut node_state = NodeState::default();
+        node_state.set("key_a", "1");
+        assert_eq!(node_state.remove("key_a").as_deref(), Some("1"));
         assert_eq!(
             node_state.get_versioned("key_a").unwrap(),
             &VersionedValue {
                 value: "".to_string(),
-                version: 1,
+                version: 2,
+                marked_for_deletion: true,
+            }
+        );
+        // Removing a tombstone or an unknown key still creates a tombstone.
+        assert_eq!(node_state.remove("key_a"), None);
+        assert_eq!(node_state.get_versioned("key_a").unwrap().version, 3);
+        assert_eq!(node_state.remove("key_b"), None);
+        assert_eq!(
+            node_state.get_versioned("key_b").unwrap(),
+            &VersionedValue {
+                value: "".to_string(),
+                version: 4,
+                marked_for_deletion: true,
+            }
+        );
+        assert_eq!(node_state.max_version, 4);
+    }
+
+    #[test]
+    fn test_cluster_state_remove_key() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        cluster_state.node_state_mut(&node1).set("key_a", "1");
+        assert_eq!(
+            cluster_state.remove_key(&node1, "key_a").as_deref(),
+            Some("1")
+        );
+        let node2 = NodeId::for_test_localhost(10_002);
+        assert_eq!(cluster_state.remove_key(&node2, "key_a"), None);
+        assert!(cluster_state.node_state(&node2).is_none());
+    }
+
+    #[test]
+    fn test_node_state_set_with_ttl() {
+        let mut node_state = NodeState::default();
+        let now = Instant::now();
+        node_state.set_with_ttl("key_a", "1", Duration::from_secs(10));
+        node_state.set_with_ttl("key_b", "2", Duration::from_secs(10));
+        node_state.set_with_ttl("key_c", "3", Duration::from_secs(30));
+        // Setting the key again without TTL removes its TTL.
+        node_state.set("key_b", "4");
+
+        node_state.expire_keys(now + Duration::from_secs(5));
+        assert_eq!(node_state.max_version, 4);
+
+        node_state.expire_keys(now + Duration::from_secs(20));
+        assert_eq!(node_state.max_version, 5);
+        let versioned_value = node_state.get_versioned("key_a").unwrap();
+        assert!(versioned_value.marked_for_deletion);
+        assert_eq!(versioned_value.version, 5);
+        assert_eq!(
+            node_state.write_source("key_a"),
+            Some(WriteSource::Internal)
+        );
+        assert!(
+            !node_state
+                .get_versioned("key_b")
+                .unwrap()
+                .marked_for_deletion
+        );
+        assert!(
+            !node_state
+                .get_versioned("key_c")
+                .unwrap()
+                .marked_for_deletion
+        );
+
+        // Refreshing the TTL postpones the expiry.
+        node_state.set_with_ttl("key_c", "3", Duration::from_secs(30));
+        node_state.expire_keys(now + Duration::from_secs(20));
+        assert!(
+            !node_state
+                .get_versioned("key_c")
+                .unwrap()
+                .marked_for_deletion
+        );
+    }
+
+    #[test]
+    fn test_scoped_node_state() {
+        let mut node_state = NodeState::default();
+        node_state.set("key", "0");
+        node_state.scope("indexer").set("key", "1");
+        node_state.scope("indexer").set("other_key", "2");
+        node_state.scope("searcher").set("key", "3");
+        node_state.scope("indexer_2").set("key", "4");
+
+        assert_eq!(node_state.get("key"), Some("0"));
+        assert_eq!(node_state.get("indexer:key"), Some("1"));
+        let mut indexer_state = node_state.scope("indexer");
+        assert_eq!(indexer_state.namespace(), "indexer");
+        assert_eq!(indexer_state.get("key"), Some("1"));
+        assert_eq!(
+            indexer_state.iter_key_values().collect::<Vec<_>>(),
+            [
+                (
+                    "key",
+                    &VersionedValue {
+                        value: "1".to_string(),
+                        version: 2,
+                        marked_for_deletion: false,
+                    }
+                ),
+                (
+                    "other_key",
+                    &VersionedValue {
+                        value: "2".to_string(),
+                        version: 3,
+                        marked_for_deletion: false,
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            indexer_state
+                .compare_and_set("key", Some("0"), "5")
+                .unwrap_err()
+                .key(),
+            "key"
+        );
+        assert_eq!(indexer_state.remove("other_key").as_deref(), Some("2"));
+        assert_eq!(indexer_state.num_keys(), 1);
+
+        node_state.mark_for_deletion("key");
+        node_state.set("key_2", "6");
+        node_state.scope("indexer").gc_keys_marked_for_deletion(0);
+        // Only the tombstones of the namespace are garbage collected.
+        assert!(node_state.get_versioned("indexer:other_key").is_none());
+        assert!(node_state.get_versioned("key").unwrap().marked_for_deletion);
+        assert_eq!(node_state.scope("searcher").get("key"), Some("3"));
+    }
+
+    #[test]
+    fn test_cluster_state_counter() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        cluster_state
+            .node_state_mut(&node1)
+            .increment_counter("docs", 10);
+        cluster_state
+            .node_state_mut(&node1)
+            .decrement_counter("docs", 3);
+        cluster_state
+            .node_state_mut(&node2)
+            .increment_counter("docs", 5);
+        assert_eq!(
+            cluster_state.node_state(&node1).unwrap().counter("docs"),
+            PnCounter {
+                increments: 10,
+                decrements: 3,
+            }
+        );
+        assert_eq!(cluster_state.counter_value("docs"), 12);
+        assert_eq!(cluster_state.counter_value("other"), 0);
+    }
+
+    #[test]
+    fn test_cluster_state_apply_delta_keeps_counter_contributions() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let counter_key = counter_key("docs");
+        cluster_state
+            .node_state_mut(&node1)
+            .set_with_version(counter_key.clone(), "10:2".to_string(), 5)
+            .unwrap();
+
+        // The contribution is stored as written by its node, so that all the copies of a
+        // version hold the same value.
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), &counter_key, "3:4", 6, false);
+        delta.add_node_delta(
+            node2.clone(),
+            &counter_key,
+            &format!("{}:0", u64::MAX),
+            1,
+            false,
+        );
+        cluster_state.apply_delta(delta);
+        assert_eq!(
+            cluster_state
+                .node_state(&node1)
+                .unwrap()
+                .get_versioned(&counter_key)
+                .unwrap(),
+            &VersionedValue {
+                value: "3:4".to_string(),
+                version: 6,
                 marked_for_deletion: false,
             }
         );
+        // Overflowing contributions saturate.
+        assert_eq!(cluster_state.counter_value("docs"), i64::MAX - 1);
+    }
+
+    #[test]
+    fn test_node_state_compact() {
+        let mut node_state = NodeState::default();
+        let mut value = String::with_capacity(100);
+        value.push_str("value");
+        node_state.set("key_a", &value);
+        node_state.set("key_b", "1");
+        node_state.remove("key_b");
+        assert_eq!(node_state.num_writes_since_compaction(), 3);
+        node_state
+            .set_with_version("key_c".to_string(), value, 4)
+            .unwrap();
+        let num_reclaimed_bytes = node_state.compact();
+        assert!(num_reclaimed_bytes >= 95);
+        assert_eq!(node_state.num_writes_since_compaction(), 0);
+        assert_eq!(node_state.get("key_a"), Some("value"));
+        assert_eq!(node_state.get_versioned("key_c").unwrap().version, 4);
+        assert_eq!(node_state.max_version, 4);
+    }
+
+    #[test]
+    fn test_cluster_state_snapshot_shares_node_states() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        cluster_state.node_state_mut(&node1).set("key_a", "1");
+        cluster_state.node_state_mut(&node2).set("key_a", "2");
+        let snapshot = ClusterStateSnapshot::from(&cluster_state);
+        assert!(Arc::ptr_eq(
+            &snapshot.node_states[&node1.id],
+            &cluster_state.node_states[&node1]
+        ));
+
+        // Node states are copied on write: the snapshot is left untouched.
+        cluster_state.node_state_mut(&node1).set("key_a", "3");
+        assert_eq!(snapshot.node_states[&node1.id].get("key_a"), Some("1"));
+        assert_eq!(
+            cluster_state.node_state(&node1).unwrap().get("key_a"),
+            Some("3")
+        );
+        assert!(!Arc::ptr_eq(
+            &snapshot.node_states[&node1.id],
+            &cluster_state.node_states[&node1]
+        ));
+        assert!(Arc::ptr_eq(
+            &snapshot.node_states[&node2.id],
+            &cluster_state.node_states[&node2]
+        ));
+
+        // Garbage collecting node states without tombstones does not copy them either.
+        cluster_state.gc_expired_tombstones(Duration::ZERO, &HashSet::new());
+        assert!(Arc::ptr_eq(
+            &snapshot.node_states[&node2.id],
+            &cluster_state.node_states[&node2]
+        ));
+
+        // Recording a heartbeat copies the node state, but shares its key-values.
+        assert!(cluster_state.record_digest_heartbeat(&node2, 1));
+        assert!(snapshot.node_states[&node2.id]
+            .key_values
+            .ptr_eq(&cluster_state.node_states[&node2].key_values));
+        let mut delta = Delta::default();
+        delta.add_node_delta(node2.clone(), "key_a", "2", 1, false);
+        cluster_state.apply_delta(delta);
+        assert!(snapshot.node_states[&node2.id]
+            .key_values
+            .ptr_eq(&cluster_state.node_states[&node2].key_values));
+    }
+
+    #[test]
+    fn test_cluster_state_compact_node_states() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        for i in 0..10 {
+            cluster_state.node_state_mut(&node1).set("key", i);
+        }
+        cluster_state.node_state_mut(&node2).set("key", 0);
+        let (num_compacted_node_states, _) = cluster_state.compact_node_states(10);
+        assert_eq!(num_compacted_node_states, 1);
+        assert_eq!(
+            cluster_state
+                .node_state(&node1)
+                .unwrap()
+                .num_writes_since_compaction(),
+            0
+        );
+        assert_eq!(
+            cluster_state
+                .node_state(&node2)
+                .unwrap()
+                .num_writes_since_compaction(),
+            1
+        );
     }
 
+    #[cfg(feature = "json")]
     #[test]
-    fn test_cluster_state_set() {
-        let mut cluster_state = ClusterState::default();
-        let node_state = cluster_state.node_state_mut(&NodeId::for_test_localhost(10_001));
-        node_state.set("key_a", "1");
+    fn test_node_state_or_set() {
+        let mut node_state = NodeState::default();
+        node_state.add_to_or_set("shards", "shard-1");
+        node_state.add_to_or_set("shards", "shard-2");
+        assert!(node_state.remove_from_or_set("shards", "shard-1"));
+        assert!(!node_state.remove_from_or_set("shards", "shard-1"));
+        assert_eq!(node_state.max_version, 3);
         assert_eq!(
-            node_state.get_versioned("key_a").unwrap(),
-            &VersionedValue {
-                value: "1".to_string(),
-                version: 1,
-                marked_for_deletion: false,
-            }
+            node_state.or_set("shards").elements().collect::<Vec<_>>(),
+            ["shard-2"]
         );
-        node_state.set("key_b", "2");
-        assert_eq!(
-            node_state.get_versioned("key_a").unwrap(),
-            &VersionedValue {
-                value: "1".to_string(),
-                version: 1,
-                marked_for_deletion: false,
-            }
+        assert!(node_state.or_set("other").is_empty());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_cluster_state_or_set() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        cluster_state
+            .node_state_mut(&node1)
+            .add_to_or_set("shards", "shard-1");
+        let mut incoming_or_set = OrSet::default();
+        incoming_or_set.insert("shard-2", 2);
+        let mut delta = Delta::default();
+        delta.add_node_delta(
+            node2.clone(),
+            &or_set_key("shards"),
+            &incoming_or_set.encode(),
+            2,
+            false,
         );
+        cluster_state.apply_delta(delta);
+        // The set of each node is stored as written by the node, and merged when read.
         assert_eq!(
-            node_state.get_versioned("key_b").unwrap(),
-            &VersionedValue {
-                value: "2".to_string(),
-                version: 2,
-                marked_for_deletion: false,
-            }
+            cluster_state.node_state(&node2).unwrap().or_set("shards"),
+            incoming_or_set
         );
-        node_state.set("key_a", "3");
         assert_eq!(
-            node_state.get_versioned("key_a").unwrap(),
-            &VersionedValue {
-                value: "3".to_string(),
-                version: 3,
-                marked_for_deletion: false,
-            }
+            cluster_state
+                .or_set("shards")
+                .elements()
+                .collect::<Vec<_>>(),
+            ["shard-1", "shard-2"]
         );
     }
 
+    #[cfg(feature = "json")]
     #[test]
-    fn test_cluster_state_set_with_same_value_updates_version() {
-        let mut cluster_state = ClusterState::default();
-        let node_state = cluster_state.node_state_mut(&NodeId::for_test_localhost(10_001));
-        node_state.set("key", "1");
+    fn test_node_state_typed_get_set() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Endpoint {
+            host: String,
+            port: u16,
+        }
+        let mut node_state = NodeState::default();
+        let endpoint = Endpoint {
+            host: "localhost".to_string(),
+            port: 7280,
+        };
+        node_state.set_typed("endpoint", &endpoint).unwrap();
         assert_eq!(
-            node_state.get_versioned("key").unwrap(),
-            &VersionedValue {
-                value: "1".to_string(),
-                version: 1,
-                marked_for_deletion: false,
-            }
+            node_state.get("endpoint").unwrap(),
+            r#"{"host":"localhost","port":7280}"#
         );
-        node_state.set("key", "1");
         assert_eq!(
-            node_state.get_versioned("key").unwrap(),
-            &VersionedValue {
-                value: "1".to_string(),
-                version: 2,
-                marked_for_deletion: false,
-            }
+            node_state.get_typed::<Endpoint>("endpoint").unwrap(),
+            Some(endpoint)
         );
+        assert_eq!(node_state.get_typed::<Endpoint>("missing").unwrap(), None);
+
+        node_state.set("endpoint", "not-json");
+        let error = node_state.get_typed::<Endpoint>("endpoint").unwrap_err();
+        assert!(matches!(error, TypedValueError::Decode { ref key, .. } if key == "endpoint"));
     }
 
     #[test]
-    fn test_cluster_state_set_and_mark_for_deletion() {
-        let mut cluster_state = ClusterState::default();
-        let node_state = cluster_state.node_state_mut(&NodeId::for_test_localhost(10_001));
-        node_state.set("key", "1");
-        node_state.mark_for_deletion("key");
-        assert_eq!(
-            node_state.get_versioned("key").unwrap(),
-            &VersionedValue {
-                value: "1".to_string(),
-                version: 2,
-                marked_for_deletion: true,
-            }
-        );
-        node_state.set("key", "2");
+    fn test_node_state_view() {
+        let node_id = NodeId::for_test_localhost(10_001);
+        let mut node_state = NodeState::default();
+        node_state.set("key_a", "1");
+        node_state.set("key_b", "2");
+        node_state.mark_for_deletion("key_b");
+        let mut node_state = Arc::new(node_state);
+        let node_state_view = NodeStateView::new(node_id.clone(), node_state.clone());
+        Arc::make_mut(&mut node_state).set("key_c", "3");
+
+        assert_eq!(node_state_view.node_id(), &node_id);
+        assert_eq!(node_state_view.max_version(), 3);
+        assert_eq!(node_state_view.get("key_a"), Some("1"));
+        assert_eq!(node_state_view.get("key_b"), None);
+        assert_eq!(node_state_view.get("key_c"), None);
         assert_eq!(
-            node_state.get_versioned("key").unwrap(),
-            &VersionedValue {
-                value: "2".to_string(),
-                version: 3,
-                marked_for_deletion: false,
-            }
+            node_state_view
+                .iter_key_values()
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>(),
+            ["key_a"]
         );
     }
 
@@ -516,17 +3404,71 @@ mod tests {
 
         let dead_nodes = HashSet::new();
         let digest = cluster_state.compute_digest(&dead_nodes);
-        let mut node_max_version_map = BTreeMap::default();
-        node_max_version_map.insert(node1.clone(), 2);
-        node_max_version_map.insert(node2.clone(), 1);
-        assert_eq!(&digest.node_max_version, &node_max_version_map);
+        assert_eq!(digest.num_nodes(), 2);
+        assert_eq!(digest.max_version(&node1), Some(2));
+        assert_eq!(digest.max_version(&node2), Some(1));
 
         // exclude node1
         let dead_nodes = HashSet::from_iter([&node1]);
         let digest = cluster_state.compute_digest(&dead_nodes);
-        let mut node_max_version_map = BTreeMap::default();
-        node_max_version_map.insert(node2, 1);
-        assert_eq!(&digest.node_max_version, &node_max_version_map);
+        assert_eq!(digest.num_nodes(), 1);
+        assert_eq!(digest.max_version(&node1), None);
+        assert_eq!(digest.max_version(&node2), Some(1));
+    }
+
+    #[test]
+    fn test_cluster_state_digest_follows_changes() {
+        fn rebuilt_digest(cluster_state: &ClusterState) -> Digest {
+            let mut digest = Digest::default();
+            for (node_id, node_state) in &cluster_state.node_states {
+                digest.add_node_with_heartbeat(
+                    node_id.clone(),
+                    node_state.heartbeat(),
+                    node_state.max_version,
+                );
+            }
+            digest
+        }
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let node3 = NodeId::for_test_localhost(10_003);
+        cluster_state.node_state_mut(&node1).set("key_a", "1");
+        cluster_state.node_state_mut(&node2).set("key_a", "1");
+        let no_dead_nodes = HashSet::new();
+        assert_eq!(
+            cluster_state.compute_digest(&no_dead_nodes),
+            rebuilt_digest(&cluster_state)
+        );
+
+        cluster_state.node_state_mut(&node1).set("key_b", "2");
+        cluster_state.node_state_mut(&node1).increment_heartbeat();
+        assert!(cluster_state.record_digest_heartbeat(&node2, 5));
+        assert!(!cluster_state.record_digest_heartbeat(&node2, 5));
+        let mut delta = Delta::default();
+        delta.add_node_delta(node3.clone(), "key_a", "1", 7, false);
+        cluster_state.apply_delta(delta);
+        let digest = cluster_state.compute_digest(&no_dead_nodes);
+        assert_eq!(digest, rebuilt_digest(&cluster_state));
+        assert_eq!(digest.max_version(&node1), Some(2));
+        assert_eq!(digest.get(&node2).unwrap().heartbeat, 5);
+        assert_eq!(digest.max_version(&node3), Some(7));
+
+        cluster_state.remove_node(&node3);
+        let dead_nodes = HashSet::from_iter([&node2]);
+        let digest = cluster_state.compute_digest(&dead_nodes);
+        assert_eq!(
+            digest
+                .iter()
+                .map(|(node_id, _)| node_id)
+                .collect::<Vec<_>>(),
+            [&node1]
+        );
+        // Dead nodes are only excluded from the digest returned.
+        assert_eq!(
+            cluster_state.compute_digest(&no_dead_nodes),
+            rebuilt_digest(&cluster_state)
+        );
     }
 
     #[test]
@@ -534,9 +3476,13 @@ mod tests {
         let mut cluster_state = ClusterState::default();
         let node1 = NodeId::for_test_localhost(10_001);
         let node1_state = cluster_state.node_state_mut(&node1);
-        node1_state.set_with_version("key_a".to_string(), "1".to_string(), 1); // 1
+        node1_state
+            .set_with_version("key_a".to_string(), "1".to_string(), 1)
+            .unwrap(); // 1
         node1_state.mark_for_deletion("key_a"); // 2
-        node1_state.set_with_version("key_b".to_string(), "3".to_string(), 13); // 3
+        node1_state
+            .set_with_version("key_b".to_string(), "3".to_string(), 13)
+            .unwrap(); // 3
 
         // No gc.
         cluster_state.gc_keys_marked_for_deletion(11, &HashSet::new());
@@ -568,17 +3514,171 @@ mod tests {
             .is_some());
     }
 
+    #[test]
+    fn test_cluster_state_apply_delta_does_not_resurrect_gced_keys() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "", 2, true);
+        delta.add_node_delta(node1.clone(), "key_b", "2", 5, false);
+        cluster_state.apply_delta(delta);
+        cluster_state.gc_keys_marked_for_deletion(1, &HashSet::new());
+        assert!(cluster_state
+            .node_state(&node1)
+            .unwrap()
+            .get_versioned("key_a")
+            .is_none());
+
+        // A delayed delta, emitted before the deletion of `key_a`, arrives after its tombstone
+        // was garbage collected.
+        let mut delayed_delta = Delta::default();
+        delayed_delta.add_node_delta(node1.clone(), "key_a", "1", 1, false);
+        cluster_state.apply_delta(delayed_delta);
+        assert!(cluster_state
+            .node_state(&node1)
+            .unwrap()
+            .get_versioned("key_a")
+            .is_none());
+
+        // Newer writes go through.
+        let mut delta = Delta::default();
+        delta.add_node_delta(node1.clone(), "key_a", "6", 6, false);
+        cluster_state.apply_delta(delta);
+        assert_eq!(
+            cluster_state.node_state(&node1).unwrap().get("key_a"),
+            Some("6")
+        );
+
+        // A reset starts over from a fresh watermark.
+        let mut delta = Delta::default();
+        delta.add_node_to_reset(node1.clone());
+        delta.add_node_delta(node1.clone(), "key_a", "1", 1, false);
+        cluster_state.apply_delta(delta);
+        assert_eq!(
+            cluster_state.node_state(&node1).unwrap().get("key_a"),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn test_node_state_gc_expired_tombstones() {
+        let mut node_state = NodeState::default();
+        node_state.set("key_a", "1");
+        node_state.set("key_b", "2");
+        node_state.mark_for_deletion("key_a");
+        node_state.gc_expired_tombstones(Duration::from_secs(3_600));
+        assert!(node_state.get_versioned("key_a").is_some());
+        node_state.gc_expired_tombstones(Duration::ZERO);
+        assert!(node_state.get_versioned("key_a").is_none());
+        assert_eq!(node_state.get("key_b"), Some("2"));
+        assert_eq!(node_state.gc_watermark, 3);
+        assert!(node_state.deletion_timestamps.is_empty());
+    }
+
+    #[test]
+    fn test_cluster_state_compute_delta_wall_clock_reset() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node1_state = cluster_state.node_state_mut(&node1);
+        node1_state.set("key_a", "1");
+        node1_state.set("key_b", "2");
+        node1_state.mark_for_deletion("key_a");
+        node1_state.set("key_c", "3");
+        cluster_state.gc_expired_tombstones(Duration::ZERO, &HashSet::new());
+
+        // The peer saw the tombstone of `key_a`: no reset is needed, whatever the number of
+        // versions written since.
+        let mut digest = Digest::default();
+        digest.add_node(node1.clone(), 3);
+        let delta = cluster_state.compute_delta(
+            &digest,
+            MAX_UDP_DATAGRAM_PAYLOAD_SIZE,
+            HashSet::new(),
+            0,
+            OversizedKeyValuePolicy::default(),
+            &HashSet::new(),
+        );
+        let mut expected_delta = Delta::default();
+        expected_delta.add_node_delta(node1.clone(), "key_c", "3", 4, false);
+        assert_eq!(delta, expected_delta);
+
+        // The peer may still hold `key_a`.
+        let mut digest = Digest::default();
+        digest.add_node(node1.clone(), 2);
+        let delta = cluster_state.compute_delta(
+            &digest,
+            MAX_UDP_DATAGRAM_PAYLOAD_SIZE,
+            HashSet::new(),
+            10_000,
+            OversizedKeyValuePolicy::default(),
+            &HashSet::new(),
+        );
+        let mut expected_delta = Delta::default();
+        expected_delta.add_node_to_reset(node1.clone());
+        expected_delta.add_node_delta(node1.clone(), "key_b", "2", 2, false);
+        expected_delta.add_node_delta(node1.clone(), "key_c", "3", 4, false);
+        assert_eq!(delta, expected_delta);
+
+        // The peer omits the nodes it deems dead from its digest, and may still hold `key_a`.
+        let delta_for_omitted_node = cluster_state.compute_delta(
+            &Digest::default(),
+            MAX_UDP_DATAGRAM_PAYLOAD_SIZE,
+            HashSet::new(),
+            10_000,
+            OversizedKeyValuePolicy::default(),
+            &HashSet::new(),
+        );
+        assert_eq!(delta_for_omitted_node, expected_delta);
+
+        // Once reset, the peer knows that `key_a` was deleted, and resets its own lagging peers.
+        let mut peer_cluster_state = ClusterState::default();
+        peer_cluster_state.apply_delta(delta);
+        assert_eq!(
+            peer_cluster_state.node_state(&node1).unwrap().gc_watermark,
+            4
+        );
+    }
+
+    #[test]
+    fn test_cluster_state_shares_keys() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        cluster_state.node_state_mut(&node1).set("key_a", "1");
+        let mut delta = Delta::default();
+        delta.add_node_delta(node2.clone(), "key_a", "2", 1, false);
+        let buf = delta.serialize_to_vec();
+        cluster_state.apply_delta(Delta::deserialize(&mut &buf[..]).unwrap());
+
+        let interned_key = |node_id: &NodeId| {
+            let node_state = cluster_state.node_state(node_id).unwrap();
+            node_state
+                .key_values
+                .get_key_value("key_a")
+                .unwrap()
+                .0
+                .clone()
+        };
+        assert!(Arc::ptr_eq(&interned_key(&node1), &interned_key(&node2)));
+    }
+
     #[test]
     fn test_cluster_state_apply_delta() {
         let mut cluster_state = ClusterState::default();
 
         let node1 = NodeId::for_test_localhost(10_001);
         let node1_state = cluster_state.node_state_mut(&node1);
-        node1_state.set_with_version("key_a".to_string(), "1".to_string(), 1); // 1
-        node1_state.set_with_version("key_b".to_string(), "3".to_string(), 3); // 2
+        node1_state
+            .set_with_version("key_a".to_string(), "1".to_string(), 1)
+            .unwrap(); // 1
+        node1_state
+            .set_with_version("key_b".to_string(), "3".to_string(), 3)
+            .unwrap(); // 2
         let node2 = NodeId::for_test_localhost(10_002);
         let node2_state = cluster_state.node_state_mut(&node2);
-        node2_state.set_with_version("key_c".to_string(), "3".to_string(), 1); // 1
+        node2_state
+            .set_with_version("key_c".to_string(), "3".to_string(), 1)
+            .unwrap(); // 1
 
         let mut delta = Delta::default();
         delta.add_node_delta(node1.clone(), "key_a", "4", 4, false);
@@ -627,13 +3727,26 @@ mod tests {
         exclude_node_ids: HashSet<&NodeId>,
         expected_delta_atoms: &[(&NodeId, &str, &str, Version, bool)],
     ) {
-        let max_delta =
-            cluster_state.compute_delta(digest, usize::MAX, exclude_node_ids.clone(), 10_000);
+        let max_delta = cluster_state.compute_delta(
+            digest,
+            usize::MAX,
+            exclude_node_ids.clone(),
+            10_000,
+            OversizedKeyValuePolicy::default(),
+            &HashSet::new(),
+        );
         let mut buf = Vec::new();
         max_delta.serialize(&mut buf);
         let mut mtu_per_num_entries = Vec::new();
         for mtu in 2..buf.len() {
-            let delta = cluster_state.compute_delta(digest, mtu, exclude_node_ids.clone(), 10_000);
+            let delta = cluster_state.compute_delta(
+                digest,
+                mtu,
+                exclude_node_ids.clone(),
+                10_000,
+                OversizedKeyValuePolicy::default(),
+                &HashSet::new(),
+            );
             let num_tuples = delta.num_tuples();
             if mtu_per_num_entries.len() == num_tuples + 1 {
                 continue;
@@ -650,13 +3763,25 @@ mod tests {
                 expected_delta.add_node_delta(node.clone(), key, val, version, marked_for_deletion);
             }
             {
-                let delta =
-                    cluster_state.compute_delta(digest, mtu, exclude_node_ids.clone(), 10_000);
+                let delta = cluster_state.compute_delta(
+                    digest,
+                    mtu,
+                    exclude_node_ids.clone(),
+                    10_000,
+                    OversizedKeyValuePolicy::default(),
+                    &HashSet::new(),
+                );
                 assert_eq!(&delta, &expected_delta);
             }
             {
-                let delta =
-                    cluster_state.compute_delta(digest, mtu + 1, exclude_node_ids.clone(), 10_000);
+                let delta = cluster_state.compute_delta(
+                    digest,
+                    mtu + 1,
+                    exclude_node_ids.clone(),
+                    10_000,
+                    OversizedKeyValuePolicy::default(),
+                    &HashSet::new(),
+                );
                 assert_eq!(&delta, &expected_delta);
             }
         }
@@ -667,15 +3792,27 @@ mod tests {
 
         let node1 = NodeId::for_test_localhost(10_001);
         let node1_state = cluster_state.node_state_mut(&node1);
-        node1_state.set_with_version("key_a".to_string(), "1".to_string(), 1); // 1
-        node1_state.set_with_version("key_b".to_string(), "2".to_string(), 2); // 3
+        node1_state
+            .set_with_version("key_a".to_string(), "1".to_string(), 1)
+            .unwrap(); // 1
+        node1_state
+            .set_with_version("key_b".to_string(), "2".to_string(), 2)
+            .unwrap(); // 3
 
         let node2 = NodeId::for_test_localhost(10_002);
         let node2_state = cluster_state.node_state_mut(&node2);
-        node2_state.set_with_version("key_a".to_string(), "1".to_string(), 1); // 1
-        node2_state.set_with_version("key_b".to_string(), "2".to_string(), 2); // 2
-        node2_state.set_with_version("key_c".to_string(), "3".to_string(), 3); // 3
-        node2_state.set_with_version("key_d".to_string(), "4".to_string(), 4); // 4
+        node2_state
+            .set_with_version("key_a".to_string(), "1".to_string(), 1)
+            .unwrap(); // 1
+        node2_state
+            .set_with_version("key_b".to_string(), "2".to_string(), 2)
+            .unwrap(); // 2
+        node2_state
+            .set_with_version("key_c".to_string(), "3".to_string(), 3)
+            .unwrap(); // 3
+        node2_state
+            .set_with_version("key_d".to_string(), "4".to_string(), 4)
+            .unwrap(); // 4
         node2_state.mark_for_deletion("key_d"); // 5
 
         cluster_state
@@ -695,8 +3832,8 @@ mod tests {
             HashSet::new(),
             &[
                 (&node2, "key_c", "3", 3, false),
-                (&node2, "key_d", "4", 5, true),
                 (&node1, "key_b", "2", 2, false),
+                (&node2, "key_d", "4", 5, true),
             ],
         );
     }
@@ -715,8 +3852,8 @@ mod tests {
             HashSet::new(),
             &[
                 (&node2, "key_c", "3", 3, false),
-                (&node2, "key_d", "4", 5, true),
                 (&node1, "key_b", "2", 2, false),
+                (&node2, "key_d", "4", 5, true),
             ],
         );
     }
@@ -734,19 +3871,118 @@ mod tests {
             HashSet::new(),
             &[
                 (&node1, "key_a", "1", 1, false),
+                (&node2, "key_d", "4", 5, true),
                 (&node1, "key_b", "2", 2, false),
-                (&node2, "key_d", "4", 4, false),
             ],
         );
     }
 
+    #[test]
+    fn test_fair_shares() {
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let node3 = NodeId::for_test_localhost(10_003);
+        let shares = fair_shares(vec![(&node1, 1_000), (&node2, 10), (&node3, 50)], 100);
+        assert_eq!(shares[&node1], 45);
+        assert_eq!(shares[&node2], 10);
+        assert_eq!(shares[&node3], 45);
+        let shares = fair_shares(vec![(&node1, 20), (&node2, 10)], 100);
+        assert_eq!(shares[&node1], 20);
+        assert_eq!(shares[&node2], 10);
+    }
+
+    #[test]
+    fn test_cluster_state_compute_delta_does_not_starve_slightly_stale_nodes() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node1_state = cluster_state.node_state_mut(&node1);
+        for i in 0..1_000 {
+            node1_state.set(format!("key_{i:04}"), "x".repeat(100));
+        }
+        let node2 = NodeId::for_test_localhost(10_002);
+        let node3 = NodeId::for_test_localhost(10_003);
+        for node_id in [&node2, &node3] {
+            let node_state = cluster_state.node_state_mut(node_id);
+            node_state.set("key_a", "1");
+            node_state.set("key_b", "2");
+        }
+        let mut digest = Digest::default();
+        for node_id in [&node1, &node2, &node3] {
+            digest.add_node(node_id.clone(), 1);
+        }
+        let delta = cluster_state.compute_delta(
+            &digest,
+            MAX_UDP_DATAGRAM_PAYLOAD_SIZE,
+            HashSet::new(),
+            10_000,
+            OversizedKeyValuePolicy::default(),
+            &HashSet::new(),
+        );
+        for node_id in [&node2, &node3] {
+            assert_eq!(
+                delta.node_deltas[node_id]
+                    .key_values
+                    .keys()
+                    .map(|key| &**key)
+                    .collect::<Vec<_>>(),
+                ["key_b"]
+            );
+        }
+        // The node with the huge backlog gets the rest of the delta.
+        let mut buf = Vec::new();
+        delta.serialize(&mut buf);
+        assert!(delta.node_deltas[&node1].num_tuples() > 500);
+        assert!(buf.len() > MAX_UDP_DATAGRAM_PAYLOAD_SIZE - 200);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_cluster_state_compute_delta_sends_stale_metadata_first() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node1_state = cluster_state.node_state_mut(&node1);
+        for i in 0..5 {
+            node1_state.set(format!("key_{i}"), "1");
+        }
+        let node2 = NodeId::for_test_localhost(10_002);
+        cluster_state.node_state_mut(&node2).set("key_a", "1");
+        let compute_delta = |cluster_state: &ClusterState| {
+            // Only one node fits in the delta.
+            cluster_state.compute_delta(
+                &Digest::default(),
+                60,
+                HashSet::new(),
+                10_000,
+                OversizedKeyValuePolicy::default(),
+                &HashSet::new(),
+            )
+        };
+        let delta = compute_delta(&cluster_state);
+        assert!(delta.node_deltas.contains_key(&node1));
+        assert!(!delta.node_deltas.contains_key(&node2));
+
+        let metadata = NodeMetadata {
+            build_version: Some("0.5.0".to_string()),
+            ..Default::default()
+        };
+        cluster_state.node_state_mut(&node2).set_metadata(&metadata);
+        let delta = compute_delta(&cluster_state);
+        assert!(!delta.node_deltas.contains_key(&node1));
+        assert!(delta.node_deltas.contains_key(&node2));
+
+        let node2_metadata = cluster_state.node_metadata(&node2).unwrap();
+        assert_eq!(node2_metadata.version, 2);
+        assert_eq!(node2_metadata.build_version.as_deref(), Some("0.5.0"));
+        assert!(cluster_state.node_metadata(&node1).is_none());
+    }
+
     #[test]
     fn test_cluster_state_compute_delta_should_ignore_dead_nodes() {
         let cluster_state = test_cluster_state();
         let digest = Digest::default();
         let node1 = NodeId::for_test_localhost(10_001);
         let node2 = NodeId::for_test_localhost(10_002);
-        let dead_nodes = vec![node2];
+        let dead_nodes = [node2];
         test_with_varying_max_transmitted_kv_helper(
             &cluster_state,
             &digest,
@@ -760,16 +3996,25 @@ mod tests {
 
     #[test]
     fn test_cluster_state_compute_delta_with_old_node_state_that_needs_reset() {
-        let mut cluster_state = ClusterState::default();
+        let mut cluster_state = ClusterState {
+            tombstone_gc_policy: TombstoneGcPolicy::VersionCount,
+            ..Default::default()
+        };
 
         let node1 = NodeId::for_test_localhost(10_001);
         let node1_state = cluster_state.node_state_mut(&node1);
-        node1_state.set_with_version("key_a".to_string(), "1".to_string(), 1); // 1
-        node1_state.set_with_version("key_b".to_string(), "2".to_string(), 10_003); // 10_003
+        node1_state
+            .set_with_version("key_a".to_string(), "1".to_string(), 1)
+            .unwrap(); // 1
+        node1_state
+            .set_with_version("key_b".to_string(), "2".to_string(), 10_003)
+            .unwrap(); // 10_003
 
         let node2 = NodeId::for_test_localhost(10_002);
         let node2_state = cluster_state.node_state_mut(&node2);
-        node2_state.set_with_version("key_c".to_string(), "3".to_string(), 2); // 2
+        node2_state
+            .set_with_version("key_c".to_string(), "3".to_string(), 2)
+            .unwrap(); // 2
 
         let mut digest = Digest::default();
         let node1 = NodeId::for_test_localhost(10_001);
@@ -780,6 +4025,8 @@ mod tests {
                 MAX_UDP_DATAGRAM_PAYLOAD_SIZE,
                 HashSet::new(),
                 10_002,
+                OversizedKeyValuePolicy::default(),
+                &HashSet::new(),
             );
             assert!(delta.nodes_to_reset.is_empty());
             let mut expected_delta = Delta::default();
@@ -796,6 +4043,8 @@ mod tests {
                 MAX_UDP_DATAGRAM_PAYLOAD_SIZE,
                 HashSet::new(),
                 10_000,
+                OversizedKeyValuePolicy::default(),
+                &HashSet::new(),
             );
             let mut expected_delta = Delta::default();
             expected_delta.add_node_to_reset(node1.clone());
@@ -805,4 +4054,132 @@ mod tests {
             assert_eq!(delta, expected_delta);
         }
     }
+
+    fn test_cluster_state_with_oversized_key_value() -> ClusterState {
+        let mut cluster_state = ClusterState::default();
+
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node1_state = cluster_state.node_state_mut(&node1);
+        node1_state
+            .set_with_version("key_a".to_string(), "1".to_string(), 1)
+            .unwrap(); // 1
+        node1_state
+            .set_with_version("key_b".to_string(), "2".repeat(1_000), 2)
+            .unwrap(); // 2
+        node1_state
+            .set_with_version("key_c".to_string(), "3".to_string(), 3)
+            .unwrap(); // 3
+
+        let node2 = NodeId::for_test_localhost(10_002);
+        let node2_state = cluster_state.node_state_mut(&node2);
+        node2_state
+            .set_with_version("key_a".to_string(), "1".to_string(), 1)
+            .unwrap(); // 1
+
+        cluster_state
+    }
+
+    #[test]
+    fn test_cluster_state_compute_delta_skips_oversized_key_value() {
+        let cluster_state = test_cluster_state_with_oversized_key_value();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let delta = cluster_state.compute_delta(
+            &Digest::default(),
+            500,
+            HashSet::new(),
+            10_000,
+            OversizedKeyValuePolicy::Skip,
+            &HashSet::new(),
+        );
+        let mut expected_delta = Delta::default();
+        expected_delta.add_node_delta(node1.clone(), "key_a", "1", 1, false);
+        expected_delta.add_node_delta(node1, "key_c", "3", 3, false);
+        expected_delta.add_node_delta(node2, "key_a", "1", 1, false);
+        assert_eq!(delta, expected_delta);
+    }
+
+    #[test]
+    fn test_cluster_state_compute_delta_truncates_oversized_key_value() {
+        let cluster_state = test_cluster_state_with_oversized_key_value();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let delta = cluster_state.compute_delta(
+            &Digest::default(),
+            500,
+            HashSet::new(),
+            10_000,
+            OversizedKeyValuePolicy::Truncate,
+            &HashSet::new(),
+        );
+        // The oversized key-value stalls node 1, but node 2 is still gossiped.
+        let mut expected_delta = Delta::default();
+        expected_delta.add_node_delta(node1.clone(), "key_a", "1", 1, false);
+        expected_delta.add_node_delta(node2, "key_a", "1", 1, false);
+        assert_eq!(delta, expected_delta);
+        // The oversized key-value was reported while computing the delta, and is not reported
+        // again at the next rounds.
+        assert!(!cluster_state.should_report_oversized_key_value(&node1, 2));
+        assert!(cluster_state.should_report_oversized_key_value(&node1, 4));
+    }
+
+    #[test]
+    fn test_cluster_state_compute_delta_keeps_batch_together() {
+        let mut cluster_state = ClusterState::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node1_state = cluster_state.node_state_mut(&node1);
+        node1_state.set("key_a", "1");
+        node1_state.set_batch([("key_b", "2".repeat(80)), ("key_c", "3".repeat(80))]);
+
+        // There is room for `key_b` but not for `key_c`: the whole batch is left out.
+        let delta = cluster_state.compute_delta(
+            &Digest::default(),
+            200,
+            HashSet::new(),
+            10_000,
+            OversizedKeyValuePolicy::Skip,
+            &HashSet::new(),
+        );
+        let mut expected_delta = Delta::default();
+        expected_delta.add_node_delta(node1.clone(), "key_a", "1", 1, false);
+        assert_eq!(delta, expected_delta);
+
+        let delta = cluster_state.compute_delta(
+            &Digest::default(),
+            300,
+            HashSet::new(),
+            10_000,
+            OversizedKeyValuePolicy::Skip,
+            &HashSet::new(),
+        );
+        let mut expected_delta = Delta::default();
+        expected_delta.add_node_delta(node1.clone(), "key_a", "1", 1, false);
+        expected_delta.add_node_delta(node1.clone(), "key_b", &"2".repeat(80), 2, false);
+        expected_delta.add_node_delta(node1, "key_c", &"3".repeat(80), 2, false);
+        assert_eq!(delta, expected_delta);
+    }
+
+    #[test]
+    fn test_cluster_state_compute_delta_with_forced_reset() {
+        let cluster_state = test_cluster_state();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let mut digest = Digest::default();
+        digest.add_node(node1.clone(), 2);
+        digest.add_node(node2.clone(), 5);
+        let delta = cluster_state.compute_delta(
+            &digest,
+            MAX_UDP_DATAGRAM_PAYLOAD_SIZE,
+            HashSet::new(),
+            10_000,
+            OversizedKeyValuePolicy::default(),
+            &HashSet::from_iter([node1.clone()]),
+        );
+        // Node 1 is up to date according to the digest, but its reset is forced.
+        let mut expected_delta = Delta::default();
+        expected_delta.add_node_to_reset(node1.clone());
+        expected_delta.add_node_delta(node1.clone(), "key_a", "1", 1, false);
+        expected_delta.add_node_delta(node1, "key_b", "2", 2, false);
+        assert_eq!(delta, expected_delta);
+    }
 }
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::{NodeId, Version};
+
+/// Gossip exchanges of the node with one of its peers. See [`GossipTopology`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct GossipEdge {
+    pub peer_addr: SocketAddr,
+    /// Node id of the peer, `None` if its state is not known yet.
+    pub node_id: Option<NodeId>,
+    /// Exchanges initiated by the node, i.e. syn acks received from the peer.
+    pub num_initiated_exchanges: u64,
+    /// Exchanges initiated by the peer, i.e. syns received from it.
+    pub num_received_exchanges: u64,
+    /// Time elapsed since the last exchange with the peer, in milliseconds.
+    pub millis_since_last_exchange: u64,
+    /// Number of versions of the self node the peer lagged behind, as of its last digest.
+    pub lag_versions: Option<Version>,
+}
+
+/// Recent gossip exchanges of a node with its peers, returned by
+/// [`crate::Chitchat::gossip_topology`].
+///
+/// Each node only sees its own edges: the connectivity of the cluster is the union of the
+/// topologies of its nodes. A node, or a group of nodes, without any recent edge to the rest of
+/// the cluster is isolated.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct GossipTopology {
+    pub node_id: NodeId,
+    /// Edges, sorted by peer address.
+    pub edges: Vec<GossipEdge>,
+}
+
+impl GossipTopology {
+    /// Renders the topology as a Graphviz DOT digraph, with an edge from the node initiating
+    /// exchanges to its peer, labelled with the number of exchanges and the time elapsed since
+    /// the last one. The edge lines of the topologies of several nodes can be concatenated into
+    /// a single digraph of the cluster.
+    pub fn to_dot(&self) -> String {
+        let self_label = &self.node_id.id;
+        let mut dot = "digraph chitchat {\n".to_string();
+        for edge in &self.edges {
+            let peer_label = edge
+                .node_id
+                .as_ref()
+                .map(|node_id| node_id.id.clone())
+                .unwrap_or_else(|| edge.peer_addr.to_string());
+            let directed_edges = [
+                (self_label, &peer_label, edge.num_initiated_exchanges),
+                (&peer_label, self_label, edge.num_received_exchanges),
+            ];
+            for (from_label, to_label, num_exchanges) in directed_edges {
+                if num_exchanges == 0 {
+                    continue;
+                }
+                let _ = writeln!(
+                    dot,
+                    "  {from_label:?} -> {to_label:?} [label=\"{num_exchanges} exchanges, {}ms \
+                     ago\"];",
+                    edge.millis_since_last_exchange
+                );
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[derive(Debug)]
+struct Interaction {
+    num_initiated_exchanges: u64,
+    num_received_exchanges: u64,
+    last_exchange_at: Instant,
+}
+
+/// Keeps track of the gossip exchanges with each peer.
+#[derive(Debug, Default)]
+pub(crate) struct InteractionTracker {
+    interactions: HashMap<SocketAddr, Interaction>,
+}
+
+impl InteractionTracker {
+    /// Records an exchange with `peer_addr`, initiated by us if `initiated` is true.
+    pub fn record_exchange(&mut self, peer_addr: SocketAddr, initiated: bool, now: Instant) {
+        let interaction = self
+            .interactions
+            .entry(peer_addr)
+            .or_insert_with(|| Interaction {
+                num_initiated_exchanges: 0,
+                num_received_exchanges: 0,
+                last_exchange_at: now,
+            });
+        if initiated {
+            interaction.num_initiated_exchanges += 1;
+        } else {
+            interaction.num_received_exchanges += 1;
+        }
+        interaction.last_exchange_at = now;
+    }
+
+    pub fn forget_peer(&mut self, peer_addr: SocketAddr) {
+        self.interactions.remove(&peer_addr);
+    }
+
+    /// Returns the edges of the topology, resolving the node id and the lag of each peer with
+    /// `peer_info`.
+    pub fn edges(
+        &self,
+        now: Instant,
+        peer_info: impl Fn(SocketAddr) -> (Option<NodeId>, Option<Version>),
+    ) -> Vec<GossipEdge> {
+        let mut edges: Vec<GossipEdge> = self
+            .interactions
+            .iter()
+            .map(|(peer_addr, interaction)| {
+                let (node_id, lag_versions) = peer_info(*peer_addr);
+                GossipEdge {
+                    peer_addr: *peer_addr,
+                    node_id,
+                    num_initiated_exchanges: interaction.num_initiated_exchanges,
+                    num_received_exchanges: interaction.num_received_exchanges,
+                    millis_since_last_exchange: now
+                        .saturating_duration_since(interaction.last_exchange_at)
+                        .as_millis() as u64,
+                    lag_versions,
+                }
+            })
+            .collect();
+        edges.sort_by_key(|edge| edge.peer_addr);
+        edges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_gossip_topology_to_dot() {
+        let node_id = NodeId::for_test_localhost(10_001);
+        let peer_id = NodeId::for_test_localhost(10_002);
+        let unknown_peer_addr: SocketAddr = "127.0.0.1:10003".parse().unwrap();
+        let mut interaction_tracker = InteractionTracker::default();
+        let now = Instant::now();
+        interaction_tracker.record_exchange(peer_id.gossip_public_address, true, now);
+        interaction_tracker.record_exchange(peer_id.gossip_public_address, false, now);
+        interaction_tracker.record_exchange(peer_id.gossip_public_address, true, now);
+        interaction_tracker.record_exchange(unknown_peer_addr, false, now);
+        let edges = interaction_tracker.edges(now + Duration::from_millis(50), |peer_addr| {
+            if peer_addr == peer_id.gossip_public_address {
+                (Some(peer_id.clone()), Some(2))
+            } else {
+                (None, None)
+            }
+        });
+        assert_eq!(edges.len(), 2);
+        assert_eq!(edges[0].num_initiated_exchanges, 2);
+        assert_eq!(edges[0].num_received_exchanges, 1);
+        assert_eq!(edges[0].millis_since_last_exchange, 50);
+        assert_eq!(edges[0].lag_versions, Some(2));
+
+        let topology = GossipTopology {
+            node_id: node_id.clone(),
+            edges,
+        };
+        let dot = topology.to_dot();
+        let self_label = &node_id.id;
+        let peer_label = &peer_id.id;
+        assert!(dot.starts_with("digraph chitchat {\n"));
+        assert!(dot.contains(&format!(
+            "  {self_label:?} -> {peer_label:?} [label=\"2 exchanges, 50ms ago\"];\n"
+        )));
+        assert!(dot.contains(&format!(
+            "  {peer_label:?} -> {self_label:?} [label=\"1 exchanges, 50ms ago\"];\n"
+        )));
+        assert!(dot.contains(&format!(
+            "  \"127.0.0.1:10003\" -> {self_label:?} [label=\"1 exchanges, 50ms ago\"];\n"
+        )));
+
+        interaction_tracker.forget_peer(unknown_peer_addr);
+        assert_eq!(interaction_tracker.edges(now, |_| (None, None)).len(), 1);
+    }
+}
@@ -2,13 +2,13 @@ use std::net::SocketAddr;
 
 use async_trait::async_trait;
 
-use crate::message::ChitchatMessage;
-
 mod channel;
+pub(crate) mod socket;
 mod udp;
 mod utils;
 
 pub use channel::{ChannelTransport, Statistics};
+pub(crate) use socket::Socket;
 pub use udp::UdpTransport;
 pub use utils::TransportExt;
 
@@ -17,16 +17,6 @@ pub trait Transport: Send + Sync + 'static {
     async fn open(&self, listen_addr: SocketAddr) -> anyhow::Result<Box<dyn Socket>>;
 }
 
-#[async_trait]
-pub trait Socket: Send + Sync + 'static {
-    // Only returns an error if the transport is broken and may not emit message
-    // in the future.
-    async fn send(&mut self, to: SocketAddr, msg: ChitchatMessage) -> anyhow::Result<()>;
-    // Only returns an error if the transport is broken and may not receive message
-    // in the future.
-    async fn recv(&mut self) -> anyhow::Result<(SocketAddr, ChitchatMessage)>;
-}
-
 #[cfg(test)]
 mod tests {
     use std::net::SocketAddr;
@@ -40,6 +30,7 @@ mod tests {
     use crate::message::ChitchatMessage;
     use crate::serialize::Serializable;
     use crate::transport::{ChannelTransport, UdpTransport};
+    use crate::ReceiveRateLimit;
 
     fn sample_syn_msg() -> ChitchatMessage {
         ChitchatMessage::Syn {
@@ -71,6 +62,33 @@ mod tests {
         assert_eq!(received_message, valid_message);
     }
 
+    #[tokio::test]
+    async fn test_udp_transport_rate_limits_payloads_before_deserializing() {
+        let recv_addr: SocketAddr = ([127, 0, 0, 1], 30_002u16).into();
+        let send_addr: SocketAddr = ([127, 0, 0, 1], 30_003u16).into();
+        let send_udp_socket: UdpSocket = UdpSocket::bind(send_addr).await.unwrap();
+        let mut recv_socket = UdpTransport.open(recv_addr).await.unwrap();
+        assert!(recv_socket.set_receive_rate_limit(ReceiveRateLimit {
+            max_messages_per_sec: 1,
+            max_bytes_per_sec: 1_000_000,
+        }));
+        let mut valid_payload: Vec<u8> = Vec::new();
+        sample_syn_msg().serialize(&mut valid_payload);
+        send_udp_socket
+            .send_to(&valid_payload[..], recv_addr)
+            .await
+            .unwrap();
+        recv_socket.recv().await.unwrap();
+        // Junk beyond the budget is dropped without being deserialized.
+        send_udp_socket.send_to(b"junk", recv_addr).await.unwrap();
+        assert!(timeout(Duration::from_millis(200), recv_socket.recv())
+            .await
+            .is_err());
+        assert_eq!(recv_socket.num_rate_limited_payloads(), 1);
+        assert_eq!(recv_socket.num_rate_limited_bytes(), 4);
+        assert_eq!(recv_socket.num_invalid_payloads(), 0);
+    }
+
     async fn test_transport_cannot_open_twice_aux(transport: &dyn Transport) {
         let addr: SocketAddr = ([127, 0, 0, 1], 10_000u16).into();
         let _socket = transport.open(addr).await.unwrap();
@@ -0,0 +1,42 @@
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+
+use crate::message::ChitchatMessage;
+use crate::{ParseLimits, ReceiveRateLimit};
+
+/// Socket opened by a [`crate::transport::Transport`], exchanging messages with peers.
+///
+/// Implementing a socket requires the messages of the protocol, exposed by
+/// `chitchat::internal` with the `unstable` feature.
+#[async_trait]
+pub trait Socket: Send + Sync + 'static {
+    // Only returns an error if the transport is broken and may not emit message
+    // in the future.
+    async fn send(&mut self, to: SocketAddr, msg: ChitchatMessage) -> anyhow::Result<()>;
+    // Only returns an error if the transport is broken and may not receive message
+    // in the future.
+    async fn recv(&mut self) -> anyhow::Result<(SocketAddr, ChitchatMessage)>;
+    // Returns the number of payloads received that could not be deserialized into a message,
+    // and were dropped.
+    fn num_invalid_payloads(&self) -> u64 {
+        0
+    }
+    // Limits the rate of the payloads received from each source address, checked on the raw
+    // payloads before they are deserialized. Returns false if the socket does not support it, in
+    // which case the server checks the rate limit of the messages once deserialized.
+    fn set_receive_rate_limit(&mut self, _rate_limit: ReceiveRateLimit) -> bool {
+        false
+    }
+    // Sets the bounds enforced while deserializing the payloads received into messages.
+    fn set_parse_limits(&mut self, _parse_limits: ParseLimits) {}
+    // Returns the number of payloads received that exceeded the rate limit of their source
+    // address, and were dropped.
+    fn num_rate_limited_payloads(&self) -> u64 {
+        0
+    }
+    // Returns the number of bytes of the payloads counted by `num_rate_limited_payloads`.
+    fn num_rate_limited_bytes(&self) -> u64 {
+        0
+    }
+}
@@ -1,12 +1,17 @@
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use async_trait::async_trait;
-use tracing::warn;
+use tracing::{debug, warn};
 
-use crate::serialize::Serializable;
+use crate::rate_limiter::SourceRateLimiter;
+use crate::serialize::{ParseLimits, Serializable};
 use crate::transport::{Socket, Transport};
-use crate::{ChitchatMessage, MAX_UDP_DATAGRAM_PAYLOAD_SIZE};
+use crate::{ChitchatMessage, ReceiveRateLimit, MAX_UDP_DATAGRAM_PAYLOAD_SIZE};
+
+/// Interval at which the refilled budgets of the rate limiter are pruned.
+const RATE_LIMITER_PRUNE_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct UdpTransport;
 
@@ -20,6 +25,11 @@ impl Transport for UdpTransport {
             buf_send: Vec::with_capacity(MAX_UDP_DATAGRAM_PAYLOAD_SIZE),
             buf_recv: Box::new([0u8; MAX_UDP_DATAGRAM_PAYLOAD_SIZE]),
             socket,
+            num_invalid_payloads: 0,
+            parse_limits: ParseLimits::for_payload_size(MAX_UDP_DATAGRAM_PAYLOAD_SIZE),
+            rate_limiter_opt: None,
+            num_rate_limited_payloads: 0,
+            num_rate_limited_bytes: 0,
         }))
     }
 }
@@ -28,6 +38,17 @@ struct UdpSocket {
     buf_send: Vec<u8>,
     buf_recv: Box<[u8; MAX_UDP_DATAGRAM_PAYLOAD_SIZE]>,
     socket: tokio::net::UdpSocket,
+    num_invalid_payloads: u64,
+    parse_limits: ParseLimits,
+    rate_limiter_opt: Option<RateLimiter>,
+    num_rate_limited_payloads: u64,
+    num_rate_limited_bytes: u64,
+}
+
+struct RateLimiter {
+    rate_limit: ReceiveRateLimit,
+    source_rate_limiter: SourceRateLimiter,
+    pruned_at: Instant,
 }
 
 #[async_trait]
@@ -47,6 +68,31 @@ impl Socket for UdpSocket {
             }
         }
     }
+
+    fn num_invalid_payloads(&self) -> u64 {
+        self.num_invalid_payloads
+    }
+
+    fn set_receive_rate_limit(&mut self, rate_limit: ReceiveRateLimit) -> bool {
+        self.rate_limiter_opt = Some(RateLimiter {
+            rate_limit,
+            source_rate_limiter: SourceRateLimiter::default(),
+            pruned_at: Instant::now(),
+        });
+        true
+    }
+
+    fn set_parse_limits(&mut self, parse_limits: ParseLimits) {
+        self.parse_limits = parse_limits;
+    }
+
+    fn num_rate_limited_payloads(&self) -> u64 {
+        self.num_rate_limited_payloads
+    }
+
+    fn num_rate_limited_bytes(&self) -> u64 {
+        self.num_rate_limited_bytes
+    }
 }
 
 impl UdpSocket {
@@ -56,16 +102,43 @@ impl UdpSocket {
             .recv_from(&mut self.buf_recv[..])
             .await
             .context("Error while receiving UDP message")?;
+        if !self.admit_payload(from_addr, len) {
+            return Ok(None);
+        }
         let mut buf = &self.buf_recv[..len];
-        match ChitchatMessage::deserialize(&mut buf) {
+        match ChitchatMessage::deserialize_with_limits(&mut buf, &self.parse_limits) {
             Ok(msg) => Ok(Some((from_addr, msg))),
             Err(err) => {
                 warn!(payload_len=len, from=%from_addr, err=%err, "invalid-chitchat-payload");
+                self.num_invalid_payloads += 1;
                 Ok(None)
             }
         }
     }
 
+    /// Returns true if a payload of `len` bytes received from `from_addr` is within the rate
+    /// limit of the address, checked before deserializing it.
+    fn admit_payload(&mut self, from_addr: SocketAddr, len: usize) -> bool {
+        let Some(rate_limiter) = &mut self.rate_limiter_opt else {
+            return true;
+        };
+        let now = Instant::now();
+        if now.saturating_duration_since(rate_limiter.pruned_at) >= RATE_LIMITER_PRUNE_INTERVAL {
+            rate_limiter.source_rate_limiter.prune(now);
+            rate_limiter.pruned_at = now;
+        }
+        if rate_limiter
+            .source_rate_limiter
+            .admit(&rate_limiter.rate_limit, from_addr, len, now)
+        {
+            return true;
+        }
+        self.num_rate_limited_payloads += 1;
+        self.num_rate_limited_bytes += len as u64;
+        debug!(peer_addr = %from_addr, num_bytes = len, "dropping-rate-limited-payload");
+        false
+    }
+
     pub(crate) async fn send_bytes(
         &self,
         to_addr: SocketAddr,
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::delta::Delta;
+use crate::NodeId;
+
+/// Keeps track of the nodes advertised by peers for which no data was ever received.
+///
+/// A misconfigured or malicious peer can keep advertising a node that does not exist.
+/// Such a node would otherwise stay forever in the cluster state as an empty placeholder,
+/// and we would keep asking peers about it in our digests.
+#[derive(Debug)]
+pub(crate) struct UnknownNodeTracker {
+    grace_period: Duration,
+    /// Instant at which each unknown node was first observed.
+    unknown_nodes: HashMap<NodeId, Instant>,
+    /// Instant at which each unknown node was forgotten. Empty deltas for these nodes
+    /// are ignored until the grace period elapses again.
+    forgotten_nodes: HashMap<NodeId, Instant>,
+    num_forgotten_nodes: u64,
+}
+
+impl UnknownNodeTracker {
+    pub fn new(grace_period: Duration) -> Self {
+        UnknownNodeTracker {
+            grace_period,
+            unknown_nodes: HashMap::new(),
+            forgotten_nodes: HashMap::new(),
+            num_forgotten_nodes: 0,
+        }
+    }
+
+    /// Removes from the delta the empty node deltas of forgotten nodes, so that they do not
+    /// reappear in the cluster state.
+    ///
+    /// A forgotten node for which data is received is not unknown anymore.
+    pub fn filter_delta(&mut self, delta: &mut Delta) {
+        if self.forgotten_nodes.is_empty() {
+            return;
+        }
+        delta.node_deltas.retain(|node_id, node_delta| {
+            if !self.forgotten_nodes.contains_key(node_id) {
+                return true;
+            }
+            if node_delta.key_values.is_empty() {
+                return false;
+            }
+            self.forgotten_nodes.remove(node_id);
+            true
+        });
+    }
+
+    /// Updates the tracker with the nodes of the cluster state for which no data was received
+    /// so far, and returns the ones that have been unknown for longer than the grace period.
+    /// These nodes must be removed from the cluster state.
+    pub fn nodes_to_forget<'a>(
+        &mut self,
+        unknown_nodes: impl Iterator<Item = &'a NodeId>,
+        now: Instant,
+    ) -> Vec<NodeId> {
+        let grace_period = self.grace_period;
+        let mut next_unknown_nodes = HashMap::new();
+        for node_id in unknown_nodes {
+            let first_seen = self.unknown_nodes.get(node_id).copied().unwrap_or(now);
+            next_unknown_nodes.insert(node_id.clone(), first_seen);
+        }
+        self.unknown_nodes = next_unknown_nodes;
+        self.forgotten_nodes
+            .retain(|_, forgotten_at| now.duration_since(*forgotten_at) <= grace_period);
+
+        let nodes_to_forget: Vec<NodeId> = self
+            .unknown_nodes
+            .iter()
+            .filter(|(_, first_seen)| now.duration_since(**first_seen) > grace_period)
+            .map(|(node_id, _)| node_id.clone())
+            .collect();
+        for node_id in &nodes_to_forget {
+            warn!(node_id = ?node_id, "forgetting-unknown-node");
+            self.unknown_nodes.remove(node_id);
+            self.forgotten_nodes.insert(node_id.clone(), now);
+            self.num_forgotten_nodes += 1;
+        }
+        nodes_to_forget
+    }
+
+    pub fn remove_node(&mut self, node_id: &NodeId) {
+        self.unknown_nodes.remove(node_id);
+        self.forgotten_nodes.remove(node_id);
+    }
+
+    /// Returns the number of nodes currently in the cluster state without any data.
+    pub fn num_unknown_nodes(&self) -> usize {
+        self.unknown_nodes.len()
+    }
+
+    /// Returns the number of unknown nodes that were forgotten since startup.
+    pub fn num_forgotten_nodes(&self) -> u64 {
+        self.num_forgotten_nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_node_tracker_forgets_nodes_after_grace_period() {
+        let mut tracker = UnknownNodeTracker::new(Duration::from_secs(10));
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let start = Instant::now();
+
+        assert!(tracker
+            .nodes_to_forget([&node1, &node2].into_iter(), start)
+            .is_empty());
+        assert_eq!(tracker.num_unknown_nodes(), 2);
+        // Node 2 received some data.
+        assert!(tracker
+            .nodes_to_forget([&node1].into_iter(), start + Duration::from_secs(5))
+            .is_empty());
+        assert_eq!(
+            tracker.nodes_to_forget([&node1].into_iter(), start + Duration::from_secs(11)),
+            vec![node1.clone()]
+        );
+        assert_eq!(tracker.num_unknown_nodes(), 0);
+        assert_eq!(tracker.num_forgotten_nodes(), 1);
+        // Node 2 is unknown again: it starts a new grace period.
+        assert!(tracker
+            .nodes_to_forget([&node2].into_iter(), start + Duration::from_secs(12))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_unknown_node_tracker_filter_delta() {
+        let mut tracker = UnknownNodeTracker::new(Duration::from_secs(10));
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let start = Instant::now();
+        tracker.nodes_to_forget([&node1, &node2].into_iter(), start);
+        tracker.nodes_to_forget(
+            [&node1, &node2].into_iter(),
+            start + Duration::from_secs(11),
+        );
+
+        let mut delta = Delta::default();
+        delta.node_deltas.insert(node1.clone(), Default::default());
+        delta.add_node_delta(node2.clone(), "key", "value", 1, false);
+        tracker.filter_delta(&mut delta);
+        assert!(!delta.node_deltas.contains_key(&node1));
+        assert!(delta.node_deltas.contains_key(&node2));
+        assert!(tracker.forgotten_nodes.contains_key(&node1));
+        assert!(!tracker.forgotten_nodes.contains_key(&node2));
+
+        // Forgotten nodes are remembered for a grace period only.
+        tracker.nodes_to_forget(std::iter::empty(), start + Duration::from_secs(22));
+        let mut delta = Delta::default();
+        delta.node_deltas.insert(node1.clone(), Default::default());
+        tracker.filter_delta(&mut delta);
+        assert!(delta.node_deltas.contains_key(&node1));
+    }
+}
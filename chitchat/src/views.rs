@@ -0,0 +1,315 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::change_journal::ChangeJournal;
+use crate::state::ClusterState;
+use crate::NodeId;
+
+/// Minimum number of incremental updates of a sum view between two recomputations of the sum.
+const MIN_SUM_RECOMPUTE_INTERVAL: usize = 64;
+
+/// Aggregation maintained by a materialized view over the values of a key.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ViewKind {
+    /// Sum of the numeric values of the key. Non-numeric values are ignored.
+    Sum,
+    /// Distinct values of the key, with the number of nodes holding each of them.
+    DistinctValues,
+}
+
+/// Current result of a materialized view.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ViewResult {
+    Sum(f64),
+    DistinctValues(BTreeMap<String, usize>),
+}
+
+impl ViewResult {
+    fn empty(kind: ViewKind) -> Self {
+        match kind {
+            ViewKind::Sum => ViewResult::Sum(0.0),
+            ViewKind::DistinctValues => ViewResult::DistinctValues(BTreeMap::new()),
+        }
+    }
+
+    fn add(&mut self, value: &str) {
+        match self {
+            ViewResult::Sum(sum) => {
+                if let Ok(number) = value.parse::<f64>() {
+                    *sum += number;
+                }
+            }
+            ViewResult::DistinctValues(value_counts) => {
+                *value_counts.entry(value.to_string()).or_default() += 1;
+            }
+        }
+    }
+
+    fn subtract(&mut self, value: &str) {
+        match self {
+            ViewResult::Sum(sum) => {
+                if let Ok(number) = value.parse::<f64>() {
+                    *sum -= number;
+                }
+            }
+            ViewResult::DistinctValues(value_counts) => {
+                if let Some(count) = value_counts.get_mut(value) {
+                    *count -= 1;
+                    if *count == 0 {
+                        value_counts.remove(value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MaterializedView {
+    key: String,
+    kind: ViewKind,
+    /// Value of the key on each of the nodes included in the view.
+    node_values: HashMap<NodeId, String>,
+    result: ViewResult,
+    /// Number of incremental updates since the result was last computed from scratch.
+    num_updates_since_recompute: usize,
+}
+
+impl MaterializedView {
+    fn new(key: &str, kind: ViewKind) -> Self {
+        MaterializedView {
+            key: key.to_string(),
+            kind,
+            node_values: HashMap::new(),
+            result: ViewResult::empty(kind),
+            num_updates_since_recompute: 0,
+        }
+    }
+
+    fn set_node_value(&mut self, node_id: &NodeId, value: Option<&str>) {
+        let previous_value_opt = if let Some(value) = value {
+            self.result.add(value);
+            self.node_values.insert(node_id.clone(), value.to_string())
+        } else {
+            self.node_values.remove(node_id)
+        };
+        if let Some(previous_value) = previous_value_opt {
+            self.result.subtract(&previous_value);
+        }
+        self.num_updates_since_recompute += 1;
+        // Adding and subtracting floats accumulates rounding errors: the sum is computed from
+        // scratch again every so often, at a cost amortized over the updates.
+        if self.kind == ViewKind::Sum
+            && self.num_updates_since_recompute
+                >= self.node_values.len().max(MIN_SUM_RECOMPUTE_INTERVAL)
+        {
+            self.recompute();
+        }
+    }
+
+    /// Computes the result from the values of the nodes.
+    fn recompute(&mut self) {
+        self.result = ViewResult::empty(self.kind);
+        for value in self.node_values.values() {
+            self.result.add(value);
+        }
+        self.num_updates_since_recompute = 0;
+    }
+
+    fn rebuild<'a>(
+        &mut self,
+        cluster_state: &ClusterState,
+        included_nodes: impl Iterator<Item = &'a NodeId>,
+    ) {
+        self.node_values.clear();
+        for node_id in included_nodes {
+            if let Some(value) = live_value(cluster_state, node_id, &self.key) {
+                self.node_values.insert(node_id.clone(), value.to_string());
+            }
+        }
+        self.recompute();
+    }
+}
+
+/// Returns the value of `key` on the node `node_id`, unless it is marked for deletion.
+fn live_value<'a>(cluster_state: &'a ClusterState, node_id: &NodeId, key: &str) -> Option<&'a str> {
+    let versioned_value = cluster_state.node_state(node_id)?.get_versioned(key)?;
+    if versioned_value.marked_for_deletion {
+        return None;
+    }
+    Some(versioned_value.value.as_str())
+}
+
+/// Aggregations over the values of keys across the self node and the live nodes, maintained
+/// incrementally from the changes recorded by the change journal.
+///
+/// Views are rebuilt from the cluster state when the set of live nodes changes, or when the
+/// journal evicted changes that were not applied to the views yet.
+#[derive(Debug, Default)]
+pub(crate) struct MaterializedViews {
+    views: HashMap<String, MaterializedView>,
+    /// Sequence number of the last journaled change applied to the views.
+    last_seq: u64,
+    liveness_revision: Option<u64>,
+    /// Nodes whose key-values are included in the views.
+    included_nodes: Vec<NodeId>,
+}
+
+impl MaterializedViews {
+    /// Registers the view `name`, replacing any view registered under the same name.
+    pub fn register(
+        &mut self,
+        name: &str,
+        key: &str,
+        kind: ViewKind,
+        cluster_state: &ClusterState,
+    ) {
+        let mut view = MaterializedView::new(key, kind);
+        view.rebuild(cluster_state, self.included_nodes.iter());
+        self.views.insert(name.to_string(), view);
+    }
+
+    pub fn unregister(&mut self, name: &str) -> bool {
+        self.views.remove(name).is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ViewResult> {
+        self.views.get(name).map(|view| &view.result)
+    }
+
+    /// Applies the changes journaled since the last update to the views.
+    ///
+    /// `included_nodes` is only called when the liveness revision changed.
+    pub fn update<'a, I: Iterator<Item = &'a NodeId>>(
+        &mut self,
+        cluster_state: &ClusterState,
+        change_journal: &ChangeJournal,
+        liveness_revision: u64,
+        included_nodes: impl FnOnce() -> I,
+    ) {
+        if self.liveness_revision != Some(liveness_revision) {
+            self.liveness_revision = Some(liveness_revision);
+            self.included_nodes = included_nodes().cloned().collect();
+            self.rebuild(cluster_state, change_journal);
+            return;
+        }
+        if self.views.is_empty() {
+            self.last_seq = change_journal.last_seq();
+            return;
+        }
+        let Some(changes) = change_journal.changes_since(self.last_seq) else {
+            self.rebuild(cluster_state, change_journal);
+            return;
+        };
+        self.last_seq = change_journal.last_seq();
+        for change in changes {
+            if !self.included_nodes.contains(&change.node_id) {
+                continue;
+            }
+            for view in self.views.values_mut() {
+                if view.key == change.key {
+                    let value = live_value(cluster_state, &change.node_id, &change.key);
+                    view.set_node_value(&change.node_id, value);
+                }
+            }
+        }
+    }
+
+    fn rebuild(&mut self, cluster_state: &ClusterState, change_journal: &ChangeJournal) {
+        self.last_seq = change_journal.last_seq();
+        for view in self.views.values_mut() {
+            view.rebuild(cluster_state, self.included_nodes.iter());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::Delta;
+
+    #[test]
+    fn test_materialized_views() {
+        let mut cluster_state = ClusterState::default();
+        let mut change_journal = ChangeJournal::default();
+        let mut views = MaterializedViews::default();
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let node3 = NodeId::for_test_localhost(10_003);
+        let included_nodes = [node1.clone(), node2.clone()];
+
+        cluster_state.node_state_mut(&node1).set("load", "1");
+        cluster_state
+            .node_state_mut(&node1)
+            .set("zone", "us-east-1a");
+        change_journal.record_changes(&cluster_state);
+        views.update(&cluster_state, &change_journal, 1, || included_nodes.iter());
+        views.register("total_load", "load", ViewKind::Sum, &cluster_state);
+        views.register("zones", "zone", ViewKind::DistinctValues, &cluster_state);
+        assert_eq!(views.get("total_load"), Some(&ViewResult::Sum(1.0)));
+
+        let mut delta = Delta::default();
+        delta.add_node_delta(node2.clone(), "load", "2.5", 1, false);
+        delta.add_node_delta(node2.clone(), "zone", "us-east-1a", 2, false);
+        delta.add_node_delta(node3.clone(), "load", "100", 1, false);
+        cluster_state.apply_delta(delta);
+        cluster_state.node_state_mut(&node1).set("load", "3");
+        change_journal.record_changes(&cluster_state);
+        views.update(&cluster_state, &change_journal, 1, || included_nodes.iter());
+        // The node 3 is not live.
+        assert_eq!(views.get("total_load"), Some(&ViewResult::Sum(5.5)));
+        assert_eq!(
+            views.get("zones"),
+            Some(&ViewResult::DistinctValues(BTreeMap::from([(
+                "us-east-1a".to_string(),
+                2
+            )])))
+        );
+
+        cluster_state
+            .node_state_mut(&node1)
+            .mark_for_deletion("zone");
+        cluster_state
+            .node_state_mut(&node1)
+            .set("load", "not-a-number");
+        change_journal.record_changes(&cluster_state);
+        views.update(&cluster_state, &change_journal, 1, || included_nodes.iter());
+        assert_eq!(views.get("total_load"), Some(&ViewResult::Sum(2.5)));
+        assert_eq!(
+            views.get("zones"),
+            Some(&ViewResult::DistinctValues(BTreeMap::from([(
+                "us-east-1a".to_string(),
+                1
+            )])))
+        );
+
+        // The set of live nodes changed.
+        let included_nodes = [node3.clone()];
+        views.update(&cluster_state, &change_journal, 2, || included_nodes.iter());
+        assert_eq!(views.get("total_load"), Some(&ViewResult::Sum(100.0)));
+        assert_eq!(
+            views.get("zones"),
+            Some(&ViewResult::DistinctValues(BTreeMap::new()))
+        );
+
+        assert!(views.unregister("zones"));
+        assert!(views.get("zones").is_none());
+    }
+
+    #[test]
+    fn test_materialized_view_sum_does_not_drift() {
+        let mut view = MaterializedView::new("load", ViewKind::Sum);
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        view.set_node_value(&node1, Some("1e16"));
+        view.set_node_value(&node2, Some("1"));
+        view.set_node_value(&node1, Some("0"));
+        // The 1 was lost by the rounding of 1e16 + 1.
+        assert_eq!(view.result, ViewResult::Sum(0.0));
+        for _ in 3..MIN_SUM_RECOMPUTE_INTERVAL {
+            view.set_node_value(&node1, Some("0"));
+        }
+        assert_eq!(view.result, ViewResult::Sum(1.0));
+    }
+}
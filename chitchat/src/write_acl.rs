@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+
+use crate::internal_keys::is_reserved_key;
+use crate::{NodeId, HEARTBEAT_KEY};
+
+/// Designates the nodes a rule of a [`WriteAcl`] applies to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WriteAclSubject {
+    /// Every node.
+    Any,
+    /// The nodes with this ID, regardless of their generation and gossip address.
+    NodeId(String),
+    /// The nodes whose metadata carries the label `key` with the value `value`.
+    ///
+    /// Labels are declared by the nodes themselves: rules on labels keep misconfigured nodes
+    /// in line, but only rules on node IDs restrict a compromised node.
+    #[cfg(feature = "json")]
+    Label { key: String, value: String },
+}
+
+impl WriteAclSubject {
+    #[cfg_attr(not(feature = "json"), allow(unused_variables))]
+    fn matches(&self, node_id: &NodeId, labels: &BTreeMap<String, String>) -> bool {
+        match self {
+            WriteAclSubject::Any => true,
+            WriteAclSubject::NodeId(id) => node_id.id == *id,
+            #[cfg(feature = "json")]
+            WriteAclSubject::Label { key, value } => labels.get(key) == Some(value),
+        }
+    }
+}
+
+/// Restricts the keys each remote node may publish to the prefixes granted to it.
+///
+/// The key-values received from peers outside of the granted prefixes are dropped, and counted
+/// by [`crate::Chitchat::num_unauthorized_key_values`]. Nodes matching no rule may only publish
+/// the keys managed by chitchat itself, e.g. their heartbeat and metadata.
+#[derive(Clone, Debug, Default)]
+pub struct WriteAcl {
+    rules: Vec<(WriteAclSubject, String)>,
+}
+
+impl WriteAcl {
+    /// Allows the nodes designated by `subject` to publish the keys starting with `key_prefix`.
+    pub fn allow(mut self, subject: WriteAclSubject, key_prefix: impl Into<String>) -> Self {
+        self.rules.push((subject, key_prefix.into()));
+        self
+    }
+
+    /// Returns true if a rule is based on labels, which then need to be looked up.
+    #[cfg(feature = "json")]
+    pub(crate) fn has_label_rules(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|(subject, _)| matches!(subject, WriteAclSubject::Label { .. }))
+    }
+
+    /// Returns true if the node `node_id`, carrying `labels`, may publish `key`.
+    pub(crate) fn is_allowed(
+        &self,
+        node_id: &NodeId,
+        labels: &BTreeMap<String, String>,
+        key: &str,
+    ) -> bool {
+        if key == HEARTBEAT_KEY || is_reserved_key(key) {
+            return true;
+        }
+        self.rules.iter().any(|(subject, key_prefix)| {
+            key.starts_with(key_prefix.as_str()) && subject.matches(node_id, labels)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_acl() {
+        let write_acl = WriteAcl::default()
+            .allow(WriteAclSubject::Any, "shared:")
+            .allow(WriteAclSubject::NodeId("indexer-1".to_string()), "indexer:");
+        let indexer = NodeId::new("indexer-1".to_string(), "127.0.0.1:10001".parse().unwrap());
+        let searcher = NodeId::new("searcher-1".to_string(), "127.0.0.1:10002".parse().unwrap());
+        let no_labels = BTreeMap::new();
+        assert!(write_acl.is_allowed(&indexer, &no_labels, "indexer:shards"));
+        assert!(write_acl.is_allowed(&indexer, &no_labels, "shared:version"));
+        assert!(!write_acl.is_allowed(&indexer, &no_labels, "searcher:cache"));
+        assert!(!write_acl.is_allowed(&searcher, &no_labels, "indexer:shards"));
+        assert!(write_acl.is_allowed(&searcher, &no_labels, "shared:version"));
+        // Chitchat keys are always allowed.
+        assert!(write_acl.is_allowed(&searcher, &no_labels, HEARTBEAT_KEY));
+        assert!(write_acl.is_allowed(&searcher, &no_labels, "__chitchat:metadata"));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_write_acl_labels() {
+        let write_acl = WriteAcl::default().allow(
+            WriteAclSubject::Label {
+                key: "role".to_string(),
+                value: "searcher".to_string(),
+            },
+            "searcher:",
+        );
+        assert!(write_acl.has_label_rules());
+        let node = NodeId::for_test_localhost(10_001);
+        let labels = BTreeMap::from([("role".to_string(), "searcher".to_string())]);
+        assert!(write_acl.is_allowed(&node, &labels, "searcher:cache"));
+        assert!(!write_acl.is_allowed(&node, &BTreeMap::new(), "searcher:cache"));
+    }
+}
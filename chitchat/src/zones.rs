@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use crate::state::ClusterState;
+use crate::{NodeId, Version};
+
+#[derive(Debug)]
+struct CachedZone {
+    /// Version of the key-value the zone was read from.
+    version: Version,
+    zone: String,
+}
+
+/// Caches the zone of each node, read from its [`crate::ChitchatConfig::zone_key`], so that the
+/// gossip rounds and the failure detector do not look it up for every node they consider.
+#[derive(Debug)]
+pub(crate) struct ZoneCache {
+    zone_key: Option<String>,
+    zones: HashMap<NodeId, CachedZone>,
+}
+
+impl ZoneCache {
+    pub fn new(zone_key: Option<String>) -> Self {
+        ZoneCache {
+            zone_key,
+            zones: HashMap::new(),
+        }
+    }
+
+    /// Catches up with the zones of the cluster state, only copying the zones whose key-value
+    /// changed since the last refresh.
+    pub fn refresh(&mut self, cluster_state: &ClusterState) {
+        let Some(zone_key) = self.zone_key.as_deref() else {
+            return;
+        };
+        self.zones
+            .retain(|node_id, _| cluster_state.node_state(node_id).is_some());
+        for (node_id, node_state) in &cluster_state.node_states {
+            let Some(versioned_value) = node_state
+                .get_versioned(zone_key)
+                .filter(|versioned_value| !versioned_value.marked_for_deletion)
+            else {
+                self.zones.remove(node_id);
+                continue;
+            };
+            if self
+                .zones
+                .get(node_id)
+                .is_some_and(|cached_zone| cached_zone.version == versioned_value.version)
+            {
+                continue;
+            }
+            let cached_zone = CachedZone {
+                version: versioned_value.version,
+                zone: versioned_value.value.clone(),
+            };
+            self.zones.insert(node_id.clone(), cached_zone);
+        }
+    }
+
+    /// Returns the zone of `node_id`, as of the last refresh.
+    pub fn zone(&self, node_id: &NodeId) -> Option<&str> {
+        self.zones
+            .get(node_id)
+            .map(|cached_zone| cached_zone.zone.as_str())
+    }
+
+    /// Returns true if the zones of both nodes are known, and differ.
+    pub fn is_cross_zone(&self, node_id: &NodeId, other_node_id: &NodeId) -> bool {
+        match (self.zone(node_id), self.zone(other_node_id)) {
+            (Some(zone), Some(other_zone)) => zone != other_zone,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zone_cache() {
+        let node1 = NodeId::for_test_localhost(10_001);
+        let node2 = NodeId::for_test_localhost(10_002);
+        let node3 = NodeId::for_test_localhost(10_003);
+        let mut cluster_state = ClusterState::default();
+        cluster_state
+            .node_state_mut(&node1)
+            .set("zone", "us-east-1a");
+        cluster_state
+            .node_state_mut(&node2)
+            .set("zone", "us-east-1b");
+        cluster_state.node_state_mut(&node3);
+
+        let mut disabled_zone_cache = ZoneCache::new(None);
+        disabled_zone_cache.refresh(&cluster_state);
+        assert!(disabled_zone_cache.zone(&node1).is_none());
+
+        let mut zone_cache = ZoneCache::new(Some("zone".to_string()));
+        // The zones are only read upon refresh.
+        assert!(zone_cache.zone(&node1).is_none());
+        zone_cache.refresh(&cluster_state);
+        assert_eq!(zone_cache.zone(&node1), Some("us-east-1a"));
+        assert!(zone_cache.is_cross_zone(&node1, &node2));
+        // Nodes of unknown zones are not in another zone.
+        assert!(!zone_cache.is_cross_zone(&node1, &node3));
+
+        cluster_state
+            .node_state_mut(&node2)
+            .set("zone", "us-east-1a");
+        cluster_state
+            .node_state_mut(&node1)
+            .mark_for_deletion("zone");
+        zone_cache.refresh(&cluster_state);
+        assert!(zone_cache.zone(&node1).is_none());
+        assert_eq!(zone_cache.zone(&node2), Some("us-east-1a"));
+
+        cluster_state.remove_node(&node2);
+        zone_cache.refresh(&cluster_state);
+        assert!(zone_cache.zone(&node2).is_none());
+    }
+}
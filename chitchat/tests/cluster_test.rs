@@ -1,11 +1,12 @@
 use std::collections::{HashMap, HashSet};
-use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::time::Duration;
 
 use anyhow::anyhow;
 use chitchat::transport::ChannelTransport;
 use chitchat::{
     spawn_chitchat, ChitchatConfig, ChitchatHandle, FailureDetectorConfig, NodeId, NodeState,
+    TombstoneGcPolicy,
 };
 use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
@@ -205,6 +206,49 @@ impl Simulator {
             },
             is_ready_predicate: None,
             marked_for_deletion_grace_period: self.marked_for_deletion_key_grace_period,
+            tombstone_gc_policy: TombstoneGcPolicy::WallClock,
+            tombstone_grace_period: self.gossip_interval
+                * self.marked_for_deletion_key_grace_period as u32,
+            oversized_key_value_policy: Default::default(),
+            unknown_node_grace_period: Duration::from_secs(60),
+            compaction_churn_threshold: None,
+            write_after_shutdown_policy: Default::default(),
+            node_state_limits: Default::default(),
+            max_cluster_state_bytes: None,
+            #[cfg(feature = "json")]
+            persistence: None,
+            delta_interceptor: None,
+            events: None,
+            write_acl: None,
+            audit_sink: None,
+            max_version_jump: Some(10_000_000),
+            metrics_recorder: None,
+            propagation_latency_tracking: false,
+            #[cfg(feature = "encryption")]
+            sealed_keys: None,
+            region_aware_gossip: None,
+            failure_detector: None,
+            indirect_probe_count: 3,
+            dead_node_eviction_policy: Default::default(),
+            full_sync_interval: None,
+            #[cfg(feature = "http-debug")]
+            debug_http_listen_addr: None,
+            gossip_fanout: Default::default(),
+            peer_selection: None,
+            zone_key: None,
+            topology: None,
+            leader_election: None,
+            gossip_interval_jitter: 0.0,
+            adaptive_gossip: None,
+            seed_provider: None,
+            seed_refresh_interval: Duration::from_secs(60),
+            exchange_peer_addrs: false,
+            seed_backoff: Default::default(),
+            cluster_key: None,
+            accepted_cluster_keys: Vec::new(),
+            replay_window: Some(Duration::from_secs(60)),
+            receive_rate_limit: None,
+            parse_limits: None,
         };
         let handle = spawn_chitchat(config, Vec::new(), &self.transport)
             .await
@@ -214,24 +258,16 @@ impl Simulator {
 }
 
 pub fn create_node_id(id: &str) -> NodeId {
-    let port = find_available_tcp_port().unwrap();
+    // Nodes communicate through a `ChannelTransport`, so ports only need to be unique.
+    static NEXT_PORT: AtomicU16 = AtomicU16::new(20_000);
+    let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
     NodeId {
         id: id.to_string(),
+        generation: 0,
         gossip_public_address: ([127, 0, 0, 1], port).into(),
     }
 }
 
-/// Copy-pasted from Quickwit repo.
-/// Finds a random available TCP port.
-///
-/// This function induces a race condition, use it only in unit tests.
-pub fn find_available_tcp_port() -> anyhow::Result<u16> {
-    let socket: SocketAddr = ([127, 0, 0, 1], 0u16).into();
-    let listener = TcpListener::bind(socket)?;
-    let port = listener.local_addr()?.port();
-    Ok(port)
-}
-
 #[tokio::test]
 async fn test_simple_simulation_insert() {
     let _ = tracing_subscriber::fmt::try_init();
@@ -345,6 +381,12 @@ async fn test_marked_for_deletion_gc_with_network_partition() {
             predicate: NodeStatePredicate::KeyPresent("key_a".to_string(), true),
             timeout_opt: Some(Duration::from_millis(300)),
         },
+        Operation::NodeStateAssert {
+            server_node_id: node_id_3.clone(),
+            node_id: node_id_1.clone(),
+            predicate: NodeStatePredicate::KeyPresent("key_a".to_string(), true),
+            timeout_opt: Some(Duration::from_millis(300)),
+        },
         // Isolate node 3.
         Operation::RemoveNetworkLink(node_id_1.clone(), node_id_3.clone()),
         Operation::RemoveNetworkLink(node_id_2.clone(), node_id_3.clone()),
@@ -455,7 +497,7 @@ async fn test_simple_simulation_heavy_insert_delete() {
             keys_values.push((key.to_string(), value.to_string()));
             let keys_entry = keys_values_inserted_per_node_id
                 .entry(node_id.clone())
-                .or_insert_with(HashSet::new);
+                .or_default();
             keys_entry.insert(key.to_string());
         }
         simulator
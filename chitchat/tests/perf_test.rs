@@ -12,6 +12,7 @@ async fn spawn_one(node_id: u16, transport: &dyn Transport) -> ChitchatHandle {
     let listen_addr: SocketAddr = ([127, 0, 0, 1], 10_000u16 + node_id).into();
     let node_id = NodeId {
         id: format!("node_{node_id}"),
+        generation: 0,
         gossip_public_address: listen_addr,
     };
     let gossip_interval = Duration::from_millis(300);
@@ -27,6 +28,48 @@ async fn spawn_one(node_id: u16, transport: &dyn Transport) -> ChitchatHandle {
         },
         is_ready_predicate: None,
         marked_for_deletion_grace_period: 10_000,
+        tombstone_gc_policy: Default::default(),
+        tombstone_grace_period: Duration::from_secs(3_600),
+        oversized_key_value_policy: Default::default(),
+        unknown_node_grace_period: Duration::from_secs(60),
+        compaction_churn_threshold: None,
+        write_after_shutdown_policy: Default::default(),
+        node_state_limits: Default::default(),
+        max_cluster_state_bytes: None,
+        #[cfg(feature = "json")]
+        persistence: None,
+        delta_interceptor: None,
+        events: None,
+        write_acl: None,
+        audit_sink: None,
+        max_version_jump: Some(10_000_000),
+        metrics_recorder: None,
+        propagation_latency_tracking: false,
+        #[cfg(feature = "encryption")]
+        sealed_keys: None,
+        region_aware_gossip: None,
+        failure_detector: None,
+        indirect_probe_count: 3,
+        dead_node_eviction_policy: Default::default(),
+        full_sync_interval: None,
+        #[cfg(feature = "http-debug")]
+        debug_http_listen_addr: None,
+        gossip_fanout: Default::default(),
+        peer_selection: None,
+        zone_key: None,
+        topology: None,
+        leader_election: None,
+        gossip_interval_jitter: 0.0,
+        adaptive_gossip: None,
+        seed_provider: None,
+        seed_refresh_interval: Duration::from_secs(60),
+        exchange_peer_addrs: false,
+        seed_backoff: Default::default(),
+        cluster_key: None,
+        accepted_cluster_keys: Vec::new(),
+        replay_window: Some(Duration::from_secs(60)),
+        receive_rate_limit: None,
+        parse_limits: None,
     };
     spawn_chitchat(config, Vec::new(), transport).await.unwrap()
 }